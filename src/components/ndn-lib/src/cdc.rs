@@ -0,0 +1,250 @@
+// 内容定义分块(FastCDC)
+// 分块边界由内容决定,文件中间插入/删除数据只会影响附近的几个chunk,
+// 没有变化的区域会得到相同的ChunkId,重新发布时可以直接复用NamedDataStore里已有的chunk
+use serde::{Serialize,Deserialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{NdnError, NdnResult, MAX_CHUNK_SIZE};
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct CdcOptions {
+    pub min_size:u64,
+    //平均chunk大小,会向下取整到2的幂
+    pub avg_size:u64,
+    pub max_size:u64,
+}
+
+impl Default for CdcOptions {
+    fn default() -> Self {
+        Self {
+            min_size: 256*1024,
+            avg_size: 1024*1024,
+            max_size: 4*1024*1024,
+        }
+    }
+}
+
+//发布时的去重统计
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct CdcDedupStats {
+    pub total_chunks:u64,
+    pub total_bytes:u64,
+    //store中已经存在,直接复用的chunk
+    pub reused_chunks:u64,
+    pub reused_bytes:u64,
+}
+
+impl CdcDedupStats {
+    pub fn add_chunk(&mut self, chunk_size:u64, is_reused:bool) {
+        self.total_chunks += 1;
+        self.total_bytes += chunk_size;
+        if is_reused {
+            self.reused_chunks += 1;
+            self.reused_bytes += chunk_size;
+        }
+    }
+
+    pub fn new_bytes(&self) -> u64 {
+        self.total_bytes - self.reused_bytes
+    }
+
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
+        }
+        self.reused_bytes as f64 / self.total_bytes as f64
+    }
+}
+
+const fn gen_gear_table() -> [u64; 256] {
+    //splitmix64,保证所有节点上的分块结果一致
+    let mut table = [0u64; 256];
+    let mut seed: u64 = 0x6275636b796f7321;
+    let mut i = 0;
+    while i < 256 {
+        seed = seed.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+const GEAR_TABLE: [u64; 256] = gen_gear_table();
+
+pub struct ContentDefinedChunker {
+    options:CdcOptions,
+    //在平均大小之前用更难满足的mask,之后用更容易满足的mask(normalized chunking),让chunk大小更集中
+    mask_s:u64,
+    mask_l:u64,
+}
+
+impl ContentDefinedChunker {
+    pub fn new(options:CdcOptions) -> NdnResult<Self> {
+        if options.min_size == 0 || options.min_size > options.avg_size || options.avg_size > options.max_size {
+            return Err(NdnError::InvalidParam(format!("invalid cdc options:{:?}",options)));
+        }
+        if options.max_size > MAX_CHUNK_SIZE {
+            return Err(NdnError::InvalidParam(format!("cdc max_size too large:{}",options.max_size)));
+        }
+
+        let bits = 63 - options.avg_size.leading_zeros();
+        if bits < 2 {
+            return Err(NdnError::InvalidParam(format!("cdc avg_size too small:{}",options.avg_size)));
+        }
+        Ok(Self {
+            options,
+            mask_s: Self::gen_mask(bits + 1),
+            mask_l: Self::gen_mask(bits - 1),
+        })
+    }
+
+    pub fn get_options(&self) -> &CdcOptions {
+        &self.options
+    }
+
+    //使用fp的高位,高位受到更多字节的影响
+    fn gen_mask(bits:u32) -> u64 {
+        ((1u64 << bits) - 1) << (64 - bits)
+    }
+
+    //返回data中第一个chunk的长度
+    pub fn find_boundary(&self, data:&[u8]) -> usize {
+        let min_size = self.options.min_size as usize;
+        let mut normal_size = self.options.avg_size as usize;
+        let mut n = data.len();
+        if n <= min_size {
+            return n;
+        }
+        if n >= self.options.max_size as usize {
+            n = self.options.max_size as usize;
+        } else if n <= normal_size {
+            normal_size = n;
+        }
+
+        let mut fp: u64 = 0;
+        let mut i = min_size;
+        while i < normal_size {
+            fp = (fp << 1).wrapping_add(GEAR_TABLE[data[i] as usize]);
+            if fp & self.mask_s == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < n {
+            fp = (fp << 1).wrapping_add(GEAR_TABLE[data[i] as usize]);
+            if fp & self.mask_l == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        n
+    }
+
+    pub fn split(&self, data:&[u8]) -> Vec<usize> {
+        let mut result = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let chunk_len = self.find_boundary(&data[pos..]);
+            result.push(chunk_len);
+            pos += chunk_len;
+        }
+        result
+    }
+}
+
+//从reader中按内容定义的边界依次读出chunk
+pub struct CdcChunkReader<'a, R: AsyncRead + Unpin> {
+    chunker:&'a ContentDefinedChunker,
+    reader:R,
+    buffer:Vec<u8>,
+    is_eof:bool,
+}
+
+impl<'a, R: AsyncRead + Unpin> CdcChunkReader<'a, R> {
+    pub fn new(chunker:&'a ContentDefinedChunker, reader:R) -> Self {
+        Self {
+            chunker,
+            reader,
+            buffer: Vec::with_capacity(chunker.options.max_size as usize),
+            is_eof: false,
+        }
+    }
+
+    pub async fn next_chunk(&mut self) -> NdnResult<Option<Vec<u8>>> {
+        let max_size = self.chunker.options.max_size as usize;
+        while !self.is_eof && self.buffer.len() < max_size {
+            let old_len = self.buffer.len();
+            self.buffer.resize(max_size, 0);
+            let n = self.reader.read(&mut self.buffer[old_len..]).await
+                .map_err(|e| NdnError::IoError(format!("cdc read failed:{}",e)))?;
+            self.buffer.truncate(old_len + n);
+            if n == 0 {
+                self.is_eof = true;
+            }
+        }
+
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let chunk_len = self.chunker.find_boundary(&self.buffer);
+        let remain = self.buffer.split_off(chunk_len);
+        let chunk = std::mem::replace(&mut self.buffer, remain);
+        Ok(Some(chunk))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChunkHasher;
+    use rand::RngCore;
+    use std::collections::HashSet;
+
+    fn chunk_hashes(chunker:&ContentDefinedChunker, data:&[u8]) -> Vec<Vec<u8>> {
+        let mut pos = 0;
+        let mut result = Vec::new();
+        for chunk_len in chunker.split(data) {
+            let mut hasher = ChunkHasher::new(None).unwrap();
+            result.push(hasher.calc_from_bytes(&data[pos..pos + chunk_len]));
+            pos += chunk_len;
+        }
+        result
+    }
+
+    #[tokio::test]
+    async fn test_cdc_split_and_dedup() {
+        let options = CdcOptions {
+            min_size: 2*1024,
+            avg_size: 8*1024,
+            max_size: 32*1024,
+        };
+        let chunker = ContentDefinedChunker::new(options.clone()).unwrap();
+        let mut data = vec![0u8; 1024*1024];
+        rand::rng().fill_bytes(&mut data);
+
+        let chunk_lens = chunker.split(&data);
+        assert_eq!(chunk_lens.iter().sum::<usize>(), data.len());
+        for chunk_len in &chunk_lens[..chunk_lens.len() - 1] {
+            assert!(*chunk_len as u64 >= options.min_size && *chunk_len as u64 <= options.max_size);
+        }
+
+        //reader和split的分块结果一致
+        let mut reader = CdcChunkReader::new(&chunker, &data[..]);
+        let mut reader_lens = Vec::new();
+        while let Some(chunk) = reader.next_chunk().await.unwrap() {
+            reader_lens.push(chunk.len());
+        }
+        assert_eq!(reader_lens, chunk_lens);
+
+        //在中间插入数据后,大部分chunk不变
+        let mut modified = data.clone();
+        modified.splice(500*1024..500*1024, b"insert some bytes".iter().cloned());
+        let old_hashes: HashSet<Vec<u8>> = chunk_hashes(&chunker, &data).into_iter().collect();
+        let new_hashes = chunk_hashes(&chunker, &modified);
+        let reused = new_hashes.iter().filter(|hash| old_hashes.contains(*hash)).count();
+        assert!(reused + 3 >= new_hashes.len(), "reused:{} total:{}", reused, new_hashes.len());
+    }
+}
@@ -18,13 +18,36 @@ pub type ChunkReader = Pin<Box<dyn AsyncRead + Unpin + Send>>;
 pub type ChunkWriter = Pin<Box<dyn AsyncWrite + Unpin + Send>>;
 //We support 3 types of chunktype:qcid, sha256, mix at this time
 //单个
-#[derive(Debug, Clone,Eq, PartialEq)]
+#[derive(Debug, Clone,Eq, PartialEq, Hash)]
 pub struct ChunkId {
     pub hash_type:String,
     pub hash_result: Vec<u8>,
 }
 
-//TODO: add mix hash support
+//mix类型的chunk id: hash_result = varint(data_length) + hash
+//接收方在下载之前就能知道chunk的大小,可以预分配空间并拒绝长度不对的数据
+pub const MIX256_HASH_TYPE: &str = "mix256";
+
+fn encode_varint(mut value:u64, buf:&mut Vec<u8>) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+//返回(value,占用的字节数)
+fn decode_varint(buf:&[u8]) -> Option<(u64,usize)> {
+    let mut value:u64 = 0;
+    for (i, byte) in buf.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
 impl ChunkId {
     pub fn new(chunk_id_str:&str) -> NdnResult<Self> {
         let obj_id = ObjId::new(chunk_id_str)?;
@@ -49,6 +72,25 @@ impl ChunkId {
         Self { hash_type:"sha256".to_string(), hash_result:hash_result.to_vec() }
     }
 
+    pub fn from_mix256_result(data_length: u64, hash_result: &[u8]) -> Self {
+        Self::from_hash_result(MIX256_HASH_TYPE, data_length, hash_result)
+    }
+
+    //根据hash_type构造chunk id,mix类型会把长度编码进去
+    pub fn from_hash_result(hash_type: &str, data_length: u64, hash_result: &[u8]) -> Self {
+        if !hash_type.starts_with("mix") {
+            return Self { hash_type:hash_type.to_string(), hash_result:hash_result.to_vec() };
+        }
+        let mut mix_result = Vec::with_capacity(hash_result.len() + 10);
+        encode_varint(data_length, &mut mix_result);
+        mix_result.extend_from_slice(hash_result);
+        Self { hash_type:hash_type.to_string(), hash_result:mix_result }
+    }
+
+    pub fn is_mix(&self) -> bool {
+        self.hash_type.starts_with("mix")
+    }
+
     pub fn to_string(&self) -> String {
         let hex_str = hex::encode(self.hash_result.clone());
         format!("{}:{}", self.hash_type, hex_str)
@@ -70,11 +112,36 @@ impl ChunkId {
 
     pub fn get_length(&self) -> Option<u64> {
         //mix hash can get length from hash_hex_string
-        None
-    }    
+        if !self.is_mix() {
+            return None;
+        }
+        decode_varint(&self.hash_result).map(|(length,_)| length)
+    }
+
+    //去掉长度前缀后的hash
+    pub fn get_hash(&self) -> &[u8] {
+        if self.is_mix() {
+            if let Some((_,prefix_len)) = decode_varint(&self.hash_result) {
+                return &self.hash_result[prefix_len..];
+            }
+        }
+        &self.hash_result
+    }
 
+    //hash_bytes是ChunkHasher计算出的hash,不包含长度
     pub fn verify_chunk(&self, hash_bytes: &[u8])->bool {
-        self.hash_result == hash_bytes
+        self.get_hash() == hash_bytes
+    }
+
+    //校验完整的chunk数据,mix类型同时校验长度
+    pub fn verify_chunk_data(&self, data: &[u8]) -> NdnResult<bool> {
+        if let Some(length) = self.get_length() {
+            if length != data.len() as u64 {
+                return Ok(false);
+            }
+        }
+        let mut hasher = ChunkHasher::new(Some(self.hash_type.as_str()))?;
+        Ok(self.verify_chunk(&hasher.calc_from_bytes(data)))
     }
 }
 
@@ -94,6 +161,7 @@ impl ChunkHasher {
         //default is sha256
         let hasher = match hash_type {
             Some("sha256") => Sha256::new(),
+            Some(MIX256_HASH_TYPE) => Sha256::new(),
             None => Sha256::new(),
             _ => return Err(NdnError::Internal(format!("invalid hash type:{}",hash_type.unwrap_or("")))),
         };
@@ -136,8 +204,8 @@ impl ChunkHasher {
     }
 
     //return the hash result and the total read size
+    //sha256和mix256都使用sha256计算hash,mix256的长度在构造chunk id时加入
     pub async fn calc_from_reader<T: AsyncRead + Unpin>(&mut self, reader: &mut T) -> NdnResult<(Vec<u8>,u64)> {
-        //TODO: add other hash type support
         let mut hasher = Sha256::new();
         let mut buffer = vec![0u8; CACL_HASH_PIECE_SIZE as usize];
         let mut total_read = 0;
@@ -161,6 +229,16 @@ impl ChunkHasher {
         Ok((hasher.finalize().to_vec(), total_read))
     }
 
+    pub async fn calc_chunk_id_from_reader<T: AsyncRead + Unpin>(&mut self, reader: &mut T) -> NdnResult<(ChunkId,u64)> {
+        let (hash_result,total_read) = self.calc_from_reader(reader).await?;
+        Ok((ChunkId::from_hash_result(&self.hash_type, total_read, &hash_result),total_read))
+    }
+
+    pub fn calc_chunk_id_from_bytes(&mut self,bytes: &[u8]) -> ChunkId {
+        let hash_result = self.calc_from_bytes(bytes);
+        ChunkId::from_hash_result(&self.hash_type, bytes.len() as u64, &hash_result)
+    }
+
     pub fn calc_from_bytes(&mut self,bytes: &[u8]) -> Vec<u8> {
         let mut hasher = Sha256::new();
         hasher.update(bytes);
@@ -179,27 +257,18 @@ impl ChunkHasher {
     }
 
     pub fn finalize_chunk_id(self) -> ChunkId {
+        let hash_type = self.hash_type.clone();
+        let data_length = self.pos;
         let hash_result = self.finalize();
-        ChunkId::from_sha256_result(&hash_result)
+        ChunkId::from_hash_result(&hash_type, data_length, &hash_result)
     }
 
     pub async fn verify_local_file_is_chunk(&self,file_path: &PathBuf,chunk_id: &ChunkId) -> NdnResult<bool> {
         let mut file = tokio::fs::File::open(file_path).await
             .map_err(|e| NdnError::IoError(e.to_string()))?;
-        let mut hasher = Sha256::new();
-        let mut buffer = vec![0u8; CACL_HASH_PIECE_SIZE as usize];
-        let mut total_read = 0;
-        loop {
-            let n = file.read(&mut buffer).await
-                .map_err(|e| NdnError::IoError(e.to_string()))?;
-            total_read += n as u64;
-            if n < CACL_HASH_PIECE_SIZE as usize {
-                break;
-            }
-            hasher.update(&buffer[..n]);
-        }
-        let hash_result = hasher.finalize();
-        Ok(hash_result.as_slice() == chunk_id.hash_result.as_slice())
+        let mut hasher = ChunkHasher::new(Some(chunk_id.hash_type.as_str()))?;
+        let (file_chunk_id,_) = hasher.calc_chunk_id_from_reader(&mut file).await?;
+        Ok(file_chunk_id == *chunk_id)
     }
 }
 
@@ -352,5 +421,38 @@ mod tests {
         assert_eq!(hash_result, hash_result_restored);
     }
 
+    #[tokio::test]
+    async fn test_mix256_chunk_id() {
+        let mut buffer = vec![0u8; 300*1024 + 7];
+        rand::rng().fill(&mut buffer[..]);
+
+        let mut chunk_hasher = ChunkHasher::new(Some(MIX256_HASH_TYPE)).unwrap();
+        let chunk_id = chunk_hasher.calc_chunk_id_from_bytes(&buffer);
+        assert!(chunk_id.is_mix());
+        assert_eq!(chunk_id.get_length(), Some(buffer.len() as u64));
+        assert_eq!(chunk_id.get_hash(), chunk_hasher.calc_from_bytes(&buffer).as_slice());
+        assert!(chunk_id.verify_chunk_data(&buffer).unwrap());
+        assert!(!chunk_id.verify_chunk_data(&buffer[..buffer.len() - 1]).unwrap());
+
+        let chunk_id2 = ChunkId::new(&chunk_id.to_string()).unwrap();
+        assert_eq!(chunk_id2, chunk_id);
+        assert_eq!(chunk_id2.get_length(), Some(buffer.len() as u64));
+
+        //分段计算和从reader计算的结果一致
+        let mut chunk_hasher = ChunkHasher::new(Some(MIX256_HASH_TYPE)).unwrap();
+        chunk_hasher.update_from_bytes(&buffer[..1024]);
+        let mut chunk_hasher = ChunkHasher::restore_from_state(chunk_hasher.save_state()).unwrap();
+        chunk_hasher.update_from_bytes(&buffer[1024..]);
+        assert_eq!(chunk_hasher.finalize_chunk_id(), chunk_id);
+        let mut chunk_hasher = ChunkHasher::new(Some(MIX256_HASH_TYPE)).unwrap();
+        let (reader_chunk_id, total_read) = chunk_hasher.calc_chunk_id_from_reader(&mut &buffer[..]).await.unwrap();
+        assert_eq!(reader_chunk_id, chunk_id);
+        assert_eq!(total_read, buffer.len() as u64);
+
+        let sha256_id = ChunkId::from_sha256_result(chunk_id.get_hash());
+        assert_eq!(sha256_id.get_length(), None);
+        assert_eq!(ChunkId::from_mix256_result(0, &[1,2,3]).get_length(), Some(0));
+    }
+
 
 }
\ No newline at end of file
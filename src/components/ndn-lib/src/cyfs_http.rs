@@ -2,7 +2,8 @@
 use std::collections::HashMap;
 use reqwest::header::HeaderMap;
 use url::Url;
-use crate::{ObjId, NdnResult, NdnError, PathObject};
+use serde::{Serialize,Deserialize};
+use crate::{ChunkId, ObjId, NdnResult, NdnError, PathObject};
 
 enum CYFSUrlMode {
     PathMode,//objid at url path
@@ -12,7 +13,7 @@ enum CYFSUrlMode {
 #[derive(Debug,Clone)]
 pub struct CYFSHttpRespHeaders {
     pub obj_id:Option<ObjId>,//cyfs-obj-id
-    pub obj_size:Option<u64>,//Content-Length
+    pub obj_size:Option<u64>,//cyfs-obj-size or Content-Length
     //if use R-Path http mode ,need this
     pub path_obj:Option<String>,//cyfs-path-obj jwt
 
@@ -58,7 +59,8 @@ pub fn get_cyfs_resp_headers(headers:&HeaderMap)->NdnResult<CYFSHttpRespHeaders>
     }
 
     let mut real_chunk_size = None;
-    let chunk_size = headers.get("Content-Length");
+    //range请求时Content-Length只是range的长度,优先使用cyfs-obj-size
+    let chunk_size = headers.get("cyfs-obj-size").or(headers.get("Content-Length"));
     if chunk_size.is_some() {
         let chunk_size = chunk_size.unwrap().to_str().unwrap();
         let chunk_size = chunk_size.parse::<u64>().map_err(|e| {
@@ -92,3 +94,135 @@ pub fn get_cyfs_resp_headers(headers:&HeaderMap)->NdnResult<CYFSHttpRespHeaders>
         embed_objs:None,
     });
 }
+
+//批量接口,挂在ndn router的根路径下,使用POST
+pub const CYFS_CHUNK_STATE_LIST_PATH: &str = "query_chunk_state_list";
+pub const CYFS_PUT_CHUNK_LIST_PATH: &str = "put_chunk_list";
+//批量接口的请求body大小上限,put_chunk_list只适合一次提交一组小chunk
+pub const MAX_CHUNK_STATE_LIST_BODY_SIZE: u64 = 1024*1024;
+pub const MAX_PUT_CHUNK_LIST_BODY_SIZE: u64 = 32*1024*1024;
+
+//可续传的chunk上传:
+// 1. HEAD chunk_url, cyfs-chunk-progress中的pos是服务端已经收到并校验过hash状态的长度
+// 2. PUT chunk_url, Content-Range: bytes {pos}-{end}/{chunk_size}, 上传剩余的数据
+// 3. 服务端收到全部数据后校验hash,通过才会complete
+// range的起点与服务端不一致时返回416,cyfs-chunk-offset中是服务端当前的位置
+pub const CYFS_CHUNK_OFFSET_HEADER: &str = "cyfs-chunk-offset";
+
+//解析 "bytes start-end/total",返回(start,end,total),end包含在range内
+pub fn parse_content_range(content_range:&str)->NdnResult<(u64,u64,u64)> {
+    let invalid = || NdnError::InvalidParam(format!("invalid content range:{}",content_range));
+    let range = content_range.trim().strip_prefix("bytes ").ok_or_else(invalid)?;
+    let (range,total) = range.split_once('/').ok_or_else(invalid)?;
+    let (start,end) = range.split_once('-').ok_or_else(invalid)?;
+    let start = start.trim().parse::<u64>().map_err(|_| invalid())?;
+    let end = end.trim().parse::<u64>().map_err(|_| invalid())?;
+    let total = total.trim().parse::<u64>().map_err(|_| invalid())?;
+    if start > end || end >= total {
+        return Err(invalid());
+    }
+    Ok((start,end,total))
+}
+
+//从chunk的progress(hasher保存的状态)中得到已经写入的长度
+pub fn get_chunk_progress_pos(progress:&str)->u64 {
+    serde_json::from_str::<serde_json::Value>(progress).ok()
+        .and_then(|progress| progress.get("pos").and_then(|pos| pos.as_u64()))
+        .unwrap_or(0)
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct ChunkStateListReq {
+    pub chunk_list:Vec<String>,
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct ChunkStateListRespItem {
+    pub chunk_id:String,
+    pub chunk_state:String,
+    pub chunk_size:u64,
+    #[serde(default)]
+    pub progress:String,
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct ChunkStateListResp {
+    pub chunk_list:Vec<ChunkStateListRespItem>,
+}
+
+// put_chunk_list的body格式,多个chunk依次排列:
+// | chunk_id_len:u16 | chunk_id(string) | data_len:u64 | data |
+// 数字都是big endian
+pub fn encode_chunk_list_body(chunk_list:&HashMap<ChunkId,Vec<u8>>)->Vec<u8> {
+    let total_size = chunk_list.iter()
+        .map(|(chunk_id,data)| 2 + chunk_id.to_string().len() + 8 + data.len())
+        .sum();
+    let mut body = Vec::with_capacity(total_size);
+    for (chunk_id,data) in chunk_list.iter() {
+        let chunk_id_str = chunk_id.to_string();
+        body.extend_from_slice(&(chunk_id_str.len() as u16).to_be_bytes());
+        body.extend_from_slice(chunk_id_str.as_bytes());
+        body.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        body.extend_from_slice(data);
+    }
+    body
+}
+
+pub fn decode_chunk_list_body(body:&[u8])->NdnResult<HashMap<ChunkId,Vec<u8>>> {
+    let mut chunk_list = HashMap::new();
+    let mut pos = 0;
+    while pos < body.len() {
+        if pos + 2 > body.len() {
+            return Err(NdnError::DecodeError("invalid chunk list body: chunk_id_len".to_string()));
+        }
+        let id_len = u16::from_be_bytes([body[pos],body[pos+1]]) as usize;
+        pos += 2;
+        if pos + id_len + 8 > body.len() {
+            return Err(NdnError::DecodeError("invalid chunk list body: chunk_id".to_string()));
+        }
+        let chunk_id_str = std::str::from_utf8(&body[pos..pos+id_len]).map_err(|e| {
+            NdnError::DecodeError(format!("invalid chunk list body: {}",e.to_string()))
+        })?;
+        let chunk_id = ChunkId::new(chunk_id_str)?;
+        pos += id_len;
+        let mut len_bytes = [0u8;8];
+        len_bytes.copy_from_slice(&body[pos..pos+8]);
+        let data_len = u64::from_be_bytes(len_bytes);
+        pos += 8;
+        if data_len > (body.len() - pos) as u64 {
+            return Err(NdnError::DecodeError(format!("invalid chunk list body: data of {}",chunk_id_str)));
+        }
+        let data_len = data_len as usize;
+        chunk_list.insert(chunk_id,body[pos..pos+data_len].to_vec());
+        pos += data_len;
+    }
+    Ok(chunk_list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_list_body() {
+        let mut chunk_list = HashMap::new();
+        chunk_list.insert(ChunkId::new("sha256:1234567890abcdef").unwrap(), b"chunk 1".to_vec());
+        chunk_list.insert(ChunkId::new("sha256:abcdef1234567890").unwrap(), vec![]);
+        let body = encode_chunk_list_body(&chunk_list);
+        let decoded = decode_chunk_list_body(&body).unwrap();
+        assert_eq!(decoded, chunk_list);
+
+        assert!(decode_chunk_list_body(&body[..body.len()-1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("bytes 0-99/200").unwrap(), (0, 99, 200));
+        assert_eq!(parse_content_range("bytes 100-199/200").unwrap(), (100, 199, 200));
+        assert!(parse_content_range("bytes 100-200/200").is_err());
+        assert!(parse_content_range("bytes 100-99/200").is_err());
+        assert!(parse_content_range("bytes=0-99").is_err());
+        assert_eq!(get_chunk_progress_pos(r#"{"pos":1024}"#), 1024);
+        assert_eq!(get_chunk_progress_pos(""), 0);
+    }
+}
@@ -3,15 +3,23 @@ use serde::{Serialize,Deserialize};
 
 use crate::{ChunkId, LinkData};
 use std::collections::HashMap;
-use crate::{OBJ_TYPE_FILE,OBJ_TYPE_PATH,build_named_object_by_json,ObjId};
+use crate::{OBJ_TYPE_FILE,OBJ_TYPE_PATH,OBJ_TYPE_MTREE,build_named_object_by_json,ObjId,NdnError,NdnResult};
 use serde_json::Value;
+
+//定长分块的chunk_list在FileObject.chunk_list中的key
+pub const FILE_CHUNK_LIST_FIXED: &str = "fixed";
+//内容定义分块(CDC)的chunk_list的key,每个chunk的大小保存在FileObject.chunk_sizes中
+pub const FILE_CHUNK_LIST_CDC: &str = "cdc";
+//大文件默认的分块大小
+pub const DEFAULT_FILE_CHUNK_SIZE: u64 = 1024*1024*16;
+
 //TODO：NDN如何提供一种通用机制，检查FileObject在本地是 完全存在的 ？ 在这里的逻辑是FileObject的Content(存在)
 // 思路：Object如果引用了另一个Object,要区分这个引用是强引用(依赖）还是弱引用，
 #[derive(Serialize,Deserialize,Clone)]
 pub struct FileObject {
     pub name:String,
     pub size:u64,
-    pub content:String,//chunkid
+    pub content:String,//chunkid,分块文件是chunk_list对应的mtree obj id
     #[serde(default)]
     #[serde(skip_serializing_if = "is_default")]
     pub exp:u64,
@@ -27,6 +35,12 @@ pub struct FileObject {
     pub chunk_list:Option<HashMap<String,Vec<String>>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub links:Option<Vec<LinkData>>,
+    //CDC分块文件每个chunk的大小,和chunk_list中FILE_CHUNK_LIST_CDC的chunk一一对应
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_sizes:Option<Vec<u64>>,
+    //分块文件的mtree完整编码保存为一个命名对象,这里是它的obj id,下载方可以直接用obj id校验
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtree:Option<String>,
     #[serde(flatten)]
     pub extra_info: HashMap<String, Value>,
 }
@@ -34,13 +48,57 @@ pub struct FileObject {
 impl FileObject {
     pub fn new(name:String,size:u64,content:String)->Self {
         Self {name,size,content,meta:None,mime:None,owner:None,exp:0,
-            create_time:None,chunk_list:None,links:None,extra_info:HashMap::new()}
+            create_time:None,chunk_list:None,links:None,chunk_sizes:None,mtree:None,extra_info:HashMap::new()}
     }
 
     pub fn gen_obj_id(&self)->(ObjId, String) {
         let json_value = serde_json::to_value(self).unwrap();
         build_named_object_by_json(OBJ_TYPE_FILE, &json_value)
     }
+
+    //分块文件:content是mtree obj id,chunk_list中有定长分块的chunk列表
+    pub fn is_chunked(&self)->bool {
+        self.content.starts_with(OBJ_TYPE_MTREE) && self.chunk_list.as_ref()
+            .map(|chunk_list| chunk_list.contains_key(FILE_CHUNK_LIST_FIXED))
+            .unwrap_or(false)
+    }
+
+    pub fn get_mtree_body_obj_id(&self)->NdnResult<ObjId> {
+        let mtree = self.mtree.as_ref()
+            .ok_or_else(|| NdnError::InvalidData(format!("fileobj {} has no mtree",self.name)))?;
+        ObjId::new(mtree)
+    }
+
+    pub fn get_fixed_chunk_list(&self)->NdnResult<Vec<ChunkId>> {
+        let chunk_list = self.chunk_list.as_ref()
+            .and_then(|chunk_list| chunk_list.get(FILE_CHUNK_LIST_FIXED))
+            .ok_or_else(|| NdnError::InvalidData(format!("fileobj {} has no fixed chunk list",self.name)))?;
+        chunk_list.iter().map(|chunk_id| ChunkId::new(chunk_id)).collect()
+    }
+
+    //CDC分块文件:content是整个文件的chunkid(不保存),chunk_list中有按内容分块的chunk列表和每个chunk的大小
+    pub fn is_cdc_chunked(&self)->bool {
+        self.chunk_list.as_ref()
+            .map(|chunk_list| chunk_list.contains_key(FILE_CHUNK_LIST_CDC))
+            .unwrap_or(false)
+    }
+
+    pub fn get_cdc_chunk_list(&self)->NdnResult<Vec<(ChunkId,u64)>> {
+        let chunk_ids = self.chunk_list.as_ref()
+            .and_then(|chunk_list| chunk_list.get(FILE_CHUNK_LIST_CDC))
+            .ok_or_else(|| NdnError::InvalidData(format!("fileobj {} has no cdc chunk list",self.name)))?;
+        let chunk_sizes = self.chunk_sizes.as_ref()
+            .ok_or_else(|| NdnError::InvalidData(format!("fileobj {} has no cdc chunk size list",self.name)))?;
+        if chunk_ids.len() != chunk_sizes.len() {
+            return Err(NdnError::InvalidData(format!("fileobj {} cdc chunk list size not match",self.name)));
+        }
+
+        let mut result = Vec::with_capacity(chunk_ids.len());
+        for (chunk_id,chunk_size) in chunk_ids.iter().zip(chunk_sizes.iter()) {
+            result.push((ChunkId::new(chunk_id)?,*chunk_size));
+        }
+        Ok(result)
+    }
 }
 
 #[derive(Serialize,Deserialize,Clone,Eq,PartialEq)]
@@ -0,0 +1,157 @@
+// NamedDataStore的GC
+// 以NamedDataMgrDB中的paths为根,沿着 对象内容 -> 引用的对象/chunk -> object_links 计算可达集合,
+// 然后清理每个NamedDataStore中不可达的chunk_items/objects/object_links(以及chunk文件)
+use std::collections::HashMap;
+use log::*;
+use serde::{Serialize,Deserialize};
+
+use crate::{FileObject, LinkData, NdnError, NdnResult, ObjId, ObjectMapBody, OBJ_TYPE_FILE, OBJ_TYPE_OBJMAPT};
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct GcOptions {
+    //只统计不删除
+    pub dry_run:bool,
+    //没有被引用的chunk/object在最后一次写入后的保护时间(秒),
+    //避免删除刚上传但还没有被path或FileObject引用的数据
+    pub grace_period:u64,
+    //额外的根,用于不保存在store里的对象(比如还在内存中的ObjectMap的entries)
+    #[serde(default)]
+    pub extra_roots:Vec<ObjId>,
+}
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            grace_period: 3600 * 24,
+            extra_roots: vec![],
+        }
+    }
+}
+
+#[derive(Debug,Clone,Default,Serialize,Deserialize)]
+pub struct GcReport {
+    pub dry_run:bool,
+    pub reachable_count:u64,
+    pub removed_chunks:Vec<String>,
+    pub removed_objects:Vec<String>,
+    pub removed_links:Vec<String>,
+    //删除的chunk的总大小
+    pub freed_bytes:u64,
+    //因为grace period而保留的chunk/object数量
+    pub skipped_recent:u64,
+}
+
+//引用计数表,key是obj_id的字符串形式,value是被引用的次数(path也算一次引用)
+pub type GcRefCounter = HashMap<String,u64>;
+
+pub(crate) fn add_obj_ref(ref_counter:&mut GcRefCounter, obj_id:&ObjId) {
+    let counter = ref_counter.entry(obj_id.to_string()).or_insert(0);
+    *counter += 1;
+}
+
+//得到一个对象直接引用的所有对象/chunk
+//已知类型的对象解码失败时返回错误,GC必须中止,否则这个对象引用的数据都会被当作不可达而删除
+pub(crate) fn collect_obj_refs(obj_type:&str, obj_json:&serde_json::Value) -> NdnResult<Vec<ObjId>> {
+    let mut refs = Vec::new();
+    match obj_type {
+        OBJ_TYPE_FILE => {
+            let file_obj = serde_json::from_value::<FileObject>(obj_json.clone())
+                .map_err(|e| NdnError::DecodeError(format!("gc: decode fileobj failed! {}", e)))?;
+            if !file_obj.content.is_empty() {
+                refs.push(ObjId::new(&file_obj.content)?);
+            }
+            if let Some(chunk_list) = file_obj.chunk_list.as_ref() {
+                for chunk_id_list in chunk_list.values() {
+                    for chunk_id in chunk_id_list {
+                        refs.push(ObjId::new(chunk_id)?);
+                    }
+                }
+            }
+            if let Some(mtree) = file_obj.mtree.as_ref() {
+                refs.push(ObjId::new(mtree)?);
+            }
+            if let Some(links) = file_obj.links.as_ref() {
+                for link in links {
+                    refs.push(get_link_target(link));
+                }
+            }
+        },
+        //ObjectMap的子对象保存在body的items里
+        OBJ_TYPE_OBJMAPT => {
+            let body = ObjectMapBody::decode(obj_json)?;
+            for item in body.items {
+                refs.push(item.obj_id);
+            }
+        },
+        //其它对象不知道具体结构,保守的把所有能解析成ObjId的字符串都当作引用
+        _ => scan_obj_refs(obj_json, &mut refs),
+    }
+    Ok(refs)
+}
+
+pub(crate) fn get_link_target(link:&LinkData) -> ObjId {
+    match link {
+        LinkData::SameAs(obj_id) => obj_id.clone(),
+        LinkData::PartOf(chunk_id,_) => chunk_id.to_obj_id(),
+    }
+}
+
+fn scan_obj_refs(value:&serde_json::Value, refs:&mut Vec<ObjId>) {
+    match value {
+        serde_json::Value::String(s) => {
+            if !s.contains(':') {
+                return;
+            }
+            if let Ok(obj_id) = ObjId::new(s) {
+                refs.push(obj_id);
+            }
+        },
+        serde_json::Value::Array(arr) => {
+            for item in arr {
+                scan_obj_refs(item, refs);
+            }
+        },
+        serde_json::Value::Object(map) => {
+            for (_, item) in map.iter() {
+                scan_obj_refs(item, refs);
+            }
+        },
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChunkId;
+    use serde_json::json;
+
+    #[test]
+    fn test_collect_obj_refs() {
+        let chunk_id = ChunkId::new("sha256:1234567890abcdef").unwrap();
+        let chunk_id2 = ChunkId::new("sha256:abcdef1234567890").unwrap();
+        let mut file_obj = FileObject::new("test.txt".to_string(), 100, chunk_id.to_string());
+        let mut chunk_list = HashMap::new();
+        chunk_list.insert("sha256".to_string(), vec![chunk_id2.to_string()]);
+        file_obj.chunk_list = Some(chunk_list);
+        let refs = collect_obj_refs(OBJ_TYPE_FILE, &serde_json::to_value(&file_obj).unwrap()).unwrap();
+        assert_eq!(refs.len(), 2);
+        assert!(refs.contains(&chunk_id.to_obj_id()));
+        assert!(refs.contains(&chunk_id2.to_obj_id()));
+
+        let dir_obj = json!({
+            "name": "dir",
+            "items": {
+                "a": chunk_id.to_string(),
+                "b": ["not an id", chunk_id2.to_string()],
+            }
+        });
+        let refs = collect_obj_refs("cydir", &dir_obj).unwrap();
+        assert_eq!(refs.len(), 2);
+
+        //已知类型的对象解码失败不能当作没有引用
+        assert!(collect_obj_refs(OBJ_TYPE_FILE, &json!({"name": "broken"})).is_err());
+        assert!(collect_obj_refs(OBJ_TYPE_OBJMAPT, &json!({"items": []})).is_err());
+    }
+}
@@ -11,6 +11,8 @@ mod fileobj;
 mod mtree;
 mod hash;
 mod object_map;
+mod gc;
+mod cdc;
 
 pub use object::*;
 pub use chunk::*;
@@ -23,6 +25,8 @@ pub use fileobj::*;
 pub use hash::*;
 pub use mtree::*;
 pub use object_map::*;
+pub use gc::*;
+pub use cdc::*;
 
 use thiserror::Error;
 
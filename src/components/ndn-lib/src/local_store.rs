@@ -1,7 +1,7 @@
 use std::pin::Pin;
 // 每个Local Chunk Store基于一个目录独立存在
 // Chunk Manage由多个Local Chunk Store组成(目前版本先搞定单OOD)
-use std::{collections::HashMap, io::SeekFrom};
+use std::{collections::{HashMap, HashSet}, io::SeekFrom};
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde_json::json;
 use tokio::{
@@ -16,7 +16,7 @@ use async_trait::async_trait;
 use tokio::sync::Mutex;
 
 use name_lib::EncodedDocument;
-use crate::{ChunkReader,ChunkWriter,ChunkHasher, ChunkId, LinkData, NdnError, NdnResult, ObjId, ObjectLink};
+use crate::{ChunkReader,ChunkWriter,ChunkHasher, ChunkId, GcOptions, GcReport, LinkData, NdnError, NdnResult, ObjId, ObjectLink, MAX_CHUNK_SIZE};
 
 pub enum ObjectState {
     Exist,
@@ -235,6 +235,41 @@ impl NamedDataDb {
         Ok(chunk)
     }
 
+    //一次加锁查询多个chunk,不存在的返回None
+    async fn get_chunk_list(&self, chunk_list: &[ChunkId]) -> NdnResult<Vec<Option<ChunkItem>>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT chunk_size, chunk_state, progress, description, create_time, update_time FROM chunk_items WHERE chunk_id = ?1"
+        ).map_err(|e| {
+            warn!("NamedDataDb: get_chunk_list failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        let mut result = Vec::with_capacity(chunk_list.len());
+        for chunk_id in chunk_list {
+            let chunk = stmt.query_row(params![chunk_id.to_string()], |row| {
+                Ok(ChunkItem {
+                    chunk_id: chunk_id.clone(),
+                    chunk_size: row.get(0)?,
+                    chunk_state: row.get(1)?,
+                    progress: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    description: row.get(3)?,
+                    create_time: row.get(4)?,
+                    update_time: row.get(5)?,
+                })
+            });
+            match chunk {
+                Ok(chunk) => result.push(Some(chunk)),
+                Err(rusqlite::Error::QueryReturnedNoRows) => result.push(None),
+                Err(e) => {
+                    warn!("NamedDataDb: get_chunk_list failed! {}", e.to_string());
+                    return Err(NdnError::DbError(e.to_string()));
+                }
+            }
+        }
+        Ok(result)
+    }
+
     async fn put_chunk_list(&self, chunk_list: Vec<ChunkItem>) -> NdnResult<()> {
         let mut conn = self.conn.lock().await;
         let tx = conn.transaction().map_err(|e| {
@@ -270,11 +305,58 @@ impl NamedDataDb {
         Ok(())
     }
 
+    async fn list_chunk_items(&self) -> NdnResult<Vec<ChunkItem>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT chunk_id, chunk_size, chunk_state, progress, description, create_time, update_time FROM chunk_items"
+        ).map_err(|e| {
+            warn!("NamedDataDb: list chunk items failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u64>(1)?,
+                row.get::<_, ChunkState>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, u64>(5)?,
+                row.get::<_, u64>(6)?,
+            ))
+        }).map_err(|e| {
+            warn!("NamedDataDb: list chunk items failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        let mut chunk_items = Vec::new();
+        for row in rows {
+            let (chunk_id_str, chunk_size, chunk_state, progress, description, create_time, update_time) = row.map_err(|e| {
+                warn!("NamedDataDb: list chunk items failed! {}", e.to_string());
+                NdnError::DbError(e.to_string())
+            })?;
+            chunk_items.push(ChunkItem {
+                chunk_id: ChunkId::new(&chunk_id_str)?,
+                chunk_size,
+                chunk_state,
+                progress: progress.unwrap_or_default(),
+                description,
+                create_time,
+                update_time,
+            });
+        }
+        Ok(chunk_items)
+    }
+
     async fn update_chunk_progress(&self, chunk_id: &ChunkId, progress: String)->NdnResult<()> {
+        let now_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
         let mut conn = self.conn.lock().await;
         conn.execute(
-            "UPDATE chunk_items SET progress = ?1, chunk_state = 'incompleted', update_time = CURRENT_TIMESTAMP WHERE chunk_id = ?2",
-            params![progress, chunk_id.to_string()],
+            "UPDATE chunk_items SET progress = ?1, chunk_state = 'incompleted', update_time = ?2 WHERE chunk_id = ?3",
+            params![progress, now_time, chunk_id.to_string()],
         ).map_err(|e| {
             warn!("ChunkDb: update chunk progress failed! {}", e.to_string());
             NdnError::DbError(e.to_string())
@@ -372,6 +454,55 @@ impl NamedDataDb {
         Ok(())
     }
 
+    //return (obj_id,obj_type,create_time)
+    async fn list_objects(&self) -> NdnResult<Vec<(String,String,u64)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT obj_id, obj_type, create_time FROM objects"
+        ).map_err(|e| {
+            warn!("NamedDataDb: list objects failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, u64>(2)?))
+        }).map_err(|e| {
+            warn!("NamedDataDb: list objects failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        //不完整的列表会让gc误删数据,任何一行读取失败都要返回错误
+        let objs = rows.collect::<Result<Vec<_>, _>>().map_err(|e| {
+            warn!("NamedDataDb: list objects failed! {}", e);
+            NdnError::DbError(e.to_string())
+        })?;
+        Ok(objs)
+    }
+
+    //return (link_obj_id,obj_link)
+    async fn list_object_links(&self) -> NdnResult<Vec<(String,String)>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare(
+            "SELECT link_obj_id, obj_link FROM object_links"
+        ).map_err(|e| {
+            warn!("NamedDataDb: list object links failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        }).map_err(|e| {
+            warn!("NamedDataDb: list object links failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        let links = rows.collect::<Result<Vec<_>, _>>().map_err(|e| {
+            warn!("NamedDataDb: list object links failed! {}", e);
+            NdnError::DbError(e.to_string())
+        })?;
+        Ok(links)
+    }
+
     async fn set_object_link(&self, obj_id: &ObjId, obj_link: &LinkData) -> NdnResult<()> {
         let conn = self.conn.lock().await;
         conn.execute(
@@ -687,9 +818,31 @@ impl NamedDataStore {
     }
 
 
-    //查询多个chunk的状态
+    //查询多个chunk的状态,结果直接填写到chunk_list的chunk_state/chunk_size/progress中
+    //不存在的chunk的状态为NotExist
     pub async fn query_chunk_state_by_list(&self, chunk_list: &mut Vec<ChunkItem>)->NdnResult<()> {
-        unimplemented!()
+        let chunk_ids: Vec<ChunkId> = chunk_list.iter().map(|item| item.chunk_id.clone()).collect();
+        let db_items = self.named_db.get_chunk_list(&chunk_ids).await?;
+        for (chunk_item, db_item) in chunk_list.iter_mut().zip(db_items.into_iter()) {
+            match db_item {
+                Some(db_item) => {
+                    chunk_item.chunk_size = db_item.chunk_size;
+                    chunk_item.chunk_state = db_item.chunk_state;
+                    chunk_item.progress = db_item.progress;
+                    chunk_item.description = db_item.description;
+                    chunk_item.create_time = db_item.create_time;
+                    chunk_item.update_time = db_item.update_time;
+                },
+                None => {
+                    //可能是link
+                    let (chunk_state, chunk_size) = self.query_chunk_by_id(&chunk_item.chunk_id).await?;
+                    chunk_item.chunk_state = chunk_state;
+                    chunk_item.chunk_size = chunk_size;
+                    chunk_item.progress = "".to_string();
+                }
+            }
+        }
+        Ok(())
     }
 
     pub async fn open_chunk_reader(&self, chunk_id: &ChunkId,offset:SeekFrom) -> NdnResult<(ChunkReader,u64)> {
@@ -721,7 +874,39 @@ impl NamedDataStore {
     }
 
     //打开writer并允许writer已经存在
+    //chunk id中带有长度时(mix),chunk_size可以为0,不为0时必须和chunk id一致
+    //mix chunk id中的长度来自远端,超过MAX_CHUNK_SIZE时拒绝,避免按这个长度预分配文件
+    fn check_chunk_size(chunk_id: &ChunkId, chunk_size:u64) -> NdnResult<u64> {
+        let chunk_size = match chunk_id.get_length() {
+            Some(length) if chunk_size == 0 => length,
+            Some(length) if length != chunk_size => {
+                warn!("chunk size {} not match chunk id {}",chunk_size,chunk_id.to_string());
+                return Err(NdnError::InvalidParam(format!("chunk size {} not match chunk id {}",chunk_size,chunk_id.to_string())));
+            },
+            _ => chunk_size,
+        };
+        if chunk_size > MAX_CHUNK_SIZE {
+            warn!("chunk size {} of {} exceeds max chunk size",chunk_size,chunk_id.to_string());
+            return Err(NdnError::InvalidParam(format!("chunk size {} of {} exceeds max chunk size {}",chunk_size,chunk_id.to_string(),MAX_CHUNK_SIZE)));
+        }
+        Ok(chunk_size)
+    }
+
+    async fn preallocate_chunk_file(chunk_id: &ChunkId, file: &File) -> NdnResult<()> {
+        if let Some(length) = chunk_id.get_length() {
+            if length > MAX_CHUNK_SIZE {
+                return Err(NdnError::InvalidParam(format!("chunk size {} of {} exceeds max chunk size {}",length,chunk_id.to_string(),MAX_CHUNK_SIZE)));
+            }
+            file.set_len(length).await.map_err(|e| {
+                warn!("preallocate chunk file failed! {}", e);
+                NdnError::IoError(e.to_string())
+            })?;
+        }
+        Ok(())
+    }
+
     pub async fn open_chunk_writer(&self, chunk_id: &ChunkId,chunk_size:u64,offset:u64) -> NdnResult<(ChunkWriter,String)> {
+        let chunk_size = Self::check_chunk_size(chunk_id, chunk_size)?;
         let chunk_item = self.named_db.get_chunk(chunk_id).await;
         let chunk_path = self.get_chunk_path(chunk_id);
         if chunk_item.is_ok() {
@@ -747,7 +932,9 @@ impl NamedDataStore {
                         NdnError::IoError(e.to_string())
                     })?;
 
-                if offset != 0 {
+                //预分配过空间的chunk,文件长度不代表已经写入的长度
+                let is_preallocated = chunk_id.get_length().is_some();
+                if offset != 0 || is_preallocated {
                     file.seek(SeekFrom::Start(offset)).await.map_err(|e| {
                         warn!("open_chunk_writer: seek file failed! {}", e.to_string());
                         NdnError::IoError(e.to_string())
@@ -760,8 +947,9 @@ impl NamedDataStore {
                 }
 
                 if chunk_item.progress.len() < 2{
+                    let pos = if is_preallocated { offset } else { file_meta.len() };
                     let progress = json!({
-                        "pos":file_meta.len(),
+                        "pos":pos,
                     }).to_string();
                     return Ok((Box::pin(file),progress));
                 }
@@ -788,6 +976,7 @@ impl NamedDataStore {
                 warn!("open_chunk_writer: create file failed! {}", e.to_string());
                 NdnError::IoError(e.to_string())
             })?;
+            Self::preallocate_chunk_file(chunk_id, &file).await?;
 
             //创建chunk_item
             let chunk_item = ChunkItem::new(&chunk_id, chunk_size, None);
@@ -799,6 +988,7 @@ impl NamedDataStore {
     }
     //打开writer,不允许writer已经存在
     pub async fn open_new_chunk_writer(&self, chunk_id: &ChunkId,chunk_size:u64) -> NdnResult<ChunkWriter> {
+        let chunk_size = Self::check_chunk_size(chunk_id, chunk_size)?;
         let chunk_item = self.named_db.get_chunk(chunk_id).await;
         if chunk_item.is_ok() {
             return Err(NdnError::AlreadyExists(format!("chunk already exists! {}",chunk_id.to_string())));
@@ -818,6 +1008,7 @@ impl NamedDataStore {
             warn!("open_chunk_writer: create file failed! {}", e.to_string());
             NdnError::IoError(e.to_string())
         })?;
+        Self::preallocate_chunk_file(chunk_id, &file).await?;
 
         let chunk_item = ChunkItem::new(chunk_id, chunk_size, None);
         self.named_db.set_chunk_item(&chunk_item).await?;
@@ -842,6 +1033,45 @@ impl NamedDataStore {
         Ok(())
     }
 
+    //用临时chunk_id写入的数据(写完才知道真正的chunk_id),在写入完成后转成正式的chunk
+    pub async fn complete_chunk_writer_as(&self, tmp_chunk_id: &ChunkId, chunk_id: &ChunkId)->NdnResult<()> {
+        let tmp_chunk_item = self.named_db.get_chunk(tmp_chunk_id).await.map_err(|_| {
+            NdnError::NotFound(format!("chunk not found! {}",tmp_chunk_id.to_string()))
+        })?;
+
+        let (is_exist,_) = self.is_chunk_exist(chunk_id, None).await?;
+        if is_exist {
+            info!("complete_chunk_writer_as: chunk {} already exists, remove tmp chunk {}",chunk_id.to_string(),tmp_chunk_id.to_string());
+            return self.remove_chunk_data(vec![tmp_chunk_id.clone()]).await;
+        }
+
+        let tmp_chunk_path = self.get_chunk_path(tmp_chunk_id);
+        let chunk_path = self.get_chunk_path(chunk_id);
+        if let Some(parent) = std::path::Path::new(&chunk_path).parent() {
+            fs::create_dir_all(parent).await
+                .map_err(|e| {
+                    warn!("complete_chunk_writer_as: create dir failed! {}",e);
+                    NdnError::IoError(e.to_string())
+                })?;
+        }
+        fs::rename(&tmp_chunk_path, &chunk_path).await.map_err(|e| {
+            warn!("complete_chunk_writer_as: rename chunk file failed! {}", e);
+            NdnError::IoError(e.to_string())
+        })?;
+
+        let chunk_item = ChunkItem::new_completed(chunk_id, tmp_chunk_item.chunk_size, None);
+        self.named_db.set_chunk_item(&chunk_item).await?;
+        self.named_db.remove_chunk(tmp_chunk_id).await?;
+        info!("complete_chunk_writer_as: complete chunk {} (tmp:{}) success itemsize:{}",
+            chunk_id.to_string(),tmp_chunk_id.to_string(),chunk_item.chunk_size);
+        Ok(())
+    }
+
+    //删除chunk的数据和记录(包括未完成的chunk)
+    pub async fn remove_chunk(&self, chunk_id: &ChunkId)->NdnResult<()> {
+        self.remove_chunk_data(vec![chunk_id.clone()]).await
+    }
+
     //删除chunkid对应的文件,注意一定会带来文件的删除
     async fn remove_chunk_data(&self, chunk_list: Vec<ChunkId>)->NdnResult<()> {
         for chunk_id in chunk_list {
@@ -857,6 +1087,90 @@ impl NamedDataStore {
         Ok(())
     }
 
+    //清理不在reachable集合中的chunk/object/link,reachable由NamedDataMgr在mark阶段计算
+    pub async fn gc_sweep(&self, reachable: &HashSet<String>, options: &GcOptions, report: &mut GcReport)->NdnResult<()> {
+        if self.read_only {
+            warn!("gc_sweep: store {} is read only, skip", self.base_dir);
+            return Ok(());
+        }
+
+        let now_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let grace_period = options.grace_period * 1000;
+
+        let chunk_items = self.named_db.list_chunk_items().await?;
+        let mut will_remove_chunks = Vec::new();
+        for chunk_item in chunk_items {
+            let chunk_id_str = chunk_item.chunk_id.to_string();
+            if reachable.contains(&chunk_id_str) {
+                continue;
+            }
+            //刚写入的chunk(包括已完成的)可能还没来得及被path或FileObject引用
+            if now_time < chunk_item.update_time + grace_period {
+                debug!("gc_sweep: skip recent chunk {}", chunk_id_str);
+                report.skipped_recent += 1;
+                continue;
+            }
+
+            report.freed_bytes += chunk_item.chunk_size;
+            report.removed_chunks.push(chunk_id_str);
+            will_remove_chunks.push(chunk_item.chunk_id);
+        }
+
+        let objs = self.named_db.list_objects().await?;
+        let mut will_remove_objs = Vec::new();
+        for (obj_id_str, _obj_type, create_time) in objs {
+            if reachable.contains(&obj_id_str) {
+                continue;
+            }
+            if now_time < create_time + grace_period {
+                debug!("gc_sweep: skip recent object {}", obj_id_str);
+                report.skipped_recent += 1;
+                continue;
+            }
+            //一行坏数据不能让整个sweep中止
+            let obj_id = ObjId::new(&obj_id_str);
+            if obj_id.is_err() {
+                warn!("gc_sweep: invalid obj_id {} in objects, skip", obj_id_str);
+                continue;
+            }
+            report.removed_objects.push(obj_id_str);
+            will_remove_objs.push(obj_id.unwrap());
+        }
+
+        let links = self.named_db.list_object_links().await?;
+        let mut will_remove_links = Vec::new();
+        for (link_obj_id_str, _obj_link) in links {
+            if reachable.contains(&link_obj_id_str) {
+                continue;
+            }
+            let link_obj_id = ObjId::new(&link_obj_id_str);
+            if link_obj_id.is_err() {
+                warn!("gc_sweep: invalid obj_id {} in object_links, skip", link_obj_id_str);
+                continue;
+            }
+            report.removed_links.push(link_obj_id_str);
+            will_remove_links.push(link_obj_id.unwrap());
+        }
+
+        if options.dry_run {
+            return Ok(());
+        }
+
+        info!("gc_sweep: store {} remove {} chunks, {} objects, {} links",
+            self.base_dir, will_remove_chunks.len(), will_remove_objs.len(), will_remove_links.len());
+        self.remove_chunk_data(will_remove_chunks).await?;
+        for obj_id in will_remove_objs.iter() {
+            self.named_db.remove_object(obj_id).await?;
+        }
+        for link_obj_id in will_remove_links.iter() {
+            self.named_db.remove_object_link(link_obj_id).await?;
+        }
+        Ok(())
+    }
+
     //=====================下面的都是helper函数了======================
     //针对小于1MB的 chunk,推荐直接返回内存
     pub async fn get_chunk_data(&self, chunk_id: &ChunkId)->NdnResult<Vec<u8>> {
@@ -880,15 +1194,58 @@ impl NamedDataStore {
     }
 
     //一口气写入一组chunk(通常是小chunk)
+    //已经完成的chunk会被跳过,所有chunk的数据写入完成后,在一个事务中更新db
     pub async fn put_chunklist(&self, chunk_list: HashMap<ChunkId, Vec<u8>>,need_verify: bool)->NdnResult<()> {
-        unimplemented!()
+        if need_verify {
+            for (chunk_id, chunk_data) in chunk_list.iter() {
+                if !chunk_id.verify_chunk_data(chunk_data)? {
+                    warn!("put_chunklist: chunk_id not equal hash_bytes! {}",chunk_id.to_string());
+                    return Err(NdnError::InvalidId(format!("chunk_id not equal hash_bytes! {}",chunk_id.to_string())));
+                }
+            }
+        }
+
+        let chunk_ids: Vec<ChunkId> = chunk_list.keys().cloned().collect();
+        let db_items = self.named_db.get_chunk_list(&chunk_ids).await?;
+        let mut chunk_items = Vec::new();
+        for (chunk_id, db_item) in chunk_ids.iter().zip(db_items.into_iter()) {
+            if let Some(db_item) = db_item {
+                if db_item.chunk_state == ChunkState::Completed {
+                    debug!("put_chunklist: chunk {} already completed, skip",chunk_id.to_string());
+                    continue;
+                }
+            }
+
+            let chunk_data = chunk_list.get(chunk_id).unwrap();
+            let chunk_path = self.get_chunk_path(chunk_id);
+            if let Some(parent) = std::path::Path::new(&chunk_path).parent() {
+                fs::create_dir_all(parent).await
+                    .map_err(|e| {
+                        warn!("put_chunklist: create dir failed! {}",e.to_string());
+                        NdnError::IoError(e.to_string())
+                    })?;
+            }
+            //先写临时文件再rename,中途失败不会留下一个内容不完整的chunk文件
+            let tmp_chunk_path = format!("{}.tmp", chunk_path);
+            fs::write(&tmp_chunk_path, chunk_data).await.map_err(|e| {
+                warn!("put_chunklist: write file failed! {}", e);
+                NdnError::IoError(e.to_string())
+            })?;
+            if let Err(e) = fs::rename(&tmp_chunk_path, &chunk_path).await {
+                warn!("put_chunklist: rename chunk file failed! {}", e);
+                let _ = fs::remove_file(&tmp_chunk_path).await;
+                return Err(NdnError::IoError(e.to_string()));
+            }
+            chunk_items.push(ChunkItem::new_completed(chunk_id, chunk_data.len() as u64, None));
+        }
+
+        info!("put_chunklist: put {} chunks, skip {} completed chunks", chunk_items.len(), chunk_ids.len() - chunk_items.len());
+        self.named_db.put_chunk_list(chunk_items).await
     }
     //写入一个在内存中的完整的chunk
     pub async fn put_chunk(&self, chunk_id: &ChunkId, chunk_data: &[u8],need_verify: bool)->NdnResult<()> {
         if need_verify {
-            let mut chunk_hasher = ChunkHasher::new(Some(chunk_id.hash_type.as_str()))?;
-            let hash_bytes = chunk_hasher.calc_from_bytes(&chunk_data);
-            if !chunk_id.verify_chunk(&hash_bytes) {
+            if !chunk_id.verify_chunk_data(chunk_data)? {
                 warn!("put_chunk: chunk_id not equal hash_bytes! {}",chunk_id.to_string());
                 return Err(NdnError::InvalidId(format!("chunk_id not equal hash_bytes! {}",chunk_id.to_string())));
             }
@@ -980,6 +1337,77 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_mix_chunk_writer_preallocate() -> NdnResult<()> {
+        let temp_dir = tempdir().unwrap();
+        let store = NamedDataStore::new(temp_dir.path().to_str().unwrap().to_string()).await?;
+        let data = vec![7u8; 64*1024 + 3];
+        let mut chunk_hasher = ChunkHasher::new(Some(crate::MIX256_HASH_TYPE)).unwrap();
+        let chunk_id = chunk_hasher.calc_chunk_id_from_bytes(&data);
+
+        assert!(store.open_chunk_writer(&chunk_id, 100, 0).await.is_err());
+        //chunk_size为0时使用chunk id中的长度
+        let (mut writer, _) = store.open_chunk_writer(&chunk_id, 0, 0).await?;
+        writer.write_all(&data[..1000]).await.unwrap();
+        writer.flush().await.unwrap();
+        drop(writer);
+        let file_meta = fs::metadata(store.get_chunk_path(&chunk_id)).await.unwrap();
+        assert_eq!(file_meta.len(), data.len() as u64);
+
+        //续传的位置由offset决定,和预分配后的文件长度无关
+        let (mut writer, progress) = store.open_chunk_writer(&chunk_id, 0, 1000).await?;
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&progress).unwrap()["pos"], 1000);
+        writer.write_all(&data[1000..]).await.unwrap();
+        writer.flush().await.unwrap();
+        drop(writer);
+        store.complete_chunk_writer(&chunk_id).await?;
+
+        let (mut reader, chunk_size) = store.open_chunk_reader(&chunk_id, SeekFrom::Start(0)).await?;
+        assert_eq!(chunk_size, data.len() as u64);
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, data);
+
+        //chunk id中的长度超过MAX_CHUNK_SIZE时不会创建文件
+        let huge_chunk_id = ChunkId::from_mix256_result(MAX_CHUNK_SIZE + 1, &[1u8; 32]);
+        assert!(store.open_chunk_writer(&huge_chunk_id, 0, 0).await.is_err());
+        assert!(fs::metadata(store.get_chunk_path(&huge_chunk_id)).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_put_chunklist_and_query_state() -> NdnResult<()> {
+        let temp_dir = tempdir().unwrap();
+        let store = NamedDataStore::new(temp_dir.path().to_str().unwrap().to_string()).await?;
+        let mut chunk_list = HashMap::new();
+        for i in 0..8u8 {
+            let data = vec![i; 100 + i as usize];
+            let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+            let chunk_id = ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(&data));
+            chunk_list.insert(chunk_id, data);
+        }
+        let chunk_ids: Vec<ChunkId> = chunk_list.keys().cloned().collect();
+        store.put_chunklist(chunk_list.clone(), true).await?;
+        //重复写入会跳过已经完成的chunk
+        store.put_chunklist(chunk_list.clone(), true).await?;
+
+        let mut bad_chunk_list = HashMap::new();
+        bad_chunk_list.insert(ChunkId::new("sha256:1234567890abcdef").unwrap(), b"bad data".to_vec());
+        assert!(store.put_chunklist(bad_chunk_list, true).await.is_err());
+
+        let not_exist_chunk_id = ChunkId::new("sha256:abcdef1234567890").unwrap();
+        let mut query_list: Vec<ChunkItem> = chunk_ids.iter().map(|chunk_id| ChunkItem::new(chunk_id, 0, None)).collect();
+        query_list.push(ChunkItem::new(&not_exist_chunk_id, 0, None));
+        store.query_chunk_state_by_list(&mut query_list).await?;
+        for chunk_item in query_list.iter().take(chunk_ids.len()) {
+            assert_eq!(chunk_item.chunk_state, ChunkState::Completed);
+            assert_eq!(chunk_item.chunk_size, chunk_list.get(&chunk_item.chunk_id).unwrap().len() as u64);
+            assert_eq!(store.get_chunk_data(&chunk_item.chunk_id).await?, *chunk_list.get(&chunk_item.chunk_id).unwrap());
+        }
+        assert_eq!(query_list.last().unwrap().chunk_state, ChunkState::NotExist);
+        Ok(())
+    }
+
     //测试 open_chunk_writer
     #[tokio::test]
     async fn test_open_chunk_writer() -> NdnResult<()> {
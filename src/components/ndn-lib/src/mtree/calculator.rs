@@ -134,6 +134,36 @@ impl SerializeHashCalculator {
         Ok(ret)
     }
 
+    // Get the hash of the node by depth and index in the depth, the reader must be set
+    pub async fn get_node_hash(&mut self, depth: u32, index: u64) -> NdnResult<Vec<u8>> {
+        assert!(self.reader.is_some());
+
+        let counts = HashNodeLocator::calc_count_per_depth(self.leaf_count);
+        if depth > self.locator.total_depth() || index >= counts[depth as usize] {
+            let msg = format!("Node out of range: depth={}, index={}", depth, index);
+            error!("{}", msg);
+            return Err(NdnError::InvalidParam(msg));
+        }
+
+        let reader = self.reader.as_mut().unwrap();
+        let hash_bytes = self.hash_method.hash_bytes();
+        let pos = self.locator.calc_index_in_stream(depth, index) * hash_bytes as u64;
+        reader.seek(SeekFrom::Start(pos)).await.map_err(|e| {
+            let msg = format!("Error seeking to position {}: {}", pos, e);
+            error!("{}", msg);
+            NdnError::IoError(msg)
+        })?;
+
+        let mut hash = vec![0u8; hash_bytes];
+        reader.read_exact(&mut hash).await.map_err(|e| {
+            let msg = format!("Error reading hash: {}", e);
+            error!("{}", msg);
+            NdnError::IoError(msg)
+        })?;
+
+        Ok(hash)
+    }
+
     // Load all leaf hashed from reader, then append the leaf hashes to the writer or verifier
     pub async fn load_leaf_hashes_from_reader(&mut self) -> NdnResult<Vec<u8>> {
         assert!(self.reader.is_some());
@@ -10,6 +10,7 @@ use core::{error, hash};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::VecDeque;
+use std::collections::BTreeMap;
 use std::collections::{HashMap, HashSet};
 use std::io::{Read, Seek, SeekFrom};
 use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
@@ -85,6 +86,16 @@ impl MerkleTreeObject {
             .await
     }
 
+    // Get the hash of the node at depth (from bottom to top, leaf is 0) and index in the depth
+    pub async fn get_node_hash(&mut self, depth: u32, index: u64) -> NdnResult<Vec<u8>> {
+        self.calculator.get_node_hash(depth, index).await
+    }
+
+    // The depth of the root node, the leaf depth is 0
+    pub fn get_total_depth(&self) -> u32 {
+        HashNodeLocator::calc_depth(self.meta.leaf_count())
+    }
+
     pub fn get_hash_method(&self) -> HashMethod {
         self.meta.hash_method
     }
@@ -108,6 +119,192 @@ impl MerkleTreeObject {
     pub fn get_obj_id(&self) -> ObjId {
         return ObjId::new_by_raw(OBJ_TYPE_MTREE.to_string(), self.root_hash.clone());
     }
+
+    // Load from the whole mtree body in memory (meta data + nodes)
+    pub async fn load_from_body(body: Vec<u8>, verify: bool) -> NdnResult<Self> {
+        let stream = MtreeReadWriteSeekWithSharedBuffer::new(SharedBuffer::from_data(body));
+        let reader = Box::new(stream) as Box<dyn MtreeReadSeek>;
+        Self::load_from_reader(reader, verify).await
+    }
+
+    // Build the whole mtree body in memory from the leaf hashes, return (root_hash, body)
+    pub async fn build_body_from_leaf_hashes(
+        data_size: u64,
+        leaf_size: u64,
+        hash_method: Option<HashMethod>,
+        leaf_hashes: &Vec<Vec<u8>>,
+    ) -> NdnResult<(Vec<u8>, Vec<u8>)> {
+        let total =
+            MerkleTreeObjectGenerator::estimate_output_bytes(data_size, leaf_size, hash_method);
+        let stream = MtreeReadWriteSeekWithSharedBuffer::new(SharedBuffer::with_size(total as usize));
+        let writer = Box::new(stream.clone()) as Box<dyn MtreeWriteSeek>;
+
+        let mut gen =
+            MerkleTreeObjectGenerator::new(data_size, leaf_size, hash_method, writer).await?;
+        gen.append_leaf_hashes(leaf_hashes).await?;
+        let root_hash = gen.finalize().await?;
+
+        let buffer = stream.buffer();
+        let body = buffer.lock().unwrap().data().clone();
+        Ok((root_hash, body))
+    }
+
+    // Verify the hash of the leaf with the proof path, the proof path must end with the root hash of this mtree
+    pub async fn verify_leaf_hash(&mut self, leaf_index: u64, leaf_hash: &[u8]) -> NdnResult<bool> {
+        let mut proof = self.get_proof_path_by_leaf_index(leaf_index).await?;
+        if proof.last().map(|item| &item.1) != Some(&self.root_hash) {
+            let msg = format!("Proof path root hash not match: {}", leaf_index);
+            warn!("{}", msg);
+            return Ok(false);
+        }
+
+        proof[0].1 = leaf_hash.to_vec();
+        MerkleTreeProofPathVerifier::new(self.meta.hash_method).verify(&proof)
+    }
+
+    // Update the leaf hashes of an existing mtree body in place, only the nodes on the paths
+    // from the changed leaves to the root are recalculated. The leaf count must not change.
+    // Return the new root hash.
+    pub async fn update_leaf_hashes(
+        mut body_reader: Box<dyn MtreeReadSeek>,
+        body_writer: Box<dyn MtreeWriteSeek>,
+        leaf_hashes: &BTreeMap<u64, Vec<u8>>,
+    ) -> NdnResult<Vec<u8>> {
+        let (meta, len) = MerkleTreeMetaData::read(&mut body_reader).await?;
+        let mut reader = MtreeReadSeekWithOffset::new(body_reader, len as u64);
+        let mut writer = MtreeWriteSeekWithOffset::new(body_writer, len as u64);
+
+        let leaf_count = meta.leaf_count();
+        let hash_bytes = meta.hash_method.hash_bytes();
+        if leaf_hashes.is_empty() {
+            let msg = "No leaf hash to update".to_string();
+            error!("{}", msg);
+            return Err(NdnError::InvalidParam(msg));
+        }
+        for (index, hash) in leaf_hashes.iter() {
+            if *index >= leaf_count || hash.len() != hash_bytes {
+                let msg = format!("Invalid leaf hash to update: {}, leaf count {}", index, leaf_count);
+                error!("{}", msg);
+                return Err(NdnError::InvalidParam(msg));
+            }
+        }
+
+        let locator = HashNodeLocator::new(leaf_count);
+        let total_depth = locator.total_depth();
+
+        // The nodes count of each depth without the padding node, if the count is odd
+        // the last node is copied once more to make a pair (except the root)
+        let padded_counts = HashNodeLocator::calc_count_per_depth(leaf_count);
+        let mut counts = vec![leaf_count];
+        counts.extend(
+            padded_counts
+                .iter()
+                .take(total_depth as usize)
+                .map(|count| count / 2),
+        );
+
+        let mut current = leaf_hashes.clone();
+        for depth in 0..=total_depth {
+            let count = counts[depth as usize];
+            for (index, hash) in current.iter() {
+                let pos = locator.calc_index_in_stream(depth, *index) * hash_bytes as u64;
+                Self::write_node_hash(&mut writer, pos, hash).await?;
+                if depth < total_depth && count % 2 != 0 && *index == count - 1 {
+                    Self::write_node_hash(&mut writer, pos + hash_bytes as u64, hash).await?;
+                }
+            }
+
+            if depth == total_depth {
+                break;
+            }
+
+            let mut parents = BTreeMap::new();
+            for index in current.keys() {
+                let parent_index = index / 2;
+                if parents.contains_key(&parent_index) {
+                    continue;
+                }
+
+                let mut pair: Vec<Vec<u8>> = Vec::with_capacity(2);
+                for child_index in [parent_index * 2, parent_index * 2 + 1] {
+                    let hash = if let Some(hash) = current.get(&child_index) {
+                        hash.clone()
+                    } else if child_index < count {
+                        let pos = locator.calc_index_in_stream(depth, child_index) * hash_bytes as u64;
+                        Self::read_node_hash(&mut reader, pos, hash_bytes).await?
+                    } else {
+                        // The padding node is the copy of the last node
+                        pair[0].clone()
+                    };
+                    pair.push(hash);
+                }
+
+                let parent_hash = HashHelper::calc_parent_hash(meta.hash_method, &pair[0], &pair[1]);
+                parents.insert(parent_index, parent_hash);
+            }
+            current = parents;
+        }
+
+        writer.flush().await.map_err(|e| {
+            let msg = format!("Error flushing mtree writer: {}", e);
+            error!("{}", msg);
+            NdnError::IoError(msg)
+        })?;
+
+        Ok(current.remove(&0).unwrap())
+    }
+
+    async fn read_node_hash<T: MtreeReadSeek>(reader: &mut T, pos: u64, hash_bytes: usize) -> NdnResult<Vec<u8>> {
+        reader.seek(SeekFrom::Start(pos)).await.map_err(|e| {
+            let msg = format!("Error seeking to position {}: {}", pos, e);
+            error!("{}", msg);
+            NdnError::IoError(msg)
+        })?;
+
+        let mut hash = vec![0u8; hash_bytes];
+        reader.read_exact(&mut hash).await.map_err(|e| {
+            let msg = format!("Error reading hash: {}", e);
+            error!("{}", msg);
+            NdnError::IoError(msg)
+        })?;
+
+        Ok(hash)
+    }
+
+    async fn write_node_hash<T: MtreeWriteSeek>(writer: &mut T, pos: u64, hash: &[u8]) -> NdnResult<()> {
+        writer.seek(SeekFrom::Start(pos)).await.map_err(|e| {
+            let msg = format!("Error seeking to position {}: {}", pos, e);
+            error!("{}", msg);
+            NdnError::IoError(msg)
+        })?;
+
+        writer.write_all(hash).await.map_err(|e| {
+            let msg = format!("Error writing hash: {}", e);
+            error!("{}", msg);
+            NdnError::IoError(msg)
+        })
+    }
+
+    // The mtree body is saved as a named object in json format: {"body": hex string}
+    pub fn encode_body_to_obj_json(body: &[u8]) -> serde_json::Value {
+        serde_json::json!({
+            "body": hex::encode(body),
+        })
+    }
+
+    pub fn decode_body_from_obj_json(obj_json: &serde_json::Value) -> NdnResult<Vec<u8>> {
+        let body = obj_json["body"].as_str().ok_or_else(|| {
+            let msg = "Invalid mtree object: no body".to_string();
+            error!("{}", msg);
+            NdnError::InvalidData(msg)
+        })?;
+
+        hex::decode(body).map_err(|e| {
+            let msg = format!("Invalid mtree object body: {}", e);
+            error!("{}", msg);
+            NdnError::InvalidData(msg)
+        })
+    }
 }
 
 pub struct MerkleTreeProofPathVerifier {
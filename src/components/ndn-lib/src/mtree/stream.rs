@@ -2,14 +2,14 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf, Result, SeekFrom};
 
-pub trait MtreeReadSeek: AsyncRead + AsyncSeek + Unpin {}
+pub trait MtreeReadSeek: AsyncRead + AsyncSeek + Unpin + Send {}
 
 // Blanket implementation for any type that implements both traits
-impl<T: AsyncRead + AsyncSeek + Unpin> MtreeReadSeek for T {}
+impl<T: AsyncRead + AsyncSeek + Unpin + Send> MtreeReadSeek for T {}
 
-pub trait MtreeWriteSeek: AsyncWrite + AsyncSeek + Unpin {}
+pub trait MtreeWriteSeek: AsyncWrite + AsyncSeek + Unpin + Send {}
 // Blanket implementation for any type that implements both traits
-impl<T: AsyncWrite + AsyncSeek + Unpin> MtreeWriteSeek for T {}
+impl<T: AsyncWrite + AsyncSeek + Unpin + Send> MtreeWriteSeek for T {}
 
 // Use this struct to wrap a MtreeReadSeek and add an offset to the read position
 pub struct MtreeReadSeekWithOffset<T: MtreeReadSeek> {
@@ -119,6 +119,14 @@ impl SharedBuffer {
             pos: 0,
         }
     }
+
+    pub fn from_data(data: Vec<u8>) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    pub fn data(&self) -> &Vec<u8> {
+        &self.data
+    }
 }
 
 #[derive(Clone)]
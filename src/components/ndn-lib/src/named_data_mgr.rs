@@ -1,5 +1,5 @@
 use buckyos_kit::{buckyos_get_unix_timestamp, get_buckyos_root_dir, get_by_json_path, get_relative_path};
-use name_lib::decode_jwt_claim_without_verify;
+use name_lib::{decode_jwt_claim_without_verify, EncodedDocument};
 use serde::{Serialize,Deserialize};
 use serde_json::json;
 //chunk_mgr默认是机器级别的，即多个进程可以共享同一个chunk_mgr
@@ -10,19 +10,22 @@ use tokio::{
     io::{self, AsyncRead,AsyncWrite, AsyncReadExt, AsyncWriteExt, AsyncSeek, AsyncSeekExt}, 
 };
 use log::*;
-use crate::{build_named_object_by_json, ChunkHasher, ChunkId, ChunkReadSeek, ChunkState, FileObject, NamedDataStore, NdnError, NdnResult, PathObject};
+use crate::{build_named_object_by_json, ChunkHasher, ChunkId, ChunkItem, ChunkReadSeek, ChunkState, FileObject, NamedDataStore, NdnError, NdnResult, ObjectState, PathObject};
+use crate::{add_obj_ref, collect_obj_refs, get_link_target, GcOptions, GcRefCounter, GcReport};
 use memmap::Mmap;
 use std::{path::PathBuf, pin::Pin};
 use std::io::SeekFrom;
 use std::sync::Mutex;
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use rusqlite::{Connection};
 use lazy_static::lazy_static;
 
 use buckyos_kit::get_buckyos_named_data_dir;
 
-use crate::{ChunkReader,ChunkWriter,ObjId};
+use crate::{ChunkReader,ChunkWriter,ObjId,COPY_CHUNK_BUFFER_SIZE,MAX_CHUNK_SIZE};
+use crate::{HashMethod, MerkleTreeObject, ObjectMap, FILE_CHUNK_LIST_FIXED, OBJ_TYPE_MTREE};
+use crate::{CdcChunkReader, CdcDedupStats, CdcOptions, ContentDefinedChunker, FILE_CHUNK_LIST_CDC};
 
 pub struct NamedDataMgrDB {
     db_path: String,
@@ -282,6 +285,84 @@ impl NamedDataMgrDB {
         Ok(())
     }
 
+    pub fn list_path_obj_ids(&self) -> NdnResult<Vec<ObjId>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT obj_id FROM paths").map_err(|e| {
+            warn!("NamedDataMgrDB: list path obj ids failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        let rows = stmt.query_map([], |row| row.get::<_, String>(0)).map_err(|e| {
+            warn!("NamedDataMgrDB: list path obj ids failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        let mut obj_ids = Vec::new();
+        for obj_id_str in rows.filter_map(Result::ok) {
+            let obj_id = ObjId::new(&obj_id_str);
+            if obj_id.is_err() {
+                warn!("NamedDataMgrDB: invalid obj_id in paths! {}", obj_id_str);
+                continue;
+            }
+            obj_ids.push(obj_id.unwrap());
+        }
+        Ok(obj_ids)
+    }
+
+    //用GC的结果重建objs表的引用计数,不再被引用的obj会从表中删除
+    pub fn update_obj_ref_counts(&self, ref_counter: &GcRefCounter) -> NdnResult<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let now_time = buckyos_get_unix_timestamp();
+        let tx = conn.transaction().map_err(|e| {
+            warn!("NamedDataMgrDB: update obj ref counts failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        tx.execute("UPDATE objs SET ref_count = 0", []).map_err(|e| {
+            warn!("NamedDataMgrDB: update obj ref counts failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        for (obj_id, ref_count) in ref_counter.iter() {
+            tx.execute(
+                "INSERT INTO objs (obj_id, ref_count, access_time) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(obj_id) DO UPDATE SET ref_count = ?2",
+                [obj_id.as_str(), ref_count.to_string().as_str(), now_time.to_string().as_str()],
+            ).map_err(|e| {
+                warn!("NamedDataMgrDB: update obj ref counts failed! {}", e.to_string());
+                NdnError::DbError(e.to_string())
+            })?;
+        }
+
+        tx.execute("DELETE FROM objs WHERE ref_count = 0", []).map_err(|e| {
+            warn!("NamedDataMgrDB: update obj ref counts failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+
+        tx.commit().map_err(|e| {
+            warn!("NamedDataMgrDB: update obj ref counts failed! {}", e.to_string());
+            NdnError::DbError(e.to_string())
+        })?;
+        Ok(())
+    }
+
+    pub fn get_obj_ref_count(&self, obj_id: &ObjId) -> NdnResult<u64> {
+        let conn = self.conn.lock().unwrap();
+        let ref_count = conn.query_row(
+            "SELECT ref_count FROM objs WHERE obj_id = ?1",
+            [obj_id.to_string()],
+            |row| row.get::<_, u64>(0),
+        );
+        match ref_count {
+            Ok(ref_count) => Ok(ref_count),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(0),
+            Err(e) => {
+                warn!("NamedDataMgrDB: get obj ref count failed! {}", e.to_string());
+                Err(NdnError::DbError(e.to_string()))
+            }
+        }
+    }
+
     pub fn set_path_obj_jwt(&self, path: &str, path_obj_jwt: &str) -> NdnResult<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
@@ -297,6 +378,11 @@ impl NamedDataMgrDB {
 
 
 
+//发布本地文件时,写入过程中使用的临时chunk类型
+const PUB_TMP_CHUNK_TYPE: &str = "pubtmp";
+//发布本地文件时,每写入这么多数据保存一次hasher状态
+const PUB_SAVE_HASHER_STATE_INTERVAL: u64 = 64 * 1024 * 1024;
+
 lazy_static! {
     pub static ref NAMED_DATA_MGR_MAP:Arc<tokio::sync::Mutex<HashMap<String,Arc<tokio::sync::Mutex<NamedDataMgr>>>>> = {
         Arc::new(tokio::sync::Mutex::new(HashMap::new()))
@@ -309,6 +395,10 @@ pub struct NamedDataMgrConfig {
     pub local_stores:Vec<String>,
     pub local_cache:Option<String>,
     pub mmap_cache_dir:Option<String>,
+    //设置后pub_local_file_as_fileobj发布的FileObject会带上CDC chunk_list,整个文件的chunk仍然保存,用于ndn_content_path
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pub_cdc_options:Option<CdcOptions>,
 }
 
 pub struct NamedDataMgr {
@@ -317,6 +407,7 @@ pub struct NamedDataMgr {
     mmap_cache_dir:Option<String>,//Cache at memory
     mgr_id:Option<String>,
     db:NamedDataMgrDB,
+    pub_cdc_options:Option<CdcOptions>,
 }
 
 impl NamedDataMgr {
@@ -349,6 +440,7 @@ impl NamedDataMgr {
                 local_stores:vec!["./".to_string()],
                 local_cache:None,
                 mmap_cache_dir:None,
+                pub_cdc_options: None,
             };
 
             let mgr_json_str = serde_json::to_string(&mgr_config).unwrap();
@@ -406,6 +498,7 @@ impl NamedDataMgr {
             mmap_cache_dir:config.mmap_cache_dir,
             mgr_id:mgr_id,
             db:db,
+            pub_cdc_options:config.pub_cdc_options,
         })
     }
 
@@ -524,6 +617,15 @@ impl NamedDataMgr {
         Ok(())
     }
 
+    //把ObjectMap以body的形式保存到store中,obj_id是mtree的root hash,可以用body重新计算校验,GC也可以通过body找到所有子对象
+    pub async fn put_object_map_impl(&self, obj_map:&ObjectMap)->NdnResult<ObjId> {
+        let obj_id = obj_map.gen_obj_id()
+            .ok_or_else(|| NdnError::InvalidState("object map must be flushed before put".to_string()))?;
+        let body = obj_map.gen_body().await?;
+        self.put_object_impl(&obj_id, body.encode().to_string().as_str()).await?;
+        Ok(obj_id)
+    }
+
     pub async fn put_object(mgr_id:Option<&str>, obj_id:&ObjId,obj_data:&str)->NdnResult<()> {
         let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
         if named_mgr.is_none() {
@@ -608,7 +710,7 @@ impl NamedDataMgr {
         info!("remove ndn path:{}", path);
         Ok(())
 
-        //TODO: 这里不立刻删除chunk,而是等统一的GC来删除
+        //这里不立刻删除chunk,而是等统一的GC(gc_impl)来删除
     }
 
     pub async fn remove_file(mgr_id:Option<&str>, path:&str)->NdnResult<()> {
@@ -685,6 +787,63 @@ impl NamedDataMgr {
         named_mgr.query_chunk_state_impl(chunk_id).await
     }
 
+    pub async fn query_chunk_state_by_list_impl(&self, chunk_list: &mut Vec<ChunkItem>) -> NdnResult<()> {
+        //在前面的store中不存在的chunk,继续到后面的store中查找
+        let mut pending_index: Vec<usize> = (0..chunk_list.len()).collect();
+        for local_store in self.local_store_list.iter() {
+            if pending_index.is_empty() {
+                break;
+            }
+            let mut store_chunk_list: Vec<ChunkItem> = pending_index.iter()
+                .map(|index| ChunkItem::new(&chunk_list[*index].chunk_id, 0, None))
+                .collect();
+            local_store.query_chunk_state_by_list(&mut store_chunk_list).await?;
+
+            let mut next_pending_index = Vec::new();
+            for (index, store_chunk_item) in pending_index.into_iter().zip(store_chunk_list.into_iter()) {
+                if store_chunk_item.chunk_state == ChunkState::NotExist {
+                    next_pending_index.push(index);
+                } else {
+                    chunk_list[index] = store_chunk_item;
+                }
+            }
+            pending_index = next_pending_index;
+        }
+
+        for index in pending_index {
+            let chunk_item = &mut chunk_list[index];
+            chunk_item.chunk_state = ChunkState::NotExist;
+            chunk_item.chunk_size = 0;
+            chunk_item.progress = "".to_string();
+        }
+        Ok(())
+    }
+
+    pub async fn query_chunk_state_by_list(mgr_id:Option<&str>, chunk_list: &mut Vec<ChunkItem>) -> NdnResult<()> {
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
+        if named_mgr.is_none() {
+            return Err(NdnError::NotFound(format!("named data mgr not found")));
+        }
+        let named_mgr = named_mgr.unwrap();
+        let named_mgr = named_mgr.lock().await;
+        named_mgr.query_chunk_state_by_list_impl(chunk_list).await
+    }
+
+    pub async fn put_chunklist_impl(&self, chunk_list: HashMap<ChunkId, Vec<u8>>, need_verify: bool) -> NdnResult<()> {
+        let default_store = self.local_store_list.first().unwrap();
+        default_store.put_chunklist(chunk_list, need_verify).await
+    }
+
+    pub async fn put_chunklist(mgr_id:Option<&str>, chunk_list: HashMap<ChunkId, Vec<u8>>, need_verify: bool) -> NdnResult<()> {
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
+        if named_mgr.is_none() {
+            return Err(NdnError::NotFound(format!("named data mgr not found")));
+        }
+        let named_mgr = named_mgr.unwrap();
+        let named_mgr = named_mgr.lock().await;
+        named_mgr.put_chunklist_impl(chunk_list, need_verify).await
+    }
+
   
     pub async fn open_chunk_reader_impl(&self, chunk_id:&ChunkId,seek_from:SeekFrom,auto_cache:bool)->NdnResult<(ChunkReader,u64)> {
         // memroy cache ==> local disk cache ==> local store
@@ -782,6 +941,12 @@ impl NamedDataMgr {
     }
 
 
+    //删除chunk(包括未完成的chunk),用于丢弃无法续传的下载
+    pub async fn remove_chunk_impl(&self, chunk_id:&ChunkId)->NdnResult<()> {
+        let default_store = self.local_store_list.first().unwrap();
+        default_store.remove_chunk(chunk_id).await
+    }
+
     pub async fn complete_chunk_writer_impl (&self, chunk_id:&ChunkId)->NdnResult<()> {
         let default_store = self.local_store_list.first().unwrap();
         default_store.complete_chunk_writer(chunk_id).await
@@ -797,6 +962,90 @@ impl NamedDataMgr {
         named_mgr.complete_chunk_writer_impl(chunk_id).await
     }
 
+    //mark: 从paths和extra_roots出发,计算所有可达的obj_id(包括chunk),同时统计引用计数
+    async fn gc_mark(&self, options:&GcOptions)->NdnResult<(HashSet<String>,GcRefCounter)> {
+        let mut ref_counter = GcRefCounter::new();
+        let mut pending = Vec::new();
+        for obj_id in self.db.list_path_obj_ids()? {
+            add_obj_ref(&mut ref_counter, &obj_id);
+            pending.push(obj_id);
+        }
+        for obj_id in options.extra_roots.iter() {
+            add_obj_ref(&mut ref_counter, obj_id);
+            pending.push(obj_id.clone());
+        }
+
+        let mut reachable = HashSet::new();
+        while let Some(obj_id) = pending.pop() {
+            if !reachable.insert(obj_id.to_string()) {
+                continue;
+            }
+
+            for local_store in self.local_store_list.iter() {
+                let obj_state = local_store.query_object_by_id(&obj_id).await?;
+                match obj_state {
+                    ObjectState::Object(obj_str) => {
+                        //对象解码失败时不知道它引用了什么,继续sweep会删掉它的整个子树,所以直接中止GC
+                        let obj_json = EncodedDocument::from_str(obj_str)
+                            .and_then(|doc| doc.to_json_value())
+                            .map_err(|e| {
+                                error!("gc: decode object {} failed, abort! {}", obj_id.to_string(), e);
+                                NdnError::DecodeError(format!("gc: decode object {} failed! {}", obj_id.to_string(), e))
+                            })?;
+                        let ref_obj_ids = collect_obj_refs(obj_id.obj_type.as_str(), &obj_json)
+                            .inspect_err(|e| error!("gc: collect refs of object {} failed, abort! {}", obj_id.to_string(), e))?;
+                        for ref_obj_id in ref_obj_ids {
+                            add_obj_ref(&mut ref_counter, &ref_obj_id);
+                            pending.push(ref_obj_id);
+                        }
+                        break;
+                    },
+                    ObjectState::Link(obj_link) => {
+                        let target_obj_id = get_link_target(&obj_link);
+                        add_obj_ref(&mut ref_counter, &target_obj_id);
+                        pending.push(target_obj_id);
+                        break;
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        Ok((reachable,ref_counter))
+    }
+
+    //local_cache中的数据本来就是可以随时淘汰的,GC只处理local_store_list
+    pub async fn gc_impl(&self, options:&GcOptions)->NdnResult<GcReport> {
+        let (reachable,ref_counter) = self.gc_mark(options).await?;
+        info!("gc: mark done, reachable count:{}", reachable.len());
+
+        let mut report = GcReport::default();
+        report.dry_run = options.dry_run;
+        report.reachable_count = reachable.len() as u64;
+        for local_store in self.local_store_list.iter() {
+            local_store.gc_sweep(&reachable, options, &mut report).await?;
+        }
+
+        if !options.dry_run {
+            self.db.update_obj_ref_counts(&ref_counter)?;
+        }
+
+        info!("gc: sweep done, dry_run:{}, removed {} chunks({} bytes), {} objects, {} links, skipped {} recent chunks/objects",
+            report.dry_run, report.removed_chunks.len(), report.freed_bytes,
+            report.removed_objects.len(), report.removed_links.len(), report.skipped_recent);
+        Ok(report)
+    }
+
+    pub async fn gc(mgr_id:Option<&str>, options:&GcOptions)->NdnResult<GcReport> {
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
+        if named_mgr.is_none() {
+            return Err(NdnError::NotFound(format!("named data mgr not found")));
+        }
+        let named_mgr = named_mgr.unwrap();
+        let named_mgr = named_mgr.lock().await;
+        named_mgr.gc_impl(options).await
+    }
+
     //下面是一些helper函数
     pub async fn pub_object_to_file(mgr_id:Option<&str>,will_pub_obj:serde_json::Value,obj_type:&str,ndn_path:&str,
                             user_id:&str,app_id:&str)->NdnResult<()> {
@@ -856,7 +1105,105 @@ impl NamedDataMgr {
         named_mgr.sigh_path_obj_impl(path,path_obj_jwt).await
     }
 
-    //会写入两个ndn_path
+    //发布本地文件时写入用的临时chunk_id,由文件路径/大小/修改时间决定,文件变化后不会续传到错误的数据上
+    fn get_pub_tmp_chunk_id(local_file_path:&std::path::Path, file_meta:&std::fs::Metadata)->ChunkId {
+        let mtime = file_meta.modified().ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|d| d.as_millis())
+            .unwrap_or(0);
+        let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+        let key = format!("{}|{}|{}", local_file_path.display(), file_meta.len(), mtime);
+        ChunkId {
+            hash_type: PUB_TMP_CHUNK_TYPE.to_string(),
+            hash_result: chunk_hasher.calc_from_bytes(key.as_bytes()),
+        }
+    }
+
+    //边算hash边写入chunk,hasher的状态保存在临时chunk的progress里,中断后再次发布同一个文件会从上次保存的位置继续
+    //return chunk_id,chunk_size
+    async fn pub_local_file_to_chunk(named_mgr:&Arc<tokio::sync::Mutex<NamedDataMgr>>,local_file_path:&PathBuf)->NdnResult<(ChunkId,u64)> {
+        let mut file_reader = tokio::fs::File::open(local_file_path).await
+            .map_err(|e| {
+                error!("open local_file_path failed, err:{}", e);
+                NdnError::IoError(format!("open local_file_path failed, err:{}", e))
+            })?;
+        let file_meta = file_reader.metadata().await
+            .map_err(|e| {
+                error!("get local_file_path metadata failed, err:{}", e);
+                NdnError::IoError(format!("get local_file_path metadata failed, err:{}", e))
+            })?;
+        let file_size = file_meta.len();
+        let tmp_chunk_id = NamedDataMgr::get_pub_tmp_chunk_id(local_file_path, &file_meta);
+
+        let real_named_mgr = named_mgr.lock().await;
+        let default_store = real_named_mgr.local_store_list.first().unwrap();
+        let (chunk_state,_,progress) = default_store.query_chunk_state(&tmp_chunk_id).await?;
+        let mut chunk_hasher = None;
+        if chunk_state != ChunkState::NotExist {
+            let restored = serde_json::from_str::<serde_json::Value>(&progress).ok()
+                .and_then(|state| ChunkHasher::restore_from_state(state).ok());
+            match restored {
+                Some(hasher) if hasher.pos > 0 && hasher.pos <= file_size => {
+                    info!("pub_local_file_to_chunk: resume {} from pos:{}", local_file_path.display(), hasher.pos);
+                    chunk_hasher = Some(hasher);
+                },
+                _ => {
+                    info!("pub_local_file_to_chunk: tmp chunk {} has no valid hasher state, restart", tmp_chunk_id.to_string());
+                    default_store.remove_chunk(&tmp_chunk_id).await?;
+                }
+            }
+        }
+        let mut chunk_hasher = match chunk_hasher {
+            Some(hasher) => hasher,
+            None => ChunkHasher::new(None).unwrap(),
+        };
+        let offset = chunk_hasher.pos;
+        let (mut chunk_writer,_) = default_store.open_chunk_writer(&tmp_chunk_id, file_size, offset).await?;
+        drop(real_named_mgr);
+
+        file_reader.seek(SeekFrom::Start(offset)).await
+            .map_err(|e| NdnError::IoError(format!("seek local_file failed, err:{}", e)))?;
+        let mut buffer = vec![0u8; COPY_CHUNK_BUFFER_SIZE];
+        let mut last_saved_pos = offset;
+        loop {
+            let n = file_reader.read(&mut buffer).await
+                .map_err(|e| NdnError::IoError(format!("read local_file failed, err:{}", e)))?;
+            if n == 0 {
+                break;
+            }
+            chunk_hasher.update_from_bytes(&buffer[..n]);
+            chunk_writer.write_all(&buffer[..n]).await
+                .map_err(|e| NdnError::IoError(format!("write chunk failed, err:{}", e)))?;
+
+            if chunk_hasher.pos - last_saved_pos >= PUB_SAVE_HASHER_STATE_INTERVAL {
+                //先保证数据落盘,再保存hasher状态
+                chunk_writer.flush().await
+                    .map_err(|e| NdnError::IoError(format!("flush chunk failed, err:{}", e)))?;
+                let real_named_mgr = named_mgr.lock().await;
+                real_named_mgr.update_chunk_progress_impl(&tmp_chunk_id, chunk_hasher.save_state().to_string()).await?;
+                drop(real_named_mgr);
+                last_saved_pos = chunk_hasher.pos;
+            }
+        }
+        chunk_writer.shutdown().await
+            .map_err(|e| NdnError::IoError(format!("close chunk writer failed, err:{}", e)))?;
+        drop(chunk_writer);
+
+        if chunk_hasher.pos != file_size {
+            warn!("pub_local_file_to_chunk: local file {} changed while publishing", local_file_path.display());
+            let real_named_mgr = named_mgr.lock().await;
+            real_named_mgr.local_store_list.first().unwrap().remove_chunk(&tmp_chunk_id).await?;
+            return Err(NdnError::VerifyError(format!("local file changed while publishing:{}", local_file_path.display())));
+        }
+
+        let chunk_id = chunk_hasher.finalize_chunk_id();
+        let real_named_mgr = named_mgr.lock().await;
+        real_named_mgr.local_store_list.first().unwrap().complete_chunk_writer_as(&tmp_chunk_id, &chunk_id).await?;
+        info!("pub_local_file_to_chunk: {} ==> chunk {}, chunk_size:{}", local_file_path.display(), chunk_id.to_string(), file_size);
+        Ok((chunk_id,file_size))
+    }
+
+    //会写入两个ndn_path,ndn_path指向FileObject,ndn_content_path指向整个文件的chunk
     pub async fn pub_local_file_as_fileobj(mgr_id:Option<&str>,local_file_path:&PathBuf,ndn_path:&str,ndn_content_path:&str,
         fileobj_template:&mut FileObject,user_id:&str,app_id:&str)->NdnResult<()> {
         let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
@@ -864,56 +1211,197 @@ impl NamedDataMgr {
             return Err(NdnError::NotFound(format!("named data mgr not found")));
         }
         let named_mgr = named_mgr.unwrap();
-        //TODO：优化，边算边传，支持断点续传
+        let cdc_options = named_mgr.lock().await.pub_cdc_options.clone();
+        if let Some(cdc_options) = cdc_options {
+            //CDC的chunk_list让下载方可以复用已有的chunk,但ndn_content_path的使用方会按一个chunk读取整个文件,
+            //所以仍然保存整个文件的chunk,它的id就是FileObject.content
+            debug!("start pub local_file_as_fileobj with cdc, local_file_path:{}", local_file_path.display());
+            let (chunk_id,_) = NamedDataMgr::pub_local_file_to_chunk(&named_mgr, local_file_path).await?;
+            NamedDataMgr::pub_local_file_as_fileobj_with_cdc(mgr_id, local_file_path, ndn_path,
+                fileobj_template, &cdc_options, user_id, app_id).await?;
+            if fileobj_template.content != chunk_id.to_string() {
+                warn!("pub_local_file_as_fileobj: local file {} changed while publishing", local_file_path.display());
+                return Err(NdnError::VerifyError(format!("local file changed while publishing:{}", local_file_path.display())));
+            }
+            let real_named_mgr = named_mgr.lock().await;
+            real_named_mgr.set_file_impl(ndn_content_path, &chunk_id.to_obj_id(), app_id, user_id).await?;
+            return Ok(());
+        }
+
         debug!("start pub local_file_as_fileobj, local_file_path:{}", local_file_path.display());
-        let mut file_reader =tokio::fs::File::open(local_file_path).await
+        let (chunk_id,chunk_size) = NamedDataMgr::pub_local_file_to_chunk(&named_mgr, local_file_path).await?;
+
+        fileobj_template.content = chunk_id.to_string();
+        fileobj_template.size = chunk_size;
+        fileobj_template.create_time = Some(buckyos_get_unix_timestamp());
+        
+        let (file_obj_id,file_obj_str) = fileobj_template.gen_obj_id();
+        let chunk_obj_id = chunk_id.to_obj_id();
+        //对象和path在同一次加锁中写入,先写对象,保证path指向的对象一定存在
+        let real_named_mgr = named_mgr.lock().await;
+        real_named_mgr.put_object_impl(&file_obj_id, file_obj_str.as_str()).await?;
+        real_named_mgr.set_file_impl(ndn_path, &file_obj_id, app_id, user_id).await?;
+        real_named_mgr.set_file_impl(ndn_content_path, &chunk_obj_id, app_id, user_id).await?;
+        Ok(())
+    }
+
+    pub async fn pub_local_file_as_chunk(mgr_id:Option<&str>,local_file_path:&PathBuf,ndn_path:&str,
+                                            user_id:&str,app_id:&str)->NdnResult<ChunkId> {
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
+        if named_mgr.is_none() {
+            return Err(NdnError::NotFound(format!("named data mgr not found")));
+        }
+        let named_mgr = named_mgr.unwrap();
+        debug!("start pub local_file_as_chunk, local_file_path:{}", local_file_path.display());
+        let (chunk_id,_) = NamedDataMgr::pub_local_file_to_chunk(&named_mgr, local_file_path).await?;
+        let real_named_mgr = named_mgr.lock().await;
+        real_named_mgr.set_file_impl(ndn_path, &chunk_id.to_obj_id(), app_id, user_id).await?;
+        Ok(chunk_id)
+    }
+
+    //用内容定义分块(FastCDC)发布本地文件,没有变化的区域会复用store里已有的chunk
+    //content仍然是整个文件的chunkid,但这里不会保存整个文件的chunk,下载方按chunk_list下载后可以用content校验
+    //需要按content读取整个文件时用pub_local_file_as_fileobj
+    pub async fn pub_local_file_as_fileobj_with_cdc(mgr_id:Option<&str>,local_file_path:&PathBuf,ndn_path:&str,
+        fileobj_template:&mut FileObject,cdc_options:&CdcOptions,user_id:&str,app_id:&str)->NdnResult<CdcDedupStats> {
+        let chunker = ContentDefinedChunker::new(cdc_options.clone())?;
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
+        if named_mgr.is_none() {
+            return Err(NdnError::NotFound(format!("named data mgr not found")));
+        }
+        let named_mgr = named_mgr.unwrap();
+        debug!("start pub local_file_as_fileobj_with_cdc, local_file_path:{}", local_file_path.display());
+        let file_reader = tokio::fs::File::open(local_file_path).await
             .map_err(|e| {
                 error!("open local_file_path failed, err:{}", e);
                 NdnError::IoError(format!("open local_file_path failed, err:{}", e))
             })?;
-        debug!("open local_file_path success");
-        let mut chunk_hasher = ChunkHasher::new(None).unwrap();
-        file_reader.seek(SeekFrom::Start(0)).await;
-        let (chunk_raw_id,chunk_size) = chunk_hasher.calc_from_reader(&mut file_reader).await.unwrap();
-    
-        let chunk_id = ChunkId::from_sha256_result(&chunk_raw_id);
-        info!("pub_local_file_as_fileobj:calc chunk_id success,chunk_id:{},chunk_size:{}", chunk_id.to_string(),chunk_size);
-        let real_named_mgr = named_mgr.lock().await;
-        let is_exist = real_named_mgr.is_chunk_exist_impl(&chunk_id).await.unwrap();
-        if !is_exist {
-            let (mut chunk_writer, _) = real_named_mgr.open_chunk_writer_impl(&chunk_id, chunk_size, 0).await?;
-            drop(real_named_mgr);
-            file_reader.seek(std::io::SeekFrom::Start(0)).await.unwrap();
-            let copy_bytes = tokio::io::copy(&mut file_reader, &mut chunk_writer).await
-                .map_err(|e| {
-                    error!("copy local_file {:?} to named-mgr failed, err:{}", local_file_path, e);
-                    NdnError::IoError(format!("copy local_file to named-mgr failed, err:{}", e))
-                })?;
 
-            info!("pub_local_file_as_fileobj:copy local_file {:?} to named-mgr's chunk success,copy_bytes:{}", local_file_path, copy_bytes);
+        let mut file_hasher = ChunkHasher::new(None).unwrap();
+        let mut chunk_reader = CdcChunkReader::new(&chunker, file_reader);
+        let mut stats = CdcDedupStats::default();
+        let mut chunk_list = Vec::new();
+        let mut chunk_size_list = Vec::new();
+        while let Some(chunk_data) = chunk_reader.next_chunk().await? {
+            file_hasher.update_from_bytes(&chunk_data);
+            let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+            let chunk_id = ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(&chunk_data));
             let real_named_mgr = named_mgr.lock().await;
-            real_named_mgr.complete_chunk_writer_impl(&chunk_id).await?;
-        } else {
+            let is_reused = real_named_mgr.is_chunk_exist_impl(&chunk_id).await?;
+            if !is_reused {
+                let default_store = real_named_mgr.local_store_list.first().unwrap();
+                default_store.put_chunk(&chunk_id, &chunk_data, false).await?;
+            }
             drop(real_named_mgr);
+
+            stats.add_chunk(chunk_data.len() as u64, is_reused);
+            chunk_list.push(chunk_id.to_string());
+            chunk_size_list.push(chunk_data.len() as u64);
         }
 
-        fileobj_template.content = chunk_id.to_string();
-        fileobj_template.size = chunk_size;
+        fileobj_template.content = file_hasher.finalize_chunk_id().to_string();
+        fileobj_template.size = stats.total_bytes;
         fileobj_template.create_time = Some(buckyos_get_unix_timestamp());
-        
+        let mut file_chunk_list = HashMap::new();
+        file_chunk_list.insert(FILE_CHUNK_LIST_CDC.to_string(), chunk_list);
+        fileobj_template.chunk_list = Some(file_chunk_list);
+        fileobj_template.chunk_sizes = Some(chunk_size_list);
+
         let (file_obj_id,file_obj_str) = fileobj_template.gen_obj_id();
-        let chunk_obj_id = chunk_id.to_obj_id();
         let real_named_mgr = named_mgr.lock().await;
         real_named_mgr.put_object_impl(&file_obj_id, file_obj_str.as_str()).await?;
         real_named_mgr.set_file_impl(ndn_path, &file_obj_id, app_id, user_id).await?;
-        real_named_mgr.set_file_impl(ndn_content_path, &chunk_obj_id, app_id, user_id).await?;
-        Ok(())
+        info!("pub_local_file_as_fileobj_with_cdc: {} ==> {}, chunks:{}, reused chunks:{}, new bytes:{}, dedup ratio:{:.2}",
+            local_file_path.display(), file_obj_id.to_string(), stats.total_chunks, stats.reused_chunks,
+            stats.new_bytes(), stats.dedup_ratio());
+        Ok(stats)
     }
 
-    
-    pub async fn pub_local_file_as_chunk(mgr_id:Option<&str>,local_file_path:String,ndn_path:&str,
-                                            user_id:&str,app_id:&str)->NdnResult<()> {
-        unimplemented!()
+    //大文件按chunk_size定长分块发布,在chunk hash之上构造MerkleTree并作为命名对象保存,
+    //FileObject.content是mtree obj id,这样下载方可以对任意一个chunk用root hash校验,不用等整个文件下载完成
+    //返回FileObject的obj_id
+    pub async fn pub_local_file_as_chunked_fileobj(mgr_id:Option<&str>,local_file_path:&PathBuf,ndn_path:&str,chunk_size:u64,
+        fileobj_template:&mut FileObject,user_id:&str,app_id:&str)->NdnResult<ObjId> {
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(NdnError::InvalidParam(format!("invalid chunk size:{}",chunk_size)));
+        }
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
+        if named_mgr.is_none() {
+            return Err(NdnError::NotFound(format!("named data mgr not found")));
+        }
+        let named_mgr = named_mgr.unwrap();
+        debug!("start pub local_file_as_chunked_fileobj, local_file_path:{}", local_file_path.display());
+        let mut file_reader = tokio::fs::File::open(local_file_path).await
+            .map_err(|e| {
+                error!("open local_file_path failed, err:{}", e);
+                NdnError::IoError(format!("open local_file_path failed, err:{}", e))
+            })?;
+
+        let mut file_size = 0;
+        let mut chunk_list = Vec::new();
+        let mut leaf_hashes = Vec::new();
+        let mut buffer = vec![0u8; chunk_size as usize];
+        loop {
+            //每个chunk都读满chunk_size,只有最后一个chunk可以小于chunk_size
+            let mut read_len = 0;
+            while read_len < buffer.len() {
+                let n = file_reader.read(&mut buffer[read_len..]).await
+                    .map_err(|e| NdnError::IoError(format!("read local_file failed, err:{}", e)))?;
+                if n == 0 {
+                    break;
+                }
+                read_len += n;
+            }
+            if read_len == 0 {
+                break;
+            }
+
+            let chunk_data = &buffer[..read_len];
+            let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+            let hash_result = chunk_hasher.calc_from_bytes(chunk_data);
+            let chunk_id = ChunkId::from_sha256_result(&hash_result);
+            let real_named_mgr = named_mgr.lock().await;
+            if !real_named_mgr.is_chunk_exist_impl(&chunk_id).await? {
+                let default_store = real_named_mgr.local_store_list.first().unwrap();
+                default_store.put_chunk(&chunk_id, chunk_data, false).await?;
+            }
+            drop(real_named_mgr);
+
+            file_size += read_len as u64;
+            chunk_list.push(chunk_id.to_string());
+            leaf_hashes.push(hash_result);
+            if read_len < buffer.len() {
+                break;
+            }
+        }
+
+        if chunk_list.is_empty() {
+            return Err(NdnError::InvalidParam(format!("local file {} is empty", local_file_path.display())));
+        }
+
+        let (root_hash,mtree_body) = MerkleTreeObject::build_body_from_leaf_hashes(
+            file_size, chunk_size, Some(HashMethod::Sha256), &leaf_hashes).await?;
+        let mtree_obj_id = ObjId::new_by_raw(OBJ_TYPE_MTREE.to_string(), root_hash);
+        //mtree的完整编码保存为命名对象,obj id是编码后json的hash,下载方可以直接校验,GC可以通过FileObject.mtree找到它
+        let (mtree_body_obj_id,mtree_body_obj_str) = build_named_object_by_json(OBJ_TYPE_MTREE,
+            &MerkleTreeObject::encode_body_to_obj_json(&mtree_body));
+        info!("pub_local_file_as_chunked_fileobj: {} ==> {} chunks, mtree:{}, mtree body:{}", local_file_path.display(),
+            chunk_list.len(), mtree_obj_id.to_string(), mtree_body_obj_id.to_string());
+
+        fileobj_template.content = mtree_obj_id.to_string();
+        fileobj_template.mtree = Some(mtree_body_obj_id.to_string());
+        fileobj_template.size = file_size;
+        fileobj_template.create_time = Some(buckyos_get_unix_timestamp());
+        let mut file_chunk_list = HashMap::new();
+        file_chunk_list.insert(FILE_CHUNK_LIST_FIXED.to_string(), chunk_list);
+        fileobj_template.chunk_list = Some(file_chunk_list);
+
+        let (file_obj_id,file_obj_str) = fileobj_template.gen_obj_id();
+        let real_named_mgr = named_mgr.lock().await;
+        real_named_mgr.put_object_impl(&mtree_body_obj_id, mtree_body_obj_str.as_str()).await?;
+        real_named_mgr.put_object_impl(&file_obj_id, file_obj_str.as_str()).await?;
+        real_named_mgr.set_file_impl(ndn_path, &file_obj_id, app_id, user_id).await?;
+        Ok(file_obj_id)
     }
     
 }
@@ -921,7 +1409,7 @@ impl NamedDataMgr {
 
 #[cfg(test)]
 mod tests {
-    use crate::FileObject;
+    use crate::{FileObject, ObjectMapBody, OBJ_TYPE_OBJMAPT};
 
     use super::*;
     use tempfile::tempdir;
@@ -935,6 +1423,7 @@ mod tests {
             local_stores: vec![test_dir.path().to_str().unwrap().to_string()],
             local_cache: None,
             mmap_cache_dir: None,
+            pub_cdc_options: None,
         };
 
         let chunk_mgr = NamedDataMgr::from_config(
@@ -973,6 +1462,7 @@ mod tests {
             local_stores: vec![test_dir.path().to_str().unwrap().to_string()],
             local_cache: None,
             mmap_cache_dir: None,
+            pub_cdc_options: None,
         };
 
         let named_mgr = NamedDataMgr::from_config(
@@ -1050,6 +1540,445 @@ mod tests {
     }
 
 
+    #[tokio::test]
+    async fn test_gc() -> NdnResult<()> {
+        let test_dir = tempdir().unwrap();
+        let config = NamedDataMgrConfig {
+            local_stores: vec![test_dir.path().to_str().unwrap().to_string()],
+            local_cache: None,
+            mmap_cache_dir: None,
+            pub_cdc_options: None,
+        };
+
+        let named_mgr = NamedDataMgr::from_config(
+            Some("test_gc".to_string()),
+            test_dir.path().to_path_buf(),
+            config
+        ).await?;
+
+        let mut chunk_ids = vec![];
+        for data in [b"gc chunk 1", b"gc chunk 2", b"gc chunk 3"] {
+            let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+            let chunk_id = ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(data));
+            let (mut writer, _) = named_mgr.open_chunk_writer_impl(&chunk_id, data.len() as u64, 0).await?;
+            writer.write_all(data).await.unwrap();
+            named_mgr.complete_chunk_writer_impl(&chunk_id).await?;
+            chunk_ids.push(chunk_id);
+        }
+        //未完成的chunk
+        let incompleted_chunk_id = ChunkId::new("sha256:abcdef1234567890").unwrap();
+        let (mut writer, _) = named_mgr.open_chunk_writer_impl(&incompleted_chunk_id, 100, 0).await?;
+        writer.write_all(b"half").await.unwrap();
+
+        //chunk1 直接被path引用, chunk2 被fileobj引用, chunk3 没有引用
+        named_mgr.create_file_impl("/gc/chunk1", &chunk_ids[0].to_obj_id(), "test_app", "test_user").await?;
+        let file_obj = FileObject::new("file2".to_string(), 10, chunk_ids[1].to_string());
+        let (file_obj_id, file_obj_str) = file_obj.gen_obj_id();
+        named_mgr.put_object_impl(&file_obj_id, &file_obj_str).await?;
+        named_mgr.create_file_impl("/gc/file2", &file_obj_id, "test_app", "test_user").await?;
+
+        let orphan_obj = FileObject::new("orphan".to_string(), 10, chunk_ids[2].to_string());
+        let (orphan_obj_id, orphan_obj_str) = orphan_obj.gen_obj_id();
+        named_mgr.put_object_impl(&orphan_obj_id, &orphan_obj_str).await?;
+
+        //刚写入还没有被引用的chunk/object(包括已完成的)都在grace period内,不能删除
+        let mut options = GcOptions::default();
+        let report = named_mgr.gc_impl(&options).await?;
+        assert!(report.removed_chunks.is_empty());
+        assert!(report.removed_objects.is_empty());
+        assert_eq!(report.skipped_recent, 3);
+        assert!(named_mgr.is_chunk_exist_impl(&chunk_ids[2]).await?);
+        let (chunk_state, _, _) = named_mgr.query_chunk_state_impl(&incompleted_chunk_id).await?;
+        assert_eq!(chunk_state, ChunkState::New);
+
+        options.grace_period = 0;
+        options.dry_run = true;
+        let report = named_mgr.gc_impl(&options).await?;
+        assert_eq!(report.removed_chunks.len(), 2);
+        assert!(report.removed_chunks.contains(&chunk_ids[2].to_string()));
+        assert!(report.removed_chunks.contains(&incompleted_chunk_id.to_string()));
+        assert_eq!(report.removed_objects, vec![orphan_obj_id.to_string()]);
+        assert_eq!(report.freed_bytes, 110);
+        assert!(named_mgr.is_chunk_exist_impl(&chunk_ids[2]).await?);
+
+        options.dry_run = false;
+        named_mgr.gc_impl(&options).await?;
+        assert!(named_mgr.is_chunk_exist_impl(&chunk_ids[0]).await?);
+        assert!(named_mgr.is_chunk_exist_impl(&chunk_ids[1]).await?);
+        assert!(!named_mgr.is_chunk_exist_impl(&chunk_ids[2]).await?);
+        assert!(named_mgr.get_object_impl(&orphan_obj_id, None).await.is_err());
+        assert_eq!(named_mgr.db.get_obj_ref_count(&chunk_ids[1].to_obj_id())?, 1);
+        let (chunk_state, _, _) = named_mgr.query_chunk_state_impl(&incompleted_chunk_id).await?;
+        assert_eq!(chunk_state, ChunkState::NotExist);
+
+        //删除path后,fileobj和它引用的chunk都可以被回收
+        named_mgr.remove_file_impl("/gc/file2").await?;
+        let report = named_mgr.gc_impl(&options).await?;
+        assert_eq!(report.removed_chunks, vec![chunk_ids[1].to_string()]);
+        assert_eq!(report.removed_objects, vec![file_obj_id.to_string()]);
+        assert!(!named_mgr.is_chunk_exist_impl(&chunk_ids[1]).await?);
+        assert_eq!(named_mgr.db.get_obj_ref_count(&chunk_ids[1].to_obj_id())?, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc_object_map() -> NdnResult<()> {
+        let test_dir = tempdir().unwrap();
+        let config = NamedDataMgrConfig {
+            local_stores: vec![test_dir.path().to_str().unwrap().to_string()],
+            local_cache: None,
+            mmap_cache_dir: None,
+            pub_cdc_options: None,
+        };
+
+        let named_mgr = NamedDataMgr::from_config(
+            Some("test_gc_object_map".to_string()),
+            test_dir.path().to_path_buf(),
+            config
+        ).await?;
+
+        let mut chunk_ids = vec![];
+        for data in [b"objmap chunk 1", b"objmap chunk 2"] {
+            let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+            let chunk_id = ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(data));
+            let (mut writer, _) = named_mgr.open_chunk_writer_impl(&chunk_id, data.len() as u64, 0).await?;
+            writer.write_all(data).await.unwrap();
+            named_mgr.complete_chunk_writer_impl(&chunk_id).await?;
+            chunk_ids.push(chunk_id);
+        }
+
+        //chunk只被ObjectMap引用
+        let mut obj_map = ObjectMap::new(HashMethod::Sha256, Box::new(crate::MemoryStorage::new())).await?;
+        obj_map.put_object("a", chunk_ids[0].to_obj_id(), None).await?;
+        obj_map.put_object("b", chunk_ids[1].to_obj_id(), Some(vec![1, 2, 3])).await?;
+        obj_map.flush().await?;
+        let obj_map_id = named_mgr.put_object_map_impl(&obj_map).await?;
+        named_mgr.create_file_impl("/gc/objmap", &obj_map_id, "test_app", "test_user").await?;
+
+        //保存的body可以重新计算出obj_id
+        let body_json = named_mgr.get_object_impl(&obj_map_id, None).await?;
+        let body = ObjectMapBody::decode(&body_json)?;
+        assert_eq!(body.calc_obj_id().await?, obj_map_id);
+
+        let report = named_mgr.gc_impl(&GcOptions::default()).await?;
+        assert!(report.removed_chunks.is_empty());
+        assert!(report.removed_objects.is_empty());
+        assert!(named_mgr.is_chunk_exist_impl(&chunk_ids[0]).await?);
+        assert!(named_mgr.is_chunk_exist_impl(&chunk_ids[1]).await?);
+
+        named_mgr.remove_file_impl("/gc/objmap").await?;
+        let options = GcOptions {
+            grace_period: 0,
+            ..Default::default()
+        };
+        let report = named_mgr.gc_impl(&options).await?;
+        assert_eq!(report.removed_chunks.len(), 2);
+        assert_eq!(report.removed_objects, vec![obj_map_id.to_string()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gc_abort_on_decode_error() -> NdnResult<()> {
+        let test_dir = tempdir().unwrap();
+        let config = NamedDataMgrConfig {
+            local_stores: vec![test_dir.path().to_str().unwrap().to_string()],
+            local_cache: None,
+            mmap_cache_dir: None,
+            pub_cdc_options: None,
+        };
+
+        let named_mgr = NamedDataMgr::from_config(
+            Some("test_gc_abort".to_string()),
+            test_dir.path().to_path_buf(),
+            config
+        ).await?;
+
+        let data = b"gc abort chunk";
+        let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+        let chunk_id = ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(data));
+        let (mut writer, _) = named_mgr.open_chunk_writer_impl(&chunk_id, data.len() as u64, 0).await?;
+        writer.write_all(data).await.unwrap();
+        named_mgr.complete_chunk_writer_impl(&chunk_id).await?;
+
+        //path指向一个无法解码的ObjectMap,GC必须失败,不能删除任何数据
+        let broken_obj_id = ObjId::new_by_raw(OBJ_TYPE_OBJMAPT.to_string(), vec![7u8; 32]);
+        named_mgr.put_object_impl(&broken_obj_id, &json!({"items": [chunk_id.to_string()]}).to_string()).await?;
+        named_mgr.create_file_impl("/gc/broken", &broken_obj_id, "test_app", "test_user").await?;
+
+        assert!(named_mgr.gc_impl(&GcOptions::default()).await.is_err());
+        assert!(named_mgr.is_chunk_exist_impl(&chunk_id).await?);
+        assert!(named_mgr.get_object_impl(&broken_obj_id, None).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pub_local_file_resume() -> NdnResult<()> {
+        let test_dir = tempdir().unwrap();
+        let config = NamedDataMgrConfig {
+            local_stores: vec![test_dir.path().join("store").to_str().unwrap().to_string()],
+            local_cache: None,
+            mmap_cache_dir: None,
+            pub_cdc_options: None,
+        };
+        let named_mgr = NamedDataMgr::from_config(
+            Some("test_pub_local_file".to_string()),
+            test_dir.path().to_path_buf(),
+            config
+        ).await?;
+        NamedDataMgr::set_mgr_by_id(Some("test_pub_local_file"), named_mgr).await?;
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(Some("test_pub_local_file")).await.unwrap();
+
+        let file_data: Vec<u8> = (0..3 * 1024 * 1024 + 100).map(|i| (i % 251) as u8).collect();
+        let local_file_path = test_dir.path().join("local_file.bin");
+        tokio::fs::write(&local_file_path, &file_data).await.unwrap();
+        let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+        let expect_chunk_id = ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(&file_data));
+
+        //模拟上次发布写了一部分后中断:已保存1MB的hasher状态,文件里多写了一些没有保存状态的数据
+        let file_meta = std::fs::metadata(&local_file_path).unwrap();
+        let tmp_chunk_id = NamedDataMgr::get_pub_tmp_chunk_id(&local_file_path, &file_meta);
+        {
+            let real_named_mgr = named_mgr.lock().await;
+            let (mut writer, _) = real_named_mgr.open_chunk_writer_impl(&tmp_chunk_id, file_data.len() as u64, 0).await?;
+            writer.write_all(&file_data[..1024 * 1024 + 10]).await.unwrap();
+            writer.flush().await.unwrap();
+            let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+            chunk_hasher.update_from_bytes(&file_data[..1024 * 1024]);
+            real_named_mgr.update_chunk_progress_impl(&tmp_chunk_id, chunk_hasher.save_state().to_string()).await?;
+        }
+
+        let chunk_id = NamedDataMgr::pub_local_file_as_chunk(Some("test_pub_local_file"), &local_file_path,
+            "/pub/chunk", "test_user", "test_app").await?;
+        assert_eq!(chunk_id, expect_chunk_id);
+
+        let real_named_mgr = named_mgr.lock().await;
+        let (chunk_state, _, _) = real_named_mgr.query_chunk_state_impl(&tmp_chunk_id).await?;
+        assert_eq!(chunk_state, ChunkState::NotExist);
+        let (mut reader, chunk_size) = real_named_mgr.open_chunk_reader_impl(&chunk_id, SeekFrom::Start(0), false).await?;
+        assert_eq!(chunk_size, file_data.len() as u64);
+        let mut read_data = Vec::new();
+        reader.read_to_end(&mut read_data).await.unwrap();
+        assert_eq!(read_data, file_data);
+        let (obj_id, _) = real_named_mgr.get_obj_id_by_path_impl("/pub/chunk").await?;
+        assert_eq!(obj_id, chunk_id.to_obj_id());
+        drop(real_named_mgr);
+
+        //再次发布同一个文件为FileObject,chunk已经存在
+        let mut file_obj = FileObject::new("local_file.bin".to_string(), 0, "".to_string());
+        NamedDataMgr::pub_local_file_as_fileobj(Some("test_pub_local_file"), &local_file_path,
+            "/pub/file", "/pub/file_content", &mut file_obj, "test_user", "test_app").await?;
+        assert_eq!(file_obj.content, chunk_id.to_string());
+        assert_eq!(file_obj.size, file_data.len() as u64);
+        let real_named_mgr = named_mgr.lock().await;
+        let (obj_id, _) = real_named_mgr.get_obj_id_by_path_impl("/pub/file").await?;
+        let obj_json = real_named_mgr.get_object_impl(&obj_id, None).await?;
+        assert_eq!(obj_json["content"].as_str().unwrap(), chunk_id.to_string());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pub_local_file_republish() -> NdnResult<()> {
+        let test_dir = tempdir().unwrap();
+        let config = NamedDataMgrConfig {
+            local_stores: vec![test_dir.path().join("store").to_str().unwrap().to_string()],
+            local_cache: None,
+            mmap_cache_dir: None,
+            pub_cdc_options: None,
+        };
+        let named_mgr = NamedDataMgr::from_config(
+            Some("test_pub_republish".to_string()),
+            test_dir.path().to_path_buf(),
+            config
+        ).await?;
+        NamedDataMgr::set_mgr_by_id(Some("test_pub_republish"), named_mgr).await?;
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(Some("test_pub_republish")).await.unwrap();
+
+        let file_data_a = vec![1u8; 4096];
+        let local_file_path = test_dir.path().join("local_file.bin");
+        tokio::fs::write(&local_file_path, &file_data_a).await.unwrap();
+        let mtime = std::fs::metadata(&local_file_path).unwrap().modified().unwrap();
+
+        //上次发布为FileObject时中断,从保存的hasher状态继续
+        let file_meta = std::fs::metadata(&local_file_path).unwrap();
+        let tmp_chunk_id = NamedDataMgr::get_pub_tmp_chunk_id(&local_file_path, &file_meta);
+        {
+            let real_named_mgr = named_mgr.lock().await;
+            let (mut writer, _) = real_named_mgr.open_chunk_writer_impl(&tmp_chunk_id, file_data_a.len() as u64, 0).await?;
+            writer.write_all(&file_data_a[..1024]).await.unwrap();
+            writer.flush().await.unwrap();
+            let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+            chunk_hasher.update_from_bytes(&file_data_a[..1024]);
+            real_named_mgr.update_chunk_progress_impl(&tmp_chunk_id, chunk_hasher.save_state().to_string()).await?;
+        }
+        let mut file_obj = FileObject::new("local_file.bin".to_string(), 0, "".to_string());
+        NamedDataMgr::pub_local_file_as_fileobj(Some("test_pub_republish"), &local_file_path,
+            "/pub/file", "/pub/file_content", &mut file_obj, "test_user", "test_app").await?;
+        let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+        let chunk_id_a = ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(&file_data_a));
+        assert_eq!(file_obj.content, chunk_id_a.to_string());
+
+        //内容变了但大小和修改时间没变,再次发布必须重新读取文件,不能复用上次的chunk
+        let file_data_b = vec![2u8; 4096];
+        std::fs::write(&local_file_path, &file_data_b).unwrap();
+        std::fs::File::options().write(true).open(&local_file_path).unwrap().set_modified(mtime).unwrap();
+
+        //没有有效hasher状态的临时chunk会被丢弃,从头开始发布
+        {
+            let real_named_mgr = named_mgr.lock().await;
+            let (mut writer, _) = real_named_mgr.open_chunk_writer_impl(&tmp_chunk_id, file_data_b.len() as u64, 0).await?;
+            writer.write_all(&file_data_a[..1024]).await.unwrap();
+            writer.flush().await.unwrap();
+            real_named_mgr.update_chunk_progress_impl(&tmp_chunk_id, "invalid state".to_string()).await?;
+        }
+        let mut file_obj = FileObject::new("local_file.bin".to_string(), 0, "".to_string());
+        NamedDataMgr::pub_local_file_as_fileobj(Some("test_pub_republish"), &local_file_path,
+            "/pub/file", "/pub/file_content", &mut file_obj, "test_user", "test_app").await?;
+        let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+        let chunk_id_b = ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(&file_data_b));
+        assert_eq!(file_obj.content, chunk_id_b.to_string());
+
+        let real_named_mgr = named_mgr.lock().await;
+        let (chunk_state, _, _) = real_named_mgr.query_chunk_state_impl(&tmp_chunk_id).await?;
+        assert_eq!(chunk_state, ChunkState::NotExist);
+        let (mut reader, _) = real_named_mgr.open_chunk_reader_impl(&chunk_id_b, SeekFrom::Start(0), false).await?;
+        let mut read_data = Vec::new();
+        reader.read_to_end(&mut read_data).await.unwrap();
+        assert_eq!(read_data, file_data_b);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pub_chunked_fileobj() -> NdnResult<()> {
+        let test_dir = tempdir().unwrap();
+        let config = NamedDataMgrConfig {
+            local_stores: vec![test_dir.path().join("store").to_str().unwrap().to_string()],
+            local_cache: None,
+            mmap_cache_dir: None,
+            pub_cdc_options: None,
+        };
+        let named_mgr = NamedDataMgr::from_config(
+            Some("test_pub_chunked".to_string()),
+            test_dir.path().to_path_buf(),
+            config
+        ).await?;
+        NamedDataMgr::set_mgr_by_id(Some("test_pub_chunked"), named_mgr).await?;
+
+        let chunk_size = 64 * 1024;
+        let file_data: Vec<u8> = (0..chunk_size * 5 + 77).map(|i| (i % 253) as u8).collect();
+        let local_file_path = test_dir.path().join("big_file.bin");
+        tokio::fs::write(&local_file_path, &file_data).await.unwrap();
+
+        let mut file_obj = FileObject::new("big_file.bin".to_string(), 0, "".to_string());
+        let file_obj_id = NamedDataMgr::pub_local_file_as_chunked_fileobj(Some("test_pub_chunked"), &local_file_path,
+            "/pub/big_file", chunk_size as u64, &mut file_obj, "test_user", "test_app").await?;
+        assert!(file_obj.is_chunked());
+        let chunk_list = file_obj.get_fixed_chunk_list()?;
+        assert_eq!(chunk_list.len(), 6);
+        assert_eq!(file_obj.size, file_data.len() as u64);
+
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(Some("test_pub_chunked")).await.unwrap();
+        let real_named_mgr = named_mgr.lock().await;
+        let (obj_id, _) = real_named_mgr.get_obj_id_by_path_impl("/pub/big_file").await?;
+        assert_eq!(obj_id, file_obj_id);
+
+        let mtree_obj_id = ObjId::new(&file_obj.content)?;
+        let mtree_body_obj_id = file_obj.get_mtree_body_obj_id()?;
+        let mtree_json = real_named_mgr.get_object_impl(&mtree_body_obj_id, None).await?;
+        let (calc_body_obj_id, _) = build_named_object_by_json(OBJ_TYPE_MTREE, &mtree_json);
+        assert_eq!(calc_body_obj_id, mtree_body_obj_id);
+        let mtree_body = MerkleTreeObject::decode_body_from_obj_json(&mtree_json)?;
+        let mut mtree = MerkleTreeObject::load_from_body(mtree_body, true).await?;
+        assert_eq!(mtree.get_obj_id(), mtree_obj_id);
+        assert_eq!(mtree.get_leaf_count(), 6);
+
+        for (index, chunk_id) in chunk_list.iter().enumerate() {
+            let (mut reader, _) = real_named_mgr.open_chunk_reader_impl(chunk_id, SeekFrom::Start(0), false).await?;
+            let mut chunk_data = Vec::new();
+            reader.read_to_end(&mut chunk_data).await.unwrap();
+            let begin = index * chunk_size;
+            assert_eq!(chunk_data, file_data[begin..(begin + chunk_size).min(file_data.len())].to_vec());
+            assert!(mtree.verify_leaf_hash(index as u64, &chunk_id.hash_result).await?);
+        }
+        //错误的chunk hash或者错误的位置都不能通过校验
+        let mut bad_hash = chunk_list[0].hash_result.clone();
+        bad_hash[0] = !bad_hash[0];
+        assert!(!mtree.verify_leaf_hash(0, &bad_hash).await?);
+        assert!(!mtree.verify_leaf_hash(1, &chunk_list[0].hash_result).await?);
+
+        //mtree对象只被FileObject引用,GC不能删除它
+        let options = GcOptions {
+            grace_period: 0,
+            ..Default::default()
+        };
+        let report = real_named_mgr.gc_impl(&options).await?;
+        assert!(report.removed_chunks.is_empty());
+        assert!(report.removed_objects.is_empty());
+        assert!(real_named_mgr.get_object_impl(&mtree_body_obj_id, None).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pub_fileobj_with_cdc() -> NdnResult<()> {
+        let test_dir = tempdir().unwrap();
+        let config = NamedDataMgrConfig {
+            local_stores: vec![test_dir.path().join("store").to_str().unwrap().to_string()],
+            local_cache: None,
+            mmap_cache_dir: None,
+            pub_cdc_options: None,
+        };
+        let named_mgr = NamedDataMgr::from_config(
+            Some("test_pub_cdc".to_string()),
+            test_dir.path().to_path_buf(),
+            config
+        ).await?;
+        NamedDataMgr::set_mgr_by_id(Some("test_pub_cdc"), named_mgr).await?;
+
+        let cdc_options = CdcOptions {
+            min_size: 4 * 1024,
+            avg_size: 16 * 1024,
+            max_size: 64 * 1024,
+        };
+        let mut seed: u32 = 7;
+        let file_data: Vec<u8> = (0..1024 * 1024).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }).collect();
+        let local_file_path = test_dir.path().join("image_v1.bin");
+        tokio::fs::write(&local_file_path, &file_data).await.unwrap();
+        let mut file_obj = FileObject::new("image.bin".to_string(), 0, "".to_string());
+        let stats = NamedDataMgr::pub_local_file_as_fileobj_with_cdc(Some("test_pub_cdc"), &local_file_path,
+            "/pub/image", &mut file_obj, &cdc_options, "test_user", "test_app").await?;
+        assert_eq!(stats.total_bytes, file_data.len() as u64);
+        assert!(file_obj.is_cdc_chunked());
+        let chunk_list = file_obj.get_cdc_chunk_list()?;
+        assert_eq!(chunk_list.len() as u64, stats.total_chunks);
+        assert_eq!(chunk_list.iter().map(|(_, size)| size).sum::<u64>(), file_data.len() as u64);
+        let mut chunk_hasher = ChunkHasher::new(None).unwrap();
+        assert_eq!(file_obj.content, ChunkId::from_sha256_result(&chunk_hasher.calc_from_bytes(&file_data)).to_string());
+
+        //修改文件中间的一小段后重新发布,大部分chunk被复用
+        let mut modified_data = file_data.clone();
+        modified_data.splice(300 * 1024..300 * 1024 + 10, b"modified data in the middle".iter().cloned());
+        let local_file_path = test_dir.path().join("image_v2.bin");
+        tokio::fs::write(&local_file_path, &modified_data).await.unwrap();
+        let mut file_obj2 = FileObject::new("image.bin".to_string(), 0, "".to_string());
+        let stats = NamedDataMgr::pub_local_file_as_fileobj_with_cdc(Some("test_pub_cdc"), &local_file_path,
+            "/pub/image", &mut file_obj2, &cdc_options, "test_user", "test_app").await?;
+        assert!(stats.reused_chunks + 3 >= stats.total_chunks, "stats:{:?}", stats);
+        assert!(stats.dedup_ratio() > 0.8, "stats:{:?}", stats);
+
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(Some("test_pub_cdc")).await.unwrap();
+        let real_named_mgr = named_mgr.lock().await;
+        let mut read_data = Vec::new();
+        for (chunk_id, _) in file_obj2.get_cdc_chunk_list()? {
+            let (mut reader, _) = real_named_mgr.open_chunk_reader_impl(&chunk_id, SeekFrom::Start(0), false).await?;
+            reader.read_to_end(&mut read_data).await.unwrap();
+        }
+        assert_eq!(read_data, modified_data);
+        Ok(())
+    }
+
     //test get_chunk_mgr_by_id，然后再创建并写入一个chunk，再读取
     #[tokio::test]
     async fn test_get_chunk_mgr_by_id() -> NdnResult<()> {
@@ -1095,6 +2024,7 @@ mod tests {
             local_stores: vec![test_dir.path().to_str().unwrap().to_string()],
             local_cache: None,
             mmap_cache_dir: None,
+            pub_cdc_options: None,
         };
 
         let chunk_mgr = NamedDataMgr::from_config(
@@ -15,8 +15,14 @@ use tokio::io::{BufReader,BufWriter};
 use std::collections::HashMap;
 use futures::Future;
 use rand::RngCore;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::sync::Semaphore;
 
 use crate::{build_named_object_by_json, build_obj_id, copy_chunk, cyfs_get_obj_id_from_url, get_cyfs_resp_headers, verify_named_object, CYFSHttpRespHeaders, ChunkState, FileObject, PathObject};
+use crate::{encode_chunk_list_body, ChunkStateListReq, ChunkStateListResp, CYFS_CHUNK_STATE_LIST_PATH, CYFS_PUT_CHUNK_LIST_PATH, MAX_PUT_CHUNK_LIST_BODY_SIZE};
+use crate::{get_chunk_progress_pos, parse_content_range, CYFS_CHUNK_OFFSET_HEADER};
+use crate::MerkleTreeObject;
 
 
 pub enum ChunkWorkState {
@@ -25,6 +31,76 @@ pub enum ChunkWorkState {
     DownloadError(String),//error message
 }
 
+//push_chunk每个请求上传的数据长度,网络中断时最多需要重传这么多
+const PUSH_CHUNK_PIECE_SIZE:u64 = 1024*1024*4;
+const PUSH_CHUNK_MAX_RETRY:u32 = 3;
+
+pub type DownloadProgressCallback = Arc<dyn Fn(&ChunkId,u64,u64) + Send + Sync>;
+
+//多源下载的参数
+#[derive(Clone)]
+pub struct MultiSourceOptions {
+    //chunk按piece_size分成多个range并行下载
+    pub piece_size:u64,
+    //同时进行的请求数
+    pub max_parallel:usize,
+    //单个range最多尝试的次数,失败后会换一个源重试
+    pub max_retry:u32,
+    //连续失败这么多次的源不再优先使用
+    pub max_source_failures:u32,
+    //每写入这么多个piece保存一次进度
+    pub save_progress_interval:u64,
+    //(chunk_id,已下载的位置,chunk大小)
+    pub progress_callback:Option<DownloadProgressCallback>,
+}
+
+impl Default for MultiSourceOptions {
+    fn default() -> Self {
+        Self {
+            piece_size: 1024*1024*2,
+            max_parallel: 4,
+            max_retry: 3,
+            max_source_failures: 3,
+            save_progress_interval: 4,
+            progress_callback: None,
+        }
+    }
+}
+
+struct DownloadSources {
+    base_urls:Vec<String>,
+    failures:Vec<AtomicU32>,
+    max_source_failures:u32,
+}
+
+impl DownloadSources {
+    fn new(base_urls:&[String],max_source_failures:u32)->Self {
+        Self {
+            base_urls: base_urls.to_vec(),
+            failures: base_urls.iter().map(|_| AtomicU32::new(0)).collect(),
+            max_source_failures,
+        }
+    }
+
+    //从seed开始轮转,让不同的range分散到不同的源上,失败过多的源排在最后
+    fn select_order(&self,seed:usize)->Vec<usize> {
+        let count = self.base_urls.len();
+        let (mut healthy,bad):(Vec<usize>,Vec<usize>) = (0..count)
+            .map(|i| (seed + i) % count)
+            .partition(|i| self.failures[*i].load(Ordering::Relaxed) < self.max_source_failures);
+        healthy.extend(bad);
+        healthy
+    }
+
+    fn report_result(&self,index:usize,is_ok:bool) {
+        if is_ok {
+            self.failures[index].store(0, Ordering::Relaxed);
+        } else {
+            self.failures[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
 pub struct NdnGetChunkResult {
     pub chunk_id : ChunkId,
     pub chunk_size : u64,
@@ -69,6 +145,10 @@ impl NdnClient {
     }
 
     pub fn gen_chunk_url(&self,chunk_id:&ChunkId,base_url:Option<String>)->String {
+        self.gen_obj_url(&chunk_id.to_obj_id(),base_url)
+    }
+
+    pub fn gen_obj_url(&self,obj_id:&ObjId,base_url:Option<String>)->String {
         let real_base_url;
         if base_url.is_some() {
             real_base_url = base_url.unwrap();
@@ -77,9 +157,9 @@ impl NdnClient {
         }
         let result;
         if self.obj_id_in_host {
-            result = format!("{}.{}",chunk_id.to_base32(),real_base_url);
+            result = format!("{}.{}",obj_id.to_base32(),real_base_url);
         } else {
-            result = format!("{}/{}",real_base_url,chunk_id.to_base32());
+            result = format!("{}/{}",real_base_url,obj_id.to_base32());
         }
         //去掉多余的/
         let result = result.replace("//", "/");
@@ -137,12 +217,38 @@ impl NdnClient {
         }
     }       
 
+    //查询远端chunk的状态和已经上传的长度
+    pub async fn query_chunk_upload_offset(&self,chunk_url:&str)->NdnResult<(ChunkState,u64)> {
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()
+            .map_err(|e| NdnError::Internal(format!("Failed to create client: {}", e)))?;
+
+        let head_res = client.head(chunk_url)
+            .send()
+            .await
+            .map_err(|e| NdnError::RemoteError(format!("HEAD request failed: {}", e)))?;
+        match head_res.status() {
+            StatusCode::OK => Ok((ChunkState::Completed,0)),
+            StatusCode::NOT_FOUND => Ok((ChunkState::NotExist,0)),
+            StatusCode::PARTIAL_CONTENT | StatusCode::CREATED => {
+                let progress = head_res.headers().get("cyfs-chunk-progress")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("");
+                Ok((ChunkState::Incompleted,get_chunk_progress_pos(progress)))
+            },
+            _ => Err(NdnError::RemoteError(format!("HEAD request failed: {}", head_res.status()))),
+        }
+    }
+
+    //上传chunk,支持断点续传:先查询远端已经收到的长度,再分段上传剩余部分,失败后重新查询位置重试
     pub async fn push_chunk(&self,chunk_id:ChunkId,target_url:Option<String>)->NdnResult<()> {
         let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(self.default_ndn_mgr_id.as_deref()).await
             .ok_or_else(|| NdnError::Internal("No named data manager available".to_string()))?;
         let real_named_mgr = named_mgr.lock().await;
-        let (mut chunk_reader,len) = real_named_mgr.open_chunk_reader_impl(&chunk_id,SeekFrom::Start(0),false).await?;
+        let (chunk_reader,len) = real_named_mgr.open_chunk_reader_impl(&chunk_id,SeekFrom::Start(0),false).await?;
         debug!("push_chunk:local chunk_reader open success");
+        drop(chunk_reader);
         drop(real_named_mgr);
         
         let chunk_url;
@@ -152,38 +258,222 @@ impl NdnClient {
             chunk_url = self.gen_chunk_url(&chunk_id, None);
         }
 
-        let chunk_state = self.query_chunk_state(chunk_id,Some(chunk_url.clone())).await?;
+        let (chunk_state,mut offset) = self.query_chunk_upload_offset(&chunk_url).await?;
         if chunk_state == ChunkState::Completed {
             info!("push_chunk:remote chunk already exists, skip");
             return Ok(());
         }
-
-        if chunk_state != ChunkState::NotExist {
-            return Err(NdnError::InvalidId("invlaid remote chunk state".to_string()));
+        if offset > len {
+            offset = 0;
+        }
+        if offset > 0 {
+            info!("push_chunk:resume upload {} from {}", chunk_id.to_string(), offset);
         }
 
-        let mut client = Client::builder()
+        let client = Client::builder()
             .timeout(std::time::Duration::from_secs(30))
             .build()
             .map_err(|e| NdnError::Internal(format!("Failed to create client: {}", e)))?;
 
-        let stream = tokio_util::io::ReaderStream::new(chunk_reader);
-        info!("SEND PUT chunk request, chunk_url:{}",chunk_url);
-        let res = client.put(chunk_url.clone())
+        let mut retry_count = 0;
+        loop {
+            let end = if len == 0 { 0 } else { (offset + PUSH_CHUNK_PIECE_SIZE).min(len) };
+            let result = self.put_chunk_range(&client, &named_mgr, &chunk_id, &chunk_url, offset..end, len).await;
+            match result {
+                Ok((status, remote_offset)) => {
+                    if status == StatusCode::OK {
+                        return Ok(());
+                    }
+                    if status == StatusCode::CONFLICT {
+                        //另一个写入者正在上传这个chunk,等待后重新查询远端位置
+                        retry_count += 1;
+                        if retry_count > PUSH_CHUNK_MAX_RETRY {
+                            return Err(NdnError::RemoteError(format!("chunk {} is being written by another writer", chunk_id.to_string())));
+                        }
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                        let (chunk_state,remote_offset) = self.query_chunk_upload_offset(&chunk_url).await?;
+                        if chunk_state == ChunkState::Completed {
+                            return Ok(());
+                        }
+                        offset = remote_offset.min(len);
+                        continue;
+                    }
+                    if status == StatusCode::RANGE_NOT_SATISFIABLE || status == StatusCode::ACCEPTED {
+                        if status == StatusCode::RANGE_NOT_SATISFIABLE {
+                            info!("push_chunk:remote offset of {} is {}", chunk_id.to_string(), remote_offset);
+                            retry_count += 1;
+                        }
+                        if remote_offset > len {
+                            return Err(NdnError::RemoteError(format!("invalid remote offset {} for {}", remote_offset, chunk_url)));
+                        }
+                        offset = remote_offset;
+                    } else {
+                        return Err(NdnError::RemoteError(format!("HTTP error: {} for {}", status, chunk_url)));
+                    }
+                },
+                Err(e) => {
+                    warn!("push_chunk:put {} range {}-{} failed: {}", chunk_id.to_string(), offset, end, e);
+                    retry_count += 1;
+                    if retry_count > PUSH_CHUNK_MAX_RETRY {
+                        return Err(e);
+                    }
+                    let (chunk_state,remote_offset) = self.query_chunk_upload_offset(&chunk_url).await?;
+                    if chunk_state == ChunkState::Completed {
+                        return Ok(());
+                    }
+                    offset = remote_offset.min(len);
+                    continue;
+                }
+            }
+            if retry_count > PUSH_CHUNK_MAX_RETRY {
+                return Err(NdnError::RemoteError(format!("push chunk {} failed, too many retries", chunk_id.to_string())));
+            }
+        }
+    }
+
+    //返回(http status,远端当前的位置)
+    async fn put_chunk_range(&self,client:&Client,named_mgr:&Arc<tokio::sync::Mutex<NamedDataMgr>>,chunk_id:&ChunkId,
+        chunk_url:&str,range:Range<u64>,chunk_size:u64)->NdnResult<(StatusCode,u64)> {
+        let real_named_mgr = named_mgr.lock().await;
+        let (chunk_reader,_) = real_named_mgr.open_chunk_reader_impl(chunk_id,SeekFrom::Start(range.start),false).await?;
+        drop(real_named_mgr);
+
+        let stream = tokio_util::io::ReaderStream::new(chunk_reader.take(range.end - range.start));
+        info!("SEND PUT chunk request, chunk_url:{} range:{:?}",chunk_url,range);
+        let mut req = client.put(chunk_url)
             .header("Content-Type", "application/octet-stream")
-            .header("cyfs-chunk-size", len.to_string())
-            .body(Body::wrap_stream(stream))
+            .header("cyfs-chunk-size", chunk_size.to_string());
+        if range.end > range.start {
+            req = req.header(reqwest::header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, chunk_size));
+        }
+        let res = req.body(Body::wrap_stream(stream))
             .send()
             .await
             .map_err(|e| NdnError::RemoteError(format!("Request failed: {}", e)))?;
 
+        let status = res.status();
+        if !status.is_success() && status != StatusCode::RANGE_NOT_SATISFIABLE && status != StatusCode::CONFLICT {
+            return Err(NdnError::RemoteError(format!("HTTP error: {} for {}", status, chunk_url)));
+        }
+        let remote_offset = res.headers().get(CYFS_CHUNK_OFFSET_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(range.end);
+        Ok((status,remote_offset))
+    }
+
+    fn gen_batch_url(&self,batch_path:&str,base_url:Option<String>)->String {
+        let real_base_url;
+        if base_url.is_some() {
+            real_base_url = base_url.unwrap();
+        } else {
+            real_base_url = self.default_remote_url.as_ref().unwrap().clone();
+        }
+        format!("{}/{}",real_base_url.trim_end_matches('/'),batch_path)
+    }
+
+    //一次请求查询多个chunk在远端的状态
+    pub async fn query_chunk_state_list(&self,chunk_list:&Vec<ChunkId>,base_url:Option<String>)->NdnResult<Vec<(ChunkId,ChunkState,u64)>> {
+        let url = self.gen_batch_url(CYFS_CHUNK_STATE_LIST_PATH, base_url);
+        let req = ChunkStateListReq {
+            chunk_list: chunk_list.iter().map(|chunk_id| chunk_id.to_string()).collect(),
+        };
+
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()
+            .map_err(|e| NdnError::Internal(format!("Failed to create client: {}", e)))?;
+        let res = client.post(&url)
+            .json(&req)
+            .send()
+            .await
+            .map_err(|e| NdnError::RemoteError(format!("Request failed: {}", e)))?;
         if !res.status().is_success() {
-            return Err(NdnError::RemoteError(
-                format!("HTTP error: {} for {}", res.status(), chunk_url)
-            ));
+            return Err(NdnError::RemoteError(format!("HTTP error: {} for {}", res.status(), url)));
         }
-        
-        Ok(())
+
+        let resp: ChunkStateListResp = res.json().await
+            .map_err(|e| NdnError::DecodeError(format!("decode chunk state list failed: {}", e)))?;
+        if resp.chunk_list.len() != chunk_list.len() {
+            return Err(NdnError::RemoteError(format!("chunk state list size not match, expect {} got {}", chunk_list.len(), resp.chunk_list.len())));
+        }
+
+        let mut result = Vec::with_capacity(resp.chunk_list.len());
+        for (chunk_id, item) in chunk_list.iter().zip(resp.chunk_list.into_iter()) {
+            if item.chunk_id != chunk_id.to_string() {
+                return Err(NdnError::RemoteError(format!("chunk state list order not match: {}", item.chunk_id)));
+            }
+            result.push((chunk_id.clone(), ChunkState::from_str(item.chunk_state.as_str()), item.chunk_size));
+        }
+        Ok(result)
+    }
+
+    //返回远端没有完整保存的chunk
+    pub async fn query_missing_chunks(&self,chunk_list:&Vec<ChunkId>,base_url:Option<String>)->NdnResult<Vec<ChunkId>> {
+        let state_list = self.query_chunk_state_list(chunk_list, base_url).await?;
+        let missing_chunks = state_list.into_iter()
+            .filter(|(_, chunk_state, _)| *chunk_state != ChunkState::Completed)
+            .map(|(chunk_id, _, _)| chunk_id)
+            .collect();
+        Ok(missing_chunks)
+    }
+
+    //把一组(小)chunk一次性推送到远端,远端已经存在的chunk会被跳过
+    pub async fn push_chunk_list(&self,chunk_list:&Vec<ChunkId>,base_url:Option<String>)->NdnResult<usize> {
+        let missing_chunks = self.query_missing_chunks(chunk_list, base_url.clone()).await?;
+        if missing_chunks.is_empty() {
+            info!("push_chunk_list: remote already has all chunks, skip");
+            return Ok(0);
+        }
+
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(self.default_ndn_mgr_id.as_deref()).await
+            .ok_or_else(|| NdnError::Internal("No named data manager available".to_string()))?;
+        //按服务端的body大小上限分批推送
+        let mut batch_list = vec![HashMap::new()];
+        let mut batch_size = 0;
+        {
+            let real_named_mgr = named_mgr.lock().await;
+            for chunk_id in missing_chunks.iter() {
+                let (mut chunk_reader,len) = real_named_mgr.open_chunk_reader_impl(chunk_id,SeekFrom::Start(0),false).await?;
+                let item_size = 2 + chunk_id.to_string().len() as u64 + 8 + len;
+                if item_size > MAX_PUT_CHUNK_LIST_BODY_SIZE {
+                    return Err(NdnError::InvalidParam(format!("chunk {} is too large for chunk list, use push_chunk", chunk_id.to_string())));
+                }
+                if batch_size + item_size > MAX_PUT_CHUNK_LIST_BODY_SIZE {
+                    batch_list.push(HashMap::new());
+                    batch_size = 0;
+                }
+                let mut buffer = Vec::with_capacity(len as usize);
+                chunk_reader.read_to_end(&mut buffer).await.map_err(|e| {
+                    warn!("push_chunk_list: read chunk {} failed! {}", chunk_id.to_string(), e);
+                    NdnError::IoError(e.to_string())
+                })?;
+                batch_list.last_mut().unwrap().insert(chunk_id.clone(), buffer);
+                batch_size += item_size;
+            }
+        }
+
+        let url = self.gen_batch_url(CYFS_PUT_CHUNK_LIST_PATH, base_url);
+        let client = Client::builder()
+            .timeout(std::time::Duration::from_secs(120))
+            .build()
+            .map_err(|e| NdnError::Internal(format!("Failed to create client: {}", e)))?;
+        let mut push_count = 0;
+        for chunk_datas in batch_list.iter() {
+            let body = encode_chunk_list_body(chunk_datas);
+            info!("SEND POST chunk list request, url:{}, chunk count:{}", url, chunk_datas.len());
+            let res = client.post(&url)
+                .header("Content-Type", "application/octet-stream")
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| NdnError::RemoteError(format!("Request failed: {}", e)))?;
+            if !res.status().is_success() {
+                return Err(NdnError::RemoteError(format!("HTTP error: {} for {}", res.status(), url)));
+            }
+            push_count += chunk_datas.len();
+        }
+        Ok(push_count)
     }
 
     //helper function
@@ -345,8 +635,11 @@ impl NdnClient {
             .build()
             .map_err(|e| NdnError::Internal(format!("Failed to create client: {}", e)))?;
         
-        let res = client.get(chunk_url)
-            .send()
+        let mut req = client.get(chunk_url);
+        if let Some(range) = range.as_ref() {
+            req = req.header(reqwest::header::RANGE, format!("bytes={}-{}", range.start, range.end - 1));
+        }
+        let res = req.send()
             .await
             .map_err(|e| NdnError::RemoteError(format!("Request failed: {}", e)))?;
         
@@ -361,6 +654,11 @@ impl NdnClient {
             return Err(NdnError::RemoteError(format!("content length not found for {}", chunk_url)));
         }
         let content_length = content_length.unwrap();
+        if let Some(range) = range.as_ref() {
+            //请求了range时,服务端必须返回206和匹配的Content-Range,否则会把错误位置的数据当作range数据
+            NdnClient::check_range_response(&res, range, content_length)
+                .map_err(|e| NdnError::RemoteError(format!("{} for {}", e, chunk_url)))?;
+        }
 
         let stream = res.bytes_stream().map(|r| {
             r.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("Stream error: {}", e)))
@@ -494,6 +792,62 @@ impl NdnClient {
         Ok((chunk_id, chunk_size))
     }
 
+    //分块文件按chunk顺序下载,每个chunk校验通过后才写入本地文件
+    async fn download_chunked_fileobj_to_local(&self,file_obj:&FileObject,local_path:&PathBuf) -> NdnResult<()> {
+        let (mut mtree,chunk_list) = self.open_chunked_fileobj(file_obj, None).await?;
+        if let Some(parent) = local_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| NdnError::IoError(format!("Failed to create directory: {}", e)))?;
+        }
+        let mut file = tokio::fs::File::create(local_path)
+            .await
+            .map_err(|e| NdnError::IoError(format!("Failed to create file: {}", e)))?;
+        for (chunk_index, chunk_id) in chunk_list.iter().enumerate() {
+            let chunk_data = self.get_verified_chunk(&mut mtree, chunk_index as u64, chunk_id, None).await?;
+            file.write_all(&chunk_data).await
+                .map_err(|e| NdnError::IoError(format!("Failed to write file: {}", e)))?;
+        }
+        file.flush().await
+            .map_err(|e| NdnError::IoError(format!("Failed to write file: {}", e)))?;
+        info!("download chunked fileobj {} to {:?} OK, chunk count:{}", file_obj.name, local_path, chunk_list.len());
+        Ok(())
+    }
+
+    //CDC分块文件按chunk_list顺序下载,每个chunk校验hash和大小,最后用content校验整个文件
+    async fn download_cdc_fileobj_to_local(&self,file_obj:&FileObject,local_path:&PathBuf) -> NdnResult<()> {
+        let chunk_list = file_obj.get_cdc_chunk_list()?;
+        let content_chunk_id = ChunkId::new(file_obj.content.as_str())?;
+        if let Some(parent) = local_path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| NdnError::IoError(format!("Failed to create directory: {}", e)))?;
+        }
+        let mut file = tokio::fs::File::create(local_path)
+            .await
+            .map_err(|e| NdnError::IoError(format!("Failed to create file: {}", e)))?;
+        let mut file_hasher = ChunkHasher::new(Some(content_chunk_id.hash_type.as_str()))?;
+        for (chunk_id, chunk_size) in chunk_list.iter() {
+            let chunk_url = self.gen_chunk_url(chunk_id, None);
+            let (mut reader,_) = self.open_chunk_reader_by_url(chunk_url.as_str(), Some(chunk_id.clone()), None).await?;
+            let mut chunk_data = Vec::with_capacity(*chunk_size as usize);
+            reader.read_to_end(&mut chunk_data).await
+                .map_err(|e| NdnError::IoError(format!("read chunk {} failed: {}", chunk_id.to_string(), e)))?;
+            let mut hasher = ChunkHasher::new(Some(chunk_id.hash_type.as_str()))?;
+            if chunk_data.len() as u64 != *chunk_size || !chunk_id.verify_chunk(&hasher.calc_from_bytes(&chunk_data)) {
+                return Err(NdnError::VerifyError(format!("chunk {} verify failed", chunk_id.to_string())));
+            }
+            file_hasher.update_from_bytes(&chunk_data);
+            file.write_all(&chunk_data).await
+                .map_err(|e| NdnError::IoError(format!("Failed to write file: {}", e)))?;
+        }
+        file.flush().await
+            .map_err(|e| NdnError::IoError(format!("Failed to write file: {}", e)))?;
+        if file_hasher.finalize_chunk_id() != content_chunk_id {
+            return Err(NdnError::VerifyError(format!("fileobj {} content verify failed", file_obj.name)));
+        }
+        info!("download cdc fileobj {} to {:?} OK, chunk count:{}", file_obj.name, local_path, chunk_list.len());
+        Ok(())
+    }
+
     //使用这种模式是发布方承诺用 R-Link发布FileObject,用O-Link发布chunk的模式
     //返回下载成功的FileObj和obj_id，下载成功后named mgr中chunk存在于cache中
     pub async fn download_fileobj_to_local(&self,fileobj_url:&str,local_path:&PathBuf,no_verify:Option<bool>) -> NdnResult<(ObjId,FileObject)> {
@@ -503,6 +857,15 @@ impl NdnClient {
         let file_obj: FileObject = serde_json::from_value(file_obj_json)
             .map_err(|e| NdnError::Internal(format!("Failed to parse FileObject: {}", e)))?;
         
+        if file_obj.is_chunked() {
+            self.download_chunked_fileobj_to_local(&file_obj, local_path).await?;
+            return Ok((obj_id, file_obj));
+        }
+        if file_obj.is_cdc_chunked() {
+            self.download_cdc_fileobj_to_local(&file_obj, local_path).await?;
+            return Ok((obj_id, file_obj));
+        }
+
         // 2. 得到fileobj的content chunkid
         let content_chunk_id = ChunkId::new(file_obj.content.as_str())
             .map_err(|e| NdnError::Internal(format!("Failed to parse content chunk id: {}", e)))?;
@@ -525,6 +888,81 @@ impl NdnClient {
         Ok((obj_id, file_obj))
     }
 
+    //得到分块文件的mtree:mtree的编码保存为命名对象,先用obj id校验数据,再校验root hash与mtree obj id一致
+    pub async fn get_mtree_by_obj_id(&self,mtree_body_obj_id:&ObjId,mtree_obj_id:&ObjId,base_url:Option<String>) -> NdnResult<MerkleTreeObject> {
+        let mtree_url = self.gen_obj_url(mtree_body_obj_id, base_url);
+        let (_, mtree_json) = self.get_obj_by_url(mtree_url.as_str(), Some(mtree_body_obj_id.clone())).await?;
+        let mtree_body = MerkleTreeObject::decode_body_from_obj_json(&mtree_json)?;
+
+        //不需要在这里校验整棵树,每个chunk下载后会用proof path校验到root hash
+        let mtree = MerkleTreeObject::load_from_body(mtree_body, false).await?;
+        if mtree.get_obj_id() != *mtree_obj_id {
+            return Err(NdnError::VerifyError(format!("mtree root hash not match, known:{} remote:{}",
+                mtree_obj_id.to_string(),mtree.get_obj_id().to_string())));
+        }
+        Ok(mtree)
+    }
+
+    //准备下载分块文件:得到chunk列表和已经校验过root hash的mtree
+    pub async fn open_chunked_fileobj(&self,file_obj:&FileObject,base_url:Option<String>) -> NdnResult<(MerkleTreeObject,Vec<ChunkId>)> {
+        if !file_obj.is_chunked() {
+            return Err(NdnError::InvalidObjType(format!("fileobj {} is not chunked",file_obj.name)));
+        }
+        let chunk_list = file_obj.get_fixed_chunk_list()?;
+        let mtree_obj_id = ObjId::new(file_obj.content.as_str())?;
+        let mtree_body_obj_id = file_obj.get_mtree_body_obj_id()?;
+        let mtree = self.get_mtree_by_obj_id(&mtree_body_obj_id, &mtree_obj_id, base_url).await?;
+        if mtree.get_leaf_count() != chunk_list.len() as u64 || mtree.get_data_size() != file_obj.size {
+            return Err(NdnError::VerifyError(format!("fileobj {} chunk list not match mtree {}",
+                file_obj.name,mtree_obj_id.to_string())));
+        }
+        Ok((mtree,chunk_list))
+    }
+
+    //下载分块文件中的一个chunk,校验chunk hash并用proof path校验到mtree的root hash
+    pub async fn get_verified_chunk(&self,mtree:&mut MerkleTreeObject,chunk_index:u64,chunk_id:&ChunkId,base_url:Option<String>) -> NdnResult<Vec<u8>> {
+        let chunk_url = self.gen_chunk_url(chunk_id, base_url);
+        let (mut reader,_) = self.open_chunk_reader_by_url(chunk_url.as_str(), Some(chunk_id.clone()), None).await?;
+        let mut chunk_data = Vec::new();
+        reader.read_to_end(&mut chunk_data).await
+            .map_err(|e| NdnError::IoError(format!("read chunk {} failed: {}", chunk_id.to_string(), e)))?;
+
+        let mut hasher = ChunkHasher::new(Some(chunk_id.hash_type.as_str()))?;
+        let hash_result = hasher.calc_from_bytes(&chunk_data);
+        if !chunk_id.verify_chunk(&hash_result) {
+            return Err(NdnError::VerifyError(format!("chunk {} hash not match", chunk_id.to_string())));
+        }
+        if !mtree.verify_leaf_hash(chunk_index, &hash_result).await? {
+            return Err(NdnError::VerifyError(format!("chunk {} (index:{}) not match mtree {}",
+                chunk_id.to_string(), chunk_index, mtree.get_obj_id().to_string())));
+        }
+        Ok(chunk_data)
+    }
+
+    //下载分块文件的一个范围,只下载和校验范围覆盖的chunk
+    pub async fn download_fileobj_range(&self,fileobj_url:&str,range:Range<u64>) -> NdnResult<Vec<u8>> {
+        let (_, file_obj_json) = self.get_obj_by_url(fileobj_url, None).await?;
+        let file_obj: FileObject = serde_json::from_value(file_obj_json)
+            .map_err(|e| NdnError::Internal(format!("Failed to parse FileObject: {}", e)))?;
+        if range.start >= range.end || range.end > file_obj.size {
+            return Err(NdnError::InvalidParam(format!("invalid range {:?}, file size:{}", range, file_obj.size)));
+        }
+
+        let (mut mtree,chunk_list) = self.open_chunked_fileobj(&file_obj, None).await?;
+        let chunk_size = mtree.get_leaf_size();
+        let first_index = range.start / chunk_size;
+        let last_index = (range.end - 1) / chunk_size;
+        let mut result = Vec::with_capacity((range.end - range.start) as usize);
+        for chunk_index in first_index..=last_index {
+            let chunk_data = self.get_verified_chunk(&mut mtree, chunk_index, &chunk_list[chunk_index as usize], None).await?;
+            let chunk_start = chunk_index * chunk_size;
+            let begin = range.start.max(chunk_start) - chunk_start;
+            let end = range.end.min(chunk_start + chunk_data.len() as u64) - chunk_start;
+            result.extend_from_slice(&chunk_data[begin as usize..end as usize]);
+        }
+        Ok(result)
+    }
+
     pub async fn local_is_better(&self,url:&str,local_path:&PathBuf) -> NdnResult<bool> {
         // 1. 通过url下载fileojbect对象
         // 2. 计算本地文件的hash 
@@ -545,8 +983,6 @@ impl NdnClient {
         let (obj_id, file_obj_json) = self.get_obj_by_url(url, None).await?;
         let file_obj: FileObject = serde_json::from_value(file_obj_json)
             .map_err(|e| NdnError::Internal(format!("Failed to parse FileObject: {}", e)))?;
-        let content_chunk_id = ChunkId::new(file_obj.content.as_str())
-            .map_err(|e| NdnError::Internal(format!("Failed to parse content chunk id: {}", e)))?;
 
         let local_fileobj_file = PathBuf::from(format!("{}.fileobj",local_path.to_string_lossy()));
         if local_fileobj_file.exists() {
@@ -567,15 +1003,49 @@ impl NdnClient {
 
         info!("start calculate hash!");
 
-        let mut hasher = ChunkHasher::new(None)
+        //分块文件的content不是chunkid,需要按chunk_list逐个chunk比较
+        if file_obj.is_chunked() {
+            let (mtree,chunk_list) = self.open_chunked_fileobj(&file_obj, None).await?;
+            let chunk_size = mtree.get_leaf_size();
+            let last_chunk_size = file_obj.size - chunk_size * (chunk_list.len() as u64).saturating_sub(1);
+            let chunk_count = chunk_list.len();
+            let chunk_list:Vec<(ChunkId,u64)> = chunk_list.into_iter().enumerate()
+                .map(|(index,chunk_id)| (chunk_id, if index + 1 == chunk_count { last_chunk_size } else { chunk_size }))
+                .collect();
+            return Self::local_file_match_chunk_list(&mut file, &chunk_list).await;
+        }
+        if file_obj.is_cdc_chunked() {
+            let chunk_list = file_obj.get_cdc_chunk_list()?;
+            return Self::local_file_match_chunk_list(&mut file, &chunk_list).await;
+        }
+
+        let content_chunk_id = ChunkId::new(file_obj.content.as_str())
+            .map_err(|e| NdnError::Internal(format!("Failed to parse content chunk id: {}", e)))?;
+        let mut hasher = ChunkHasher::new(Some(content_chunk_id.hash_type.as_str()))
             .map_err(|e| NdnError::Internal(format!("Failed to create chunk hasher: {}", e)))?;
-        let (hash_result,_) = hasher.calc_from_reader(&mut file).await
+        let (file_chunk_id,_) = hasher.calc_chunk_id_from_reader(&mut file).await
             .map_err(|e| NdnError::Internal(format!("Failed to calculate hash: {}", e)))?;
-        let file_chunk_id = ChunkId::from_sha256_result(&hash_result);
  
         Ok(file_chunk_id == content_chunk_id)
     }
 
+    //按chunk_list的顺序和大小读取本地文件,每个chunk的hash都一致才认为本地文件和fileobj一致
+    async fn local_file_match_chunk_list(file:&mut tokio::fs::File,chunk_list:&[(ChunkId,u64)]) -> NdnResult<bool> {
+        for (chunk_id, chunk_size) in chunk_list.iter() {
+            let mut chunk_data = vec![0u8; *chunk_size as usize];
+            if file.read_exact(&mut chunk_data).await.is_err() {
+                info!("local_is_better: local file is shorter than chunk list");
+                return Ok(false);
+            }
+            let mut hasher = ChunkHasher::new(Some(chunk_id.hash_type.as_str()))?;
+            if !chunk_id.verify_chunk(&hasher.calc_from_bytes(&chunk_data)) {
+                info!("local_is_better: chunk {} not match", chunk_id.to_string());
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
     pub async fn pull_chunk_by_url(&self, chunk_url:String,chunk_id:ChunkId,mgr_id:Option<&str>)->NdnResult<u64> {
         let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(mgr_id).await;
         if named_mgr.is_none() {
@@ -606,6 +1076,10 @@ impl NdnClient {
                 }
                 let (mut _reader,resp_headers) = open_result.unwrap();
                 chunk_size = resp_headers.obj_size.unwrap();
+                if chunk_id.get_length().is_some_and(|length| length != chunk_size) {
+                    warn!("pull_chunk: chunk size {} not match chunk id {}",chunk_size,chunk_id.to_string());
+                    return Err(NdnError::VerifyError(format!("chunk size {} not match chunk id {}",chunk_size,chunk_id.to_string())));
+                }
                 reader = Some(_reader);
             },
             _ => {
@@ -681,6 +1155,197 @@ impl NdnClient {
     }
 
     
+    fn check_range_response(res:&reqwest::Response,range:&Range<u64>,content_length:u64)->NdnResult<()> {
+        if res.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(NdnError::RemoteError(format!("expect 206 for range {:?}, got {}", range, res.status())));
+        }
+        let content_range = res.headers().get(reqwest::header::CONTENT_RANGE)
+            .and_then(|content_range| content_range.to_str().ok())
+            .ok_or_else(|| NdnError::RemoteError(format!("no content range for range {:?}", range)))?;
+        let (start,end,total) = parse_content_range(content_range)?;
+        if start != range.start || end + 1 != range.end || content_length != range.end - range.start {
+            return Err(NdnError::RemoteError(format!("content range {} not match range {:?}", content_range, range)));
+        }
+        if let Some(obj_size) = res.headers().get("cyfs-obj-size")
+            .and_then(|obj_size| obj_size.to_str().ok())
+            .and_then(|obj_size| obj_size.parse::<u64>().ok()) {
+            if obj_size != total {
+                return Err(NdnError::RemoteError(format!("content range {} not match obj size {}", content_range, obj_size)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn fetch_chunk_range(&self,chunk_url:&str,chunk_id:&ChunkId,range:Range<u64>)->NdnResult<Vec<u8>> {
+        let (mut reader,_) = self.open_chunk_reader_by_url(chunk_url, Some(chunk_id.clone()), Some(range.clone())).await?;
+        let mut buffer = vec![0u8; (range.end - range.start) as usize];
+        reader.read_exact(&mut buffer).await
+            .map_err(|e| NdnError::IoError(format!("read chunk {} range {:?} failed: {}", chunk_id.to_string(), range, e)))?;
+        Ok(buffer)
+    }
+
+    async fn fetch_chunk_range_with_failover(&self,chunk_id:&ChunkId,sources:&DownloadSources,seed:usize,
+        range:Range<u64>,max_retry:u32,limiter:&Semaphore)->NdnResult<Vec<u8>> {
+        let _permit = limiter.acquire().await
+            .map_err(|e| NdnError::Internal(format!("acquire download permit failed: {}", e)))?;
+        let source_order = sources.select_order(seed);
+        let mut last_err = NdnError::NotFound(chunk_id.to_string());
+        for retry in 0..max_retry.max(1) as usize {
+            let source_index = source_order[retry % source_order.len()];
+            let chunk_url = self.gen_chunk_url(chunk_id, Some(sources.base_urls[source_index].clone()));
+            match self.fetch_chunk_range(&chunk_url, chunk_id, range.clone()).await {
+                Ok(data) => {
+                    sources.report_result(source_index, true);
+                    return Ok(data);
+                },
+                Err(e) => {
+                    warn!("fetch chunk range {:?} from {} failed: {}, retry:{}", range, chunk_url, e, retry);
+                    sources.report_result(source_index, false);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+
+    async fn query_chunk_size_from_sources(&self,chunk_id:&ChunkId,sources:&DownloadSources)->NdnResult<u64> {
+        let mut last_err = NdnError::NotFound(chunk_id.to_string());
+        for source_index in sources.select_order(0) {
+            let chunk_url = self.gen_chunk_url(chunk_id, Some(sources.base_urls[source_index].clone()));
+            match self.open_chunk_reader_by_url(&chunk_url, Some(chunk_id.clone()), Some(0..1)).await {
+                Ok((_,resp_headers)) if resp_headers.obj_size.is_some() => {
+                    sources.report_result(source_index, true);
+                    return Ok(resp_headers.obj_size.unwrap());
+                },
+                Ok(_) => {
+                    last_err = NdnError::RemoteError(format!("no chunk size from {}", chunk_url));
+                },
+                Err(e) => {
+                    warn!("query chunk size from {} failed: {}", chunk_url, e);
+                    last_err = e;
+                }
+            }
+            sources.report_result(source_index, false);
+        }
+        Err(last_err)
+    }
+
+    //从多个源并行下载一个chunk的不同range,按顺序写入chunk writer,进度(hasher状态)保存在named mgr中,重启后可以续传
+    //返回本次下载的字节数
+    pub async fn download_chunk_multi_source(&self,chunk_id:&ChunkId,base_urls:&[String],options:&MultiSourceOptions)->NdnResult<u64> {
+        if base_urls.is_empty() {
+            return Err(NdnError::InvalidParam("no download source".to_string()));
+        }
+        let sources = DownloadSources::new(base_urls, options.max_source_failures);
+        let limiter = Semaphore::new(options.max_parallel.max(1));
+        self.download_chunk_with_sources(chunk_id, &sources, options, &limiter).await
+    }
+
+    //并行下载一组chunk,所有chunk共享源的状态和并发限制
+    pub async fn download_chunk_list_multi_source(&self,chunk_list:&[ChunkId],base_urls:&[String],options:&MultiSourceOptions)->NdnResult<u64> {
+        if base_urls.is_empty() {
+            return Err(NdnError::InvalidParam("no download source".to_string()));
+        }
+        let sources = DownloadSources::new(base_urls, options.max_source_failures);
+        let limiter = Semaphore::new(options.max_parallel.max(1));
+        let mut results = futures::stream::iter(chunk_list.iter())
+            .map(|chunk_id| self.download_chunk_with_sources(chunk_id, &sources, options, &limiter))
+            .buffer_unordered(options.max_parallel.max(1));
+        let mut total_download = 0;
+        while let Some(result) = results.next().await {
+            total_download += result?;
+        }
+        Ok(total_download)
+    }
+
+    async fn download_chunk_with_sources(&self,chunk_id:&ChunkId,sources:&DownloadSources,
+        options:&MultiSourceOptions,limiter:&Semaphore)->NdnResult<u64> {
+        let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(self.default_ndn_mgr_id.as_deref()).await
+            .ok_or_else(|| NdnError::Internal("No named data manager available".to_string()))?;
+        let real_named_mgr = named_mgr.lock().await;
+        let (chunk_state,chunk_size,progress) = real_named_mgr.query_chunk_state_impl(chunk_id).await?;
+        drop(real_named_mgr);
+
+        let mut chunk_hasher = None;
+        let mut chunk_size = chunk_size;
+        match chunk_state {
+            ChunkState::Completed => {
+                info!("download_chunk_multi_source: chunk {} already exists", chunk_id.to_string());
+                return Ok(0);
+            },
+            ChunkState::NotExist => {},
+            _ => {
+                chunk_hasher = serde_json::from_str::<serde_json::Value>(&progress).ok()
+                    .and_then(|state| ChunkHasher::restore_from_state(state).ok());
+                if chunk_hasher.is_none() {
+                    //没有保存进度,无法确认已经写入的数据,重新下载
+                    info!("download_chunk_multi_source: chunk {} has no progress, restart", chunk_id.to_string());
+                    named_mgr.lock().await.remove_chunk_impl(chunk_id).await?;
+                }
+            }
+        }
+        if chunk_hasher.is_none() {
+            chunk_size = match chunk_id.get_length() {
+                Some(length) => length,
+                None => self.query_chunk_size_from_sources(chunk_id, sources).await?,
+            };
+        }
+        let mut chunk_hasher = match chunk_hasher {
+            Some(hasher) => hasher,
+            None => ChunkHasher::new(Some(chunk_id.hash_type.as_str()))?,
+        };
+        let start_pos = chunk_hasher.pos;
+        info!("download_chunk_multi_source: chunk {} size:{} start at:{}", chunk_id.to_string(), chunk_size, start_pos);
+
+        let real_named_mgr = named_mgr.lock().await;
+        let (mut chunk_writer,_) = real_named_mgr.open_chunk_writer_impl(chunk_id, chunk_size, start_pos).await?;
+        drop(real_named_mgr);
+
+        let piece_size = options.piece_size.max(1);
+        let mut pieces = Vec::new();
+        let mut pos = start_pos;
+        while pos < chunk_size {
+            let end = (pos + piece_size).min(chunk_size);
+            pieces.push(pos..end);
+            pos = end;
+        }
+
+        //buffered保证结果按piece的顺序返回,这样可以顺序写入并计算hash
+        let mut piece_results = futures::stream::iter(pieces.into_iter().enumerate())
+            .map(|(index,range)| self.fetch_chunk_range_with_failover(chunk_id, sources, index, range, options.max_retry, limiter))
+            .buffered(options.max_parallel.max(1));
+        let mut piece_count:u64 = 0;
+        while let Some(piece_result) = piece_results.next().await {
+            let piece_data = piece_result?;
+            chunk_hasher.update_from_bytes(&piece_data);
+            chunk_writer.write_all(&piece_data).await
+                .map_err(|e| NdnError::IoError(format!("write chunk {} failed: {}", chunk_id.to_string(), e)))?;
+            piece_count += 1;
+            if piece_count.is_multiple_of(options.save_progress_interval.max(1)) {
+                chunk_writer.flush().await
+                    .map_err(|e| NdnError::IoError(format!("flush chunk {} failed: {}", chunk_id.to_string(), e)))?;
+                let real_named_mgr = named_mgr.lock().await;
+                real_named_mgr.update_chunk_progress_impl(chunk_id, chunk_hasher.save_state().to_string()).await?;
+            }
+            if let Some(progress_callback) = options.progress_callback.as_ref() {
+                progress_callback(chunk_id, chunk_hasher.pos, chunk_size);
+            }
+        }
+        drop(piece_results);
+        chunk_writer.shutdown().await
+            .map_err(|e| NdnError::IoError(format!("close chunk {} writer failed: {}", chunk_id.to_string(), e)))?;
+        drop(chunk_writer);
+
+        let download_size = chunk_hasher.pos - start_pos;
+        if chunk_hasher.finalize_chunk_id() != *chunk_id {
+            warn!("download_chunk_multi_source: chunk {} hash not match, remove it", chunk_id.to_string());
+            named_mgr.lock().await.remove_chunk_impl(chunk_id).await?;
+            return Err(NdnError::VerifyError(format!("chunk {} hash not match", chunk_id.to_string())));
+        }
+        named_mgr.lock().await.complete_chunk_writer_impl(chunk_id).await?;
+        Ok(download_size)
+    }
+
     //async fn open_chunk_writer_by_url(&self,chunk_url:String,open_mode:ChunkWriterOpenMode)->NdnResult<(ChunkWriter,Option<ChunkHasher>)> {
     //    unimplemented!()
     //}
@@ -743,6 +1408,7 @@ mod tests {
             local_stores: vec![temp_dir.path().to_str().unwrap().to_string()],
             local_cache: None,
             mmap_cache_dir: None,
+            pub_cdc_options: None,
         };
         
         let named_mgr = NamedDataMgr::from_config(
@@ -791,6 +1457,7 @@ mod tests {
             local_stores: vec![temp_dir.path().to_str().unwrap().to_string()],
             local_cache: None,
             mmap_cache_dir: None,
+            pub_cdc_options: None,
         };    
         let named_mgr2 = NamedDataMgr::from_config(
             Some("test_client".to_string()),
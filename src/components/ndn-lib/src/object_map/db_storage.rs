@@ -0,0 +1,302 @@
+use super::storage::{InnerStorage, InnerStorageStat};
+use crate::mtree::{MtreeReadSeek, MtreeWriteSeek};
+use crate::{NdnError, NdnResult};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::{Path, PathBuf};
+use tokio::sync::Mutex;
+
+// Object map storage on disk, the items and meta are saved in sqlite, and the mtree data is saved
+// in a separate file next to the db file. The mtree is written to a temp file which is synced and
+// renamed over the mtree file on commit, so a crash during flush never leaves a torn mtree file
+pub struct DbStorage {
+    conn: Mutex<Connection>,
+    mtree_path: PathBuf,
+    mtree_tmp_path: PathBuf,
+}
+
+impl DbStorage {
+    pub fn new(db_path: &Path) -> NdnResult<Self> {
+        let conn = Connection::open(db_path).map_err(|e| {
+            let msg = format!("Error opening object map db: {:?}, {}", db_path, e);
+            error!("{}", msg);
+            NdnError::DbError(msg)
+        })?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS items (
+                key TEXT PRIMARY KEY,
+                value BLOB NOT NULL,
+                mtree_index INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS meta (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                value BLOB NOT NULL
+            );",
+        )
+        .map_err(|e| {
+            let msg = format!("Error creating object map tables: {}", e);
+            error!("{}", msg);
+            NdnError::DbError(msg)
+        })?;
+
+        let mut mtree_path = db_path.as_os_str().to_owned();
+        mtree_path.push(".mtree");
+        let mut mtree_tmp_path = mtree_path.clone();
+        mtree_tmp_path.push(".tmp");
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            mtree_path: PathBuf::from(mtree_path),
+            mtree_tmp_path: PathBuf::from(mtree_tmp_path),
+        })
+    }
+
+    fn db_error(action: &str, e: rusqlite::Error) -> NdnError {
+        let msg = format!("Error {} in object map db: {}", action, e);
+        error!("{}", msg);
+        NdnError::DbError(msg)
+    }
+
+    fn io_error(action: &str, e: std::io::Error) -> NdnError {
+        let msg = format!("Error {} object map mtree data: {}", action, e);
+        error!("{}", msg);
+        NdnError::IoError(msg)
+    }
+
+    // Sync the temp mtree file and rename it over the mtree file
+    async fn commit_mtree_tmp_file(&self) -> NdnResult<()> {
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&self.mtree_tmp_path)
+            .await
+            .map_err(|e| Self::io_error("opening", e))?;
+        file.sync_all()
+            .await
+            .map_err(|e| Self::io_error("syncing", e))?;
+        drop(file);
+
+        tokio::fs::rename(&self.mtree_tmp_path, &self.mtree_path)
+            .await
+            .map_err(|e| Self::io_error("renaming", e))?;
+
+        // Sync the parent dir so the rename is durable, not supported on every platform
+        if let Some(dir) = self.mtree_path.parent() {
+            if let Ok(dir) = tokio::fs::File::open(dir).await {
+                let _ = dir.sync_all().await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl InnerStorage for DbStorage {
+    async fn put(&mut self, key: &str, value: &[u8]) -> NdnResult<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT OR REPLACE INTO items (key, value, mtree_index) VALUES (?1, ?2, NULL)",
+            params![key, value],
+        )
+        .map_err(|e| Self::db_error("putting item", e))?;
+
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> NdnResult<Option<(Vec<u8>, Option<u64>)>> {
+        let conn = self.conn.lock().await;
+        conn.query_row(
+            "SELECT value, mtree_index FROM items WHERE key = ?1",
+            params![key],
+            |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<u64>>(1)?)),
+        )
+        .optional()
+        .map_err(|e| Self::db_error("getting item", e))
+    }
+
+    async fn remove(&mut self, key: &str) -> NdnResult<Option<Vec<u8>>> {
+        let conn = self.conn.lock().await;
+        let value = conn
+            .query_row(
+                "SELECT value FROM items WHERE key = ?1",
+                params![key],
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()
+            .map_err(|e| Self::db_error("getting item", e))?;
+
+        if value.is_some() {
+            conn.execute("DELETE FROM items WHERE key = ?1", params![key])
+                .map_err(|e| Self::db_error("removing item", e))?;
+        }
+
+        Ok(value)
+    }
+
+    async fn is_exist(&self, key: &str) -> NdnResult<bool> {
+        let conn = self.conn.lock().await;
+        let ret = conn
+            .query_row("SELECT 1 FROM items WHERE key = ?1", params![key], |_| Ok(()))
+            .optional()
+            .map_err(|e| Self::db_error("checking item", e))?;
+
+        Ok(ret.is_some())
+    }
+
+    async fn list(&self, page_index: usize, page_size: usize) -> NdnResult<Vec<String>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn
+            .prepare("SELECT key FROM items ORDER BY key LIMIT ?1 OFFSET ?2")
+            .map_err(|e| Self::db_error("listing items", e))?;
+        let rows = stmt
+            .query_map(
+                params![page_size as i64, (page_index * page_size) as i64],
+                |row| row.get::<_, String>(0),
+            )
+            .map_err(|e| Self::db_error("listing items", e))?;
+
+        let mut list = Vec::with_capacity(page_size);
+        for key in rows {
+            list.push(key.map_err(|e| Self::db_error("listing items", e))?);
+        }
+
+        Ok(list)
+    }
+
+    async fn stat(&self) -> NdnResult<InnerStorageStat> {
+        let conn = self.conn.lock().await;
+        let total_count = conn
+            .query_row("SELECT COUNT(*) FROM items", [], |row| row.get::<_, u64>(0))
+            .map_err(|e| Self::db_error("counting items", e))?;
+
+        Ok(InnerStorageStat { total_count })
+    }
+
+    async fn put_meta(&mut self, value: &[u8]) -> NdnResult<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT OR REPLACE INTO meta (id, value) VALUES (0, ?1)",
+            params![value],
+        )
+        .map_err(|e| Self::db_error("putting meta", e))?;
+
+        Ok(())
+    }
+
+    async fn get_meta(&self) -> NdnResult<Option<Vec<u8>>> {
+        let conn = self.conn.lock().await;
+        conn.query_row("SELECT value FROM meta WHERE id = 0", [], |row| {
+            row.get::<_, Vec<u8>>(0)
+        })
+        .optional()
+        .map_err(|e| Self::db_error("getting meta", e))
+    }
+
+    async fn update_mtree_index(&mut self, key: &str, index: u64) -> NdnResult<()> {
+        let conn = self.conn.lock().await;
+        let count = conn
+            .execute(
+                "UPDATE items SET mtree_index = ?1 WHERE key = ?2",
+                params![index, key],
+            )
+            .map_err(|e| Self::db_error("updating mtree index", e))?;
+
+        if count == 0 {
+            let msg = format!("No such key: {}", key);
+            return Err(NdnError::NotFound(msg));
+        }
+
+        Ok(())
+    }
+
+    async fn update_mtree_index_list(&mut self, list: &[(String, u64)]) -> NdnResult<()> {
+        let mut conn = self.conn.lock().await;
+        let tx = conn
+            .transaction()
+            .map_err(|e| Self::db_error("updating mtree index", e))?;
+        {
+            let mut stmt = tx
+                .prepare("UPDATE items SET mtree_index = ?1 WHERE key = ?2")
+                .map_err(|e| Self::db_error("updating mtree index", e))?;
+            for (key, index) in list {
+                let count = stmt
+                    .execute(params![index, key])
+                    .map_err(|e| Self::db_error("updating mtree index", e))?;
+                if count == 0 {
+                    let msg = format!("No such key: {}", key);
+                    return Err(NdnError::NotFound(msg));
+                }
+            }
+        }
+        tx.commit()
+            .map_err(|e| Self::db_error("updating mtree index", e))
+    }
+
+    async fn get_mtree_index(&self, key: &str) -> NdnResult<Option<u64>> {
+        let conn = self.conn.lock().await;
+        let ret = conn
+            .query_row(
+                "SELECT mtree_index FROM items WHERE key = ?1",
+                params![key],
+                |row| row.get::<_, Option<u64>>(0),
+            )
+            .optional()
+            .map_err(|e| Self::db_error("getting mtree index", e))?;
+
+        Ok(ret.flatten())
+    }
+
+    async fn put_mtree_data(&mut self, value: &[u8]) -> NdnResult<()> {
+        tokio::fs::write(&self.mtree_tmp_path, value)
+            .await
+            .map_err(|e| Self::io_error("writing", e))?;
+        self.commit_mtree_tmp_file().await
+    }
+
+    async fn load_mtree_data(&self) -> NdnResult<Option<Vec<u8>>> {
+        match tokio::fs::read(&self.mtree_path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Self::io_error("reading", e)),
+        }
+    }
+
+    async fn open_mtree_data_reader(&self) -> NdnResult<Option<Box<dyn MtreeReadSeek>>> {
+        match tokio::fs::File::open(&self.mtree_path).await {
+            Ok(file) => Ok(Some(Box::new(file) as Box<dyn MtreeReadSeek>)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Self::io_error("opening", e)),
+        }
+    }
+
+    async fn open_mtree_data_writer(&mut self, size: Option<u64>) -> NdnResult<Box<dyn MtreeWriteSeek>> {
+        // Always write to the temp file, the current mtree file stays intact until commit
+        let file = match size {
+            Some(size) => {
+                let file = tokio::fs::File::create(&self.mtree_tmp_path)
+                    .await
+                    .map_err(|e| Self::io_error("creating", e))?;
+                file.set_len(size)
+                    .await
+                    .map_err(|e| Self::io_error("resizing", e))?;
+                file
+            }
+            None => {
+                tokio::fs::copy(&self.mtree_path, &self.mtree_tmp_path)
+                    .await
+                    .map_err(|e| Self::io_error("copying", e))?;
+                tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&self.mtree_tmp_path)
+                    .await
+                    .map_err(|e| Self::io_error("opening", e))?
+            }
+        };
+
+        Ok(Box::new(file) as Box<dyn MtreeWriteSeek>)
+    }
+
+    async fn commit_mtree_data(&mut self) -> NdnResult<()> {
+        self.commit_mtree_tmp_file().await
+    }
+}
@@ -0,0 +1,336 @@
+use super::object_map::{ObjectMap, ObjectMapItem};
+use super::storage::InnerStorage;
+use crate::mtree::MerkleTreeObject;
+use crate::{NdnError, NdnResult};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone)]
+pub enum ObjectMapDiffItem {
+    Added(ObjectMapItem),
+    Removed(ObjectMapItem),
+    Changed {
+        old: ObjectMapItem,
+        new: ObjectMapItem,
+    },
+}
+
+impl ObjectMapDiffItem {
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Added(item) => &item.key,
+            Self::Removed(item) => &item.key,
+            Self::Changed { new, .. } => &new.key,
+        }
+    }
+
+    // The item after the change, None if the key is removed
+    pub fn new_item(&self) -> Option<&ObjectMapItem> {
+        match self {
+            Self::Added(item) => Some(item),
+            Self::Removed(_) => None,
+            Self::Changed { new, .. } => Some(new),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjectMapMergeConflict {
+    pub key: String,
+    pub base: Option<ObjectMapItem>,
+    pub ours: Option<ObjectMapItem>,
+    pub theirs: Option<ObjectMapItem>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ObjectMapMergeResolution {
+    UseOurs,
+    UseTheirs,
+    // Use the specified item, None means remove the key
+    Custom(Option<ObjectMapItem>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ObjectMapMergeResult {
+    // Changes from theirs applied to ours without conflict
+    pub applied: Vec<String>,
+    // Keys changed on both sides in different ways, resolved by the callback
+    pub conflicts: Vec<String>,
+}
+
+// Iterate the keys of the storage in order, and can jump to any position
+struct ObjectMapKeyCursor<'a> {
+    storage: &'a dyn InnerStorage,
+    page: Vec<String>,
+    page_start: u64,
+    pos: u64,
+}
+
+impl<'a> ObjectMapKeyCursor<'a> {
+    const PAGE_SIZE: usize = 128;
+
+    fn new(storage: &'a dyn InnerStorage) -> Self {
+        Self {
+            storage,
+            page: Vec::new(),
+            page_start: 0,
+            pos: 0,
+        }
+    }
+
+    async fn current(&mut self) -> NdnResult<Option<String>> {
+        if self.pos < self.page_start || self.pos >= self.page_start + self.page.len() as u64 {
+            let page_index = self.pos as usize / Self::PAGE_SIZE;
+            self.page = self.storage.list(page_index, Self::PAGE_SIZE).await?;
+            self.page_start = (page_index * Self::PAGE_SIZE) as u64;
+        }
+
+        let offset = (self.pos - self.page_start) as usize;
+        Ok(self.page.get(offset).cloned())
+    }
+
+    fn advance(&mut self) {
+        self.pos += 1;
+    }
+
+    fn seek(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+}
+
+impl ObjectMap {
+    // Compare two object maps and return the changes from old to new in key order.
+    // If both maps are flushed, the subtrees with the same hash at the same position in the mtree
+    // are skipped, so maps with few changes can be compared without reading all the items.
+    pub async fn diff(old: &mut ObjectMap, new: &mut ObjectMap) -> NdnResult<Vec<ObjectMapDiffItem>> {
+        let same_method = old.hash_method() == new.hash_method();
+        let same_ranges = match (old.clean_mtree(), new.clean_mtree()) {
+            (Some(old_mtree), Some(new_mtree)) => {
+                if same_method && old_mtree.get_root_hash() == new_mtree.get_root_hash() {
+                    return Ok(vec![]);
+                }
+                Self::find_same_leaf_ranges(old_mtree, new_mtree).await?
+            }
+            _ => vec![],
+        };
+
+        let old: &ObjectMap = old;
+        let new: &ObjectMap = new;
+        let mut old_cursor = ObjectMapKeyCursor::new(old.storage.as_ref());
+        let mut new_cursor = ObjectMapKeyCursor::new(new.storage.as_ref());
+        let mut ranges = same_ranges.iter().peekable();
+        let mut result = Vec::new();
+        loop {
+            // The items at the same position in the same subtree are identical, skip them all
+            if old_cursor.pos == new_cursor.pos {
+                while let Some((_, end)) = ranges.peek() {
+                    if *end <= old_cursor.pos {
+                        ranges.next();
+                    } else {
+                        break;
+                    }
+                }
+                if let Some((start, end)) = ranges.peek() {
+                    if *start <= old_cursor.pos {
+                        old_cursor.seek(*end);
+                        new_cursor.seek(*end);
+                        continue;
+                    }
+                }
+            }
+
+            let old_key = old_cursor.current().await?;
+            let new_key = new_cursor.current().await?;
+            match (old_key, new_key) {
+                (None, None) => break,
+                (Some(old_key), None) => {
+                    result.push(ObjectMapDiffItem::Removed(old.load_item(&old_key).await?));
+                    old_cursor.advance();
+                }
+                (None, Some(new_key)) => {
+                    result.push(ObjectMapDiffItem::Added(new.load_item(&new_key).await?));
+                    new_cursor.advance();
+                }
+                (Some(old_key), Some(new_key)) => {
+                    if old_key < new_key {
+                        result.push(ObjectMapDiffItem::Removed(old.load_item(&old_key).await?));
+                        old_cursor.advance();
+                    } else if old_key > new_key {
+                        result.push(ObjectMapDiffItem::Added(new.load_item(&new_key).await?));
+                        new_cursor.advance();
+                    } else {
+                        let old_item = old.load_item(&old_key).await?;
+                        let new_item = new.load_item(&new_key).await?;
+                        if !Self::is_same_item(&old_item, &new_item)? {
+                            result.push(ObjectMapDiffItem::Changed {
+                                old: old_item,
+                                new: new_item,
+                            });
+                        }
+                        old_cursor.advance();
+                        new_cursor.advance();
+                    }
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Three-way merge, apply the changes from base to theirs into ours. If a key is changed on
+    // both sides in different ways, the resolver is called to decide the result.
+    // The merged map is dirty, call flush to update the mtree.
+    pub async fn merge<F>(
+        base: &mut ObjectMap,
+        ours: &mut ObjectMap,
+        theirs: &mut ObjectMap,
+        mut resolver: F,
+    ) -> NdnResult<ObjectMapMergeResult>
+    where
+        F: FnMut(&ObjectMapMergeConflict) -> NdnResult<ObjectMapMergeResolution>,
+    {
+        let their_changes = Self::diff(base, theirs).await?;
+        if their_changes.is_empty() {
+            return Ok(ObjectMapMergeResult::default());
+        }
+
+        let our_changes: BTreeMap<String, ObjectMapDiffItem> = Self::diff(base, ours)
+            .await?
+            .into_iter()
+            .map(|item| (item.key().to_owned(), item))
+            .collect();
+
+        let mut result = ObjectMapMergeResult::default();
+        for their_change in their_changes {
+            let key = their_change.key().to_owned();
+            let their_item = their_change.new_item();
+            let our_change = our_changes.get(&key);
+            if our_change.is_none() {
+                ours.apply_item(&key, their_item).await?;
+                result.applied.push(key);
+                continue;
+            }
+
+            // Both sides changed the key, it's not a conflict if the results are the same
+            let our_item = our_change.unwrap().new_item();
+            let same = match (our_item, their_item) {
+                (None, None) => true,
+                (Some(our_item), Some(their_item)) => Self::is_same_item(our_item, their_item)?,
+                _ => false,
+            };
+            if same {
+                continue;
+            }
+
+            let conflict = ObjectMapMergeConflict {
+                key: key.clone(),
+                base: base.get_object(&key).await?,
+                ours: our_item.cloned(),
+                theirs: their_item.cloned(),
+            };
+            match resolver(&conflict)? {
+                ObjectMapMergeResolution::UseOurs => {}
+                ObjectMapMergeResolution::UseTheirs => {
+                    ours.apply_item(&key, their_item).await?;
+                }
+                ObjectMapMergeResolution::Custom(item) => {
+                    if let Some(item) = &item {
+                        if item.key != key {
+                            let msg = format!(
+                                "Unmatched key of resolved item: expected {}, got {}",
+                                key, item.key
+                            );
+                            error!("{}", msg);
+                            return Err(NdnError::InvalidParam(msg));
+                        }
+                    }
+                    ours.apply_item(&key, item.as_ref()).await?;
+                }
+            }
+            result.conflicts.push(key);
+        }
+
+        Ok(result)
+    }
+
+    // The mtree can be used to compare only if it matches the items in storage
+    fn clean_mtree(&mut self) -> Option<&mut MerkleTreeObject> {
+        if self.is_dirty {
+            return None;
+        }
+
+        self.mtree.as_mut()
+    }
+
+    async fn load_item(&self, key: &str) -> NdnResult<ObjectMapItem> {
+        self.get_object(key).await?.ok_or_else(|| {
+            let msg = format!("Error getting object map item: {}", key);
+            error!("{}", msg);
+            NdnError::InvalidState(msg)
+        })
+    }
+
+    async fn apply_item(&mut self, key: &str, item: Option<&ObjectMapItem>) -> NdnResult<()> {
+        match item {
+            Some(item) => {
+                self.put_object(key, item.obj_id.clone(), item.meta.clone())
+                    .await
+            }
+            None => {
+                self.remove_object(key).await?;
+                Ok(())
+            }
+        }
+    }
+
+    fn is_same_item(left: &ObjectMapItem, right: &ObjectMapItem) -> NdnResult<bool> {
+        Ok(left.encode()? == right.encode()?)
+    }
+
+    // Walk the two mtrees from top to bottom, return the leaf ranges [start, end) whose subtrees
+    // have the same hash in both mtrees, the ranges are sorted and not overlapped.
+    async fn find_same_leaf_ranges(
+        left: &mut MerkleTreeObject,
+        right: &mut MerkleTreeObject,
+    ) -> NdnResult<Vec<(u64, u64)>> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        if left.get_hash_method() != right.get_hash_method() {
+            return Ok(ranges);
+        }
+
+        // Only the subtrees fully inside both mtrees can be compared, the subtrees at the end
+        // may contain the padding nodes
+        let leaf_count = std::cmp::min(left.get_leaf_count(), right.get_leaf_count());
+        if leaf_count == 0 {
+            return Ok(ranges);
+        }
+
+        let top = std::cmp::min(left.get_total_depth(), right.get_total_depth());
+        let top_count = (leaf_count + (1u64 << top) - 1) >> top;
+        let mut stack: Vec<(u32, u64)> = (0..top_count).rev().map(|index| (top, index)).collect();
+        while let Some((depth, index)) = stack.pop() {
+            let start = index << depth;
+            let end = (index + 1) << depth;
+            if end <= leaf_count {
+                let left_hash = left.get_node_hash(depth, index).await?;
+                let right_hash = right.get_node_hash(depth, index).await?;
+                if left_hash == right_hash {
+                    match ranges.last_mut() {
+                        Some(last) if last.1 == start => last.1 = end,
+                        _ => ranges.push((start, end)),
+                    }
+                    continue;
+                }
+            }
+
+            if depth > 0 {
+                let right_child = (depth - 1, index * 2 + 1);
+                if (right_child.1 << right_child.0) < leaf_count {
+                    stack.push(right_child);
+                }
+                stack.push((depth - 1, index * 2));
+            }
+        }
+
+        Ok(ranges)
+    }
+}
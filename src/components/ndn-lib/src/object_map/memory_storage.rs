@@ -1,6 +1,9 @@
 use super::storage::{InnerStorage, InnerStorageStat};
+use crate::mtree::{MtreeReadSeek, MtreeReadWriteSeekWithSharedBuffer, MtreeWriteSeek, SharedBuffer};
 use crate::{NdnError, NdnResult};
 use std::collections::BTreeMap;
+use std::io::SeekFrom;
+use tokio::io::AsyncSeekExt;
 
 struct MemoryStorageItem {
     value: Vec<u8>,
@@ -10,7 +13,7 @@ struct MemoryStorageItem {
 pub struct MemoryStorage {
     storage: BTreeMap<String, MemoryStorageItem>,
     meta: Option<Vec<u8>>,
-    mtree_data: Option<Vec<u8>>,
+    mtree_data: Option<MtreeReadWriteSeekWithSharedBuffer>,
 }
 
 impl MemoryStorage {
@@ -21,6 +24,20 @@ impl MemoryStorage {
             mtree_data: None,
         }
     }
+
+    // The cloned streams share the same position, so rewind it before handing out
+    async fn rewind(
+        stream: &MtreeReadWriteSeekWithSharedBuffer,
+    ) -> NdnResult<MtreeReadWriteSeekWithSharedBuffer> {
+        let mut stream = stream.clone();
+        stream.seek(SeekFrom::Start(0)).await.map_err(|e| {
+            let msg = format!("Error seeking to start: {}", e);
+            error!("{}", msg);
+            NdnError::IoError(msg)
+        })?;
+
+        Ok(stream)
+    }
 }
 
 #[async_trait::async_trait]
@@ -105,11 +122,38 @@ impl InnerStorage for MemoryStorage {
     }
 
     async fn put_mtree_data(&mut self, value: &[u8]) -> NdnResult<()> {
-        self.mtree_data = Some(value.to_vec());
+        let buf = SharedBuffer::from_data(value.to_vec());
+        self.mtree_data = Some(MtreeReadWriteSeekWithSharedBuffer::new(buf));
         Ok(())
     }
 
     async fn load_mtree_data(&self) -> NdnResult<Option<Vec<u8>>> {
-        Ok(self.mtree_data.clone())
+        Ok(self
+            .mtree_data
+            .as_ref()
+            .map(|stream| stream.buffer().lock().unwrap().data().clone()))
+    }
+
+    async fn open_mtree_data_reader(&self) -> NdnResult<Option<Box<dyn MtreeReadSeek>>> {
+        match self.mtree_data.as_ref() {
+            Some(stream) => Ok(Some(Box::new(Self::rewind(stream).await?) as Box<dyn MtreeReadSeek>)),
+            None => Ok(None),
+        }
+    }
+
+    async fn open_mtree_data_writer(&mut self, size: Option<u64>) -> NdnResult<Box<dyn MtreeWriteSeek>> {
+        if let Some(size) = size {
+            let buf = SharedBuffer::with_size(size as usize);
+            self.mtree_data = Some(MtreeReadWriteSeekWithSharedBuffer::new(buf));
+        }
+
+        match self.mtree_data.as_ref() {
+            Some(stream) => Ok(Box::new(Self::rewind(stream).await?) as Box<dyn MtreeWriteSeek>),
+            None => {
+                let msg = "Mtree data is not found".to_string();
+                error!("{}", msg);
+                Err(NdnError::NotFound(msg))
+            }
+        }
     }
 }
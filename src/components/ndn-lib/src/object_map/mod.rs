@@ -1,10 +1,14 @@
 mod object_map;
 mod storage;
 mod memory_storage;
+mod db_storage;
+mod diff;
 
 pub use object_map::*;
 pub use storage::*;
 pub use memory_storage::*;
+pub use db_storage::*;
+pub use diff::*;
 
 #[cfg(test)]
 mod test;
\ No newline at end of file
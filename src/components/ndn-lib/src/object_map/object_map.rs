@@ -12,9 +12,10 @@ use crate::{MerkleTreeProofPathVerifier, OBJ_TYPE_OBJMAPT};
 use core::hash;
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::collections::VecDeque;
 use std::io::SeekFrom;
+use std::str::FromStr;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -54,6 +55,107 @@ impl ObjectMapItem {
     }
 }
 
+// Each item takes one leaf of the mtree
+const OBJECT_MAP_LEAF_SIZE: u64 = 256;
+
+// The canonical body of an object map saved in the named data store. The items are kept in leaf
+// index order, so the obj id (the mtree root hash) can be recalculated from the stored data, and
+// gc can find the referenced objects without opening the inner storage of the map
+#[derive(Debug, Clone)]
+pub struct ObjectMapBody {
+    pub hash_method: HashMethod,
+    pub items: Vec<ObjectMapItem>,
+}
+
+impl ObjectMapBody {
+    pub fn encode(&self) -> serde_json::Value {
+        let items: Vec<serde_json::Value> = self
+            .items
+            .iter()
+            .map(|item| {
+                let mut value = serde_json::json!({
+                    "key": item.key,
+                    "obj_id": item.obj_id.to_string(),
+                });
+                if let Some(meta) = item.meta.as_ref() {
+                    value["meta"] = serde_json::Value::String(hex::encode(meta));
+                }
+                value
+            })
+            .collect();
+
+        serde_json::json!({
+            "hash_method": self.hash_method.as_str(),
+            "items": items,
+        })
+    }
+
+    pub fn decode(obj_json: &serde_json::Value) -> NdnResult<Self> {
+        let invalid = |msg: String| {
+            error!("{}", msg);
+            NdnError::InvalidData(msg)
+        };
+
+        let hash_method = obj_json["hash_method"]
+            .as_str()
+            .ok_or_else(|| invalid("Invalid object map body: no hash_method".to_string()))?;
+        let hash_method = HashMethod::from_str(hash_method)?;
+        let list = obj_json["items"]
+            .as_array()
+            .ok_or_else(|| invalid("Invalid object map body: no items".to_string()))?;
+
+        let mut items = Vec::with_capacity(list.len());
+        for value in list {
+            let key = value["key"]
+                .as_str()
+                .ok_or_else(|| invalid(format!("Invalid object map item: {}", value)))?;
+            let obj_id = value["obj_id"]
+                .as_str()
+                .ok_or_else(|| invalid(format!("Invalid object map item: {}", value)))?;
+            let obj_id = ObjId::new(obj_id)?;
+            let meta = match value.get("meta") {
+                Some(meta) => {
+                    let meta = meta
+                        .as_str()
+                        .ok_or_else(|| invalid(format!("Invalid object map item meta: {}", key)))?;
+                    Some(hex::decode(meta).map_err(|e| {
+                        invalid(format!("Invalid object map item meta: {}, {}", key, e))
+                    })?)
+                }
+                None => None,
+            };
+            items.push(ObjectMapItem::new(key, obj_id, meta));
+        }
+
+        Ok(Self { hash_method, items })
+    }
+
+    // Rebuild the mtree from the items and return the obj id, used to verify the stored body
+    pub async fn calc_obj_id(&self) -> NdnResult<ObjId> {
+        if self.items.is_empty() {
+            let msg = "Empty object map has no obj id".to_string();
+            error!("{}", msg);
+            return Err(NdnError::InvalidData(msg));
+        }
+
+        let mut leaf_hashes = Vec::with_capacity(self.items.len());
+        for item in self.items.iter() {
+            leaf_hashes.push(HashHelper::calc_hash(self.hash_method, &item.encode()?));
+        }
+
+        let data_size = self.items.len() as u64 * OBJECT_MAP_LEAF_SIZE;
+        let (root_hash, _) = MerkleTreeObject::build_body_from_leaf_hashes(
+            data_size,
+            OBJECT_MAP_LEAF_SIZE,
+            Some(self.hash_method),
+            &leaf_hashes,
+        )
+        .await?;
+
+        Ok(ObjId::new_by_raw(OBJ_TYPE_OBJMAPT.to_owned(), root_hash))
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ObjectMapMeta {
     // Default is Sha256
@@ -71,6 +173,12 @@ pub struct ObjectMap {
     pub is_dirty: bool,
     pub storage: Box<dyn InnerStorage>,
     pub mtree: Option<MerkleTreeObject>,
+
+    // Keys are added or removed since the last flush, the leaf indexes are changed and the
+    // whole mtree must be regenerated
+    layout_changed: bool,
+    // Keys whose value is updated in place since the last flush, key -> leaf index
+    updated_leaves: HashMap<String, u64>,
 }
 
 impl ObjectMap {
@@ -94,11 +202,14 @@ impl ObjectMap {
             is_dirty: false,
             storage,
             mtree: None,
+            layout_changed: false,
+            updated_leaves: HashMap::new(),
         })
     }
 
-    // Load object map from storage
-    pub async fn load(storage: Box<dyn InnerStorage>) -> NdnResult<Self> {
+    // Load object map from storage, if verify is true, the mtree data and the leaf hash of each
+    // item will be checked, and the mtree will be regenerated on next flush if check failed
+    pub async fn load(storage: Box<dyn InnerStorage>, verify: bool) -> NdnResult<Self> {
         // First load meta from storage
         let ret = storage.get_meta().await.map_err(|e| {
             let msg = format!("Error getting object map meta: {}", e);
@@ -119,26 +230,25 @@ impl ObjectMap {
         })?;
 
         // Try load mtree data from storage
-        let ret = storage.load_mtree_data().await.map_err(|e| {
+        let ret = storage.open_mtree_data_reader().await.map_err(|e| {
             let msg = format!("Error loading mtree data: {}", e);
             error!("{}", msg);
             e
         })?;
 
-        let mtree = if let Some(data) = ret {
-            let stream = std::io::Cursor::new(data);
-            let reader = Box::new(stream) as Box<dyn MtreeReadSeek>;
-
-            // TODO should we verify the mtree data on loading? and if error happens, should we regenerate the mtree or return error?
-            let mtree = MerkleTreeObject::load_from_reader(reader, false)
-                .await
-                .map_err(|e| {
+        let mtree = if let Some(reader) = ret {
+            match MerkleTreeObject::load_from_reader(reader, verify).await {
+                Ok(mtree) => Some(mtree),
+                Err(e) if verify => {
+                    warn!("Invalid mtree data, will regenerate on flush: {}", e);
+                    None
+                }
+                Err(e) => {
                     let msg = format!("Error loading mtree object: {}", e);
                     error!("{}", msg);
-                    e
-                })?;
-
-            Some(mtree)
+                    return Err(e);
+                }
+            }
         } else {
             None
         };
@@ -148,15 +258,73 @@ impl ObjectMap {
             is_dirty: false,
             storage,
             mtree,
+            layout_changed: false,
+            updated_leaves: HashMap::new(),
         };
 
+        if verify && map.mtree.is_some() {
+            if let Err(e) = map.verify_mtree().await {
+                warn!("Verify object map mtree failed, will regenerate on flush: {}", e);
+                map.mtree = None;
+            }
+        }
+
         if map.mtree.is_none() {
             map.is_dirty = true;
+            map.layout_changed = true;
         }
 
         Ok(map)
     }
 
+    // Check every item is in the mtree with the right leaf index and leaf hash
+    async fn verify_mtree(&mut self) -> NdnResult<()> {
+        let count = self.storage.stat().await?.total_count;
+        let leaf_count = self.mtree.as_ref().unwrap().get_leaf_count();
+        if count != leaf_count {
+            let msg = format!(
+                "Unmatched mtree leaf count: expected {}, got {}",
+                count, leaf_count
+            );
+            return Err(NdnError::InvalidData(msg));
+        }
+
+        let mut page_index = 0;
+        let page_size = 128;
+        let mut leaf_index = 0;
+        loop {
+            let list = self.storage.list(page_index, page_size).await?;
+            if list.is_empty() {
+                break;
+            }
+            page_index += 1;
+
+            for key in list {
+                let (item, index) = self.get_object_inner(&key).await?.ok_or_else(|| {
+                    NdnError::InvalidState(format!("Error getting object map item: {}", key))
+                })?;
+                if index != Some(leaf_index) {
+                    let msg = format!(
+                        "Unmatched mtree index: {}, expected {}, got {:?}",
+                        key, leaf_index, index
+                    );
+                    return Err(NdnError::InvalidData(msg));
+                }
+
+                let hash = HashHelper::calc_hash(self.meta.hash_method, &item.encode()?);
+                let mtree = self.mtree.as_mut().unwrap();
+                if !mtree.verify_leaf_hash(leaf_index, &hash).await? {
+                    let msg = format!("Unmatched mtree leaf hash: {}, {}", key, leaf_index);
+                    return Err(NdnError::InvalidData(msg));
+                }
+
+                leaf_index += 1;
+            }
+        }
+
+        Ok(())
+    }
+
     // If mtree exists, return the current objid, otherwise return None
     // If mtree is dirty, then should call flush to regenerate the mtree first
     pub fn gen_obj_id(&self) -> Option<ObjId> {
@@ -184,12 +352,31 @@ impl ObjectMap {
         let item = ObjectMapItem::new(key.to_owned(), obj_id, meta);
         let data = item.encode()?;
 
+        // If the key is already in the mtree, the leaf index will not change, so only the leaf
+        // hash need to be updated on flush
+        let leaf_index = if self.mtree.is_some() && !self.layout_changed {
+            self.storage.get_mtree_index(key).await?
+        } else {
+            None
+        };
+
         self.storage.put(&key, &data).await.map_err(|e| {
             let msg = format!("Error putting object map item: {}", e);
             error!("{}", msg);
             e
         })?;
 
+        match leaf_index {
+            Some(index) => {
+                // Storage will reset the mtree index on put, so restore it here
+                self.storage.update_mtree_index(key, index).await?;
+                self.updated_leaves.insert(key.to_owned(), index);
+            }
+            None => {
+                self.layout_changed = true;
+            }
+        }
+
         self.is_dirty = true;
 
         Ok(())
@@ -289,6 +476,7 @@ impl ObjectMap {
         }
 
         self.is_dirty = true;
+        self.layout_changed = true;
 
         let item = ObjectMapItem::decode(&ret.unwrap()).map_err(|e| {
             let msg = format!("Error decoding object map item: {}, {}", key, e);
@@ -309,6 +497,66 @@ impl ObjectMap {
         Ok(ret)
     }
 
+    // List all the object ids referenced by the map, used by gc to mark the reachable objects
+    pub async fn list_obj_ids(&self) -> NdnResult<Vec<ObjId>> {
+        let mut result = Vec::new();
+        let mut page_index = 0;
+        let page_size = 128;
+        loop {
+            let list = self.storage.list(page_index, page_size).await?;
+            if list.is_empty() {
+                break;
+            }
+            page_index += 1;
+
+            for key in list {
+                if let Some(item) = self.get_object(&key).await? {
+                    result.push(item.obj_id);
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Generate the canonical body to save the map in the named data store, the map must be flushed
+    pub async fn gen_body(&self) -> NdnResult<ObjectMapBody> {
+        if self.is_dirty || self.mtree.is_none() {
+            let msg = "Object map must be flushed before generating the body".to_string();
+            error!("{}", msg);
+            return Err(NdnError::InvalidState(msg));
+        }
+
+        let mut list = Vec::new();
+        let mut page_index = 0;
+        let page_size = 128;
+        loop {
+            let keys = self.storage.list(page_index, page_size).await?;
+            if keys.is_empty() {
+                break;
+            }
+            page_index += 1;
+
+            for key in keys {
+                let ret = self.get_object_inner(&key).await?;
+                match ret {
+                    Some((item, Some(index))) => list.push((index, item)),
+                    _ => {
+                        let msg = format!("Object map item has no mtree index: {}", key);
+                        error!("{}", msg);
+                        return Err(NdnError::InvalidState(msg));
+                    }
+                }
+            }
+        }
+
+        list.sort_by_key(|(index, _)| *index);
+        Ok(ObjectMapBody {
+            hash_method: self.meta.hash_method,
+            items: list.into_iter().map(|(_, item)| item).collect(),
+        })
+    }
+
     pub fn is_dirty(&self) -> bool {
         self.is_dirty
     }
@@ -316,18 +564,16 @@ impl ObjectMap {
     // Regenerate the merkle tree without checking the dirty flag
     pub async fn regenerate_merkle_tree(&mut self) -> NdnResult<()> {
         let count = self.storage.stat().await?.total_count;
-        let leaf_size = 256u64;
+        let leaf_size = OBJECT_MAP_LEAF_SIZE;
         let data_size = count as u64 * leaf_size;
 
-        // TODO now use the memory buffer to store the merkle tree, need to optimize for further usage
+        // The mtree is written to the storage directly, so a large map will not be held in memory
         let buf_size = MerkleTreeObjectGenerator::estimate_output_bytes(
             data_size,
             leaf_size,
             Some(self.meta.hash_method),
         );
-        let buf = SharedBuffer::with_size(buf_size as usize);
-        let stream = MtreeReadWriteSeekWithSharedBuffer::new(buf);
-        let mtree_writer = Box::new(stream.clone()) as Box<dyn MtreeWriteSeek>;
+        let mtree_writer = self.storage.open_mtree_data_writer(Some(buf_size)).await?;
 
         let mut mtree_generator = MerkleTreeObjectGenerator::new(
             data_size,
@@ -347,6 +593,7 @@ impl ObjectMap {
             }
             page_index += 1;
 
+            let mut index_list = Vec::with_capacity(list.len());
             for key in list {
                 let item = self.get_object(&key).await?;
                 if item.is_none() {
@@ -365,34 +612,36 @@ impl ObjectMap {
                         error!("{}", msg);
                         e
                     })?;
-                
-                // Update the mtree index in storage
-                self.storage
-                    .update_mtree_index(&key, leaf_index)
-                    .await
-                    .map_err(|e| {
-                        let msg = format!("Error updating mtree index: {}, {}, {}", key, leaf_index, e);
-                        error!("{}", msg);
-                        e
-                    })?;
 
+                index_list.push((key, leaf_index));
                 leaf_index += 1;
             }
+
+            // Update the mtree index in storage
+            self.storage
+                .update_mtree_index_list(&index_list)
+                .await
+                .map_err(|e| {
+                    let msg = format!("Error updating mtree index: {}", e);
+                    error!("{}", msg);
+                    e
+                })?;
         }
 
         let root_hash = mtree_generator.finalize().await?;
         info!("Regenerated merkle tree root hash: {:?}", root_hash);
 
-        // Create the merkle tree object from the stream
-        let mut stream = stream.clone();
-        stream.seek(SeekFrom::Start(0)).await.map_err(|e| {
-            let msg = format!("Error seeking to start: {}", e);
+        let mut writer = mtree_generator.into_writer();
+        writer.flush().await.map_err(|e| {
+            let msg = format!("Error flushing mtree data: {}", e);
             error!("{}", msg);
             NdnError::IoError(msg)
         })?;
+        drop(writer);
+        self.storage.commit_mtree_data().await?;
 
-        let reader = Box::new(stream) as Box<dyn MtreeReadSeek>;
-        let object = MerkleTreeObject::load_from_reader(reader, true).await?;
+        // Create the merkle tree object from the storage
+        let object = self.load_mtree_from_storage(true).await?;
         let root_hash1 = object.get_root_hash();
         assert_eq!(root_hash, root_hash1);
 
@@ -402,14 +651,61 @@ impl ObjectMap {
         Ok(())
     }
 
+    // Recalculate only the leaves of the updated items and their paths to the root
+    async fn update_merkle_tree(&mut self) -> NdnResult<()> {
+        let mut leaf_hashes = BTreeMap::new();
+        for (key, index) in self.updated_leaves.iter() {
+            let item = self.get_object(key).await?.ok_or_else(|| {
+                let msg = format!("Error getting object map item: {}", key);
+                error!("{}", msg);
+                NdnError::InvalidState(msg)
+            })?;
+
+            let hash = HashHelper::calc_hash(self.meta.hash_method, &item.encode()?);
+            leaf_hashes.insert(*index, hash);
+        }
+
+        let reader = self.storage.open_mtree_data_reader().await?.ok_or_else(|| {
+            let msg = "Mtree data is not found".to_string();
+            error!("{}", msg);
+            NdnError::InvalidState(msg)
+        })?;
+        let writer = self.storage.open_mtree_data_writer(None).await?;
+        let root_hash = MerkleTreeObject::update_leaf_hashes(reader, writer, &leaf_hashes).await?;
+        self.storage.commit_mtree_data().await?;
+        info!("Updated merkle tree root hash: {:?}, leaves={}", root_hash, leaf_hashes.len());
+
+        let object = self.load_mtree_from_storage(false).await?;
+        assert_eq!(root_hash, object.get_root_hash());
+        self.mtree = Some(object);
+
+        Ok(())
+    }
+
+    async fn load_mtree_from_storage(&self, verify: bool) -> NdnResult<MerkleTreeObject> {
+        let reader = self.storage.open_mtree_data_reader().await?.ok_or_else(|| {
+            let msg = "Mtree data is not found".to_string();
+            error!("{}", msg);
+            NdnError::InvalidState(msg)
+        })?;
+
+        MerkleTreeObject::load_from_reader(reader, verify).await
+    }
+
     pub async fn flush(&mut self) -> NdnResult<()> {
         if !self.is_dirty && self.mtree.is_some() {
             return Ok(());
         }
 
-        self.regenerate_merkle_tree().await?;
+        if self.mtree.is_some() && !self.layout_changed && !self.updated_leaves.is_empty() {
+            self.update_merkle_tree().await?;
+        } else {
+            self.regenerate_merkle_tree().await?;
+        }
 
         self.is_dirty = false;
+        self.layout_changed = false;
+        self.updated_leaves.clear();
 
         Ok(())
     }
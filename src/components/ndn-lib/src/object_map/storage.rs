@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use crate::mtree::{MtreeReadSeek, MtreeWriteSeek};
 use crate::NdnResult;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +27,28 @@ pub trait InnerStorage: Send + Sync {
     async fn get_mtree_index(&self, key: &str) -> NdnResult<Option<u64>>;
     async fn put_mtree_data(&mut self, value: &[u8]) -> NdnResult<()>;
     async fn load_mtree_data(&self) -> NdnResult<Option<Vec<u8>>>;
+
+    // Update the mtree index of multiple keys, storage can override it to update in one transaction
+    async fn update_mtree_index_list(&mut self, list: &[(String, u64)]) -> NdnResult<()> {
+        for (key, index) in list {
+            self.update_mtree_index(key, *index).await?;
+        }
+        Ok(())
+    }
+
+    // Open the stored mtree data as stream, so the mtree of a large map can be read and
+    // updated in place without loading the whole data into memory
+    async fn open_mtree_data_reader(&self) -> NdnResult<Option<Box<dyn MtreeReadSeek>>>;
+
+    // If size is set, the mtree data will be reset to the size with all zero, otherwise open the
+    // existing mtree data for update. The written data is not visible to the reader until
+    // commit_mtree_data is called, storage may write to a staging copy until then
+    async fn open_mtree_data_writer(&mut self, size: Option<u64>) -> NdnResult<Box<dyn MtreeWriteSeek>>;
+
+    // Make the data written by the last opened mtree writer the current mtree data
+    async fn commit_mtree_data(&mut self) -> NdnResult<()> {
+        Ok(())
+    }
 }
 
 
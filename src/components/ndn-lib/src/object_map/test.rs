@@ -1,9 +1,11 @@
+use super::db_storage::DbStorage;
 use super::memory_storage::MemoryStorage;
 use super::*;
 use crate::hash::HashHelper;
 use crate::{HashMethod, ObjId, OBJ_TYPE_FILE};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
+use tokio::io::AsyncWriteExt;
 use tokio::test;
 
 fn generate_random_buf(seed: &str, len: usize) -> Vec<u8> {
@@ -71,3 +73,239 @@ async fn test_object_map() {
         }
     }
 }
+
+async fn put_test_objects(obj_map: &mut ObjectMap, count: usize, tag: &str) {
+    for i in 0..count {
+        let key = format!("key{}", i);
+        let hash = generate_random_buf(&format!("{}{}", tag, i), HashMethod::Sha256.hash_bytes());
+        let obj_id = ObjId::new_by_raw(OBJ_TYPE_FILE.to_owned(), hash);
+        obj_map.put_object(&key, obj_id, None).await.unwrap();
+    }
+}
+
+async fn check_proofs(obj_map: &mut ObjectMap, keys: &[String]) {
+    let objid = obj_map.gen_obj_id().unwrap();
+    let verifier = ObjectMapProofVerifier::new(obj_map.hash_method());
+    for key in keys {
+        let proof = obj_map.get_object_proof_path(key).await.unwrap().unwrap();
+        assert!(verifier.verify(&objid, &proof).unwrap());
+    }
+}
+
+async fn test_incremental_update(storage: Box<dyn InnerStorage>) {
+    let mut obj_map = ObjectMap::new(HashMethod::Sha256, storage).await.unwrap();
+
+    // Odd leaf count, so the last leaf is duplicated on each level
+    let count = 1001;
+    put_test_objects(&mut obj_map, count, "v1").await;
+    obj_map.flush().await.unwrap();
+
+    // Update some existing keys, include the last one in the mtree
+    let mut updated = vec![];
+    for i in [0, 1, 77, 500, 998, 999] {
+        let key = format!("key{}", i);
+        let hash = generate_random_buf(&format!("v2{}", i), HashMethod::Sha256.hash_bytes());
+        let obj_id = ObjId::new_by_raw(OBJ_TYPE_FILE.to_owned(), hash);
+        obj_map.put_object(&key, obj_id, Some(vec![1, 2, 3])).await.unwrap();
+        updated.push(key);
+    }
+    assert!(obj_map.is_dirty());
+    obj_map.flush().await.unwrap();
+    let incremental_root = obj_map.get_root_hash().unwrap();
+    check_proofs(&mut obj_map, &updated).await;
+
+    // The incremental result must be the same as regenerating the whole mtree
+    obj_map.regenerate_merkle_tree().await.unwrap();
+    assert_eq!(obj_map.get_root_hash().unwrap(), incremental_root);
+
+    // Adding a new key changes the layout and goes through the full regeneration
+    let obj_id = ObjId::new_by_raw(OBJ_TYPE_FILE.to_owned(), vec![0u8; 32]);
+    obj_map.put_object("key_new", obj_id, None).await.unwrap();
+    obj_map.flush().await.unwrap();
+    assert_ne!(obj_map.get_root_hash().unwrap(), incremental_root);
+    check_proofs(&mut obj_map, &["key_new".to_string(), "key999".to_string()]).await;
+}
+
+#[test]
+async fn test_object_map_incremental_mtree() {
+    test_incremental_update(Box::new(MemoryStorage::new())).await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let storage = DbStorage::new(&dir.path().join("objmap.db")).unwrap();
+    test_incremental_update(Box::new(storage)).await;
+}
+
+#[test]
+async fn test_object_map_load_verify() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("objmap.db");
+
+    let storage = Box::new(DbStorage::new(&db_path).unwrap()) as Box<dyn InnerStorage>;
+    let mut obj_map = ObjectMap::new(HashMethod::Sha256, storage).await.unwrap();
+    put_test_objects(&mut obj_map, 333, "v1").await;
+    obj_map.flush().await.unwrap();
+    let root_hash = obj_map.get_root_hash().unwrap();
+    drop(obj_map);
+
+    // Reload with verify
+    let storage = Box::new(DbStorage::new(&db_path).unwrap()) as Box<dyn InnerStorage>;
+    let mut obj_map = ObjectMap::load(storage, true).await.unwrap();
+    assert!(!obj_map.is_dirty());
+    assert_eq!(obj_map.get_root_hash().unwrap(), root_hash);
+    check_proofs(&mut obj_map, &["key0".to_string(), "key332".to_string()]).await;
+    drop(obj_map);
+
+    // Corrupt the mtree data, the mtree will be regenerated on flush
+    let mtree_path = dir.path().join("objmap.db.mtree");
+    let mut data = std::fs::read(&mtree_path).unwrap();
+    let len = data.len();
+    data[len - 40] ^= 0xff;
+    std::fs::write(&mtree_path, data).unwrap();
+
+    let storage = Box::new(DbStorage::new(&db_path).unwrap()) as Box<dyn InnerStorage>;
+    let mut obj_map = ObjectMap::load(storage, true).await.unwrap();
+    assert!(obj_map.is_dirty());
+    obj_map.flush().await.unwrap();
+    assert_eq!(obj_map.get_root_hash().unwrap(), root_hash);
+}
+
+fn test_obj_id(tag: &str) -> ObjId {
+    let hash = generate_random_buf(tag, HashMethod::Sha256.hash_bytes());
+    ObjId::new_by_raw(OBJ_TYPE_FILE.to_owned(), hash)
+}
+
+async fn new_test_map(items: &[(String, String)]) -> ObjectMap {
+    let storage = Box::new(MemoryStorage::new()) as Box<dyn InnerStorage>;
+    let mut obj_map = ObjectMap::new(HashMethod::Sha256, storage).await.unwrap();
+    for (key, tag) in items {
+        obj_map.put_object(key, test_obj_id(tag), None).await.unwrap();
+    }
+    obj_map.flush().await.unwrap();
+    obj_map
+}
+
+#[test]
+async fn test_object_map_diff() {
+    let base_items: Vec<(String, String)> = (0..500)
+        .map(|i| (format!("key{:04}", i), format!("v1{}", i)))
+        .collect();
+    let mut base = new_test_map(&base_items).await;
+
+    // Same content, no changes
+    let mut same = new_test_map(&base_items).await;
+    assert!(ObjectMap::diff(&mut base, &mut same).await.unwrap().is_empty());
+
+    let mut new_items = base_items.clone();
+    new_items[10].1 = "v2".to_string();
+    new_items[300].1 = "v2".to_string();
+    new_items.remove(499);
+    new_items.push(("key0500".to_string(), "v2".to_string()));
+    let mut new = new_test_map(&new_items).await;
+
+    let diff = ObjectMap::diff(&mut base, &mut new).await.unwrap();
+    let keys: Vec<&str> = diff.iter().map(|item| item.key()).collect();
+    assert_eq!(keys, vec!["key0010", "key0300", "key0499", "key0500"]);
+    assert!(matches!(diff[0], ObjectMapDiffItem::Changed { .. }));
+    assert!(matches!(diff[1], ObjectMapDiffItem::Changed { .. }));
+    assert!(matches!(diff[2], ObjectMapDiffItem::Removed(_)));
+    assert!(matches!(diff[3], ObjectMapDiffItem::Added(_)));
+
+    // Insert at the front shifts all the leaves, and the dirty map has no mtree to compare
+    let mut shifted = new_test_map(&base_items).await;
+    shifted.put_object("key", test_obj_id("v2"), None).await.unwrap();
+    let diff = ObjectMap::diff(&mut base, &mut shifted).await.unwrap();
+    assert_eq!(diff.len(), 1);
+    assert_eq!(diff[0].key(), "key");
+    shifted.flush().await.unwrap();
+    let diff = ObjectMap::diff(&mut shifted, &mut base).await.unwrap();
+    assert_eq!(diff.len(), 1);
+    assert!(matches!(diff[0], ObjectMapDiffItem::Removed(_)));
+}
+
+#[test]
+async fn test_object_map_merge() {
+    let base_items: Vec<(String, String)> = (0..200)
+        .map(|i| (format!("key{:04}", i), format!("v1{}", i)))
+        .collect();
+    let mut base = new_test_map(&base_items).await;
+
+    // Ours: change key1 and key2, remove key3
+    let mut our_items = base_items.clone();
+    our_items[1].1 = "ours".to_string();
+    our_items[2].1 = "same".to_string();
+    our_items.remove(3);
+    let mut ours = new_test_map(&our_items).await;
+
+    // Theirs: change key1 and key2, change key3, remove key4, add key_new
+    let mut their_items = base_items.clone();
+    their_items[1].1 = "theirs".to_string();
+    their_items[2].1 = "same".to_string();
+    their_items[3].1 = "theirs".to_string();
+    their_items.remove(4);
+    their_items.push(("key_new".to_string(), "theirs".to_string()));
+    let mut theirs = new_test_map(&their_items).await;
+
+    let mut conflicts = vec![];
+    let result = ObjectMap::merge(&mut base, &mut ours, &mut theirs, |conflict| {
+        conflicts.push(conflict.clone());
+        if conflict.key == "key0001" {
+            Ok(ObjectMapMergeResolution::UseTheirs)
+        } else {
+            Ok(ObjectMapMergeResolution::UseOurs)
+        }
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(result.applied, vec!["key0004", "key_new"]);
+    assert_eq!(result.conflicts, vec!["key0001", "key0003"]);
+    assert!(conflicts[1].ours.is_none());
+    assert!(conflicts[1].theirs.is_some());
+    assert!(conflicts[1].base.is_some());
+
+    let item = ours.get_object("key0001").await.unwrap().unwrap();
+    assert_eq!(item.obj_id, test_obj_id("theirs"));
+    assert!(ours.get_object("key0003").await.unwrap().is_none());
+    assert!(ours.get_object("key0004").await.unwrap().is_none());
+    assert!(ours.get_object("key_new").await.unwrap().is_some());
+
+    ours.flush().await.unwrap();
+    let diff = ObjectMap::diff(&mut theirs, &mut ours).await.unwrap();
+    let keys: Vec<&str> = diff.iter().map(|item| item.key()).collect();
+    assert_eq!(keys, vec!["key0003"]);
+}
+
+#[test]
+async fn test_object_map_mtree_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("objmap.db");
+    let mtree_path = dir.path().join("objmap.db.mtree");
+
+    let storage = Box::new(DbStorage::new(&db_path).unwrap()) as Box<dyn InnerStorage>;
+    let mut obj_map = ObjectMap::new(HashMethod::Sha256, storage).await.unwrap();
+    put_test_objects(&mut obj_map, 100, "v1").await;
+    obj_map.flush().await.unwrap();
+    let root_hash = obj_map.get_root_hash().unwrap();
+    let mtree_data = std::fs::read(&mtree_path).unwrap();
+    drop(obj_map);
+
+    // An interrupted write only touches the temp file, the mtree file is kept intact
+    let mut storage = DbStorage::new(&db_path).unwrap();
+    let mut writer = storage.open_mtree_data_writer(None).await.unwrap();
+    writer.write_all(&[0xffu8; 64]).await.unwrap();
+    writer.flush().await.unwrap();
+    drop(writer);
+    assert_eq!(std::fs::read(&mtree_path).unwrap(), mtree_data);
+
+    let mut obj_map = ObjectMap::load(Box::new(storage), true).await.unwrap();
+    assert!(!obj_map.is_dirty());
+    assert_eq!(obj_map.get_root_hash().unwrap(), root_hash);
+
+    // The incremental update is visible after flush and no temp file is left
+    let obj_id = ObjId::new_by_raw(OBJ_TYPE_FILE.to_owned(), vec![1u8; 32]);
+    obj_map.put_object("key7", obj_id, None).await.unwrap();
+    obj_map.flush().await.unwrap();
+    assert_ne!(obj_map.get_root_hash().unwrap(), root_hash);
+    assert_ne!(std::fs::read(&mtree_path).unwrap(), mtree_data);
+    assert!(!dir.path().join("objmap.db.mtree.tmp").exists());
+}
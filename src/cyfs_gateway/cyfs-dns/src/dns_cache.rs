@@ -0,0 +1,183 @@
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{Record, RecordType};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// 缓存的最大TTL,避免上游返回过大的TTL导致记录长期不更新
+const MAX_CACHE_TTL: u32 = 86400;
+const DEFAULT_MAX_CACHE_ITEMS: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct DnsCacheAnswer {
+    pub response_code: ResponseCode,
+    pub answers: Vec<Record>,
+    pub name_servers: Vec<Record>,
+}
+
+struct DnsCacheItem {
+    answer: DnsCacheAnswer,
+    insert_at: Instant,
+    expire_at: Instant,
+}
+
+// 按(name,record_type)缓存应答,resolver_chain和fallback共用,返回时会减去已经缓存的时间
+pub struct DnsCache {
+    items: Mutex<HashMap<(String, RecordType), DnsCacheItem>>,
+    max_items: usize,
+}
+
+impl DnsCache {
+    pub fn new(max_items: Option<usize>) -> Self {
+        Self {
+            items: Mutex::new(HashMap::new()),
+            max_items: max_items.unwrap_or(DEFAULT_MAX_CACHE_ITEMS),
+        }
+    }
+
+    fn cache_key(name: &str, record_type: RecordType) -> (String, RecordType) {
+        (name.trim_end_matches('.').to_lowercase(), record_type)
+    }
+
+    // NoError的应答使用answers中最小的TTL,NXDomain/NoData使用authority中SOA的TTL
+    fn calc_ttl(answer: &DnsCacheAnswer) -> Option<u32> {
+        let ttl = match answer.response_code {
+            ResponseCode::NoError if !answer.answers.is_empty() => {
+                answer.answers.iter().map(|record| record.ttl()).min()
+            }
+            ResponseCode::NoError | ResponseCode::NXDomain => answer
+                .name_servers
+                .iter()
+                .filter(|record| record.record_type() == RecordType::SOA)
+                .map(|record| record.ttl())
+                .min(),
+            _ => None,
+        }?;
+
+        if ttl == 0 {
+            return None;
+        }
+        Some(ttl.min(MAX_CACHE_TTL))
+    }
+
+    pub fn get(&self, name: &str, record_type: RecordType) -> Option<DnsCacheAnswer> {
+        let key = Self::cache_key(name, record_type);
+        let mut items = self.items.lock().unwrap();
+        let item = items.get(&key)?;
+
+        let now = Instant::now();
+        if item.expire_at <= now {
+            items.remove(&key);
+            return None;
+        }
+
+        let elapsed = now.duration_since(item.insert_at).as_secs() as u32;
+        let mut answer = item.answer.clone();
+        for record in answer
+            .answers
+            .iter_mut()
+            .chain(answer.name_servers.iter_mut())
+        {
+            let ttl = record.ttl().saturating_sub(elapsed);
+            record.set_ttl(ttl);
+        }
+
+        Some(answer)
+    }
+
+    pub fn put(&self, name: &str, record_type: RecordType, answer: DnsCacheAnswer) {
+        let ttl = match Self::calc_ttl(&answer) {
+            Some(ttl) => ttl,
+            None => return,
+        };
+
+        let now = Instant::now();
+        let key = Self::cache_key(name, record_type);
+        let mut items = self.items.lock().unwrap();
+        if items.len() >= self.max_items && !items.contains_key(&key) {
+            items.retain(|_, item| item.expire_at > now);
+            if items.len() >= self.max_items {
+                let oldest = items
+                    .iter()
+                    .min_by_key(|(_, item)| item.expire_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    items.remove(&oldest);
+                }
+            }
+        }
+
+        items.insert(
+            key,
+            DnsCacheItem {
+                answer,
+                insert_at: now,
+                expire_at: now + Duration::from_secs(ttl as u64),
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hickory_proto::rr::rdata::A;
+    use hickory_proto::rr::{Name, RData};
+    use std::net::Ipv4Addr;
+    use std::str::FromStr;
+
+    fn a_record(name: &str, ttl: u32) -> Record {
+        Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            ttl,
+            RData::A(A::from(Ipv4Addr::new(192, 168, 1, 1))),
+        )
+    }
+
+    #[test]
+    fn test_dns_cache() {
+        let cache = DnsCache::new(Some(2));
+        let answer = DnsCacheAnswer {
+            response_code: ResponseCode::NoError,
+            answers: vec![a_record("www.example.com.", 300), a_record("www.example.com.", 60)],
+            name_servers: vec![],
+        };
+        cache.put("www.example.com.", RecordType::A, answer);
+
+        let ret = cache.get("WWW.example.com", RecordType::A).unwrap();
+        assert_eq!(ret.answers.len(), 2);
+        assert!(cache.get("www.example.com", RecordType::AAAA).is_none());
+
+        // TTL 0 and SERVFAIL are not cached
+        let answer = DnsCacheAnswer {
+            response_code: ResponseCode::NoError,
+            answers: vec![a_record("a.example.com.", 0)],
+            name_servers: vec![],
+        };
+        cache.put("a.example.com.", RecordType::A, answer);
+        let answer = DnsCacheAnswer {
+            response_code: ResponseCode::ServFail,
+            answers: vec![],
+            name_servers: vec![],
+        };
+        cache.put("b.example.com.", RecordType::A, answer);
+        assert_eq!(cache.len(), 1);
+
+        // The item expires first is evicted when full
+        for name in ["c.example.com.", "d.example.com."] {
+            let answer = DnsCacheAnswer {
+                response_code: ResponseCode::NoError,
+                answers: vec![a_record(name, 600)],
+                name_servers: vec![],
+            };
+            cache.put(name, RecordType::A, answer);
+        }
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get("www.example.com.", RecordType::A).is_none());
+        assert!(cache.get("d.example.com.", RecordType::A).is_some());
+    }
+}
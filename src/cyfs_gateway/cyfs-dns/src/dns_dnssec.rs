@@ -0,0 +1,347 @@
+use anyhow::Result;
+use hickory_proto::rr::rdata::NULL;
+use hickory_proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use hickory_proto::serialize::binary::BinEncodable;
+use log::info;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Ed25519, RFC 8080
+const DNSSEC_ALGORITHM_ED25519: u8 = 15;
+// Zone Key + Secure Entry Point, the same key is used as KSK and ZSK
+const DNSKEY_FLAGS: u16 = 257;
+const DNSKEY_PROTOCOL: u8 = 3;
+// RFC 9824 compact denial of existence
+const RECORD_TYPE_NXNAME: u16 = 128;
+
+const SIGNATURE_INCEPTION_OFFSET: u32 = 3600;
+const SIGNATURE_VALIDITY: u32 = 7 * 24 * 3600;
+
+// A set of records with the same name and type, DNSSEC signs the records by set
+#[derive(Debug, Clone)]
+pub struct DnsRecordSet {
+    pub name: Name,
+    pub record_type: RecordType,
+    pub ttl: u32,
+    pub rdatas: Vec<RData>,
+}
+
+impl DnsRecordSet {
+    pub fn new(name: Name, ttl: u32, rdatas: Vec<RData>) -> Option<Self> {
+        let record_type = rdatas.first()?.record_type();
+        Some(Self {
+            name,
+            record_type,
+            ttl,
+            rdatas,
+        })
+    }
+
+    pub fn to_records(&self) -> Vec<Record> {
+        self.rdatas
+            .iter()
+            .map(|rdata| Record::from_rdata(self.name.clone(), self.ttl, rdata.clone()))
+            .collect()
+    }
+}
+
+// 在线签名:应答时对每个RRset生成RRSIG,否定应答使用RFC 9824的compact denial,不需要预先生成整个zone的NSEC链
+pub struct DnsZoneSigner {
+    origin: Name,
+    key_pair: Ed25519KeyPair,
+    dnskey_rdata: Vec<u8>,
+    key_tag: u16,
+}
+
+impl DnsZoneSigner {
+    pub fn new(origin: Name, pkcs8: &[u8]) -> Result<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(pkcs8)
+            .map_err(|e| anyhow::anyhow!("Invalid dnssec zone key: {}", e))?;
+
+        let mut dnskey_rdata = Vec::new();
+        dnskey_rdata.extend_from_slice(&DNSKEY_FLAGS.to_be_bytes());
+        dnskey_rdata.push(DNSKEY_PROTOCOL);
+        dnskey_rdata.push(DNSSEC_ALGORITHM_ED25519);
+        dnskey_rdata.extend_from_slice(key_pair.public_key().as_ref());
+        let key_tag = calc_key_tag(&dnskey_rdata);
+
+        let signer = Self {
+            origin,
+            key_pair,
+            dnskey_rdata,
+            key_tag,
+        };
+        info!(
+            "dnssec zone key loaded, publish DS at the parent zone: {} DS {} {} 2 {}",
+            signer.origin,
+            signer.key_tag,
+            DNSSEC_ALGORITHM_ED25519,
+            signer.ds_digest_hex()
+        );
+        Ok(signer)
+    }
+
+    pub fn load(origin: Name, key_path: &Path) -> Result<Self> {
+        let pkcs8 = name_lib::load_raw_private_key(key_path).map_err(|e| {
+            anyhow::anyhow!("Load dnssec zone key {} failed: {}", key_path.display(), e)
+        })?;
+        Self::new(origin, &pkcs8)
+    }
+
+    pub fn key_tag(&self) -> u16 {
+        self.key_tag
+    }
+
+    pub fn dnskey_record_set(&self, ttl: u32) -> DnsRecordSet {
+        DnsRecordSet {
+            name: self.origin.clone(),
+            record_type: RecordType::DNSKEY,
+            ttl,
+            rdatas: vec![RData::Unknown {
+                code: RecordType::DNSKEY,
+                rdata: NULL::with(self.dnskey_rdata.clone()),
+            }],
+        }
+    }
+
+    // digest type 2: sha256(owner | dnskey rdata)
+    pub fn ds_digest_hex(&self) -> String {
+        let mut data = canonical_name(&self.origin);
+        data.extend_from_slice(&self.dnskey_rdata);
+        let digest = ring::digest::digest(&ring::digest::SHA256, &data);
+        digest
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect()
+    }
+
+    // RFC 9824: NSEC的next name是 \000.name, 证明name本身之外没有其它名字;
+    // 名字不存在时type bitmap中只有NXNAME
+    pub fn compact_nsec_record_set(
+        &self,
+        name: &Name,
+        types: &[RecordType],
+        name_exists: bool,
+        ttl: u32,
+    ) -> Result<DnsRecordSet> {
+        let next_name = Name::from_labels(
+            std::iter::once(&b"\x00"[..]).chain(name.iter()),
+        )?;
+        let mut type_codes: Vec<u16> = vec![u16::from(RecordType::RRSIG), u16::from(RecordType::NSEC)];
+        if name_exists {
+            type_codes.extend(types.iter().map(|t| u16::from(*t)));
+        } else {
+            type_codes.push(RECORD_TYPE_NXNAME);
+        }
+
+        let mut rdata = canonical_name(&next_name);
+        rdata.extend_from_slice(&encode_type_bitmap(&type_codes));
+        Ok(DnsRecordSet {
+            name: name.clone(),
+            record_type: RecordType::NSEC,
+            ttl,
+            rdatas: vec![RData::Unknown {
+                code: RecordType::NSEC,
+                rdata: NULL::with(rdata),
+            }],
+        })
+    }
+
+    pub fn sign(&self, record_set: &DnsRecordSet) -> Result<Record> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32;
+        self.sign_with_time(record_set, now)
+    }
+
+    fn sign_with_time(&self, record_set: &DnsRecordSet, now: u32) -> Result<Record> {
+        let inception = now.wrapping_sub(SIGNATURE_INCEPTION_OFFSET);
+        let expiration = now.wrapping_add(SIGNATURE_VALIDITY);
+
+        let mut rrsig = Vec::new();
+        rrsig.extend_from_slice(&u16::from(record_set.record_type).to_be_bytes());
+        rrsig.push(DNSSEC_ALGORITHM_ED25519);
+        rrsig.push(count_labels(&record_set.name));
+        rrsig.extend_from_slice(&record_set.ttl.to_be_bytes());
+        rrsig.extend_from_slice(&expiration.to_be_bytes());
+        rrsig.extend_from_slice(&inception.to_be_bytes());
+        rrsig.extend_from_slice(&self.key_tag.to_be_bytes());
+        rrsig.extend_from_slice(&canonical_name(&self.origin));
+
+        // RRs in the set are sorted by canonical rdata, duplicated rdata is removed
+        let mut rdatas = record_set
+            .rdatas
+            .iter()
+            .map(canonical_rdata)
+            .collect::<Result<Vec<_>>>()?;
+        rdatas.sort();
+        rdatas.dedup();
+
+        let owner = canonical_name(&record_set.name);
+        let mut data = rrsig.clone();
+        for rdata in rdatas.iter() {
+            data.extend_from_slice(&owner);
+            data.extend_from_slice(&u16::from(record_set.record_type).to_be_bytes());
+            data.extend_from_slice(&u16::from(DNSClass::IN).to_be_bytes());
+            data.extend_from_slice(&record_set.ttl.to_be_bytes());
+            data.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+            data.extend_from_slice(rdata);
+        }
+
+        let signature = self.key_pair.sign(&data);
+        rrsig.extend_from_slice(signature.as_ref());
+        Ok(Record::from_rdata(
+            record_set.name.clone(),
+            record_set.ttl,
+            RData::Unknown {
+                code: RecordType::RRSIG,
+                rdata: NULL::with(rrsig),
+            },
+        ))
+    }
+
+    // Convert the record sets to records, with the RRSIG of each set appended
+    pub fn sign_record_sets(&self, record_sets: &[DnsRecordSet]) -> Result<Vec<Record>> {
+        let mut records = Vec::new();
+        for record_set in record_sets.iter() {
+            records.extend(record_set.to_records());
+            records.push(self.sign(record_set)?);
+        }
+        Ok(records)
+    }
+}
+
+// RFC 4034 Appendix B
+fn calc_key_tag(dnskey_rdata: &[u8]) -> u16 {
+    let mut ac: u32 = 0;
+    for (i, b) in dnskey_rdata.iter().enumerate() {
+        if i & 1 == 1 {
+            ac += *b as u32;
+        } else {
+            ac += (*b as u32) << 8;
+        }
+    }
+    ac += (ac >> 16) & 0xFFFF;
+    (ac & 0xFFFF) as u16
+}
+
+// Labels of the owner name, not include the root and the leftmost wildcard label
+fn count_labels(name: &Name) -> u8 {
+    let mut count = name.iter().count();
+    if name.iter().next() == Some(&b"*"[..]) {
+        count -= 1;
+    }
+    count as u8
+}
+
+// Uncompressed wire format with lowercase labels
+pub fn canonical_name(name: &Name) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for label in name.iter() {
+        buf.push(label.len() as u8);
+        buf.extend(label.iter().map(|b| b.to_ascii_lowercase()));
+    }
+    buf.push(0);
+    buf
+}
+
+fn canonical_rdata(rdata: &RData) -> Result<Vec<u8>> {
+    let ret = match rdata {
+        RData::CNAME(cname) => canonical_name(&cname.0),
+        RData::NS(ns) => canonical_name(&ns.0),
+        // A/AAAA/TXT/DNSKEY/NSEC are encoded as is, the names in SOA are lowercased when the zone
+        // is created, and a fresh encoder never compresses the first occurrence of a name
+        _ => rdata.to_bytes()?,
+    };
+    Ok(ret)
+}
+
+fn encode_type_bitmap(type_codes: &[u16]) -> Vec<u8> {
+    let mut type_codes = type_codes.to_vec();
+    type_codes.sort();
+    type_codes.dedup();
+
+    let mut buf = Vec::new();
+    let mut index = 0;
+    while index < type_codes.len() {
+        let window = (type_codes[index] >> 8) as u8;
+        let mut bitmap = [0u8; 32];
+        let mut len = 0;
+        while index < type_codes.len() && (type_codes[index] >> 8) as u8 == window {
+            let low = (type_codes[index] & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            len = low / 8 + 1;
+            index += 1;
+        }
+        buf.push(window);
+        buf.push(len as u8);
+        buf.extend_from_slice(&bitmap[..len]);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hickory_proto::rr::rdata::A;
+    use ring::signature::{UnparsedPublicKey, ED25519};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_type_bitmap() {
+        // RFC 4034 4.3 example: A MX RRSIG NSEC TYPE1234
+        let bitmap = encode_type_bitmap(&[1, 15, 46, 47, 1234]);
+        assert_eq!(
+            bitmap,
+            vec![
+                0x00, 0x06, 0x40, 0x01, 0x00, 0x00, 0x00, 0x03, 0x04, 0x1b, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sign_record_set() {
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+        let origin = Name::from_str("example.com.").unwrap();
+        let signer = DnsZoneSigner::new(origin, pkcs8.as_ref()).unwrap();
+
+        let name = Name::from_str("WWW.example.com.").unwrap();
+        let rdatas = vec![
+            RData::A(A::new(10, 0, 0, 2)),
+            RData::A(A::new(10, 0, 0, 1)),
+        ];
+        let record_set = DnsRecordSet::new(name, 300, rdatas).unwrap();
+        let now = 1_700_000_000;
+        let rrsig = signer.sign_with_time(&record_set, now).unwrap();
+        assert_eq!(rrsig.record_type(), RecordType::RRSIG);
+
+        // Rebuild the signed data and verify it with the public key of the DNSKEY
+        let rrsig_rdata = canonical_rdata(&RData::Unknown {
+            code: RecordType::RRSIG,
+            rdata: NULL::with(rrsig_bytes(&rrsig)),
+        })
+        .unwrap();
+        let (header, signature) = rrsig_rdata.split_at(rrsig_rdata.len() - 64);
+        assert_eq!(header[3], 3);
+        let mut data = header.to_vec();
+        for ip in [[10, 0, 0, 1], [10, 0, 0, 2]] {
+            data.extend_from_slice(&canonical_name(&Name::from_str("www.example.com.").unwrap()));
+            data.extend_from_slice(&[0, 1, 0, 1]);
+            data.extend_from_slice(&300u32.to_be_bytes());
+            data.extend_from_slice(&[0, 4]);
+            data.extend_from_slice(&ip);
+        }
+        let public_key = UnparsedPublicKey::new(&ED25519, &signer.dnskey_rdata[4..]);
+        public_key.verify(&data, signature).unwrap();
+    }
+
+    fn rrsig_bytes(record: &Record) -> Vec<u8> {
+        // Skip owner name, type, class, ttl and rdlength of the encoded record
+        let bytes = record.to_bytes().unwrap();
+        let owner_len = canonical_name(record.name()).len();
+        bytes[owner_len + 10..].to_vec()
+    }
+}
@@ -4,16 +4,16 @@ use std::str::FromStr;
 
 use async_trait::async_trait;
 
-use hickory_proto::serialize::binary::{BinDecodable, BinEncodable};
+use hickory_proto::serialize::binary::{BinDecodable, BinEncodable, BinEncoder};
 use hickory_server::authority::{Catalog, MessageRequest, MessageResponse, MessageResponseBuilder};
 use hickory_server::proto::op::*;
 use hickory_server::proto::rr::*;
-use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo};
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
 use hickory_server::ServerFuture;
 use log::trace;
 use log::{debug, error, info, warn};
 use rdata::{A, AAAA, CNAME, TXT};
-use tokio::net::UdpSocket;
+use tokio::net::{TcpListener, UdpSocket};
 
 use anyhow::Result;
 use cyfs_gateway_lib::*;
@@ -21,14 +21,22 @@ use cyfs_sn::get_sn_server_by_id;
 use futures::stream::{self, StreamExt};
 use name_client::{DnsProvider, NameInfo, NsProvider, RecordType};
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::time::timeout;
 use url::Url;
 
+use crate::dns_cache::{DnsCache, DnsCacheAnswer};
+use crate::dns_dnssec::DnsRecordSet;
+use crate::dns_tls::{create_dot_tls_config, start_dot_listener, start_tcp_listener};
+use crate::dns_upstream::DnsUpstream;
+use crate::dns_zone::DnsZone;
+use hickory_proto::rr::RecordType as DnsRecordType;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Name not found: {0:}")]
     NameNotFound(String),
+    #[error("All upstream dns servers failed: {0:}")]
+    UpstreamFailed(String),
     #[error("Invalid OpCode {0:}")]
     InvalidOpCode(OpCode),
     #[error("Invalid MessageType {0:}")]
@@ -43,10 +51,27 @@ pub enum Error {
     Proto(#[from] hickory_proto::ProtoError),
 }
 
+impl Error {
+    // 只有确定名字不存在时才返回NXDOMAIN,其它错误返回SERVFAIL
+    fn response_code(&self) -> ResponseCode {
+        match self {
+            Error::NameNotFound(_) => ResponseCode::NXDomain,
+            Error::InvalidOpCode(_) => ResponseCode::NotImp,
+            Error::InvalidMessageType(_) => ResponseCode::FormErr,
+            _ => ResponseCode::ServFail,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DNSServer {
     config: DNSServerConfig,
     resolver_chain: Arc<Vec<Box<dyn NsProvider>>>,
+    upstreams: Arc<Vec<DnsUpstream>>,
+    cache: Arc<DnsCache>,
+    zone: Option<Arc<DnsZone>>,
+    // listener tasks, aborted by stop
+    tasks: Arc<std::sync::Mutex<Vec<tokio::task::JoinHandle<()>>>>,
 }
 
 pub async fn create_ns_provider(
@@ -194,22 +219,84 @@ impl DNSServer {
             }
         }
 
+        let mut upstreams = Vec::new();
+        for server in config.fallback.iter() {
+            let upstream = DnsUpstream::parse(server);
+            if upstream.is_err() {
+                warn!("Ignore fallback dns server {}: {}", server, upstream.err().unwrap());
+                continue;
+            }
+            let upstream = upstream.unwrap();
+            if Self::is_self_addr(&config, &upstream.addr) {
+                warn!("Ignore fallback dns server {}, it's this server self", server);
+                continue;
+            }
+            upstreams.push(upstream);
+        }
+
+        let zone = match (config.zone.as_ref(), config.this_name.as_ref()) {
+            (Some(zone_config), Some(this_name)) => {
+                Some(Arc::new(DnsZone::new(this_name, zone_config)?))
+            }
+            (Some(_), None) => {
+                return Err(anyhow::anyhow!("Dns zone config requires this_name"));
+            }
+            _ => None,
+        };
+
         Ok(DNSServer {
             config,
             resolver_chain: Arc::new(resolver_chain),
+            upstreams: Arc::new(upstreams),
+            cache: Arc::new(DnsCache::new(None)),
+            zone,
+            tasks: Arc::new(std::sync::Mutex::new(Vec::new())),
         })
     }
 
+    // fallback指向自己会导致查询死循环
+    fn is_self_addr(config: &DNSServerConfig, addr: &SocketAddr) -> bool {
+        if addr.port() != config.port {
+            return false;
+        }
+        if addr.ip().is_loopback() || addr.ip().is_unspecified() {
+            return true;
+        }
+        match config.bind.as_ref().map(|bind| IpAddr::from_str(bind)) {
+            Some(Ok(bind_ip)) => bind_ip.is_unspecified() || bind_ip == addr.ip(),
+            _ => true,
+        }
+    }
+
+    // this_name下的名字只能由resolver_chain解析,不能转发给上游,否则上游可能再转发回来
+    fn is_local_name(&self, name: &str) -> bool {
+        if let Some(this_name) = self.config.this_name.as_ref() {
+            let this_name = this_name.trim_end_matches('.').to_lowercase();
+            let name = name.trim_end_matches('.').to_lowercase();
+            return name == this_name || name.ends_with(format!(".{}", this_name).as_str());
+        }
+        false
+    }
+
     async fn start(&self) -> Result<()> {
         let bind_addr = self.config.bind.clone().unwrap_or("0.0.0.0".to_string());
         let addr = format!("{}:{}", bind_addr, self.config.port);
         info!("cyfs-dns-server try bind at:{}", addr);
         let udp_socket = UdpSocket::bind(addr.clone()).await?;
+        // 客户端收到截断的应答后会用tcp重试
+        let tcp_listener = TcpListener::bind(addr.clone()).await?;
 
+        // tcp连接不交给ServerFuture,它停止时会中断所有正在处理的连接
         let mut server = ServerFuture::new(self.clone());
         server.register_socket(udp_socket);
+        let tcp_task = start_tcp_listener(self.clone(), tcp_listener);
+        self.tasks.lock().unwrap().push(tcp_task);
 
-        tokio::spawn(async move {
+        if self.config.dot_port > 0 {
+            self.start_dot(bind_addr.as_str()).await?;
+        }
+
+        let task = tokio::spawn(async move {
             info!("cyfs-dns-server run at:{}", addr);
             match server.block_until_done().await {
                 Ok(_) => {
@@ -220,45 +307,305 @@ impl DNSServer {
                 }
             }
         });
+        self.tasks.lock().unwrap().push(task);
 
         Ok(())
     }
 
-    async fn stop(&self) -> Result<()> {
-        // TODO: stop server for config dynamic update or outside control
+    async fn start_dot(&self, bind_addr: &str) -> Result<()> {
+        let this_name = self.config.this_name.as_ref().ok_or_else(|| {
+            anyhow::anyhow!("DoT requires this_name to select the tls cert")
+        })?;
+        let tls_config = self.config.tls.clone().ok_or_else(|| {
+            anyhow::anyhow!("DoT requires tls config of {}", this_name)
+        })?;
+
+        let tls_config = create_dot_tls_config(this_name, tls_config)?;
+        let addr = format!("{}:{}", bind_addr, self.config.dot_port);
+        let task = start_dot_listener(self.clone(), addr, tls_config).await?;
+        self.tasks.lock().unwrap().push(task);
         Ok(())
     }
 
+    // 停止监听,已经建立的tcp/DoT连接继续处理完
+    pub async fn stop(&self) {
+        let tasks: Vec<_> = self.tasks.lock().unwrap().drain(..).collect();
+        for task in tasks {
+            task.abort();
+            let _ = task.await;
+        }
+        info!("cyfs-dns-server stopped, port: {}", self.config.port);
+    }
+
+    // 客户端使用EDNS时,应答也带上EDNS,udp应答的大小由客户端声明的payload决定
+    fn build_response_edns(request: &Request) -> Option<Edns> {
+        let req_edns = request.edns()?;
+        let mut edns = Edns::new();
+        edns.set_max_payload(req_edns.max_payload().max(512));
+        edns.set_version(0);
+        Some(edns)
+    }
+
+    async fn send_answer<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        answer: &DnsCacheAnswer,
+        mut response: R,
+    ) -> Result<ResponseInfo, Error> {
+        let mut builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(edns) = Self::build_response_edns(request) {
+            builder.edns(edns);
+        }
+
+        let mut header = Header::response_from_request(request.header());
+        header.set_response_code(answer.response_code);
+        header.set_recursion_available(!self.upstreams.is_empty());
+        let message = builder.build(
+            header,
+            answer.answers.iter(),
+            answer.name_servers.iter(),
+            &[],
+            &[],
+        );
+        let info = response.send_response(message).await?;
+        Ok(info)
+    }
+
     async fn handle_fallback<R: ResponseHandler>(
         &self,
         request: &Request,
-        server_name: &str,
+        query: &Query,
+        response: R,
+    ) -> Result<ResponseInfo, Error> {
+        let name = query.name().to_string();
+        for upstream in self.upstreams.iter() {
+            let resp_message = upstream.query(query).await;
+            if resp_message.is_err() {
+                warn!(
+                    "Fallback dns server {} can't resolve name:{}, {}",
+                    upstream.addr,
+                    name,
+                    resp_message.err().unwrap()
+                );
+                continue;
+            }
+
+            let mut resp_message = resp_message.unwrap();
+            let response_code = resp_message.response_code();
+            if response_code != ResponseCode::NoError && response_code != ResponseCode::NXDomain {
+                warn!(
+                    "Fallback dns server {} return {} for name:{}",
+                    upstream.addr, response_code, name
+                );
+                continue;
+            }
+
+            let answer = DnsCacheAnswer {
+                response_code,
+                answers: resp_message.take_answers(),
+                name_servers: resp_message.take_name_servers(),
+            };
+            self.cache.put(&name, query.query_type(), answer.clone());
+            info!(
+                "<==|name:{} {} resolved by fallback:{}, {}",
+                name,
+                query.query_type(),
+                upstream.addr,
+                response_code
+            );
+            return self.send_answer(request, &answer, response).await;
+        }
+
+        // 所有上游都失败时不能当作名字不存在,否则客户端会缓存错误的NXDOMAIN
+        Err(Error::UpstreamFailed(name))
+    }
+
+    async fn resolve_by_providers(
+        &self,
+        name: &str,
+        record_type: &RecordType,
+        from_ip: IpAddr,
+    ) -> Option<(Vec<RData>, u32)> {
+        for provider in self.resolver_chain.iter() {
+            let name_info = provider
+                .query(name, Some(record_type.clone()), Some(from_ip))
+                .await;
+            if name_info.is_err() {
+                trace!("Provider {} can't resolve name:{}", provider.get_id(), name);
+                continue;
+            }
+
+            let name_info = name_info.unwrap();
+            let rdata_vec = nameinfo_to_rdata(record_type.to_string().as_str(), &name_info);
+            if rdata_vec.is_err() {
+                error!(
+                    "Failed to convert nameinfo to rdata:{}",
+                    rdata_vec.err().unwrap()
+                );
+                continue;
+            }
+
+            let rdata_vec = rdata_vec.unwrap();
+            if rdata_vec.is_empty() {
+                continue;
+            }
+            info!(
+                "<==|name:{} {} resolved by provider:{}",
+                name,
+                record_type.to_string(),
+                provider.get_id()
+            );
+            return Some((rdata_vec, name_info.ttl.unwrap_or(600)));
+        }
+
+        None
+    }
+
+    // DO位在EDNS的扩展flags中
+    fn is_dnssec_ok(request: &Request) -> bool {
+        match request.edns() {
+            Some(edns) => Record::from(edns).ttl() & 0x8000 != 0,
+            None => false,
+        }
+    }
+
+    async fn send_zone_answer<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        response_code: ResponseCode,
+        answers: &[Record],
+        name_servers: &[Record],
         mut response: R,
-    ) -> Result<Message, Error> {
-        let message = request.to_bytes();
-        let message = message.unwrap();
-        let socket = UdpSocket::bind("0.0.0.0:0").await?;
-        let target_url = Url::parse(server_name);
-        if target_url.is_err() {
-            return Err(Error::NameNotFound("".to_string()));
-        }
-        let target_url = target_url.unwrap();
-        let host = target_url.host_str().unwrap();
-        let port = target_url.port().unwrap_or(53);
-        let target_addr = SocketAddr::new(IpAddr::from_str(host).unwrap(), port);
-        socket.send_to(&message, target_addr).await?;
-        let mut buf = [0u8; 2048];
-        let mut resp_len = 512;
-        let proxy_result = timeout(Duration::from_secs(5), socket.recv_from(&mut buf)).await;
-        //let resp_vec =buf[0..resp_len].to_vec();
-        let resp_message = Message::from_vec(&buf[0..resp_len]);
-        if resp_message.is_err() {
-            return Err(Error::NameNotFound("".to_string()));
-        }
-        let resp_message = resp_message.unwrap();
-        let resp_info = resp_message.into();
-        return Ok(resp_info);
-        //unimplemented!("handle_fallback");
+    ) -> Result<ResponseInfo, Error> {
+        let mut builder = MessageResponseBuilder::from_message_request(request);
+        if let Some(mut edns) = Self::build_response_edns(request) {
+            if Self::is_dnssec_ok(request) {
+                edns.set_dnssec_ok(true);
+            }
+            builder.edns(edns);
+        }
+
+        let mut header = Header::response_from_request(request.header());
+        header.set_response_code(response_code);
+        header.set_authoritative(true);
+        header.set_recursion_available(false);
+        let message = builder.build(header, answers.iter(), name_servers.iter(), &[], &[]);
+        let info = response.send_response(message).await?;
+        Ok(info)
+    }
+
+    // 只允许配置的secondary通过tcp做AXFR
+    async fn handle_zone_transfer<R: ResponseHandler>(
+        &self,
+        zone: &DnsZone,
+        request: &Request,
+        query: &Query,
+        response: R,
+    ) -> Result<ResponseInfo, Error> {
+        let from_ip = request.src().ip();
+        if matches!(request.protocol(), Protocol::Udp)
+            || query.name() != zone.origin()
+            || !zone.is_transfer_allowed(&from_ip)
+        {
+            warn!("Refuse zone transfer of {} from {}", query.name(), from_ip);
+            return self
+                .send_zone_answer(request, ResponseCode::Refused, &[], &[], response)
+                .await;
+        }
+
+        info!("<==|zone transfer {} to {}", zone.origin(), from_ip);
+        let records = zone.axfr_records();
+        self.send_zone_answer(request, ResponseCode::NoError, &records, &[], response)
+            .await
+    }
+
+    // this_name下的名字由本服务器权威应答: 静态记录优先,然后是resolver_chain;
+    // 都没有结果时根据名字是否存在返回NODATA或NXDOMAIN,authority中带上SOA
+    async fn handle_zone_request<R: ResponseHandler>(
+        &self,
+        zone: &DnsZone,
+        request: &Request,
+        query: &Query,
+        response: R,
+    ) -> Result<ResponseInfo, Error> {
+        let query_type = query.query_type();
+        if query_type == DnsRecordType::AXFR {
+            return self
+                .handle_zone_transfer(zone, request, query, response)
+                .await;
+        }
+
+        let qname = query.name().clone();
+        let name = qname.to_string();
+        let from_ip = request.src().ip();
+        let mut answer_sets = Vec::new();
+        if let Some(record_set) = zone.lookup(&qname, query_type) {
+            answer_sets.push(record_set);
+        } else if let Some(record_set) = zone.lookup(&qname, DnsRecordType::CNAME) {
+            answer_sets.push(record_set);
+        } else if let Some(record_type) = RecordType::from_str(&query_type.to_string()) {
+            if let Some((rdatas, ttl)) = self
+                .resolve_by_providers(&name, &record_type, from_ip)
+                .await
+            {
+                answer_sets.extend(DnsRecordSet::new(qname.clone(), ttl, rdatas));
+            }
+        }
+
+        // 名字是否存在只看zone的数据,不再逐个类型查询resolver_chain;
+        // resolver_chain不为空时静态记录之外的名字也可能存在(比如设备名),只能返回NODATA
+        let types = zone.record_types(&qname);
+        let name_exists = !answer_sets.is_empty()
+            || zone.name_exists(&qname)
+            || !self.resolver_chain.is_empty();
+
+        let mut response_code = ResponseCode::NoError;
+        let mut authority_sets = Vec::new();
+        if answer_sets.is_empty() {
+            if !name_exists {
+                response_code = ResponseCode::NXDomain;
+            }
+            let mut soa = zone.soa().clone();
+            soa.ttl = zone.negative_ttl();
+            authority_sets.push(soa);
+        }
+
+        let signer = zone.signer().filter(|_| Self::is_dnssec_ok(request));
+        let (answers, name_servers) = match signer {
+            Some(signer) => {
+                if answer_sets.is_empty() {
+                    // RFC 9824: 用NSEC的NXNAME类型表示名字不存在,rcode统一为NOERROR
+                    let nsec = signer
+                        .compact_nsec_record_set(&qname, &types, name_exists, zone.negative_ttl())
+                        .map_err(|_| Error::InvalidZone(qname.clone().into()))?;
+                    authority_sets.push(nsec);
+                    response_code = ResponseCode::NoError;
+                }
+                let answers = signer.sign_record_sets(&answer_sets);
+                let name_servers = signer.sign_record_sets(&authority_sets);
+                match (answers, name_servers) {
+                    (Ok(answers), Ok(name_servers)) => (answers, name_servers),
+                    _ => {
+                        error!("Failed to sign the answer of {}", name);
+                        return Err(Error::InvalidZone(qname.into()));
+                    }
+                }
+            }
+            None => (
+                answer_sets.iter().flat_map(|set| set.to_records()).collect(),
+                authority_sets.iter().flat_map(|set| set.to_records()).collect(),
+            ),
+        };
+
+        info!(
+            "<==|name:{} {} answered by zone {}, {}",
+            name,
+            query_type,
+            zone.origin(),
+            response_code
+        );
+        self.send_zone_answer(request, response_code, &answers, &name_servers, response)
+            .await
     }
 
     async fn do_handle_request<R: ResponseHandler>(
@@ -288,58 +635,41 @@ impl DNSServer {
 
         info!("|==>DNS query name:{}, record_type:{:?}", name, record_type);
 
-        for provider in self.resolver_chain.iter() {
-            let name_info = provider
-                .query(name.as_str(), Some(record_type.clone()), Some(from_ip))
-                .await;
-            if name_info.is_err() {
-                trace!("Provider {} can't resolve name:{}", provider.get_id(), name);
-                continue;
-            }
-
-            let name_info = name_info.unwrap();
-            let rdata_vec = nameinfo_to_rdata(record_type.to_string().as_str(), &name_info);
-            if rdata_vec.is_err() {
-                error!(
-                    "Failed to convert nameinfo to rdata:{}",
-                    rdata_vec.err().unwrap()
-                );
-                continue;
+        if let Some(zone) = self.zone.as_ref() {
+            if zone.contains(&reqeust_info.query.name().into()) {
+                let query = reqeust_info.query.original().clone();
+                return self.handle_zone_request(zone, request, &query, response).await;
             }
+        }
 
-            let rdata_vec = rdata_vec.unwrap();
-            let mut builder = MessageResponseBuilder::from_message_request(request);
-            let mut header = Header::response_from_request(request.header());
-            header.set_response_code(ResponseCode::NoError);
-
-            let mut ttl = name_info.ttl.unwrap_or(600);
+        // resolver_chain的结果不进缓存,本地的记录(比如设备的ip)变化后要立即生效
+        if let Some((rdata_vec, ttl)) = self
+            .resolve_by_providers(&name, &record_type, from_ip)
+            .await
+        {
             let records = rdata_vec
                 .into_iter()
                 .map(|rdata| Record::from_rdata(reqeust_info.query.name().into(), ttl, rdata))
                 .collect::<Vec<_>>();
-            let mut message = builder.build(header, records.iter(), &[], &[], &[]);
-            response.send_response(message).await;
-            info!(
-                "<==|name:{} {} resolved by provider:{}",
-                name,
-                record_type.to_string(),
-                provider.get_id()
-            );
-            //let mut response = message.into();
-            return Ok(header.into());
+            let answer = DnsCacheAnswer {
+                response_code: ResponseCode::NoError,
+                answers: records,
+                name_servers: vec![],
+            };
+            return self.send_answer(request, &answer, response).await;
         }
 
-        if let Some(server_name) = self.config.this_name.as_ref() {
-            if !name.ends_with(server_name.as_str()) {
-                info!("All providers can't resolve name:{} enter fallback", name);
-                // for server_name in self.config.fallback.iter() {
-                //     let resp_message = self.handle_fallback(request,server_name,response.clone()).await;
-                //     if resp_message.is_ok() {
+        // 缓存中只有上游的应答
+        let query_type = reqeust_info.query.query_type();
+        if let Some(answer) = self.cache.get(&name, query_type) {
+            debug!("<==|name:{} {} resolved by cache", name, query_type);
+            return self.send_answer(request, &answer, response).await;
+        }
 
-                //         return resp_info;
-                //     }
-                // }
-            }
+        if !self.is_local_name(&name) && !self.upstreams.is_empty() {
+            info!("All providers can't resolve name:{} enter fallback", name);
+            let query = reqeust_info.query.original().clone();
+            return self.handle_fallback(request, &query, response).await;
         }
 
         warn!("All providers can't resolve name:{}", name);
@@ -347,6 +677,62 @@ impl DNSServer {
     }
 }
 
+// 收集hickory编码后的应答,用于DoT/DoH这些不经过ServerFuture的入口
+#[derive(Clone, Default)]
+struct DnsMessageCollector {
+    message: Arc<std::sync::Mutex<Option<Vec<u8>>>>,
+}
+
+#[async_trait]
+impl ResponseHandler for DnsMessageCollector {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let mut buf = Vec::with_capacity(512);
+        let info = {
+            let mut encoder = BinEncoder::new(&mut buf);
+            response
+                .destructive_emit(&mut encoder)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+        };
+        *self.message.lock().unwrap() = Some(buf);
+        Ok(info)
+    }
+}
+
+impl DNSServer {
+    // 处理一个完整的dns请求报文,返回编码后的应答报文
+    pub async fn handle_message(
+        &self,
+        message: &[u8],
+        src: SocketAddr,
+        protocol: Protocol,
+    ) -> Result<Vec<u8>> {
+        let message = MessageRequest::from_bytes(message)?;
+        let request = Request::new(message, src, protocol);
+        let collector = DnsMessageCollector::default();
+        self.handle_request(&request, collector.clone()).await;
+
+        let response = collector.message.lock().unwrap().take();
+        response.ok_or_else(|| anyhow::anyhow!("No dns response from {}", src))
+    }
+}
+
+#[async_trait]
+impl DnsMessageHandler for DNSServer {
+    async fn handle_dns_message(&self, message: &[u8], client_addr: SocketAddr) -> Result<Vec<u8>> {
+        self.handle_message(message, client_addr, Protocol::Https).await
+    }
+}
+
 #[async_trait]
 impl RequestHandler for DNSServer {
     async fn handle_request<R: ResponseHandler>(
@@ -362,7 +748,7 @@ impl RequestHandler for DNSServer {
                 error!("Error in RequestHandler: {error}");
                 let mut builder = MessageResponseBuilder::from_message_request(request);
                 let mut header = Header::response_from_request(request.header());
-                header.set_response_code(ResponseCode::NXDomain);
+                header.set_response_code(error.response_code());
                 let records = vec![];
                 let mut message = builder.build(header, records.iter(), &[], &[], &[]);
                 resp2.send_response(message).await;
@@ -378,3 +764,16 @@ pub async fn start_cyfs_dns_server(config: DNSServerConfig) -> anyhow::Result<DN
 
     Ok(server)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_response_code() {
+        assert_eq!(Error::NameNotFound("test.com.".to_string()).response_code(), ResponseCode::NXDomain);
+        assert_eq!(Error::UpstreamFailed("test.com.".to_string()).response_code(), ResponseCode::ServFail);
+        assert_eq!(Error::InvalidOpCode(OpCode::Update).response_code(), ResponseCode::NotImp);
+        assert_eq!(Error::InvalidMessageType(MessageType::Response).response_code(), ResponseCode::FormErr);
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::Result;
+use cyfs_gateway_lib::{get_shared_cert, insert_shared_tls_config, TlsConfig};
+use hickory_server::server::Protocol;
+use log::{debug, error, info, warn};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+
+use crate::dns_server::DNSServer;
+
+// RFC 7766: 连接空闲一段时间后由服务端关闭
+const DOT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 证书由cyfs-warp的证书管理器统一申请和续期,cyfs-dns本身没有http/tls-alpn入口
+// 很多DoT客户端用ip连接,不带SNI,这时使用this_name的证书
+struct DotCertResolver {
+    default_host: String,
+}
+
+impl ResolvesServerCert for DotCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let host = client_hello.server_name().unwrap_or(self.default_host.as_str());
+        get_shared_cert(host)
+    }
+}
+
+pub(crate) fn create_dot_tls_config(
+    this_name: &str,
+    tls_config: TlsConfig,
+) -> Result<Arc<rustls::ServerConfig>> {
+    let this_name = this_name.trim_end_matches('.').to_string();
+    insert_shared_tls_config(this_name.clone(), tls_config).map_err(|e| {
+        error!("DoT requires the shared cert manager of cyfs-warp: {}", e);
+        e
+    })?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(DotCertResolver {
+            default_host: this_name,
+        }));
+    config.alpn_protocols = vec![b"dot".to_vec()];
+    Ok(Arc::new(config))
+}
+
+pub(crate) async fn start_dot_listener(
+    server: DNSServer,
+    addr: String,
+    tls_config: Arc<rustls::ServerConfig>,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let listener = TcpListener::bind(addr.clone()).await.map_err(|e| {
+        error!("bind dot server {} failed, {}", addr, e);
+        anyhow::anyhow!("bind dot server {} failed, {}", addr, e)
+    })?;
+    let acceptor = TlsAcceptor::from(tls_config);
+
+    let task = tokio::spawn(async move {
+        info!("cyfs-dns-server DoT run at:{}", addr);
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    error!("cyfs-dns-server DoT accept error: {}, {}", e, addr);
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.clone();
+            let server = server.clone();
+            tokio::spawn(async move {
+                let stream = match timeout(DOT_IDLE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        warn!("DoT tls handshake with {} failed: {}", remote_addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("DoT tls handshake with {} timeout", remote_addr);
+                        return;
+                    }
+                };
+
+                if let Err(e) = serve_tcp_stream(&server, stream, remote_addr, Protocol::Tls).await {
+                    debug!("DoT connection from {} closed: {}", remote_addr, e);
+                }
+            });
+        }
+    });
+
+    Ok(task)
+}
+
+// 明文的dns over tcp也在这里处理,每个连接在独立的task里运行,停止监听时不会中断已经建立的连接
+pub(crate) fn start_tcp_listener(
+    server: DNSServer,
+    listener: TcpListener,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let (stream, remote_addr) = match listener.accept().await {
+                Ok(ret) => ret,
+                Err(e) => {
+                    error!("cyfs-dns-server tcp accept error: {}", e);
+                    continue;
+                }
+            };
+
+            let server = server.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_tcp_stream(&server, stream, remote_addr, Protocol::Tcp).await {
+                    debug!("tcp connection from {} closed: {}", remote_addr, e);
+                }
+            });
+        }
+    })
+}
+
+// 和dns over tcp一样,每个报文前面有2字节的长度
+async fn serve_tcp_stream<S>(
+    server: &DNSServer,
+    mut stream: S,
+    remote_addr: std::net::SocketAddr,
+    protocol: Protocol,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let len = match timeout(DOT_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Ok(()),
+        };
+        let mut buf = vec![0u8; len];
+        timeout(DOT_IDLE_TIMEOUT, stream.read_exact(&mut buf))
+            .await
+            .map_err(|_| anyhow::anyhow!("read dns message timeout"))??;
+
+        let response = server
+            .handle_message(&buf, remote_addr, protocol)
+            .await?;
+        let mut buf = Vec::with_capacity(response.len() + 2);
+        buf.extend_from_slice(&(response.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&response);
+        stream.write_all(&buf).await?;
+        stream.flush().await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cyfs_gateway_lib::DNSServerConfig;
+    use hickory_proto::op::{Message, MessageType, Query, ResponseCode};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_serve_dot_stream() {
+        let config = r#"
+{
+  "port": 2053,
+  "this_name": "web3.buckyos.io",
+  "resolver_chain": [],
+  "fallback": [],
+  "zone": {
+    "ns": ["sn.buckyos.io"],
+    "records": [{"name": "sn", "type": "A", "value": "192.168.1.1"}]
+  }
+}
+"#;
+        let config: DNSServerConfig = serde_json::from_str(config).unwrap();
+        let server = DNSServer::new(config).await.unwrap();
+
+        let (mut client, stream) = tokio::io::duplex(4096);
+        let remote_addr = "127.0.0.1:5353".parse().unwrap();
+        tokio::spawn(async move {
+            serve_tcp_stream(&server, stream, remote_addr, Protocol::Tls).await.unwrap();
+        });
+
+        // Two queries on the same connection
+        for (name, answers) in [("sn.web3.buckyos.io.", 1), ("none.web3.buckyos.io.", 0)] {
+            let mut request = Message::new();
+            request
+                .set_id(1234)
+                .add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+            let request = request.to_vec().unwrap();
+            client.write_u16(request.len() as u16).await.unwrap();
+            client.write_all(&request).await.unwrap();
+
+            let len = client.read_u16().await.unwrap() as usize;
+            let mut buf = vec![0u8; len];
+            client.read_exact(&mut buf).await.unwrap();
+            let response = Message::from_vec(&buf).unwrap();
+            assert_eq!(response.id(), 1234);
+            assert_eq!(response.message_type(), MessageType::Response);
+            assert!(response.authoritative());
+            assert_eq!(response.answers().len(), answers);
+            if answers == 0 {
+                assert_eq!(response.response_code(), ResponseCode::NXDomain);
+                assert_eq!(response.name_servers().len(), 1);
+            }
+        }
+    }
+}
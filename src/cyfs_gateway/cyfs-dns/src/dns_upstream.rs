@@ -0,0 +1,223 @@
+use anyhow::Result;
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query};
+use log::{debug, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::timeout;
+use url::Url;
+
+// 向上游查询时声明的EDNS udp payload大小, 1232可以避免绝大多数网络上的IP分片
+pub const UPSTREAM_EDNS_PAYLOAD: u16 = 1232;
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsUpstreamProtocol {
+    // udp优先, 应答被截断时用tcp重试
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsUpstream {
+    pub addr: SocketAddr,
+    pub protocol: DnsUpstreamProtocol,
+}
+
+impl DnsUpstream {
+    // 支持 "8.8.8.8", "8.8.8.8:53", "udp://8.8.8.8:53", "tcp://[2001:4860:4860::8888]:53"
+    pub fn parse(server: &str) -> Result<Self> {
+        if let Ok(ip) = IpAddr::from_str(server) {
+            return Ok(Self {
+                addr: SocketAddr::new(ip, 53),
+                protocol: DnsUpstreamProtocol::Udp,
+            });
+        }
+        if let Ok(addr) = SocketAddr::from_str(server) {
+            return Ok(Self {
+                addr,
+                protocol: DnsUpstreamProtocol::Udp,
+            });
+        }
+
+        let url = Url::parse(server)
+            .map_err(|e| anyhow::anyhow!("Invalid dns server: {}, {}", server, e))?;
+        let protocol = match url.scheme() {
+            "udp" | "dns" => DnsUpstreamProtocol::Udp,
+            "tcp" => DnsUpstreamProtocol::Tcp,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported dns server protocol: {}",
+                    server
+                ));
+            }
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid dns server: {}", server))?;
+        let ip = IpAddr::from_str(host.trim_start_matches('[').trim_end_matches(']'))
+            .map_err(|e| anyhow::anyhow!("Dns server must be ip address: {}, {}", server, e))?;
+
+        Ok(Self {
+            addr: SocketAddr::new(ip, url.port().unwrap_or(53)),
+            protocol,
+        })
+    }
+
+    fn build_request(query: &Query) -> Message {
+        let mut message = Message::new();
+        message
+            .set_id(rand::random::<u16>())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(query.clone());
+
+        let mut edns = Edns::new();
+        edns.set_max_payload(UPSTREAM_EDNS_PAYLOAD);
+        message.set_edns(edns);
+        message
+    }
+
+    fn check_response(request: &Message, response: &Message) -> bool {
+        response.id() == request.id()
+            && response.message_type() == MessageType::Response
+            && response.queries() == request.queries()
+    }
+
+    pub async fn query(&self, query: &Query) -> Result<Message> {
+        let request = Self::build_request(query);
+        let request_bytes = request.to_vec()?;
+
+        if self.protocol == DnsUpstreamProtocol::Udp {
+            let response = self.query_udp(&request, &request_bytes).await?;
+            if !response.truncated() {
+                return Ok(response);
+            }
+            debug!(
+                "dns response from {} is truncated, retry with tcp: {}",
+                self.addr,
+                query.name()
+            );
+        }
+
+        self.query_tcp(&request, &request_bytes).await
+    }
+
+    async fn query_udp(&self, request: &Message, request_bytes: &[u8]) -> Result<Message> {
+        let bind_addr = if self.addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(self.addr).await?;
+        socket.send(request_bytes).await?;
+
+        let mut buf = vec![0u8; 4096];
+        let deadline = tokio::time::Instant::now() + UPSTREAM_TIMEOUT;
+        loop {
+            let len = tokio::time::timeout_at(deadline, socket.recv(&mut buf))
+                .await
+                .map_err(|_| anyhow::anyhow!("Query dns server timeout: {}", self.addr))??;
+
+            // 丢弃id或query不匹配的包,避免被伪造的应答污染缓存
+            match Message::from_vec(&buf[..len]) {
+                Ok(response) if Self::check_response(request, &response) => return Ok(response),
+                Ok(_) => warn!("Unmatched dns response from {}, ignore it", self.addr),
+                Err(e) => warn!("Invalid dns response from {}: {}", self.addr, e),
+            }
+        }
+    }
+
+    async fn query_tcp(&self, request: &Message, request_bytes: &[u8]) -> Result<Message> {
+        let ret = timeout(UPSTREAM_TIMEOUT, async {
+            let mut stream = TcpStream::connect(self.addr).await?;
+            let mut buf = Vec::with_capacity(request_bytes.len() + 2);
+            buf.extend_from_slice(&(request_bytes.len() as u16).to_be_bytes());
+            buf.extend_from_slice(request_bytes);
+            stream.write_all(&buf).await?;
+
+            let len = stream.read_u16().await? as usize;
+            let mut buf = vec![0u8; len];
+            stream.read_exact(&mut buf).await?;
+            Ok::<Vec<u8>, std::io::Error>(buf)
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("Query dns server by tcp timeout: {}", self.addr))??;
+
+        let response = Message::from_vec(&ret)?;
+        if !Self::check_response(request, &response) {
+            return Err(anyhow::anyhow!(
+                "Unmatched dns response from {}",
+                self.addr
+            ));
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_dns_upstream() {
+        let upstream = DnsUpstream::parse("8.8.8.8").unwrap();
+        assert_eq!(upstream.addr, "8.8.8.8:53".parse().unwrap());
+        assert_eq!(upstream.protocol, DnsUpstreamProtocol::Udp);
+
+        let upstream = DnsUpstream::parse("114.114.114.114:5353").unwrap();
+        assert_eq!(upstream.addr, "114.114.114.114:5353".parse().unwrap());
+
+        let upstream = DnsUpstream::parse("tcp://[2001:4860:4860::8888]").unwrap();
+        assert_eq!(upstream.addr, "[2001:4860:4860::8888]:53".parse().unwrap());
+        assert_eq!(upstream.protocol, DnsUpstreamProtocol::Tcp);
+
+        assert!(DnsUpstream::parse("https://dns.google/dns-query").is_err());
+        assert!(DnsUpstream::parse("udp://dns.google").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dns_upstream_tcp_on_truncation() {
+        use hickory_proto::rr::rdata::A;
+        use hickory_proto::rr::{Name, RData, Record, RecordType};
+        use tokio::net::TcpListener;
+
+        // Fake upstream: udp always returns a truncated response, tcp returns the answer
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 4096];
+            let (len, from) = udp.recv_from(&mut buf).await.unwrap();
+            let mut response = Message::from_vec(&buf[..len]).unwrap();
+            response.set_message_type(MessageType::Response).set_truncated(true);
+            udp.send_to(&response.to_vec().unwrap(), from).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap() as usize;
+            let mut buf = vec![0u8; len];
+            stream.read_exact(&mut buf).await.unwrap();
+            let mut response = Message::from_vec(&buf).unwrap();
+            let name = response.queries()[0].name().clone();
+            response
+                .set_message_type(MessageType::Response)
+                .add_answer(Record::from_rdata(name, 60, RData::A(A::new(10, 0, 0, 1))));
+            let response = response.to_vec().unwrap();
+            stream.write_u16(response.len() as u16).await.unwrap();
+            stream.write_all(&response).await.unwrap();
+        });
+
+        let upstream = DnsUpstream::parse(&addr.to_string()).unwrap();
+        let query = Query::query(Name::from_str("www.example.com.").unwrap(), RecordType::A);
+        let response = upstream.query(&query).await.unwrap();
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].ttl(), 60);
+    }
+}
@@ -0,0 +1,322 @@
+use anyhow::Result;
+use cyfs_gateway_lib::DNSZoneConfig;
+use hickory_proto::rr::rdata::{A, AAAA, CNAME, NS, SOA, TXT};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use log::info;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dns_dnssec::{DnsRecordSet, DnsZoneSigner};
+
+const SOA_REFRESH: i32 = 3600;
+const SOA_RETRY: i32 = 600;
+const SOA_EXPIRE: i32 = 604800;
+
+// this_name下的权威区域: SOA/NS和静态记录来自配置,其余名字仍由resolver_chain解析
+pub struct DnsZone {
+    origin: Name,
+    ttl: u32,
+    soa: DnsRecordSet,
+    ns: DnsRecordSet,
+    records: HashMap<(Name, RecordType), DnsRecordSet>,
+    allow_transfer: Vec<IpAddr>,
+    signer: Option<DnsZoneSigner>,
+}
+
+impl DnsZone {
+    pub fn new(this_name: &str, config: &DNSZoneConfig) -> Result<Self> {
+        let origin = Self::parse_absolute_name(this_name)?;
+        if config.ns.is_empty() {
+            return Err(anyhow::anyhow!("Zone {} has no name server", origin));
+        }
+
+        let ns_rdatas = config
+            .ns
+            .iter()
+            .map(|ns| Ok(RData::NS(NS(Self::parse_absolute_name(ns)?))))
+            .collect::<Result<Vec<_>>>()?;
+        let ns = DnsRecordSet::new(origin.clone(), config.ttl, ns_rdatas).unwrap();
+
+        let rname = match config.admin.as_ref() {
+            Some(admin) => Self::parse_absolute_name(admin)?,
+            None => Name::from_str("hostmaster")?.append_domain(&origin)?,
+        };
+        let serial = match config.serial {
+            Some(serial) => serial,
+            None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as u32,
+        };
+        let soa = SOA::new(
+            Self::parse_absolute_name(&config.ns[0])?,
+            rname,
+            serial,
+            SOA_REFRESH,
+            SOA_RETRY,
+            SOA_EXPIRE,
+            config.ttl,
+        );
+        let soa = DnsRecordSet::new(origin.clone(), config.ttl, vec![RData::SOA(soa)]).unwrap();
+
+        let mut records: HashMap<(Name, RecordType), DnsRecordSet> = HashMap::new();
+        for record in config.records.iter() {
+            let name = if record.name == "@" {
+                origin.clone()
+            } else if record.name.ends_with('.') {
+                Self::parse_absolute_name(&record.name)?
+            } else {
+                Name::from_str(&record.name)?
+                    .to_lowercase()
+                    .append_domain(&origin)?
+            };
+            if !origin.zone_of(&name) {
+                return Err(anyhow::anyhow!(
+                    "Record {} is out of zone {}",
+                    record.name,
+                    origin
+                ));
+            }
+
+            let rdata = Self::parse_rdata(&record.record_type, &record.value)?;
+            let record_type = rdata.record_type();
+            if name == origin && record_type == RecordType::CNAME {
+                return Err(anyhow::anyhow!(
+                    "CNAME record at the zone apex is not allowed: {}",
+                    record.value
+                ));
+            }
+            // apex的NS来自zone.ns;apex下的NS是子域委派,不支持referral,直接拒绝
+            // 否则会把委派出去的名字当作本区域的权威数据应答
+            if record_type == RecordType::NS {
+                return Err(anyhow::anyhow!(
+                    "NS record {} is not allowed, zone {} does not support delegation",
+                    record.name,
+                    origin
+                ));
+            }
+            let ttl = record.ttl.unwrap_or(config.ttl);
+            match records.get_mut(&(name.clone(), record_type)) {
+                Some(record_set) => {
+                    record_set.ttl = record_set.ttl.min(ttl);
+                    record_set.rdatas.push(rdata);
+                }
+                None => {
+                    let record_set = DnsRecordSet::new(name.clone(), ttl, vec![rdata]).unwrap();
+                    records.insert((name, record_type), record_set);
+                }
+            }
+        }
+
+        let mut allow_transfer = Vec::new();
+        for ip in config.allow_transfer.iter() {
+            let ip = IpAddr::from_str(ip)
+                .map_err(|e| anyhow::anyhow!("Invalid allow_transfer ip {}: {}", ip, e))?;
+            allow_transfer.push(ip);
+        }
+
+        let signer = match config.dnssec_key_path.as_ref() {
+            Some(key_path) => Some(DnsZoneSigner::load(origin.clone(), Path::new(key_path))?),
+            None => None,
+        };
+
+        info!(
+            "dns zone {} loaded, serial:{}, records:{}, dnssec:{}",
+            origin,
+            serial,
+            records.len(),
+            signer.is_some()
+        );
+        Ok(Self {
+            origin,
+            ttl: config.ttl,
+            soa,
+            ns,
+            records,
+            allow_transfer,
+            signer,
+        })
+    }
+
+    fn parse_absolute_name(name: &str) -> Result<Name> {
+        let mut name = Name::from_str(name)?.to_lowercase();
+        name.set_fqdn(true);
+        Ok(name)
+    }
+
+    fn parse_rdata(record_type: &str, value: &str) -> Result<RData> {
+        let rdata = match record_type.to_uppercase().as_str() {
+            "A" => RData::A(A::from(Ipv4Addr::from_str(value)?)),
+            "AAAA" => RData::AAAA(AAAA::from(Ipv6Addr::from_str(value)?)),
+            "CNAME" => RData::CNAME(CNAME(Self::parse_absolute_name(value)?)),
+            "NS" => RData::NS(NS(Self::parse_absolute_name(value)?)),
+            "TXT" => RData::TXT(TXT::new(vec![value.to_string()])),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported zone record type: {}",
+                    record_type
+                ));
+            }
+        };
+        Ok(rdata)
+    }
+
+    pub fn origin(&self) -> &Name {
+        &self.origin
+    }
+
+    pub fn contains(&self, name: &Name) -> bool {
+        self.origin.zone_of(name)
+    }
+
+    pub fn signer(&self) -> Option<&DnsZoneSigner> {
+        self.signer.as_ref()
+    }
+
+    pub fn soa(&self) -> &DnsRecordSet {
+        &self.soa
+    }
+
+    // RFC 2308: 否定应答的缓存时间取SOA的TTL和minimum中较小的一个
+    pub fn negative_ttl(&self) -> u32 {
+        self.ttl
+    }
+
+    pub fn is_transfer_allowed(&self, ip: &IpAddr) -> bool {
+        self.allow_transfer.contains(ip)
+    }
+
+    // 配置中的记录,以及apex上的SOA/NS/DNSKEY
+    pub fn lookup(&self, name: &Name, record_type: RecordType) -> Option<DnsRecordSet> {
+        let name = name.to_lowercase();
+        if name == self.origin {
+            match record_type {
+                RecordType::SOA => return Some(self.soa.clone()),
+                RecordType::NS => return Some(self.ns.clone()),
+                RecordType::DNSKEY => {
+                    return self
+                        .signer
+                        .as_ref()
+                        .map(|signer| signer.dnskey_record_set(self.ttl));
+                }
+                _ => {}
+            }
+        }
+
+        self.records.get(&(name, record_type)).cloned()
+    }
+
+    // The types of the static records at the name
+    pub fn record_types(&self, name: &Name) -> Vec<RecordType> {
+        let name = name.to_lowercase();
+        let mut types: Vec<RecordType> = self
+            .records
+            .keys()
+            .filter(|(key, _)| *key == name)
+            .map(|(_, record_type)| *record_type)
+            .collect();
+        if name == self.origin {
+            types.push(RecordType::SOA);
+            types.push(RecordType::NS);
+            if self.signer.is_some() {
+                types.push(RecordType::DNSKEY);
+            }
+        }
+        types
+    }
+
+    // 空的非终端名字(例如只有a.b.zone时的b.zone)也是存在的,应该返回NODATA而不是NXDOMAIN
+    pub fn name_exists(&self, name: &Name) -> bool {
+        let name = name.to_lowercase();
+        name == self.origin || self.records.keys().any(|(key, _)| name.zone_of(key))
+    }
+
+    // AXFR: SOA开头和结尾,中间是zone的全部记录
+    pub fn axfr_records(&self) -> Vec<Record> {
+        let mut records = self.soa.to_records();
+        records.extend(self.ns.to_records());
+        let mut record_sets: Vec<&DnsRecordSet> = self.records.values().collect();
+        record_sets.sort_by(|a, b| {
+            a.name
+                .cmp(&b.name)
+                .then(u16::from(a.record_type).cmp(&u16::from(b.record_type)))
+        });
+        for record_set in record_sets {
+            records.extend(record_set.to_records());
+        }
+        records.extend(self.soa.to_records());
+        records
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cyfs_gateway_lib::DNSServerConfig;
+
+    #[test]
+    fn test_dns_zone() {
+        let config = r#"
+{
+  "port": 2053,
+  "this_name": "web3.buckyos.io",
+  "resolver_chain": [],
+  "fallback": [],
+  "zone": {
+    "ns": ["sn.buckyos.io"],
+    "serial": 2024120101,
+    "records": [
+      {"name": "sn", "type": "A", "value": "192.168.1.1"},
+      {"name": "sn", "type": "A", "value": "192.168.1.2", "ttl": 60},
+      {"name": "www.app", "type": "CNAME", "value": "sn.web3.buckyos.io"},
+      {"name": "@", "type": "TXT", "value": "hello"}
+    ],
+    "allow_transfer": ["10.0.0.2"]
+  }
+}
+"#;
+        let config: DNSServerConfig = serde_json::from_str(config).unwrap();
+        let zone = DnsZone::new(
+            config.this_name.as_ref().unwrap(),
+            config.zone.as_ref().unwrap(),
+        )
+        .unwrap();
+
+        let origin = Name::from_str("web3.buckyos.io.").unwrap();
+        assert!(zone.contains(&Name::from_str("a.WEB3.buckyos.io.").unwrap()));
+        assert!(!zone.contains(&Name::from_str("aweb3.buckyos.io.").unwrap()));
+
+        let soa = zone.lookup(&origin, RecordType::SOA).unwrap();
+        assert_eq!(soa.rdatas.len(), 1);
+        assert_eq!(zone.lookup(&origin, RecordType::NS).unwrap().rdatas.len(), 1);
+        assert!(zone.lookup(&origin, RecordType::DNSKEY).is_none());
+
+        let sn = Name::from_str("SN.web3.buckyos.io.").unwrap();
+        let record_set = zone.lookup(&sn, RecordType::A).unwrap();
+        assert_eq!(record_set.rdatas.len(), 2);
+        assert_eq!(record_set.ttl, 60);
+        assert!(zone.lookup(&sn, RecordType::AAAA).is_none());
+        assert_eq!(zone.record_types(&sn), vec![RecordType::A]);
+
+        // app.web3.buckyos.io is an empty non-terminal
+        assert!(zone.name_exists(&Name::from_str("app.web3.buckyos.io.").unwrap()));
+        assert!(!zone.name_exists(&Name::from_str("none.web3.buckyos.io.").unwrap()));
+
+        let records = zone.axfr_records();
+        assert_eq!(records.len(), 7);
+        assert_eq!(records[0].record_type(), RecordType::SOA);
+        assert_eq!(records[6].record_type(), RecordType::SOA);
+
+        assert!(zone.is_transfer_allowed(&"10.0.0.2".parse().unwrap()));
+        assert!(!zone.is_transfer_allowed(&"10.0.0.3".parse().unwrap()));
+
+        // Delegation below the apex is rejected
+        let mut zone_config = config.zone.clone().unwrap();
+        let mut ns_record = zone_config.records[0].clone();
+        ns_record.name = "sub".to_string();
+        ns_record.record_type = "NS".to_string();
+        ns_record.value = "ns.other.io".to_string();
+        zone_config.records.push(ns_record);
+        assert!(DnsZone::new(config.this_name.as_ref().unwrap(), &zone_config).is_err());
+    }
+}
@@ -1,8 +1,18 @@
 #![allow(unused)]
 
 mod dns_server;
+mod dns_cache;
+mod dns_upstream;
+mod dns_zone;
+mod dns_dnssec;
+mod dns_tls;
 
 pub use dns_server::*;
+pub use dns_cache::*;
+pub use dns_upstream::*;
+pub use dns_zone::*;
+pub use dns_dnssec::*;
+pub use dns_tls::*;
 
 
 #[cfg(test)]
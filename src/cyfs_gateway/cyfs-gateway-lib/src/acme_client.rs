@@ -221,6 +221,11 @@ pub trait AcmeChallengeResponder: Send + Sync {
     /// 响应 TLS-ALPN 挑战
     async fn respond_tls_alpn(&self, domain: &str, key_auth: &str) -> Result<()>;
     fn revert_tls_alpn(&self, domain: &str, key_auth: &str);
+
+    /// 支持的挑战类型，按优先级排序；通配符证书只能使用 dns-01
+    fn challenge_types(&self) -> Vec<&'static str> {
+        vec!["http-01"]
+    }
 }
 
 /// 证书订单会话
@@ -275,7 +280,8 @@ impl<R: AcmeChallengeResponder> AcmeOrderSession<R> {
         if let Some(order_info) = &self.order_info {
             for auth_url in &order_info.authorizations {
                 // 获取挑战信息
-                let challenge = self.client.get_challenge(auth_url).await?;
+                let challenge_types = self.responder.challenge_types();
+                let challenge = self.client.get_challenge(auth_url, &challenge_types).await?;
                 info!("got acme challenge, client: {}, challenge: {:?}", self, challenge);
                 // 准备挑战响应
                 match challenge.type_.as_str() {
@@ -390,16 +396,22 @@ impl std::fmt::Display for AcmeClient {
     }
 }
 
+pub const LETSENCRYPT_DIRECTORY_URL: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
 impl AcmeClient {
     // 已有方法改为使用 inner
     pub async fn new(account: AcmeAccount) -> Result<Self> {
-        info!("create acme client, account: {}", account);
-        let http_client = reqwest::Client::new();
-        
+        Self::new_with_directory(account, LETSENCRYPT_DIRECTORY_URL, reqwest::Client::new()).await
+    }
+
+    /// 使用其它 ACME 服务器，例如测试用的 pebble
+    pub async fn new_with_directory(account: AcmeAccount, directory_url: &str, http_client: reqwest::Client) -> Result<Self> {
+        info!("create acme client, account: {}, directory: {}", account, directory_url);
+
         info!("get acme directory");
         // 从 ACME 服务器获取目录
         let directory: Directory = http_client
-            .get("https://acme-v02.api.letsencrypt.org/directory")
+            .get(directory_url)
             .send()
             .await
             .map_err(|e| {
@@ -514,7 +526,7 @@ impl AcmeClient {
     }
 
     // 新增方法
-    async fn get_challenge(&self, auth_url: &str) -> Result<Challenge> {
+    async fn get_challenge(&self, auth_url: &str, challenge_types: &[&str]) -> Result<Challenge> {
         info!("get acme challenge, client: {}, auth_url: {}", self, auth_url);
         
         let response = self.inner.http_client
@@ -528,21 +540,26 @@ impl AcmeClient {
 
         let authz: AuthzResponse = response.json().await?;
         
-        // 选择 http-01 挑战
-        let challenge = authz.challenges.iter()
-            .find(|c| c.type_ == "http-01")
-            .ok_or_else(|| anyhow::anyhow!("No http-01 challenge found"))?;
+        // 按 responder 支持的顺序选择挑战
+        let challenge = select_challenge(&authz.challenges, challenge_types)
+            .ok_or_else(|| anyhow::anyhow!("No supported challenge found, auth_url: {}, supported: {}", auth_url, challenge_types.join(",")))?;
 
         // 计算 key authorization
         let key_auth = self.compute_key_authorization(&challenge.token)?;
-        
+        // dns-01 的 TXT 记录内容
+        let digest = if challenge.type_ == "dns-01" {
+            dns_challenge_digest(&key_auth)
+        } else {
+            "".to_string()
+        };
+
         Ok(Challenge {
             type_: challenge.type_.clone(),
             url: challenge.url.clone(),
             token: challenge.token.clone(),
             domain: authz.identifier.value,
             key_auth,
-            digest: "".to_string(), // 对于 http-01 挑战，不需要 digest
+            digest,
         })
     }
 
@@ -918,6 +935,86 @@ struct ChallengeResponse {
     token: String,
 }
 
+fn select_challenge<'a>(challenges: &'a [ChallengeResponse], challenge_types: &[&str]) -> Option<&'a ChallengeResponse> {
+    challenge_types.iter()
+        .find_map(|type_| challenges.iter().find(|c| c.type_ == *type_))
+}
+
+// base64url(sha256(key_auth))，RFC 8555 8.4
+fn dns_challenge_digest(key_auth: &str) -> String {
+    let mut hasher = openssl::sha::Sha256::new();
+    hasher.update(key_auth.as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hasher.finish())
+}
+
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+// id-pe-acmeIdentifier，RFC 8737 3
+const ACME_IDENTIFIER_OID: &str = "1.3.6.1.5.5.7.1.31";
+
+/// 生成 tls-alpn-01 挑战证书，返回 (cert pem, key pem)
+pub fn create_tls_alpn_challenge_cert(domain: &str, key_auth: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut hasher = openssl::sha::Sha256::new();
+    hasher.update(key_auth.as_bytes());
+    // 扩展值是 DER 编码的 OCTET STRING (SIZE (32))
+    let mut der = vec![0x04, 0x20];
+    der.extend_from_slice(&hasher.finish());
+    build_self_signed_cert(&[domain.to_string()], 7, Some(&der))
+}
+
+/// 生成自签名证书，返回 (cert pem, key pem)
+pub fn create_self_signed_cert(domains: &[String], valid_days: u32) -> Result<(Vec<u8>, Vec<u8>)> {
+    build_self_signed_cert(domains, valid_days, None)
+}
+
+fn build_self_signed_cert(domains: &[String], valid_days: u32, acme_identifier: Option<&[u8]>) -> Result<(Vec<u8>, Vec<u8>)> {
+    use openssl::asn1::{Asn1Integer, Asn1Object, Asn1OctetString, Asn1Time};
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::x509::{X509Builder, X509Extension};
+
+    if domains.is_empty() {
+        return Err(anyhow::anyhow!("No domain for self signed cert"));
+    }
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let pkey = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name_builder = X509NameBuilder::new()?;
+    name_builder.append_entry_by_text("CN", &domains[0])?;
+    let name = name_builder.build();
+
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let serial = Asn1Integer::from_bn(&serial)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&pkey)?;
+    builder.set_not_before(&*Asn1Time::days_from_now(0)?)?;
+    builder.set_not_after(&*Asn1Time::days_from_now(valid_days)?)?;
+
+    let mut san = SubjectAlternativeName::new();
+    for domain in domains {
+        san.dns(domain);
+    }
+    let san = san.build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
+
+    if let Some(acme_identifier) = acme_identifier {
+        let oid = Asn1Object::from_str(ACME_IDENTIFIER_OID)?;
+        let value = Asn1OctetString::new_from_bytes(acme_identifier)?;
+        builder.append_extension(X509Extension::new_from_der(&oid, true, &value)?)?;
+    }
+
+    builder.sign(&pkey, openssl::hash::MessageDigest::sha256())?;
+    Ok((builder.build().to_pem()?, pkey.private_key_to_pem_pkcs8()?))
+}
+
 #[derive(Debug, Deserialize)]
 struct FinalizeResponse {
     status: String,
@@ -0,0 +1,311 @@
+use crate::acme_client::AcmeChallengeResponder;
+use crate::cert_mgr::AcmeChallengeEntry;
+use anyhow::Result;
+use name_client::{NameInfo, NameProof, NsUpdateProvider, RecordType};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+// TXT记录的TTL尽量短,避免验证失败后重试时读到旧的记录
+const ACME_CHALLENGE_TXT_TTL: u32 = 60;
+
+pub fn acme_challenge_record_name(domain: &str) -> String {
+    format!("_acme-challenge.{}", domain.trim_start_matches("*.").trim_end_matches('.'))
+}
+
+// 证书管理器配置里的dns-01 provider
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum AcmeDnsProviderConfig {
+    Cloudflare {
+        api_token: String,
+        #[serde(default)]
+        email: String,
+        #[serde(default)]
+        known_domains: Vec<String>,
+    },
+    // 同一个gateway里的SN server,写到它的dns zone
+    Sn { server_id: String },
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AcmeDnsConfig {
+    #[serde(flatten)]
+    pub provider: AcmeDnsProviderConfig,
+    #[serde(default = "default_propagation_delay")]
+    pub propagation_delay: u64, // 写入记录后等待生效的秒数
+}
+
+fn default_propagation_delay() -> u64 {
+    10
+}
+
+// 在已有的challenge entry上增加dns-01支持,_acme-challenge的TXT记录通过NsUpdateProvider写入,
+// 可以是name-client的cloudflare provider,也可以是SN自己的dns zone
+// 没有provider时不提供dns-01,全部交给inner
+pub struct Dns01ChallengeEntry<E: AcmeChallengeEntry> {
+    inner: E,
+    provider: Option<Arc<dyn NsUpdateProvider>>,
+    // 写入记录后等待dns生效的时间
+    propagation_delay: Duration,
+}
+
+impl<E: AcmeChallengeEntry> Dns01ChallengeEntry<E> {
+    pub fn new(inner: E, provider: Arc<dyn NsUpdateProvider>, propagation_delay: Duration) -> Self {
+        Self {
+            inner,
+            provider: Some(provider),
+            propagation_delay,
+        }
+    }
+
+    pub fn without_provider(inner: E) -> Self {
+        Self {
+            inner,
+            provider: None,
+            propagation_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl<E: AcmeChallengeEntry> AcmeChallengeEntry for Dns01ChallengeEntry<E> {
+    type Responder = Dns01ChallengeResponder<E::Responder>;
+    fn create_challenge_responder(&self) -> Self::Responder {
+        Dns01ChallengeResponder {
+            inner: self.inner.create_challenge_responder(),
+            provider: self.provider.clone(),
+            propagation_delay: self.propagation_delay,
+        }
+    }
+}
+
+pub struct Dns01ChallengeResponder<R: AcmeChallengeResponder> {
+    inner: R,
+    provider: Option<Arc<dyn NsUpdateProvider>>,
+    propagation_delay: Duration,
+}
+
+#[async_trait::async_trait]
+impl<R: AcmeChallengeResponder> AcmeChallengeResponder for Dns01ChallengeResponder<R> {
+    async fn respond_http(&self, domain: &str, token: &str, key_auth: &str) -> Result<()> {
+        self.inner.respond_http(domain, token, key_auth).await
+    }
+    fn revert_http(&self, domain: &str, token: &str) {
+        self.inner.revert_http(domain, token)
+    }
+
+    async fn respond_dns(&self, domain: &str, digest: &str) -> Result<()> {
+        let provider = match self.provider.as_ref() {
+            Some(provider) => provider,
+            None => return self.inner.respond_dns(domain, digest).await,
+        };
+        let name = acme_challenge_record_name(domain);
+        info!("write acme dns challenge record: {} TXT {}", name, digest);
+        let record = NameInfo {
+            name: name.clone(),
+            address: vec![],
+            cname: None,
+            txt: Some(digest.to_string()),
+            did_document: None,
+            pk_x_list: None,
+            proof_type: NameProof::None,
+            create_time: 0,
+            ttl: Some(ACME_CHALLENGE_TXT_TTL),
+        };
+        provider.update(RecordType::TXT, record).await.map_err(|e| {
+            error!("write acme dns challenge record {} failed: {}", name, e);
+            anyhow::anyhow!("write acme dns challenge record {} failed: {}", name, e)
+        })?;
+
+        if !self.propagation_delay.is_zero() {
+            tokio::time::sleep(self.propagation_delay).await;
+        }
+        Ok(())
+    }
+    fn revert_dns(&self, domain: &str, digest: &str) {
+        let provider = match self.provider.clone() {
+            Some(provider) => provider,
+            None => return self.inner.revert_dns(domain, digest),
+        };
+        // 订单会话drop时调用,不能等待,在后台删除
+        let name = acme_challenge_record_name(domain);
+        tokio::spawn(async move {
+            if let Err(e) = provider.delete(&name, RecordType::TXT).await {
+                warn!("delete acme dns challenge record {} failed: {}", name, e);
+            }
+        });
+    }
+
+    async fn respond_tls_alpn(&self, domain: &str, key_auth: &str) -> Result<()> {
+        self.inner.respond_tls_alpn(domain, key_auth).await
+    }
+    fn revert_tls_alpn(&self, domain: &str, key_auth: &str) {
+        self.inner.revert_tls_alpn(domain, key_auth)
+    }
+
+    fn challenge_types(&self) -> Vec<&'static str> {
+        let mut types = vec![];
+        if self.provider.is_some() {
+            types.push("dns-01");
+        }
+        types.extend(self.inner.challenge_types());
+        types
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acme_client::{AcmeAccount, AcmeClient, AcmeOrderSession};
+    use name_lib::{NSError, NSResult};
+    use tokio::sync::Mutex;
+
+    struct NoopResponder;
+
+    #[async_trait::async_trait]
+    impl AcmeChallengeResponder for NoopResponder {
+        async fn respond_http(&self, _domain: &str, _token: &str, _key_auth: &str) -> Result<()> {
+            Err(anyhow::anyhow!("http-01 not supported"))
+        }
+        fn revert_http(&self, _domain: &str, _token: &str) {}
+        async fn respond_dns(&self, _domain: &str, _digest: &str) -> Result<()> {
+            Err(anyhow::anyhow!("dns-01 not supported"))
+        }
+        fn revert_dns(&self, _domain: &str, _digest: &str) {}
+        async fn respond_tls_alpn(&self, _domain: &str, _key_auth: &str) -> Result<()> {
+            Err(anyhow::anyhow!("tls-alpn-01 not supported"))
+        }
+        fn revert_tls_alpn(&self, _domain: &str, _key_auth: &str) {}
+    }
+
+    struct NoopEntry;
+
+    impl AcmeChallengeEntry for NoopEntry {
+        type Responder = NoopResponder;
+        fn create_challenge_responder(&self) -> Self::Responder {
+            NoopResponder
+        }
+    }
+
+    #[derive(Default)]
+    struct MemoryProvider {
+        records: Mutex<Vec<(String, String)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl NsUpdateProvider for MemoryProvider {
+        async fn update(&self, _record_type: RecordType, record: NameInfo) -> NSResult<NameInfo> {
+            self.records.lock().await.push((record.name.clone(), record.txt.clone().unwrap()));
+            Ok(record)
+        }
+        async fn delete(&self, name: &str, _record_type: RecordType) -> NSResult<Option<NameInfo>> {
+            self.records.lock().await.retain(|(n, _)| n != name);
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_acme_challenge_record_name() {
+        assert_eq!(acme_challenge_record_name("test.example.com"), "_acme-challenge.test.example.com");
+        assert_eq!(acme_challenge_record_name("*.example.com"), "_acme-challenge.example.com");
+        assert_eq!(acme_challenge_record_name("example.com."), "_acme-challenge.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_dns01_responder() {
+        let provider = Arc::new(MemoryProvider::default());
+        let entry = Dns01ChallengeEntry::new(NoopEntry, provider.clone(), Duration::ZERO);
+        let responder = entry.create_challenge_responder();
+        assert_eq!(responder.challenge_types(), vec!["dns-01", "http-01"]);
+
+        responder.respond_dns("*.example.com", "digest1").await.unwrap();
+        {
+            let records = provider.records.lock().await;
+            assert_eq!(records.as_slice(), &[("_acme-challenge.example.com".to_string(), "digest1".to_string())]);
+        }
+
+        responder.revert_dns("*.example.com", "digest1");
+        for _ in 0..100 {
+            if provider.records.lock().await.is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("acme challenge record not deleted");
+    }
+
+    #[test]
+    fn test_dns01_without_provider() {
+        let entry = Dns01ChallengeEntry::without_provider(NoopEntry);
+        let responder = entry.create_challenge_responder();
+        assert_eq!(responder.challenge_types(), vec!["http-01"]);
+    }
+
+    #[test]
+    fn test_acme_dns_config() {
+        let config: AcmeDnsConfig = serde_json::from_str(r#"{"provider": "sn", "server_id": "sn"}"#).unwrap();
+        assert_eq!(config.provider, AcmeDnsProviderConfig::Sn { server_id: "sn".to_string() });
+        assert_eq!(config.propagation_delay, 10);
+
+        let config: AcmeDnsConfig = serde_json::from_str(
+            r#"{"provider": "cloudflare", "api_token": "token", "propagation_delay": 30}"#,
+        ).unwrap();
+        assert!(matches!(config.provider, AcmeDnsProviderConfig::Cloudflare { .. }));
+        assert_eq!(config.propagation_delay, 30);
+    }
+
+    // pebble-challtestsrv的管理接口,pebble用它作为dns解析
+    struct ChallTestSrvProvider {
+        management_url: String,
+        client: reqwest::Client,
+    }
+
+    #[async_trait::async_trait]
+    impl NsUpdateProvider for ChallTestSrvProvider {
+        async fn update(&self, _record_type: RecordType, record: NameInfo) -> NSResult<NameInfo> {
+            let body = serde_json::json!({
+                "host": format!("{}.", record.name),
+                "value": record.txt.clone().unwrap(),
+            });
+            self.client.post(format!("{}/set-txt", self.management_url)).json(&body).send().await
+                .map_err(|e| NSError::Failed(e.to_string()))?;
+            Ok(record)
+        }
+        async fn delete(&self, name: &str, _record_type: RecordType) -> NSResult<Option<NameInfo>> {
+            let body = serde_json::json!({ "host": format!("{}.", name) });
+            self.client.post(format!("{}/clear-txt", self.management_url)).json(&body).send().await
+                .map_err(|e| NSError::Failed(e.to_string()))?;
+            Ok(None)
+        }
+    }
+
+    // 需要本地运行pebble和pebble-challtestsrv:
+    // PEBBLE_DIRECTORY=https://127.0.0.1:14000/dir PEBBLE_CHALLTESTSRV=http://127.0.0.1:8055 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_wildcard_cert_with_pebble() {
+        let directory = std::env::var("PEBBLE_DIRECTORY").unwrap_or("https://127.0.0.1:14000/dir".to_string());
+        let management_url = std::env::var("PEBBLE_CHALLTESTSRV").unwrap_or("http://127.0.0.1:8055".to_string());
+        let http_client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+
+        let client = AcmeClient::new_with_directory(AcmeAccount::new("test@example.com".to_string()), &directory, http_client)
+            .await
+            .unwrap();
+        let provider = Arc::new(ChallTestSrvProvider {
+            management_url,
+            client: reqwest::Client::new(),
+        });
+        let entry = Dns01ChallengeEntry::new(NoopEntry, provider, Duration::ZERO);
+        let session = AcmeOrderSession::new(
+            vec!["example.com".to_string(), "*.example.com".to_string()],
+            client,
+            entry.create_challenge_responder(),
+        );
+        let (cert, key) = session.start().await.unwrap();
+        assert!(!cert.is_empty());
+        assert!(!key.is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hkdf;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use futures::ready;
+
+// 每个frame: [u16 密文长度][密文+tag], 长度字段作为AAD参与认证
+// 明文为空的frame是关闭帧, 只在shutdown时发送, 没收到关闭帧就读到EOF说明流被截断
+const FRAME_HEADER_LEN: usize = 2;
+const TAG_LEN: usize = 16;
+pub const AEAD_MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+
+fn aead_error(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// HKDF-SHA256派生32字节key
+pub fn hkdf_derive_key(ikm: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(ikm);
+    let mut out = [0u8; 32];
+    prk.expand(&[info], hkdf::HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut out))
+        .expect("hkdf expand 32 bytes");
+    out
+}
+
+// 按方向派生(send_key, recv_key), 两端is_initiator相反, 得到的key正好交叉
+pub fn derive_direction_keys(session_key: &[u8; 32], salt: &[u8], is_initiator: bool) -> ([u8; 32], [u8; 32]) {
+    let i2r = hkdf_derive_key(session_key, salt, b"rtcp aead initiator->responder");
+    let r2i = hkdf_derive_key(session_key, salt, b"rtcp aead responder->initiator");
+    if is_initiator {
+        (i2r, r2i)
+    } else {
+        (r2i, i2r)
+    }
+}
+
+struct FrameCipher {
+    key: LessSafeKey,
+    counter: u64,
+}
+
+impl FrameCipher {
+    fn new(key: &[u8; 32]) -> Self {
+        let unbound = UnboundKey::new(&CHACHA20_POLY1305, key).expect("chacha20 key len is 32");
+        Self {
+            key: LessSafeKey::new(unbound),
+            counter: 0,
+        }
+    }
+
+    // nonce = 4字节0 + 8字节递增计数器, 收发双方各自计数,
+    // 被重放/重排/丢弃的frame会因为nonce不一致认证失败
+    fn next_nonce(&mut self) -> std::io::Result<Nonce> {
+        if self.counter == u64::MAX {
+            return Err(aead_error("aead nonce counter exhausted"));
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+}
+
+// 基于ChaCha20-Poly1305的分帧加密流, 用于替代只有机密性的EncryptedStream
+pub struct AeadStream<S> {
+    inner: S,
+    seal: FrameCipher,
+    open: FrameCipher,
+
+    // 已从inner读到但还没组成完整frame的数据
+    read_buf: Vec<u8>,
+    plain_buf: Vec<u8>,
+    plain_pos: usize,
+
+    // 已加密但还没写完的frame, 以及它对应的明文长度
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    write_plain_len: usize,
+
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<S> AeadStream<S> {
+    pub fn new(inner: S, send_key: &[u8; 32], recv_key: &[u8; 32]) -> Self {
+        Self {
+            inner,
+            seal: FrameCipher::new(send_key),
+            open: FrameCipher::new(recv_key),
+            read_buf: Vec::new(),
+            plain_buf: Vec::new(),
+            plain_pos: 0,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            write_plain_len: 0,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    // 加密一个frame放到write_buf, 调用前write_buf必须已经写完
+    fn seal_frame(&mut self, plain: &[u8]) -> std::io::Result<()> {
+        let header = ((plain.len() + TAG_LEN) as u16).to_be_bytes();
+        let mut frame = plain.to_vec();
+        let nonce = self.seal.next_nonce()?;
+        self.seal
+            .key
+            .seal_in_place_append_tag(nonce, Aad::from(header), &mut frame)
+            .map_err(|_| aead_error("aead seal failed"))?;
+
+        self.write_buf.extend_from_slice(&header);
+        self.write_buf.extend_from_slice(&frame);
+        self.write_pos = 0;
+        self.write_plain_len = plain.len();
+        Ok(())
+    }
+
+    fn try_decode_frame(&mut self) -> std::io::Result<bool> {
+        if self.read_buf.len() < FRAME_HEADER_LEN {
+            return Ok(false);
+        }
+        let header = [self.read_buf[0], self.read_buf[1]];
+        let frame_len = u16::from_be_bytes(header) as usize;
+        if !(TAG_LEN..=AEAD_MAX_FRAME_PAYLOAD + TAG_LEN).contains(&frame_len) {
+            return Err(aead_error("invalid aead frame length"));
+        }
+        if self.read_buf.len() < FRAME_HEADER_LEN + frame_len {
+            return Ok(false);
+        }
+
+        let mut frame: Vec<u8> = self
+            .read_buf
+            .drain(..FRAME_HEADER_LEN + frame_len)
+            .skip(FRAME_HEADER_LEN)
+            .collect();
+        let nonce = self.open.next_nonce()?;
+        let plain_len = self
+            .open
+            .key
+            .open_in_place(nonce, Aad::from(header), &mut frame)
+            .map_err(|_| aead_error("aead frame authentication failed"))?
+            .len();
+        if plain_len == 0 {
+            self.close_received = true;
+        }
+        frame.truncate(plain_len);
+        self.plain_buf = frame;
+        self.plain_pos = 0;
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AeadStream<S> {
+    fn poll_write_pending(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for AeadStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.plain_pos < this.plain_buf.len() {
+                let n = std::cmp::min(buf.remaining(), this.plain_buf.len() - this.plain_pos);
+                buf.put_slice(&this.plain_buf[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.close_received {
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.try_decode_frame()? {
+                continue;
+            }
+
+            let mut tmp = [0u8; 8192];
+            let mut tmp_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+            let filled = tmp_buf.filled();
+            if filled.is_empty() {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "aead stream closed without close frame",
+                )));
+            }
+            this.read_buf.extend_from_slice(filled);
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for AeadStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        // 上次Pending时frame已经加密, 调用方按约定会用同样的数据重试,
+        // 这里只需把剩余部分写完并返回当时的明文长度
+        if !this.write_buf.is_empty() {
+            ready!(this.poll_write_pending(cx))?;
+            return Poll::Ready(Ok(this.write_plain_len));
+        }
+        if this.close_sent {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "aead stream already closed",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let plain_len = std::cmp::min(buf.len(), AEAD_MAX_FRAME_PAYLOAD);
+        this.seal_frame(&buf[..plain_len])?;
+        ready!(this.poll_write_pending(cx))?;
+        Poll::Ready(Ok(plain_len))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.poll_write_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if !this.close_sent {
+            ready!(this.poll_write_pending(cx))?;
+            this.seal_frame(&[])?;
+            this.close_sent = true;
+        }
+        ready!(this.poll_write_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_aead_stream_roundtrip_and_tamper() {
+        let session_key = [7u8; 32];
+        let (a_send, a_recv) = derive_direction_keys(&session_key, b"stream", true);
+        let (b_send, b_recv) = derive_direction_keys(&session_key, b"stream", false);
+        assert_eq!(a_send, b_recv);
+        assert_ne!(a_send, a_recv);
+
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = AeadStream::new(a, &a_send, &a_recv);
+        let mut b = AeadStream::new(b, &b_send, &b_recv);
+
+        let data = vec![0x5au8; AEAD_MAX_FRAME_PAYLOAD * 2 + 100];
+        let expect = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&data).await.unwrap();
+            a.write_all(b"hello").await.unwrap();
+            a.shutdown().await.unwrap();
+        });
+        let mut got = Vec::new();
+        b.read_to_end(&mut got).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(&got[..expect.len()], &expect[..]);
+        assert_eq!(&got[expect.len()..], b"hello");
+
+        // 篡改密文
+        let (a, mut raw) = tokio::io::duplex(1024);
+        let mut a = AeadStream::new(a, &a_send, &a_recv);
+        a.write_all(b"secret").await.unwrap();
+        let mut frame = vec![0u8; FRAME_HEADER_LEN + 6 + TAG_LEN];
+        raw.read_exact(&mut frame).await.unwrap();
+        frame[3] ^= 1;
+
+        let (c, mut c_raw) = tokio::io::duplex(1024);
+        let mut c = AeadStream::new(c, &b_send, &b_recv);
+        c_raw.write_all(&frame).await.unwrap();
+        let mut buf = [0u8; 16];
+        assert!(c.read(&mut buf).await.is_err());
+
+        // 重放同一个frame, 计数器不匹配应失败
+        frame[3] ^= 1;
+        let (d, mut d_raw) = tokio::io::duplex(1024);
+        let mut d = AeadStream::new(d, &b_send, &b_recv);
+        d_raw.write_all(&frame).await.unwrap();
+        d_raw.write_all(&frame).await.unwrap();
+        assert_eq!(d.read(&mut buf).await.unwrap(), 6);
+        assert_eq!(&buf[..6], b"secret");
+        assert!(d.read(&mut buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_aead_stream_close_frame() {
+        let session_key = [9u8; 32];
+        let (a_send, a_recv) = derive_direction_keys(&session_key, b"close", true);
+        let (b_send, b_recv) = derive_direction_keys(&session_key, b"close", false);
+
+        // 正常关闭, 对端读到关闭帧后返回EOF
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = AeadStream::new(a, &a_send, &a_recv);
+        let mut b = AeadStream::new(b, &b_send, &b_recv);
+        a.write_all(b"bye").await.unwrap();
+        a.shutdown().await.unwrap();
+        assert!(a.write_all(b"more").await.is_err());
+        let mut got = Vec::new();
+        b.read_to_end(&mut got).await.unwrap();
+        assert_eq!(got, b"bye");
+
+        // 底层连接直接断开, 没有关闭帧, 对端应该报错而不是当作正常结束
+        let (a, b) = tokio::io::duplex(1024);
+        let mut a = AeadStream::new(a, &a_send, &a_recv);
+        let mut b = AeadStream::new(b, &b_send, &b_recv);
+        a.write_all(b"partial").await.unwrap();
+        drop(a);
+        let mut got = Vec::new();
+        let err = b.read_to_end(&mut got).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(got, b"partial");
+
+        // 伪造的空frame没有正确的tag, 不能当作关闭帧
+        let (c, mut c_raw) = tokio::io::duplex(1024);
+        let mut c = AeadStream::new(c, &b_send, &b_recv);
+        let mut fake_close = (TAG_LEN as u16).to_be_bytes().to_vec();
+        fake_close.extend_from_slice(&[0u8; TAG_LEN]);
+        c_raw.write_all(&fake_close).await.unwrap();
+        let mut buf = [0u8; 16];
+        assert!(c.read(&mut buf).await.is_err());
+    }
+}
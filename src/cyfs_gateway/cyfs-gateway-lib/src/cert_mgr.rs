@@ -1,18 +1,26 @@
 use rand::Rng;
 use tokio::fs;
 use anyhow::Result;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use rustls::server::{ResolvesServerCert, ClientHello};
 use rustls::sign::CertifiedKey;
 use std::sync::Arc;
 use std::sync::RwLock;
 use tokio::task;
 use log::*;
-use crate::acme_client::{AcmeClient, AcmeOrderSession, AcmeChallengeResponder, AcmeAccount};
+use crate::acme_client::{AcmeClient, AcmeOrderSession, AcmeChallengeResponder, AcmeAccount, ACME_TLS_ALPN_PROTOCOL, create_self_signed_cert, create_tls_alpn_challenge_cert};
 use crate::config::TlsConfig;
+use crate::acme_dns::AcmeDnsConfig;
 use openssl::x509::X509;
 use std::sync::Mutex;
 use serde::Deserialize;
+use lazy_static::lazy_static;
+use std::time::{Duration, Instant};
+
+// 按需申请失败后的退避时间,每次失败翻倍
+const ON_DEMAND_RETRY_BASE: Duration = Duration::from_secs(60);
+const ON_DEMAND_RETRY_MAX: Duration = Duration::from_secs(3600);
+const ON_DEMAND_ASK_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 struct CertInfo {
@@ -39,6 +47,8 @@ struct CertStubInner<R: AcmeChallengeEntry> {
     acme_client: AcmeClient,
     responder: Arc<R>,
     config: TlsConfig,
+    // 按需申请的证书在申请完成前使用的自签名证书
+    fallback: Option<Arc<CertifiedKey>>,
     mut_part: Mutex<CertMutPart<R::Responder>>,
 }
 
@@ -68,7 +78,8 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
         keystore_path: String, 
         acme_client: AcmeClient, 
         responder: Arc<R>, 
-        config: TlsConfig
+        config: TlsConfig,
+        fallback: Option<Arc<CertifiedKey>>,
     ) -> Self {
         Self {
             inner: Arc::new(CertStubInner {
@@ -77,6 +88,7 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
                 acme_client,
                 responder,
                 config,
+                fallback,
                 mut_part: Mutex::new(CertMutPart {
                     state: CertState::None,
                     ordering: false,
@@ -86,17 +98,6 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
         }
     }
 
-    fn create_certified_key(cert_data: &[u8], key_data: &[u8]) -> Result<CertifiedKey> {
-        let cert_chain = vec![rustls_pemfile::certs(&mut &*cert_data)?.remove(0)];
-        let key = rustls::PrivateKey(rustls_pemfile::pkcs8_private_keys(&mut &*key_data)?.remove(0));
-        
-        let signing_key = rustls::sign::any_supported_type(&key)
-            .map_err(|e| anyhow::anyhow!("Invalid private key: {}", e))?;
-        
-        let cert_chain = cert_chain.into_iter().map(rustls::Certificate).collect();
-        Ok(CertifiedKey::new(cert_chain, signing_key))
-    }
-
     fn get_cert_expiry(cert_data: &[u8]) -> Result<chrono::DateTime<chrono::Utc>> {
         let cert = X509::from_pem(cert_data)?;
         let not_after = cert.not_after().to_string();
@@ -116,8 +117,8 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
         match &mut_part.state {
             CertState::Ready(info) => Some(info.key.clone()),
             CertState::Renewing(info) => Some(info.key.clone()),
-            CertState::Expired(_) => None,
-            CertState::None => None,
+            CertState::Expired(_) => self.inner.fallback.clone(),
+            CertState::None => self.inner.fallback.clone(),
         }
     }
 
@@ -156,10 +157,9 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
             }
             
             if entries.is_empty() {
-                // 如果没有找到证书，启动证书申请流程
+                // 如果没有找到证书，启动证书申请流程；load_cert 已经占用了 ordering 标记
                 info!("no cert found in keystore, start ordering new cert, stub: {}", self);
-                self.start_order().await?;
-                return Ok(());
+                return self.order_and_update().await;
             }
             
             // 按文件名（时间戳）排序，取最新的
@@ -186,7 +186,7 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
                 anyhow::anyhow!("load cert failed, stub: {}, key_path: {}, {}", self, key_path, e)
             })?;
         
-        let certified_key = Self::create_certified_key(&cert_data, &key_data)
+        let certified_key = create_certified_key(&cert_data, &key_data)
             .map_err(|e| {
                 error!("create certified key failed, stub: {}, cert_path: {}, key_path: {}, {}", self, cert_path, key_path, e);
                 anyhow::anyhow!("create certified key failed, stub: {}, cert_path: {}, key_path: {}, {}", self, cert_path, key_path, e)
@@ -236,7 +236,12 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
                         }
                     }
                 }
-                CertState::Renewing(_) => true,
+                CertState::Renewing(info) => {
+                    if chrono::Utc::now() >= info.expires {
+                        mut_part.state = CertState::Expired(info.clone());
+                    }
+                    true
+                }
                 CertState::Expired(_) => true
             }
         };
@@ -263,7 +268,7 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
         fs::write(&cert_path, &cert_data).await?;
         fs::write(&key_path, &key_data).await?;
 
-        let certified_key = Self::create_certified_key(&cert_data, &key_data)?;
+        let certified_key = create_certified_key(&cert_data, &key_data)?;
         let expires = Self::get_cert_expiry(&cert_data)?;
 
         info!("save cert success, stub: {}, cert_path: {}, key_path: {}, expires: {}", 
@@ -281,22 +286,106 @@ impl<R: AcmeChallengeEntry> CertStub<R> {
             mut_part.ordering = true;
         }
       
-        let result = self.order_inner().await;
+        let result = self.order_and_update().await;
         
         let mut mut_part = self.inner.mut_part.lock().unwrap();
         mut_part.ordering = false;
-        match result {
-            Ok((certified_key, expires)) => {
-                mut_part.state = CertState::Ready(CertInfo {
-                    key: Arc::new(certified_key),
-                    expires,
-                });
-                Ok(())
-            }
-            Err(e) => {
-                Err(e)
-            }
+        result
+    }
+
+    // 调用者需要持有 ordering 标记
+    async fn order_and_update(&self) -> Result<()> {
+        let (certified_key, expires) = self.order_inner().await?;
+        let mut mut_part = self.inner.mut_part.lock().unwrap();
+        mut_part.state = CertState::Ready(CertInfo {
+            key: Arc::new(certified_key),
+            expires,
+        });
+        Ok(())
+    }
+}
+
+fn create_certified_key(cert_data: &[u8], key_data: &[u8]) -> Result<CertifiedKey> {
+    let cert_chain = vec![rustls_pemfile::certs(&mut &*cert_data)?.remove(0)];
+    let key = rustls::PrivateKey(rustls_pemfile::pkcs8_private_keys(&mut &*key_data)?.remove(0));
+    
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|e| anyhow::anyhow!("Invalid private key: {}", e))?;
+    
+    let cert_chain = cert_chain.into_iter().map(rustls::Certificate).collect();
+    Ok(CertifiedKey::new(cert_chain, signing_key))
+}
+
+// tls-alpn-01 的挑战证书，由 CertManager 在 acme-tls/1 握手时直接返回
+type TlsAlpnChallenges = Arc<RwLock<HashMap<String, Arc<CertifiedKey>>>>;
+
+// 包装使用者提供的 challenge entry，增加 tls-alpn-01 的应答
+struct CertChallengeEntry<R: AcmeChallengeEntry> {
+    entry: Arc<R>,
+    tls_alpn_challenges: TlsAlpnChallenges,
+    // 按需申请的证书优先使用 tls-alpn-01
+    prefer_tls_alpn: bool,
+}
+
+impl<R: AcmeChallengeEntry> AcmeChallengeEntry for CertChallengeEntry<R> {
+    type Responder = CertChallengeResponder<R::Responder>;
+    fn create_challenge_responder(&self) -> Self::Responder {
+        CertChallengeResponder {
+            inner: self.entry.create_challenge_responder(),
+            tls_alpn_challenges: self.tls_alpn_challenges.clone(),
+            prefer_tls_alpn: self.prefer_tls_alpn,
+        }
+    }
+}
+
+struct CertChallengeResponder<R: AcmeChallengeResponder> {
+    inner: R,
+    tls_alpn_challenges: TlsAlpnChallenges,
+    prefer_tls_alpn: bool,
+}
+
+#[async_trait::async_trait]
+impl<R: AcmeChallengeResponder> AcmeChallengeResponder for CertChallengeResponder<R> {
+    async fn respond_http(&self, domain: &str, token: &str, key_auth: &str) -> Result<()> {
+        self.inner.respond_http(domain, token, key_auth).await
+    }
+    fn revert_http(&self, domain: &str, token: &str) {
+        self.inner.revert_http(domain, token)
+    }
+
+    async fn respond_dns(&self, domain: &str, digest: &str) -> Result<()> {
+        self.inner.respond_dns(domain, digest).await
+    }
+    fn revert_dns(&self, domain: &str, digest: &str) {
+        self.inner.revert_dns(domain, digest)
+    }
+
+    async fn respond_tls_alpn(&self, domain: &str, key_auth: &str) -> Result<()> {
+        let (cert_data, key_data) = create_tls_alpn_challenge_cert(domain, key_auth)?;
+        let certified_key = create_certified_key(&cert_data, &key_data)?;
+        info!("set tls-alpn-01 challenge cert for domain: {}", domain);
+        self.tls_alpn_challenges.write().unwrap().insert(domain.to_string(), Arc::new(certified_key));
+        Ok(())
+    }
+    fn revert_tls_alpn(&self, domain: &str, _key_auth: &str) {
+        self.tls_alpn_challenges.write().unwrap().remove(domain);
+    }
+
+    fn challenge_types(&self) -> Vec<&'static str> {
+        if !self.prefer_tls_alpn {
+            return self.inner.challenge_types();
         }
+        let mut types = vec!["tls-alpn-01"];
+        types.extend(self.inner.challenge_types().into_iter().filter(|t| *t != "tls-alpn-01"));
+        types
+    }
+}
+
+/// 客户端是否在进行 tls-alpn-01 验证
+pub fn is_tls_alpn_challenge(client_hello: &ClientHello) -> bool {
+    match client_hello.alpn() {
+        Some(mut protocols) => protocols.any(|p| p == ACME_TLS_ALPN_PROTOCOL),
+        None => false,
     }
 }
 
@@ -317,19 +406,34 @@ impl<R: AcmeChallengeEntry> Clone for CertManager<R> {
     }
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct CertManagerConfig {
+    #[serde(default)]
     pub keystore_path: String,
     #[serde(default = "default_check_interval")]
     pub check_interval: chrono::Duration,     // 检查证书的时间间隔
     #[serde(default = "default_renew_before_expiry")]
     pub renew_before_expiry: chrono::Duration, // 过期前多久开始续期
+    #[serde(default)]
+    pub acme_directory: Option<String>,        // 默认使用 letsencrypt
+    #[serde(default)]
+    pub on_demand_suffixes: Vec<String>,       // 收到这些域名下未配置的 SNI 时按需申请证书
+    #[serde(default)]
+    pub on_demand_ask: Option<String>,         // 按需申请前询问的url, GET ?domain=host 返回200才申请
+    #[serde(default = "default_max_on_demand_certs")]
+    pub max_on_demand_certs: usize,            // 按需申请(含申请中)的证书数量上限
+    #[serde(default)]
+    pub acme_dns: Option<AcmeDnsConfig>,       // 配置后支持 dns-01 挑战，通配符证书需要
 }
 
 fn default_check_interval() -> chrono::Duration {
     chrono::Duration::hours(12)  // 默认每12小时检查一次
 }
 
+fn default_max_on_demand_certs() -> usize {
+    100
+}
+
 fn default_renew_before_expiry() -> chrono::Duration {
     chrono::Duration::days(30)   // 默认过期前30天续期
 }
@@ -340,6 +444,11 @@ impl Default for CertManagerConfig {
             keystore_path: String::new(),
             check_interval: default_check_interval(),
             renew_before_expiry: default_renew_before_expiry(),
+            acme_directory: None,
+            on_demand_suffixes: vec![],
+            on_demand_ask: None,
+            max_on_demand_certs: default_max_on_demand_certs(),
+            acme_dns: None,
         }
     }
 }
@@ -347,8 +456,53 @@ impl Default for CertManagerConfig {
 struct CertManagerInner<R: AcmeChallengeEntry> {
     config: CertManagerConfig,
     acme_client: AcmeClient,
-    responder: Arc<R>,
-    certs: RwLock<HashMap<String, CertStub<R>>>,
+    responder: Arc<CertChallengeEntry<R>>,
+    on_demand_responder: Arc<CertChallengeEntry<R>>,
+    tls_alpn_challenges: TlsAlpnChallenges,
+    certs: RwLock<HashMap<String, CertStub<CertChallengeEntry<R>>>>,
+    on_demand: Mutex<OnDemandState>,
+}
+
+// 按需申请的host,限制总数并对失败的host退避
+#[derive(Default)]
+struct OnDemandState {
+    pending: HashSet<String>,
+    issued: HashSet<String>,
+    failures: HashMap<String, (u32, Instant)>, // 失败次数, 下次允许重试的时间
+}
+
+impl OnDemandState {
+    fn try_start(&mut self, host: &str, max_certs: usize, now: Instant) -> bool {
+        if self.pending.contains(host) || self.issued.contains(host) {
+            return false;
+        }
+        if let Some((_, retry_at)) = self.failures.get(host) {
+            if now < *retry_at {
+                return false;
+            }
+        }
+        if self.pending.len() + self.issued.len() >= max_certs {
+            warn!("too many on demand certs, limit: {}, ignore host: {}", max_certs, host);
+            return false;
+        }
+        self.pending.insert(host.to_string());
+        true
+    }
+
+    fn finish(&mut self, host: &str, success: bool, now: Instant) {
+        self.pending.remove(host);
+        if success {
+            self.failures.remove(host);
+            self.issued.insert(host.to_string());
+            return;
+        }
+
+        let count = self.failures.get(host).map(|(count, _)| *count).unwrap_or(0) + 1;
+        let backoff = ON_DEMAND_RETRY_BASE
+            .saturating_mul(1 << (count - 1).min(16))
+            .min(ON_DEMAND_RETRY_MAX);
+        self.failures.insert(host.to_string(), (count, now + backoff));
+    }
 }
 
 impl<R: 'static + AcmeChallengeEntry> std::fmt::Display for CertManager<R> {
@@ -377,18 +531,34 @@ impl<R: 'static + AcmeChallengeEntry> CertManager<R> {
             }
         };
 
-        let acme_client = AcmeClient::new(account).await?;
+        let acme_client = match config.acme_directory.as_ref() {
+            Some(directory) => AcmeClient::new_with_directory(account, directory, reqwest::Client::new()).await?,
+            None => AcmeClient::new(account).await?,
+        };
         let account = acme_client.account();
         if let Err(e) = account.save_to_file(&*account_path).await {
             error!("Failed to save ACME account: {}", e);
         }
 
+        let responder = Arc::new(responder);
+        let tls_alpn_challenges = TlsAlpnChallenges::default();
         let manager = Self {
             inner: Arc::new(CertManagerInner {
                 config: config.clone(),
                 acme_client,
-                responder: Arc::new(responder),
+                responder: Arc::new(CertChallengeEntry {
+                    entry: responder.clone(),
+                    tls_alpn_challenges: tls_alpn_challenges.clone(),
+                    prefer_tls_alpn: false,
+                }),
+                on_demand_responder: Arc::new(CertChallengeEntry {
+                    entry: responder,
+                    tls_alpn_challenges: tls_alpn_challenges.clone(),
+                    prefer_tls_alpn: true,
+                }),
+                tls_alpn_challenges,
                 certs: RwLock::new(HashMap::new()),
+                on_demand: Mutex::new(OnDemandState::default()),
             })
         };
 
@@ -427,12 +597,18 @@ impl<R: 'static + AcmeChallengeEntry> CertManager<R> {
             return Err(anyhow::anyhow!("Failed to create certificate storage directory: {}", e));
         }
 
+        // 通配符证书同时包含 host 和 *.host，只能通过 dns-01 验证
+        let mut domains = vec![host.clone()];
+        if tls_config.enable_acme && tls_config.wildcard && !host.starts_with("*.") {
+            domains.push(format!("*.{}", host));
+        }
         let cert_stub = CertStub::new(
-            vec![host.clone()], 
+            domains, 
             keystore_path.to_str().unwrap().to_string(),
             self.inner.acme_client.clone(), 
             self.inner.responder.clone(), 
-            tls_config
+            tls_config,
+            None,
         );
         self.inner.certs.write().unwrap().insert(host, cert_stub.clone());
         task::spawn(async move {
@@ -443,7 +619,14 @@ impl<R: 'static + AcmeChallengeEntry> CertManager<R> {
         Ok(())
     }
 
-    fn get_cert_by_host(&self,host:&str) -> Option<CertStub<R>> {
+    pub fn remove_config(&self, host: &str) {
+        if self.inner.certs.write().unwrap().remove(host).is_some() {
+            info!("remove tls config for host: {}", host);
+        }
+        self.inner.on_demand.lock().unwrap().issued.remove(host);
+    }
+
+    fn get_cert_by_host(&self,host:&str) -> Option<CertStub<CertChallengeEntry<R>>> {
         let certs = self.inner.certs.read().unwrap();
         let cert = certs.get(host);
         if cert.is_some() {
@@ -452,17 +635,103 @@ impl<R: 'static + AcmeChallengeEntry> CertManager<R> {
         }
 
         for (key,value) in certs.iter() {
-            if key.starts_with("*.") {
-                if host.ends_with(&key[2..]) {
+            if let Some(domain) = key.strip_prefix("*.") {
+                if is_wildcard_match(host, domain) {
                     info!("find tls config for host: {} ==> key:{}",host,key);
                     return Some(value.clone());
                 }
+            } else if value.inner.config.wildcard && is_wildcard_match(host, key) {
+                info!("find wildcard tls config for host: {} ==> key:{}",host,key);
+                return Some(value.clone());
             }
         }
 
         None
     }
 
+    // 给非https的tls入口(例如DoT)使用,客户端没有带SNI时可以指定默认的host
+    pub fn get_cert(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        if let Some(cert_stub) = self.get_cert_by_host(host) {
+            return cert_stub.get_cert();
+        }
+        self.insert_on_demand(host);
+        None
+    }
+
+    // 正在进行的 tls-alpn-01 挑战证书
+    pub fn get_tls_alpn_challenge_cert(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        let cert = self.inner.tls_alpn_challenges.read().unwrap().get(host).cloned();
+        if cert.is_none() {
+            warn!("no tls-alpn-01 challenge for host: {}", host);
+        }
+        cert
+    }
+
+    // 未配置的 SNI 在允许的后缀下时在后台创建证书，本次握手失败；
+    // 创建完成后申请期间使用自签名证书
+    fn insert_on_demand(&self, host: &str) {
+        let host = host.to_ascii_lowercase();
+        if !is_on_demand_host(&host, &self.inner.config.on_demand_suffixes) {
+            return;
+        }
+        if !self.inner.on_demand.lock().unwrap().try_start(&host, self.inner.config.max_on_demand_certs, Instant::now()) {
+            return;
+        }
+
+        info!("on demand cert for host: {}", host);
+        let manager = self.clone();
+        task::spawn(async move {
+            let result = manager.create_on_demand(&host).await;
+            if let Err(e) = &result {
+                error!("on demand cert failed, host: {}, {}", host, e);
+            }
+            manager.inner.on_demand.lock().unwrap().finish(&host, result.is_ok(), Instant::now());
+        });
+    }
+
+    async fn create_on_demand(&self, host: &str) -> Result<()> {
+        if let Some(ask) = self.inner.config.on_demand_ask.as_ref() {
+            ask_on_demand(ask, host).await?;
+        }
+
+        let keystore_path = buckyos_kit::path_join(&self.inner.config.keystore_path, &sanitize_path_component(host));
+        fs::create_dir_all(&keystore_path).await
+            .map_err(|e| anyhow::anyhow!("Failed to create certificate storage directory: {} {}", e, keystore_path.display()))?;
+
+        let domains = vec![host.to_string()];
+        let fallback = task::spawn_blocking(move || {
+            create_self_signed_cert(&domains, 30)
+                .and_then(|(cert_data, key_data)| create_certified_key(&cert_data, &key_data))
+        }).await??;
+
+        let tls_config = TlsConfig {
+            enable_acme: true,
+            ..Default::default()
+        };
+        let cert_stub = CertStub::new(
+            vec![host.to_string()],
+            keystore_path.to_str().unwrap().to_string(),
+            self.inner.acme_client.clone(),
+            self.inner.on_demand_responder.clone(),
+            tls_config,
+            Some(Arc::new(fallback)),
+        );
+        self.inner.certs.write().unwrap().insert(host.to_string(), cert_stub.clone());
+
+        // 已经签发过的证书从 keystore 加载，没有时申请；失败后移除，退避后再重试
+        if let Err(e) = cert_stub.load_cert().await {
+            let mut certs = self.inner.certs.write().unwrap();
+            if certs.get(host).map(|stub| Arc::ptr_eq(&stub.inner, &cert_stub.inner)).unwrap_or(false) {
+                certs.remove(host);
+            }
+            return Err(e);
+        }
+        if let Err(e) = cert_stub.check_cert(self.inner.config.renew_before_expiry).await {
+            error!("check on demand cert error, stub: {}, {}", cert_stub, e);
+        }
+        Ok(())
+    }
+
     async fn check_all_certs(&self) -> Result<()> {
         let certs = self.inner.certs.read().unwrap().values().cloned().collect::<Vec<_>>();
         
@@ -484,12 +753,99 @@ impl<R: AcmeChallengeEntry> std::fmt::Debug for CertManager<R> {
 impl<R: 'static + AcmeChallengeEntry> ResolvesServerCert for CertManager<R> {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
         let server_name = client_hello.server_name().unwrap_or("").to_string();
-        let cert_stub = self.get_cert_by_host(&server_name);
-        if cert_stub.is_some() {
-            return cert_stub.unwrap().get_cert();
+        if is_tls_alpn_challenge(&client_hello) {
+            return self.get_tls_alpn_challenge_cert(&server_name);
+        }
+        self.get_cert(&server_name)
+    }
+}
+
+// 同一个gateway里的其它tls入口(例如cyfs-dns的DoT)复用cyfs-warp的证书管理器,
+// 这样证书只申请一次,acme挑战也由warp的http/tls-alpn入口完成
+pub trait SharedCertManager: Send + Sync {
+    fn insert_config(&self, host: String, tls_config: TlsConfig) -> Result<()>;
+    fn get_cert(&self, host: &str) -> Option<Arc<CertifiedKey>>;
+}
+
+impl<R: 'static + AcmeChallengeEntry> SharedCertManager for CertManager<R> {
+    fn insert_config(&self, host: String, tls_config: TlsConfig) -> Result<()> {
+        CertManager::insert_config(self, host, tls_config)
+    }
+
+    fn get_cert(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        CertManager::get_cert(self, host)
+    }
+}
+
+#[derive(Default)]
+struct SharedCertRegistry {
+    cert_mgr: Option<Arc<dyn SharedCertManager>>,
+    // 其它入口登记的host,warp重建证书管理器后需要重新插入
+    hosts: HashMap<String, TlsConfig>,
+}
+
+lazy_static! {
+    static ref SHARED_CERT_REGISTRY: RwLock<SharedCertRegistry> = RwLock::new(SharedCertRegistry::default());
+}
+
+pub fn set_shared_cert_manager(cert_mgr: Arc<dyn SharedCertManager>) {
+    let mut registry = SHARED_CERT_REGISTRY.write().unwrap();
+    for (host, tls_config) in registry.hosts.iter() {
+        if let Err(e) = cert_mgr.insert_config(host.clone(), tls_config.clone()) {
+            error!("insert shared tls config for host {} failed: {}", host, e);
         }
-        None
     }
+    info!("set shared cert manager");
+    registry.cert_mgr = Some(cert_mgr);
+}
+
+pub fn insert_shared_tls_config(host: String, tls_config: TlsConfig) -> Result<()> {
+    let mut registry = SHARED_CERT_REGISTRY.write().unwrap();
+    let cert_mgr = registry.cert_mgr.clone().ok_or_else(|| {
+        anyhow::anyhow!("no shared cert manager, a cyfs-warp server with tls is required")
+    })?;
+    cert_mgr.insert_config(host.clone(), tls_config.clone())?;
+    registry.hosts.insert(host, tls_config);
+    Ok(())
+}
+
+pub fn get_shared_cert(host: &str) -> Option<Arc<CertifiedKey>> {
+    let cert_mgr = SHARED_CERT_REGISTRY.read().unwrap().cert_mgr.clone()?;
+    cert_mgr.get_cert(host)
+}
+
+// 通配符证书只覆盖一级子域名, *.example.com 不匹配 a.b.example.com
+fn is_wildcard_match(host: &str, domain: &str) -> bool {
+    match host.strip_suffix(domain).and_then(|prefix| prefix.strip_suffix('.')) {
+        Some(label) => !label.is_empty() && !label.contains('.'),
+        None => false,
+    }
+}
+
+async fn ask_on_demand(ask: &str, host: &str) -> Result<()> {
+    let resp = reqwest::Client::new()
+        .get(ask)
+        .query(&[("domain", host)])
+        .timeout(ON_DEMAND_ASK_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("ask on demand cert for {} failed: {}", host, e))?;
+    if !resp.status().is_success() {
+        return Err(anyhow::anyhow!("on demand cert for {} is not allowed, status: {}", host, resp.status()));
+    }
+    Ok(())
+}
+
+fn is_on_demand_host(host: &str, suffixes: &[String]) -> bool {
+    if host.is_empty() || !host.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.') {
+        return false;
+    }
+    suffixes.iter().any(|suffix| {
+        let suffix = suffix.trim_start_matches("*.").trim_start_matches('.');
+        host.len() > suffix.len() + 1
+            && host.ends_with(suffix)
+            && host[..host.len() - suffix.len()].ends_with('.')
+    })
 }
 
 fn sanitize_path_component(s: &str) -> String {
@@ -508,3 +864,72 @@ fn sanitize_path_component(s: &str) -> String {
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_on_demand_host() {
+        let suffixes = vec!["*.app.example.com".to_string(), "zone.io".to_string()];
+        assert!(is_on_demand_host("a.app.example.com", &suffixes));
+        assert!(is_on_demand_host("b.a.zone.io", &suffixes));
+        assert!(!is_on_demand_host("app.example.com", &suffixes));
+        assert!(!is_on_demand_host("xzone.io", &suffixes));
+        assert!(!is_on_demand_host("../zone.io", &suffixes));
+        assert!(!is_on_demand_host("a.example.com", &suffixes));
+    }
+
+    #[test]
+    fn test_on_demand_state() {
+        let now = Instant::now();
+        let mut state = OnDemandState::default();
+        assert!(state.try_start("a.zone.io", 2, now));
+        assert!(!state.try_start("a.zone.io", 2, now));
+        assert!(state.try_start("b.zone.io", 2, now));
+        // 达到上限
+        assert!(!state.try_start("c.zone.io", 2, now));
+
+        state.finish("a.zone.io", true, now);
+        assert!(!state.try_start("a.zone.io", 2, now));
+        assert!(!state.try_start("c.zone.io", 2, now));
+
+        // 失败的host退避,并且不再占用名额
+        state.finish("b.zone.io", false, now);
+        assert!(!state.try_start("b.zone.io", 2, now));
+        assert!(state.try_start("b.zone.io", 2, now + ON_DEMAND_RETRY_BASE));
+        state.finish("b.zone.io", false, now);
+        assert!(!state.try_start("b.zone.io", 2, now + ON_DEMAND_RETRY_BASE));
+        assert!(state.try_start("b.zone.io", 2, now + ON_DEMAND_RETRY_BASE * 2));
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(is_wildcard_match("a.example.com", "example.com"));
+        assert!(!is_wildcard_match("a.b.example.com", "example.com"));
+        assert!(!is_wildcard_match("example.com", "example.com"));
+        assert!(!is_wildcard_match(".example.com", "example.com"));
+        assert!(!is_wildcard_match("aexample.com", "example.com"));
+    }
+
+    #[test]
+    fn test_tls_alpn_challenge_cert() {
+        let (cert_data, key_data) = create_tls_alpn_challenge_cert("test.example.com", "token.thumbprint").unwrap();
+        create_certified_key(&cert_data, &key_data).unwrap();
+
+        let cert = X509::from_pem(&cert_data).unwrap();
+        let names: Vec<String> = cert.subject_alt_names().unwrap().iter()
+            .filter_map(|name| name.dnsname().map(|s| s.to_string()))
+            .collect();
+        assert_eq!(names, vec!["test.example.com".to_string()]);
+
+        let mut hasher = openssl::sha::Sha256::new();
+        hasher.update(b"token.thumbprint");
+        let digest = hasher.finish();
+        let der = cert.to_der().unwrap();
+        assert!(der.windows(digest.len() + 2).any(|w| w[0] == 0x04 && w[1] == 0x20 && w[2..] == digest));
+
+        let (cert_data, key_data) = create_self_signed_cert(&["a.example.com".to_string()], 30).unwrap();
+        create_certified_key(&cert_data, &key_data).unwrap();
+    }
+}
@@ -3,6 +3,7 @@
 use tokio::fs;
 use std::collections::HashMap;
 use serde::Deserialize;
+use crate::cert_mgr::CertManagerConfig;
 use url::Url;
 use cyfs_socks::SocksProxyConfig;
 
@@ -10,7 +11,7 @@ fn default_true() -> bool {
     true
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct NamedDataMgrRouteConfig {
     pub named_data_mgr_id : String,
     #[serde(default = "default_true")]
@@ -42,7 +43,7 @@ impl Default for NamedDataMgrRouteConfig {
 }
 
 
-#[derive(Debug, Deserialize, Clone, Default)]
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
 pub struct HostConfig {
     #[serde(default)]
     pub enable_cors: bool,
@@ -54,19 +55,41 @@ pub struct HostConfig {
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum RedirectType {
     None,
     Permanent,
     Temporary,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-#[serde(from = "String")]
-
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(from = "UpstreamRouteConfigValue")]
 pub struct UpstreamRouteConfig {
+    // the first target of the group, used by single target upstream
     pub target: String,
     pub redirect: RedirectType,
+    pub group: Option<UpstreamGroupConfig>,
+}
+
+// "upstream": "http://localhost:9090 redirect" or "upstream": { "targets": [...] }
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UpstreamRouteConfigValue {
+    Target(String),
+    Group(UpstreamGroupConfig),
+}
+
+impl From<UpstreamRouteConfigValue> for UpstreamRouteConfig {
+    fn from(value: UpstreamRouteConfigValue) -> Self {
+        match value {
+            UpstreamRouteConfigValue::Target(s) => Self::from_str(&s),
+            UpstreamRouteConfigValue::Group(group) => Self {
+                target: group.targets.first().map(|t| t.url.clone()).unwrap_or_default(),
+                redirect: RedirectType::None,
+                group: Some(group),
+            },
+        }
+    }
 }
 
 impl From<String> for UpstreamRouteConfig {
@@ -95,12 +118,109 @@ impl UpstreamRouteConfig {
 
         Self {
             target,
-            redirect
+            redirect,
+            group: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub enum LoadBalancePolicy {
+    #[default]
+    #[serde(rename = "round_robin")]
+    RoundRobin,
+    #[serde(rename = "least_conn")]
+    LeastConn,
+    #[serde(rename = "consistent_hash")]
+    ConsistentHash,
+}
+
+fn default_upstream_weight() -> u32 {
+    1
+}
+
+fn default_upstream_retries() -> u32 {
+    1
+}
+
+// target is http(s) url or stream url like rtcp://ood1/:8080
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(from = "UpstreamTargetConfigValue")]
+pub struct UpstreamTargetConfig {
+    pub url: String,
+    pub weight: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UpstreamTargetConfigValue {
+    Url(String),
+    Target {
+        url: String,
+        #[serde(default = "default_upstream_weight")]
+        weight: u32,
+    },
+}
+
+impl From<UpstreamTargetConfigValue> for UpstreamTargetConfig {
+    fn from(value: UpstreamTargetConfigValue) -> Self {
+        match value {
+            UpstreamTargetConfigValue::Url(url) => Self { url, weight: 1 },
+            UpstreamTargetConfigValue::Target { url, weight } => Self { url, weight },
         }
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_health_check_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_check_interval() -> u64 {
+    10
+}
+
+fn default_health_check_timeout() -> u64 {
+    3
+}
+
+fn default_health_check_fails() -> u32 {
+    3
+}
+
+fn default_health_check_passes() -> u32 {
+    2
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct UpstreamHealthCheckConfig {
+    #[serde(default = "default_health_check_path")]
+    pub path: String,
+    #[serde(default = "default_health_check_interval")]
+    pub interval: u64, // seconds
+    #[serde(default = "default_health_check_timeout")]
+    pub timeout: u64, // seconds
+    // 连续失败多少次后摘除target,请求转发失败也会计数
+    #[serde(default = "default_health_check_fails")]
+    pub fails: u32,
+    // 摘除后连续成功多少次恢复
+    #[serde(default = "default_health_check_passes")]
+    pub passes: u32,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct UpstreamGroupConfig {
+    pub targets: Vec<UpstreamTargetConfig>,
+    #[serde(default)]
+    pub policy: LoadBalancePolicy,
+    // consistent_hash key, a request header name, client ip is used if not set
+    pub hash_key: Option<String>,
+    pub health_check: Option<UpstreamHealthCheckConfig>,
+    // only idempotent requests are retried on another target
+    #[serde(default = "default_upstream_retries")]
+    pub retries: u32,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct ResponseRouteConfig {
     pub status: Option<u16>,
     pub headers: Option<HashMap<String, String>>,
@@ -108,10 +228,69 @@ pub struct ResponseRouteConfig {
 }
 
 
+// path rewrite before the request is handled, "/api/v1/x" => strip "/api" + add "/app" => "/app/v1/x"
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct RewriteConfig {
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub struct HeaderRulesConfig {
+    #[serde(default)]
+    pub set: HashMap<String, String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+pub enum RateLimitKey {
+    #[default]
+    #[serde(rename = "client_ip")]
+    ClientIp,
+    // session token verified by the krpc_token auth of the route, fallback to client ip
+    #[serde(rename = "session_token")]
+    SessionToken,
+}
+
+fn default_rate_limit_period() -> u64 {
+    1
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub key: RateLimitKey,
+    // allowed requests per period
+    pub requests: u32,
+    #[serde(default = "default_rate_limit_period")]
+    pub period: u64, // seconds
+    // max requests in a burst, default is requests
+    pub burst: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum RouteAuthConfig {
+    #[serde(rename = "basic")]
+    Basic {
+        // username => "sha256:" + hex(sha256(password)), plaintext passwords are rejected
+        users: HashMap<String, String>,
+        realm: Option<String>,
+    },
+    // kRPC session token in Authorization header or kRPC request body
+    #[serde(rename = "krpc_token")]
+    KRPCToken {
+        // kid => public key jwk, "$default" for token without kid
+        trust_keys: HashMap<String, serde_json::Value>,
+        appids: Option<Vec<String>>,
+    },
+}
+
 fn default_enable_cors() -> bool {
     true
 }
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct RouteConfig {
     #[serde(default = "default_enable_cors")]
     pub enable_cors: bool, 
@@ -122,14 +301,26 @@ pub struct RouteConfig {
     pub tunnel_selector: Option<String>,
     pub bucky_service: Option<String>,
     pub named_mgr: Option<NamedDataMgrRouteConfig>,
+
+    // filters, applied before the handler above
+    pub rewrite: Option<RewriteConfig>,
+    pub request_headers: Option<HeaderRulesConfig>,
+    // applied after cors headers, so cors headers can be overridden
+    pub response_headers: Option<HeaderRulesConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub max_body_size: Option<u64>,
+    pub auth: Option<RouteAuthConfig>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct TlsConfig {
     pub disable_tls: bool,
     pub enable_acme: bool,
     pub cert_path: Option<String>,
     pub key_path: Option<String>,
+    //request a cert for both host and *.host by acme, requires dns-01 challenge
+    #[serde(default)]
+    pub wildcard: bool,
 }
 
 
@@ -140,6 +331,7 @@ impl Default for TlsConfig {
             enable_acme: false,
             cert_path: None,
             key_path: None,
+            wildcard: false,
         }
     }
 }
@@ -153,7 +345,7 @@ fn default_http_port() -> u16 {
     80
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct WarpServerConfig {
     #[serde(default = "default_tls_port")]
     pub tls_port:u16,
@@ -161,6 +353,9 @@ pub struct WarpServerConfig {
     pub http_port:u16,
     pub bind:Option<String>,
     pub hosts: HashMap<String, HostConfig>,
+    //acme and on-demand tls, keystore_path defaults to the cyfs-warp data dir
+    #[serde(default)]
+    pub cert_mgr: Option<CertManagerConfig>,
 }
 
 impl WarpServerConfig {
@@ -173,14 +368,14 @@ impl WarpServerConfig {
 
 
 
-#[derive(Deserialize, Debug,Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub enum DNSProviderType {
     #[serde(rename = "dns")]
     DNS,//query name info by system
     SN,//query name info by sn server
 }
 
-#[derive(Deserialize,Clone,Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct DNSProviderConfig {
     #[serde(rename = "type")]
     pub provider_type: DNSProviderType,
@@ -188,27 +383,68 @@ pub struct DNSProviderConfig {
     pub config: serde_json::Value,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Clone, Debug, PartialEq)]
 pub struct DNSServerConfig {
     pub bind : Option<String>,
     pub port : u16,
-    //dot_port : u16,
-    //doh_port : u16,
-    //tls: Option<TlsConfig>, include cert.pem and key.pem
+    //DNS-over-TLS port, usually 853, 0 means disabled
+    #[serde(default)]
+    pub dot_port : u16,
+    //DNS-over-HTTPS is served by cyfs-warp: a route with inner_service set to the id of this server
+    //tls config of this_name, DoT uses the cert from CertManager
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
     //dnssec: bool,
     pub this_name:Option<String>,
     pub resolver_chain : Vec<DNSProviderConfig>,
     pub fallback : Vec<String>,//fallback dns servers
+    //authoritative zone for names under this_name, if set, the server will answer SOA/NS with AA flag
+    #[serde(default)]
+    pub zone: Option<DNSZoneConfig>,
+}
+
+fn default_zone_ttl() -> u32 {
+    600
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DNSZoneRecordConfig {
+    //relative to the zone, "@" is the zone apex
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,//A,AAAA,CNAME,TXT, NS is only allowed in DNSZoneConfig.ns
+    pub value: String,
+    pub ttl: Option<u32>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct DNSZoneConfig {
+    //name servers of the zone, like ["sn.buckyos.io"]
+    pub ns: Vec<String>,
+    //mailbox of the zone admin in SOA, like "hostmaster.buckyos.io"
+    pub admin: Option<String>,
+    //default is the start time of the server
+    pub serial: Option<u32>,
+    #[serde(default = "default_zone_ttl")]
+    pub ttl: u32,
+    //static records of the zone, include the glue records of ns, they are also sent in AXFR
+    #[serde(default)]
+    pub records: Vec<DNSZoneRecordConfig>,
+    //ip of the secondary servers allowed to AXFR
+    #[serde(default)]
+    pub allow_transfer: Vec<String>,
+    //ed25519 zone key (pkcs8 pem) for DNSSEC signing, like "{BUCKYOS_ROOT}/etc/sn_zone_key.pem"
+    pub dnssec_key_path: Option<String>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ServerConfig {
     Warp(WarpServerConfig),
     DNS(DNSServerConfig),
     Socks(SocksProxyConfig),
 }
 
-#[derive(Clone,Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DispatcherTarget {
     Forward(Url),
     Server(String),
@@ -216,7 +452,7 @@ pub enum DispatcherTarget {
     ProbeSelector(String,String), //probeid,selectorid
 }
 
-#[derive(Clone,Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct DispatcherConfig {
     pub incoming: Url,
     pub target: DispatcherTarget
@@ -253,6 +489,23 @@ impl DispatcherConfig {
     }
 }
 
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct StreamSelectorRule {
+    //"example.com" or "*.example.com", None means any host
+    pub host: Option<String>,
+    //app_protocol set by probe, like https/http/ssh/socks, None means any protocol
+    pub protocol: Option<String>,
+    //stream url
+    pub target: String,
+}
+
+//selectors in gateway config, used by probe_selector dispatcher to fan out one port to different backends
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct StreamSelectorConfig {
+    #[serde(default)]
+    pub rules: Vec<StreamSelectorRule>,
+    pub default: Option<String>,
+}
 
 pub fn gen_demo_gateway_json_config() -> String {
     let result = r#"
@@ -373,6 +626,21 @@ pub fn gen_demo_gateway_json_config() -> String {
         "tcp://0.0.0.0:6001":{
             "type":"forward",
             "target":"192.168.1.102:6001"
+        },
+        "tcp://0.0.0.0:8443":{
+            "type":"probe_selector",
+            "probe_id":"protocol",
+            "selector_id":"main_8443_selector"
+        }
+    },
+    "selectors" : {
+        "main_8443_selector":{
+            "rules":[
+                {"host":"*.app.example.com", "target":"rtcp://ood02/127.0.0.1:443"},
+                {"host":"another.com", "protocol":"https", "target":"tcp:///127.0.0.1:443"},
+                {"protocol":"ssh", "target":"tcp:///127.0.0.1:22"}
+            ],
+            "default":"tcp:///127.0.0.1:80"
         }
     }
 }    
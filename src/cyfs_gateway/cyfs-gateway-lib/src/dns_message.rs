@@ -0,0 +1,38 @@
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+// DoH等非udp的入口把原始的dns报文交给dns server处理,返回编码后的应答报文
+#[async_trait]
+pub trait DnsMessageHandler: Send + Sync {
+    async fn handle_dns_message(
+        &self,
+        message: &[u8],
+        client_addr: SocketAddr,
+    ) -> anyhow::Result<Vec<u8>>;
+}
+
+lazy_static! {
+    static ref DNS_MESSAGE_HANDLERS: RwLock<HashMap<String, Arc<dyn DnsMessageHandler>>> =
+        RwLock::new(HashMap::new());
+}
+
+// server_id是gateway配置中cyfs-dns server的id, cyfs-warp的inner_service可以直接引用它来提供DoH
+pub fn register_dns_message_handler(server_id: &str, handler: Arc<dyn DnsMessageHandler>) {
+    info!("register dns message handler: {}", server_id);
+    DNS_MESSAGE_HANDLERS
+        .write()
+        .unwrap()
+        .insert(server_id.to_string(), handler);
+}
+
+pub fn unregister_dns_message_handler(server_id: &str) {
+    info!("unregister dns message handler: {}", server_id);
+    DNS_MESSAGE_HANDLERS.write().unwrap().remove(server_id);
+}
+
+pub fn get_dns_message_handler(server_id: &str) -> Option<Arc<dyn DnsMessageHandler>> {
+    DNS_MESSAGE_HANDLERS.read().unwrap().get(server_id).cloned()
+}
@@ -1,9 +1,11 @@
 #![allow(dead_code)]
 
 mod aes_stream;
+mod aead_stream;
 mod config;
 mod ip;
 mod rtcp;
+mod rudp;
 mod tunnel;
 mod tunnel_connector;
 mod tunnel_mgr;
@@ -11,11 +13,15 @@ mod socks;
 mod selector;
 mod acme_client;
 mod cert_mgr;
+mod acme_dns;
+mod dns_message;
 
 
 pub use aes_stream::*;
+pub use aead_stream::*;
 pub use config::*;
 pub use rtcp::*;
+pub use rudp::*;
 pub use tunnel::*;
 pub use tunnel_connector::*;
 pub use tunnel_mgr::*;
@@ -23,6 +29,8 @@ pub use socks::*;
 pub use selector::*;
 pub use cert_mgr::*;
 pub use acme_client::*;
+pub use acme_dns::*;
+pub use dns_message::*;
 
 use once_cell::sync::OnceCell;
 use thiserror::Error;
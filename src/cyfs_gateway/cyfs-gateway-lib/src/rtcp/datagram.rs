@@ -1,4 +1,4 @@
-use crate::tunnel::DatagramClient;
+use crate::tunnel::{DatagramClient, DatagramClientBox};
 use buckyos_kit::AsyncStream;
 use std::sync::Arc;
 use tokio::io::AsyncReadExt;
@@ -98,11 +98,20 @@ impl DatagramClient for RTcpTunnelDatagramClient {
 pub struct DatagramForwarder {
     target_addr: String,
     client: Arc<UdpSocket>,
-    stream: AsyncStreamWithDatagram,
+    stream: Box<dyn DatagramClientBox>,
 }
 
 impl DatagramForwarder {
     pub async fn new(target_addr: &str, bind: &str, stream: Box<dyn AsyncStream>) -> std::io::Result<Self> {
+        let stream = RTcpTunnelDatagramClient::new(stream);
+        Self::with_client(target_addr, bind, Box::new(stream)).await
+    }
+
+    pub async fn with_client(
+        target_addr: &str,
+        bind: &str,
+        stream: Box<dyn DatagramClientBox>,
+    ) -> std::io::Result<Self> {
         let client = UdpSocket::bind(bind).await.map_err(|e| {
             let msg = format!("UDP socket bind to {} failed: {:?}", bind, e);
             error!("{}", msg);
@@ -112,7 +121,7 @@ impl DatagramForwarder {
         let ret = Self {
             target_addr: target_addr.to_string(),
             client: Arc::new(client),
-            stream: AsyncStreamWithDatagram::new(stream),
+            stream,
         };
 
         Ok(ret)
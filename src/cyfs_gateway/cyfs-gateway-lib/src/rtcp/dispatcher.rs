@@ -1,6 +1,6 @@
-use super::datagram::AsyncStreamWithDatagram;
+use super::datagram::RTcpTunnelDatagramClient;
 use crate::tunnel::TunnelEndpoint;
-use crate::tunnel::{DatagramServer, StreamListener};
+use crate::tunnel::{DatagramClientBox, DatagramServer, StreamListener};
 use crate::{TunnelError, TunnelResult};
 use buckyos_kit::AsyncStream;
 use std::collections::HashMap;
@@ -57,7 +57,7 @@ impl StreamListener for RTcpStreamDispatcher {
 #[derive(Clone)]
 pub struct RTcpDatagramDispatcher {
     bind_url: Url,
-    streams: Arc<Mutex<HashMap<TunnelEndpoint, AsyncStreamWithDatagram>>>,
+    streams: Arc<Mutex<HashMap<TunnelEndpoint, Box<dyn DatagramClientBox>>>>,
 
     rx: Arc<AsyncMutex<mpsc::Receiver<(Vec<u8>, TunnelEndpoint)>>>,
     tx: Arc<AsyncMutex<mpsc::Sender<(Vec<u8>, TunnelEndpoint)>>>,
@@ -79,9 +79,18 @@ impl RTcpDatagramDispatcher {
         &self,
         stream: Box<dyn AsyncStream>,
         endpoint: TunnelEndpoint,
+    ) -> TunnelResult<()> {
+        let client = RTcpTunnelDatagramClient::new(stream);
+        self.on_new_datagram_client(Box::new(client), endpoint).await
+    }
+
+    // 不经过stream封装的datagram会话(如rudp)直接交给dispatcher
+    pub async fn on_new_datagram_client(
+        &self,
+        stream: Box<dyn DatagramClientBox>,
+        endpoint: TunnelEndpoint,
     ) -> TunnelResult<()> {
         let mut streams = self.streams.lock().unwrap();
-        let stream = AsyncStreamWithDatagram::new(stream);
         let prev = streams.insert(endpoint.clone(), stream.clone());
         if prev.is_some() {
             // If the stream already exists, we should replace it
@@ -101,7 +110,7 @@ impl RTcpDatagramDispatcher {
         Ok(())
     }
 
-    async fn run_recv(&self, stream: AsyncStreamWithDatagram, endpoint: TunnelEndpoint) {
+    async fn run_recv(&self, stream: Box<dyn DatagramClientBox>, endpoint: TunnelEndpoint) {
         let mut buffer = vec![0u8; 4096];
         loop {
             match stream.recv_datagram(&mut buffer).await {
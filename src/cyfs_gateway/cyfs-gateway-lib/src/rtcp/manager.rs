@@ -1,4 +1,6 @@
 use super::stack::RTcpStack;
+use super::stats::{RTcpKeepaliveConfig, RTcpTunnelStats};
+use crate::rudp::{RUdpStack, DEFAULT_RUDP_STACK_PORT};
 use crate::GatewayDeviceRef;
 use crate::TunnelResult;
 use name_lib::DID;
@@ -10,6 +12,10 @@ use tokio::sync::Mutex;
 pub struct RTcpStackManager {
     device: GatewayDeviceRef,
     stack_map: Arc<Mutex<HashMap<DID, RTcpStack>>>,
+    rudp_stack: Arc<Mutex<Option<RUdpStack>>>,
+    rendezvous_addr: Option<String>,
+    keepalive: RTcpKeepaliveConfig,
+    allow_legacy: bool,
 }
 
 impl RTcpStackManager {
@@ -17,9 +23,25 @@ impl RTcpStackManager {
         Self {
             device,
             stack_map: Arc::new(Mutex::new(HashMap::new())),
+            rudp_stack: Arc::new(Mutex::new(None)),
+            rendezvous_addr: None,
+            keepalive: RTcpKeepaliveConfig::default(),
+            allow_legacy: false,
         }
     }
 
+    pub fn set_rendezvous(&mut self, rendezvous_addr: Option<String>) {
+        self.rendezvous_addr = rendezvous_addr;
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: RTcpKeepaliveConfig) {
+        self.keepalive = keepalive;
+    }
+
+    pub fn set_allow_legacy(&mut self, allow_legacy: bool) {
+        self.allow_legacy = allow_legacy;
+    }
+
     pub async fn get_rtcp_stack(&self, device_did: &DID) -> Option<RTcpStack> {
         let stack_map = self.stack_map.lock().await;
         stack_map.get(device_did).cloned()
@@ -45,10 +67,39 @@ impl RTcpStackManager {
             2980,
             Some(self.device.private_key.clone()),
         );
+        result_rtcp_stack.set_keepalive(self.keepalive.clone());
+        result_rtcp_stack.set_allow_legacy(self.allow_legacy);
+        if let Some(rendezvous_addr) = self.rendezvous_addr.as_ref() {
+            result_rtcp_stack.enable_punch(rendezvous_addr)?;
+        }
         result_rtcp_stack.start().await?;
 
         rtcp_stack_map.insert(self.device.config.id.clone(), result_rtcp_stack.clone());
 
         return Ok(result_rtcp_stack);
     }
+
+    // 只统计已经启动的stack, 不会为了查询去创建stack
+    pub async fn get_tunnel_stats(&self) -> Vec<RTcpTunnelStats> {
+        let stacks: Vec<RTcpStack> = self.stack_map.lock().await.values().cloned().collect();
+        let mut stats = Vec::new();
+        for stack in stacks {
+            stats.extend(stack.get_tunnel_stats().await);
+        }
+        stats
+    }
+
+    // rudp复用当前设备rtcp stack的身份和token
+    pub async fn get_current_device_rudp_stack(&self) -> TunnelResult<RUdpStack> {
+        let mut rudp_stack = self.rudp_stack.lock().await;
+        if let Some(stack) = rudp_stack.as_ref() {
+            return Ok(stack.clone());
+        }
+
+        let rtcp_stack = self.get_current_device_stack().await?;
+        let mut stack = RUdpStack::new(rtcp_stack, DEFAULT_RUDP_STACK_PORT);
+        stack.start().await?;
+        *rudp_stack = Some(stack.clone());
+        Ok(stack)
+    }
 }
@@ -7,8 +7,19 @@ mod stack;
 mod manager;
 mod dispatcher;
 mod datagram;
+mod mux;
+mod punch;
+mod stats;
 mod test;
 
 pub use protocol::*;
 pub use stack::*;
-pub use manager::*;
\ No newline at end of file
+pub use manager::*;
+pub use stats::{RTcpKeepaliveConfig, RTcpTunnelStats};
+pub(crate) use package::StreamPurpose;
+pub(crate) use dispatcher::RTcpDispatcherManager;
+pub(crate) use datagram::DatagramForwarder;
+pub use punch::{
+    create_rendezvous_token, read_rendezvous_message, write_rendezvous_message,
+    RTcpPunchClient, RendezvousMessage, RendezvousTokenPayload,
+};
\ No newline at end of file
@@ -0,0 +1,449 @@
+// tunnel复用模式: 所有stream作为子通道跑在tunnel的tcp连接上,
+// 数据包按stream_id分发, 每个stream有独立的发送窗口, 通过MuxWindow归还
+use super::package::*;
+use log::*;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+pub(crate) const MUX_INITIAL_WINDOW: u32 = 256 * 1024;
+pub(crate) const MUX_MAX_DATA_LEN: usize = 32 * 1024;
+const MUX_OPEN_TIMEOUT: Duration = Duration::from_secs(60);
+
+// 待写入tunnel的帧, 由tunnel的writer任务按顺序发送
+#[derive(Debug)]
+pub(crate) enum MuxFrame {
+    Open(RTcpMuxOpenPackage),
+    OpenResp(RTcpMuxOpenRespPackage),
+    Data(u32, Vec<u8>),
+    Window(u32, u32),
+    Close(u32, bool),
+    Shutdown,
+}
+
+struct MuxStreamState {
+    recv_buf: VecDeque<Vec<u8>>,
+    recv_len: usize,
+    recv_closed: bool,
+    local_closed: bool,
+    reset: bool,
+
+    send_window: u32,
+    // 已经被读走但还没归还给对端的窗口
+    unacked: u32,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl MuxStreamState {
+    fn new() -> Self {
+        Self {
+            recv_buf: VecDeque::new(),
+            recv_len: 0,
+            recv_closed: false,
+            local_closed: false,
+            reset: false,
+            send_window: MUX_INITIAL_WINDOW,
+            unacked: 0,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn wake_all(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type MuxStreamShared = Arc<Mutex<MuxStreamState>>;
+
+#[derive(Clone)]
+pub(crate) struct RTcpMux {
+    streams: Arc<Mutex<HashMap<u32, MuxStreamShared>>>,
+    pending_open: Arc<Mutex<HashMap<u32, oneshot::Sender<u32>>>>,
+    next_stream_id: Arc<AtomicU32>,
+    frame_tx: mpsc::UnboundedSender<MuxFrame>,
+    frame_rx: Arc<Mutex<Option<mpsc::UnboundedReceiver<MuxFrame>>>>,
+}
+
+impl RTcpMux {
+    // 发起方使用奇数stream_id, 接受方使用偶数, 双方同时open不会冲突
+    pub fn new(is_initiator: bool) -> Self {
+        let (frame_tx, frame_rx) = mpsc::unbounded_channel();
+        Self {
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            pending_open: Arc::new(Mutex::new(HashMap::new())),
+            next_stream_id: Arc::new(AtomicU32::new(if is_initiator { 1 } else { 2 })),
+            frame_tx,
+            frame_rx: Arc::new(Mutex::new(Some(frame_rx))),
+        }
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.streams.lock().unwrap().len()
+    }
+
+    pub fn take_frame_receiver(&self) -> Option<mpsc::UnboundedReceiver<MuxFrame>> {
+        self.frame_rx.lock().unwrap().take()
+    }
+
+    fn post_frame(&self, frame: MuxFrame) -> bool {
+        self.frame_tx.send(frame).is_ok()
+    }
+
+    fn register_stream(&self, stream_id: u32) -> RTcpMuxStream {
+        let shared = Arc::new(Mutex::new(MuxStreamState::new()));
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(stream_id, shared.clone());
+        RTcpMuxStream {
+            stream_id,
+            shared,
+            mux: self.clone(),
+        }
+    }
+
+    fn get_stream(&self, stream_id: u32) -> Option<MuxStreamShared> {
+        self.streams.lock().unwrap().get(&stream_id).cloned()
+    }
+
+    pub async fn open_stream(
+        &self,
+        seq: u32,
+        purpose: Option<StreamPurpose>,
+        dest_port: u16,
+        dest_host: Option<String>,
+    ) -> Result<RTcpMuxStream, std::io::Error> {
+        let stream_id = self.next_stream_id.fetch_add(2, Ordering::SeqCst);
+        let stream = self.register_stream(stream_id);
+        let (tx, rx) = oneshot::channel();
+        self.pending_open.lock().unwrap().insert(stream_id, tx);
+
+        let open_package =
+            RTcpMuxOpenPackage::new(seq, stream_id, purpose, dest_port, dest_host);
+        if !self.post_frame(MuxFrame::Open(open_package)) {
+            self.pending_open.lock().unwrap().remove(&stream_id);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "rtcp tunnel closed",
+            ));
+        }
+
+        let result = timeout(MUX_OPEN_TIMEOUT, rx).await;
+        self.pending_open.lock().unwrap().remove(&stream_id);
+        match result {
+            Ok(Ok(0)) => Ok(stream),
+            Ok(Ok(result)) => {
+                let msg = format!("mux open stream {} failed, result:{}", stream_id, result);
+                warn!("{}", msg);
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    msg,
+                ))
+            }
+            Ok(Err(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "rtcp tunnel closed",
+            )),
+            Err(_) => {
+                error!("Timeout: mux open stream {} no response", stream_id);
+                Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Timeout"))
+            }
+        }
+    }
+
+    // 收到对端MuxOpen, 返回None表示stream_id重复
+    pub fn accept_stream(&self, stream_id: u32) -> Option<RTcpMuxStream> {
+        if self.get_stream(stream_id).is_some() {
+            warn!("mux stream {} already exists", stream_id);
+            return None;
+        }
+        Some(self.register_stream(stream_id))
+    }
+
+    pub fn send_open_resp(&self, seq: u32, stream_id: u32, result: u32) {
+        let resp_package = RTcpMuxOpenRespPackage::new(seq, stream_id, result);
+        self.post_frame(MuxFrame::OpenResp(resp_package));
+    }
+
+    pub fn on_open_resp(&self, stream_id: u32, result: u32) {
+        let tx = self.pending_open.lock().unwrap().remove(&stream_id);
+        match tx {
+            Some(tx) => {
+                let _ = tx.send(result);
+            }
+            None => warn!("mux open resp for unknown stream {}", stream_id),
+        }
+    }
+
+    pub fn on_data(&self, stream_id: u32, data: Vec<u8>) {
+        let shared = self.get_stream(stream_id);
+        if shared.is_none() {
+            // stream已经关闭, 通知对端不要再发
+            debug!("mux data for unknown stream {}, reset it", stream_id);
+            self.post_frame(MuxFrame::Close(stream_id, true));
+            return;
+        }
+        let shared = shared.unwrap();
+        let mut state = shared.lock().unwrap();
+        if state.reset || state.recv_closed {
+            return;
+        }
+        if state.recv_len + data.len() > MUX_INITIAL_WINDOW as usize {
+            warn!("mux stream {} peer exceed recv window, reset it", stream_id);
+            state.reset = true;
+            state.wake_all();
+            drop(state);
+            self.post_frame(MuxFrame::Close(stream_id, true));
+            return;
+        }
+        if data.is_empty() {
+            return;
+        }
+        state.recv_len += data.len();
+        state.recv_buf.push_back(data);
+        if let Some(waker) = state.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn on_window(&self, stream_id: u32, increment: u32) {
+        if let Some(shared) = self.get_stream(stream_id) {
+            let mut state = shared.lock().unwrap();
+            state.send_window = state.send_window.saturating_add(increment);
+            if let Some(waker) = state.write_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn on_close(&self, stream_id: u32, reset: bool) {
+        if let Some(shared) = self.get_stream(stream_id) {
+            let mut state = shared.lock().unwrap();
+            if reset {
+                state.reset = true;
+                state.wake_all();
+            } else {
+                state.recv_closed = true;
+                if let Some(waker) = state.read_waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    // tunnel断开, 所有子stream都被reset
+    pub fn close_all(&self) {
+        let streams: Vec<MuxStreamShared> =
+            self.streams.lock().unwrap().drain().map(|(_, v)| v).collect();
+        for shared in streams {
+            let mut state = shared.lock().unwrap();
+            state.reset = true;
+            state.wake_all();
+        }
+        self.pending_open.lock().unwrap().clear();
+        self.post_frame(MuxFrame::Shutdown);
+    }
+}
+
+pub(crate) struct RTcpMuxStream {
+    stream_id: u32,
+    shared: MuxStreamShared,
+    mux: RTcpMux,
+}
+
+impl RTcpMuxStream {
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+}
+
+impl Drop for RTcpMuxStream {
+    fn drop(&mut self) {
+        self.mux.streams.lock().unwrap().remove(&self.stream_id);
+        let state = self.shared.lock().unwrap();
+        if !state.reset && (!state.local_closed || !state.recv_closed) {
+            self.mux.post_frame(MuxFrame::Close(self.stream_id, true));
+        }
+    }
+}
+
+impl AsyncRead for RTcpMuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut state = this.shared.lock().unwrap();
+        if state.recv_buf.is_empty() {
+            if state.reset {
+                return Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
+            }
+            if state.recv_closed {
+                return Poll::Ready(Ok(()));
+            }
+            state.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let mut read_len = 0;
+        while buf.remaining() > 0 {
+            let chunk = match state.recv_buf.front_mut() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let n = std::cmp::min(buf.remaining(), chunk.len());
+            buf.put_slice(&chunk[..n]);
+            if n == chunk.len() {
+                state.recv_buf.pop_front();
+            } else {
+                chunk.drain(..n);
+            }
+            read_len += n;
+        }
+        state.recv_len -= read_len;
+        state.unacked += read_len as u32;
+
+        // 消费过半窗口后再归还, 避免每次读都发一个包
+        if state.unacked >= MUX_INITIAL_WINDOW / 2 && !state.recv_closed && !state.reset {
+            let increment = state.unacked;
+            state.unacked = 0;
+            drop(state);
+            this.mux
+                .post_frame(MuxFrame::Window(this.stream_id, increment));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RTcpMuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let mut state = this.shared.lock().unwrap();
+        if state.reset {
+            return Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
+        }
+        if state.local_closed {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = std::cmp::min(
+            std::cmp::min(buf.len(), MUX_MAX_DATA_LEN),
+            state.send_window as usize,
+        );
+        state.send_window -= n as u32;
+        drop(state);
+
+        if !this
+            .mux
+            .post_frame(MuxFrame::Data(this.stream_id, buf[..n].to_vec()))
+        {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut state = this.shared.lock().unwrap();
+        if !state.local_closed && !state.reset {
+            state.local_closed = true;
+            drop(state);
+            this.mux.post_frame(MuxFrame::Close(this.stream_id, false));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // 把一端发出的帧直接投递给另一端, 模拟tunnel连接
+    fn pump(from: &RTcpMux, to: RTcpMux) {
+        let mut rx = from.take_frame_receiver().unwrap();
+        tokio::spawn(async move {
+            while let Some(frame) = rx.recv().await {
+                match frame {
+                    MuxFrame::Open(pkg) => {
+                        let stream = to.accept_stream(pkg.body.stream_id).unwrap();
+                        to.send_open_resp(pkg.seq, pkg.body.stream_id, 0);
+                        // echo
+                        tokio::spawn(async move {
+                            let (mut r, mut w) = tokio::io::split(stream);
+                            tokio::io::copy(&mut r, &mut w).await.unwrap();
+                            w.shutdown().await.unwrap();
+                        });
+                    }
+                    MuxFrame::OpenResp(pkg) => to.on_open_resp(pkg.body.stream_id, pkg.body.result),
+                    MuxFrame::Data(id, data) => to.on_data(id, data),
+                    MuxFrame::Window(id, inc) => to.on_window(id, inc),
+                    MuxFrame::Close(id, reset) => to.on_close(id, reset),
+                    MuxFrame::Shutdown => break,
+                }
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_mux_stream_echo_with_flow_control() {
+        let a = RTcpMux::new(true);
+        let b = RTcpMux::new(false);
+        pump(&a, b.clone());
+        pump(&b, a.clone());
+
+        let stream = a.open_stream(0, None, 80, None).await.unwrap();
+        assert_eq!(stream.stream_id(), 1);
+        let (mut r, mut w) = tokio::io::split(stream);
+
+        // 超过初始窗口, 需要依赖window归还才能写完
+        let data: Vec<u8> = (0..MUX_INITIAL_WINDOW as usize * 3).map(|i| i as u8).collect();
+        let expect = data.clone();
+        let writer = tokio::spawn(async move {
+            w.write_all(&data).await.unwrap();
+            w.shutdown().await.unwrap();
+            w
+        });
+        let mut got = Vec::new();
+        r.read_to_end(&mut got).await.unwrap();
+        let _w = writer.await.unwrap();
+        assert_eq!(got, expect);
+
+        let second = a.open_stream(1, None, 80, None).await.unwrap();
+        assert_eq!(second.stream_id(), 3);
+        a.close_all();
+        let mut second = second;
+        let mut buf = [0u8; 4];
+        assert!(second.read(&mut buf).await.is_err());
+    }
+}
@@ -17,6 +17,11 @@ pub(crate) enum CmdType {
     ROpenResp = 6,
     Open = 7,
     OpenResp = 8,
+    MuxOpen = 9,
+    MuxOpenResp = 10,
+    MuxData = 11,
+    MuxWindow = 12,
+    MuxClose = 13,
 }
 
 impl From<u8> for CmdType {
@@ -30,6 +35,11 @@ impl From<u8> for CmdType {
             6 => CmdType::ROpenResp,
             7 => CmdType::Open,
             8 => CmdType::OpenResp,
+            9 => CmdType::MuxOpen,
+            10 => CmdType::MuxOpenResp,
+            11 => CmdType::MuxData,
+            12 => CmdType::MuxWindow,
+            13 => CmdType::MuxClose,
             _ => CmdType::UnknownProtocol,
         }
     }
@@ -59,6 +69,14 @@ pub(crate) struct TunnelTokenPayload {
     pub from: String,
     pub xpub: String,
     pub exp: u64,
+    // 发起方希望使用的协议版本和支持的最高版本, 放在签名里防止被改成老版本
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_protocol_version: Option<u8>,
+    // 发起方是否希望复用stream, 同样需要签名
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mux: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -67,6 +85,11 @@ pub(crate) struct RTcpHelloBody {
     pub to_id: String,
     pub my_port: u16,
     pub tunnel_token: Option<String>, //jwt token ,payload is TunnelTokenPayload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u8>,
+    // 是否希望在tunnel连接上复用stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mux: Option<bool>,
 }
 
 pub(crate) type RTcpHelloPackage = RTcpTunnelPackageImpl<RTcpHelloBody>;
@@ -77,6 +100,8 @@ impl RTcpHelloPackage {
         to_id: String,
         my_port: u16,
         tunnel_token: Option<String>,
+        protocol_version: Option<u8>,
+        mux: Option<bool>,
     ) -> Self {
         RTcpHelloPackage {
             len: 0,
@@ -88,6 +113,8 @@ impl RTcpHelloPackage {
                 to_id,
                 my_port,
                 tunnel_token,
+                protocol_version,
+                mux,
             },
         }
     }
@@ -113,18 +140,35 @@ impl RTcpHelloPackage {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub(crate) struct RTcpHelloAckBody {
-    test_result: bool,
+    pub test_result: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mux: Option<bool>,
 }
 pub(crate) type RTcpHelloAckPackage = RTcpTunnelPackageImpl<RTcpHelloAckBody>;
 
 impl RTcpHelloAckPackage {
-    pub fn new(seq: u32, test_result: bool) -> Self {
+    pub fn new(
+        seq: u32,
+        test_result: bool,
+        protocol_version: Option<u8>,
+        nonce: Option<String>,
+        mux: Option<bool>,
+    ) -> Self {
         RTcpHelloAckPackage {
             len: 0,
             json_pos: 0,
             cmd: CmdType::HelloAck.into(),
             seq: seq,
-            body: RTcpHelloAckBody { test_result },
+            body: RTcpHelloAckBody {
+                test_result,
+                protocol_version,
+                nonce,
+                mux,
+            },
         }
     }
 
@@ -415,6 +459,135 @@ impl RTcpOpenRespPackage {
 }
 
 
+// 复用模式下在tunnel连接上打开一个子stream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RTcpMuxOpenBody {
+    pub stream_id: u32,
+    pub purpose: Option<StreamPurpose>,
+    pub dest_port: u16,
+    pub dest_host: Option<String>,
+}
+pub(crate) type RTcpMuxOpenPackage = RTcpTunnelPackageImpl<RTcpMuxOpenBody>;
+
+impl RTcpMuxOpenPackage {
+    pub fn new(
+        seq: u32,
+        stream_id: u32,
+        purpose: Option<StreamPurpose>,
+        dest_port: u16,
+        dest_host: Option<String>,
+    ) -> Self {
+        RTcpMuxOpenPackage {
+            len: 0,
+            json_pos: 0,
+            cmd: CmdType::MuxOpen.into(),
+            seq,
+            body: RTcpMuxOpenBody {
+                stream_id,
+                purpose,
+                dest_port,
+                dest_host,
+            },
+        }
+    }
+
+    pub fn from_json(seq: u32, json_value: serde_json::Value) -> Result<Self, std::io::Error> {
+        let body = serde_json::from_value::<RTcpMuxOpenBody>(json_value).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "parse package error")
+        })?;
+        Ok(RTcpMuxOpenPackage::new(
+            seq,
+            body.stream_id,
+            body.purpose,
+            body.dest_port,
+            body.dest_host,
+        ))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RTcpMuxOpenRespBody {
+    pub stream_id: u32,
+    pub result: u32,
+}
+pub(crate) type RTcpMuxOpenRespPackage = RTcpTunnelPackageImpl<RTcpMuxOpenRespBody>;
+
+impl RTcpMuxOpenRespPackage {
+    pub fn new(seq: u32, stream_id: u32, result: u32) -> Self {
+        RTcpMuxOpenRespPackage {
+            len: 0,
+            json_pos: 0,
+            cmd: CmdType::MuxOpenResp.into(),
+            seq,
+            body: RTcpMuxOpenRespBody { stream_id, result },
+        }
+    }
+
+    pub fn from_json(seq: u32, json_value: serde_json::Value) -> Result<Self, std::io::Error> {
+        let body = serde_json::from_value::<RTcpMuxOpenRespBody>(json_value).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "parse package error")
+        })?;
+        Ok(RTcpMuxOpenRespPackage::new(seq, body.stream_id, body.result))
+    }
+}
+
+// 接收方消费数据后归还发送窗口
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RTcpMuxWindowBody {
+    pub stream_id: u32,
+    pub increment: u32,
+}
+pub(crate) type RTcpMuxWindowPackage = RTcpTunnelPackageImpl<RTcpMuxWindowBody>;
+
+impl RTcpMuxWindowPackage {
+    pub fn new(stream_id: u32, increment: u32) -> Self {
+        RTcpMuxWindowPackage {
+            len: 0,
+            json_pos: 0,
+            cmd: CmdType::MuxWindow.into(),
+            seq: 0,
+            body: RTcpMuxWindowBody {
+                stream_id,
+                increment,
+            },
+        }
+    }
+
+    pub fn from_json(_seq: u32, json_value: serde_json::Value) -> Result<Self, std::io::Error> {
+        let body = serde_json::from_value::<RTcpMuxWindowBody>(json_value).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "parse package error")
+        })?;
+        Ok(RTcpMuxWindowPackage::new(body.stream_id, body.increment))
+    }
+}
+
+// reset为false表示本端写关闭(FIN), true表示直接中断stream
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RTcpMuxCloseBody {
+    pub stream_id: u32,
+    pub reset: bool,
+}
+pub(crate) type RTcpMuxClosePackage = RTcpTunnelPackageImpl<RTcpMuxCloseBody>;
+
+impl RTcpMuxClosePackage {
+    pub fn new(stream_id: u32, reset: bool) -> Self {
+        RTcpMuxClosePackage {
+            len: 0,
+            json_pos: 0,
+            cmd: CmdType::MuxClose.into(),
+            seq: 0,
+            body: RTcpMuxCloseBody { stream_id, reset },
+        }
+    }
+
+    pub fn from_json(_seq: u32, json_value: serde_json::Value) -> Result<Self, std::io::Error> {
+        let body = serde_json::from_value::<RTcpMuxCloseBody>(json_value).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "parse package error")
+        })?;
+        Ok(RTcpMuxClosePackage::new(body.stream_id, body.reset))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) enum RTcpTunnelPackage {
     HelloStream(String),
@@ -426,6 +599,12 @@ pub(crate) enum RTcpTunnelPackage {
     ROpenResp(RTcpROpenRespPackage),
     Open(RTcpOpenPackage),
     OpenResp(RTcpOpenRespPackage),
+    MuxOpen(RTcpMuxOpenPackage),
+    MuxOpenResp(RTcpMuxOpenRespPackage),
+    // (stream_id, payload), 数据包不走json
+    MuxData(u32, Vec<u8>),
+    MuxWindow(RTcpMuxWindowPackage),
+    MuxClose(RTcpMuxClosePackage),
 }
 
 const TUNNEL_KEY_DEFAULT: [u8; 32] = [6; 32];
@@ -496,6 +675,10 @@ impl RTcpTunnelPackage {
             //start read json
             let _len = json_pos - 2;
             let read_buf = &buf[(_len as usize)..];
+            if cmd_type == CmdType::MuxData as u8 {
+                // seq字段即stream_id, json位置之后是原始数据
+                return Ok(RTcpTunnelPackage::MuxData(seq, read_buf.to_vec()));
+            }
             //let base64_str: std::borrow::Cow<'_, str> = String::from_utf8_lossy(read_buf);
             let json_str = String::from_utf8_lossy(read_buf);
 
@@ -515,36 +698,52 @@ impl RTcpTunnelPackage {
             match cmd {
                 CmdType::Hello => {
                     let result_package = RTcpHelloPackage::from_json(seq, package_value)?;
-                    return Ok(RTcpTunnelPackage::Hello(result_package));
+                    Ok(RTcpTunnelPackage::Hello(result_package))
                 }
                 CmdType::HelloAck => {
                     let result_package = RTcpHelloAckPackage::from_json(seq, package_value)?;
-                    return Ok(RTcpTunnelPackage::HelloAck(result_package));
+                    Ok(RTcpTunnelPackage::HelloAck(result_package))
                 }
                 CmdType::Ping => {
                     let result_package: RTcpTunnelPackageImpl<RTcpPingBody> =
                         RTcpPingPackage::from_json(seq, package_value)?;
-                    return Ok(RTcpTunnelPackage::Ping(result_package));
+                    Ok(RTcpTunnelPackage::Ping(result_package))
                 }
                 CmdType::Pong => {
                     let result_package = RTcpPongPackage::from_json(seq, package_value)?;
-                    return Ok(RTcpTunnelPackage::Pong(result_package));
+                    Ok(RTcpTunnelPackage::Pong(result_package))
                 }
                 CmdType::ROpen => {
                     let result_package = RTcpROpenPackage::from_json(seq, package_value)?;
-                    return Ok(RTcpTunnelPackage::ROpen(result_package));
+                    Ok(RTcpTunnelPackage::ROpen(result_package))
                 }
                 CmdType::ROpenResp => {
                     let result_package = RTcpROpenRespPackage::from_json(seq, package_value)?;
-                    return Ok(RTcpTunnelPackage::ROpenResp(result_package));
+                    Ok(RTcpTunnelPackage::ROpenResp(result_package))
                 }
                 CmdType::Open => {
                     let result_package = RTcpOpenPackage::from_json(seq, package_value)?;
-                    return Ok(RTcpTunnelPackage::Open(result_package));
+                    Ok(RTcpTunnelPackage::Open(result_package))
                 }
                 CmdType::OpenResp => {
                     let result_package = RTcpOpenRespPackage::from_json(seq, package_value)?;
-                    return Ok(RTcpTunnelPackage::OpenResp(result_package));
+                    Ok(RTcpTunnelPackage::OpenResp(result_package))
+                }
+                CmdType::MuxOpen => {
+                    let result_package = RTcpMuxOpenPackage::from_json(seq, package_value)?;
+                    Ok(RTcpTunnelPackage::MuxOpen(result_package))
+                }
+                CmdType::MuxOpenResp => {
+                    let result_package = RTcpMuxOpenRespPackage::from_json(seq, package_value)?;
+                    Ok(RTcpTunnelPackage::MuxOpenResp(result_package))
+                }
+                CmdType::MuxWindow => {
+                    let result_package = RTcpMuxWindowPackage::from_json(seq, package_value)?;
+                    Ok(RTcpTunnelPackage::MuxWindow(result_package))
+                }
+                CmdType::MuxClose => {
+                    let result_package = RTcpMuxClosePackage::from_json(seq, package_value)?;
+                    Ok(RTcpTunnelPackage::MuxClose(result_package))
                 }
                 v @ _ => {
                     let msg = format!("Unsupported package type {:?}", v);
@@ -604,6 +803,38 @@ impl RTcpTunnelPackage {
         Ok(())
     }
 
+    // 复用模式的数据包: len + json_pos + cmd + stream_id + payload
+    pub async fn send_mux_data<S>(
+        mut stream: Pin<&mut S>,
+        stream_id: u32,
+        data: &[u8],
+    ) -> Result<()>
+    where
+        S: AsyncWriteExt,
+    {
+        let json_pos: u8 = 2 + 1 + 1 + 4;
+        let total_len = json_pos as usize + data.len();
+        if total_len > 0xffff {
+            let msg = format!("mux data too long: {}", total_len);
+            error!("{}", msg);
+            return Err(anyhow::anyhow!(msg));
+        }
+
+        let mut write_buf: Vec<u8> = Vec::with_capacity(total_len);
+        write_buf.extend_from_slice(&u16::to_be_bytes(total_len as u16));
+        write_buf.push(json_pos);
+        write_buf.push(CmdType::MuxData as u8);
+        write_buf.extend_from_slice(&u32::to_be_bytes(stream_id));
+        write_buf.extend_from_slice(data);
+
+        stream.write_all(&write_buf).await.map_err(|e| {
+            error!("Send mux data error: {}", e);
+            e
+        })?;
+
+        Ok(())
+    }
+
     pub async fn send_hello_stream(
         stream: &mut TcpStream,
         session_key: &str,
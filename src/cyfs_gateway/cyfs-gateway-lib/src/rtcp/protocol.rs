@@ -17,12 +17,19 @@ from_id: string,
 to_id: string,
 test_port:u16
 seession_key:option<string> （用对方公钥加密的key,并有自己的签名）
+protocol_version:option<u8> （不带则为老版本1）
 }
-后续所有命令都用tunel key 对称加密
+如果hello带了protocol_version，对端在切换加密前回复明文hello_ack，
+双方使用min(protocol_version)。版本1用tunnel key做AES-CTR，
+版本2用HKDF(tunnel key, random_pk+nonce)派生session key，
+每个stream按方向派生key，使用ChaCha20-Poly1305分帧+计数器nonce
 {
 cmd:hello_ack
 test_result:bool
+protocol_version:option<u8>
+nonce:option<string> （hex，32字节随机数）
 }
+后续所有命令都用tunel key 对称加密
 
 
 {
@@ -48,6 +55,34 @@ cmd:ropen_resp
 result:u32
 }
 
+//复用模式：hello/hello_ack里双方都带mux:true时启用，stream作为子通道跑在tunnel连接上，
+//不再open/ropen+新建tcp连接。发起方stream_id为奇数，接受方为偶数
+{
+cmd:mux_open
+stream_id:u32
+purpose:option<StreamPurpose>
+dest_port:u16
+dest_host:option<string>
+}
+{
+cmd:mux_open_resp
+stream_id:u32
+result:u32
+}
+//mux_data不是json，seq字段为stream_id，json位置之后是原始数据
+//每个stream初始发送窗口256K，接收方读走一半后用mux_window归还
+{
+cmd:mux_window
+stream_id:u32
+increment:u32
+}
+//reset为false表示写关闭，true表示中断stream
+{
+cmd:mux_close
+stream_id:u32
+reset:bool
+}
+
 */
 
 use name_lib::DID;
@@ -55,6 +90,10 @@ use anyhow::Result;
 
 pub const DEFAULT_RTCP_STACK_PORT: u16 = 2980;
 
+// 版本1: AES-CTR, 版本2: ChaCha20-Poly1305分帧
+pub const RTCP_PROTOCOL_VERSION_LEGACY: u8 = 1;
+pub const RTCP_PROTOCOL_VERSION: u8 = 2;
+
 
 #[derive(Debug, Clone, PartialEq)]
 pub(crate) struct RTcpTargetStackEP {
@@ -73,9 +112,16 @@ impl RTcpTargetStackEP {
 
 // xxx.dev.did:2980 or xxx:2980 
 pub(crate) fn parse_rtcp_stack_id(stack_id: &str) -> Option<RTcpTargetStackEP> {
-    let stack_port = DEFAULT_RTCP_STACK_PORT;
-    //let mut target_host_name = stack_id.to_string();
-    let target_did = DID::from_str(stack_id);
+    let mut stack_port = DEFAULT_RTCP_STACK_PORT;
+    let mut target_host_name = stack_id;
+    // did:method:id里的冒号不是端口分隔符
+    if !DID::is_did(stack_id) {
+        if let Some((host, port)) = stack_id.rsplit_once(':') {
+            stack_port = port.parse().ok()?;
+            target_host_name = host;
+        }
+    }
+    let target_did = DID::from_str(target_host_name);
     if target_did.is_err() {
         return None;
     }
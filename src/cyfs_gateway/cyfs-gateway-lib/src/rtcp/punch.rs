@@ -0,0 +1,601 @@
+// 通过SN的rendezvous服务做TCP打洞:
+// 1. 设备从rtcp stack端口(SO_REUSEPORT)连接SN, 用SN下发的challenge签名后注册, SN记录观察到的外网地址
+// 2. 发起方请求connect, SN把双方的地址(外网+内网)推送给两端
+// 3. 双方同时从stack端口connect对方(TCP simultaneous open), 先连上的作为tunnel连接
+// 4. 打洞失败时由SN中继: 双方各自新建连接到SN并bind同一个session, SN负责转发
+use buckyos_kit::buckyos_get_unix_timestamp;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use log::*;
+use name_lib::DID;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio::time::timeout;
+
+const RENDEZVOUS_MAX_MESSAGE_LEN: usize = 64 * 1024;
+const RENDEZVOUS_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+const RENDEZVOUS_RECONNECT_INTERVAL: Duration = Duration::from_secs(10);
+const RENDEZVOUS_RESP_TIMEOUT: Duration = Duration::from_secs(10);
+const PUNCH_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(8);
+const RENDEZVOUS_TOKEN_EXPIRE: u64 = 60;
+
+// SN和设备之间的控制消息, 每条消息是一行json
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum RendezvousMessage {
+    // SN在每条新连接上先下发, 设备的token必须签上这个nonce, 截获的token不能重放
+    Challenge {
+        nonce: String,
+    },
+    Register {
+        device_id: String,
+        token: String,
+        local_eps: Vec<String>,
+        // 设备是否参与打洞, 已知对称NAT的设备可以直接走中继
+        punch: bool,
+    },
+    RegisterResp {
+        result: u32,
+        observed_ep: Option<String>,
+    },
+    Connect {
+        session_id: String,
+        target_id: String,
+        relay: bool,
+    },
+    Punch {
+        session_id: String,
+        peer_id: String,
+        peer_eps: Vec<String>,
+        initiator: bool,
+    },
+    RelayRequest {
+        session_id: String,
+        peer_id: String,
+    },
+    RelayBind {
+        session_id: String,
+        device_id: String,
+        token: String,
+    },
+    RelayReady {
+        session_id: String,
+    },
+    ConnectFailed {
+        session_id: String,
+        reason: String,
+    },
+    Ping,
+    Pong,
+}
+
+impl RendezvousMessage {
+    fn session_id(&self) -> Option<&str> {
+        match self {
+            RendezvousMessage::Connect { session_id, .. }
+            | RendezvousMessage::Punch { session_id, .. }
+            | RendezvousMessage::RelayRequest { session_id, .. }
+            | RendezvousMessage::RelayBind { session_id, .. }
+            | RendezvousMessage::RelayReady { session_id }
+            | RendezvousMessage::ConnectFailed { session_id, .. } => Some(session_id.as_str()),
+            _ => None,
+        }
+    }
+}
+
+// 设备用自己的ed25519 key签名, SN用did里的公钥验证
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RendezvousTokenPayload {
+    pub from: String,
+    pub nonce: String,
+    pub exp: u64,
+}
+
+pub fn create_rendezvous_token(
+    device_id: &DID,
+    key: &EncodingKey,
+    nonce: &str,
+) -> Result<String, String> {
+    let payload = RendezvousTokenPayload {
+        from: device_id.to_string(),
+        nonce: nonce.to_string(),
+        exp: buckyos_get_unix_timestamp() + RENDEZVOUS_TOKEN_EXPIRE,
+    };
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.typ = None;
+    encode(&header, &payload, key).map_err(|e| format!("create rendezvous token error:{}", e))
+}
+
+pub async fn write_rendezvous_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &RendezvousMessage,
+) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(msg)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await
+}
+
+// 逐字节读到换行, 不会多读, 中继连接在ready之后的数据要原样留给tunnel
+pub async fn read_rendezvous_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<RendezvousMessage>> {
+    let mut line = Vec::new();
+    loop {
+        let mut byte = [0u8; 1];
+        let n = reader.read(&mut byte).await?;
+        if n == 0 {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+        if line.len() > RENDEZVOUS_MAX_MESSAGE_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "rendezvous message too long",
+            ));
+        }
+    }
+    let msg = serde_json::from_slice(&line)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    Ok(Some(msg))
+}
+
+// 打洞需要和stack的listener共用同一个本地端口
+pub(crate) fn new_reuse_socket(local_port: u16, remote_is_ipv4: bool) -> std::io::Result<TcpSocket> {
+    let (socket, local_ip) = if remote_is_ipv4 {
+        (TcpSocket::new_v4()?, IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+    } else {
+        (TcpSocket::new_v6()?, IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+    };
+    socket.set_reuseaddr(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuseport(true)?;
+    socket.bind(SocketAddr::new(local_ip, local_port))?;
+    Ok(socket)
+}
+
+async fn connect_from_port(local_port: u16, remote: SocketAddr) -> std::io::Result<TcpStream> {
+    let socket = new_reuse_socket(local_port, remote.is_ipv4())?;
+    socket.connect(remote).await
+}
+
+// 同时向对端所有候选地址发起连接, 在超时前不断重试, 返回第一个连上的
+pub(crate) async fn simultaneous_connect(
+    local_port: u16,
+    peer_eps: &[SocketAddr],
+    total_timeout: Duration,
+) -> std::io::Result<TcpStream> {
+    if peer_eps.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no peer endpoint to punch",
+        ));
+    }
+
+    let (tx, mut rx) = mpsc::channel(peer_eps.len());
+    let mut handles = Vec::new();
+    for ep in peer_eps.iter().cloned() {
+        let tx = tx.clone();
+        handles.push(task::spawn(async move {
+            loop {
+                match timeout(PUNCH_CONNECT_TIMEOUT, connect_from_port(local_port, ep)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(stream).await;
+                        return;
+                    }
+                    Ok(Err(e)) => debug!("punch connect to {} error:{}", ep, e),
+                    Err(_) => debug!("punch connect to {} timeout", ep),
+                }
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        }));
+    }
+    drop(tx);
+
+    let result = timeout(total_timeout, rx.recv()).await;
+    for handle in handles {
+        handle.abort();
+    }
+    match result {
+        Ok(Some(stream)) => Ok(stream),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "punch timeout",
+        )),
+    }
+}
+
+fn parse_endpoints(eps: &[String]) -> Vec<SocketAddr> {
+    let mut result: Vec<SocketAddr> = Vec::new();
+    for ep in eps.iter() {
+        match ep.parse::<SocketAddr>() {
+            Ok(addr) => {
+                if !result.contains(&addr) {
+                    result.push(addr);
+                }
+            }
+            Err(_) => warn!("invalid punch endpoint: {}", ep),
+        }
+    }
+    result
+}
+
+// 打洞或中继建好的入站连接和对端地址
+type IncomingConn = (TcpStream, SocketAddr);
+
+#[derive(Clone)]
+pub struct RTcpPunchClient {
+    sn_addr: String,
+    this_device: DID,
+    local_port: u16,
+    signing_key: EncodingKey,
+    punch_enabled: bool,
+
+    ctrl_tx: Arc<Mutex<Option<mpsc::UnboundedSender<RendezvousMessage>>>>,
+    pending: Arc<Mutex<HashMap<String, oneshot::Sender<RendezvousMessage>>>>,
+
+    // 对端发起打洞或中继时, 建好的连接交给stack按普通入站连接处理
+    incoming_tx: mpsc::UnboundedSender<IncomingConn>,
+    incoming_rx: Arc<Mutex<Option<mpsc::UnboundedReceiver<IncomingConn>>>>,
+}
+
+impl RTcpPunchClient {
+    pub fn new(
+        sn_addr: &str,
+        this_device: DID,
+        local_port: u16,
+        signing_key: EncodingKey,
+        punch_enabled: bool,
+    ) -> Self {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        Self {
+            sn_addr: sn_addr.to_string(),
+            this_device,
+            local_port,
+            signing_key,
+            punch_enabled,
+            ctrl_tx: Arc::new(Mutex::new(None)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            incoming_tx,
+            incoming_rx: Arc::new(Mutex::new(Some(incoming_rx))),
+        }
+    }
+
+    pub fn take_incoming_receiver(
+        &self,
+    ) -> Option<mpsc::UnboundedReceiver<IncomingConn>> {
+        self.incoming_rx.lock().unwrap().take()
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.ctrl_tx.lock().unwrap().is_some()
+    }
+
+    pub fn start(&self) {
+        let this = self.clone();
+        task::spawn(async move {
+            loop {
+                if let Err(e) = this.run_control().await {
+                    warn!("rendezvous control to {} error:{}", this.sn_addr, e);
+                }
+                *this.ctrl_tx.lock().unwrap() = None;
+                this.pending.lock().unwrap().clear();
+                tokio::time::sleep(RENDEZVOUS_RECONNECT_INTERVAL).await;
+            }
+        });
+    }
+
+    async fn resolve_sn_addr(&self) -> std::io::Result<SocketAddr> {
+        tokio::net::lookup_host(self.sn_addr.as_str())
+            .await?
+            .next()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("cann't resolve rendezvous server {}", self.sn_addr),
+                )
+            })
+    }
+
+    fn create_token(&self, nonce: &str) -> std::io::Result<String> {
+        create_rendezvous_token(&self.this_device, &self.signing_key, nonce)
+            .map_err(std::io::Error::other)
+    }
+
+    async fn read_challenge<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<String> {
+        match timeout(RENDEZVOUS_RESP_TIMEOUT, read_rendezvous_message(reader)).await {
+            Ok(Ok(Some(RendezvousMessage::Challenge { nonce }))) => Ok(nonce),
+            Ok(Ok(msg)) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("expect rendezvous challenge, got {:?}", msg),
+            )),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    async fn run_control(&self) -> std::io::Result<()> {
+        let sn_addr = self.resolve_sn_addr().await?;
+        let stream = connect_from_port(self.local_port, sn_addr).await?;
+        let local_ep = stream.local_addr()?;
+        let (mut reader, mut writer) = stream.into_split();
+
+        let nonce = Self::read_challenge(&mut reader).await?;
+        let register = RendezvousMessage::Register {
+            device_id: self.this_device.to_string(),
+            token: self.create_token(nonce.as_str())?,
+            local_eps: vec![local_ep.to_string()],
+            punch: self.punch_enabled,
+        };
+        write_rendezvous_message(&mut writer, &register).await?;
+        match timeout(RENDEZVOUS_RESP_TIMEOUT, read_rendezvous_message(&mut reader)).await {
+            Ok(Ok(Some(RendezvousMessage::RegisterResp {
+                result: 0,
+                observed_ep,
+            }))) => {
+                info!(
+                    "rtcp register to rendezvous {} ok, local:{}, observed:{:?}",
+                    self.sn_addr, local_ep, observed_ep
+                );
+            }
+            Ok(Ok(msg)) => {
+                let msg = format!("register to rendezvous failed: {:?}", msg);
+                return Err(std::io::Error::other(msg));
+            }
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<RendezvousMessage>();
+        *self.ctrl_tx.lock().unwrap() = Some(tx);
+        let writer_task = task::spawn(async move {
+            loop {
+                let msg = match timeout(RENDEZVOUS_KEEPALIVE_INTERVAL, rx.recv()).await {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => break,
+                    // 保持NAT映射
+                    Err(_) => RendezvousMessage::Ping,
+                };
+                if let Err(e) = write_rendezvous_message(&mut writer, &msg).await {
+                    warn!("write rendezvous message error:{}", e);
+                    break;
+                }
+            }
+        });
+
+        let result = loop {
+            let msg = match read_rendezvous_message(&mut reader).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            match msg {
+                RendezvousMessage::Punch {
+                    initiator: false,
+                    session_id,
+                    peer_id,
+                    peer_eps,
+                } => {
+                    let this = self.clone();
+                    task::spawn(async move {
+                        this.on_punch_request(session_id, peer_id, peer_eps).await;
+                    });
+                }
+                RendezvousMessage::RelayRequest {
+                    ref session_id,
+                    ref peer_id,
+                } if !self.pending.lock().unwrap().contains_key(session_id) => {
+                    let this = self.clone();
+                    let session_id = session_id.clone();
+                    let peer_id = peer_id.clone();
+                    task::spawn(async move {
+                        this.on_relay_request(session_id, peer_id).await;
+                    });
+                }
+                RendezvousMessage::Pong => {}
+                msg => {
+                    let waiter = msg
+                        .session_id()
+                        .and_then(|id| self.pending.lock().unwrap().remove(id));
+                    match waiter {
+                        Some(waiter) => {
+                            let _ = waiter.send(msg);
+                        }
+                        None => warn!("unexpected rendezvous message: {:?}", msg),
+                    }
+                }
+            }
+        };
+        writer_task.abort();
+        result
+    }
+
+    async fn request(&self, msg: RendezvousMessage) -> std::io::Result<RendezvousMessage> {
+        let session_id = msg.session_id().unwrap_or_default().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(session_id.clone(), tx);
+
+        let sent = match self.ctrl_tx.lock().unwrap().as_ref() {
+            Some(ctrl_tx) => ctrl_tx.send(msg).is_ok(),
+            None => false,
+        };
+        if !sent {
+            self.pending.lock().unwrap().remove(&session_id);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "not registered to rendezvous server",
+            ));
+        }
+
+        let resp = timeout(RENDEZVOUS_RESP_TIMEOUT, rx).await;
+        self.pending.lock().unwrap().remove(&session_id);
+        match resp {
+            Ok(Ok(RendezvousMessage::ConnectFailed { reason, .. })) => Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                reason,
+            )),
+            Ok(Ok(resp)) => Ok(resp),
+            Ok(Err(_)) => Err(std::io::ErrorKind::ConnectionAborted.into()),
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    fn new_session_id() -> String {
+        let random_bytes: [u8; 16] = rand::rng().random();
+        hex::encode(random_bytes)
+    }
+
+    // 发起方: 通过SN拿到对端地址后同时打开
+    pub async fn punch(&self, target_id: &str) -> std::io::Result<TcpStream> {
+        let resp = self
+            .request(RendezvousMessage::Connect {
+                session_id: Self::new_session_id(),
+                target_id: target_id.to_string(),
+                relay: false,
+            })
+            .await?;
+        let peer_eps = match resp {
+            RendezvousMessage::Punch { peer_eps, .. } => parse_endpoints(&peer_eps),
+            msg => {
+                let msg = format!("unexpected punch response: {:?}", msg);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+            }
+        };
+
+        info!("rtcp punch to {} via {:?}", target_id, peer_eps);
+        simultaneous_connect(self.local_port, &peer_eps, PUNCH_TIMEOUT).await
+    }
+
+    // 被动方: 同时向发起方connect, 连上后当作入站连接等待hello
+    async fn on_punch_request(&self, session_id: String, peer_id: String, peer_eps: Vec<String>) {
+        if !self.punch_enabled {
+            return;
+        }
+        let peer_eps = parse_endpoints(&peer_eps);
+        info!(
+            "rtcp punch request {} from {} via {:?}",
+            session_id, peer_id, peer_eps
+        );
+        match simultaneous_connect(self.local_port, &peer_eps, PUNCH_TIMEOUT).await {
+            Ok(stream) => {
+                let addr = stream.peer_addr().unwrap_or(peer_eps[0]);
+                let _ = self.incoming_tx.send((stream, addr));
+            }
+            // 对端可能已经直接连上了本地的listener
+            Err(e) => debug!("rtcp punch to {} failed:{}", peer_id, e),
+        }
+    }
+
+    async fn bind_relay(&self, session_id: &str) -> std::io::Result<TcpStream> {
+        let sn_addr = self.resolve_sn_addr().await?;
+        let mut stream = TcpStream::connect(sn_addr).await?;
+        let nonce = Self::read_challenge(&mut stream).await?;
+        let bind = RendezvousMessage::RelayBind {
+            session_id: session_id.to_string(),
+            device_id: self.this_device.to_string(),
+            token: self.create_token(nonce.as_str())?,
+        };
+        write_rendezvous_message(&mut stream, &bind).await?;
+        match timeout(RENDEZVOUS_RESP_TIMEOUT, read_rendezvous_message(&mut stream)).await {
+            Ok(Ok(Some(RendezvousMessage::RelayReady { .. }))) => Ok(stream),
+            Ok(Ok(msg)) => {
+                let msg = format!("relay bind failed: {:?}", msg);
+                Err(std::io::Error::other(msg))
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+        }
+    }
+
+    // 打洞失败时通过SN中继, tunnel本身是端到端加密的
+    pub async fn relay(&self, target_id: &str) -> std::io::Result<TcpStream> {
+        let session_id = Self::new_session_id();
+        let resp = self
+            .request(RendezvousMessage::Connect {
+                session_id: session_id.clone(),
+                target_id: target_id.to_string(),
+                relay: true,
+            })
+            .await?;
+        if !matches!(resp, RendezvousMessage::RelayRequest { .. }) {
+            let msg = format!("unexpected relay response: {:?}", resp);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+        }
+
+        info!("rtcp relay to {} via {}", target_id, self.sn_addr);
+        self.bind_relay(&session_id).await
+    }
+
+    async fn on_relay_request(&self, session_id: String, peer_id: String) {
+        info!("rtcp relay request {} from {}", session_id, peer_id);
+        match self.bind_relay(&session_id).await {
+            Ok(stream) => {
+                let addr = stream.peer_addr();
+                if let Ok(addr) = addr {
+                    let _ = self.incoming_tx.send((stream, addr));
+                }
+            }
+            Err(e) => warn!("rtcp relay bind for {} error:{}", peer_id, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rendezvous_message_codec() {
+        let msg = RendezvousMessage::Punch {
+            session_id: "s1".to_string(),
+            peer_id: "did:dev:abc".to_string(),
+            peer_eps: vec!["1.2.3.4:2980".to_string()],
+            initiator: true,
+        };
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        write_rendezvous_message(&mut a, &msg).await.unwrap();
+        a.write_all(b"raw").await.unwrap();
+        drop(a);
+
+        let got = read_rendezvous_message(&mut b).await.unwrap().unwrap();
+        assert_eq!(got, msg);
+        // 消息之后的数据不会被读走
+        let mut rest = Vec::new();
+        b.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"raw");
+    }
+
+    #[tokio::test]
+    async fn test_simultaneous_connect_prefers_reachable_ep() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        // 没有监听的端口模拟丢弃入站连接的NAT
+        let blocked = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap()
+        };
+
+        let stream = simultaneous_connect(0, &[blocked, reachable], Duration::from_secs(3))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+
+        let ret = simultaneous_connect(0, &[blocked], Duration::from_millis(500)).await;
+        assert!(ret.is_err());
+    }
+}
@@ -1,7 +1,9 @@
 use super::package::*;
 use super::protocol::*;
 use super::stream_helper::RTcpStreamBuildHelper;
-use super::tunnel::RTcpTunnel;
+use super::punch::{new_reuse_socket, RTcpPunchClient};
+use super::stats::{RTcpKeepaliveConfig, RTcpTunnelStats};
+use super::tunnel::{RTcpTunnel, RTcpTunnelCipher};
 use super::tunnel_map::RTcpTunnelMap;
 use crate::tunnel::{DatagramServerBox, StreamListener, TunnelBox, TunnelBuilder};
 use crate::{TunnelError, TunnelResult};
@@ -16,12 +18,21 @@ use name_lib::*;
 use sha2::{Digest, Sha256};
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task;
 use url::Url;
 use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
 use super::dispatcher::RTcpDispatcherManager;
 
+// 老版本对端不会回复hello_ack, 只有允许老版本时超时后才按版本1处理
+const RTCP_HELLO_ACK_TIMEOUT: Duration = Duration::from_secs(5);
+const RTCP_DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// 打洞时本端连出去的连接可能只是连上了对端的listener, 一直收不到hello就丢弃
+const RTCP_PUNCHED_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const RTCP_RELAY_UPGRADE_INTERVAL: Duration = Duration::from_secs(30);
+const RTCP_RELAY_UPGRADE_MAX_RETRY: u32 = 5;
+
 #[derive(Clone)]
 pub struct RTcpStack {
     tunnel_map: RTcpTunnelMap,
@@ -32,6 +43,15 @@ pub struct RTcpStack {
     this_device_did: DID, //name or did
     this_device_ed25519_sk: Option<EncodingKey>,
     this_device_x25519_sk: Option<StaticSecret>,
+    // 是否尝试与对端协商stream复用, 对端不支持时回退为每个stream一条tcp连接
+    enable_mux: bool,
+    // 是否允许与不支持协议协商的老版本对端通信, 不允许时没有hello_ack或版本不一致都视为失败
+    allow_legacy: bool,
+    // 配置了SN rendezvous时, 直连失败会尝试打洞或中继
+    punch_client: Option<RTcpPunchClient>,
+    // 中继tunnel尝试升级为直连的间隔, 第n次重试等待n倍
+    relay_upgrade_interval: Duration,
+    keepalive: RTcpKeepaliveConfig,
 }
 
 impl RTcpStack {
@@ -66,12 +86,50 @@ impl RTcpStack {
             this_device_did,
             this_device_ed25519_sk: this_device_ed25519_sk, //for sign tunnel token
             this_device_x25519_sk: this_device_x25519_sk,   //for decode tunnel token from remote
+            enable_mux: true,
+            allow_legacy: false,
+            punch_client: None,
+            relay_upgrade_interval: RTCP_RELAY_UPGRADE_INTERVAL,
+            keepalive: RTcpKeepaliveConfig::default(),
         };
         return result;
     }
 
+    pub fn set_enable_mux(&mut self, enable_mux: bool) {
+        self.enable_mux = enable_mux;
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: RTcpKeepaliveConfig) {
+        self.keepalive = keepalive;
+    }
+
+    pub fn set_allow_legacy(&mut self, allow_legacy: bool) {
+        self.allow_legacy = allow_legacy;
+    }
+
+    // 配置SN的rendezvous地址(host:port), 需要在start之前调用
+    pub fn enable_punch(&mut self, rendezvous_addr: &str) -> TunnelResult<()> {
+        if self.this_device_ed25519_sk.is_none() {
+            return Err(TunnelError::DocumentError(
+                "this device ed25519 sk is none".to_string(),
+            ));
+        }
+        self.punch_client = Some(RTcpPunchClient::new(
+            rendezvous_addr,
+            self.this_device_did.clone(),
+            self.tunnel_port,
+            self.this_device_ed25519_sk.clone().unwrap(),
+            true,
+        ));
+        Ok(())
+    }
+
+    pub(crate) fn this_device_did(&self) -> &DID {
+        &self.this_device_did
+    }
+
     // return (tunnel_token,aes_key,my_public_bytes)
-    async fn generate_tunnel_token(
+    pub(crate) async fn generate_tunnel_token(
         &self,
         target_hostname: String,
     ) -> Result<(String, [u8; 32], [u8; 32]), TunnelError> {
@@ -113,27 +171,32 @@ impl RTcpStack {
             from: self.this_device_did.to_host_name(),
             xpub: my_public_hex,
             exp: buckyos_get_unix_timestamp() + 3600 * 2,
+            protocol_version: Some(RTCP_PROTOCOL_VERSION),
+            max_protocol_version: Some(RTCP_PROTOCOL_VERSION),
+            mux: if self.enable_mux { Some(true) } else { None },
         };
         info!("send tunnel_token_payload: {:?}", tunnel_token_payload);
-        let payload = serde_json::to_value(&tunnel_token_payload).map_err(|op| {
+        let tunnel_token = self.encode_tunnel_token(&tunnel_token_payload)?;
+
+        Ok((tunnel_token, aes_key, my_public_bytes))
+    }
+
+    fn encode_tunnel_token(
+        &self,
+        tunnel_token_payload: &TunnelTokenPayload,
+    ) -> Result<String, TunnelError> {
+        let encoding_key = self.this_device_ed25519_sk.as_ref().ok_or_else(|| {
+            TunnelError::DocumentError("this device ed25519 sk is none".to_string())
+        })?;
+        let payload = serde_json::to_value(tunnel_token_payload).map_err(|op| {
             TunnelError::ReasonError(format!("encode tunnel token payload error:{}", op))
         })?;
 
         let mut header = Header::new(Algorithm::EdDSA);
         header.kid = None;
         header.typ = None;
-        let tunnel_token = encode(
-            &header,
-            &payload,
-            &self.this_device_ed25519_sk.as_ref().unwrap(),
-        );
-        if tunnel_token.is_err() {
-            let err_str = tunnel_token.err().unwrap().to_string();
-            return Err(TunnelError::ReasonError(err_str));
-        }
-        let tunnel_token = tunnel_token.unwrap();
-
-        Ok((tunnel_token, aes_key, my_public_bytes))
+        encode(&header, &payload, encoding_key)
+            .map_err(|e| TunnelError::ReasonError(e.to_string()))
     }
 
     fn generate_aes256_key(
@@ -151,11 +214,298 @@ impl RTcpStack {
         //return shared_secret.as_bytes().clone();
     }
 
+    async fn connect_direct(
+        &self,
+        target: &RTcpTargetStackEP,
+    ) -> TunnelResult<(TcpStream, String)> {
+        let target_id_str = target.did.to_string();
+        let device_ip = resolve_ip(target_id_str.as_str()).await;
+        if device_ip.is_err() {
+            warn!(
+                "cann't resolve target device {} ip.",
+                target_id_str.as_str()
+            );
+            return Err(TunnelError::ConnectError(format!(
+                "cann't resolve target device {} ip.",
+                target_id_str.as_str()
+            )));
+        }
+        let device_ip = device_ip.unwrap();
+        let port = target.stack_port;
+        let remote_addr = format!("{}:{}", device_ip, port);
+
+        info!(
+            "Will open tunnel to {}, target addr is {}",
+            target_id_str.as_str(),
+            remote_addr.as_str()
+        );
+
+        // connect to target, 可以打洞时不要在直连上等太久
+        let tunnel_stream = if self.punch_client.is_some() {
+            tokio::time::timeout(
+                RTCP_DIRECT_CONNECT_TIMEOUT,
+                tokio::net::TcpStream::connect(remote_addr.clone()),
+            )
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+        } else {
+            tokio::net::TcpStream::connect(remote_addr.clone()).await
+        };
+        if tunnel_stream.is_err() {
+            warn!(
+                "connect to {} error:{}",
+                remote_addr,
+                tunnel_stream.err().unwrap()
+            );
+            return Err(TunnelError::ConnectError(format!(
+                "connect to {} error.",
+                remote_addr
+            )));
+        }
+
+        Ok((tunnel_stream.unwrap(), remote_addr))
+    }
+
+    // 在已经建立的连接上发送hello并创建tunnel
+    // 打洞和中继的连接无法再为每个stream建新连接, 必须协商出复用模式
+    async fn build_tunnel(
+        &self,
+        target: &RTcpTargetStackEP,
+        mut tunnel_stream: TcpStream,
+        remote_addr: &str,
+        require_mux: bool,
+    ) -> TunnelResult<RTcpTunnel> {
+        let target_id_str = target.did.to_string();
+        // create tunnel token
+        let (tunnel_token, aes_key, random_pk) = self
+            .generate_tunnel_token(target_id_str.clone())
+            .await
+            .map_err(|e| {
+                let msg = format!("generate tunnel token error: {}, {}", target_id_str, e);
+                error!("{}", msg);
+                e
+            })?;
+
+        // send hello to target
+        let hello_package = RTcpHelloPackage::new(
+            0,
+            self.this_device_did.to_string(),
+            target_id_str.clone(),
+            self.tunnel_port,
+            Some(tunnel_token),
+            Some(RTCP_PROTOCOL_VERSION),
+            if self.enable_mux { Some(true) } else { None },
+        );
+        let send_result =
+            RTcpTunnelPackage::send_package(Pin::new(&mut tunnel_stream), hello_package).await;
+        if send_result.is_err() {
+            warn!(
+                "send hello package to {} error:{}",
+                remote_addr,
+                send_result.err().unwrap()
+            );
+            return Err(TunnelError::ConnectError(format!(
+                "send hello package to {} error.",
+                remote_addr
+            )));
+        }
+
+        let (cipher, enable_mux) = RTcpStack::wait_hello_ack(
+            &mut tunnel_stream,
+            remote_addr,
+            aes_key,
+            random_pk,
+            self.allow_legacy,
+        )
+        .await?;
+        info!(
+            "Tunnel to {} use protocol v{}, mux:{}",
+            remote_addr,
+            cipher.version(),
+            enable_mux
+        );
+        if require_mux && !enable_mux {
+            let msg = format!("tunnel to {} via rendezvous need mux, but peer not support", remote_addr);
+            warn!("{}", msg);
+            return Err(TunnelError::ConnectError(msg));
+        }
+
+        Ok(RTcpTunnel::new(
+            self.stream_helper.clone(),
+            self.dispatcher_manager.clone(),
+            self.this_device_did.clone(),
+            target,
+            true,
+            tunnel_stream,
+            cipher,
+            enable_mux,
+            self.keepalive.clone(),
+        ))
+    }
+
+    fn spawn_tunnel(&self, tunnel_key: String, tunnel: RTcpTunnel) {
+        let tunnel_map = self.tunnel_map.clone();
+        task::spawn(async move {
+            info!(
+                "RTcp tunnel {} established, tunnel running",
+                tunnel_key.as_str()
+            );
+            tunnel.clone().run().await;
+
+            // remove tunnel from manager
+            tunnel_map.remove_tunnel(&tunnel_key, &tunnel).await;
+
+            info!("RTcp tunnel {} end", tunnel_key.as_str());
+        });
+    }
+
+    // 中继tunnel建立后在后台继续尝试打洞, 成功后用直连tunnel替换
+    async fn upgrade_relayed_tunnel(
+        self,
+        target: RTcpTargetStackEP,
+        tunnel_key: String,
+        relayed_tunnel: RTcpTunnel,
+    ) {
+        let punch_client = match self.punch_client.clone() {
+            Some(punch_client) => punch_client,
+            None => return,
+        };
+        let target_id_str = target.did.to_string();
+        for retry in 1..=RTCP_RELAY_UPGRADE_MAX_RETRY {
+            tokio::time::sleep(self.relay_upgrade_interval * retry).await;
+
+            // 中继tunnel已经断开或者被替换了就不用再升级
+            match self.tunnel_map.get_tunnel(&tunnel_key).await {
+                Some(tunnel) if tunnel.is_same(&relayed_tunnel) => {}
+                _ => return,
+            }
+
+            let stream = match punch_client.punch(target_id_str.as_str()).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("upgrade relayed tunnel {} punch failed: {}", tunnel_key, e);
+                    continue;
+                }
+            };
+            let remote_addr = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_default();
+            let tunnel = match self
+                .build_tunnel(&target, stream, remote_addr.as_str(), true)
+                .await
+            {
+                Ok(tunnel) => tunnel,
+                Err(e) => {
+                    warn!("upgrade relayed tunnel {} error: {}", tunnel_key, e);
+                    continue;
+                }
+            };
+
+            info!(
+                "upgrade relayed tunnel {} to direct, remote addr is {}",
+                tunnel_key, remote_addr
+            );
+            self.tunnel_map.on_new_tunnel(&tunnel_key, tunnel.clone()).await;
+            self.spawn_tunnel(tunnel_key.clone(), tunnel);
+            relayed_tunnel.close_when_idle().await;
+            return;
+        }
+    }
+
+    // 发送hello后等待hello_ack协商协议版本和是否复用, return (cipher, enable_mux)
+    async fn wait_hello_ack(
+        stream: &mut TcpStream,
+        remote_addr: &str,
+        aes_key: [u8; 32],
+        random_pk: [u8; 32],
+        allow_legacy: bool,
+    ) -> TunnelResult<(RTcpTunnelCipher, bool)> {
+        let ret = tokio::time::timeout(
+            RTCP_HELLO_ACK_TIMEOUT,
+            RTcpTunnelPackage::read_package(Pin::new(stream), false, remote_addr),
+        )
+        .await;
+        match ret {
+            Err(_) if allow_legacy => {
+                warn!(
+                    "wait hello_ack from {} timeout, peer may not support protocol version, use v{}",
+                    remote_addr, RTCP_PROTOCOL_VERSION_LEGACY
+                );
+                Ok((RTcpTunnelCipher::new_legacy(aes_key, random_pk, true), false))
+            }
+            Err(_) => {
+                let msg = format!(
+                    "wait hello_ack from {} timeout, legacy peer is not allowed",
+                    remote_addr
+                );
+                warn!("{}", msg);
+                Err(TunnelError::ConnectError(msg))
+            }
+            Ok(Err(e)) => {
+                let msg = format!("read hello_ack from {} error:{}", remote_addr, e);
+                warn!("{}", msg);
+                Err(TunnelError::ConnectError(msg))
+            }
+            Ok(Ok(RTcpTunnelPackage::HelloAck(ack_package))) => {
+                let enable_mux = ack_package.body.mux == Some(true);
+                let version = ack_package
+                    .body
+                    .protocol_version
+                    .unwrap_or(RTCP_PROTOCOL_VERSION_LEGACY);
+                if version < RTCP_PROTOCOL_VERSION {
+                    if !allow_legacy {
+                        let msg = format!(
+                            "peer {} negotiate protocol v{}, legacy peer is not allowed",
+                            remote_addr, version
+                        );
+                        warn!("{}", msg);
+                        return Err(TunnelError::ConnectError(msg));
+                    }
+                    let cipher = RTcpTunnelCipher::new_legacy(aes_key, random_pk, true);
+                    return Ok((cipher, enable_mux));
+                }
+
+                let nonce: [u8; 32] = ack_package
+                    .body
+                    .nonce
+                    .as_ref()
+                    .and_then(|nonce| hex::decode(nonce).ok())
+                    .and_then(|nonce| nonce.try_into().ok())
+                    .ok_or_else(|| {
+                        let msg = format!("invalid hello_ack nonce from {}", remote_addr);
+                        warn!("{}", msg);
+                        TunnelError::ConnectError(msg)
+                    })?;
+                let cipher =
+                    RTcpTunnelCipher::new_aead(aes_key, random_pk, &nonce, enable_mux, true);
+                Ok((cipher, enable_mux))
+            }
+            Ok(Ok(_)) => {
+                let msg = format!("expect hello_ack from {}", remote_addr);
+                warn!("{}", msg);
+                Err(TunnelError::ConnectError(msg))
+            }
+        }
+    }
+
     pub async fn decode_tunnel_token(
         this_private_key: &StaticSecret,
         token: String,
         from_hostname: String,
     ) -> Result<([u8; 32], [u8; 32]), TunnelError> {
+        let (aes_key, remote_pk, _) =
+            RTcpStack::decode_tunnel_token_payload(this_private_key, token, from_hostname)
+                .await?;
+        Ok((aes_key, remote_pk))
+    }
+
+    // return (aes_key,remote_public_bytes,payload)
+    async fn decode_tunnel_token_payload(
+        this_private_key: &StaticSecret,
+        token: String,
+        from_hostname: String,
+    ) -> Result<([u8; 32], [u8; 32], TunnelTokenPayload), TunnelError> {
         let from_did = DID::from_str(from_hostname.as_str());
         if from_did.is_err() {
             return Err(TunnelError::DocumentError(
@@ -188,7 +538,9 @@ impl RTcpStack {
         let tunnel_token_payload = tunnel_token_payload.unwrap();
         let tunnel_token_payload = tunnel_token_payload.claims;
         //info!("tunnel_token_payload: {:?}",tunnel_token_payload);
-        let remomte_x25519_pk = hex::decode(tunnel_token_payload.xpub).unwrap();
+        let remomte_x25519_pk = hex::decode(tunnel_token_payload.xpub.as_str()).map_err(|_op| {
+            TunnelError::ReasonError("decode remote x25519 hex error".to_string())
+        })?;
 
         let remomte_x25519_pk: [u8; 32] = remomte_x25519_pk.try_into().map_err(|_op| {
             let msg = format!("decode remote x25519 hex error");
@@ -199,7 +551,19 @@ impl RTcpStack {
         //info!("remomte_x25519_pk: {:?}",remomte_x25519_pk);
         let aes_key = RTcpStack::get_aes256_key(this_private_key, remomte_x25519_pk.clone());
         //info!("aes_key: {:?}",aes_key);
-        Ok((aes_key, remomte_x25519_pk))
+        Ok((aes_key, remomte_x25519_pk, tunnel_token_payload))
+    }
+
+    // 用本设备的私钥解开对端发来的tunnel token, return (aes_key,remote_public_bytes)
+    pub(crate) async fn decode_hello_token(
+        &self,
+        token: String,
+        from_hostname: String,
+    ) -> Result<([u8; 32], [u8; 32]), TunnelError> {
+        let this_private_key = self.this_device_x25519_sk.as_ref().ok_or_else(|| {
+            TunnelError::DocumentError("this device x25519 sk is none".to_string())
+        })?;
+        RTcpStack::decode_tunnel_token(this_private_key, token, from_hostname).await
     }
 
     fn get_aes256_key(
@@ -219,7 +583,13 @@ impl RTcpStack {
     pub async fn start(&mut self) -> TunnelResult<()> {
         // create a tcp listener for tunnel
         let bind_addr = format!("0.0.0.0:{}", self.tunnel_port);
-        let rtcp_listener = TcpListener::bind(&bind_addr).await.map_err(|e| {
+        let rtcp_listener = if self.punch_client.is_some() {
+            // 打洞时会从同一个端口主动连出, listener也要开启端口复用
+            new_reuse_socket(self.tunnel_port, true).and_then(|socket| socket.listen(1024))
+        } else {
+            TcpListener::bind(&bind_addr).await
+        };
+        let rtcp_listener = rtcp_listener.map_err(|e| {
             let msg = format!("bind rtcp listener error:{}", e);
             error!("{}", msg);
             TunnelError::BindError(msg)
@@ -244,6 +614,33 @@ impl RTcpStack {
             }
         });
 
+        if let Some(punch_client) = &self.punch_client {
+            if let Some(mut incoming_rx) = punch_client.take_incoming_receiver() {
+                let this = self.clone();
+                task::spawn(async move {
+                    while let Some((stream, addr)) = incoming_rx.recv().await {
+                        debug!("RTcp stack accept punched stream from {}", addr);
+                        let this = this.clone();
+                        task::spawn(async move {
+                            let mut buf = [0u8; 1];
+                            let ret = tokio::time::timeout(
+                                RTCP_PUNCHED_HELLO_TIMEOUT,
+                                stream.peek(&mut buf),
+                            )
+                            .await;
+                            match ret {
+                                Ok(Ok(n)) if n > 0 => {
+                                    this.process_new_income_stream(stream, addr).await
+                                }
+                                _ => debug!("punched stream from {} has no hello, drop it", addr),
+                            }
+                        });
+                    }
+                });
+            }
+            punch_client.start();
+        }
+
         Ok(())
     }
 
@@ -306,14 +703,14 @@ impl RTcpStack {
             .await;
     }
 
-    async fn on_new_tunnel(&self, stream: TcpStream, hello_package: RTcpHelloPackage) {
+    async fn on_new_tunnel(&self, mut stream: TcpStream, hello_package: RTcpHelloPackage) {
         // decode hello.body.tunnel_token
         if hello_package.body.tunnel_token.is_none() {
             error!("hello.body.tunnel_token is none");
             return;
         }
         let token = hello_package.body.tunnel_token.as_ref().unwrap().clone();
-        let aes_key = RTcpStack::decode_tunnel_token(
+        let aes_key = RTcpStack::decode_tunnel_token_payload(
             &self.this_device_x25519_sk.as_ref().unwrap(),
             token,
             hello_package.body.from_id.clone(),
@@ -324,7 +721,7 @@ impl RTcpStack {
             return;
         }
 
-        let (aes_key, random_pk) = aes_key.unwrap();
+        let (aes_key, random_pk, token_payload) = aes_key.unwrap();
         let from_did = DID::from_str(hello_package.body.from_id.as_str());
         if from_did.is_err() {
             error!("parser remote did error:{}", from_did.err().unwrap());
@@ -340,6 +737,89 @@ impl RTcpStack {
             return;
         }
         let target = target.unwrap();
+
+        // 协议版本以签名的token为准, hello里的明文版本被去掉或改动时拒绝,
+        // token里没有版本的是老版本对端, 只有允许老版本时才按原有行为处理
+        let mut enable_mux = false;
+        let cipher = match token_payload.protocol_version {
+            Some(offered_version) => {
+                if hello_package.body.protocol_version != Some(offered_version) {
+                    error!(
+                        "hello protocol version {:?} from {} mismatch with tunnel token v{}",
+                        hello_package.body.protocol_version,
+                        hello_package.body.from_id.as_str(),
+                        offered_version
+                    );
+                    return;
+                }
+                if hello_package.body.mux != token_payload.mux {
+                    error!(
+                        "hello mux {:?} from {} mismatch with tunnel token {:?}",
+                        hello_package.body.mux,
+                        hello_package.body.from_id.as_str(),
+                        token_payload.mux
+                    );
+                    return;
+                }
+                let max_version = token_payload
+                    .max_protocol_version
+                    .unwrap_or(offered_version);
+                let version = std::cmp::min(max_version, RTCP_PROTOCOL_VERSION);
+                if version < RTCP_PROTOCOL_VERSION && !self.allow_legacy {
+                    error!(
+                        "tunnel from {} only support protocol v{}, legacy peer is not allowed",
+                        hello_package.body.from_id.as_str(),
+                        version
+                    );
+                    return;
+                }
+                enable_mux = self.enable_mux && token_payload.mux == Some(true);
+                let mut cipher = RTcpTunnelCipher::new_legacy(aes_key, random_pk, false);
+                let mut nonce_hex = None;
+                if version >= RTCP_PROTOCOL_VERSION {
+                    let nonce: [u8; 32] = rand::random();
+                    nonce_hex = Some(hex::encode(nonce));
+                    cipher = RTcpTunnelCipher::new_aead(
+                        aes_key, random_pk, &nonce, enable_mux, false,
+                    );
+                }
+                let ack_package = RTcpHelloAckPackage::new(
+                    hello_package.seq,
+                    true,
+                    Some(version),
+                    nonce_hex,
+                    Some(enable_mux),
+                );
+                let send_result =
+                    RTcpTunnelPackage::send_package(Pin::new(&mut stream), ack_package).await;
+                if send_result.is_err() {
+                    error!(
+                        "send hello_ack to {} error:{}",
+                        hello_package.body.from_id.as_str(),
+                        send_result.err().unwrap()
+                    );
+                    return;
+                }
+                cipher
+            }
+            None => {
+                if !self.allow_legacy {
+                    error!(
+                        "tunnel from {} not support protocol negotiation, legacy peer is not allowed",
+                        hello_package.body.from_id.as_str()
+                    );
+                    return;
+                }
+                RTcpTunnelCipher::new_legacy(aes_key, random_pk, false)
+            }
+        };
+        info!(
+            "Tunnel from {} use protocol v{}, mux:{}",
+            hello_package.body.from_id.as_str(),
+            cipher.version(),
+            enable_mux
+        );
+
         let tunnel = RTcpTunnel::new(
             self.stream_helper.clone(),
             self.dispatcher_manager.clone(),
@@ -347,8 +827,9 @@ impl RTcpStack {
             &target,
             false,
             stream,
-            aes_key,
-            random_pk,
+            cipher,
+            enable_mux,
+            self.keepalive.clone(),
         );
 
         let tunnel_key = format!(
@@ -369,27 +850,17 @@ impl RTcpStack {
             hello_package.body.from_id.as_str(),
             tunnel_key.as_str()
         );
-        tunnel.run().await;
+        tunnel.clone().run().await;
 
         info!("Tunnel {} end", tunnel_key.as_str());
 
-        self.tunnel_map.remove_tunnel(&tunnel_key).await;
+        self.tunnel_map.remove_tunnel(&tunnel_key, &tunnel).await;
     }
 }
 
-#[async_trait]
-impl TunnelBuilder for RTcpStack {
-    async fn create_tunnel(
-        &self,
-        tunnel_stack_id: Option<&str>,
-    ) -> TunnelResult<Box<dyn TunnelBox>> {
+impl RTcpStack {
+    async fn get_or_create_tunnel(&self, tunnel_stack_id: &str) -> TunnelResult<RTcpTunnel> {
         // lookup existing tunnel and resue it
-        if tunnel_stack_id.is_none() {
-            return Err(TunnelError::ReasonError(
-                "rtcp target stack id is none".to_string(),
-            ));
-        }
-        let tunnel_stack_id = tunnel_stack_id.unwrap();
         let target = parse_rtcp_stack_id(tunnel_stack_id);
         if target.is_none() {
             return Err(TunnelError::ConnectError(format!(
@@ -412,118 +883,149 @@ impl TunnelBuilder for RTcpStack {
         );
 
         // First check if the tunnel already exists, then we can reuse it
-        let tunnels = self.tunnel_map.tunnel_map().clone();
-        let mut all_tunnel = tunnels.lock().await;
-        let tunnel = all_tunnel.get(tunnel_key.as_str());
-        if tunnel.is_some() {
+        if let Some(tunnel) = self.tunnel_map.get_alive_tunnel(&tunnel_key).await {
             debug!("Reuse tunnel {}", tunnel_key.as_str());
-            return Ok(Box::new(tunnel.unwrap().clone()));
+            return Ok(tunnel);
         }
 
-        // 1） resolve target auth-key and ip (rtcp base on tcp,so need ip)
-
-        let device_ip = resolve_ip(target_id_str.as_str()).await;
-        if device_ip.is_err() {
-            warn!(
-                "cann't resolve target device {} ip.",
-                target_id_str.as_str()
-            );
-            return Err(TunnelError::ConnectError(format!(
-                "cann't resolve target device {} ip.",
-                target_id_str.as_str()
-            )));
-        }
-        let device_ip = device_ip.unwrap();
-        let port = target.stack_port;
-        let remote_addr = format!("{}:{}", device_ip, port);
-
-        info!(
-            "Will open tunnel to {}, target addr is {}",
-            target_id_str.as_str(),
-            remote_addr.as_str()
-        );
+        // 建立过程中不持有tunnel map的锁, 同一个目标的并发请求在pending entry上等待
+        let pending = self.tunnel_map.pending_entry(&tunnel_key).await;
+        let result = {
+            let _connecting = pending.lock().await;
+            match self.tunnel_map.get_alive_tunnel(&tunnel_key).await {
+                Some(tunnel) => {
+                    debug!("Reuse tunnel {} created by other request", tunnel_key.as_str());
+                    Ok(tunnel)
+                }
+                None => self.create_tunnel_to(target, tunnel_key.clone()).await,
+            }
+        };
+        self.tunnel_map.release_pending(&tunnel_key, pending).await;
+        result
+    }
 
-        // connect to target
-        let tunnel_stream = tokio::net::TcpStream::connect(remote_addr.clone()).await;
-        if tunnel_stream.is_err() {
-            warn!(
-                "connect to {} error:{}",
-                remote_addr,
-                tunnel_stream.err().unwrap()
-            );
-            return Err(TunnelError::ConnectError(format!(
-                "connect to {} error.",
-                remote_addr
-            )));
-        }
-        // create tunnel token
-        let (tunnel_token, aes_key, random_pk) = self
-            .generate_tunnel_token(target_id_str.clone())
-            .await
-            .map_err(|e| {
-                let msg = format!("generate tunnel token error: {}, {}", target_id_str, e);
-                error!("{}", msg);
-                e
-            })?;
+    async fn create_tunnel_to(
+        &self,
+        target: RTcpTargetStackEP,
+        tunnel_key: String,
+    ) -> TunnelResult<RTcpTunnel> {
+        let target_id_str = target.did.to_string();
 
-        // send hello to target
-        let mut tunnel_stream = tunnel_stream.unwrap();
-        let hello_package = RTcpHelloPackage::new(
-            0,
-            self.this_device_did.to_string(),
-            target_id_str.clone(),
-            self.tunnel_port,
-            Some(tunnel_token),
-        );
-        let send_result =
-            RTcpTunnelPackage::send_package(Pin::new(&mut tunnel_stream), hello_package).await;
-        if send_result.is_err() {
-            warn!(
-                "send hello package to {} error:{}",
-                remote_addr,
-                send_result.err().unwrap()
-            );
-            return Err(TunnelError::ConnectError(format!(
-                "send hello package to {} error.",
-                remote_addr
-            )));
-        }
+        // 1） resolve target auth-key and ip (rtcp base on tcp,so need ip)
+        let direct_result = self.connect_direct(&target).await;
+        let via_rendezvous = direct_result.is_err();
+        let (tunnel_stream, remote_addr, relayed) = match direct_result {
+            Ok((stream, remote_addr)) => (stream, remote_addr, false),
+            Err(e) => {
+                // 直连失败时通过SN打洞, 打洞失败再走SN中继
+                if self.punch_client.is_none() {
+                    return Err(e);
+                }
+                let punch_client = self.punch_client.as_ref().unwrap();
+                match punch_client.punch(target_id_str.as_str()).await {
+                    Ok(stream) => {
+                        let remote_addr = stream
+                            .peer_addr()
+                            .map(|addr| addr.to_string())
+                            .unwrap_or_default();
+                        info!("punch to {} ok, remote addr is {}", target_id_str, remote_addr);
+                        (stream, remote_addr, false)
+                    }
+                    Err(punch_err) => {
+                        warn!("punch to {} failed: {}, try relay", target_id_str, punch_err);
+                        let stream = punch_client
+                            .relay(target_id_str.as_str())
+                            .await
+                            .map_err(|e| {
+                                let msg = format!("relay to {} error:{}", target_id_str, e);
+                                warn!("{}", msg);
+                                TunnelError::ConnectError(msg)
+                            })?;
+                        (stream, format!("relay:{}", target_id_str), true)
+                    }
+                }
+            }
+        };
 
-        // create tunnel and add to map
-        let tunnel = RTcpTunnel::new(
-            self.stream_helper.clone(),
-            self.dispatcher_manager.clone(),
-            self.this_device_did.clone(),
-            &target,
-            true,
-            tunnel_stream,
-            aes_key,
-            random_pk,
-        );
-        all_tunnel.insert(tunnel_key.clone(), tunnel.clone());
+        // 2) hello, create tunnel and add to map
+        let tunnel = self
+            .build_tunnel(&target, tunnel_stream, remote_addr.as_str(), via_rendezvous)
+            .await?;
+        self.tunnel_map.on_new_tunnel(&tunnel_key, tunnel.clone()).await;
         info!(
             "create tunnel {} ok, remote addr is {}",
             tunnel_key.as_str(),
             remote_addr.as_str()
         );
-        drop(all_tunnel);
 
-        let result: TunnelResult<Box<dyn TunnelBox>> = Ok(Box::new(tunnel.clone()));
-        let tunnel_map = self.tunnel_map.clone();
-        task::spawn(async move {
-            info!(
-                "RTcp tunnel {} established, tunnel running",
-                tunnel_key.as_str()
-            );
-            tunnel.run().await;
+        self.spawn_tunnel(tunnel_key.clone(), tunnel.clone());
+        if relayed {
+            let this = self.clone();
+            let relayed_tunnel = tunnel.clone();
+            task::spawn(async move {
+                this.upgrade_relayed_tunnel(target, tunnel_key, relayed_tunnel)
+                    .await;
+            });
+        }
 
-            // remove tunnel from manager
-            tunnel_map.remove_tunnel(&tunnel_key).await;
+        Ok(tunnel)
+    }
 
-            info!("RTcp tunnel {} end", tunnel_key.as_str());
+    // 保持到目标的tunnel, 断开后按退避时间自动重连
+    pub fn keep_tunnel(&self, tunnel_stack_id: &str) {
+        let this = self.clone();
+        let tunnel_stack_id = tunnel_stack_id.to_string();
+        task::spawn(async move {
+            let keepalive = this.keepalive.clone();
+            let mut backoff = keepalive.reconnect_min_backoff;
+            loop {
+                match this.get_or_create_tunnel(tunnel_stack_id.as_str()).await {
+                    Ok(tunnel) => {
+                        let connected_at = std::time::Instant::now();
+                        tunnel.wait_closed().await;
+                        info!("kept tunnel to {} closed, will reconnect", tunnel_stack_id);
+                        // 连上很快又断开的不重置退避时间, 避免频繁重连
+                        if connected_at.elapsed() >= keepalive.ping_interval {
+                            backoff = keepalive.reconnect_min_backoff;
+                            continue;
+                        }
+                    }
+                    Err(e) => {
+                        warn!(
+                            "keep tunnel to {} failed: {}, retry after {:?}",
+                            tunnel_stack_id, e, backoff
+                        );
+                    }
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, keepalive.reconnect_max_backoff);
+            }
         });
+    }
 
-        return result;
+    pub async fn get_tunnel_stats(&self) -> Vec<RTcpTunnelStats> {
+        let tunnels = self.tunnel_map.tunnel_map();
+        let all_tunnel = tunnels.lock().await;
+        all_tunnel
+            .iter()
+            .map(|(tunnel_key, tunnel)| tunnel.get_stats(tunnel_key))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl TunnelBuilder for RTcpStack {
+    async fn create_tunnel(
+        &self,
+        tunnel_stack_id: Option<&str>,
+    ) -> TunnelResult<Box<dyn TunnelBox>> {
+        if tunnel_stack_id.is_none() {
+            return Err(TunnelError::ReasonError(
+                "rtcp target stack id is none".to_string(),
+            ));
+        }
+        let tunnel = self.get_or_create_tunnel(tunnel_stack_id.unwrap()).await?;
+        Ok(Box::new(tunnel))
     }
 
     async fn create_stream_listener(
@@ -542,3 +1044,615 @@ impl TunnelBuilder for RTcpStack {
         Ok(Box::new(dispatcher) as Box<dyn DatagramServerBox>)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::Tunnel;
+    use crate::rtcp::punch::{read_rendezvous_message, write_rendezvous_message, RendezvousMessage};
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Mutex};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    fn new_test_stack(allow_legacy: bool) -> RTcpStack {
+        let (sk, sk_pkcs) = generate_ed25519_key();
+        let jwk = encode_ed25519_sk_to_pk_jwk(&sk);
+        let x = jwk.get("x").unwrap().as_str().unwrap();
+        let mut stack = RTcpStack::new(DID::new("dev", x), 0, Some(sk_pkcs));
+        stack.set_allow_legacy(allow_legacy);
+        stack
+    }
+
+    // 监听本地端口, 收到的连接交给stack按正常流程处理
+    async fn start_acceptor(stack: RTcpStack) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            loop {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let stack = stack.clone();
+                task::spawn(async move { stack.process_new_income_stream(stream, addr).await });
+            }
+        });
+        addr
+    }
+
+    // 老版本对端只读hello, 不回复hello_ack
+    async fn start_legacy_acceptor() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            loop {
+                let (mut stream, addr) = listener.accept().await.unwrap();
+                task::spawn(async move {
+                    let source = addr.to_string();
+                    let _ = RTcpTunnelPackage::read_package(Pin::new(&mut stream), true, &source)
+                        .await;
+                    tokio::time::sleep(Duration::from_secs(30)).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn connect_tunnel(
+        initiator: &RTcpStack,
+        acceptor_did: &DID,
+        addr: SocketAddr,
+    ) -> TunnelResult<RTcpTunnel> {
+        let target = RTcpTargetStackEP::new(acceptor_did.clone(), addr.port()).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        initiator
+            .build_tunnel(&target, stream, addr.to_string().as_str(), false)
+            .await
+    }
+
+    // 手工构造hello, 模拟老版本对端或者被中间人改过的hello
+    async fn send_hello(
+        initiator: &RTcpStack,
+        acceptor_did: &DID,
+        addr: SocketAddr,
+        token_version: Option<u8>,
+        hello_version: Option<u8>,
+        token_mux: Option<bool>,
+    ) -> TcpStream {
+        let xpub = PublicKey::from(&EphemeralSecret::random());
+        let payload = TunnelTokenPayload {
+            to: acceptor_did.to_host_name(),
+            from: initiator.this_device_did.to_host_name(),
+            xpub: xpub.encode_hex(),
+            exp: buckyos_get_unix_timestamp() + 3600,
+            protocol_version: token_version,
+            max_protocol_version: token_version,
+            mux: token_mux,
+        };
+        let token = initiator.encode_tunnel_token(&payload).unwrap();
+        let hello = RTcpHelloPackage::new(
+            0,
+            initiator.this_device_did.to_string(),
+            acceptor_did.to_string(),
+            0,
+            Some(token),
+            hello_version,
+            None,
+        );
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        RTcpTunnelPackage::send_package(Pin::new(&mut stream), hello)
+            .await
+            .unwrap();
+        stream
+    }
+
+    async fn is_rejected(stream: &mut TcpStream) -> bool {
+        let ret = tokio::time::timeout(
+            Duration::from_secs(3),
+            RTcpTunnelPackage::read_package(Pin::new(stream), false, "test"),
+        )
+        .await;
+        matches!(ret, Ok(Err(_)))
+    }
+
+    #[tokio::test]
+    async fn test_hello_v2_to_v2() {
+        let initiator = new_test_stack(false);
+        let acceptor = new_test_stack(false);
+        let addr = start_acceptor(acceptor.clone()).await;
+
+        let tunnel = connect_tunnel(&initiator, &acceptor.this_device_did, addr)
+            .await
+            .unwrap();
+        let stats = tunnel.get_stats("test");
+        assert_eq!(stats.protocol_version, RTCP_PROTOCOL_VERSION);
+        assert!(stats.mux);
+        task::spawn(tunnel.clone().run());
+        check_echo(&tunnel).await;
+
+        // 目标连不上时对端回复失败, 而不是先回复成功再reset
+        let closed_port = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().port()
+        };
+        let ret = tunnel
+            .open_stream_by_dest(closed_port, Some("127.0.0.1".to_string()))
+            .await;
+        assert_eq!(
+            ret.err().unwrap().kind(),
+            std::io::ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hello_mux_disabled_on_peer() {
+        let initiator = new_test_stack(false);
+        let mut acceptor = new_test_stack(false);
+        acceptor.set_enable_mux(false);
+        let addr = start_acceptor(acceptor.clone()).await;
+
+        // 对端不支持复用时回退为每个stream一条连接
+        let tunnel = connect_tunnel(&initiator, &acceptor.this_device_did, addr)
+            .await
+            .unwrap();
+        let stats = tunnel.get_stats("test");
+        assert_eq!(stats.protocol_version, RTCP_PROTOCOL_VERSION);
+        assert!(!stats.mux);
+        task::spawn(tunnel.clone().run());
+        check_echo(&tunnel).await;
+
+        // 打洞和中继的连接必须复用
+        let target = RTcpTargetStackEP::new(acceptor.this_device_did.clone(), addr.port()).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let ret = initiator
+            .build_tunnel(&target, stream, addr.to_string().as_str(), true)
+            .await;
+        assert!(ret.is_err());
+
+        // mux标记在签名的token里, hello里的明文被改动时拒绝
+        let mux_acceptor = new_test_stack(false);
+        let addr = start_acceptor(mux_acceptor.clone()).await;
+        let mut stream = send_hello(
+            &initiator,
+            &mux_acceptor.this_device_did,
+            addr,
+            Some(RTCP_PROTOCOL_VERSION),
+            Some(RTCP_PROTOCOL_VERSION),
+            Some(true),
+        )
+        .await;
+        assert!(is_rejected(&mut stream).await);
+    }
+
+    type FakePeers = Arc<std::sync::Mutex<HashMap<String, mpsc::UnboundedSender<RendezvousMessage>>>>;
+    type FakeRelays = Arc<Mutex<HashMap<String, TcpStream>>>;
+
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    // 模拟的SN: 目标设备在NAT后面, SN只知道它在NAT上的映射地址, 打洞时只给出这个地址
+    async fn start_fake_rendezvous(nat_ep: SocketAddr, allow_relay: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peers: FakePeers = Default::default();
+        let relays: FakeRelays = Default::default();
+        task::spawn(async move {
+            loop {
+                let (stream, peer_addr) = listener.accept().await.unwrap();
+                let peers = peers.clone();
+                let relays = relays.clone();
+                task::spawn(async move {
+                    fake_rendezvous_conn(stream, peer_addr, peers, relays, nat_ep, allow_relay)
+                        .await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn fake_rendezvous_conn(
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+        peers: FakePeers,
+        relays: FakeRelays,
+        nat_ep: SocketAddr,
+        allow_relay: bool,
+    ) {
+        let challenge = RendezvousMessage::Challenge {
+            nonce: "test".to_string(),
+        };
+        write_rendezvous_message(&mut stream, &challenge).await.unwrap();
+        match read_rendezvous_message(&mut stream).await {
+            Ok(Some(RendezvousMessage::Register { device_id, .. })) => {
+                let (mut reader, mut writer) = stream.into_split();
+                let (tx, mut rx) = mpsc::unbounded_channel();
+                let _ = tx.send(RendezvousMessage::RegisterResp {
+                    result: 0,
+                    observed_ep: Some(peer_addr.to_string()),
+                });
+                peers.lock().unwrap().insert(device_id.clone(), tx.clone());
+                task::spawn(async move {
+                    while let Some(msg) = rx.recv().await {
+                        if write_rendezvous_message(&mut writer, &msg).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                while let Ok(Some(msg)) = read_rendezvous_message(&mut reader).await {
+                    match msg {
+                        RendezvousMessage::Ping => {
+                            let _ = tx.send(RendezvousMessage::Pong);
+                        }
+                        RendezvousMessage::Connect {
+                            session_id,
+                            target_id,
+                            relay: false,
+                        } => {
+                            let _ = tx.send(RendezvousMessage::Punch {
+                                session_id,
+                                peer_id: target_id,
+                                peer_eps: vec![nat_ep.to_string()],
+                                initiator: true,
+                            });
+                        }
+                        RendezvousMessage::Connect {
+                            session_id,
+                            relay: true,
+                            ..
+                        } if !allow_relay => {
+                            let _ = tx.send(RendezvousMessage::ConnectFailed {
+                                session_id,
+                                reason: "relay disabled".to_string(),
+                            });
+                        }
+                        RendezvousMessage::Connect {
+                            session_id,
+                            target_id,
+                            relay: true,
+                        } => {
+                            let target_tx = peers.lock().unwrap().get(&target_id).cloned();
+                            if let Some(target_tx) = target_tx {
+                                let _ = target_tx.send(RendezvousMessage::RelayRequest {
+                                    session_id: session_id.clone(),
+                                    peer_id: device_id.clone(),
+                                });
+                            }
+                            let _ = tx.send(RendezvousMessage::RelayRequest {
+                                session_id,
+                                peer_id: target_id,
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Ok(Some(RendezvousMessage::RelayBind { session_id, .. })) => {
+                let mut all_relays = relays.lock().await;
+                let mut other = match all_relays.remove(&session_id) {
+                    Some(other) => other,
+                    None => {
+                        all_relays.insert(session_id, stream);
+                        return;
+                    }
+                };
+                drop(all_relays);
+                let ready = RendezvousMessage::RelayReady { session_id };
+                write_rendezvous_message(&mut stream, &ready).await.unwrap();
+                write_rendezvous_message(&mut other, &ready).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut other).await;
+            }
+            _ => {}
+        }
+    }
+
+    // 模拟NAT映射: 打开之前映射端口上的入站连接都到不了设备, 打开后转发到设备的stack端口
+    async fn open_simulated_nat(nat_ep: SocketAddr, inner_port: u16) {
+        let listener = TcpListener::bind(nat_ep).await.unwrap();
+        task::spawn(async move {
+            loop {
+                let (mut outer, _) = listener.accept().await.unwrap();
+                task::spawn(async move {
+                    let mut inner = TcpStream::connect(("127.0.0.1", inner_port)).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut outer, &mut inner).await;
+                });
+            }
+        });
+    }
+
+    async fn start_punch_stack(sn_addr: SocketAddr, keepalive: RTcpKeepaliveConfig) -> RTcpStack {
+        let mut stack = new_test_stack(false);
+        stack.set_keepalive(keepalive);
+        stack.tunnel_port = free_port();
+        stack.relay_upgrade_interval = Duration::from_secs(1);
+        stack.enable_punch(sn_addr.to_string().as_str()).unwrap();
+        stack.start().await.unwrap();
+        for _ in 0..50 {
+            if stack.punch_client.as_ref().unwrap().is_registered() {
+                return stack;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("register to fake rendezvous timeout");
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_relayed_tunnel() {
+        let nat_ep: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+        let sn_addr = start_fake_rendezvous(nat_ep, true).await;
+        let stack_a = start_punch_stack(sn_addr, RTcpKeepaliveConfig::default()).await;
+        let stack_b = start_punch_stack(sn_addr, RTcpKeepaliveConfig::default()).await;
+
+        // NAT还没有映射, 打洞失败, 只能走SN中继
+        let b_id = stack_b.this_device_did.to_string();
+        let tunnel = stack_a.get_or_create_tunnel(b_id.as_str()).await.unwrap();
+        let stats = stack_a.get_tunnel_stats().await;
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].peer_addr, sn_addr.to_string());
+        check_echo(&tunnel).await;
+
+        // NAT映射打开后, 后台升级应该把中继tunnel换成直连
+        open_simulated_nat(nat_ep, stack_b.tunnel_port).await;
+        let mut upgraded = false;
+        for _ in 0..200 {
+            let stats = stack_a.get_tunnel_stats().await;
+            if stats.len() == 1 && stats[0].peer_addr == nat_ep.to_string() {
+                upgraded = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(upgraded);
+        let tunnel = stack_a.get_or_create_tunnel(b_id.as_str()).await.unwrap();
+        check_echo(&tunnel).await;
+    }
+
+    // 可以冻结的转发: 冻结后收到的数据都丢掉, 新连接直接断开, 模拟对端不再响应
+    struct FreezableProxy {
+        frozen: Arc<AtomicBool>,
+        accepted: Arc<AtomicU32>,
+    }
+
+    async fn start_freezable_proxy(listen: SocketAddr, inner_port: u16) -> FreezableProxy {
+        let frozen = Arc::new(AtomicBool::new(false));
+        let accepted = Arc::new(AtomicU32::new(0));
+        let listener = TcpListener::bind(listen).await.unwrap();
+        let proxy = FreezableProxy {
+            frozen: frozen.clone(),
+            accepted: accepted.clone(),
+        };
+        task::spawn(async move {
+            loop {
+                let (mut outer, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+                if frozen.load(Ordering::SeqCst) {
+                    continue;
+                }
+                let mut inner = TcpStream::connect(("127.0.0.1", inner_port)).await.unwrap();
+                let frozen = frozen.clone();
+                task::spawn(async move {
+                    let (mut outer_read, mut outer_write) = outer.split();
+                    let (mut inner_read, mut inner_write) = inner.split();
+                    // 任意一个方向结束就关闭两边
+                    tokio::select! {
+                        _ = forward_unless_frozen(&mut outer_read, &mut inner_write, &frozen) => {}
+                        _ = forward_unless_frozen(&mut inner_read, &mut outer_write, &frozen) => {}
+                    }
+                });
+            }
+        });
+        proxy
+    }
+
+    async fn forward_unless_frozen<R, W>(reader: &mut R, writer: &mut W, frozen: &AtomicBool)
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let len = match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            if frozen.load(Ordering::SeqCst) {
+                continue;
+            }
+            if writer.write_all(&buf[..len]).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn wait_kept_tunnel(stack: &RTcpStack, peer_addr: &str, wait: Duration) -> bool {
+        let deadline = std::time::Instant::now() + wait;
+        while std::time::Instant::now() < deadline {
+            let stats = stack.get_tunnel_stats().await;
+            if stats.iter().any(|stats| stats.alive && stats.peer_addr == peer_addr) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_keep_tunnel_ping_timeout() {
+        let nat_ep: SocketAddr = format!("127.0.0.1:{}", free_port()).parse().unwrap();
+        let sn_addr = start_fake_rendezvous(nat_ep, false).await;
+        let keepalive = RTcpKeepaliveConfig {
+            ping_interval: Duration::from_millis(200),
+            ping_timeout: Duration::from_secs(1),
+            reconnect_min_backoff: Duration::from_millis(300),
+            reconnect_max_backoff: Duration::from_millis(1200),
+        };
+        let stack_a = start_punch_stack(sn_addr, keepalive).await;
+        let stack_b = start_punch_stack(sn_addr, RTcpKeepaliveConfig::default()).await;
+        let proxy = start_freezable_proxy(nat_ep, stack_b.tunnel_port).await;
+        let b_id = stack_b.this_device_did.to_string();
+        let peer_addr = nat_ep.to_string();
+
+        stack_a.keep_tunnel(b_id.as_str());
+        assert!(wait_kept_tunnel(&stack_a, peer_addr.as_str(), Duration::from_secs(5)).await);
+        let tunnel = stack_a.get_or_create_tunnel(b_id.as_str()).await.unwrap();
+        check_echo(&tunnel).await;
+
+        // 对端不再回复pong, ping_timeout之后tunnel被关闭并从map里移除
+        proxy.frozen.store(true, Ordering::SeqCst);
+        let accepted_before = proxy.accepted.load(Ordering::SeqCst);
+        let frozen_at = std::time::Instant::now();
+        tunnel.wait_closed().await;
+        let closed_after = frozen_at.elapsed();
+        assert!(closed_after >= Duration::from_millis(800), "{:?}", closed_after);
+        assert!(closed_after < Duration::from_secs(3), "{:?}", closed_after);
+        let stats = tunnel.get_stats("kept");
+        assert!(!stats.alive);
+        assert!(stats.last_error.unwrap().contains("no package received"));
+        // 关闭的tunnel不再持有连接, 重连才能复用同一个本地端口
+        drop(tunnel);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(stack_a
+            .get_tunnel_stats()
+            .await
+            .iter()
+            .all(|stats| stats.peer_addr != peer_addr || !stats.alive));
+
+        // 重连失败时按退避时间重试, 不会不停地重连
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        let attempts = proxy.accepted.load(Ordering::SeqCst) - accepted_before;
+        assert!((2..=8).contains(&attempts), "reconnect attempts: {}", attempts);
+
+        // 对端恢复后自动重连
+        proxy.frozen.store(false, Ordering::SeqCst);
+        assert!(wait_kept_tunnel(&stack_a, peer_addr.as_str(), Duration::from_secs(5)).await);
+        let tunnel = stack_a.get_or_create_tunnel(b_id.as_str()).await.unwrap();
+        check_echo(&tunnel).await;
+    }
+
+    async fn check_echo(tunnel: &RTcpTunnel) {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_port = echo.local_addr().unwrap().port();
+        task::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        let mut stream = tunnel
+            .open_stream_by_dest(echo_port, Some("127.0.0.1".to_string()))
+            .await
+            .unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_hello_v2_to_legacy() {
+        let legacy_addr = start_legacy_acceptor().await;
+        let legacy_did = new_test_stack(false).this_device_did;
+
+        // 没有hello_ack时, 只有显式允许老版本才回退到版本1
+        let strict = new_test_stack(false);
+        let compatible = new_test_stack(true);
+        let (strict_ret, compatible_ret) = tokio::join!(
+            connect_tunnel(&strict, &legacy_did, legacy_addr),
+            connect_tunnel(&compatible, &legacy_did, legacy_addr)
+        );
+        assert!(strict_ret.is_err());
+        let tunnel = compatible_ret.unwrap();
+        let stats = tunnel.get_stats("test");
+        assert_eq!(stats.protocol_version, RTCP_PROTOCOL_VERSION_LEGACY);
+        assert!(!stats.mux);
+
+        // 老版本发起方的token里没有协议版本
+        let legacy_initiator = new_test_stack(false);
+        let strict_acceptor = new_test_stack(false);
+        let addr = start_acceptor(strict_acceptor.clone()).await;
+        let mut stream = send_hello(
+            &legacy_initiator,
+            &strict_acceptor.this_device_did,
+            addr,
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert!(is_rejected(&mut stream).await);
+
+        let compatible_acceptor = new_test_stack(true);
+        let addr = start_acceptor(compatible_acceptor.clone()).await;
+        let _stream = send_hello(
+            &legacy_initiator,
+            &compatible_acceptor.this_device_did,
+            addr,
+            None,
+            None,
+            None,
+        )
+        .await;
+        let tunnel_key = format!(
+            "{}_{}",
+            compatible_acceptor.this_device_did.to_string(),
+            legacy_initiator.this_device_did.to_string()
+        );
+        let mut accepted = None;
+        for _ in 0..30 {
+            accepted = compatible_acceptor.tunnel_map.get_tunnel(&tunnel_key).await;
+            if accepted.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let stats = accepted.unwrap().get_stats(&tunnel_key);
+        assert_eq!(stats.protocol_version, RTCP_PROTOCOL_VERSION_LEGACY);
+    }
+
+    #[tokio::test]
+    async fn test_hello_stripped_version() {
+        // 允许老版本也不能接受签名token里有版本, 明文hello里被去掉或改掉的情况
+        let initiator = new_test_stack(true);
+        let acceptor = new_test_stack(true);
+        let addr = start_acceptor(acceptor.clone()).await;
+
+        let mut stream = send_hello(
+            &initiator,
+            &acceptor.this_device_did,
+            addr,
+            Some(RTCP_PROTOCOL_VERSION),
+            None,
+            None,
+        )
+        .await;
+        assert!(is_rejected(&mut stream).await);
+
+        let mut stream = send_hello(
+            &initiator,
+            &acceptor.this_device_did,
+            addr,
+            Some(RTCP_PROTOCOL_VERSION),
+            Some(RTCP_PROTOCOL_VERSION_LEGACY),
+            None,
+        )
+        .await;
+        assert!(is_rejected(&mut stream).await);
+
+        let mut stream = send_hello(
+            &initiator,
+            &acceptor.this_device_did,
+            addr,
+            Some(RTCP_PROTOCOL_VERSION),
+            Some(RTCP_PROTOCOL_VERSION),
+            None,
+        )
+        .await;
+        let ret = RTcpTunnelPackage::read_package(Pin::new(&mut stream), false, "test").await;
+        match ret {
+            Ok(RTcpTunnelPackage::HelloAck(ack)) => {
+                assert_eq!(ack.body.protocol_version, Some(RTCP_PROTOCOL_VERSION))
+            }
+            _ => panic!("expect hello_ack"),
+        }
+    }
+}
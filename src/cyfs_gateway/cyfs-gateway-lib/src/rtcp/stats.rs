@@ -0,0 +1,272 @@
+use buckyos_kit::buckyos_get_unix_timestamp;
+use buckyos_kit::AsyncStream;
+use serde::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+
+// tunnel保活和断线重连的参数
+#[derive(Debug, Clone)]
+pub struct RTcpKeepaliveConfig {
+    pub ping_interval: Duration,
+    // 超过这个时间没有收到任何包就认为tunnel已经断开
+    pub ping_timeout: Duration,
+    pub reconnect_min_backoff: Duration,
+    pub reconnect_max_backoff: Duration,
+}
+
+impl Default for RTcpKeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(30),
+            ping_timeout: Duration::from_secs(90),
+            reconnect_min_backoff: Duration::from_secs(1),
+            reconnect_max_backoff: Duration::from_secs(60),
+        }
+    }
+}
+
+// 提供给控制面板查询的tunnel状态
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RTcpTunnelStats {
+    pub tunnel_key: String,
+    pub target: String,
+    pub peer_addr: String,
+    pub protocol_version: u8,
+    pub mux: bool,
+    pub alive: bool,
+    pub rtt_ms: Option<u64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub open_streams: u32,
+    pub created_at: u64,
+    pub last_active: u64,
+    pub last_error: Option<String>,
+}
+
+pub(crate) struct TunnelMetrics {
+    created_at: u64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    open_streams: AtomicU32,
+    // 单位us, 0表示还没有测到
+    rtt_us: AtomicU64,
+    last_active: Mutex<Instant>,
+    last_active_unix: AtomicU64,
+    last_error: Mutex<Option<String>>,
+    // 只跟踪最近一个ping, 新ping发出时还没回的就不再计算rtt
+    pending_ping: Mutex<Option<(u32, Instant)>>,
+    closed: watch::Sender<bool>,
+}
+
+impl TunnelMetrics {
+    pub fn new() -> Self {
+        let now = buckyos_get_unix_timestamp();
+        Self {
+            created_at: now,
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            open_streams: AtomicU32::new(0),
+            rtt_us: AtomicU64::new(0),
+            last_active: Mutex::new(Instant::now()),
+            last_active_unix: AtomicU64::new(now),
+            last_error: Mutex::new(None),
+            pending_ping: Mutex::new(None),
+            closed: watch::channel(false).0,
+        }
+    }
+
+    pub fn on_active(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+        self.last_active_unix
+            .store(buckyos_get_unix_timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.last_active.lock().unwrap().elapsed()
+    }
+
+    pub fn on_ping_sent(&self, seq: u32) {
+        *self.pending_ping.lock().unwrap() = Some((seq, Instant::now()));
+    }
+
+    pub fn on_pong(&self, seq: u32) {
+        let mut pending_ping = self.pending_ping.lock().unwrap();
+        if let Some((ping_seq, sent_at)) = *pending_ping {
+            if ping_seq == seq {
+                let rtt = std::cmp::max(sent_at.elapsed().as_micros() as u64, 1);
+                self.rtt_us.store(rtt, Ordering::Relaxed);
+                *pending_ping = None;
+            }
+        }
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.rtt_us.load(Ordering::Relaxed) {
+            0 => None,
+            rtt => Some(Duration::from_micros(rtt)),
+        }
+    }
+
+    pub fn set_error(&self, error: String) {
+        *self.last_error.lock().unwrap() = Some(error);
+    }
+
+    pub fn mark_closed(&self) {
+        self.closed.send_replace(true);
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    pub async fn wait_closed(&self) {
+        let mut rx = self.closed.subscribe();
+        let _ = rx.wait_for(|closed| *closed).await;
+    }
+
+    pub fn open_streams(&self) -> u32 {
+        self.open_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn fill_stats(&self, stats: &mut RTcpTunnelStats) {
+        stats.alive = !self.is_closed();
+        stats.rtt_ms = self.rtt().map(|rtt| rtt.as_millis() as u64);
+        stats.bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
+        stats.bytes_received = self.bytes_received.load(Ordering::Relaxed);
+        stats.open_streams = self.open_streams();
+        stats.created_at = self.created_at;
+        stats.last_active = self.last_active_unix.load(Ordering::Relaxed);
+        stats.last_error = self.last_error.lock().unwrap().clone();
+    }
+}
+
+// 统计经过tunnel的stream流量和当前打开的stream数
+pub(crate) struct CountedStream {
+    inner: Box<dyn AsyncStream>,
+    metrics: Arc<TunnelMetrics>,
+}
+
+impl CountedStream {
+    pub fn new(inner: Box<dyn AsyncStream>, metrics: Arc<TunnelMetrics>) -> Self {
+        metrics.open_streams.fetch_add(1, Ordering::Relaxed);
+        Self { inner, metrics }
+    }
+}
+
+impl Drop for CountedStream {
+    fn drop(&mut self) {
+        self.metrics.open_streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AsyncRead for CountedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let ret = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read_len = buf.filled().len() - before;
+        if read_len > 0 {
+            self.metrics
+                .bytes_received
+                .fetch_add(read_len as u64, Ordering::Relaxed);
+        }
+        ret
+    }
+}
+
+impl AsyncWrite for CountedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let ret = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = &ret {
+            self.metrics
+                .bytes_sent
+                .fetch_add(*len as u64, Ordering::Relaxed);
+        }
+        ret
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_tunnel_metrics() {
+        let metrics = Arc::new(TunnelMetrics::new());
+        let (local, mut remote) = tokio::io::duplex(1024);
+        let mut stream = CountedStream::new(Box::new(local), metrics.clone());
+        assert_eq!(metrics.open_streams(), 1);
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        remote.read_exact(&mut buf).await.unwrap();
+        remote.write_all(b"world!").await.unwrap();
+        let mut buf = [0u8; 6];
+        stream.read_exact(&mut buf).await.unwrap();
+        drop(stream);
+
+        metrics.on_ping_sent(3);
+        // seq不匹配的pong不计算rtt
+        metrics.on_pong(2);
+        assert!(metrics.rtt().is_none());
+        metrics.on_pong(3);
+        assert!(metrics.rtt().is_some());
+
+        metrics.set_error("read package error".to_string());
+        let waiter = {
+            let metrics = metrics.clone();
+            tokio::spawn(async move { metrics.wait_closed().await })
+        };
+        metrics.mark_closed();
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut stats = RTcpTunnelStats {
+            tunnel_key: "a_b".to_string(),
+            target: "b".to_string(),
+            peer_addr: "127.0.0.1:2980".to_string(),
+            protocol_version: 2,
+            mux: false,
+            alive: true,
+            rtt_ms: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            open_streams: 0,
+            created_at: 0,
+            last_active: 0,
+            last_error: None,
+        };
+        metrics.fill_stats(&mut stats);
+        assert!(!stats.alive);
+        assert_eq!(stats.bytes_sent, 5);
+        assert_eq!(stats.bytes_received, 6);
+        assert_eq!(stats.open_streams, 0);
+        assert_eq!(stats.last_error.as_deref(), Some("read package error"));
+    }
+}
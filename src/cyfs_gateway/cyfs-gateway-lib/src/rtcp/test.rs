@@ -33,6 +33,24 @@ mod tests {
         info!("target5: {:?}", target5);
     }
 
+    #[test]
+    fn test_parse_rtcp_stack_id_port() {
+        let target = parse_rtcp_stack_id("dev02.devices.web3.buckyos.io:3080").unwrap();
+        assert_eq!(target.stack_port, 3080);
+        assert_eq!(target.did, DID::from_str("dev02.devices.web3.buckyos.io").unwrap());
+
+        let target = parse_rtcp_stack_id("LBgzvFCD4VqQxTsO2LCZjs9FPVaQV2Dt0Q5W_lr4mr0.dev.did").unwrap();
+        assert_eq!(target.stack_port, DEFAULT_RTCP_STACK_PORT);
+        assert_eq!(target.did, DID::new("dev", "LBgzvFCD4VqQxTsO2LCZjs9FPVaQV2Dt0Q5W_lr4mr0"));
+
+        // did形式里的冒号不当作端口
+        let target = parse_rtcp_stack_id("did:dev:LBgzvFCD4VqQxTsO2LCZjs9FPVaQV2Dt0Q5W_lr4mr0").unwrap();
+        assert_eq!(target.stack_port, DEFAULT_RTCP_STACK_PORT);
+        assert_eq!(target.did, DID::new("dev", "LBgzvFCD4VqQxTsO2LCZjs9FPVaQV2Dt0Q5W_lr4mr0"));
+
+        assert!(parse_rtcp_stack_id("dev02:bad_port").is_none());
+    }
+
     #[tokio::test]
     async fn test_rtcp_tunnel() {
         //rtcp tunnel setup quick start:
@@ -1,9 +1,12 @@
 use super::datagram::RTcpTunnelDatagramClient;
 use super::dispatcher::RTcpDispatcherManager;
+use super::mux::{MuxFrame, RTcpMux};
 use super::package::StreamPurpose;
 use super::package::*;
 use super::protocol::*;
+use super::stats::{CountedStream, RTcpKeepaliveConfig, RTcpTunnelStats, TunnelMetrics};
 use super::stream_helper::RTcpStreamBuildHelper;
+use crate::aead_stream::{derive_direction_keys, hkdf_derive_key, AeadStream};
 use crate::aes_stream::EncryptedStream;
 use crate::tunnel::*;
 use anyhow::Result;
@@ -26,6 +29,78 @@ use tokio::sync::Notify;
 use tokio::task;
 use tokio::time::timeout;
 
+// tunnel协商后的加密参数, 控制流和每个stream都用它来包装
+#[derive(Clone)]
+pub(crate) struct RTcpTunnelCipher {
+    version: u8,
+    is_initiator: bool,
+    aes_key: [u8; 32],
+    random_pk: [u8; 32],
+    // 版本2下由aes_key和双方随机数派生, 避免重放整个hello后复用同一组key
+    session_key: [u8; 32],
+}
+
+impl RTcpTunnelCipher {
+    pub fn new_legacy(aes_key: [u8; 32], random_pk: [u8; 32], is_initiator: bool) -> Self {
+        Self {
+            version: RTCP_PROTOCOL_VERSION_LEGACY,
+            is_initiator,
+            aes_key,
+            random_pk,
+            session_key: aes_key,
+        }
+    }
+
+    // hello_ack没有签名, 协商出的mux也参与派生, 被篡改时双方key不一致
+    pub fn new_aead(
+        aes_key: [u8; 32],
+        random_pk: [u8; 32],
+        ack_nonce: &[u8; 32],
+        enable_mux: bool,
+        is_initiator: bool,
+    ) -> Self {
+        let mut salt = Vec::with_capacity(65);
+        salt.extend_from_slice(&random_pk);
+        salt.extend_from_slice(ack_nonce);
+        salt.push(enable_mux as u8);
+        let session_key = hkdf_derive_key(&aes_key, &salt, b"rtcp tunnel session v2");
+        Self {
+            version: RTCP_PROTOCOL_VERSION,
+            is_initiator,
+            aes_key,
+            random_pk,
+            session_key,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn is_initiator(&self) -> bool {
+        self.is_initiator
+    }
+
+    pub fn wrap_stream<S>(&self, stream: S, stream_iv: &[u8; 16]) -> Box<dyn AsyncStream>
+    where
+        S: AsyncStream + 'static,
+    {
+        if self.version >= RTCP_PROTOCOL_VERSION {
+            let (send_key, recv_key) =
+                derive_direction_keys(&self.session_key, stream_iv, self.is_initiator);
+            Box::new(AeadStream::new(stream, &send_key, &recv_key))
+        } else {
+            Box::new(EncryptedStream::new(stream, &self.aes_key, stream_iv))
+        }
+    }
+
+    fn control_iv(&self) -> [u8; 16] {
+        let mut iv = [0u8; 16];
+        iv.copy_from_slice(&self.random_pk[..16]);
+        iv
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct RTcpTunnel {
     build_helper: RTcpStreamBuildHelper,
@@ -35,17 +110,23 @@ pub(crate) struct RTcpTunnel {
     can_direct: bool,
     peer_addr: SocketAddr,
     this_device: DID,
-    aes_key: [u8; 32],
+    cipher: RTcpTunnelCipher,
     //random_pk:[u8;32],
     //write_stream:Arc<Mutex<WriteHalf<EncryptedStream<TcpStream>>>>,
     //read_stream:Arc<Mutex<ReadHalf<EncryptedStream<TcpStream>>>>,
-    write_stream: Arc<Mutex<WriteHalf<EncryptedStream<TcpStream>>>>,
-    read_stream: Arc<Mutex<ReadHalf<EncryptedStream<TcpStream>>>>,
+    write_stream: Arc<Mutex<WriteHalf<Box<dyn AsyncStream>>>>,
+    read_stream: Arc<Mutex<ReadHalf<Box<dyn AsyncStream>>>>,
 
     next_seq: Arc<AtomicU32>,
 
+    // 协商了复用模式时, stream直接跑在tunnel连接上
+    mux: Option<RTcpMux>,
+
     // Use to notify the open stream waiter
     open_resp_notify: Arc<Mutex<HashMap<u32, Arc<Notify>>>>,
+
+    keepalive: RTcpKeepaliveConfig,
+    metrics: Arc<TunnelMetrics>,
 }
 
 impl RTcpTunnel {
@@ -56,13 +137,17 @@ impl RTcpTunnel {
         target: &RTcpTargetStackEP,
         can_direct: bool,
         stream: TcpStream,
-        aes_key: [u8; 32],
-        random_pk: [u8; 32],
+        cipher: RTcpTunnelCipher,
+        enable_mux: bool,
+        keepalive: RTcpKeepaliveConfig,
     ) -> Self {
         let peer_addr = stream.peer_addr().unwrap();
-        let mut iv = [0u8; 16];
-        iv.copy_from_slice(&random_pk[..16]);
-        let encrypted_stream = EncryptedStream::new(stream, &aes_key, &iv);
+        let encrypted_stream = cipher.wrap_stream(stream, &cipher.control_iv());
+        let mux = if enable_mux {
+            Some(RTcpMux::new(cipher.is_initiator()))
+        } else {
+            None
+        };
         let (read_stream, write_stream) = tokio::io::split(encrypted_stream);
         //let (read_stream,write_stream) =  tokio::io::split(stream);
         let this_target = target.clone();
@@ -75,12 +160,15 @@ impl RTcpTunnel {
             can_direct, //Considering the limit of port mapping, the default configuration is configured as "NoDirect" mode
             peer_addr: peer_addr,
             this_device: this_device,
-            aes_key: aes_key,
+            cipher,
             read_stream: Arc::new(Mutex::new(read_stream)),
             write_stream: Arc::new(Mutex::new(write_stream)),
 
             next_seq: Arc::new(AtomicU32::new(0)),
+            mux,
             open_resp_notify: Arc::new(Mutex::new(HashMap::new())),
+            keepalive,
+            metrics: Arc::new(TunnelMetrics::new()),
         }
     }
 
@@ -90,8 +178,106 @@ impl RTcpTunnel {
         //read_stream.shutdown().await;
     }
 
+    pub fn is_same(&self, other: &RTcpTunnel) -> bool {
+        Arc::ptr_eq(&self.write_stream, &other.write_stream)
+    }
+
+    // 被替换的tunnel等复用stream都结束后再关闭, 对端读到EOF后会结束tunnel
+    pub async fn close_when_idle(&self) {
+        if let Some(mux) = &self.mux {
+            while mux.stream_count() > 0 {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        }
+        let mut write_stream = self.write_stream.lock().await;
+        let _ = tokio::io::AsyncWriteExt::shutdown(&mut *write_stream).await;
+    }
+
+    pub fn is_alive(&self) -> bool {
+        !self.metrics.is_closed()
+    }
+
+    pub async fn wait_closed(&self) {
+        self.metrics.wait_closed().await
+    }
+
+    pub fn get_stats(&self, tunnel_key: &str) -> RTcpTunnelStats {
+        let mut stats = RTcpTunnelStats {
+            tunnel_key: tunnel_key.to_string(),
+            target: self.target.did.to_string(),
+            peer_addr: self.peer_addr.to_string(),
+            protocol_version: self.cipher.version(),
+            mux: self.mux.is_some(),
+            alive: true,
+            rtt_ms: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            open_streams: 0,
+            created_at: 0,
+            last_active: 0,
+            last_error: None,
+        };
+        self.metrics.fill_stats(&mut stats);
+        if let Some(mux) = &self.mux {
+            // 复用stream在对端打开时不经过CountedStream, 以mux记录的为准
+            stats.open_streams = std::cmp::max(stats.open_streams, mux.stream_count() as u32);
+        }
+        stats
+    }
+
+    async fn send_ping(&self) -> Result<(), std::io::Error> {
+        let seq = self.next_seq();
+        let timestamp = buckyos_get_unix_timestamp();
+        let ping_package = RTcpPingPackage::new(seq, timestamp);
+        let mut write_stream = self.write_stream.lock().await;
+        let write_stream = Pin::new(&mut *write_stream);
+        // 对端不读的时候写可能一直阻塞, 不能超过判定断线的时间
+        match timeout(
+            self.keepalive.ping_timeout,
+            RTcpTunnelPackage::send_package(write_stream, ping_package),
+        )
+        .await
+        {
+            Ok(Ok(_)) => {
+                self.metrics.on_ping_sent(seq);
+                Ok(())
+            }
+            Ok(Err(e)) => Err(std::io::Error::other(format!("send ping error:{}", e))),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "send ping timeout",
+            )),
+        }
+    }
+
+    // 定时发送ping, 超时没有收到任何包就关闭tunnel
+    async fn run_keepalive(self) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.keepalive.ping_interval) => {}
+                _ = self.metrics.wait_closed() => break,
+            }
+
+            let idle_time = self.metrics.idle_time();
+            if idle_time >= self.keepalive.ping_timeout {
+                let msg = format!("no package received in {:?}", idle_time);
+                warn!("RTcp tunnel to {} dead: {}", self.target.did.to_string(), msg);
+                self.metrics.set_error(msg);
+                self.metrics.mark_closed();
+                break;
+            }
+
+            if let Err(e) = self.send_ping().await {
+                warn!("RTcp tunnel to {} ping failed: {}", self.target.did.to_string(), e);
+                self.metrics.set_error(e.to_string());
+                self.metrics.mark_closed();
+                break;
+            }
+        }
+    }
+
     pub fn get_key(&self) -> &[u8; 32] {
-        return &self.aes_key;
+        &self.cipher.aes_key
     }
 
     fn next_seq(&self) -> u32 {
@@ -133,7 +319,33 @@ impl RTcpTunnel {
 
                 Ok(())
             }
-            RTcpTunnelPackage::Pong(_pong_package) => Ok(()),
+            RTcpTunnelPackage::Pong(pong_package) => {
+                self.metrics.on_pong(pong_package.seq);
+                Ok(())
+            }
+            RTcpTunnelPackage::MuxOpen(open_package) => {
+                self.on_mux_open(open_package);
+                Ok(())
+            }
+            RTcpTunnelPackage::MuxOpenResp(resp_package) if self.mux.is_some() => {
+                let mux = self.mux.as_ref().unwrap();
+                mux.on_open_resp(resp_package.body.stream_id, resp_package.body.result);
+                Ok(())
+            }
+            RTcpTunnelPackage::MuxData(stream_id, data) if self.mux.is_some() => {
+                self.mux.as_ref().unwrap().on_data(stream_id, data);
+                Ok(())
+            }
+            RTcpTunnelPackage::MuxWindow(window_package) if self.mux.is_some() => {
+                let mux = self.mux.as_ref().unwrap();
+                mux.on_window(window_package.body.stream_id, window_package.body.increment);
+                Ok(())
+            }
+            RTcpTunnelPackage::MuxClose(close_package) if self.mux.is_some() => {
+                let mux = self.mux.as_ref().unwrap();
+                mux.on_close(close_package.body.stream_id, close_package.body.reset);
+                Ok(())
+            }
             t @ _ => {
                 error!("Unsupport tunnel package type: {:?}", t);
                 Ok(())
@@ -141,6 +353,95 @@ impl RTcpTunnel {
         }
     }
 
+    fn on_mux_open(&self, open_package: RTcpMuxOpenPackage) {
+        info!(
+            "RTcp tunnel mux open request: {}, {:?}:{}, {:?}",
+            open_package.body.stream_id,
+            open_package.body.dest_host,
+            open_package.body.dest_port,
+            open_package.body.purpose
+        );
+        if self.mux.is_none() {
+            error!("RTcp tunnel mux is not enabled, ignore mux open");
+            return;
+        }
+        let mux = self.mux.as_ref().unwrap();
+        let stream = mux.accept_stream(open_package.body.stream_id);
+        if stream.is_none() {
+            mux.send_open_resp(open_package.seq, open_package.body.stream_id, 1);
+            return;
+        }
+        let stream = stream.unwrap();
+
+        // 不能阻塞tunnel的读循环, 连接目标放到单独的任务里, 连上后再回复结果
+        let this = self.clone();
+        let mux = mux.clone();
+        task::spawn(async move {
+            let seq = open_package.seq;
+            let stream_id = open_package.body.stream_id;
+            let reply = move |result: u32| mux.send_open_resp(seq, stream_id, result);
+            let purpose = open_package.body.purpose.clone().unwrap_or_default();
+            let result = match purpose {
+                StreamPurpose::Stream => {
+                    this.on_stream_ropen(
+                        open_package.body.dest_host,
+                        open_package.body.dest_port,
+                        Box::new(stream),
+                        reply,
+                    )
+                    .await
+                }
+                StreamPurpose::Datagram => {
+                    this.on_datagram_ropen(
+                        open_package.body.dest_host,
+                        open_package.body.dest_port,
+                        Box::new(stream),
+                        reply,
+                    )
+                    .await
+                }
+            };
+            if let Err(e) = result {
+                error!("RTcp tunnel process mux stream error: {}", e);
+            }
+        });
+    }
+
+    // 按顺序把复用帧写入tunnel连接
+    async fn run_mux_writer(self, mut frame_rx: tokio::sync::mpsc::UnboundedReceiver<MuxFrame>) {
+        while let Some(frame) = frame_rx.recv().await {
+            let mut write_stream = self.write_stream.lock().await;
+            let write_stream = Pin::new(&mut *write_stream);
+            let ret = match frame {
+                MuxFrame::Open(pkg) => RTcpTunnelPackage::send_package(write_stream, pkg).await,
+                MuxFrame::OpenResp(pkg) => {
+                    RTcpTunnelPackage::send_package(write_stream, pkg).await
+                }
+                MuxFrame::Data(stream_id, data) => {
+                    RTcpTunnelPackage::send_mux_data(write_stream, stream_id, &data).await
+                }
+                MuxFrame::Window(stream_id, increment) => {
+                    let pkg = RTcpMuxWindowPackage::new(stream_id, increment);
+                    RTcpTunnelPackage::send_package(write_stream, pkg).await
+                }
+                MuxFrame::Close(stream_id, reset) => {
+                    let pkg = RTcpMuxClosePackage::new(stream_id, reset);
+                    RTcpTunnelPackage::send_package(write_stream, pkg).await
+                }
+                MuxFrame::Shutdown => break,
+            };
+            if ret.is_err() {
+                error!(
+                    "RTcp tunnel write mux frame error: {}, {}",
+                    self.target.did.to_string(),
+                    ret.err().unwrap()
+                );
+                break;
+            }
+        }
+
+    }
+
     async fn on_ropen(&self, ropen_package: RTcpROpenPackage) -> Result<(), anyhow::Error> {
         info!(
             "RTcp tunnel ropen request: {:?}:{}, {:?}",
@@ -186,12 +487,11 @@ impl RTcpTunnel {
             .map_err(|op| anyhow::format_err!("decode stream_id error:{}", op))?
             .try_into()
             .map_err(|_op| anyhow::format_err!("decode stream_id error"))?;
-        let aes_key = self.get_key().clone();
-        let aes_stream = EncryptedStream::new(rtcp_stream, &aes_key, &nonce_bytes);
+        let aes_stream = self.cipher.wrap_stream(rtcp_stream, &nonce_bytes);
 
         info!(
-            "RTcp stream encrypted with aes_key:{}, nonce_bytes:{}",
-            hex::encode(aes_key),
+            "RTcp stream encrypted with protocol v{}, nonce_bytes:{}",
+            self.cipher.version(),
             hex::encode(nonce_bytes)
         );
 
@@ -201,7 +501,8 @@ impl RTcpTunnel {
                 self.on_stream_ropen(
                     ropen_package.body.dest_host,
                     ropen_package.body.dest_port,
-                    Box::new(aes_stream),
+                    aes_stream,
+                    |_| {},
                 )
                 .await
             }
@@ -209,19 +510,24 @@ impl RTcpTunnel {
                 self.on_datagram_ropen(
                     ropen_package.body.dest_host,
                     ropen_package.body.dest_port,
-                    Box::new(aes_stream),
+                    aes_stream,
+                    |_| {},
                 )
                 .await
             }
         }
     }
 
+    // reply在目标准备好或者连接失败时调用, 0表示成功, 2表示连接目标失败
     async fn on_stream_ropen(
         &self,
         dest_host: Option<String>,
         dest_port: u16,
-        mut stream: Box<dyn AsyncStream>,
+        stream: Box<dyn AsyncStream>,
+        reply: impl FnOnce(u32) + Send,
     ) -> Result<(), anyhow::Error> {
+        let mut stream: Box<dyn AsyncStream> =
+            Box::new(CountedStream::new(stream, self.metrics.clone()));
         // Get real request target address
         let request_target_addr = match dest_host {
             Some(ref host) => format!("{}:{}", host, dest_port),
@@ -241,6 +547,7 @@ impl RTcpTunnel {
                 device_id: self.target.did.to_string(),
                 port: self.target.stack_port,
             };
+            reply(0);
             dispatcher.on_new_stream(stream, end_point).await?;
             return Ok(());
         }
@@ -254,10 +561,12 @@ impl RTcpTunnel {
                 request_target_addr,
                 raw_stream_to_target.err().unwrap()
             );
+            reply(2);
 
             return Ok(());
         }
         let mut raw_stream_to_target = raw_stream_to_target.unwrap();
+        reply(0);
 
         // 3. bind aes_stream and raw_stream_to_target
         info!(
@@ -290,7 +599,10 @@ impl RTcpTunnel {
         dest_host: Option<String>,
         dest_port: u16,
         stream: Box<dyn AsyncStream>,
+        reply: impl FnOnce(u32) + Send,
     ) -> Result<(), anyhow::Error> {
+        let stream: Box<dyn AsyncStream> =
+            Box::new(CountedStream::new(stream, self.metrics.clone()));
         let bind_addr;
         let request_target_addr = match dest_host {
             Some(ref host) => {
@@ -316,6 +628,7 @@ impl RTcpTunnel {
                 device_id: self.target.did.to_string(),
                 port: self.target.stack_port,
             };
+            reply(0);
             dispatcher.on_new_stream(stream, end_point).await?;
             return Ok(());
         }
@@ -326,8 +639,16 @@ impl RTcpTunnel {
             bind_addr,
             stream,
         )
-        .await?;
+        .await;
+        let forwarder = match forwarder {
+            Ok(forwarder) => forwarder,
+            Err(e) => {
+                reply(2);
+                return Err(e.into());
+            }
+        };
 
+        reply(0);
         forwarder.start();
 
         Ok(())
@@ -345,7 +666,7 @@ impl RTcpTunnel {
             self.this_device.to_string(),
             open_package.body.stream_id
         );
-        self.build_helper.wait_ropen_stream(&real_key).await?;
+        self.build_helper.new_wait_stream(&real_key).await;
 
         // 2. send open_resp with success
         {
@@ -362,12 +683,11 @@ impl RTcpTunnel {
             .map_err(|op| anyhow::format_err!("decode stream_id error:{}", op))?
             .try_into()
             .map_err(|_op| anyhow::format_err!("decode stream_id error"))?;
-        let aes_key = self.get_key().clone();
-        let aes_stream = EncryptedStream::new(stream, &aes_key, &nonce_bytes);
+        let aes_stream = self.cipher.wrap_stream(stream, &nonce_bytes);
 
         info!(
-            "RTcp stream encrypted with aes_key:{}, nonce_bytes:{}",
-            hex::encode(aes_key),
+            "RTcp stream encrypted with protocol v{}, nonce_bytes:{}",
+            self.cipher.version(),
             hex::encode(nonce_bytes)
         );
 
@@ -377,7 +697,8 @@ impl RTcpTunnel {
                 self.on_stream_ropen(
                     open_package.body.dest_host,
                     open_package.body.dest_port,
-                    Box::new(aes_stream),
+                    aes_stream,
+                    |_| {},
                 )
                 .await
             }
@@ -385,7 +706,8 @@ impl RTcpTunnel {
                 self.on_datagram_ropen(
                     open_package.body.dest_host,
                     open_package.body.dest_port,
-                    Box::new(aes_stream),
+                    aes_stream,
+                    |_| {},
                 )
                 .await
             }
@@ -394,6 +716,14 @@ impl RTcpTunnel {
 
     pub async fn run(self) {
         let source_info = self.target.did.to_string();
+        if let Some(mux) = &self.mux {
+            if let Some(frame_rx) = mux.take_frame_receiver() {
+                task::spawn(self.clone().run_mux_writer(frame_rx));
+            }
+        }
+
+        task::spawn(self.clone().run_keepalive());
+
         let mut read_stream = self.read_stream.lock().await;
         //let read_stream = self.read_stream.clone();
         loop {
@@ -406,29 +736,34 @@ impl RTcpTunnel {
             let read_stream = Pin::new(&mut *read_stream);
             //info!("rtcp tunnel try read package from {}",self.peer_addr.to_string());
 
-            let ret =
-                RTcpTunnelPackage::read_package(read_stream, false, source_info.as_str()).await;
+            let ret = tokio::select! {
+                ret = RTcpTunnelPackage::read_package(read_stream, false, source_info.as_str()) => ret,
+                // 保活检测到断线
+                _ = self.metrics.wait_closed() => break,
+            };
             //info!("rtcp tunnel read package from {} ok",source_info.as_str());
             if ret.is_err() {
-                error!(
-                    "Read package from tunnel error: {}, {:?}",
-                    source_info,
-                    ret.err().unwrap()
-                );
+                let msg = format!("read package error: {:?}", ret.err().unwrap());
+                error!("Tunnel {} {}", source_info, msg);
+                self.metrics.set_error(msg);
                 break;
             }
+            self.metrics.on_active();
 
             let package = ret.unwrap();
             let result = self.process_package(package).await;
             if result.is_err() {
-                error!(
-                    "process package error: {}, {}",
-                    source_info,
-                    result.err().unwrap()
-                );
+                let msg = format!("process package error: {}", result.err().unwrap());
+                error!("Tunnel {} {}", source_info, msg);
+                self.metrics.set_error(msg);
                 break;
             }
         }
+
+        self.metrics.mark_closed();
+        if let Some(mux) = &self.mux {
+            mux.close_all();
+        }
     }
 
     async fn post_ropen(
@@ -483,6 +818,21 @@ impl RTcpTunnel {
         purpose: Option<StreamPurpose>,
         dest_port: u16,
         dest_host: Option<String>,
+    ) -> Result<Box<dyn AsyncStream>, std::io::Error> {
+        match self.open_stream_inner(purpose, dest_port, dest_host).await {
+            Ok(stream) => Ok(Box::new(CountedStream::new(stream, self.metrics.clone()))),
+            Err(e) => {
+                self.metrics.set_error(format!("open stream error: {}", e));
+                Err(e)
+            }
+        }
+    }
+
+    async fn open_stream_inner(
+        &self,
+        purpose: Option<StreamPurpose>,
+        dest_port: u16,
+        dest_host: Option<String>,
     ) -> Result<Box<dyn AsyncStream>, std::io::Error> {
         // First generate 32byte session_key
         let random_bytes: [u8; 16] = rand::rng().random();
@@ -490,6 +840,16 @@ impl RTcpTunnel {
         let real_key = format!("{}_{}", self.this_device.to_string(), session_key);
         let seq = self.next_seq();
 
+        if let Some(mux) = &self.mux {
+            let stream = mux.open_stream(seq, purpose, dest_port, dest_host).await?;
+            info!(
+                "RTcp tunnel open mux stream {} to {}",
+                stream.stream_id(),
+                self.target.did.to_string()
+            );
+            return Ok(Box::new(stream));
+        }
+
         info!(
             "RTcp tunnel open stream to {}:{}, can_direct:{}",
             dest_host.clone().unwrap_or("127.0.0.1".to_string()),
@@ -544,8 +904,7 @@ impl RTcpTunnel {
                     std::io::Error::new(std::io::ErrorKind::Other, msg)
                 })?;
 
-            let aes_stream: EncryptedStream<TcpStream> =
-                EncryptedStream::new(stream, &self.get_key(), &random_bytes);
+            let aes_stream = self.cipher.wrap_stream(stream, &random_bytes);
 
             info!(
                 "RTcp tunnel open direct stream to {}, {}",
@@ -553,7 +912,7 @@ impl RTcpTunnel {
                 self.target.did.to_string()
             );
 
-            Ok(Box::new(aes_stream))
+            Ok(aes_stream)
         } else {
             //send ropen to target
 
@@ -565,10 +924,9 @@ impl RTcpTunnel {
 
             // wait new stream with session_key from target
             let stream = self.wait_ropen_stream(&session_key.as_str()).await?;
-            let aes_stream: EncryptedStream<TcpStream> =
-                EncryptedStream::new(stream, &self.get_key(), &random_bytes);
+            let aes_stream = self.cipher.wrap_stream(stream, &random_bytes);
             //info!("wait ropen stream ok,return aes stream: aes_key:{},nonce_bytes:{}",hex::encode(self.get_key()),hex::encode(random_bytes));
-            Ok(Box::new(aes_stream))
+            Ok(aes_stream)
         }
     }
 }
@@ -576,12 +934,13 @@ impl RTcpTunnel {
 #[async_trait]
 impl Tunnel for RTcpTunnel {
     async fn ping(&self) -> Result<(), std::io::Error> {
-        let timestamp = buckyos_get_unix_timestamp();
-        let ping_package = RTcpPingPackage::new(0, timestamp);
-        let mut write_stream = self.write_stream.lock().await;
-        let write_stream = Pin::new(&mut *write_stream);
-        let _ = RTcpTunnelPackage::send_package(write_stream, ping_package).await;
-        Ok(())
+        if !self.is_alive() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "tunnel closed",
+            ));
+        }
+        self.send_ping().await
     }
 
     async fn open_stream_by_dest(
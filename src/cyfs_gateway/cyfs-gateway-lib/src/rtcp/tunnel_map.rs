@@ -3,18 +3,49 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+type PendingEntry = Arc<Mutex<()>>;
+
 #[derive(Clone)]
 pub struct RTcpTunnelMap {
     tunnel_map: Arc<Mutex<HashMap<String, RTcpTunnel>>>,
+    // 正在建立的tunnel, 同一个目标同时只有一个建立过程, 不阻塞到其他目标的请求
+    pending: Arc<Mutex<HashMap<String, PendingEntry>>>,
 }
 
 impl RTcpTunnelMap {
     pub fn new() -> Self {
         RTcpTunnelMap {
             tunnel_map: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    // 拿到目标的pending entry, 调用方锁住它再建立tunnel, 结束后release
+    pub async fn pending_entry(&self, tunnel_key: &str) -> PendingEntry {
+        let mut pending = self.pending.lock().await;
+        pending.entry(tunnel_key.to_string()).or_default().clone()
+    }
+
+    pub async fn release_pending(&self, tunnel_key: &str, entry: PendingEntry) {
+        let mut pending = self.pending.lock().await;
+        // 还有其他请求在等同一个entry时保留, 它们会复用建好的tunnel
+        if Arc::strong_count(&entry) <= 2 {
+            pending.remove(tunnel_key);
+        }
+    }
+
+    // 返回可以复用的tunnel, 已经断开的tunnel直接移除
+    pub async fn get_alive_tunnel(&self, tunnel_key: &str) -> Option<RTcpTunnel> {
+        let mut all_tunnel = self.tunnel_map.lock().await;
+        let tunnel = all_tunnel.get(tunnel_key)?;
+        if tunnel.is_alive() {
+            return Some(tunnel.clone());
+        }
+        info!("Tunnel {} is dead, remove it", tunnel_key);
+        all_tunnel.remove(tunnel_key);
+        None
+    }
+
     pub fn tunnel_map(&self) -> Arc<Mutex<HashMap<String, RTcpTunnel>>> {
         self.tunnel_map.clone()
     }
@@ -39,8 +70,13 @@ impl RTcpTunnelMap {
         all_tunnel.insert(tunnel_key.to_owned(), tunnel);
     }
 
-    pub async fn remove_tunnel(&self, tunnel_key: &str) {
+    // 只移除同一个tunnel, 避免旧tunnel结束时把替换上来的新tunnel删掉
+    pub async fn remove_tunnel(&self, tunnel_key: &str, tunnel: &RTcpTunnel) {
         let mut all_tunnel = self.tunnel_map.lock().await;
-        all_tunnel.remove(tunnel_key);
+        if let Some(current) = all_tunnel.get(tunnel_key) {
+            if current.is_same(tunnel) {
+                all_tunnel.remove(tunnel_key);
+            }
+        }
     }
 }
@@ -0,0 +1,1104 @@
+// rudp连接: 与一个对端之间的加密udp会话, 承载多个可靠stream和不可靠datagram.
+// 收到的包由stack通过on_packet投递进来, run()负责发送/重传/保活
+use super::packet::*;
+use super::stream::RUdpStream;
+use crate::rtcp::StreamPurpose;
+use log::*;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::hash_map::Entry;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::timeout;
+
+const RUDP_INITIAL_RTO: Duration = Duration::from_millis(500);
+const RUDP_MIN_RTO: Duration = Duration::from_millis(200);
+const RUDP_MAX_RTO: Duration = Duration::from_secs(10);
+const RUDP_MAX_RETRIES: u32 = 12;
+const RUDP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+// 发起方收到对端第一个加密包之前, 按这个间隔重发ping证明自己持有key
+const RUDP_CONFIRM_INTERVAL: Duration = Duration::from_millis(500);
+const RUDP_IDLE_TIMEOUT: Duration = Duration::from_secs(40);
+const RUDP_OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+const RUDP_STREAM_SEND_BUF: usize = 256 * 1024;
+const RUDP_STREAM_RECV_BUF: usize = 256 * 1024;
+const RUDP_RECV_WINDOW_SEGMENTS: u32 = (RUDP_STREAM_RECV_BUF / RUDP_MAX_SEGMENT_LEN) as u32;
+// 接收方最多缓存recv_next之后多少个segment
+const RUDP_MAX_RECV_AHEAD: u32 = 512;
+const RUDP_INITIAL_CWND: f64 = 32.0;
+const RUDP_MIN_CWND: f64 = 4.0;
+const RUDP_MAX_CWND: f64 = 2048.0;
+const RUDP_FAST_RETRANSMIT_THRESHOLD: u32 = 3;
+const RUDP_DATAGRAM_QUEUE: usize = 256;
+const RUDP_MAX_CONTROL_QUEUE: usize = 1024;
+
+fn reset_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::ConnectionReset, "rudp stream reset")
+}
+
+fn closed_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "rudp connection closed")
+}
+
+struct Segment {
+    flags: u8,
+    data: Vec<u8>,
+    sent_at: Option<Instant>,
+    retries: u32,
+    // 被后面的sack跳过的次数, 达到阈值后快速重传
+    skipped: u32,
+    lost: bool,
+}
+
+struct StreamState {
+    send_next: u32,
+    segments: BTreeMap<u32, Segment>,
+    send_buf_len: usize,
+    peer_cum: u32,
+    peer_wnd: u32,
+    local_closed: bool,
+
+    recv_next: u32,
+    out_of_order: BTreeMap<u32, (u8, Vec<u8>)>,
+    recv_buf: VecDeque<Vec<u8>>,
+    recv_len: usize,
+    recv_closed: bool,
+    ack_pending: bool,
+    advertised_wnd: u32,
+
+    reset: bool,
+    // 本地句柄已经释放, 等待数据发完后移除
+    dropped: bool,
+    open_waiter: Option<oneshot::Sender<u32>>,
+    datagram_tx: Option<mpsc::Sender<Vec<u8>>>,
+    datagram_rx: Option<mpsc::Receiver<Vec<u8>>>,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn new() -> Self {
+        Self {
+            send_next: 0,
+            segments: BTreeMap::new(),
+            send_buf_len: 0,
+            peer_cum: 0,
+            peer_wnd: RUDP_RECV_WINDOW_SEGMENTS,
+            local_closed: false,
+            recv_next: 0,
+            out_of_order: BTreeMap::new(),
+            recv_buf: VecDeque::new(),
+            recv_len: 0,
+            recv_closed: false,
+            ack_pending: false,
+            advertised_wnd: RUDP_RECV_WINDOW_SEGMENTS,
+            reset: false,
+            dropped: false,
+            open_waiter: None,
+            datagram_tx: None,
+            datagram_rx: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn enable_datagram(&mut self) {
+        let (tx, rx) = mpsc::channel(RUDP_DATAGRAM_QUEUE);
+        self.datagram_tx = Some(tx);
+        self.datagram_rx = Some(rx);
+    }
+
+    fn push_segment(&mut self, flags: u8, data: Vec<u8>) {
+        self.send_buf_len += data.len();
+        self.segments.insert(
+            self.send_next,
+            Segment {
+                flags,
+                data,
+                sent_at: None,
+                retries: 0,
+                skipped: 0,
+                lost: false,
+            },
+        );
+        self.send_next += 1;
+    }
+
+    fn recv_wnd(&self) -> u32 {
+        let free = RUDP_STREAM_RECV_BUF.saturating_sub(self.recv_len);
+        std::cmp::min((free / RUDP_MAX_SEGMENT_LEN) as u32, RUDP_MAX_RECV_AHEAD)
+    }
+
+    fn make_ack(&mut self, stream_id: u32) -> Frame {
+        let wnd = self.recv_wnd();
+        self.ack_pending = false;
+        self.advertised_wnd = wnd;
+        Frame::Ack {
+            stream_id,
+            cum: self.recv_next,
+            wnd: std::cmp::min(wnd, u16::MAX as u32) as u16,
+            sacks: self
+                .out_of_order
+                .keys()
+                .take(RUDP_MAX_SACKS)
+                .cloned()
+                .collect(),
+        }
+    }
+
+    fn do_reset(&mut self) {
+        self.reset = true;
+        self.segments.clear();
+        self.send_buf_len = 0;
+        self.out_of_order.clear();
+        self.open_waiter = None;
+        self.datagram_tx = None;
+        self.wake_all();
+    }
+
+    fn wake_all(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct ConnState {
+    peer_addr: SocketAddr,
+    next_pn: u64,
+    replay: ReplayWindow,
+
+    streams: HashMap<u32, StreamState>,
+    next_stream_id: u32,
+    max_peer_stream_id: u32,
+    // 轮询各stream发送的起点, 避免一个大流量stream一直占满拥塞窗口
+    round_robin: usize,
+    control: VecDeque<Frame>,
+    incoming_tx: Option<mpsc::UnboundedSender<(RUdpStream, RUdpOpenBody)>>,
+
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    cwnd: f64,
+    ssthresh: f64,
+    last_loss: Option<Instant>,
+
+    last_recv: Instant,
+    last_send: Instant,
+    // 收到过对端通过认证的包, 说明对端确实持有派生出的key
+    confirmed: bool,
+    confirm_tx: Option<oneshot::Sender<()>>,
+    closed: bool,
+}
+
+impl ConnState {
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rttvar = sample / 2;
+            }
+            Some(srtt) => {
+                let diff = srtt.abs_diff(sample);
+                self.rttvar = self.rttvar * 3 / 4 + diff / 4;
+                self.srtt = Some(srtt * 7 / 8 + sample / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + std::cmp::max(self.rttvar * 4, Duration::from_millis(10));
+        self.rto = rto.clamp(RUDP_MIN_RTO, RUDP_MAX_RTO);
+    }
+
+    fn on_segment_acked(&mut self) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += 1.0;
+        } else {
+            self.cwnd += 1.0 / self.cwnd;
+        }
+        self.cwnd = self.cwnd.min(RUDP_MAX_CWND);
+    }
+
+    // 一个rtt内多次丢包只减一次窗口
+    fn on_loss(&mut self, now: Instant) {
+        let rtt = self.srtt.unwrap_or(RUDP_INITIAL_RTO);
+        if let Some(last_loss) = self.last_loss {
+            if now.duration_since(last_loss) < rtt {
+                return;
+            }
+        }
+        self.last_loss = Some(now);
+        self.ssthresh = (self.cwnd / 2.0).max(RUDP_MIN_CWND);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        for stream in self.streams.values_mut() {
+            stream.do_reset();
+        }
+        self.streams.clear();
+        self.control.clear();
+        self.incoming_tx = None;
+    }
+}
+
+fn retransmit_timeout(rto: Duration, retries: u32) -> Duration {
+    std::cmp::min(rto * (1u32 << std::cmp::min(retries, 6)), RUDP_MAX_RTO)
+}
+
+struct ConnShared {
+    conn_id: u32,
+    is_initiator: bool,
+    socket: Arc<UdpSocket>,
+    cipher: PacketCipher,
+    state: Mutex<ConnState>,
+    notify: Notify,
+}
+
+#[derive(Clone)]
+pub(crate) struct RUdpConnection {
+    shared: Arc<ConnShared>,
+}
+
+impl RUdpConnection {
+    // 发起方使用奇数stream_id, 接受方使用偶数
+    pub fn new(
+        conn_id: u32,
+        is_initiator: bool,
+        socket: Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+        send_key: &[u8; 32],
+        recv_key: &[u8; 32],
+    ) -> (Self, mpsc::UnboundedReceiver<(RUdpStream, RUdpOpenBody)>) {
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        let now = Instant::now();
+        let state = ConnState {
+            peer_addr,
+            next_pn: 0,
+            replay: ReplayWindow::default(),
+            streams: HashMap::new(),
+            next_stream_id: if is_initiator { 1 } else { 2 },
+            max_peer_stream_id: 0,
+            round_robin: 0,
+            control: VecDeque::new(),
+            incoming_tx: Some(incoming_tx),
+            srtt: None,
+            rttvar: RUDP_INITIAL_RTO / 2,
+            rto: RUDP_INITIAL_RTO,
+            cwnd: RUDP_INITIAL_CWND,
+            ssthresh: RUDP_MAX_CWND,
+            last_loss: None,
+            last_recv: now,
+            last_send: now,
+            confirmed: false,
+            confirm_tx: None,
+            closed: false,
+        };
+        let conn = Self {
+            shared: Arc::new(ConnShared {
+                conn_id,
+                is_initiator,
+                socket,
+                cipher: PacketCipher::new(send_key, recv_key),
+                state: Mutex::new(state),
+                notify: Notify::new(),
+            }),
+        };
+        (conn, incoming_rx)
+    }
+
+    pub fn conn_id(&self) -> u32 {
+        self.shared.conn_id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.shared.state.lock().unwrap().peer_addr
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.state.lock().unwrap().closed
+    }
+
+    pub fn is_same(&self, other: &RUdpConnection) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().close();
+        self.shared.notify.notify_one();
+    }
+
+    pub fn ping(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.closed {
+            state.control.push_back(Frame::Ping);
+        }
+        drop(state);
+        self.shared.notify.notify_one();
+    }
+
+    // 收到对端第一个通过认证的包时完成
+    pub fn wait_confirmed(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.shared.state.lock().unwrap();
+        if state.confirmed {
+            let _ = tx.send(());
+        } else {
+            state.confirm_tx = Some(tx);
+        }
+        rx
+    }
+
+    fn wake_sender(&self) {
+        self.shared.notify.notify_one();
+    }
+
+    pub fn on_packet(&self, from: SocketAddr, packet: &[u8]) {
+        let header = match PacketHeader::decode(packet) {
+            Some(header)
+                if header.ptype == PacketType::Data && header.conn_id == self.shared.conn_id =>
+            {
+                header
+            }
+            _ => return,
+        };
+        let plain = match self.shared.cipher.open(&header, packet) {
+            Some(plain) => plain,
+            None => {
+                debug!("rudp packet from {} authentication failed, drop it", from);
+                return;
+            }
+        };
+        let frames = match Frame::decode_all(&plain) {
+            Some(frames) => frames,
+            None => {
+                warn!("rudp packet from {} has invalid frame", from);
+                return;
+            }
+        };
+
+        let mut incoming = Vec::new();
+        let incoming_tx;
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed || !state.replay.check_and_update(header.pn) {
+                return;
+            }
+            let now = Instant::now();
+            state.last_recv = now;
+            if !state.confirmed {
+                state.confirmed = true;
+                if let Some(tx) = state.confirm_tx.take() {
+                    let _ = tx.send(());
+                }
+            }
+            // 通过认证的包才更新对端地址, 支持NAT重新映射
+            if state.peer_addr != from {
+                info!(
+                    "rudp connection {} peer addr changed: {} -> {}",
+                    self.shared.conn_id, state.peer_addr, from
+                );
+                state.peer_addr = from;
+            }
+
+            for frame in frames {
+                match frame {
+                    Frame::Ping => state.control.push_back(Frame::Pong),
+                    Frame::Pong => {}
+                    Frame::Stream {
+                        stream_id,
+                        seq,
+                        flags,
+                        data,
+                    } => self.on_stream_frame(&mut state, stream_id, seq, flags, data, &mut incoming),
+                    Frame::Ack {
+                        stream_id,
+                        cum,
+                        wnd,
+                        sacks,
+                    } => Self::on_ack(&mut state, stream_id, cum, wnd, sacks, now),
+                    Frame::Reset { stream_id } => {
+                        if let Some(stream) = state.streams.get_mut(&stream_id) {
+                            debug!("rudp stream {} reset by peer", stream_id);
+                            stream.do_reset();
+                        }
+                    }
+                    Frame::Datagram { stream_id, data } => {
+                        let tx = state
+                            .streams
+                            .get(&stream_id)
+                            .and_then(|stream| stream.datagram_tx.as_ref());
+                        if let Some(tx) = tx {
+                            // 接收方处理不过来时直接丢弃
+                            let _ = tx.try_send(data);
+                        }
+                    }
+                }
+            }
+            incoming_tx = state.incoming_tx.clone();
+        }
+
+        // 不能在持有锁时创建/释放stream句柄
+        for (stream_id, open_body) in incoming {
+            let stream = RUdpStream::new(self.clone(), stream_id);
+            if let Some(tx) = incoming_tx.as_ref() {
+                let _ = tx.send((stream, open_body));
+            }
+        }
+        self.wake_sender();
+    }
+
+    fn on_stream_frame(
+        &self,
+        state: &mut ConnState,
+        stream_id: u32,
+        seq: u32,
+        flags: u8,
+        data: Vec<u8>,
+        incoming: &mut Vec<(u32, RUdpOpenBody)>,
+    ) {
+        let ConnState {
+            streams,
+            control,
+            max_peer_stream_id,
+            ..
+        } = state;
+        let stream = match streams.entry(stream_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let peer_parity = if self.shared.is_initiator { 0 } else { 1 };
+                if seq == 0
+                    && flags & STREAM_FLAG_OPEN != 0
+                    && stream_id % 2 == peer_parity
+                    && stream_id > *max_peer_stream_id
+                {
+                    *max_peer_stream_id = stream_id;
+                    entry.insert(StreamState::new())
+                } else {
+                    // 已经关闭的stream, 通知对端不要再发
+                    control.push_back(Frame::Reset { stream_id });
+                    return;
+                }
+            }
+        };
+        if stream.reset {
+            return;
+        }
+        stream.ack_pending = true;
+        if seq < stream.recv_next || seq - stream.recv_next >= RUDP_MAX_RECV_AHEAD {
+            return;
+        }
+        stream.out_of_order.entry(seq).or_insert((flags, data));
+
+        while let Some((flags, data)) = stream.out_of_order.remove(&stream.recv_next) {
+            stream.recv_next += 1;
+            if flags & STREAM_FLAG_OPEN != 0 {
+                match serde_json::from_slice::<RUdpOpenBody>(&data) {
+                    Ok(open_body) => {
+                        if matches!(open_body.purpose, Some(StreamPurpose::Datagram)) {
+                            stream.enable_datagram();
+                        }
+                        incoming.push((stream_id, open_body));
+                    }
+                    Err(e) => {
+                        warn!("rudp stream {} invalid open body: {}", stream_id, e);
+                        stream.do_reset();
+                        control.push_back(Frame::Reset { stream_id });
+                        return;
+                    }
+                }
+            } else if flags & STREAM_FLAG_OPEN_RESP != 0 {
+                let result = data
+                    .get(..4)
+                    .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                    .unwrap_or(u32::MAX);
+                if let Some(waiter) = stream.open_waiter.take() {
+                    let _ = waiter.send(result);
+                }
+            } else if !data.is_empty() {
+                stream.recv_len += data.len();
+                stream.recv_buf.push_back(data);
+                if let Some(waker) = stream.read_waker.take() {
+                    waker.wake();
+                }
+            }
+
+            if flags & STREAM_FLAG_FIN != 0 {
+                stream.recv_closed = true;
+                if let Some(waker) = stream.read_waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+
+    fn on_ack(
+        state: &mut ConnState,
+        stream_id: u32,
+        cum: u32,
+        wnd: u16,
+        sacks: Vec<u32>,
+        now: Instant,
+    ) {
+        let stream = match state.streams.get_mut(&stream_id) {
+            Some(stream) if !stream.reset => stream,
+            _ => return,
+        };
+        if cum > stream.send_next {
+            warn!("rudp stream {} ack unsent seq {}", stream_id, cum);
+            return;
+        }
+        // 乱序到达的旧ack不能回退窗口
+        if cum >= stream.peer_cum {
+            stream.peer_cum = cum;
+            stream.peer_wnd = wnd as u32;
+        }
+
+        let mut acked_seqs: Vec<u32> = stream.segments.range(..cum).map(|(seq, _)| *seq).collect();
+        acked_seqs.extend(sacks.iter().filter(|seq| **seq >= cum));
+        let mut acked = 0;
+        let mut rtt_sample = None;
+        for seq in acked_seqs {
+            if let Some(segment) = stream.segments.remove(&seq) {
+                stream.send_buf_len -= segment.data.len();
+                acked += 1;
+                // 只用没有重传过的segment估算rtt
+                if segment.retries == 0 {
+                    if let Some(sent_at) = segment.sent_at {
+                        rtt_sample = Some(now.duration_since(sent_at));
+                    }
+                }
+            }
+        }
+
+        let mut lost = false;
+        if let Some(max_sack) = sacks.iter().max() {
+            for (_, segment) in stream.segments.range_mut(..*max_sack) {
+                if segment.sent_at.is_some() && !segment.lost {
+                    segment.skipped += 1;
+                    if segment.skipped >= RUDP_FAST_RETRANSMIT_THRESHOLD {
+                        segment.lost = true;
+                        lost = true;
+                    }
+                }
+            }
+        }
+        if acked > 0 {
+            if let Some(waker) = stream.write_waker.take() {
+                waker.wake();
+            }
+        }
+
+        if let Some(rtt) = rtt_sample {
+            state.update_rtt(rtt);
+        }
+        for _ in 0..acked {
+            state.on_segment_acked();
+        }
+        if lost {
+            state.on_loss(now);
+        }
+    }
+
+    // 生成这一轮要发送的包, 返回None表示连接已经关闭
+    fn poll_transmit(&self, now: Instant) -> Option<(Vec<Vec<u8>>, SocketAddr, Duration)> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        if now.duration_since(state.last_recv) >= RUDP_IDLE_TIMEOUT {
+            warn!(
+                "rudp connection {} to {} idle timeout",
+                self.shared.conn_id, state.peer_addr
+            );
+            state.close();
+            return None;
+        }
+        // 接受方在对端证明持有key之前不会启动发送, 所以只有发起方需要重发
+        let confirming = self.shared.is_initiator && !state.confirmed;
+        if now.duration_since(state.last_send) >= RUDP_KEEPALIVE_INTERVAL
+            || (confirming && now.duration_since(state.last_send) >= RUDP_CONFIRM_INTERVAL)
+        {
+            state.control.push_back(Frame::Ping);
+        }
+
+        let mut frames: Vec<Frame> = state.control.drain(..).collect();
+        let rto = state.rto;
+        let mut next_timer = state.last_recv + RUDP_IDLE_TIMEOUT;
+        let mut in_flight = 0usize;
+        let mut timeout_loss = false;
+        let mut dead = false;
+
+        // 检查超时的segment
+        state.streams.retain(|_, stream| {
+            !(stream.dropped && (stream.reset || stream.segments.is_empty()))
+        });
+        for (stream_id, stream) in state.streams.iter_mut() {
+            if stream.ack_pending {
+                frames.push(stream.make_ack(*stream_id));
+            }
+            for segment in stream.segments.values_mut() {
+                let sent_at = match segment.sent_at {
+                    Some(sent_at) if !segment.lost => sent_at,
+                    _ => continue,
+                };
+                let deadline = sent_at + retransmit_timeout(rto, segment.retries);
+                if now >= deadline {
+                    if segment.retries >= RUDP_MAX_RETRIES {
+                        dead = true;
+                    }
+                    segment.lost = true;
+                    timeout_loss = true;
+                } else {
+                    in_flight += 1;
+                    next_timer = next_timer.min(deadline);
+                }
+            }
+        }
+        if dead {
+            warn!(
+                "rudp connection {} to {} retransmit too many times",
+                self.shared.conn_id, state.peer_addr
+            );
+            state.close();
+            return None;
+        }
+        if timeout_loss {
+            state.on_loss(now);
+        }
+
+        // 先重传丢失的, 再在拥塞窗口和对端接收窗口内发送新数据
+        let cwnd = state.cwnd as usize;
+        let mut stream_ids: Vec<u32> = state.streams.keys().cloned().collect();
+        stream_ids.sort();
+        if !stream_ids.is_empty() {
+            let start = state.round_robin % stream_ids.len();
+            stream_ids.rotate_left(start);
+        }
+        state.round_robin = state.round_robin.wrapping_add(1);
+        for stream_id in stream_ids {
+            let stream = state.streams.get_mut(&stream_id).unwrap();
+            // 对端窗口为0时仍允许一个segment作为探测
+            let limit = stream.peer_cum.saturating_add(std::cmp::max(stream.peer_wnd, 1));
+            for (seq, segment) in stream.segments.iter_mut() {
+                if segment.lost {
+                    segment.lost = false;
+                    segment.retries += 1;
+                    segment.skipped = 0;
+                } else if segment.sent_at.is_none() {
+                    if *seq >= limit || in_flight >= cwnd {
+                        break;
+                    }
+                } else {
+                    continue;
+                }
+                segment.sent_at = Some(now);
+                in_flight += 1;
+                next_timer = next_timer.min(now + retransmit_timeout(rto, segment.retries));
+                frames.push(Frame::Stream {
+                    stream_id,
+                    seq: *seq,
+                    flags: segment.flags,
+                    data: segment.data.clone(),
+                });
+            }
+        }
+
+        let mut packets = Vec::new();
+        let mut buf = Vec::new();
+        for frame in frames {
+            if !buf.is_empty() && buf.len() + frame.encoded_len() > RUDP_MAX_FRAMES_LEN {
+                let pn = state.next_pn;
+                state.next_pn += 1;
+                packets.push(self.shared.cipher.seal(self.shared.conn_id, pn, buf));
+                buf = Vec::new();
+            }
+            frame.encode(&mut buf);
+        }
+        if !buf.is_empty() {
+            let pn = state.next_pn;
+            state.next_pn += 1;
+            packets.push(self.shared.cipher.seal(self.shared.conn_id, pn, buf));
+        }
+        if !packets.is_empty() {
+            state.last_send = now;
+        }
+        next_timer = next_timer.min(state.last_send + RUDP_KEEPALIVE_INTERVAL);
+        if confirming {
+            next_timer = next_timer.min(state.last_send + RUDP_CONFIRM_INTERVAL);
+        }
+
+        let wait = std::cmp::max(
+            next_timer.saturating_duration_since(now),
+            Duration::from_millis(1),
+        );
+        Some((packets, state.peer_addr, wait))
+    }
+
+    pub async fn run(self) {
+        while let Some((packets, peer_addr, wait)) = self.poll_transmit(Instant::now()) {
+            for packet in packets {
+                if let Err(e) = self.shared.socket.send_to(&packet, peer_addr).await {
+                    debug!("rudp send packet to {} error: {}", peer_addr, e);
+                }
+            }
+            tokio::select! {
+                _ = self.shared.notify.notified() => {}
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+        info!("rudp connection {} closed", self.shared.conn_id);
+    }
+
+    pub async fn open_stream(
+        &self,
+        purpose: Option<StreamPurpose>,
+        dest_port: u16,
+        dest_host: Option<String>,
+    ) -> Result<RUdpStream, std::io::Error> {
+        let (tx, rx) = oneshot::channel();
+        let stream_id = {
+            let mut state = self.shared.state.lock().unwrap();
+            if state.closed {
+                return Err(closed_error());
+            }
+            let stream_id = state.next_stream_id;
+            state.next_stream_id += 2;
+
+            let mut stream = StreamState::new();
+            if matches!(purpose, Some(StreamPurpose::Datagram)) {
+                stream.enable_datagram();
+            }
+            stream.open_waiter = Some(tx);
+            let open_body = RUdpOpenBody {
+                purpose,
+                dest_port,
+                dest_host,
+            };
+            stream.push_segment(STREAM_FLAG_OPEN, serde_json::to_vec(&open_body).unwrap());
+            state.streams.insert(stream_id, stream);
+            stream_id
+        };
+        self.wake_sender();
+
+        let stream = RUdpStream::new(self.clone(), stream_id);
+        match timeout(RUDP_OPEN_TIMEOUT, rx).await {
+            Ok(Ok(0)) => Ok(stream),
+            Ok(Ok(result)) => {
+                let msg = format!("rudp open stream {} failed, result:{}", stream_id, result);
+                warn!("{}", msg);
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    msg,
+                ))
+            }
+            Ok(Err(_)) => Err(reset_error()),
+            Err(_) => {
+                error!("Timeout: rudp open stream {} no response", stream_id);
+                Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Timeout"))
+            }
+        }
+    }
+
+    pub(super) fn send_open_resp(&self, stream_id: u32, result: u32) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(stream) = state.streams.get_mut(&stream_id) {
+            if !stream.reset {
+                stream.push_segment(STREAM_FLAG_OPEN_RESP, result.to_be_bytes().to_vec());
+            }
+        }
+        drop(state);
+        self.wake_sender();
+    }
+
+    pub(super) fn take_datagram_receiver(&self, stream_id: u32) -> Option<mpsc::Receiver<Vec<u8>>> {
+        let mut state = self.shared.state.lock().unwrap();
+        state
+            .streams
+            .get_mut(&stream_id)
+            .and_then(|stream| stream.datagram_rx.take())
+    }
+
+    pub(super) fn send_datagram(&self, stream_id: u32, data: &[u8]) -> Result<usize, std::io::Error> {
+        if data.len() > RUDP_MAX_DATAGRAM_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("rudp datagram too large: {} > {}", data.len(), RUDP_MAX_DATAGRAM_LEN),
+            ));
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(closed_error());
+        }
+        match state.streams.get(&stream_id) {
+            Some(stream) if !stream.reset => {}
+            _ => return Err(reset_error()),
+        }
+        // 队列满时直接丢弃, datagram本来就不保证送达
+        if state.control.len() < RUDP_MAX_CONTROL_QUEUE {
+            state.control.push_back(Frame::Datagram {
+                stream_id,
+                data: data.to_vec(),
+            });
+        }
+        drop(state);
+        self.wake_sender();
+        Ok(data.len())
+    }
+
+    pub(super) fn poll_stream_read(
+        &self,
+        stream_id: u32,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut state = self.shared.state.lock().unwrap();
+        let stream = match state.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return Poll::Ready(Err(reset_error())),
+        };
+        if stream.recv_buf.is_empty() {
+            if stream.reset {
+                return Poll::Ready(Err(reset_error()));
+            }
+            if stream.recv_closed {
+                return Poll::Ready(Ok(()));
+            }
+            stream.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let mut read_len = 0;
+        while buf.remaining() > 0 {
+            let chunk = match stream.recv_buf.front_mut() {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let n = std::cmp::min(buf.remaining(), chunk.len());
+            buf.put_slice(&chunk[..n]);
+            if n == chunk.len() {
+                stream.recv_buf.pop_front();
+            } else {
+                chunk.drain(..n);
+            }
+            read_len += n;
+        }
+        stream.recv_len -= read_len;
+
+        // 窗口明显变大时主动通告, 避免对端停在零窗口上等探测
+        let notify = !stream.recv_closed
+            && stream.recv_wnd() >= stream.advertised_wnd + RUDP_RECV_WINDOW_SEGMENTS / 4;
+        if notify {
+            stream.ack_pending = true;
+        }
+        drop(state);
+        if notify {
+            self.wake_sender();
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    pub(super) fn poll_stream_write(
+        &self,
+        stream_id: u32,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut state = self.shared.state.lock().unwrap();
+        let stream = match state.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return Poll::Ready(Err(reset_error())),
+        };
+        if stream.reset {
+            return Poll::Ready(Err(reset_error()));
+        }
+        if stream.local_closed {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if stream.send_buf_len >= RUDP_STREAM_SEND_BUF {
+            stream.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = std::cmp::min(buf.len(), RUDP_STREAM_SEND_BUF - stream.send_buf_len);
+        for chunk in buf[..n].chunks(RUDP_MAX_SEGMENT_LEN) {
+            stream.push_segment(0, chunk.to_vec());
+        }
+        drop(state);
+        self.wake_sender();
+        Poll::Ready(Ok(n))
+    }
+
+    pub(super) fn shutdown_stream(&self, stream_id: u32) {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(stream) = state.streams.get_mut(&stream_id) {
+            if !stream.local_closed && !stream.reset {
+                stream.local_closed = true;
+                stream.push_segment(STREAM_FLAG_FIN, Vec::new());
+            }
+        }
+        drop(state);
+        self.wake_sender();
+    }
+
+    // 双向都正常关闭的stream等FIN被确认后再移除, 否则直接reset
+    pub(super) fn drop_stream(&self, stream_id: u32) {
+        let mut state = self.shared.state.lock().unwrap();
+        let graceful = match state.streams.get_mut(&stream_id) {
+            Some(stream) => {
+                stream.dropped = true;
+                stream.datagram_rx = None;
+                stream.reset || (stream.local_closed && stream.recv_closed)
+            }
+            None => return,
+        };
+        if !graceful {
+            state.streams.remove(&stream_id);
+            state.control.push_back(Frame::Reset { stream_id });
+        }
+        drop(state);
+        self.wake_sender();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rudp::stream::RUdpDatagramClient;
+    use crate::tunnel::DatagramClient;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // 中间转发的socket, 按固定规律丢包和乱序
+    async fn run_lossy_relay(relay: UdpSocket, a: SocketAddr, b: SocketAddr) {
+        let mut buf = vec![0u8; 2048];
+        let mut count = 0u32;
+        let mut held: Option<(Vec<u8>, SocketAddr)> = None;
+        loop {
+            let (len, from) = relay.recv_from(&mut buf).await.unwrap();
+            let to = if from == a { b } else { a };
+            count += 1;
+            if count % 20 == 7 {
+                continue;
+            }
+            if count % 13 == 5 && held.is_none() {
+                held = Some((buf[..len].to_vec(), to));
+                continue;
+            }
+            let _ = relay.send_to(&buf[..len], to).await;
+            if let Some((packet, to)) = held.take() {
+                let _ = relay.send_to(&packet, to).await;
+            }
+        }
+    }
+
+    fn start_conn(
+        socket: UdpSocket,
+        is_initiator: bool,
+        relay_addr: SocketAddr,
+        send_key: &[u8; 32],
+        recv_key: &[u8; 32],
+    ) -> (RUdpConnection, mpsc::UnboundedReceiver<(RUdpStream, RUdpOpenBody)>) {
+        let socket = Arc::new(socket);
+        let (conn, incoming_rx) =
+            RUdpConnection::new(7, is_initiator, socket.clone(), relay_addr, send_key, recv_key);
+        tokio::spawn(conn.clone().run());
+        let recv_conn = conn.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                recv_conn.on_packet(from, &buf[..len]);
+            }
+        });
+        (conn, incoming_rx)
+    }
+
+    #[tokio::test]
+    async fn test_rudp_stream_and_datagram_over_lossy_link() {
+        let socket_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        tokio::spawn(run_lossy_relay(
+            relay,
+            socket_a.local_addr().unwrap(),
+            socket_b.local_addr().unwrap(),
+        ));
+
+        let key1 = [1u8; 32];
+        let key2 = [2u8; 32];
+        let (conn_a, _) = start_conn(socket_a, true, relay_addr, &key1, &key2);
+        let (_conn_b, mut incoming_b) = start_conn(socket_b, false, relay_addr, &key2, &key1);
+
+        // b端把收到的stream和datagram原样发回
+        tokio::spawn(async move {
+            while let Some((stream, open_body)) = incoming_b.recv().await {
+                stream.send_open_resp(0);
+                if matches!(open_body.purpose, Some(StreamPurpose::Datagram)) {
+                    let client = RUdpDatagramClient::new(stream).unwrap();
+                    tokio::spawn(async move {
+                        let mut buf = vec![0u8; RUDP_MAX_DATAGRAM_LEN];
+                        while let Ok(len) = client.recv_datagram(&mut buf).await {
+                            let _ = client.send_datagram(&buf[..len]).await;
+                        }
+                    });
+                } else {
+                    tokio::spawn(async move {
+                        let (mut reader, mut writer) = tokio::io::split(stream);
+                        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                        writer.shutdown().await.unwrap();
+                    });
+                }
+            }
+        });
+
+        let stream = conn_a
+            .open_stream(Some(StreamPurpose::Stream), 80, None)
+            .await
+            .unwrap();
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let send_data = data.clone();
+        let writer_task = tokio::spawn(async move {
+            writer.write_all(&send_data).await.unwrap();
+            writer.shutdown().await.unwrap();
+        });
+        let mut echo = Vec::new();
+        timeout(Duration::from_secs(30), reader.read_to_end(&mut echo))
+            .await
+            .unwrap()
+            .unwrap();
+        writer_task.await.unwrap();
+        assert_eq!(echo, data);
+
+        let stream = conn_a
+            .open_stream(Some(StreamPurpose::Datagram), 53, None)
+            .await
+            .unwrap();
+        let client = RUdpDatagramClient::new(stream).unwrap();
+        let mut buf = vec![0u8; RUDP_MAX_DATAGRAM_LEN];
+        let mut echo_len = None;
+        for i in 0..20 {
+            let datagram = format!("datagram-{}", i);
+            client.send_datagram(datagram.as_bytes()).await.unwrap();
+            if let Ok(ret) = timeout(Duration::from_millis(200), client.recv_datagram(&mut buf)).await {
+                echo_len = Some(ret.unwrap());
+                break;
+            }
+        }
+        let echo_len = echo_len.unwrap();
+        assert!(buf[..echo_len].starts_with(b"datagram-"));
+
+        let oversized = vec![0u8; RUDP_MAX_DATAGRAM_LEN + 1];
+        assert!(client.send_datagram(&oversized).await.is_err());
+    }
+}
@@ -0,0 +1,8 @@
+mod packet;
+mod connection;
+mod stream;
+mod tunnel;
+mod stack;
+
+pub use packet::RUDP_MAX_DATAGRAM_LEN;
+pub use stack::*;
@@ -0,0 +1,380 @@
+/*
+rudp的udp包格式, 包头13字节:
+type:u8, 1=hello 2=hello_ack 3=data
+conn_id:u32, 由发起方随机生成
+pn:u64, 包序号, 每个方向独立递增
+payload
+
+hello/hello_ack的payload是明文json, 复用rtcp的tunnel token做密钥协商
+{
+from_id: string,
+to_id: string,
+my_port: u16,
+tunnel_token: string
+}
+{
+result: u32,
+nonce: option<string> (hex, 32字节随机数)
+}
+双方用HKDF(tunnel key, random_pk+nonce)派生session key, 再按方向派生包加密key.
+data包的payload是ChaCha20-Poly1305加密后的frame序列, 包头作为AAD, nonce=4字节0+pn
+
+frame:
+ping: 0x01
+pong: 0x02
+stream: 0x03 stream_id:u32 seq:u32 flags:u8 len:u16 data
+ack: 0x04 stream_id:u32 cum:u32 wnd:u16 count:u8 sack_seq:u32*count
+reset: 0x05 stream_id:u32
+datagram: 0x06 stream_id:u32 len:u16 data
+
+每个stream有独立的seq空间和重传, 一个stream丢包不会阻塞其他stream.
+stream的第一个segment带open标志, data是json(purpose,dest_port,dest_host), 对端回复带open_resp标志的segment,
+data是u32的result. datagram frame不重传, 只在purpose为datagram的stream上使用
+*/
+use crate::rtcp::StreamPurpose;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+
+pub(crate) const RUDP_PACKET_HEADER_LEN: usize = 13;
+const RUDP_TAG_LEN: usize = 16;
+// 保守的udp payload大小, 避免ip分片
+pub(crate) const RUDP_MAX_PACKET_LEN: usize = 1200;
+pub(crate) const RUDP_MAX_FRAMES_LEN: usize =
+    RUDP_MAX_PACKET_LEN - RUDP_PACKET_HEADER_LEN - RUDP_TAG_LEN;
+const RUDP_STREAM_FRAME_HEADER_LEN: usize = 12;
+const RUDP_DATAGRAM_FRAME_HEADER_LEN: usize = 7;
+pub(crate) const RUDP_MAX_SEGMENT_LEN: usize = RUDP_MAX_FRAMES_LEN - RUDP_STREAM_FRAME_HEADER_LEN;
+pub const RUDP_MAX_DATAGRAM_LEN: usize = RUDP_MAX_FRAMES_LEN - RUDP_DATAGRAM_FRAME_HEADER_LEN;
+pub(crate) const RUDP_MAX_SACKS: usize = 16;
+
+pub(crate) const STREAM_FLAG_OPEN: u8 = 0x01;
+pub(crate) const STREAM_FLAG_OPEN_RESP: u8 = 0x02;
+pub(crate) const STREAM_FLAG_FIN: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PacketType {
+    Hello = 1,
+    HelloAck = 2,
+    Data = 3,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PacketHeader {
+    pub ptype: PacketType,
+    pub conn_id: u32,
+    pub pn: u64,
+}
+
+impl PacketHeader {
+    pub fn encode(&self) -> [u8; RUDP_PACKET_HEADER_LEN] {
+        let mut buf = [0u8; RUDP_PACKET_HEADER_LEN];
+        buf[0] = self.ptype as u8;
+        buf[1..5].copy_from_slice(&self.conn_id.to_be_bytes());
+        buf[5..13].copy_from_slice(&self.pn.to_be_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<PacketHeader> {
+        if buf.len() < RUDP_PACKET_HEADER_LEN {
+            return None;
+        }
+        let ptype = match buf[0] {
+            1 => PacketType::Hello,
+            2 => PacketType::HelloAck,
+            3 => PacketType::Data,
+            _ => return None,
+        };
+        Some(PacketHeader {
+            ptype,
+            conn_id: u32::from_be_bytes(buf[1..5].try_into().unwrap()),
+            pn: u64::from_be_bytes(buf[5..13].try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RUdpHelloBody {
+    pub from_id: String,
+    pub to_id: String,
+    pub my_port: u16,
+    pub tunnel_token: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RUdpHelloAckBody {
+    pub result: u32,
+    pub nonce: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct RUdpOpenBody {
+    pub purpose: Option<StreamPurpose>,
+    pub dest_port: u16,
+    pub dest_host: Option<String>,
+}
+
+pub(crate) fn encode_plain_packet<T: Serialize>(
+    ptype: PacketType,
+    conn_id: u32,
+    body: &T,
+) -> Vec<u8> {
+    let header = PacketHeader {
+        ptype,
+        conn_id,
+        pn: 0,
+    };
+    let mut buf = header.encode().to_vec();
+    buf.extend_from_slice(&serde_json::to_vec(body).unwrap());
+    buf
+}
+
+pub(crate) fn decode_plain_body<T: for<'de> Deserialize<'de>>(packet: &[u8]) -> Option<T> {
+    serde_json::from_slice(&packet[RUDP_PACKET_HEADER_LEN..]).ok()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Frame {
+    Ping,
+    Pong,
+    Stream {
+        stream_id: u32,
+        seq: u32,
+        flags: u8,
+        data: Vec<u8>,
+    },
+    Ack {
+        stream_id: u32,
+        cum: u32,
+        wnd: u16,
+        sacks: Vec<u32>,
+    },
+    Reset {
+        stream_id: u32,
+    },
+    Datagram {
+        stream_id: u32,
+        data: Vec<u8>,
+    },
+}
+
+impl Frame {
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            Frame::Ping | Frame::Pong => 1,
+            Frame::Stream { data, .. } => RUDP_STREAM_FRAME_HEADER_LEN + data.len(),
+            Frame::Ack { sacks, .. } => 12 + sacks.len() * 4,
+            Frame::Reset { .. } => 5,
+            Frame::Datagram { data, .. } => RUDP_DATAGRAM_FRAME_HEADER_LEN + data.len(),
+        }
+    }
+
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Frame::Ping => buf.push(0x01),
+            Frame::Pong => buf.push(0x02),
+            Frame::Stream {
+                stream_id,
+                seq,
+                flags,
+                data,
+            } => {
+                buf.push(0x03);
+                buf.extend_from_slice(&stream_id.to_be_bytes());
+                buf.extend_from_slice(&seq.to_be_bytes());
+                buf.push(*flags);
+                buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+            Frame::Ack {
+                stream_id,
+                cum,
+                wnd,
+                sacks,
+            } => {
+                buf.push(0x04);
+                buf.extend_from_slice(&stream_id.to_be_bytes());
+                buf.extend_from_slice(&cum.to_be_bytes());
+                buf.extend_from_slice(&wnd.to_be_bytes());
+                buf.push(sacks.len() as u8);
+                for seq in sacks {
+                    buf.extend_from_slice(&seq.to_be_bytes());
+                }
+            }
+            Frame::Reset { stream_id } => {
+                buf.push(0x05);
+                buf.extend_from_slice(&stream_id.to_be_bytes());
+            }
+            Frame::Datagram { stream_id, data } => {
+                buf.push(0x06);
+                buf.extend_from_slice(&stream_id.to_be_bytes());
+                buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+                buf.extend_from_slice(data);
+            }
+        }
+    }
+
+    // 任何一个frame格式错误, 整个包都丢弃
+    pub fn decode_all(buf: &[u8]) -> Option<Vec<Frame>> {
+        let mut frames = Vec::new();
+        let mut reader = FrameReader { buf, pos: 0 };
+        while reader.pos < buf.len() {
+            let frame = match reader.u8()? {
+                0x01 => Frame::Ping,
+                0x02 => Frame::Pong,
+                0x03 => {
+                    let stream_id = reader.u32()?;
+                    let seq = reader.u32()?;
+                    let flags = reader.u8()?;
+                    let len = reader.u16()? as usize;
+                    Frame::Stream {
+                        stream_id,
+                        seq,
+                        flags,
+                        data: reader.bytes(len)?.to_vec(),
+                    }
+                }
+                0x04 => {
+                    let stream_id = reader.u32()?;
+                    let cum = reader.u32()?;
+                    let wnd = reader.u16()?;
+                    let count = reader.u8()? as usize;
+                    let mut sacks = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        sacks.push(reader.u32()?);
+                    }
+                    Frame::Ack {
+                        stream_id,
+                        cum,
+                        wnd,
+                        sacks,
+                    }
+                }
+                0x05 => Frame::Reset {
+                    stream_id: reader.u32()?,
+                },
+                0x06 => {
+                    let stream_id = reader.u32()?;
+                    let len = reader.u16()? as usize;
+                    Frame::Datagram {
+                        stream_id,
+                        data: reader.bytes(len)?.to_vec(),
+                    }
+                }
+                _ => return None,
+            };
+            frames.push(frame);
+        }
+        Some(frames)
+    }
+}
+
+struct FrameReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FrameReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.pos + len > self.buf.len() {
+            return None;
+        }
+        let ret = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Some(ret)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+    }
+}
+
+fn packet_nonce(pn: u64) -> Nonce {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[4..].copy_from_slice(&pn.to_be_bytes());
+    Nonce::assume_unique_for_key(nonce)
+}
+
+pub(crate) struct PacketCipher {
+    seal: LessSafeKey,
+    open: LessSafeKey,
+}
+
+impl PacketCipher {
+    pub fn new(send_key: &[u8; 32], recv_key: &[u8; 32]) -> Self {
+        let seal = UnboundKey::new(&CHACHA20_POLY1305, send_key).expect("chacha20 key len is 32");
+        let open = UnboundKey::new(&CHACHA20_POLY1305, recv_key).expect("chacha20 key len is 32");
+        Self {
+            seal: LessSafeKey::new(seal),
+            open: LessSafeKey::new(open),
+        }
+    }
+
+    pub fn seal(&self, conn_id: u32, pn: u64, mut frames: Vec<u8>) -> Vec<u8> {
+        let header = PacketHeader {
+            ptype: PacketType::Data,
+            conn_id,
+            pn,
+        }
+        .encode();
+        self.seal
+            .seal_in_place_append_tag(packet_nonce(pn), Aad::from(header), &mut frames)
+            .expect("rudp seal packet");
+        let mut packet = Vec::with_capacity(RUDP_PACKET_HEADER_LEN + frames.len());
+        packet.extend_from_slice(&header);
+        packet.extend_from_slice(&frames);
+        packet
+    }
+
+    pub fn open(&self, header: &PacketHeader, packet: &[u8]) -> Option<Vec<u8>> {
+        let aad: [u8; RUDP_PACKET_HEADER_LEN] =
+            packet[..RUDP_PACKET_HEADER_LEN].try_into().ok()?;
+        let mut payload = packet[RUDP_PACKET_HEADER_LEN..].to_vec();
+        let plain_len = self
+            .open
+            .open_in_place(packet_nonce(header.pn), Aad::from(aad), &mut payload)
+            .ok()?
+            .len();
+        payload.truncate(plain_len);
+        Some(payload)
+    }
+}
+
+// 防重放窗口, 只接受最近64个包序号内没见过的包
+#[derive(Default)]
+pub(crate) struct ReplayWindow {
+    max_pn: u64,
+    bitmap: u64,
+    inited: bool,
+}
+
+impl ReplayWindow {
+    pub fn check_and_update(&mut self, pn: u64) -> bool {
+        if !self.inited {
+            self.inited = true;
+            self.max_pn = pn;
+            self.bitmap = 1;
+            return true;
+        }
+        if pn > self.max_pn {
+            let shift = pn - self.max_pn;
+            self.bitmap = if shift >= 64 { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.max_pn = pn;
+            return true;
+        }
+        let offset = self.max_pn - pn;
+        if offset >= 64 || self.bitmap & (1 << offset) != 0 {
+            return false;
+        }
+        self.bitmap |= 1 << offset;
+        true
+    }
+}
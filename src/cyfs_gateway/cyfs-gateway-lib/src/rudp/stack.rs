@@ -0,0 +1,677 @@
+use super::connection::RUdpConnection;
+use super::packet::*;
+use super::stream::RUdpStream;
+use super::tunnel::RUdpTunnel;
+use crate::aead_stream::{derive_direction_keys, hkdf_derive_key};
+use crate::rtcp::{parse_rtcp_stack_id, RTcpDispatcherManager, RTcpStack, RTcpTargetStackEP};
+use crate::tunnel::{DatagramServerBox, StreamListener, TunnelBox, TunnelBuilder, TunnelEndpoint};
+use crate::{TunnelError, TunnelResult};
+use async_trait::async_trait;
+use log::*;
+use name_client::resolve_ip;
+use name_lib::DID;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Mutex as AsyncMutex;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use url::Url;
+
+pub const DEFAULT_RUDP_STACK_PORT: u16 = 2980;
+const RUDP_HELLO_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+const RUDP_HELLO_MAX_RETRY: u32 = 10;
+// 接受方回复hello_ack后, 对端需要在这个时间内发来第一个加密包
+const RUDP_HELLO_PROOF_TIMEOUT: Duration = Duration::from_secs(5);
+// 每个源ip在一个窗口内最多发起的握手数, 验证token需要做签名校验和ECDH
+const RUDP_HANDSHAKE_RATE_WINDOW: Duration = Duration::from_secs(10);
+const RUDP_MAX_HANDSHAKE_PER_SOURCE: u32 = 16;
+
+// 发起方等待hello_ack
+type HelloAckWaiter = oneshot::Sender<(SocketAddr, RUdpHelloAckBody)>;
+
+struct RUdpConnEntry {
+    conn: RUdpConnection,
+    // 接受方保存hello_ack, 对端重发hello时直接回复
+    hello_ack: Option<Vec<u8>>,
+}
+
+// rudp与rtcp共用设备身份和tunnel token, 只是底层换成udp
+#[derive(Clone)]
+pub struct RUdpStack {
+    rtcp_stack: RTcpStack,
+    dispatcher_manager: RTcpDispatcherManager,
+    port: u16,
+    socket: Option<Arc<UdpSocket>>,
+
+    conns: Arc<Mutex<HashMap<u32, RUdpConnEntry>>>,
+    // 正在验证token的hello, 避免对端重发hello时重复处理
+    handshaking: Arc<Mutex<HashSet<u32>>>,
+    handshake_rate: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+    pending_hello: Arc<Mutex<HashMap<u32, HelloAckWaiter>>>,
+    tunnels: Arc<AsyncMutex<HashMap<String, RUdpTunnel>>>,
+}
+
+impl RUdpStack {
+    pub fn new(rtcp_stack: RTcpStack, port: u16) -> RUdpStack {
+        RUdpStack {
+            rtcp_stack,
+            dispatcher_manager: RTcpDispatcherManager::new(),
+            port,
+            socket: None,
+            conns: Arc::new(Mutex::new(HashMap::new())),
+            handshaking: Arc::new(Mutex::new(HashSet::new())),
+            handshake_rate: Arc::new(Mutex::new(HashMap::new())),
+            pending_hello: Arc::new(Mutex::new(HashMap::new())),
+            tunnels: Arc::new(AsyncMutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn start(&mut self) -> TunnelResult<()> {
+        let bind_addr = format!("0.0.0.0:{}", self.port);
+        let socket = UdpSocket::bind(bind_addr.as_str()).await.map_err(|e| {
+            let msg = format!("bind rudp socket {} error:{}", bind_addr, e);
+            error!("{}", msg);
+            TunnelError::BindError(msg)
+        })?;
+        info!("RUdp stack bind on {}", bind_addr);
+        let socket = Arc::new(socket);
+        self.socket = Some(socket.clone());
+
+        let this = self.clone();
+        task::spawn(async move {
+            this.run_recv(socket).await;
+        });
+        Ok(())
+    }
+
+    async fn run_recv(&self, socket: Arc<UdpSocket>) {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(ret) => ret,
+                // windows上对端端口不可达时recv_from会报ConnectionReset, 忽略即可
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    error!("rudp socket recv error:{}", e);
+                    break;
+                }
+            };
+            let packet = &buf[..len];
+            let header = match PacketHeader::decode(packet) {
+                Some(header) => header,
+                None => continue,
+            };
+            match header.ptype {
+                PacketType::Hello => self.on_hello(&socket, from, header.conn_id, packet).await,
+                PacketType::HelloAck => {
+                    let ack = match decode_plain_body::<RUdpHelloAckBody>(packet) {
+                        Some(ack) => ack,
+                        None => continue,
+                    };
+                    let waiter = self.pending_hello.lock().unwrap().remove(&header.conn_id);
+                    if let Some(waiter) = waiter {
+                        let _ = waiter.send((from, ack));
+                    }
+                }
+                PacketType::Data => {
+                    let conn = self
+                        .conns
+                        .lock()
+                        .unwrap()
+                        .get(&header.conn_id)
+                        .map(|entry| entry.conn.clone());
+                    if let Some(conn) = conn {
+                        conn.on_packet(from, packet);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn on_hello(&self, socket: &Arc<UdpSocket>, from: SocketAddr, conn_id: u32, packet: &[u8]) {
+        let hello_ack = {
+            let conns = self.conns.lock().unwrap();
+            conns.get(&conn_id).map(|entry| entry.hello_ack.clone())
+        };
+        if let Some(hello_ack) = hello_ack {
+            // 已经建立的连接, 说明对端没收到hello_ack
+            if let Some(hello_ack) = hello_ack {
+                let _ = socket.send_to(&hello_ack, from).await;
+            }
+            return;
+        }
+
+        let hello_body = match decode_plain_body::<RUdpHelloBody>(packet) {
+            Some(body) => body,
+            None => {
+                warn!("invalid rudp hello from {}", from);
+                return;
+            }
+        };
+        {
+            let mut handshaking = self.handshaking.lock().unwrap();
+            if handshaking.contains(&conn_id) {
+                return;
+            }
+            if !self.check_handshake_rate(from.ip()) {
+                debug!("too many rudp handshakes from {}, drop hello", from);
+                return;
+            }
+            handshaking.insert(conn_id);
+        }
+
+        let this = self.clone();
+        let socket = socket.clone();
+        task::spawn(async move {
+            let from_id = hello_body.from_id.clone();
+            if let Err(e) = this.accept_hello(&socket, from, conn_id, hello_body).await {
+                error!("accept rudp hello from {} {} error:{}", from_id, from, e);
+                let ack_body = RUdpHelloAckBody {
+                    result: 1,
+                    nonce: None,
+                };
+                let ack = encode_plain_packet(PacketType::HelloAck, conn_id, &ack_body);
+                let _ = socket.send_to(&ack, from).await;
+            }
+            this.handshaking.lock().unwrap().remove(&conn_id);
+        });
+    }
+
+    fn check_handshake_rate(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut handshake_rate = self.handshake_rate.lock().unwrap();
+        if handshake_rate.len() > 1024 {
+            handshake_rate
+                .retain(|_, (start, _)| now.duration_since(*start) < RUDP_HANDSHAKE_RATE_WINDOW);
+        }
+        let (start, count) = handshake_rate.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= RUDP_HANDSHAKE_RATE_WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= RUDP_MAX_HANDSHAKE_PER_SOURCE {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    async fn accept_hello(
+        &self,
+        socket: &Arc<UdpSocket>,
+        from: SocketAddr,
+        conn_id: u32,
+        hello_body: RUdpHelloBody,
+    ) -> TunnelResult<()> {
+        let (aes_key, random_pk) = self
+            .rtcp_stack
+            .decode_hello_token(hello_body.tunnel_token, hello_body.from_id.clone())
+            .await?;
+        let from_did = DID::from_str(hello_body.from_id.as_str()).map_err(|e| {
+            TunnelError::DocumentError(format!("parser remote did error:{}", e))
+        })?;
+
+        let nonce: [u8; 32] = rand::random();
+        let (send_key, recv_key) = derive_conn_keys(&aes_key, &random_pk, &nonce, false);
+        let (conn, incoming_rx) =
+            RUdpConnection::new(conn_id, false, socket.clone(), from, &send_key, &recv_key);
+        let ack_body = RUdpHelloAckBody {
+            result: 0,
+            nonce: Some(hex::encode(nonce)),
+        };
+        let hello_ack = encode_plain_packet(PacketType::HelloAck, conn_id, &ack_body);
+        let confirmed = conn.wait_confirmed();
+        self.conns.lock().unwrap().insert(
+            conn_id,
+            RUdpConnEntry {
+                conn: conn.clone(),
+                hello_ack: Some(hello_ack.clone()),
+            },
+        );
+        let _ = socket.send_to(&hello_ack, from).await;
+
+        // token可能是重放的, 对端用派生出的key发来加密包之后才替换已有的tunnel
+        if !matches!(
+            tokio::time::timeout(RUDP_HELLO_PROOF_TIMEOUT, confirmed).await,
+            Ok(Ok(()))
+        ) {
+            warn!(
+                "rudp hello from {} {} not confirmed by an encrypted packet, drop it",
+                hello_body.from_id, from
+            );
+            self.conns.lock().unwrap().remove(&conn_id);
+            conn.close();
+            return Ok(());
+        }
+
+        let target = TunnelEndpoint {
+            device_id: from_did.to_string(),
+            port: hello_body.my_port,
+        };
+        let tunnel = RUdpTunnel::new(conn.clone(), target, self.dispatcher_manager.clone());
+        let tunnel_key = format!(
+            "{}_{}",
+            self.rtcp_stack.this_device_did().to_string(),
+            hello_body.from_id.as_str()
+        );
+        info!("RUdp tunnel {} accept from {} OK", tunnel_key, from);
+        self.tunnels
+            .lock()
+            .await
+            .insert(tunnel_key.clone(), tunnel.clone());
+        self.spawn_connection(tunnel_key, tunnel, conn, incoming_rx);
+        Ok(())
+    }
+
+    fn spawn_connection(
+        &self,
+        tunnel_key: String,
+        tunnel: RUdpTunnel,
+        conn: RUdpConnection,
+        incoming_rx: mpsc::UnboundedReceiver<(RUdpStream, RUdpOpenBody)>,
+    ) {
+        task::spawn(tunnel.clone().run_incoming(incoming_rx));
+
+        let this = self.clone();
+        task::spawn(async move {
+            conn.clone().run().await;
+            info!("RUdp tunnel {} end", tunnel_key);
+            this.conns.lock().unwrap().remove(&conn.conn_id());
+            let mut tunnels = this.tunnels.lock().await;
+            if let Some(current) = tunnels.get(&tunnel_key) {
+                if current.is_same(&tunnel) {
+                    tunnels.remove(&tunnel_key);
+                }
+            }
+        });
+    }
+
+    async fn connect(&self, target: &RTcpTargetStackEP) -> TunnelResult<RUdpTunnel> {
+        let socket = self.socket.clone().ok_or_else(|| {
+            TunnelError::InvalidState("rudp stack not started".to_string())
+        })?;
+        let target_id_str = target.did.to_string();
+        let device_ip = resolve_ip(target_id_str.as_str()).await.map_err(|e| {
+            let msg = format!("cann't resolve target device {} ip: {}", target_id_str, e);
+            warn!("{}", msg);
+            TunnelError::ConnectError(msg)
+        })?;
+        let remote_addr = SocketAddr::new(device_ip, target.stack_port);
+        self.connect_to(target, socket, remote_addr).await
+    }
+
+    async fn connect_to(
+        &self,
+        target: &RTcpTargetStackEP,
+        socket: Arc<UdpSocket>,
+        remote_addr: SocketAddr,
+    ) -> TunnelResult<RUdpTunnel> {
+        let target_id_str = target.did.to_string();
+        let (tunnel_token, aes_key, random_pk) = self
+            .rtcp_stack
+            .generate_tunnel_token(target_id_str.clone())
+            .await
+            .map_err(|e| {
+                let msg = format!("generate tunnel token error: {}, {}", target_id_str, e);
+                error!("{}", msg);
+                e
+            })?;
+
+        let (tx, mut rx) = oneshot::channel();
+        let conn_id = {
+            let conns = self.conns.lock().unwrap();
+            let mut pending_hello = self.pending_hello.lock().unwrap();
+            let conn_id = loop {
+                let conn_id: u32 = rand::random();
+                if conn_id != 0 && !conns.contains_key(&conn_id) && !pending_hello.contains_key(&conn_id) {
+                    break conn_id;
+                }
+            };
+            pending_hello.insert(conn_id, tx);
+            conn_id
+        };
+
+        let hello_body = RUdpHelloBody {
+            from_id: self.rtcp_stack.this_device_did().to_string(),
+            to_id: target_id_str.clone(),
+            my_port: self.port,
+            tunnel_token,
+        };
+        let hello = encode_plain_packet(PacketType::Hello, conn_id, &hello_body);
+        info!("Will open rudp tunnel to {}, target addr is {}", target_id_str, remote_addr);
+
+        // udp可能丢包, hello按固定间隔重发
+        let mut ack = None;
+        for _ in 0..RUDP_HELLO_MAX_RETRY {
+            if let Err(e) = socket.send_to(&hello, remote_addr).await {
+                warn!("send rudp hello to {} error:{}", remote_addr, e);
+            }
+            match tokio::time::timeout(RUDP_HELLO_RETRY_INTERVAL, &mut rx).await {
+                Ok(Ok(ret)) => {
+                    ack = Some(ret);
+                    break;
+                }
+                Ok(Err(_)) => break,
+                Err(_) => continue,
+            }
+        }
+        self.pending_hello.lock().unwrap().remove(&conn_id);
+
+        let (peer_addr, ack) = ack.ok_or_else(|| {
+            let msg = format!("Timeout: wait rudp hello_ack from {}", remote_addr);
+            warn!("{}", msg);
+            TunnelError::ConnectError(msg)
+        })?;
+        if ack.result != 0 {
+            let msg = format!("rudp hello to {} rejected, result:{}", remote_addr, ack.result);
+            warn!("{}", msg);
+            return Err(TunnelError::ConnectError(msg));
+        }
+        let nonce: [u8; 32] = ack
+            .nonce
+            .and_then(|nonce| hex::decode(nonce).ok())
+            .and_then(|nonce| nonce.try_into().ok())
+            .ok_or_else(|| {
+                let msg = format!("invalid rudp hello_ack nonce from {}", remote_addr);
+                error!("{}", msg);
+                TunnelError::ReasonError(msg)
+            })?;
+
+        let (send_key, recv_key) = derive_conn_keys(&aes_key, &random_pk, &nonce, true);
+        let (conn, incoming_rx) =
+            RUdpConnection::new(conn_id, true, socket, peer_addr, &send_key, &recv_key);
+        self.conns.lock().unwrap().insert(
+            conn_id,
+            RUdpConnEntry {
+                conn: conn.clone(),
+                hello_ack: None,
+            },
+        );
+        // 第一个加密包向接受方证明持有key, 之后run()会一直重发直到收到回复
+        conn.ping();
+
+        let endpoint = TunnelEndpoint {
+            device_id: target_id_str.clone(),
+            port: target.stack_port,
+        };
+        let tunnel = RUdpTunnel::new(conn.clone(), endpoint, self.dispatcher_manager.clone());
+        let tunnel_key = format!(
+            "{}_{}",
+            self.rtcp_stack.this_device_did().to_string(),
+            target_id_str
+        );
+        self.spawn_connection(tunnel_key, tunnel.clone(), conn, incoming_rx);
+        Ok(tunnel)
+    }
+}
+
+// 每个连接用hello_ack里的nonce重新派生key, 同一个tunnel token重放也得不到相同的key
+fn derive_conn_keys(
+    aes_key: &[u8; 32],
+    random_pk: &[u8; 32],
+    nonce: &[u8; 32],
+    is_initiator: bool,
+) -> ([u8; 32], [u8; 32]) {
+    let mut salt = random_pk.to_vec();
+    salt.extend_from_slice(nonce);
+    let session_key = hkdf_derive_key(aes_key, &salt, b"rudp tunnel session");
+    derive_direction_keys(&session_key, b"rudp packet", is_initiator)
+}
+
+#[async_trait]
+impl TunnelBuilder for RUdpStack {
+    async fn create_tunnel(
+        &self,
+        tunnel_stack_id: Option<&str>,
+    ) -> TunnelResult<Box<dyn TunnelBox>> {
+        let tunnel_stack_id = tunnel_stack_id.ok_or_else(|| {
+            TunnelError::ReasonError("rudp target stack id is none".to_string())
+        })?;
+        let target = parse_rtcp_stack_id(tunnel_stack_id).ok_or_else(|| {
+            TunnelError::ConnectError(format!("invalid target url:{}", tunnel_stack_id))
+        })?;
+        let tunnel_key = format!(
+            "{}_{}",
+            self.rtcp_stack.this_device_did().to_string(),
+            target.did.to_string()
+        );
+
+        let mut tunnels = self.tunnels.lock().await;
+        if let Some(tunnel) = tunnels.get(&tunnel_key) {
+            if !tunnel.is_closed() {
+                debug!("Reuse rudp tunnel {}", tunnel_key);
+                return Ok(Box::new(tunnel.clone()));
+            }
+            tunnels.remove(&tunnel_key);
+        }
+
+        let tunnel = self.connect(&target).await?;
+        tunnels.insert(tunnel_key.clone(), tunnel.clone());
+        info!("create rudp tunnel {} ok", tunnel_key);
+        Ok(Box::new(tunnel))
+    }
+
+    async fn create_stream_listener(
+        &self,
+        bind_url: &Url,
+    ) -> TunnelResult<Box<dyn StreamListener>> {
+        let dispatcher = self.dispatcher_manager.new_stream_dispatcher(bind_url)?;
+        Ok(Box::new(dispatcher) as Box<dyn StreamListener>)
+    }
+
+    async fn create_datagram_server(
+        &self,
+        bind_url: &Url,
+    ) -> TunnelResult<Box<dyn DatagramServerBox>> {
+        let dispatcher = self.dispatcher_manager.new_datagram_dispatcher(bind_url)?;
+        Ok(Box::new(dispatcher) as Box<dyn DatagramServerBox>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::Tunnel;
+    use name_lib::{encode_ed25519_sk_to_pk_jwk, generate_ed25519_key};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn new_test_rtcp_stack() -> RTcpStack {
+        let (sk, sk_pkcs) = generate_ed25519_key();
+        let jwk = encode_ed25519_sk_to_pk_jwk(&sk);
+        let x = jwk.get("x").unwrap().as_str().unwrap();
+        RTcpStack::new(DID::new("dev", x), 0, Some(sk_pkcs))
+    }
+
+    async fn start_test_stack() -> (RUdpStack, SocketAddr) {
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut stack = RUdpStack::new(new_test_rtcp_stack(), port);
+        stack.start().await.unwrap();
+        (stack, format!("127.0.0.1:{}", port).parse().unwrap())
+    }
+
+    fn target_of(stack: &RUdpStack) -> RTcpTargetStackEP {
+        RTcpTargetStackEP {
+            did: stack.rtcp_stack.this_device_did().clone(),
+            stack_port: stack.port,
+        }
+    }
+
+    fn tunnel_key(from: &RUdpStack, to: &RUdpStack) -> String {
+        format!(
+            "{}_{}",
+            from.rtcp_stack.this_device_did().to_string(),
+            to.rtcp_stack.this_device_did().to_string()
+        )
+    }
+
+    async fn start_echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        task::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                task::spawn(async move {
+                    let mut buf = [0u8; 5];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                });
+            }
+        });
+        port
+    }
+
+    async fn check_echo(tunnel: &dyn Tunnel, path: &str) {
+        let mut stream = tunnel.open_stream(path).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    async fn wait_accepted(stack: &RUdpStack, key: &str) -> RUdpTunnel {
+        for _ in 0..50 {
+            if let Some(tunnel) = stack.tunnels.lock().await.get(key) {
+                return tunnel.clone();
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("rudp tunnel {} not accepted", key);
+    }
+
+    // 用socket直接发hello, 返回收到的hello_ack
+    async fn send_hello(
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        conn_id: u32,
+        body: &RUdpHelloBody,
+    ) -> Option<RUdpHelloAckBody> {
+        let hello = encode_plain_packet(PacketType::Hello, conn_id, body);
+        socket.send_to(&hello, addr).await.unwrap();
+        let mut buf = vec![0u8; 2048];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        decode_plain_body(&buf[..len])
+    }
+
+    #[tokio::test]
+    async fn test_rudp_handshake() {
+        let (stack_a, _) = start_test_stack().await;
+        let (stack_b, addr_b) = start_test_stack().await;
+        let echo_port = start_echo_server().await;
+
+        let socket = stack_a.socket.clone().unwrap();
+        let tunnel = stack_a
+            .connect_to(&target_of(&stack_b), socket, addr_b)
+            .await
+            .unwrap();
+        let path = format!("/127.0.0.1:{}", echo_port);
+        check_echo(&tunnel, path.as_str()).await;
+
+        // 接受方在收到加密包之后才登记tunnel
+        let accepted = wait_accepted(&stack_b, tunnel_key(&stack_b, &stack_a).as_str()).await;
+        assert!(!accepted.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_rudp_url() {
+        let (stack_a, _) = start_test_stack().await;
+        let (stack_b, addr_b) = start_test_stack().await;
+        let echo_port = start_echo_server().await;
+
+        let socket = stack_a.socket.clone().unwrap();
+        let tunnel = stack_a
+            .connect_to(&target_of(&stack_b), socket, addr_b)
+            .await
+            .unwrap();
+        stack_a
+            .tunnels
+            .lock()
+            .await
+            .insert(tunnel_key(&stack_a, &stack_b), tunnel.clone());
+
+        // rudp://$device_host/$target, 设备名可以写成did的host形式
+        let did_b = stack_b.rtcp_stack.this_device_did().clone();
+        let url = Url::parse(
+            format!("rudp://{}/127.0.0.1:{}", did_b.to_host_name(), echo_port).as_str(),
+        )
+        .unwrap();
+        let reused = stack_a.create_tunnel(Some(url.authority())).await.unwrap();
+        check_echo(reused.as_ref(), url.path()).await;
+
+        assert!(stack_a.create_tunnel(None).await.is_err());
+        assert!(stack_a.create_tunnel(Some("did:")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rudp_unproven_hello() {
+        let (stack_a, _) = start_test_stack().await;
+        let (stack_b, addr_b) = start_test_stack().await;
+        let echo_port = start_echo_server().await;
+        let path = format!("/127.0.0.1:{}", echo_port);
+
+        let socket = stack_a.socket.clone().unwrap();
+        let tunnel = stack_a
+            .connect_to(&target_of(&stack_b), socket, addr_b)
+            .await
+            .unwrap();
+        check_echo(&tunnel, path.as_str()).await;
+        let key = tunnel_key(&stack_b, &stack_a);
+        let accepted = wait_accepted(&stack_b, key.as_str()).await;
+
+        // 拿到合法token但没有key的一方: hello能通过token校验, 但发不出加密包
+        let (tunnel_token, _, _) = stack_a
+            .rtcp_stack
+            .generate_tunnel_token(stack_b.rtcp_stack.this_device_did().to_string())
+            .await
+            .unwrap();
+        let hello_body = RUdpHelloBody {
+            from_id: stack_a.rtcp_stack.this_device_did().to_string(),
+            to_id: stack_b.rtcp_stack.this_device_did().to_string(),
+            my_port: 0,
+            tunnel_token,
+        };
+        let attacker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let conn_id = 0x5a5a5a5a;
+        let ack = send_hello(&attacker, addr_b, conn_id, &hello_body).await.unwrap();
+        assert_eq!(ack.result, 0);
+
+        // 握手没有完成, 已有的tunnel不能被替换
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let current = stack_b.tunnels.lock().await.get(&key).cloned().unwrap();
+        assert!(current.is_same(&accepted));
+        tokio::time::sleep(RUDP_HELLO_PROOF_TIMEOUT + Duration::from_secs(1)).await;
+        assert!(!stack_b.conns.lock().unwrap().contains_key(&conn_id));
+        let current = stack_b.tunnels.lock().await.get(&key).cloned().unwrap();
+        assert!(current.is_same(&accepted));
+        check_echo(&tunnel, path.as_str()).await;
+    }
+
+    #[tokio::test]
+    async fn test_rudp_handshake_rate_limit() {
+        let (stack_b, addr_b) = start_test_stack().await;
+        let hello_body = RUdpHelloBody {
+            from_id: new_test_rtcp_stack().this_device_did().to_string(),
+            to_id: stack_b.rtcp_stack.this_device_did().to_string(),
+            my_port: 0,
+            tunnel_token: "invalid token".to_string(),
+        };
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        for conn_id in 1..=RUDP_MAX_HANDSHAKE_PER_SOURCE {
+            let ack = send_hello(&socket, addr_b, conn_id, &hello_body).await.unwrap();
+            assert_ne!(ack.result, 0);
+        }
+        // 超过限制之后的新握手直接丢弃, 不再回复
+        let conn_id = RUDP_MAX_HANDSHAKE_PER_SOURCE + 1;
+        assert!(send_hello(&socket, addr_b, conn_id, &hello_body).await.is_none());
+    }
+}
@@ -0,0 +1,113 @@
+use super::connection::RUdpConnection;
+use crate::tunnel::DatagramClient;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, Mutex};
+
+pub(crate) struct RUdpStream {
+    conn: RUdpConnection,
+    stream_id: u32,
+}
+
+impl RUdpStream {
+    pub(super) fn new(conn: RUdpConnection, stream_id: u32) -> Self {
+        Self { conn, stream_id }
+    }
+
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    // 接受方处理完open后回复结果, 0表示成功
+    pub fn send_open_resp(&self, result: u32) {
+        self.conn.send_open_resp(self.stream_id, result);
+    }
+}
+
+impl Drop for RUdpStream {
+    fn drop(&mut self) {
+        self.conn.drop_stream(self.stream_id);
+    }
+}
+
+impl AsyncRead for RUdpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.conn.poll_stream_read(self.stream_id, cx, buf)
+    }
+}
+
+impl AsyncWrite for RUdpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.conn.poll_stream_write(self.stream_id, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.conn.shutdown_stream(self.stream_id);
+        Poll::Ready(Ok(()))
+    }
+}
+
+// purpose为datagram的stream只用来建立和关闭会话, 数据走不重传的datagram frame
+#[derive(Clone)]
+pub(crate) struct RUdpDatagramClient {
+    stream: Arc<RUdpStream>,
+    rx: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
+}
+
+impl RUdpDatagramClient {
+    pub fn new(stream: RUdpStream) -> Result<Self, std::io::Error> {
+        let rx = stream
+            .conn
+            .take_datagram_receiver(stream.stream_id)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("rudp stream {} is not datagram session", stream.stream_id),
+                )
+            })?;
+        Ok(Self {
+            stream: Arc::new(stream),
+            rx: Arc::new(Mutex::new(rx)),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl DatagramClient for RUdpDatagramClient {
+    async fn recv_datagram(&self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        let datagram = self.rx.lock().await.recv().await.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "rudp datagram session closed",
+            )
+        })?;
+        if buffer.len() < datagram.len() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "buffer size not enough",
+            ));
+        }
+        buffer[..datagram.len()].copy_from_slice(&datagram);
+        Ok(datagram.len())
+    }
+
+    async fn send_datagram(&self, buffer: &[u8]) -> Result<usize, std::io::Error> {
+        self.stream
+            .conn
+            .send_datagram(self.stream.stream_id, buffer)
+    }
+}
@@ -0,0 +1,218 @@
+use super::connection::RUdpConnection;
+use super::packet::RUdpOpenBody;
+use super::stream::{RUdpDatagramClient, RUdpStream};
+use crate::rtcp::{DatagramForwarder, RTcpDispatcherManager, StreamPurpose};
+use crate::tunnel::{get_dest_info_from_url_path, DatagramClientBox, Tunnel, TunnelEndpoint};
+use async_trait::async_trait;
+use buckyos_kit::AsyncStream;
+use log::*;
+use tokio::sync::mpsc;
+use tokio::task;
+
+const RUDP_OPEN_RESULT_OK: u32 = 0;
+const RUDP_OPEN_RESULT_FAILED: u32 = 2;
+
+#[derive(Clone)]
+pub(crate) struct RUdpTunnel {
+    conn: RUdpConnection,
+    target: TunnelEndpoint,
+    dispatcher_manager: RTcpDispatcherManager,
+}
+
+impl RUdpTunnel {
+    pub fn new(
+        conn: RUdpConnection,
+        target: TunnelEndpoint,
+        dispatcher_manager: RTcpDispatcherManager,
+    ) -> Self {
+        Self {
+            conn,
+            target,
+            dispatcher_manager,
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.conn.is_closed()
+    }
+
+    pub fn is_same(&self, other: &RUdpTunnel) -> bool {
+        self.conn.is_same(&other.conn)
+    }
+
+    pub fn close(&self) {
+        self.conn.close();
+    }
+
+    // 处理对端打开的stream, 直到连接关闭
+    pub async fn run_incoming(self, mut incoming_rx: mpsc::UnboundedReceiver<(RUdpStream, RUdpOpenBody)>) {
+        while let Some((stream, open_body)) = incoming_rx.recv().await {
+            info!(
+                "RUdp tunnel open request: {}, {:?}:{}, {:?}",
+                stream.stream_id(),
+                open_body.dest_host,
+                open_body.dest_port,
+                open_body.purpose
+            );
+            // 连接目标可能比较慢, 不能阻塞其他stream的open
+            let this = self.clone();
+            task::spawn(async move {
+                let purpose = open_body.purpose.clone().unwrap_or_default();
+                let result = match purpose {
+                    StreamPurpose::Stream => {
+                        this.on_stream_open(open_body.dest_host, open_body.dest_port, stream)
+                            .await
+                    }
+                    StreamPurpose::Datagram => {
+                        this.on_datagram_open(open_body.dest_host, open_body.dest_port, stream)
+                            .await
+                    }
+                };
+                if let Err(e) = result {
+                    error!("RUdp tunnel process open stream error: {}", e);
+                }
+            });
+        }
+    }
+
+    // 先连上目标再回复open结果, 这样发起方能拿到连接失败的错误
+    async fn on_stream_open(
+        &self,
+        dest_host: Option<String>,
+        dest_port: u16,
+        mut stream: RUdpStream,
+    ) -> Result<(), anyhow::Error> {
+        let request_target_addr = match dest_host {
+            Some(ref host) => format!("{}:{}", host, dest_port),
+            None => format!("127.0.0.1:{}", dest_port),
+        };
+
+        if let Some(dispatcher) = self.dispatcher_manager.get_stream_dispatcher(dest_port) {
+            stream.send_open_resp(RUDP_OPEN_RESULT_OK);
+            dispatcher
+                .on_new_stream(Box::new(stream), self.target.clone())
+                .await?;
+            return Ok(());
+        }
+
+        let mut raw_stream_to_target =
+            match tokio::net::TcpStream::connect(request_target_addr.as_str()).await {
+                Ok(raw_stream) => raw_stream,
+                Err(e) => {
+                    error!(
+                        "open tcp stream to target {} error:{}",
+                        request_target_addr, e
+                    );
+                    stream.send_open_resp(RUDP_OPEN_RESULT_FAILED);
+                    return Ok(());
+                }
+            };
+        stream.send_open_resp(RUDP_OPEN_RESULT_OK);
+
+        info!(
+            "Start copy rudp stream to raw_tcp_stream, {} -> {}",
+            self.conn.peer_addr(),
+            request_target_addr
+        );
+        match tokio::io::copy_bidirectional(&mut stream, &mut raw_stream_to_target).await {
+            Ok(copy_len) => {
+                info!("copy rudp stream to raw_tcp_stream ok,len:{:?}", copy_len);
+            }
+            Err(e) => {
+                error!("copy rudp stream to raw_tcp_stream error:{}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn on_datagram_open(
+        &self,
+        dest_host: Option<String>,
+        dest_port: u16,
+        stream: RUdpStream,
+    ) -> Result<(), anyhow::Error> {
+        let bind_addr;
+        let request_target_addr = match dest_host {
+            Some(ref host) => {
+                bind_addr = "0.0.0.0:0";
+                format!("{}:{}", host, dest_port)
+            }
+            None => {
+                bind_addr = "127.0.0.1:0";
+                format!("127.0.0.1:{}", dest_port)
+            }
+        };
+
+        info!(
+            "RUdp tunnel open request target datagram to {}",
+            request_target_addr
+        );
+
+        stream.send_open_resp(RUDP_OPEN_RESULT_OK);
+        let client = RUdpDatagramClient::new(stream)?;
+        if let Some(dispatcher) = self.dispatcher_manager.get_datagram_dispatcher(dest_port) {
+            dispatcher
+                .on_new_datagram_client(Box::new(client), self.target.clone())
+                .await?;
+            return Ok(());
+        }
+
+        let forwarder = DatagramForwarder::with_client(
+            request_target_addr.as_str(),
+            bind_addr,
+            Box::new(client),
+        )
+        .await?;
+        forwarder.start();
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Tunnel for RUdpTunnel {
+    async fn ping(&self) -> Result<(), std::io::Error> {
+        self.conn.ping();
+        Ok(())
+    }
+
+    async fn open_stream_by_dest(
+        &self,
+        dest_port: u16,
+        dest_host: Option<String>,
+    ) -> Result<Box<dyn AsyncStream>, std::io::Error> {
+        let stream = self
+            .conn
+            .open_stream(Some(StreamPurpose::Stream), dest_port, dest_host)
+            .await?;
+        Ok(Box::new(stream))
+    }
+
+    async fn open_stream(&self, stream_id: &str) -> Result<Box<dyn AsyncStream>, std::io::Error> {
+        let (dest_host, dest_port) = get_dest_info_from_url_path(stream_id)?;
+        self.open_stream_by_dest(dest_port, dest_host).await
+    }
+
+    async fn create_datagram_client_by_dest(
+        &self,
+        dest_port: u16,
+        dest_host: Option<String>,
+    ) -> Result<Box<dyn DatagramClientBox>, std::io::Error> {
+        let stream = self
+            .conn
+            .open_stream(Some(StreamPurpose::Datagram), dest_port, dest_host)
+            .await?;
+        let client = RUdpDatagramClient::new(stream)?;
+        Ok(Box::new(client) as Box<dyn DatagramClientBox>)
+    }
+
+    async fn create_datagram_client(
+        &self,
+        session_id: &str,
+    ) -> Result<Box<dyn DatagramClientBox>, std::io::Error> {
+        let (dest_host, dest_port) = get_dest_info_from_url_path(session_id)?;
+        self.create_datagram_client_by_dest(dest_port, dest_host)
+            .await
+    }
+}
@@ -1,6 +1,12 @@
 use std::net::SocketAddr;
 use async_trait::async_trait;
-use crate::TunnelResult;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::{StreamSelectorConfig, TunnelError, TunnelResult};
+
+//内置probe的id,可以在dispatcher的probe_id中直接使用
+pub const HTTPS_SNI_PROBE_ID: &str = "https_sni";
+pub const HTTP_HOST_PROBE_ID: &str = "http_host";
+pub const PROTOCOL_PROBE_ID: &str = "protocol";
 
 #[derive(Clone)]
 pub struct StreamRequest {
@@ -46,7 +52,7 @@ pub trait DatagramProbe {
 }
 
 #[async_trait]
-pub trait StreamSelector {
+pub trait StreamSelector: Send + Sync {
     //return stream_url
     async fn select(&self, request: StreamRequest) -> TunnelResult<String>;
 }
@@ -54,64 +60,87 @@ pub trait StreamSelector {
 pub struct HttpsSniProbe;
 
 impl HttpsSniProbe {
-    // 解析TLS Client Hello中的SNI
+    // 解析TLS Client Hello中的SNI,buffer可能只是ClientHello的一部分,所有下标都要检查buffer长度
     fn extract_sni(buffer: &[u8]) -> Option<String> {
         // 检查是否是TLS握手消息
         if buffer.len() < 5 || buffer[0] != 0x16 || buffer[1] != 0x03 {
             return None;
         }
+        let read_u16 = |pos: usize| -> Option<usize> {
+            if pos + 2 <= buffer.len() {
+                Some(((buffer[pos] as usize) << 8) | (buffer[pos + 1] as usize))
+            } else {
+                None
+            }
+        };
 
         let mut pos = 43; // 跳过TLS记录头和Client Hello固定部分
-        if buffer.len() <= pos {
-            return None;
-        }
 
         // 跳过Session ID
-        if pos < buffer.len() {
-            let session_id_len = buffer[pos] as usize;
-            pos += 1 + session_id_len;
-        }
+        let session_id_len = *buffer.get(pos)? as usize;
+        pos += 1 + session_id_len;
 
         // 跳过Cipher Suites
-        if pos + 2 <= buffer.len() {
-            let cipher_len = ((buffer[pos] as usize) << 8) | (buffer[pos + 1] as usize);
-            pos += 2 + cipher_len;
-        }
+        let cipher_len = read_u16(pos)?;
+        pos += 2 + cipher_len;
 
         // 跳过Compression Methods
-        if pos + 1 <= buffer.len() {
-            let comp_len = buffer[pos] as usize;
-            pos += 1 + comp_len;
-        }
+        let comp_len = *buffer.get(pos)? as usize;
+        pos += 1 + comp_len;
 
         // 解析扩展
-        if pos + 2 <= buffer.len() {
-            let extensions_len = ((buffer[pos] as usize) << 8) | (buffer[pos + 1] as usize);
-            pos += 2;
-            let extensions_end = pos + extensions_len;
-
-            while pos + 4 <= extensions_end {
-                let ext_type = ((buffer[pos] as u16) << 8) | (buffer[pos + 1] as u16);
-                let ext_len = ((buffer[pos + 2] as usize) << 8) | (buffer[pos + 3] as usize);
-                pos += 4;
-
-                // SNI 扩展类型为 0
-                if ext_type == 0 && pos + ext_len <= buffer.len() {
-                    // 解析SNI内容
-                    if ext_len > 5 {
-                        let sni_len = ((buffer[pos + 3] as usize) << 8) | (buffer[pos + 4] as usize);
-                        if pos + 5 + sni_len <= buffer.len() {
-                            return String::from_utf8(buffer[pos+5..pos+5+sni_len].to_vec()).ok();
-                        }
-                    }
+        let extensions_len = read_u16(pos)?;
+        pos += 2;
+        let extensions_end = (pos + extensions_len).min(buffer.len());
+        while pos + 4 <= extensions_end {
+            let ext_type = read_u16(pos)?;
+            let ext_len = read_u16(pos + 2)?;
+            pos += 4;
+            if pos + ext_len > extensions_end {
+                return None;
+            }
+
+            // SNI 扩展类型为 0
+            if ext_type == 0 && ext_len > 5 {
+                // 解析SNI内容
+                let sni_len = read_u16(pos + 3)?;
+                if 5 + sni_len <= ext_len {
+                    return String::from_utf8(buffer[pos + 5..pos + 5 + sni_len].to_vec()).ok();
                 }
-                pos += ext_len;
             }
+            pos += ext_len;
         }
         None
     }
 }
 
+// 读取用于probe的首包.TLS ClientHello经常超过一个read(比如带有较大的key share),
+// 按记录头里的长度读完整个TLS记录,或者读满buffer为止
+pub async fn read_probe_buffer<S>(stream: &mut S, buffer: &mut [u8]) -> std::io::Result<usize>
+where
+    S: AsyncRead + Unpin + ?Sized,
+{
+    let mut read_len = stream.read(buffer).await?;
+    if read_len == 0 || buffer[0] != 0x16 {
+        return Ok(read_len);
+    }
+
+    while read_len < buffer.len() {
+        if read_len >= 5 {
+            let record_len = ((buffer[3] as usize) << 8) | (buffer[4] as usize);
+            if read_len >= 5 + record_len {
+                break;
+            }
+        }
+        let n = stream.read(&mut buffer[read_len..]).await?;
+        if n == 0 {
+            break;
+        }
+        read_len += n;
+    }
+    Ok(read_len)
+}
+
 impl StreamProbe for HttpsSniProbe {
     fn probe(&self, buffer: &[u8], request: &StreamRequest) -> TunnelResult<StreamRequest> {
         let mut new_request:StreamRequest = request.clone();
@@ -125,4 +154,295 @@ impl StreamProbe for HttpsSniProbe {
     }
 }
 
+pub struct HttpHostProbe;
+
+impl HttpHostProbe {
+    const METHODS: [&'static str; 9] = [
+        "GET ", "POST ", "PUT ", "DELETE ", "HEAD ", "OPTIONS ", "PATCH ", "CONNECT ", "TRACE ",
+    ];
+
+    pub fn is_http_request(buffer: &[u8]) -> bool {
+        Self::METHODS.iter().any(|method| buffer.starts_with(method.as_bytes()))
+    }
+
+    // 解析HTTP/1.x请求头中的Host,去掉端口
+    fn extract_host(buffer: &[u8]) -> Option<String> {
+        if !Self::is_http_request(buffer) {
+            return None;
+        }
+
+        let header = String::from_utf8_lossy(buffer);
+        for line in header.split("\r\n").skip(1) {
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = match line.split_once(':') {
+                Some(kv) => kv,
+                None => continue,
+            };
+            if !name.trim().eq_ignore_ascii_case("host") {
+                continue;
+            }
+
+            let value = value.trim();
+            let host = if value.starts_with('[') {
+                // ipv6: [::1]:80
+                value.split_once(']').map(|(host, _)| &host[1..]).unwrap_or(value)
+            } else {
+                value.split(':').next().unwrap_or(value)
+            };
+            if host.is_empty() {
+                return None;
+            }
+            return Some(host.to_lowercase());
+        }
+        None
+    }
+}
+
+impl StreamProbe for HttpHostProbe {
+    fn probe(&self, buffer: &[u8], request: &StreamRequest) -> TunnelResult<StreamRequest> {
+        let mut new_request = request.clone();
+
+        if let Some(hostname) = Self::extract_host(buffer) {
+            new_request.dest_host = Some(hostname);
+            new_request.app_protocol = Some("http".to_string());
+        }
+
+        Ok(new_request)
+    }
+}
+
+// 根据第一个包识别协议:tls(https)/http/ssh/socks,tls和http会同时解析出dest_host
+pub struct ProtocolProbe;
+
+impl StreamProbe for ProtocolProbe {
+    fn probe(&self, buffer: &[u8], request: &StreamRequest) -> TunnelResult<StreamRequest> {
+        if buffer.len() >= 3 && buffer[0] == 0x16 && buffer[1] == 0x03 {
+            let mut new_request = HttpsSniProbe.probe(buffer, request)?;
+            new_request.app_protocol = Some("https".to_string());
+            return Ok(new_request);
+        }
+
+        if HttpHostProbe::is_http_request(buffer) {
+            let mut new_request = HttpHostProbe.probe(buffer, request)?;
+            new_request.app_protocol = Some("http".to_string());
+            return Ok(new_request);
+        }
+
+        let mut new_request = request.clone();
+        if buffer.starts_with(b"SSH-") {
+            new_request.app_protocol = Some("ssh".to_string());
+        } else if buffer.len() >= 2
+            && buffer[0] == 0x05
+            && buffer.len() == 2 + buffer[1] as usize
+        {
+            // socks5 greeting: ver | nmethods | methods
+            new_request.app_protocol = Some("socks".to_string());
+        } else if buffer.len() >= 9 && buffer[0] == 0x04 && (buffer[1] == 0x01 || buffer[1] == 0x02) {
+            // socks4 request: ver | cmd | port | ip | userid | 0
+            new_request.app_protocol = Some("socks".to_string());
+        }
+
+        Ok(new_request)
+    }
+}
+
+// 按配置的规则选择目标stream url,规则按顺序匹配,都不匹配时使用default
+pub struct RuleStreamSelector {
+    config: StreamSelectorConfig,
+}
+
+impl RuleStreamSelector {
+    pub fn new(config: StreamSelectorConfig) -> Self {
+        Self { config }
+    }
+
+    // "example.com" 完全匹配,"*.example.com" 匹配所有子域名,"*" 匹配所有
+    fn match_host(pattern: &str, host: &str) -> bool {
+        let host = host.trim_end_matches('.');
+        if pattern == "*" {
+            return true;
+        }
+        if let Some(suffix) = pattern.strip_prefix("*.") {
+            let suffix = suffix.to_lowercase();
+            let host = host.to_lowercase();
+            return host.len() > suffix.len() + 1
+                && host.ends_with(&suffix)
+                && host.as_bytes()[host.len() - suffix.len() - 1] == b'.';
+        }
+        pattern.eq_ignore_ascii_case(host)
+    }
+
+    pub fn select_url(&self, request: &StreamRequest) -> Option<String> {
+        for rule in self.config.rules.iter() {
+            if let Some(pattern) = rule.host.as_ref() {
+                match request.dest_host.as_ref() {
+                    Some(host) if Self::match_host(pattern, host) => {}
+                    _ => continue,
+                }
+            }
+            if let Some(protocol) = rule.protocol.as_ref() {
+                match request.app_protocol.as_ref() {
+                    Some(app_protocol) if app_protocol.eq_ignore_ascii_case(protocol) => {}
+                    _ => continue,
+                }
+            }
+            return Some(rule.target.clone());
+        }
+
+        self.config.default.clone()
+    }
+}
+
+#[async_trait]
+impl StreamSelector for RuleStreamSelector {
+    async fn select(&self, request: StreamRequest) -> TunnelResult<String> {
+        self.select_url(&request).ok_or_else(|| {
+            let msg = format!(
+                "No stream selector rule matched, host: {:?}, protocol: {:?}",
+                request.dest_host, request.app_protocol
+            );
+            warn!("{}", msg);
+            TunnelError::ReasonError(msg)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_probe() {
+        let request = StreamRequest::new();
+        let probe = ProtocolProbe;
+
+        let http = b"GET /index.html HTTP/1.1\r\nUser-Agent: test\r\nHost: WWW.Example.com:8080\r\n\r\n";
+        let ret = probe.probe(http, &request).unwrap();
+        assert_eq!(ret.app_protocol.as_deref(), Some("http"));
+        assert_eq!(ret.dest_host.as_deref(), Some("www.example.com"));
 
+        let ret = probe.probe(b"SSH-2.0-OpenSSH_9.6\r\n", &request).unwrap();
+        assert_eq!(ret.app_protocol.as_deref(), Some("ssh"));
+
+        let ret = probe.probe(&[0x05, 0x02, 0x00, 0x02], &request).unwrap();
+        assert_eq!(ret.app_protocol.as_deref(), Some("socks"));
+
+        let ret = probe.probe(b"\x00\x01unknown", &request).unwrap();
+        assert!(ret.app_protocol.is_none());
+        assert!(ret.dest_host.is_none());
+    }
+
+    fn build_client_hello(sni: &str) -> Vec<u8> {
+        let mut sni_ext = vec![];
+        sni_ext.extend_from_slice(&((sni.len() + 3) as u16).to_be_bytes());
+        sni_ext.push(0);
+        sni_ext.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        sni_ext.extend_from_slice(sni.as_bytes());
+
+        let mut extensions = vec![];
+        // 放在SNI前面的大扩展,模拟key share
+        extensions.extend_from_slice(&[0x00, 0x33]);
+        extensions.extend_from_slice(&1200u16.to_be_bytes());
+        extensions.extend_from_slice(&[0u8; 1200]);
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&sni_ext);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.push(0);
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01, 0x00];
+        handshake.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_https_sni_probe() {
+        let request = StreamRequest::new();
+        let hello = build_client_hello("www.example.com");
+        let ret = HttpsSniProbe.probe(&hello, &request).unwrap();
+        assert_eq!(ret.dest_host.as_deref(), Some("www.example.com"));
+
+        // 被截断的ClientHello不能panic,也不能解析出错误的host
+        for len in 0..hello.len() {
+            let ret = HttpsSniProbe.probe(&hello[..len], &request).unwrap();
+            assert!(ret.dest_host.is_none());
+        }
+
+        // 扩展长度比实际数据大
+        let mut bad_hello = hello.clone();
+        let ext_len_pos = 5 + 4 + 2 + 32 + 1 + 4 + 2;
+        bad_hello[ext_len_pos] = 0xff;
+        bad_hello[ext_len_pos + 1] = 0xff;
+        HttpsSniProbe.probe(&bad_hello, &request).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_probe_buffer() {
+        let hello = build_client_hello("www.example.com");
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let data = hello.clone();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            for piece in data.chunks(100) {
+                client.write_all(piece).await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            client.write_all(b"app data").await.unwrap();
+        });
+
+        let mut buffer = [0u8; 4096];
+        let read_len = read_probe_buffer(&mut server, &mut buffer).await.unwrap();
+        assert!(read_len >= hello.len());
+        assert_eq!(&buffer[..hello.len()], hello.as_slice());
+        let ret = HttpsSniProbe.probe(&buffer[..read_len], &StreamRequest::new()).unwrap();
+        assert_eq!(ret.dest_host.as_deref(), Some("www.example.com"));
+
+        // 非TLS只读一次
+        let (mut client, mut server) = tokio::io::duplex(64);
+        tokio::io::AsyncWriteExt::write_all(&mut client, b"GET / HTTP/1.1\r\n").await.unwrap();
+        let read_len = read_probe_buffer(&mut server, &mut buffer).await.unwrap();
+        assert_eq!(&buffer[..read_len], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_rule_selector() {
+        let config: StreamSelectorConfig = serde_json::from_value(serde_json::json!({
+            "rules": [
+                {"host": "*.app.example.com", "target": "tcp:///127.0.0.1:8443"},
+                {"host": "example.com", "protocol": "https", "target": "rtcp://dev01/127.0.0.1:443"},
+                {"protocol": "ssh", "target": "tcp:///127.0.0.1:22"}
+            ],
+            "default": "tcp:///127.0.0.1:443"
+        }))
+        .unwrap();
+        let selector = RuleStreamSelector::new(config);
+
+        let mut request = StreamRequest::new();
+        request.dest_host = Some("a.App.example.com".to_string());
+        assert_eq!(selector.select_url(&request).unwrap(), "tcp:///127.0.0.1:8443");
+
+        request.dest_host = Some("app.example.com".to_string());
+        assert_eq!(selector.select_url(&request).unwrap(), "tcp:///127.0.0.1:443");
+
+        request.dest_host = Some("example.com".to_string());
+        request.app_protocol = Some("https".to_string());
+        assert_eq!(selector.select_url(&request).unwrap(), "rtcp://dev01/127.0.0.1:443");
+
+        request.dest_host = None;
+        request.app_protocol = Some("ssh".to_string());
+        assert_eq!(selector.select_url(&request).unwrap(), "tcp:///127.0.0.1:22");
+    }
+}
@@ -2,13 +2,20 @@ use crate::ip::IPTunnelBuilder;
 use crate::socks::SocksTunnelBuilder;
 use crate::DatagramClientBox;
 use crate::{
-    DatagramServerBox, GatewayDeviceRef, RTcpStackManager, StreamListener, StreamProbe,
-    StreamSelector, TunnelBox, TunnelBuilder, TunnelError, TunnelResult,
+    DatagramServerBox, GatewayDeviceRef, HttpHostProbe, HttpsSniProbe, ProtocolProbe,
+    RTcpKeepaliveConfig, RTcpStackManager, RTcpTunnelStats, RuleStreamSelector, StreamListener, StreamProbe, StreamSelector,
+    StreamSelectorConfig, TunnelBox, TunnelBuilder, TunnelError, TunnelResult,
+    HTTPS_SNI_PROBE_ID, HTTP_HOST_PROBE_ID, PROTOCOL_PROBE_ID,
 };
 use buckyos_kit::AsyncStream;
 use log::*;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use url::Url;
 
+pub type StreamProbeBuilder = Arc<dyn Fn() -> Box<dyn StreamProbe + Send + Sync> + Send + Sync>;
+pub type StreamSelectorBuilder = Arc<dyn Fn() -> Box<dyn StreamSelector> + Send + Sync>;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ProtocolCategory {
     Stream,
@@ -33,20 +40,114 @@ pub fn get_protocol_category(str_protocol: &str) -> TunnelResult<ProtocolCategor
     }
 }
 
+// rudp既能承载stream也能承载datagram
+pub fn is_protocol_support_category(str_protocol: &str, category: &ProtocolCategory) -> bool {
+    let str_protocol = str_protocol.to_lowercase();
+    if str_protocol == "rudp" {
+        return true;
+    }
+    match get_protocol_category(str_protocol.as_str()) {
+        Ok(protocol_category) => protocol_category == *category,
+        Err(_) => false,
+    }
+}
+
 #[derive(Clone)]
 pub struct TunnelManager {
     device: GatewayDeviceRef,
     rtcp_stack_manager: RTcpStackManager,
+    stream_probes: Arc<RwLock<HashMap<String, StreamProbeBuilder>>>,
+    stream_selectors: Arc<RwLock<HashMap<String, StreamSelectorBuilder>>>,
 }
 
 impl TunnelManager {
     pub fn new(device: GatewayDeviceRef) -> Self {
-        Self {
+        let this = Self {
             device: device.clone(),
             rtcp_stack_manager: RTcpStackManager::new(device),
+            stream_probes: Arc::new(RwLock::new(HashMap::new())),
+            stream_selectors: Arc::new(RwLock::new(HashMap::new())),
+        };
+
+        this.register_stream_probe(HTTPS_SNI_PROBE_ID, Arc::new(|| Box::new(HttpsSniProbe)));
+        this.register_stream_probe(HTTP_HOST_PROBE_ID, Arc::new(|| Box::new(HttpHostProbe)));
+        this.register_stream_probe(PROTOCOL_PROBE_ID, Arc::new(|| Box::new(ProtocolProbe)));
+        this
+    }
+
+    // 需要在第一次使用rtcp stack之前设置
+    pub fn set_rtcp_rendezvous(&mut self, rendezvous_addr: Option<String>) {
+        self.rtcp_stack_manager.set_rendezvous(rendezvous_addr);
+    }
+
+    // 需要在第一次使用rtcp stack之前设置
+    pub fn set_rtcp_keepalive(&mut self, keepalive: RTcpKeepaliveConfig) {
+        self.rtcp_stack_manager.set_keepalive(keepalive);
+    }
+
+    // 需要在第一次使用rtcp stack之前设置
+    pub fn set_rtcp_allow_legacy(&mut self, allow_legacy: bool) {
+        self.rtcp_stack_manager.set_allow_legacy(allow_legacy);
+    }
+
+    pub async fn get_rtcp_tunnel_stats(&self) -> Vec<RTcpTunnelStats> {
+        self.rtcp_stack_manager.get_tunnel_stats().await
+    }
+
+    // 保持到目标的tunnel, 断开后自动重连, 目前只支持rtcp
+    pub async fn keep_tunnel(&self, target_url: &Url) -> TunnelResult<()> {
+        match target_url.scheme() {
+            "rtcp" => {
+                let host = target_url.host_str().ok_or_else(|| {
+                    TunnelError::UrlParseError(
+                        target_url.to_string(),
+                        "target host is none".to_string(),
+                    )
+                })?;
+                // 端口是对端rtcp stack的端口, 没写时用默认端口
+                let target = match target_url.port() {
+                    Some(port) => format!("{}:{}", host, port),
+                    None => host.to_string(),
+                };
+                let stack = self.rtcp_stack_manager.get_current_device_stack().await?;
+                stack.keep_tunnel(target.as_str());
+                Ok(())
+            }
+            _ => {
+                let msg = format!("keep tunnel not support protocol: {}", target_url.scheme());
+                error!("{}", msg);
+                Err(TunnelError::UnknownProtocol(msg))
+            }
+        }
+    }
+
+    pub fn register_stream_probe(&self, probe_id: &str, builder: StreamProbeBuilder) {
+        let mut probes = self.stream_probes.write().unwrap();
+        if probes.insert(probe_id.to_string(), builder).is_some() {
+            warn!("Stream probe {} already registered, will be replaced", probe_id);
         }
     }
 
+    pub fn register_stream_selector(&self, selector_id: &str, builder: StreamSelectorBuilder) {
+        let mut selectors = self.stream_selectors.write().unwrap();
+        if selectors.insert(selector_id.to_string(), builder).is_some() {
+            warn!("Stream selector {} already registered, will be replaced", selector_id);
+        }
+    }
+
+    pub fn register_rule_stream_selector(&self, selector_id: &str, config: StreamSelectorConfig) {
+        self.register_stream_selector(
+            selector_id,
+            Arc::new(move || Box::new(RuleStreamSelector::new(config.clone()))),
+        );
+    }
+
+    // streams already selected keep running, only new streams are affected
+    pub fn unregister_stream_selector(&self, selector_id: &str) -> bool {
+        let mut selectors = self.stream_selectors.write().unwrap();
+        selectors.remove(selector_id).is_some()
+    }
+
     pub async fn get_tunnel_builder_by_protocol(
         &self,
         protocol: &str,
@@ -59,7 +160,7 @@ impl TunnelManager {
                 Ok(Box::new(stack))
             }
             "rudp" => {
-                let stack = self.rtcp_stack_manager.get_current_device_stack().await?;
+                let stack = self.rtcp_stack_manager.get_current_device_rudp_stack().await?;
                 Ok(Box::new(stack))
             }
             "socks" => {
@@ -74,15 +175,27 @@ impl TunnelManager {
         }
     }
 
-    pub fn get_stream_probe(&self, _probe_id: &str) -> TunnelResult<Box<dyn StreamProbe + Send>> {
-        unimplemented!()
+    pub fn get_stream_probe(
+        &self,
+        probe_id: &str,
+    ) -> TunnelResult<Box<dyn StreamProbe + Send + Sync>> {
+        let probes = self.stream_probes.read().unwrap();
+        let builder = probes.get(probe_id).ok_or_else(|| {
+            let msg = format!("Stream probe not found: {}", probe_id);
+            error!("{}", msg);
+            TunnelError::ReasonError(msg)
+        })?;
+        Ok(builder())
     }
 
-    pub fn get_stream_selector(
-        &self,
-        _selector_id: &str,
-    ) -> TunnelResult<Box<dyn StreamSelector + Send>> {
-        unimplemented!()
+    pub fn get_stream_selector(&self, selector_id: &str) -> TunnelResult<Box<dyn StreamSelector>> {
+        let selectors = self.stream_selectors.read().unwrap();
+        let builder = selectors.get(selector_id).ok_or_else(|| {
+            let msg = format!("Stream selector not found: {}", selector_id);
+            error!("{}", msg);
+            TunnelError::ReasonError(msg)
+        })?;
+        Ok(builder())
     }
 
     pub async fn get_tunnel(
@@ -2,4 +2,6 @@
 
 mod sn_server;
 mod sn_db;
+mod sn_rendezvous;
 pub use sn_server::*;
+pub use sn_rendezvous::*;
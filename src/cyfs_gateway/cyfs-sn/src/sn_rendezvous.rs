@@ -0,0 +1,610 @@
+// SN上的rtcp rendezvous服务:
+// 设备注册后保持一条控制连接, SN记录其外网地址, 在两端之间协调打洞;
+// 打洞失败时把双方各自新建的连接配对转发(中继), tunnel数据本身是端到端加密的
+// 只有属于本SN的设备可以注册和中继, 每条连接都先下发challenge, 设备签名后才能使用
+use cyfs_gateway_lib::{
+    read_rendezvous_message, write_rendezvous_message, RendezvousMessage, RendezvousTokenPayload,
+};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use log::*;
+use name_lib::DID;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::timeout;
+
+const RENDEZVOUS_FIRST_MESSAGE_TIMEOUT: Duration = Duration::from_secs(10);
+const RELAY_BIND_TIMEOUT: Duration = Duration::from_secs(30);
+// 每个设备同时进行中(含等待配对)的中继数上限
+const MAX_RELAYS_PER_DEVICE: usize = 8;
+
+// 判断设备是否属于本SN(在设备库或者zone的gateway列表里)
+pub type RendezvousDeviceChecker = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+struct RendezvousPeer {
+    conn_id: u64,
+    observed_ep: SocketAddr,
+    local_eps: Vec<String>,
+    punch: bool,
+    tx: mpsc::UnboundedSender<RendezvousMessage>,
+}
+
+impl RendezvousPeer {
+    fn endpoints(&self) -> Vec<String> {
+        let mut eps = vec![self.observed_ep.to_string()];
+        for ep in self.local_eps.iter() {
+            if !eps.contains(ep) {
+                eps.push(ep.clone());
+            }
+        }
+        eps
+    }
+}
+
+type RelayCounts = Arc<std::sync::Mutex<HashMap<String, usize>>>;
+
+// 中继占用的计数, 会话超时/配对失败/转发结束时随session或转发任务一起释放
+struct RelayCountGuard {
+    counts: RelayCounts,
+    devices: [String; 2],
+}
+
+impl RelayCountGuard {
+    fn acquire(counts: &RelayCounts, initiator: &str, target: &str) -> Option<Self> {
+        let mut all_counts = counts.lock().unwrap();
+        for device_id in [initiator, target] {
+            if all_counts.get(device_id).copied().unwrap_or(0) >= MAX_RELAYS_PER_DEVICE {
+                return None;
+            }
+        }
+        for device_id in [initiator, target] {
+            *all_counts.entry(device_id.to_string()).or_insert(0) += 1;
+        }
+        Some(Self {
+            counts: counts.clone(),
+            devices: [initiator.to_string(), target.to_string()],
+        })
+    }
+}
+
+impl Drop for RelayCountGuard {
+    fn drop(&mut self) {
+        let mut all_counts = self.counts.lock().unwrap();
+        for device_id in self.devices.iter() {
+            if let Some(count) = all_counts.get_mut(device_id) {
+                *count -= 1;
+                if *count == 0 {
+                    all_counts.remove(device_id);
+                }
+            }
+        }
+    }
+}
+
+struct RelaySession {
+    initiator: String,
+    target: String,
+    waiting: Option<(String, TcpStream)>,
+    count_guard: RelayCountGuard,
+}
+
+#[derive(Clone)]
+pub struct RendezvousServer {
+    peers: Arc<Mutex<HashMap<String, RendezvousPeer>>>,
+    relays: Arc<Mutex<HashMap<String, RelaySession>>>,
+    relay_counts: RelayCounts,
+    next_conn_id: Arc<AtomicU64>,
+    device_checker: RendezvousDeviceChecker,
+}
+
+fn verify_device_token(device_id: &str, token: &str, nonce: &str) -> Result<(), String> {
+    let did = DID::from_str(device_id).map_err(|e| format!("invalid device id {}: {}", device_id, e))?;
+    let verify_public_key = DecodingKey::from_ed_components(did.id.as_str())
+        .map_err(|e| format!("decode device {} public key error: {}", device_id, e))?;
+    let payload = decode::<RendezvousTokenPayload>(
+        token,
+        &verify_public_key,
+        &Validation::new(Algorithm::EdDSA),
+    )
+    .map_err(|e| format!("verify device {} token error: {}", device_id, e))?;
+    if payload.claims.from != device_id {
+        return Err(format!("device token is not from {}", device_id));
+    }
+    if payload.claims.nonce != nonce {
+        return Err(format!("device {} token is not signed for this connection", device_id));
+    }
+    Ok(())
+}
+
+impl RendezvousServer {
+    pub fn new(device_checker: RendezvousDeviceChecker) -> Self {
+        RendezvousServer {
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            relays: Arc::new(Mutex::new(HashMap::new())),
+            relay_counts: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            next_conn_id: Arc::new(AtomicU64::new(1)),
+            device_checker,
+        }
+    }
+
+    // 先验证token签名和challenge, 再确认设备属于本SN
+    fn verify_device(&self, device_id: &str, token: &str, nonce: &str) -> Result<(), String> {
+        verify_device_token(device_id, token, nonce)?;
+        if !(self.device_checker)(device_id) {
+            return Err(format!("device {} is not registered in this sn", device_id));
+        }
+        Ok(())
+    }
+
+    pub async fn start(&self, bind_addr: &str) -> Result<SocketAddr, String> {
+        let listener = TcpListener::bind(bind_addr).await.map_err(|e| {
+            let msg = format!("bind rendezvous listener {} error: {}", bind_addr, e);
+            error!("{}", msg);
+            msg
+        })?;
+        let local_addr = listener.local_addr().map_err(|e| e.to_string())?;
+        info!("sn rendezvous server start at {}", local_addr);
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match listener.accept().await {
+                    Ok(ret) => ret,
+                    Err(e) => {
+                        warn!("rendezvous accept error: {}", e);
+                        continue;
+                    }
+                };
+                let this = this.clone();
+                tokio::spawn(async move {
+                    this.handle_conn(stream, addr).await;
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+
+    pub async fn is_online(&self, device_id: &str) -> bool {
+        self.peers.lock().await.contains_key(device_id)
+    }
+
+    async fn handle_conn(&self, mut stream: TcpStream, addr: SocketAddr) {
+        let nonce = format!("{:032x}", rand::random::<u128>());
+        let challenge = RendezvousMessage::Challenge {
+            nonce: nonce.clone(),
+        };
+        if let Err(e) = write_rendezvous_message(&mut stream, &challenge).await {
+            warn!("send rendezvous challenge to {} error: {}", addr, e);
+            return;
+        }
+
+        let first_msg = timeout(
+            RENDEZVOUS_FIRST_MESSAGE_TIMEOUT,
+            read_rendezvous_message(&mut stream),
+        )
+        .await;
+        match first_msg {
+            Ok(Ok(Some(RendezvousMessage::Register {
+                device_id,
+                token,
+                local_eps,
+                punch,
+            }))) => {
+                if let Err(e) = self.verify_device(&device_id, &token, &nonce) {
+                    warn!("rendezvous register from {} rejected: {}", addr, e);
+                    let resp = RendezvousMessage::RegisterResp {
+                        result: 1,
+                        observed_ep: None,
+                    };
+                    let _ = write_rendezvous_message(&mut stream, &resp).await;
+                    return;
+                }
+                self.on_register(stream, addr, device_id, local_eps, punch)
+                    .await;
+            }
+            Ok(Ok(Some(RendezvousMessage::RelayBind {
+                session_id,
+                device_id,
+                token,
+            }))) => {
+                self.on_relay_bind(stream, session_id, device_id, token, &nonce)
+                    .await;
+            }
+            Ok(Ok(msg)) => warn!("invalid first rendezvous message from {}: {:?}", addr, msg),
+            Ok(Err(e)) => warn!("read rendezvous message from {} error: {}", addr, e),
+            Err(_) => warn!("read rendezvous message from {} timeout", addr),
+        }
+    }
+
+    async fn on_register(
+        &self,
+        stream: TcpStream,
+        addr: SocketAddr,
+        device_id: String,
+        local_eps: Vec<String>,
+        punch: bool,
+    ) {
+        let conn_id = self.next_conn_id.fetch_add(1, Ordering::SeqCst);
+        let (mut reader, mut writer) = stream.into_split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<RendezvousMessage>();
+        let _ = tx.send(RendezvousMessage::RegisterResp {
+            result: 0,
+            observed_ep: Some(addr.to_string()),
+        });
+        let writer_task = tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                if let Err(e) = write_rendezvous_message(&mut writer, &msg).await {
+                    warn!("write rendezvous message error: {}", e);
+                    break;
+                }
+            }
+        });
+
+        info!("rendezvous register {} from {}, local_eps:{:?}", device_id, addr, local_eps);
+        self.peers.lock().await.insert(
+            device_id.clone(),
+            RendezvousPeer {
+                conn_id,
+                observed_ep: addr,
+                local_eps,
+                punch,
+                tx: tx.clone(),
+            },
+        );
+
+        loop {
+            let msg = match read_rendezvous_message(&mut reader).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("read rendezvous message from {} error: {}", device_id, e);
+                    break;
+                }
+            };
+            match msg {
+                RendezvousMessage::Ping => {
+                    let _ = tx.send(RendezvousMessage::Pong);
+                }
+                RendezvousMessage::Connect {
+                    session_id,
+                    target_id,
+                    relay,
+                } => {
+                    self.on_connect(&device_id, session_id, target_id, relay, &tx)
+                        .await;
+                }
+                msg => warn!("unexpected rendezvous message from {}: {:?}", device_id, msg),
+            }
+        }
+
+        // 设备可能已经用新连接重新注册了
+        let mut peers = self.peers.lock().await;
+        if peers.get(&device_id).map(|p| p.conn_id) == Some(conn_id) {
+            peers.remove(&device_id);
+        }
+        drop(peers);
+        writer_task.abort();
+        info!("rendezvous peer {} offline", device_id);
+    }
+
+    async fn on_connect(
+        &self,
+        device_id: &str,
+        session_id: String,
+        target_id: String,
+        relay: bool,
+        tx: &mpsc::UnboundedSender<RendezvousMessage>,
+    ) {
+        let peers = self.peers.lock().await;
+        let target = peers.get(&target_id);
+        if target.is_none() {
+            let _ = tx.send(RendezvousMessage::ConnectFailed {
+                session_id,
+                reason: format!("{} not online", target_id),
+            });
+            return;
+        }
+        let target = target.unwrap();
+
+        if relay {
+            let count_guard =
+                match RelayCountGuard::acquire(&self.relay_counts, device_id, &target_id) {
+                    Some(guard) => guard,
+                    None => {
+                        warn!("rendezvous relay {} -> {} rejected, too many relays", device_id, target_id);
+                        let _ = tx.send(RendezvousMessage::ConnectFailed {
+                            session_id,
+                            reason: "too many relays".to_string(),
+                        });
+                        return;
+                    }
+                };
+            let mut relays = self.relays.lock().await;
+            if relays.contains_key(&session_id) {
+                let _ = tx.send(RendezvousMessage::ConnectFailed {
+                    session_id,
+                    reason: "relay session already exists".to_string(),
+                });
+                return;
+            }
+            info!("rendezvous relay {} -> {}, session:{}", device_id, target_id, session_id);
+            relays.insert(
+                session_id.clone(),
+                RelaySession {
+                    initiator: device_id.to_string(),
+                    target: target_id.clone(),
+                    waiting: None,
+                    count_guard,
+                },
+            );
+            drop(relays);
+            let _ = target.tx.send(RendezvousMessage::RelayRequest {
+                session_id: session_id.clone(),
+                peer_id: device_id.to_string(),
+            });
+            let _ = tx.send(RendezvousMessage::RelayRequest {
+                session_id: session_id.clone(),
+                peer_id: target_id,
+            });
+
+            // 超时没有配对成功就清理掉
+            let relays = self.relays.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(RELAY_BIND_TIMEOUT).await;
+                relays.lock().await.remove(&session_id);
+            });
+            return;
+        }
+
+        if !target.punch {
+            let _ = tx.send(RendezvousMessage::ConnectFailed {
+                session_id,
+                reason: format!("{} not accept punch", target_id),
+            });
+            return;
+        }
+        let me = peers.get(device_id);
+        if me.is_none() {
+            return;
+        }
+        let me = me.unwrap();
+
+        info!(
+            "rendezvous punch {} {:?} <-> {} {:?}, session:{}",
+            device_id,
+            me.endpoints(),
+            target_id,
+            target.endpoints(),
+            session_id
+        );
+        let _ = target.tx.send(RendezvousMessage::Punch {
+            session_id: session_id.clone(),
+            peer_id: device_id.to_string(),
+            peer_eps: me.endpoints(),
+            initiator: false,
+        });
+        let _ = tx.send(RendezvousMessage::Punch {
+            session_id,
+            peer_id: target_id,
+            peer_eps: target.endpoints(),
+            initiator: true,
+        });
+    }
+
+    async fn on_relay_bind(
+        &self,
+        mut stream: TcpStream,
+        session_id: String,
+        device_id: String,
+        token: String,
+        nonce: &str,
+    ) {
+        let failed = |reason: String| RendezvousMessage::ConnectFailed {
+            session_id: session_id.clone(),
+            reason,
+        };
+        if let Err(e) = self.verify_device(&device_id, &token, nonce) {
+            warn!("rendezvous relay bind rejected: {}", e);
+            let _ = write_rendezvous_message(&mut stream, &failed(e)).await;
+            return;
+        }
+
+        let mut relays = self.relays.lock().await;
+        let session = relays.get_mut(&session_id);
+        if session.is_none() {
+            drop(relays);
+            let _ = write_rendezvous_message(&mut stream, &failed("relay session not found".to_string())).await;
+            return;
+        }
+        let session = session.unwrap();
+        if device_id != session.initiator && device_id != session.target {
+            drop(relays);
+            let _ = write_rendezvous_message(&mut stream, &failed("not relay peer".to_string())).await;
+            return;
+        }
+
+        let (other_id, mut other_stream) = match session.waiting.take() {
+            None => {
+                session.waiting = Some((device_id, stream));
+                return;
+            }
+            Some((other_id, other_stream)) if other_id == device_id => {
+                session.waiting = Some((other_id, other_stream));
+                drop(relays);
+                let _ = write_rendezvous_message(&mut stream, &failed("already bound".to_string())).await;
+                return;
+            }
+            Some(waiting) => waiting,
+        };
+        // 计数随转发任务持有, 转发结束才释放
+        let count_guard = relays.remove(&session_id).map(|session| session.count_guard);
+        drop(relays);
+
+        let ready = RendezvousMessage::RelayReady {
+            session_id: session_id.clone(),
+        };
+        if write_rendezvous_message(&mut stream, &ready).await.is_err()
+            || write_rendezvous_message(&mut other_stream, &ready).await.is_err()
+        {
+            warn!("rendezvous relay {} ready failed", session_id);
+            return;
+        }
+
+        info!("rendezvous relay {} <-> {} start, session:{}", device_id, other_id, session_id);
+        tokio::spawn(async move {
+            let _count_guard = count_guard;
+            let ret = tokio::io::copy_bidirectional(&mut stream, &mut other_stream).await;
+            info!("rendezvous relay {} end: {:?}", session_id, ret);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cyfs_gateway_lib::{create_rendezvous_token, RTcpPunchClient};
+    use jsonwebtoken::EncodingKey;
+    use name_lib::{encode_ed25519_sk_to_pk_jwk, generate_ed25519_key};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpSocket;
+
+    fn new_device(name: &str) -> (DID, EncodingKey) {
+        let (sk, sk_pkcs) = generate_ed25519_key();
+        let jwk = encode_ed25519_sk_to_pk_jwk(&sk);
+        let x = jwk.get("x").unwrap().as_str().unwrap();
+        info!("test device {} x:{}", name, x);
+        (DID::new("dev", x), EncodingKey::from_ed_der(&sk_pkcs))
+    }
+
+    // 与rtcp stack一样开启端口复用, 打洞的连接从同一个端口发出
+    fn reuse_listener() -> (tokio::net::TcpListener, u16) {
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.set_reuseaddr(true).unwrap();
+        socket.set_reuseport(true).unwrap();
+        socket.bind("0.0.0.0:0".parse().unwrap()).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        (socket.listen(16).unwrap(), port)
+    }
+
+    async fn wait_online(server: &RendezvousServer, did: &DID) {
+        for _ in 0..50 {
+            if server.is_online(did.to_string().as_str()).await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("{} not online", did.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous_punch_and_relay() {
+        let server = RendezvousServer::new(Arc::new(|_: &str| true));
+        let sn_addr = server.start("127.0.0.1:0").await.unwrap().to_string();
+
+        // A: 发起方, 本地端口上没有listener
+        let (did_a, key_a) = new_device("a");
+        let (listener_a, port_a) = reuse_listener();
+        drop(listener_a);
+        let client_a = RTcpPunchClient::new(&sn_addr, did_a.clone(), port_a, key_a, true);
+        client_a.start();
+
+        // B: 锥形NAT替身, 映射建立后允许入站
+        let (did_b, key_b) = new_device("b");
+        let (listener_b, port_b) = reuse_listener();
+        let client_b = RTcpPunchClient::new(&sn_addr, did_b.clone(), port_b, key_b, true);
+        let mut incoming_b = client_b.take_incoming_receiver().unwrap();
+        client_b.start();
+
+        // C: 对称NAT替身, 不参与打洞只能中继
+        let (did_c, key_c) = new_device("c");
+        let client_c = RTcpPunchClient::new(&sn_addr, did_c.clone(), 0, key_c, false);
+        let mut incoming_c = client_c.take_incoming_receiver().unwrap();
+        client_c.start();
+
+        wait_online(&server, &did_a).await;
+        wait_online(&server, &did_b).await;
+        wait_online(&server, &did_c).await;
+
+        // 1. A -> B 打洞直连
+        let mut stream_ab = client_a.punch(did_b.to_string().as_str()).await.unwrap();
+        stream_ab.write_all(b"hello b").await.unwrap();
+        let mut stream_b = tokio::select! {
+            ret = listener_b.accept() => ret.unwrap().0,
+            ret = incoming_b.recv() => ret.unwrap().0,
+        };
+        let mut buf = [0u8; 7];
+        stream_b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello b");
+
+        // 2. A -> C 打洞被拒绝, 回退到SN中继
+        assert!(client_a.punch(did_c.to_string().as_str()).await.is_err());
+        let mut stream_ac = client_a.relay(did_c.to_string().as_str()).await.unwrap();
+        let (mut stream_c, _) = incoming_c.recv().await.unwrap();
+        stream_ac.write_all(b"hello c").await.unwrap();
+        stream_c.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello c");
+        stream_c.write_all(b"hello a").await.unwrap();
+        stream_ac.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello a");
+
+        // 3. 未注册的设备
+        let (did_d, _) = new_device("d");
+        assert!(client_a.relay(did_d.to_string().as_str()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rendezvous_device_auth() {
+        let (did_a, key_a) = new_device("a");
+        let (did_e, key_e) = new_device("e");
+        let allowed = did_a.to_string();
+        let server = RendezvousServer::new(Arc::new(move |device_id: &str| device_id == allowed));
+        let sn_addr = server.start("127.0.0.1:0").await.unwrap();
+
+        // 不属于本SN的设备不能注册
+        let client_e = RTcpPunchClient::new(&sn_addr.to_string(), did_e.clone(), 0, key_e, false);
+        client_e.start();
+
+        // 用别的连接的challenge签的token不能重放
+        let mut stream = TcpStream::connect(sn_addr).await.unwrap();
+        let challenge = read_rendezvous_message(&mut stream).await.unwrap();
+        assert!(matches!(challenge, Some(RendezvousMessage::Challenge { .. })));
+        let register = RendezvousMessage::Register {
+            device_id: did_a.to_string(),
+            token: create_rendezvous_token(&did_a, &key_a, "replayed nonce").unwrap(),
+            local_eps: vec![],
+            punch: false,
+        };
+        write_rendezvous_message(&mut stream, &register).await.unwrap();
+        let resp = read_rendezvous_message(&mut stream).await.unwrap();
+        assert!(matches!(resp, Some(RendezvousMessage::RegisterResp { result: 1, .. })));
+
+        // 签上本连接的challenge才能注册
+        let client_a = RTcpPunchClient::new(&sn_addr.to_string(), did_a.clone(), 0, key_a, false);
+        client_a.start();
+        wait_online(&server, &did_a).await;
+        assert!(!server.is_online(did_e.to_string().as_str()).await);
+    }
+
+    #[test]
+    fn test_relay_count_limit() {
+        let counts: RelayCounts = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let mut guards = Vec::new();
+        for i in 0..MAX_RELAYS_PER_DEVICE {
+            let target = format!("target{}", i);
+            guards.push(RelayCountGuard::acquire(&counts, "a", &target).unwrap());
+        }
+        // a已经到上限, 其他设备不受影响
+        assert!(RelayCountGuard::acquire(&counts, "a", "other").is_none());
+        assert!(RelayCountGuard::acquire(&counts, "other", "a").is_none());
+        assert!(RelayCountGuard::acquire(&counts, "b", "other").is_some());
+
+        guards.pop();
+        assert!(RelayCountGuard::acquire(&counts, "a", "other").is_some());
+        drop(guards);
+        assert!(counts.lock().unwrap().is_empty());
+    }
+}
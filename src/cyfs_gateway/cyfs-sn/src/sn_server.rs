@@ -15,6 +15,7 @@ use std::str::FromStr;
 use lazy_static::lazy_static;
 use jsonwebtoken::DecodingKey;
 use crate::sn_db::{self, *};
+use crate::sn_rendezvous::{RendezvousDeviceChecker, RendezvousServer};
 use buckyos_api::CURRENT_DEVICE_CONFIG;
 
 #[derive(Debug,Clone,Serialize,Deserialize)]
@@ -23,6 +24,9 @@ pub struct SNServerConfig {
     ip:String,
     zone_config_jwt:String,
     zone_config_pkx:String,
+    //rtcp打洞/中继的rendezvous端口,不配置则不启动
+    #[serde(default)]
+    rendezvous_port:Option<u16>,
 }
 
 
@@ -41,6 +45,10 @@ pub struct SNServer {
     zone_boot_config:String,
     zone_boot_config_pkx:String,
     zone_gateway_list:Option<Vec<String>>,//device_list is the list of device_did
+    //_acme-challenge TXT records written by dns-01 responders, name without trailing dot
+    acme_challenge_records:Arc<Mutex<HashMap<String,String>>>,
+    rendezvous_port:Option<u16>,
+    rendezvous:RendezvousServer,
 }
 
 impl SNServer {
@@ -67,6 +75,21 @@ impl SNServer {
         //TODO:需要改进
         let zone_config = server_config.zone_config_jwt;
         let zone_config_pkx = server_config.zone_config_pkx;
+
+        //只有zone的gateway和在SN注册过的设备可以使用rendezvous
+        let gateway_list = device_list.clone().unwrap_or_default();
+        let device_checker:RendezvousDeviceChecker = Arc::new(move |device_id:&str| {
+            if gateway_list.iter().any(|id| id == device_id) {
+                return true;
+            }
+            match sn_db::get_sn_db_conn() {
+                Ok(conn) => matches!(sn_db::query_device(&conn, device_id),Ok(Some(_))),
+                Err(e) => {
+                    warn!("Failed to get sn_db_conn: {:?}",e);
+                    false
+                }
+            }
+        });
          
 
         SNServer {
@@ -77,9 +100,19 @@ impl SNServer {
             zone_boot_config:zone_config,
             zone_boot_config_pkx:zone_config_pkx,
             zone_gateway_list:device_list,
+            acme_challenge_records:Arc::new(Mutex::new(HashMap::new())),
+            rendezvous_port:server_config.rendezvous_port,
+            rendezvous:RendezvousServer::new(device_checker),
         }
     }
 
+    pub async fn start_rendezvous(&self) -> Result<(),String> {
+        if let Some(port) = self.rendezvous_port {
+            self.rendezvous.start(format!("0.0.0.0:{}",port).as_str()).await?;
+        }
+        Ok(())
+    }
+
     pub async fn get_user_tls_cert(&self,req:RPCRequest) -> Result<RPCResponse,RPCErrors> {
         unimplemented!();
     }
@@ -435,6 +468,18 @@ impl NsProvider for SNServer {
             return Err(NSError::NotFound(format!("sn-server not support record type {}",record_type.to_string())));
         }
 
+        if record_type == RecordType::TXT && name.starts_with("_acme-challenge.") {
+            let key = name.trim_end_matches('.');
+            let records = self.acme_challenge_records.lock().await;
+            if let Some(txt) = records.get(key) {
+                let mut result_name_info = NameInfo::from_address_vec(name, vec![]);
+                result_name_info.txt = Some(txt.clone());
+                result_name_info.ttl = Some(60);
+                return Ok(result_name_info);
+            }
+            return Err(NSError::NotFound(name.to_string()));
+        }
+
         let full_server_host = format!("{}.",self.server_host.as_str());
         if name == self.server_host || name == full_server_host {
             //返回当前服务器的地址
@@ -548,6 +593,62 @@ impl NsProvider for SNServer {
     }
 }
 
+//sn自己的dns记录只允许写入acme dns-01挑战需要的TXT记录
+#[async_trait]
+impl NsUpdateProvider for SNServer {
+    async fn update(&self, record_type: RecordType, record: NameInfo) -> NSResult<NameInfo> {
+        if record_type != RecordType::TXT || !record.name.starts_with("_acme-challenge.") {
+            return Err(NSError::Failed(format!("sn-server only accept _acme-challenge TXT record update, name: {}",record.name)));
+        }
+        let txt = record.txt.clone()
+            .ok_or_else(|| NSError::Failed("No TXT content provided".to_string()))?;
+        let key = record.name.trim_end_matches('.').to_string();
+        info!("sn server set acme challenge record: {} TXT {}",key,txt);
+        self.acme_challenge_records.lock().await.insert(key, txt);
+        Ok(record)
+    }
+
+    async fn delete(&self, name: &str, record_type: RecordType) -> NSResult<Option<NameInfo>> {
+        if record_type != RecordType::TXT {
+            return Ok(None);
+        }
+        let key = name.trim_end_matches('.');
+        let txt = self.acme_challenge_records.lock().await.remove(key);
+        Ok(txt.map(|txt| {
+            let mut name_info = NameInfo::from_address_vec(name, vec![]);
+            name_info.txt = Some(txt);
+            name_info
+        }))
+    }
+}
+
+// 给同一个gateway里cyfs-warp的证书管理器写dns-01挑战记录,SN server可能晚于warp启动,用到时再查找
+pub struct SnAcmeDnsProvider {
+    server_id: String,
+}
+
+impl SnAcmeDnsProvider {
+    pub fn new(server_id: &str) -> Self {
+        SnAcmeDnsProvider { server_id: server_id.to_string() }
+    }
+
+    async fn get_sn_server(&self) -> NSResult<SNServer> {
+        get_sn_server_by_id(self.server_id.as_str()).await
+            .ok_or_else(|| NSError::NotFound(format!("sn server {} not found", self.server_id)))
+    }
+}
+
+#[async_trait]
+impl NsUpdateProvider for SnAcmeDnsProvider {
+    async fn update(&self, record_type: RecordType, record: NameInfo) -> NSResult<NameInfo> {
+        self.get_sn_server().await?.update(record_type, record).await
+    }
+
+    async fn delete(&self, name: &str, record_type: RecordType) -> NSResult<Option<NameInfo>> {
+        self.get_sn_server().await?.delete(name, record_type).await
+    }
+}
+
 #[async_trait]
 impl InnerServiceHandler for SNServer {
     async fn handle_rpc_call(&self, req:RPCRequest,ip_from:IpAddr) -> Result<RPCResponse,RPCErrors> {
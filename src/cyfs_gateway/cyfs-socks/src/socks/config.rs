@@ -7,7 +7,7 @@ use buckyos_kit::get_buckyos_system_etc_dir;
 use std::net::SocketAddr;
 use url::Url;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SocksProxyAuth {
     None,
     Password(String, String),
@@ -32,6 +32,20 @@ pub struct SocksProxyConfig {
     pub rule_engine: Option<RuleEngine>,
 }
 
+// The rule engine is loaded from rule_config, so it is not compared
+impl PartialEq for SocksProxyConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+            && self.bind == other.bind
+            && self.port == other.port
+            && self.addr == other.addr
+            && self.target == other.target
+            && self.enable_tunnel == other.enable_tunnel
+            && self.auth == other.auth
+            && self.rule_config == other.rule_config
+    }
+}
+
 impl SocksProxyConfig {
     pub fn load(config: &serde_json::Value) -> SocksResult<Self> {
         let id = config["id"].as_str().unwrap_or("socks5");
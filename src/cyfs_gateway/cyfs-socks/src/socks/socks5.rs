@@ -138,6 +138,20 @@ impl Socks5Proxy {
         }
     }
 
+    // Stop and wait until the listener is released, so the addr can be bound again
+    pub async fn stop_and_wait(&self) {
+        let task = {
+            let mut slot = self.task.lock().unwrap();
+            slot.take()
+        };
+
+        if let Some(task) = task {
+            task.abort();
+            let _ = task.await;
+            info!("Socks5 proxy task stopped: {}", self.config.id);
+        }
+    }
+
     async fn run(&self, listener: TcpListener) -> SocksResult<()> {
         // Standard TCP loop
         loop {
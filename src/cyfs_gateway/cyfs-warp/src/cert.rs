@@ -1,9 +1,37 @@
 use crate::router::Router;
 use anyhow::Result;
 use cyfs_gateway_lib::{
-    AcmeChallengeEntry, AcmeChallengeResponder, ResponseRouteConfig, RouteConfig,
+    AcmeChallengeEntry, AcmeChallengeResponder, AcmeDnsConfig, AcmeDnsProviderConfig,
+    Dns01ChallengeEntry, ResponseRouteConfig, RouteConfig,
 };
+use cyfs_sn::SnAcmeDnsProvider;
+use name_client::{CloudflareConfig, CloudflareProvider, NsUpdateProvider};
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// http-01和tls-alpn-01由warp自己响应,配置了acme_dns时再支持dns-01
+pub(crate) type WarpChallengeEntry = Dns01ChallengeEntry<ChallengeEntry>;
+
+pub(crate) fn create_challenge_entry(router: Router, acme_dns: Option<&AcmeDnsConfig>) -> WarpChallengeEntry {
+    let entry = ChallengeEntry::new(router);
+    let acme_dns = match acme_dns {
+        Some(acme_dns) => acme_dns,
+        None => return Dns01ChallengeEntry::without_provider(entry),
+    };
+
+    let provider: Arc<dyn NsUpdateProvider> = match &acme_dns.provider {
+        AcmeDnsProviderConfig::Cloudflare { api_token, email, known_domains } => {
+            Arc::new(CloudflareProvider::new(CloudflareConfig {
+                api_token: api_token.clone(),
+                email: email.clone(),
+                known_domains: known_domains.clone(),
+            }))
+        }
+        AcmeDnsProviderConfig::Sn { server_id } => Arc::new(SnAcmeDnsProvider::new(server_id)),
+    };
+    Dns01ChallengeEntry::new(entry, provider, Duration::from_secs(acme_dns.propagation_delay))
+}
 
 pub(crate) struct ChallengeEntry {
     router: Router,
@@ -48,6 +76,12 @@ impl AcmeChallengeResponder for ChallengeResponder {
             tunnel_selector: None,
             bucky_service: None,
             named_mgr: None,
+            rewrite: None,
+            request_headers: None,
+            response_headers: None,
+            rate_limit: None,
+            max_body_size: None,
+            auth: None,
         };
         self.router
             .insert_route_config(domain, path.as_str(), config);
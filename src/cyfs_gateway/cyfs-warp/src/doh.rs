@@ -0,0 +1,80 @@
+use anyhow::Result;
+use base64::Engine;
+use cyfs_gateway_lib::DnsMessageHandler;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::*;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::read_body_with_limit;
+
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+const MAX_DNS_MESSAGE_SIZE: u64 = 65535;
+
+fn bad_request(msg: &str) -> Result<Response<Body>> {
+    warn!("invalid doh request: {}", msg);
+    Ok(Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(msg.to_string()))?)
+}
+
+// RFC 8484: GET ?dns=<base64url>, 或者POST application/dns-message
+pub(crate) async fn handle_doh(
+    handler: Arc<dyn DnsMessageHandler>,
+    req: Request<Body>,
+    client_addr: SocketAddr,
+) -> Result<Response<Body>> {
+    let message = match *req.method() {
+        Method::GET => {
+            let dns = req.uri().query().and_then(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "dns")
+                    .map(|(_, value)| value.to_string())
+            });
+            let dns = match dns {
+                Some(dns) => dns,
+                None => return bad_request("missing dns param"),
+            };
+            match base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(dns.trim_end_matches('=')) {
+                Ok(message) => message,
+                Err(e) => return bad_request(&format!("invalid dns param: {}", e)),
+            }
+        }
+        Method::POST => {
+            let content_type = req
+                .headers()
+                .get(hyper::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            if content_type != DNS_MESSAGE_CONTENT_TYPE {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+                    .body(Body::empty())?);
+            }
+            match read_body_with_limit(req, MAX_DNS_MESSAGE_SIZE).await? {
+                Some(message) => message,
+                None => {
+                    warn!("doh request body from {} is too large", client_addr);
+                    return Ok(Response::builder()
+                        .status(StatusCode::PAYLOAD_TOO_LARGE)
+                        .body(Body::empty())?);
+                }
+            }
+        }
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .body(Body::empty())?);
+        }
+    };
+
+    if message.len() < 12 || message.len() as u64 > MAX_DNS_MESSAGE_SIZE {
+        return bad_request("invalid dns message size");
+    }
+
+    let response = handler.handle_dns_message(&message, client_addr).await?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, DNS_MESSAGE_CONTENT_TYPE)
+        .body(Body::from(response))?)
+}
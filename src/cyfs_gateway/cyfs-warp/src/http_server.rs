@@ -1,5 +1,6 @@
 
 use crate::router::*;
+use crate::cert::{create_challenge_entry, WarpChallengeEntry};
 use anyhow::Result;
 use cyfs_gateway_lib::*;
 use futures::stream::StreamExt;
@@ -20,7 +21,13 @@ use rustls_pemfile::pkcs8_private_keys;
 use std::fs::File;
 use std::io::BufReader;
 pub struct CyfsWarpServer {
-    config: WarpServerConfig,
+    config: std::sync::RwLock<WarpServerConfig>,
+
+    // shared by all listeners, updated in place when the config changes
+    http_router: std::sync::Mutex<Option<Router>>,
+    https_router: std::sync::Mutex<Option<Router>>,
+    sni_resolver: std::sync::Mutex<Option<Arc<SNIResolver>>>,
+    cert_mgr: std::sync::Mutex<Option<CertManager<WarpChallengeEntry>>>,
 
     http_servers: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
     https_servers: Arc<Mutex<Vec<tokio::task::JoinHandle<()>>>>,
@@ -29,90 +36,87 @@ pub struct CyfsWarpServer {
 impl CyfsWarpServer {
     pub fn new(config: WarpServerConfig) -> Self {
         Self {
-            config,
+            config: std::sync::RwLock::new(config),
+            http_router: std::sync::Mutex::new(None),
+            https_router: std::sync::Mutex::new(None),
+            sni_resolver: std::sync::Mutex::new(None),
+            cert_mgr: std::sync::Mutex::new(None),
             http_servers: Arc::new(Mutex::new(Vec::new())),
             https_servers: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
+    fn https_host_routes(host_config: &HostConfig) -> HashMap<String, Arc<RouteConfig>> {
+        HashMap::from_iter(host_config.routes.iter().map(|(route, route_config)| {
+            (route.clone(), Arc::new(route_config.clone()))
+        }))
+    }
+
+    fn http_host_routes(host: &str, host_config: &HostConfig) -> HashMap<String, Arc<RouteConfig>> {
+        if host_config.redirect_to_https {
+            HashMap::from_iter(vec![(
+                "/".to_string(),
+                Arc::new(RouteConfig {
+                    enable_cors: host_config.enable_cors,
+                    response: Some(ResponseRouteConfig {
+                        status: Some(301),
+                        headers: Some(HashMap::from_iter(vec![(
+                            "Location".to_string(),
+                            format!("https://{}", host),
+                        )])),
+                        body: None,
+                    }),
+                    upstream: None,
+                    local_dir: None,
+                    inner_service: None,
+                    tunnel_selector: None,
+                    bucky_service: None,
+                    named_mgr: None,
+                    rewrite: None,
+                    request_headers: None,
+                    response_headers: None,
+                    rate_limit: None,
+                    max_body_size: None,
+                    auth: None,
+                }),
+            )])
+        } else {
+            Self::https_host_routes(host_config)
+        }
+    }
+
     pub async fn start(&self) -> Result<()> {
+        let config = self.config.read().unwrap().clone();
         // Router for HTTP and HTTPS
-        let https_router = Router::new(HashMap::from_iter(self.config.hosts.iter().map(
-            |(host, host_config)| {
-                (
-                    host.clone(),
-                    HashMap::from_iter(host_config.routes.iter().map(|(route, route_config)| {
-                        (route.clone(), Arc::new(route_config.clone()))
-                    })),
-                )
-            },
+        let https_router = Router::new(HashMap::from_iter(config.hosts.iter().map(
+            |(host, host_config)| (host.clone(), Self::https_host_routes(host_config)),
         )));
 
-        let http_router = Router::new(HashMap::from_iter(self.config.hosts.iter().map(
-            |(host, host_config)| {
-                if host_config.redirect_to_https {
-                    (
-                        host.clone(),
-                        HashMap::from_iter(vec![(
-                            "/".to_string(),
-                            Arc::new(RouteConfig {
-                                enable_cors: host_config.enable_cors,
-                                response: Some(ResponseRouteConfig {
-                                    status: Some(301),
-                                    headers: Some(HashMap::from_iter(vec![(
-                                        "Location".to_string(),
-                                        format!("https://{}", host),
-                                    )])),
-                                    body: None,
-                                }),
-                                upstream: None,
-                                local_dir: None,
-                                inner_service: None,
-                                tunnel_selector: None,
-                                bucky_service: None,
-                                named_mgr: None,
-                            }),
-                        )]),
-                    )
-                } else {
-                    (
-                        host.clone(),
-                        HashMap::from_iter(host_config.routes.iter().map(
-                            |(route, route_config)| (route.clone(), Arc::new(route_config.clone())),
-                        )),
-                    )
-                }
-            },
+        let http_router = Router::new(HashMap::from_iter(config.hosts.iter().map(
+            |(host, host_config)| (host.clone(), Self::http_host_routes(host, host_config)),
         )));
 
-        // // Cert manager for HTTPS
-        // let root_path = get_buckyos_service_data_dir("cyfs-warp");
-        // info!("Will use cyfs-warp data directory: {}", root_path.display());
-        // if !root_path.exists() {
-        //     info!("Creating cyfs-warp data directory: {}", root_path.display());
-        //     if let Err(e) = std::fs::create_dir_all(&root_path) {
-        //         let msg = format!(
-        //             "Failed to create cyfs-warp data directory: {}, {}",
-        //             e,
-        //             root_path.display()
-        //         );
-        //         error!("{}", msg);
-        //         return Err(anyhow::anyhow!(msg));
-        //     }
-        // }
-
-        // let mut cert_mgr_config = CertManagerConfig::default();
-        // cert_mgr_config.keystore_path = root_path.to_string_lossy().to_string();
-
-        // let cert_mgr =
-        //     CertManager::new(cert_mgr_config, ChallengeEntry::new(http_router.clone())).await?;
-
-        // for (host, host_config) in self.config.hosts.iter() {
-        //     cert_mgr.insert_config(host.clone(), host_config.tls.clone())?;
-        // }
+        // Cert manager for HTTPS, only when acme or on-demand tls is used
+        let cert_mgr = Self::create_cert_mgr(&config, &http_router).await;
+        let sni_resolver = if config.tls_port > 0 {
+            Some(Arc::new(SNIResolver::new_with_cert_mgr(
+                Self::load_tls_configs(&config)?,
+                cert_mgr.clone(),
+            )))
+        } else {
+            None
+        };
+        *self.http_router.lock().unwrap() = Some(http_router.clone());
+        *self.https_router.lock().unwrap() = Some(https_router.clone());
+        *self.sni_resolver.lock().unwrap() = sni_resolver.clone();
+        *self.cert_mgr.lock().unwrap() = cert_mgr.clone();
+        if let Some(cert_mgr) = cert_mgr.as_ref() {
+            // DoT等其它tls入口复用这个证书管理器
+            set_shared_cert_manager(Arc::new(cert_mgr.clone()));
+        }
 
         // Start all servers
-        let bind = self.config.bind.clone().unwrap_or("0.0.0.0".to_string());
+        let bind = config.bind.clone().unwrap_or("0.0.0.0".to_string());
         let bind_addrs: Vec<&str> = bind.split(';').collect();
         for bind_addr in bind_addrs {
             let http_router = http_router.clone();
@@ -125,7 +129,7 @@ impl CyfsWarpServer {
                 bind_addr.to_string()
             };
 
-            let bind_addr_http = format!("{}:{}", formatted_bind_addr, self.config.http_port);
+            let bind_addr_http = format!("{}:{}", formatted_bind_addr, config.http_port);
             match Self::start_listen_http(bind_addr_http, http_router).await {
                 Ok(server_task) => {
                     self.http_servers.lock().await.push(server_task);
@@ -135,12 +139,13 @@ impl CyfsWarpServer {
                     error!("Failed to start HTTP server: {}", e);
                 }
             }
-            if self.config.tls_port > 0 {
-                let bind_addr_https = format!("{}:{}", formatted_bind_addr, self.config.tls_port);
+            if let Some(sni_resolver) = sni_resolver.as_ref() {
+                let bind_addr_https = format!("{}:{}", formatted_bind_addr, config.tls_port);
                 match Self::start_listen_https(
                     bind_addr_https,
                     https_router,
-                    &self.config,
+                    sni_resolver.clone(),
+                    cert_mgr.is_some(),
                 )
                 .await
                 {
@@ -158,18 +163,132 @@ impl CyfsWarpServer {
         Ok(())
     }
 
+    fn need_cert_mgr(config: &WarpServerConfig) -> bool {
+        config.tls_port > 0
+            && (config.hosts.values().any(|host_config| Self::is_acme_host(host_config))
+                || config.cert_mgr.as_ref().map(|c| !c.on_demand_suffixes.is_empty()).unwrap_or(false))
+    }
+
+    fn is_acme_host(host_config: &HostConfig) -> bool {
+        host_config.tls.enable_acme && !host_config.tls.disable_tls && host_config.tls.cert_path.is_none()
+    }
+
+    // listeners and the cert manager can't be changed without restart
+    pub fn can_update_in_place(&self, new_config: &WarpServerConfig) -> bool {
+        let config = self.config.read().unwrap();
+        config.bind == new_config.bind
+            && config.http_port == new_config.http_port
+            && config.tls_port == new_config.tls_port
+            && config.cert_mgr == new_config.cert_mgr
+            && Self::need_cert_mgr(&config) == Self::need_cert_mgr(new_config)
+    }
+
+    // 只更新变化的host,已经建立的连接不受影响
+    pub async fn update_config(&self, new_config: WarpServerConfig) -> Result<()> {
+        if !self.can_update_in_place(&new_config) {
+            return Err(anyhow::anyhow!("warp server listeners changed, need restart"));
+        }
+
+        let old_config = self.config.read().unwrap().clone();
+        let http_router = self.http_router.lock().unwrap().clone();
+        let https_router = self.https_router.lock().unwrap().clone();
+        if http_router.is_none() || https_router.is_none() {
+            // not started yet
+            *self.config.write().unwrap() = new_config;
+            return Ok(());
+        }
+        let http_router = http_router.unwrap();
+        let https_router = https_router.unwrap();
+
+        let empty_host = HostConfig::default();
+        let mut tls_changed = false;
+        let mut all_hosts: Vec<&String> = old_config.hosts.keys().chain(new_config.hosts.keys()).collect();
+        all_hosts.sort();
+        all_hosts.dedup();
+        for host in all_hosts {
+            let old_host = old_config.hosts.get(host);
+            let new_host = new_config.hosts.get(host);
+            if old_host == new_host {
+                continue;
+            }
+            info!("warp host {} config changed", host);
+            let old_host_config = old_host.unwrap_or(&empty_host);
+            let new_host_config = new_host.unwrap_or(&empty_host);
+            Self::update_host_routes(
+                &https_router,
+                host,
+                Self::https_host_routes(old_host_config),
+                Self::https_host_routes(new_host_config),
+            );
+            Self::update_host_routes(
+                &http_router,
+                host,
+                Self::http_host_routes(host, old_host_config),
+                Self::http_host_routes(host, new_host_config),
+            );
+
+            if old_host.map(|h| &h.tls) != new_host.map(|h| &h.tls) {
+                tls_changed = true;
+                let cert_mgr = self.cert_mgr.lock().unwrap().clone();
+                if let Some(cert_mgr) = cert_mgr {
+                    match new_host {
+                        Some(new_host) if Self::is_acme_host(new_host) => {
+                            if let Err(e) = cert_mgr.insert_config(host.clone(), new_host.tls.clone()) {
+                                error!("Failed to insert tls config for host: {}, {}", host, e);
+                            }
+                        }
+                        _ => {
+                            cert_mgr.remove_config(host);
+                        }
+                    }
+                }
+            }
+        }
+
+        if tls_changed {
+            let sni_resolver = self.sni_resolver.lock().unwrap().clone();
+            if let Some(sni_resolver) = sni_resolver {
+                sni_resolver.update_configs(Self::load_tls_configs(&new_config)?);
+            }
+        }
+
+        *self.config.write().unwrap() = new_config;
+        Ok(())
+    }
+
+    fn update_host_routes(
+        router: &Router,
+        host: &str,
+        old_routes: HashMap<String, Arc<RouteConfig>>,
+        new_routes: HashMap<String, Arc<RouteConfig>>,
+    ) {
+        for path in old_routes.keys() {
+            if !new_routes.contains_key(path) {
+                info!("remove route {}{}", host, path);
+                router.remove_route_config(host, path);
+            }
+        }
+        for (path, route_config) in new_routes {
+            if old_routes.get(&path) != Some(&route_config) {
+                info!("update route {}{}", host, path);
+                router.insert_route_config(host, &path, route_config.as_ref().clone());
+            }
+        }
+    }
+
     pub async fn stop(&self) -> Result<()> {
+        // 只停止accept,已经建立的连接在各自的task中继续运行
         let mut http_servers = self.http_servers.lock().await;
-        for server in http_servers.iter_mut() {
+        for server in http_servers.drain(..) {
             server.abort();
+            let _ = server.await;
         }
-        http_servers.clear();
 
         let mut https_servers = self.https_servers.lock().await;
-        for server in https_servers.iter_mut() {
+        for server in https_servers.drain(..) {
             server.abort();
+            let _ = server.await;
         }
-        https_servers.clear();
 
         Ok(())
     }
@@ -235,11 +354,50 @@ impl CyfsWarpServer {
         Ok(server_task)
     }
 
-    async fn start_listen_https(
-        https_bind_addr: String,
-        https_router: Router,
-        server_config: &WarpServerConfig,
-    ) -> Result<tokio::task::JoinHandle<()>> {
+    async fn create_cert_mgr(config: &WarpServerConfig, http_router: &Router) -> Option<CertManager<WarpChallengeEntry>> {
+        if !Self::need_cert_mgr(config) {
+            return None;
+        }
+        let acme_hosts: Vec<(&String, &HostConfig)> = config.hosts.iter()
+            .filter(|(_, host_config)| Self::is_acme_host(host_config))
+            .collect();
+
+        let mut cert_mgr_config = config.cert_mgr.clone().unwrap_or_default();
+        if cert_mgr_config.keystore_path.is_empty() {
+            let root_path = buckyos_kit::get_buckyos_service_data_dir("cyfs-warp");
+            info!("Will use cyfs-warp data directory: {}", root_path.display());
+            if !root_path.exists() {
+                info!("Creating cyfs-warp data directory: {}", root_path.display());
+                if let Err(e) = std::fs::create_dir_all(&root_path) {
+                    error!(
+                        "Failed to create cyfs-warp data directory: {}, {}",
+                        e,
+                        root_path.display()
+                    );
+                    return None;
+                }
+            }
+            cert_mgr_config.keystore_path = root_path.to_string_lossy().to_string();
+        }
+
+        let challenge_entry = create_challenge_entry(http_router.clone(), cert_mgr_config.acme_dns.as_ref());
+        let cert_mgr = match CertManager::new(cert_mgr_config, challenge_entry).await {
+            Ok(cert_mgr) => cert_mgr,
+            Err(e) => {
+                error!("Failed to create cert manager: {}", e);
+                return None;
+            }
+        };
+
+        for (host, host_config) in acme_hosts {
+            if let Err(e) = cert_mgr.insert_config(host.clone(), host_config.tls.clone()) {
+                error!("Failed to insert tls config for host: {}, {}", host, e);
+            }
+        }
+        Some(cert_mgr)
+    }
+
+    fn load_tls_configs(server_config: &WarpServerConfig) -> Result<HashMap<String, Arc<ServerConfig>>> {
         let mut tls_cfg_map = HashMap::new();
         for (host, host_config) in server_config.hosts.iter() {
             if host_config.tls.disable_tls {
@@ -279,13 +437,26 @@ impl CyfsWarpServer {
                 tls_cfg_map.insert(host.clone(), Arc::new(config));
             }
         }
+        Ok(tls_cfg_map)
+    }
 
-        let tls_cfg = Arc::new(
-            ServerConfig::builder()
-                .with_safe_defaults()
-                .with_no_client_auth()
-                .with_cert_resolver(Arc::new(SNIResolver::new(tls_cfg_map))),
-        );
+    async fn start_listen_https(
+        https_bind_addr: String,
+        https_router: Router,
+        sni_resolver: Arc<SNIResolver>,
+        enable_acme: bool,
+    ) -> Result<tokio::task::JoinHandle<()>> {
+        let mut tls_cfg = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(sni_resolver);
+        // hyper serves both http/1.1 and h2 on the same connection type
+        tls_cfg.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        if enable_acme {
+            // tls-alpn-01 验证需要协商出 acme-tls/1
+            tls_cfg.alpn_protocols.push(ACME_TLS_ALPN_PROTOCOL.to_vec());
+        }
+        let tls_cfg = Arc::new(tls_cfg);
         let tls_acceptor = TlsAcceptor::from(tls_cfg.clone());
         let listener = TcpListener::bind(https_bind_addr.clone()).await;
         if listener.is_err() {
@@ -4,35 +4,74 @@ mod router;
 mod http_server;
 mod ndn_router;
 mod cert;
+mod doh;
+mod upstream;
+mod route_filter;
 
 pub use router::*;
 pub use http_server::*;
+pub use upstream::UpstreamGroup;
 
 use anyhow::Result;
+use hyper::body::HttpBody;
+use hyper::{Body, Request};
 
 // 辅助函数：解析Range header
 pub fn parse_range(range: &str, file_size: u64) -> Result<(u64, u64)> {
+  let (start, end) = parse_range_without_size(range)?;
+  let end = end.unwrap_or(file_size.saturating_sub(1));
+
+  // 验证范围有效性
+  if start >= file_size || end >= file_size || start > end {
+      return Err(anyhow::anyhow!("Invalid range"));
+  }
+
+  Ok((start, end))
+}
+
+// 不知道文件大小时解析range,没有指定end时返回None
+pub fn parse_range_without_size(range: &str) -> Result<(u64, Option<u64>)> {
   // 解析 "bytes=start-end" 格式
   let range = range.trim_start_matches("bytes=");
   let mut parts = range.split('-');
-  
+
   let start = parts.next()
       .and_then(|s| s.parse::<u64>().ok())
       .unwrap_or(0);
 
-      
   let end = parts.next()
-      .and_then(|s| s.parse::<u64>().ok())
-      .unwrap_or(file_size - 1);
+      .and_then(|s| s.parse::<u64>().ok());
 
-  // 验证范围有效性
-  if start >= file_size || end >= file_size || start > end {
-      return Err(anyhow::anyhow!("Invalid range"));
+  if let Some(end) = end {
+      if start > end {
+          return Err(anyhow::anyhow!("Invalid range"));
+      }
   }
 
   Ok((start, end))
 }
 
+// 读取整个请求body,超过max_size时返回None:先检查Content-Length,再在读取过程中累计长度
+pub(crate) async fn read_body_with_limit(req: Request<Body>, max_size: u64) -> Result<Option<Vec<u8>>> {
+  let content_length = req.headers().get(hyper::header::CONTENT_LENGTH)
+      .and_then(|value| value.to_str().ok())
+      .and_then(|value| value.parse::<u64>().ok());
+  if content_length.is_some_and(|content_length| content_length > max_size) {
+      return Ok(None);
+  }
+
+  let mut body = req.into_body();
+  let mut body_bytes = Vec::new();
+  while let Some(data) = body.data().await {
+      let data = data.map_err(|e| anyhow::anyhow!("Failed to read request body: {}", e))?;
+      if (body_bytes.len() + data.len()) as u64 > max_size {
+          return Ok(None);
+      }
+      body_bytes.extend_from_slice(&data);
+  }
+  Ok(Some(body_bytes))
+}
+
 mod test {
     #![allow(unused)]
     use super::*;
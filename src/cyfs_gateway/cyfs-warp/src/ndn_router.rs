@@ -2,13 +2,18 @@ use buckyos_kit::get_by_json_path;
 use log::*;
 use anyhow::Result;
 use hyper::{Request,Response,Body,StatusCode};
+use hyper::body::HttpBody;
+use tokio::io::AsyncWriteExt;
 
 use std::{io::SeekFrom, sync::Arc};
+use std::collections::HashSet;
 use std::net::IpAddr;
+use lazy_static::lazy_static;
 use ndn_lib::*;
 use cyfs_gateway_lib::{NamedDataMgrRouteConfig};
 use serde_json::Value;
-use crate::parse_range;
+use crate::parse_range_without_size;
+use crate::read_body_with_limit;
 
 //1. get objid and inner path
 //2. if enable, try use relative path to get objid and inner path
@@ -76,7 +81,8 @@ pub struct InnerPathInfo {
     pub mtree_path:Option<String>,
 }
 
-async fn build_response_by_obj_get_result(obj_get_result:GetObjResult,start:u64,inner_path_info:Option<InnerPathInfo>)->Result<Response<Body>> {
+//range为请求的(start,end),end为None表示到结尾
+async fn build_response_by_obj_get_result(obj_get_result:GetObjResult,range:Option<(u64,Option<u64>)>,inner_path_info:Option<InnerPathInfo>)->Result<Response<Body>> {
     let body_result;
     let mut result = Response::builder()
                     .header("cyfs-obj-id", obj_get_result.real_obj_id.to_base32());
@@ -102,20 +108,30 @@ async fn build_response_by_obj_get_result(obj_get_result:GetObjResult,start:u64,
         }
         GetObjResultBody::Reader(chunk_reader,chunk_size) => {
 
-            let stream = tokio_util::io::ReaderStream::new(chunk_reader);
             result = result.header("Accept-Ranges", "bytes")
                 .header("Content-Type", "application/octet-stream")
                 .header("Cache-Control", "public,max-age=31536000")
                 .header("cyfs-obj-size", chunk_size.to_string());
-            if start > 0 {
-                result = result.header("Content-Range", format!("bytes {}-{}/{}", start, chunk_size - 1, chunk_size))
-                .header("Content-Length", chunk_size - start)
-                .status(StatusCode::PARTIAL_CONTENT);
-            } else {          
-                result = result.header("Content-Length", chunk_size)
-                .status(StatusCode::OK);
+            match range {
+                Some((start,end)) if chunk_size > 0 => {
+                    //range的end超过chunk大小时,返回到chunk结尾
+                    let last = end.map(|end| end.min(chunk_size - 1)).unwrap_or(chunk_size - 1);
+                    if start > last {
+                        return Err(anyhow::anyhow!("invalid range {}-{} for chunk size {}", start, last, chunk_size));
+                    }
+                    let stream = tokio_util::io::ReaderStream::new(tokio::io::AsyncReadExt::take(chunk_reader, last - start + 1));
+                    result = result.header("Content-Range", format!("bytes {}-{}/{}", start, last, chunk_size))
+                    .header("Content-Length", last - start + 1)
+                    .status(StatusCode::PARTIAL_CONTENT);
+                    body_result = result.body(Body::wrap_stream(stream))?;
+                },
+                _ => {
+                    let stream = tokio_util::io::ReaderStream::new(chunk_reader);
+                    result = result.header("Content-Length", chunk_size)
+                    .status(StatusCode::OK);
+                    body_result = result.body(Body::wrap_stream(stream))?;
+                }
             }
-            body_result = result.body(Body::wrap_stream(stream))?;
         }
         GetObjResultBody::TextRecord(text_record) => {
             result = result.header("Content-Type", "plain/text")
@@ -126,6 +142,32 @@ async fn build_response_by_obj_get_result(obj_get_result:GetObjResult,start:u64,
     Ok(body_result)
 }
 
+lazy_static! {
+    //正在写入的chunk,key为(named_mgr_id,chunk_id),同一个chunk同时只允许一个写入者
+    static ref PUTTING_CHUNKS: std::sync::Mutex<HashSet<(String,String)>> = std::sync::Mutex::new(HashSet::new());
+}
+
+struct ChunkPutGuard {
+    key:(String,String),
+}
+
+impl ChunkPutGuard {
+    fn try_acquire(named_mgr_id:&str, chunk_id:&ChunkId) -> Option<Self> {
+        let key = (named_mgr_id.to_string(), chunk_id.to_string());
+        let mut putting_chunks = PUTTING_CHUNKS.lock().unwrap();
+        if !putting_chunks.insert(key.clone()) {
+            return None;
+        }
+        Some(Self { key })
+    }
+}
+
+impl Drop for ChunkPutGuard {
+    fn drop(&mut self) {
+        PUTTING_CHUNKS.lock().unwrap().remove(&self.key);
+    }
+}
+
 pub async fn handle_chunk_put(mgr_config: &NamedDataMgrRouteConfig, req: Request<Body>, _host: &str, _client_ip:IpAddr,_route_path: &str) -> Result<Response<Body>> {
     if mgr_config.read_only {
         error!("Named manager is read only,cann't process put");
@@ -147,51 +189,147 @@ pub async fn handle_chunk_put(mgr_config: &NamedDataMgrRouteConfig, req: Request
     let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(Some(named_mgr_id.as_str())).await
         .ok_or_else(|| anyhow::anyhow!("Named manager not found: {}", named_mgr_id))?;
     
-    let named_mgr_lock = named_mgr.lock().await;
     let chunk_id = ChunkId::from_obj_id(&obj_id);
 
-    // 获取总大小
-    let total_size = req.headers()
-        .get("cyfs-chunk-size")
+    // 获取总大小,续传时从Content-Range中获取
+    let content_range = req.headers()
+        .get(hyper::header::CONTENT_RANGE)
         .and_then(|v| v.to_str().ok())
-        .and_then(|s| s.parse::<u64>().ok())
-        .unwrap_or(0);
-        // 如果是最后一块数据，完成写入
+        .map(|s| s.to_string());
+    let (range_start, range_end, total_size) = match content_range {
+        Some(content_range) => {
+            let (start, end, total) = parse_content_range(&content_range)
+                .map_err(|e| anyhow::anyhow!("parse content range failed: {}", e))?;
+            (start, end + 1, total)
+        }
+        None => {
+            let total_size = req.headers()
+                .get("cyfs-chunk-size")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|s| s.parse::<u64>().ok())
+                .or(chunk_id.get_length())
+                .unwrap_or(0);
+            (0, total_size, total_size)
+        }
+    };
+    if total_size > MAX_CHUNK_SIZE {
+        warn!("put chunk {} size {} exceeds max chunk size", chunk_id.to_string(), total_size);
+        return Ok(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(Body::from("chunk size exceeds max chunk size"))?);
+    }
+    // mix chunk id中带有长度,可以在接收数据之前拒绝长度不对的请求
+    if let Some(chunk_length) = chunk_id.get_length() {
+        if chunk_length != total_size {
+            warn!("put chunk {} size {} not match chunk id", chunk_id.to_string(), total_size);
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("chunk size not match chunk id"))?);
+        }
+    }
+
+    // 另一个连接正在写入这个chunk时返回409,客户端稍后从新的位置续传
+    let _put_guard = match ChunkPutGuard::try_acquire(&named_mgr_id, &chunk_id) {
+        Some(guard) => guard,
+        None => {
+            warn!("chunk {} is being put by another writer", chunk_id.to_string());
+            return Ok(Response::builder()
+                .status(StatusCode::CONFLICT)
+                .body(Body::from("chunk is being written by another writer"))?);
+        }
+    };
+
+    let named_mgr_lock = named_mgr.lock().await;
+    let (chunk_state, _, progress) = named_mgr_lock.query_chunk_state_impl(&chunk_id).await?;
+    if chunk_state == ChunkState::Completed {
+        info!("chunk {} already exists, skip put", chunk_id.to_string());
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CYFS_CHUNK_OFFSET_HEADER, total_size.to_string())
+            .body(Body::empty())?);
+    }
+
+    // 从上次保存的hasher状态继续,没有有效状态的未完成chunk只能重新开始
+    let mut chunk_hasher = None;
+    if chunk_state != ChunkState::NotExist {
+        chunk_hasher = serde_json::from_str::<Value>(&progress).ok()
+            .and_then(|state| ChunkHasher::restore_from_state(state).ok());
+        if chunk_hasher.is_none() {
+            named_mgr_lock.remove_chunk_impl(&chunk_id).await?;
+        }
+    }
+    let mut chunk_hasher = match chunk_hasher {
+        Some(hasher) => hasher,
+        None => ChunkHasher::new(Some(chunk_id.hash_type.as_str()))?,
+    };
+    let current_pos = chunk_hasher.pos;
+    if range_start != current_pos || range_end > total_size {
+        warn!("chunk {} put range {}-{} not match current pos {}", chunk_id.to_string(), range_start, range_end, current_pos);
+        return Ok(Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(CYFS_CHUNK_OFFSET_HEADER, current_pos.to_string())
+            .body(Body::empty())?);
+    }
 
     // 打开写入器
-    let (chunk_writer, _) = named_mgr_lock.open_chunk_writer_impl(&chunk_id, total_size, 0).await?;
+    let (mut chunk_writer, _) = named_mgr_lock.open_chunk_writer_impl(&chunk_id, total_size, current_pos).await?;
     drop(named_mgr_lock);
-    
-    // 读取整个请求体到内存
-    let body_bytes = hyper::body::to_bytes(req.into_body()).await
-        .map_err(|e| anyhow::anyhow!("Failed to read request body: {}", e))?;
-    
-    // 创建一个内存读取器
-    let chunk_reader = std::io::Cursor::new(body_bytes);
-    
-    // 使用 copy_chunk 函数
-    let write_result = ndn_lib::copy_chunk(
-        chunk_id.clone(), 
-        chunk_reader, 
-        chunk_writer, 
-        None, 
-        None::<fn(ChunkId, u64, &Option<ChunkHasher>) -> _>
-    ).await
-        .map_err(|e| {
-            warn!("Failed to copy chunk: {}", e);
-            anyhow::anyhow!("Failed to copy chunk: {}", e)
-        })?;
-    
-    if write_result == total_size {
-        let named_mgr_lock = named_mgr.lock().await;
-        named_mgr_lock.complete_chunk_writer_impl(&chunk_id).await?;
-    } else {
-        warn!("Failed to complete chunk: {}", write_result);
-        return Err(anyhow::anyhow!("Failed to complete chunk: {}", write_result));
+
+    // 边接收边写入,连接中断时保存已经写入部分的hasher状态,客户端可以从这里续传
+    let mut body = req.into_body();
+    let mut body_err = None;
+    while let Some(data) = body.data().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                body_err = Some(anyhow::anyhow!("Failed to read request body: {}", e));
+                break;
+            }
+        };
+        if chunk_hasher.pos + data.len() as u64 > range_end {
+            body_err = Some(anyhow::anyhow!("request body exceeds content range"));
+            break;
+        }
+        if let Err(e) = chunk_writer.write_all(&data).await {
+            body_err = Some(anyhow::anyhow!("Failed to write chunk: {}", e));
+            break;
+        }
+        chunk_hasher.update_from_bytes(&data);
     }
-    
+    chunk_writer.flush().await?;
+    drop(chunk_writer);
+
+    let named_mgr_lock = named_mgr.lock().await;
+    if chunk_hasher.pos > current_pos {
+        named_mgr_lock.update_chunk_progress_impl(&chunk_id, chunk_hasher.save_state().to_string()).await?;
+    }
+    if let Some(e) = body_err {
+        warn!("put chunk {} interrupted at {}: {}", chunk_id.to_string(), chunk_hasher.pos, e);
+        return Err(e);
+    }
+
+    if chunk_hasher.pos < total_size {
+        return Ok(Response::builder()
+            .status(StatusCode::ACCEPTED)
+            .header(CYFS_CHUNK_OFFSET_HEADER, chunk_hasher.pos.to_string())
+            .body(Body::empty())?);
+    }
+
+    // 全部数据收到后校验hash
+    let result_chunk_id = chunk_hasher.finalize_chunk_id();
+    if result_chunk_id != chunk_id {
+        warn!("put chunk {} hash mismatch: {}", chunk_id.to_string(), result_chunk_id.to_string());
+        named_mgr_lock.remove_chunk_impl(&chunk_id).await?;
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .header(CYFS_CHUNK_OFFSET_HEADER, "0")
+            .body(Body::from("chunk hash mismatch"))?);
+    }
+    named_mgr_lock.complete_chunk_writer_impl(&chunk_id).await?;
+
     return Ok(Response::builder()
         .status(StatusCode::OK)
+        .header(CYFS_CHUNK_OFFSET_HEADER, total_size.to_string())
         .body(Body::empty())?);
 }
 
@@ -236,6 +374,78 @@ pub async fn handle_chunk_status(mgr_config: &NamedDataMgrRouteConfig, req: Requ
 }
 
 
+fn payload_too_large(max_size: u64) -> Result<Response<Body>> {
+    warn!("request body exceeds {} bytes", max_size);
+    Ok(Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .body(Body::from(format!("request body exceeds {} bytes", max_size)))?)
+}
+
+//POST {route}/query_chunk_state_list, body: ChunkStateListReq
+//一次请求查询多个chunk的状态,返回结果的顺序与请求一致
+pub async fn handle_chunk_state_list(mgr_config: &NamedDataMgrRouteConfig, req: Request<Body>, _host: &str, _client_ip:IpAddr,_route_path: &str) -> Result<Response<Body>> {
+    let body_bytes = match read_body_with_limit(req, MAX_CHUNK_STATE_LIST_BODY_SIZE).await? {
+        Some(body_bytes) => body_bytes,
+        None => return payload_too_large(MAX_CHUNK_STATE_LIST_BODY_SIZE),
+    };
+    let state_req: ChunkStateListReq = serde_json::from_slice(&body_bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to parse chunk state list request: {}", e))?;
+
+    let mut chunk_list = Vec::with_capacity(state_req.chunk_list.len());
+    for chunk_id_str in state_req.chunk_list.iter() {
+        let chunk_id = ChunkId::new(chunk_id_str)
+            .map_err(|e| anyhow::anyhow!("Invalid chunk id {}: {}", chunk_id_str, e))?;
+        chunk_list.push(ChunkItem::new(&chunk_id, 0, None));
+    }
+
+    NamedDataMgr::query_chunk_state_by_list(Some(mgr_config.named_data_mgr_id.as_str()), &mut chunk_list).await?;
+    let resp = ChunkStateListResp {
+        chunk_list: chunk_list.into_iter().map(|chunk_item| ChunkStateListRespItem {
+            chunk_id: chunk_item.chunk_id.to_string(),
+            chunk_state: chunk_item.chunk_state.to_str(),
+            chunk_size: chunk_item.chunk_size,
+            progress: chunk_item.progress,
+        }).collect(),
+    };
+
+    return Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(&resp)?))?);
+}
+
+//POST {route}/put_chunk_list, body格式见 ndn_lib::encode_chunk_list_body
+pub async fn handle_chunk_list_put(mgr_config: &NamedDataMgrRouteConfig, req: Request<Body>, _host: &str, _client_ip:IpAddr,_route_path: &str) -> Result<Response<Body>> {
+    if mgr_config.read_only {
+        error!("Named manager is read only,cann't process put");
+        return Err(anyhow::anyhow!("Named manager is read only"));
+    }
+
+    if !mgr_config.enable_zone_put_chunk {
+        error!("Named manager is not enable zone put chunk");
+        return Err(anyhow::anyhow!("Named manager is not enable zone put chunk"));
+    }
+
+    let body_bytes = match read_body_with_limit(req, MAX_PUT_CHUNK_LIST_BODY_SIZE).await? {
+        Some(body_bytes) => body_bytes,
+        None => return payload_too_large(MAX_PUT_CHUNK_LIST_BODY_SIZE),
+    };
+    let chunk_list = decode_chunk_list_body(&body_bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to decode chunk list: {}", e))?;
+    let chunk_count = chunk_list.len();
+
+    NamedDataMgr::put_chunklist(Some(mgr_config.named_data_mgr_id.as_str()), chunk_list, true).await
+        .map_err(|e| {
+            warn!("Failed to put chunk list: {}", e);
+            anyhow::anyhow!("Failed to put chunk list: {}", e)
+        })?;
+    info!("put chunk list OK, chunk count:{}", chunk_count);
+
+    return Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::empty())?);
+}
+
 pub async fn handle_ndn_get(mgr_config: &NamedDataMgrRouteConfig, req: Request<Body>, host: &str, _client_ip:IpAddr,route_path: &str) -> Result<Response<Body>> {
     let named_mgr_id = mgr_config.named_data_mgr_id.clone();
     let named_mgr = NamedDataMgr::get_named_data_mgr_by_id(Some(named_mgr_id.as_str())).await;
@@ -247,16 +457,17 @@ pub async fn handle_ndn_get(mgr_config: &NamedDataMgrRouteConfig, req: Request<B
     let named_mgr = named_mgr.unwrap();
     let named_mgr2 = named_mgr.clone();
 
-    let range_str = req.headers().get(hyper::header::RANGE);
-    let mut start = 0;
-    if range_str.is_some() {
-        let range_str = range_str.unwrap().to_str().unwrap();
-        (start,_) = parse_range(range_str,u64::MAX)
+    let mut range = None;
+    if let Some(range_str) = req.headers().get(hyper::header::RANGE) {
+        let range_str = range_str.to_str()
+            .map_err(|e| anyhow::anyhow!("invalid range header: {}", e))?;
+        range = Some(parse_range_without_size(range_str)
             .map_err(|e| {
                 warn!("parse range failed: {}", e);
                 anyhow::anyhow!("parse range failed: {}", e)
-            })?;
+            })?);
     }
+    let start = range.map(|(start,_)| start).unwrap_or(0);
 
     //let chunk_id_result;
     let mut obj_id:Option<ObjId> = None;
@@ -360,12 +571,27 @@ pub async fn handle_ndn_get(mgr_config: &NamedDataMgrRouteConfig, req: Request<B
     }
     get_result.path_obj_jwt = path_obj_jwt;
     //info!("ndn_router:get_result.path_obj_jwt {:?}", get_result.path_obj_jwt.as_ref());
-    let response = build_response_by_obj_get_result(get_result, start,inner_path_obj).await?;
+    let response = build_response_by_obj_get_result(get_result, range, inner_path_obj).await?;
     Ok(response)
 }
 
 
 pub async fn handle_ndn(mgr_config: &NamedDataMgrRouteConfig, req: Request<Body>, host: &str, _client_ip:IpAddr,route_path: &str) -> Result<Response<Body>> {
+    if req.method() == hyper::Method::POST {
+        let sub_path = buckyos_kit::get_relative_path(route_path, req.uri().path());
+        match sub_path.trim_matches('/') {
+            CYFS_CHUNK_STATE_LIST_PATH => {
+                return handle_chunk_state_list(mgr_config, req, host, _client_ip, route_path).await;
+            }
+            CYFS_PUT_CHUNK_LIST_PATH => {
+                return handle_chunk_list_put(mgr_config, req, host, _client_ip, route_path).await;
+            }
+            _ => {
+                return Err(anyhow::anyhow!("Invalid ndn post path: {}", sub_path));
+            }
+        }
+    }
+
     if req.method() == hyper::Method::PUT || req.method() == hyper::Method::PATCH{
         return handle_chunk_put(mgr_config, req, host, _client_ip, route_path).await;
     }
@@ -387,6 +613,7 @@ mod tests {
     use super::*;
     use buckyos_kit::*;
     use rand::RngCore;
+    use std::collections::HashMap;
     use tokio::io::{AsyncReadExt,AsyncWriteExt};
     use crate::*;
     use serde_json::json;
@@ -400,6 +627,64 @@ mod tests {
         buffer
     }
 
+    //启动一个只有ndn router的cyfs-warp,路由到pub_mgr_id对应的named data mgr
+    async fn start_test_ndn_server(http_port: u16, pub_mgr_id: &str) {
+        let test_server_config = json!({
+            "tls_port":0,
+            "http_port":http_port,
+            "hosts": {
+              "*": {
+                "enable_cors":true,
+                "routes": {
+                  "/ndn/": {
+                    "named_mgr": {
+                        "named_data_mgr_id":pub_mgr_id,
+                        "read_only":false,
+                        "guest_access":true,
+                        "is_object_id_in_path":true,
+                        "enable_mgr_file_path":true,
+                        "enable_zone_put_chunk":true
+                    }
+                  }
+                }
+              }
+            }
+          });
+        let test_server_config:WarpServerConfig = serde_json::from_value(test_server_config).unwrap();
+        tokio::spawn(async move {
+            let _ = start_cyfs_warp_server(test_server_config).await;
+        });
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+
+    //在临时目录中创建named data mgr,并用mgr_id注册
+    async fn create_test_named_mgr(mgr_id: &str, root_path: &std::path::Path) -> Arc<tokio::sync::Mutex<NamedDataMgr>> {
+        create_test_named_mgr_with_cdc(mgr_id, root_path, None).await
+    }
+
+    async fn create_test_named_mgr_with_cdc(mgr_id: &str, root_path: &std::path::Path, pub_cdc_options: Option<CdcOptions>) -> Arc<tokio::sync::Mutex<NamedDataMgr>> {
+        let config = NamedDataMgrConfig {
+            local_stores: vec![root_path.join(mgr_id).to_str().unwrap().to_string()],
+            local_cache: None,
+            mmap_cache_dir: None,
+            pub_cdc_options,
+        };
+        let named_mgr = NamedDataMgr::from_config(Some(mgr_id.to_string()), root_path.to_path_buf(), config).await.unwrap();
+        NamedDataMgr::set_mgr_by_id(Some(mgr_id), named_mgr).await.unwrap();
+        NamedDataMgr::get_named_data_mgr_by_id(Some(mgr_id)).await.unwrap()
+    }
+
+    async fn put_test_chunk(named_mgr: &Arc<tokio::sync::Mutex<NamedDataMgr>>, chunk_data: &[u8]) -> ChunkId {
+        let mut hasher = ChunkHasher::new(None).unwrap();
+        let chunk_id = ChunkId::from_sha256_result(&hasher.calc_from_bytes(chunk_data));
+        let real_named_mgr = named_mgr.lock().await;
+        let (mut chunk_writer,_) = real_named_mgr.open_chunk_writer_impl(&chunk_id, chunk_data.len() as u64, 0).await.unwrap();
+        chunk_writer.write_all(chunk_data).await.unwrap();
+        drop(chunk_writer);
+        real_named_mgr.complete_chunk_writer_impl(&chunk_id).await.unwrap();
+        chunk_id
+    }
+
     #[tokio::test]
     async fn test_ndn_basic_op() {
         init_logging("ndn_client_test",false);
@@ -439,6 +724,7 @@ mod tests {
             local_stores: vec![temp_dir.path().to_str().unwrap().to_string()],
             local_cache: None,
             mmap_cache_dir: None,
+            pub_cdc_options: None,
         };
         
         let pub_named_mgr = NamedDataMgr::from_config(
@@ -503,6 +789,7 @@ mod tests {
             local_stores: vec![temp_dir.path().to_str().unwrap().to_string()],
             local_cache: None,
             mmap_cache_dir: None,
+            pub_cdc_options: None,
         };    
         let named_mgr2 = NamedDataMgr::from_config(
             Some("test_client".to_string()),
@@ -572,11 +859,401 @@ mod tests {
         let real_named_mgr_client = named_mgr_client.lock().await;
         let (mut _reader,len) = real_named_mgr_client.open_chunk_reader_impl(&chunk_id_c,SeekFrom::Start(0),false).await.unwrap();
         assert_eq!(len,chunk_c_size);
-        
+        drop(real_named_mgr_client);
     }
 
+    #[tokio::test]
+    async fn test_ndn_mix_chunk_put() {
+        start_test_ndn_server(3299, "test_mix_pub").await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let named_mgr_pub = create_test_named_mgr("test_mix_pub", temp_dir.path()).await;
+        let named_mgr_client = create_test_named_mgr("test_mix_client", temp_dir.path()).await;
+        let client = NdnClient::new("http://localhost:3299/ndn/".to_string(),None,Some("test_mix_client".to_string()));
+
+        let http_client = hyper::Client::new();
+        let chunk_h = generate_random_bytes(1024*1024*4 + 555);
+        let mut hasher = ChunkHasher::new(Some(MIX256_HASH_TYPE)).unwrap();
+        let chunk_id_h = hasher.calc_chunk_id_from_bytes(&chunk_h);
+        assert_eq!(chunk_id_h.get_length(), Some(chunk_h.len() as u64));
+        let real_named_mgr_client = named_mgr_client.lock().await;
+        let (mut chunk_writer,_) = real_named_mgr_client.open_chunk_writer_impl(&chunk_id_h, 0, 0).await.unwrap();
+        chunk_writer.write_all(&chunk_h).await.unwrap();
+        drop(chunk_writer);
+        real_named_mgr_client.complete_chunk_writer_impl(&chunk_id_h).await.unwrap();
+        drop(real_named_mgr_client);
 
-}
+        let chunk_h_url = format!("http://localhost:3299/ndn/{}", chunk_id_h.to_base32());
+        let resp = http_client.request(Request::put(chunk_h_url.as_str())
+            .header(hyper::header::CONTENT_RANGE, format!("bytes 0-99/{}", chunk_h.len() + 1))
+            .body(Body::from(chunk_h[..100].to_vec()))
+            .unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        client.push_chunk(chunk_id_h.clone(), None).await.unwrap();
+        let real_named_mgr_pub = named_mgr_pub.lock().await;
+        let (mut reader,len) = real_named_mgr_pub.open_chunk_reader_impl(&chunk_id_h, SeekFrom::Start(0), false).await.unwrap();
+        assert_eq!(len, chunk_h.len() as u64);
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, chunk_h);
+        drop(real_named_mgr_pub);
+
+        //mix chunk id中的长度超过MAX_CHUNK_SIZE时在接收数据之前拒绝
+        let huge_chunk_id = ChunkId::from_mix256_result(MAX_CHUNK_SIZE + 1, &[1u8; 32]);
+        let huge_chunk_url = format!("http://localhost:3299/ndn/{}", huge_chunk_id.to_base32());
+        let resp = http_client.request(Request::put(huge_chunk_url.as_str())
+            .body(Body::from(vec![0u8; 100]))
+            .unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let (chunk_state, _) = client.query_chunk_upload_offset(&huge_chunk_url).await.unwrap();
+        assert_eq!(chunk_state, ChunkState::NotExist);
+    }
+
+    #[tokio::test]
+    async fn test_ndn_chunk_list_batch() {
+        start_test_ndn_server(3290, "test_batch_pub").await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let named_mgr_pub = create_test_named_mgr("test_batch_pub", temp_dir.path()).await;
+        let named_mgr_client = create_test_named_mgr("test_batch_client", temp_dir.path()).await;
+        let chunk_id_a = put_test_chunk(&named_mgr_pub, &generate_random_bytes(4096)).await;
+        let client = NdnClient::new("http://localhost:3290/ndn/".to_string(),None,Some("test_batch_client".to_string()));
+
+        let real_named_mgr_client = named_mgr_client.lock().await;
+        let mut small_chunks = HashMap::new();
+        for i in 0..16 {
+            let small_chunk = generate_random_bytes(1024 + i);
+            let mut hasher = ChunkHasher::new(None).unwrap();
+            let small_chunk_id = ChunkId::from_sha256_result(&hasher.calc_from_bytes(&small_chunk));
+            small_chunks.insert(small_chunk_id, small_chunk);
+        }
+        let mut small_chunk_ids: Vec<ChunkId> = small_chunks.keys().cloned().collect();
+        real_named_mgr_client.put_chunklist_impl(small_chunks, true).await.unwrap();
+        drop(real_named_mgr_client);
 
+        small_chunk_ids.push(chunk_id_a.clone());
+        let missing_chunks = client.query_missing_chunks(&small_chunk_ids, None).await.unwrap();
+        assert_eq!(missing_chunks.len(), 16);
+        let push_count = client.push_chunk_list(&small_chunk_ids, None).await.unwrap();
+        assert_eq!(push_count, 16);
+        let missing_chunks = client.query_missing_chunks(&small_chunk_ids, None).await.unwrap();
+        assert!(missing_chunks.is_empty());
+        let real_named_mgr_pub = named_mgr_pub.lock().await;
+        for chunk_id in small_chunk_ids.iter() {
+            assert!(real_named_mgr_pub.is_chunk_exist_impl(chunk_id).await.unwrap());
+        }
+        drop(real_named_mgr_pub);
+
+        //超过上限的body直接被拒绝,不会读入内存
+        let http_client = hyper::Client::new();
+        let put_url = format!("http://localhost:3290/ndn/{}", CYFS_PUT_CHUNK_LIST_PATH);
+        let resp = http_client.request(Request::post(put_url.as_str())
+            .header(hyper::header::CONTENT_LENGTH, (MAX_PUT_CHUNK_LIST_BODY_SIZE + 1).to_string())
+            .body(Body::empty())
+            .unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let piece = hyper::body::Bytes::from(vec![0u8; 1024*1024]);
+            for _ in 0..(MAX_PUT_CHUNK_LIST_BODY_SIZE / (1024*1024) + 1) {
+                if sender.send_data(piece.clone()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let resp = http_client.request(Request::post(put_url.as_str()).body(body).unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
 
+    #[tokio::test]
+    async fn test_ndn_chunked_fileobj() {
+        start_test_ndn_server(3291, "test_chunked_pub").await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        create_test_named_mgr("test_chunked_pub", temp_dir.path()).await;
+        create_test_named_mgr("test_chunked_client", temp_dir.path()).await;
+        let mut client = NdnClient::new("http://localhost:3291/ndn/".to_string(),None,Some("test_chunked_client".to_string()));
+        client.force_trust_remote = true;
 
+        let big_file_data = generate_random_bytes(256*1024*3 + 1234);
+        let big_file_path = temp_dir.path().join("big_file.bin");
+        tokio::fs::write(&big_file_path, &big_file_data).await.unwrap();
+        let mut big_file_obj = FileObject::new("big_file.bin".to_string(), 0, "".to_string());
+        NamedDataMgr::pub_local_file_as_chunked_fileobj(Some("test_chunked_pub"), &big_file_path, "/test/big_file",
+            256*1024, &mut big_file_obj, "test_user", "test_app").await.unwrap();
+        assert!(big_file_obj.is_chunked());
+        assert_eq!(big_file_obj.get_fixed_chunk_list().unwrap().len(), 4);
+
+        let range_data = client.download_fileobj_range("http://localhost:3291/ndn/test/big_file", 256*1024 - 100..256*1024*2 + 100).await.unwrap();
+        assert_eq!(range_data, big_file_data[256*1024 - 100..256*1024*2 + 100].to_vec());
+        let download_path = temp_dir.path().join("download_big_file.bin");
+        client.download_fileobj_to_local("http://localhost:3291/ndn/test/big_file", &download_path, None).await.unwrap();
+        let download_data = tokio::fs::read(&download_path).await.unwrap();
+        assert_eq!(download_data, big_file_data);
+
+        //本地文件按chunk_list比较,内容一致才认为本地更好
+        assert!(client.local_is_better("http://localhost:3291/ndn/test/big_file", &download_path).await.unwrap());
+        let mut changed_data = big_file_data.clone();
+        changed_data[256*1024*2 + 10] ^= 0xff;
+        tokio::fs::write(&download_path, &changed_data).await.unwrap();
+        assert!(!client.local_is_better("http://localhost:3291/ndn/test/big_file", &download_path).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_ndn_cdc_fileobj() {
+        start_test_ndn_server(3292, "test_cdc_pub").await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cdc_options = CdcOptions { min_size: 16*1024, avg_size: 64*1024, max_size: 256*1024 };
+        let named_mgr_pub = create_test_named_mgr_with_cdc("test_cdc_pub", temp_dir.path(), Some(cdc_options)).await;
+        create_test_named_mgr("test_cdc_client", temp_dir.path()).await;
+        let mut client = NdnClient::new("http://localhost:3292/ndn/".to_string(),None,Some("test_cdc_client".to_string()));
+        client.force_trust_remote = true;
+
+        let big_file_data = generate_random_bytes(256*1024*3 + 1234);
+        let big_file_path = temp_dir.path().join("big_file.bin");
+        tokio::fs::write(&big_file_path, &big_file_data).await.unwrap();
+        let mut cdc_file_obj = FileObject::new("cdc_file.bin".to_string(), 0, "".to_string());
+        NamedDataMgr::pub_local_file_as_fileobj(Some("test_cdc_pub"), &big_file_path, "/test/cdc_file", "/test/cdc_file/content",
+            &mut cdc_file_obj, "test_user", "test_app").await.unwrap();
+        let cdc_chunk_list = cdc_file_obj.get_cdc_chunk_list().unwrap();
+        assert!(cdc_chunk_list.len() > 1);
+        assert_eq!(cdc_chunk_list.iter().map(|(_, size)| *size).sum::<u64>(), big_file_data.len() as u64);
+
+        let download_path = temp_dir.path().join("download_cdc_file.bin");
+        client.download_fileobj_to_local("http://localhost:3292/ndn/test/cdc_file", &download_path, None).await.unwrap();
+        let download_data = tokio::fs::read(&download_path).await.unwrap();
+        assert_eq!(download_data, big_file_data);
+        assert!(client.local_is_better("http://localhost:3292/ndn/test/cdc_file", &download_path).await.unwrap());
+
+        //content path仍然可以按整个chunk读取
+        let (mut reader,cyfs_resp) = client.open_chunk_reader_by_url("http://localhost:3292/ndn/test/cdc_file/content",None,None).await.unwrap();
+        let mut buffer = vec![0u8;big_file_data.len()];
+        reader.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(cyfs_resp.obj_size.unwrap(),big_file_data.len() as u64);
+        assert_eq!(buffer,big_file_data);
+
+        //chunk_list里只有chunk id,gc能正常遍历cdc FileObject并保留它引用的chunk
+        let gc_options = GcOptions {
+            grace_period: 0,
+            ..Default::default()
+        };
+        NamedDataMgr::gc(Some("test_cdc_pub"), &gc_options).await.unwrap();
+        let real_named_mgr_pub = named_mgr_pub.lock().await;
+        for (chunk_id, _) in cdc_chunk_list.iter() {
+            assert!(real_named_mgr_pub.is_chunk_exist_impl(chunk_id).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ndn_multi_source_download() {
+        start_test_ndn_server(3293, "test_multi_pub").await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let named_mgr_pub = create_test_named_mgr("test_multi_pub", temp_dir.path()).await;
+        let named_mgr_client = create_test_named_mgr("test_multi_client", temp_dir.path()).await;
+        let client = NdnClient::new("http://localhost:3293/ndn/".to_string(),None,Some("test_multi_client".to_string()));
+
+        let mut pub_chunks = Vec::new();
+        for chunk_size in [1024*1024*3 + 777u64, 1024*300 + 13] {
+            let chunk_data = generate_random_bytes(chunk_size);
+            let mut hasher = ChunkHasher::new(None).unwrap();
+            let chunk_id = ChunkId::from_sha256_result(&hasher.calc_from_bytes(&chunk_data));
+            let real_named_mgr_pub = named_mgr_pub.lock().await;
+            let (mut chunk_writer,_) = real_named_mgr_pub.open_chunk_writer_impl(&chunk_id, chunk_size, 0).await.unwrap();
+            chunk_writer.write_all(&chunk_data).await.unwrap();
+            drop(chunk_writer);
+            real_named_mgr_pub.complete_chunk_writer_impl(&chunk_id).await.unwrap();
+            pub_chunks.push((chunk_id, chunk_data));
+        }
+        let (chunk_id_d, chunk_d) = pub_chunks[0].clone();
+        let (chunk_id_e, chunk_e) = pub_chunks[1].clone();
+
+        //模拟中断的下载:客户端已经写入了一部分数据并保存了进度
+        let partial_size = 1024*512 + 99;
+        let real_named_mgr_client = named_mgr_client.lock().await;
+        let (mut chunk_writer,_) = real_named_mgr_client.open_chunk_writer_impl(&chunk_id_d, chunk_d.len() as u64, 0).await.unwrap();
+        chunk_writer.write_all(&chunk_d[..partial_size]).await.unwrap();
+        drop(chunk_writer);
+        let mut hasher = ChunkHasher::new(None).unwrap();
+        hasher.update_from_bytes(&chunk_d[..partial_size]);
+        real_named_mgr_client.update_chunk_progress_impl(&chunk_id_d, hasher.save_state().to_string()).await.unwrap();
+        drop(real_named_mgr_client);
+
+        //3294上没有服务,用来测试failover
+        let sources = vec!["http://localhost:3294/ndn/".to_string(), "http://localhost:3293/ndn/".to_string()];
+        let options = MultiSourceOptions {
+            piece_size: 256*1024,
+            save_progress_interval: 2,
+            ..Default::default()
+        };
+        let download_size = client.download_chunk_multi_source(&chunk_id_d, &sources, &options).await.unwrap();
+        assert_eq!(download_size, (chunk_d.len() - partial_size) as u64);
+        let download_size = client.download_chunk_list_multi_source(&vec![chunk_id_d.clone(), chunk_id_e.clone()], &sources, &options).await.unwrap();
+        assert_eq!(download_size, chunk_e.len() as u64);
+
+        let real_named_mgr_client = named_mgr_client.lock().await;
+        for (chunk_id, chunk_data) in [(&chunk_id_d, &chunk_d), (&chunk_id_e, &chunk_e)] {
+            let (mut reader,_) = real_named_mgr_client.open_chunk_reader_impl(chunk_id, SeekFrom::Start(0), false).await.unwrap();
+            let mut buffer = Vec::new();
+            reader.read_to_end(&mut buffer).await.unwrap();
+            assert_eq!(&buffer, chunk_data);
+        }
+        drop(real_named_mgr_client);
+    }
+
+    #[tokio::test]
+    async fn test_ndn_range_response_check() {
+        start_test_ndn_server(3295, "test_range_pub").await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let named_mgr_pub = create_test_named_mgr("test_range_pub", temp_dir.path()).await;
+        let chunk_data = generate_random_bytes(1024*64);
+        let chunk_id = put_test_chunk(&named_mgr_pub, &chunk_data).await;
+        let client = NdnClient::new("http://localhost:3295/ndn/".to_string(),None,None);
+
+        //请求range时即使是整个chunk也返回206
+        let chunk_url = client.gen_chunk_url(&chunk_id, None);
+        let (mut reader,_) = client.open_chunk_reader_by_url(&chunk_url, Some(chunk_id.clone()), Some(0..chunk_data.len() as u64)).await.unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, chunk_data);
+        let (mut reader,_) = client.open_chunk_reader_by_url(&chunk_url, Some(chunk_id.clone()), Some(100..200)).await.unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, chunk_data[100..200].to_vec());
+
+        //忽略Range返回200的服务端
+        let full_data = chunk_data.clone();
+        let make_svc = hyper::service::make_service_fn(move |_| {
+            let full_data = full_data.clone();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |_req| {
+                    let full_data = full_data.clone();
+                    async move {
+                        Ok::<_, hyper::Error>(Response::builder()
+                            .status(StatusCode::OK)
+                            .header("Content-Length", full_data.len())
+                            .body(Body::from(full_data)).unwrap())
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:3296".parse().unwrap()).serve(make_svc);
+        tokio::spawn(server);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let bad_url = client.gen_chunk_url(&chunk_id, Some("http://127.0.0.1:3296/ndn/".to_string()));
+        let result = client.open_chunk_reader_by_url(&bad_url, Some(chunk_id.clone()), Some(100..200)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ndn_resumable_put() {
+        start_test_ndn_server(3297, "test_put_pub").await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let named_mgr_pub = create_test_named_mgr("test_put_pub", temp_dir.path()).await;
+        let named_mgr_client = create_test_named_mgr("test_put_client", temp_dir.path()).await;
+        let client = NdnClient::new("http://localhost:3297/ndn/".to_string(),None,Some("test_put_client".to_string()));
+
+        let chunk_f_size:u64 = 1024*1024*5 + 4321;
+        let chunk_f = generate_random_bytes(chunk_f_size);
+        let mut hasher = ChunkHasher::new(None).unwrap();
+        let chunk_id_f = ChunkId::from_sha256_result(&hasher.calc_from_bytes(&chunk_f));
+        let real_named_mgr_client = named_mgr_client.lock().await;
+        let (mut chunk_writer,_) = real_named_mgr_client.open_chunk_writer_impl(&chunk_id_f, chunk_f_size, 0).await.unwrap();
+        chunk_writer.write_all(&chunk_f).await.unwrap();
+        drop(chunk_writer);
+        real_named_mgr_client.complete_chunk_writer_impl(&chunk_id_f).await.unwrap();
+        drop(real_named_mgr_client);
+
+        //模拟上传到一半中断
+        let chunk_f_url = format!("http://localhost:3297/ndn/{}", chunk_id_f.to_base32());
+        let http_client = hyper::Client::new();
+        let put_range = |start:u64, end:u64, data:Vec<u8>| {
+            Request::put(chunk_f_url.as_str())
+                .header(hyper::header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end - 1, chunk_f_size))
+                .body(Body::from(data))
+                .unwrap()
+        };
+        let resp = http_client.request(put_range(0, 1024*1024, chunk_f[..1024*1024].to_vec())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        let (chunk_state, offset) = client.query_chunk_upload_offset(&chunk_f_url).await.unwrap();
+        assert_eq!(chunk_state, ChunkState::Incompleted);
+        assert_eq!(offset, 1024*1024);
+
+        //起点与服务端不一致时返回服务端的位置
+        let resp = http_client.request(put_range(100, 200, chunk_f[100..200].to_vec())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(resp.headers().get(CYFS_CHUNK_OFFSET_HEADER).unwrap().to_str().unwrap(), (1024*1024).to_string());
+
+        client.push_chunk(chunk_id_f.clone(), None).await.unwrap();
+        let real_named_mgr_pub = named_mgr_pub.lock().await;
+        let (mut reader,len) = real_named_mgr_pub.open_chunk_reader_impl(&chunk_id_f, SeekFrom::Start(0), false).await.unwrap();
+        assert_eq!(len, chunk_f_size);
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, chunk_f);
+        drop(real_named_mgr_pub);
+
+        //数据与chunk id不一致时不会complete
+        let bad_data = generate_random_bytes(2048);
+        let mut hasher = ChunkHasher::new(None).unwrap();
+        let chunk_id_g = ChunkId::from_sha256_result(&hasher.calc_from_bytes(&bad_data));
+        let mut bad_data = bad_data;
+        bad_data[0] ^= 0xff;
+        let chunk_g_url = format!("http://localhost:3297/ndn/{}", chunk_id_g.to_base32());
+        let resp = http_client.request(Request::put(chunk_g_url.as_str())
+            .header("cyfs-chunk-size", "2048")
+            .body(Body::from(bad_data))
+            .unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let (chunk_state, _) = client.query_chunk_upload_offset(&chunk_g_url).await.unwrap();
+        assert_eq!(chunk_state, ChunkState::NotExist);
+    }
+
+    #[tokio::test]
+    async fn test_ndn_concurrent_put() {
+        start_test_ndn_server(3298, "test_concurrent_put_pub").await;
+        let temp_dir = tempfile::tempdir().unwrap();
+        let named_mgr_pub = create_test_named_mgr("test_concurrent_put_pub", temp_dir.path()).await;
+        let client = NdnClient::new("http://localhost:3298/ndn/".to_string(),None,None);
+
+        let chunk_size:u64 = 1024*256;
+        let chunk_data = generate_random_bytes(chunk_size);
+        let mut hasher = ChunkHasher::new(None).unwrap();
+        let chunk_id = ChunkId::from_sha256_result(&hasher.calc_from_bytes(&chunk_data));
+        let chunk_url = format!("http://localhost:3298/ndn/{}", chunk_id.to_base32());
+        let http_client = hyper::Client::new();
+
+        //第一个写入者发送一部分数据后保持连接
+        let (mut body_sender, body) = Body::channel();
+        let first_put = http_client.request(Request::put(chunk_url.as_str())
+            .header("cyfs-chunk-size", chunk_size.to_string())
+            .body(body)
+            .unwrap());
+        let first_put = tokio::spawn(first_put);
+        body_sender.send_data(chunk_data[..1024*64].to_vec().into()).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        //第二个写入者在第一个完成之前被拒绝
+        let resp = http_client.request(Request::put(chunk_url.as_str())
+            .header("cyfs-chunk-size", chunk_size.to_string())
+            .body(Body::from(chunk_data.clone()))
+            .unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        //第一个写入者结束后,从它保存的位置续传
+        drop(body_sender);
+        let resp = first_put.await.unwrap().unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(resp.headers().get(CYFS_CHUNK_OFFSET_HEADER).unwrap().to_str().unwrap(), (1024*64).to_string());
+        let resp = http_client.request(Request::put(chunk_url.as_str())
+            .header(hyper::header::CONTENT_RANGE, format!("bytes {}-{}/{}", 1024*64, chunk_size - 1, chunk_size))
+            .body(Body::from(chunk_data[1024*64..].to_vec()))
+            .unwrap()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let (chunk_state, _) = client.query_chunk_upload_offset(&chunk_url).await.unwrap();
+        assert_eq!(chunk_state, ChunkState::Completed);
+
+        let real_named_mgr_pub = named_mgr_pub.lock().await;
+        let (mut reader,_) = real_named_mgr_pub.open_chunk_reader_impl(&chunk_id, SeekFrom::Start(0), false).await.unwrap();
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(buffer, chunk_data);
+    }
+
+}
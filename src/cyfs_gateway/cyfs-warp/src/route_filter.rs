@@ -0,0 +1,561 @@
+// route filters: rate limit, body size limit, auth gate, path rewrite and header rules
+
+use cyfs_gateway_lib::*;
+use futures::StreamExt;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::http::uri::PathAndQuery;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::DecodingKey;
+use ::kRPC::*;
+use log::*;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+// 超过这个数量时清理已经回满的bucket
+const MAX_RATE_LIMIT_KEYS: usize = 10000;
+// 从kRPC请求body里读取token时最多读取的大小
+const MAX_KRPC_BODY_SIZE: u64 = 1024 * 1024;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+fn error_response(status: StatusCode, msg: &str) -> Response<Body> {
+    let mut resp = Response::new(Body::from(msg.to_string()));
+    *resp.status_mut() = status;
+    resp
+}
+
+fn get_bearer_token(req: &Request<Body>) -> Option<String> {
+    let value = req.headers().get(hyper::header::AUTHORIZATION)?.to_str().ok()?;
+    value.strip_prefix("Bearer ").map(|token| token.trim().to_string())
+}
+
+// 预检请求不带认证信息,开启cors的路由需要在过滤器之前直接应答
+pub(crate) fn is_cors_preflight(req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS
+        && req.headers().contains_key(hyper::header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// "sha256:<hex>" => digest
+fn parse_basic_users(users: &HashMap<String, String>) -> HashMap<String, Vec<u8>> {
+    let mut result = HashMap::new();
+    for (user, password) in users.iter() {
+        let digest = password
+            .strip_prefix("sha256:")
+            .and_then(|digest| hex::decode(digest).ok())
+            .filter(|digest| digest.len() == ring::digest::SHA256_OUTPUT_LEN);
+        match digest {
+            Some(digest) => {
+                result.insert(user.clone(), digest);
+            }
+            None => error!("password of basic auth user {} must be sha256:<hex>", user),
+        }
+    }
+    result
+}
+
+pub(crate) fn apply_header_rules(headers: &mut hyper::HeaderMap, rules: &HeaderRulesConfig) {
+    for name in rules.remove.iter() {
+        headers.remove(name.as_str());
+    }
+
+    for (name, value) in rules.set.iter() {
+        let name = HeaderName::from_bytes(name.as_bytes());
+        let value = HeaderValue::from_str(value);
+        match (name, value) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => warn!("invalid header rule: {:?}", rules),
+        }
+    }
+}
+
+// "/api/v1/x" strip "/api" add "/app" => "/app/v1/x", query is kept
+// strip_prefix matches whole path segments, "/apiv2/x" is not stripped by "/api"
+pub(crate) fn rewrite_path(path: &str, rewrite: &RewriteConfig) -> String {
+    let mut path = path;
+    if let Some(strip_prefix) = rewrite.strip_prefix.as_ref() {
+        let strip_prefix = strip_prefix.trim_end_matches('/');
+        if let Some(rest) = path.strip_prefix(strip_prefix) {
+            if rest.is_empty() || rest.starts_with('/') {
+                path = rest;
+            }
+        }
+    }
+
+    let mut new_path = rewrite.add_prefix.clone().unwrap_or_default();
+    if new_path.ends_with('/') && path.starts_with('/') {
+        new_path.pop();
+    }
+    if !path.is_empty() && !path.starts_with('/') && !new_path.ends_with('/') {
+        new_path.push('/');
+    }
+    new_path.push_str(path);
+
+    if !new_path.starts_with('/') {
+        new_path.insert(0, '/');
+    }
+    new_path
+}
+
+fn rewrite_uri(uri: &Uri, rewrite: &RewriteConfig) -> Option<Uri> {
+    let mut path_and_query = rewrite_path(uri.path(), rewrite);
+    if let Some(query) = uri.query() {
+        path_and_query.push('?');
+        path_and_query.push_str(query);
+    }
+
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(path_and_query).ok()?);
+    Uri::from_parts(parts).ok()
+}
+
+// token bucket for each client ip or session token
+struct RateLimiter {
+    key: RateLimitKey,
+    rate: f64, // tokens per second
+    burst: f64,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    fn new(config: &RateLimitConfig) -> Self {
+        let period = config.period.max(1) as f64;
+        Self {
+            key: config.key.clone(),
+            rate: config.requests as f64 / period,
+            burst: config.burst.unwrap_or(config.requests).max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Err is the seconds to wait
+    fn check(&self, key: &str) -> std::result::Result<(), u64> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MAX_RATE_LIMIT_KEYS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, (tokens, last)| {
+                *tokens + now.duration_since(*last).as_secs_f64() * rate < burst
+            });
+        }
+
+        let (tokens, last) = buckets.entry(key.to_string()).or_insert((self.burst, now));
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+        *last = now;
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else if self.rate > 0.0 {
+            Err(((1.0 - *tokens) / self.rate).ceil() as u64)
+        } else {
+            Err(1)
+        }
+    }
+}
+
+pub(crate) struct RouteFilter {
+    config: Arc<RouteConfig>,
+    rate_limiter: Option<RateLimiter>,
+    trust_keys: HashMap<String, DecodingKey>,
+    basic_users: HashMap<String, Vec<u8>>,
+}
+
+impl RouteFilter {
+    pub fn need_filter(config: &RouteConfig) -> bool {
+        config.rewrite.is_some()
+            || config.request_headers.is_some()
+            || config.rate_limit.is_some()
+            || config.max_body_size.is_some()
+            || config.auth.is_some()
+    }
+
+    pub fn new(config: Arc<RouteConfig>) -> Self {
+        let rate_limiter = config.rate_limit.as_ref().map(RateLimiter::new);
+
+        let mut trust_keys = HashMap::new();
+        if let Some(RouteAuthConfig::KRPCToken { trust_keys: keys, .. }) = config.auth.as_ref() {
+            for (kid, jwk) in keys.iter() {
+                let key = serde_json::from_value::<Jwk>(jwk.clone())
+                    .map_err(|e| e.to_string())
+                    .and_then(|jwk| DecodingKey::from_jwk(&jwk).map_err(|e| e.to_string()));
+                match key {
+                    Ok(key) => {
+                        trust_keys.insert(kid.clone(), key);
+                    }
+                    Err(e) => error!("invalid trust key {} in route auth config: {}", kid, e),
+                }
+            }
+        }
+
+        let basic_users = match config.auth.as_ref() {
+            Some(RouteAuthConfig::Basic { users, .. }) => parse_basic_users(users),
+            _ => HashMap::new(),
+        };
+
+        Self {
+            config,
+            rate_limiter,
+            trust_keys,
+            basic_users,
+        }
+    }
+
+    fn check_rate_limit(&self, key: &str) -> std::result::Result<(), Response<Body>> {
+        let rate_limiter = match self.rate_limiter.as_ref() {
+            Some(rate_limiter) => rate_limiter,
+            None => return Ok(()),
+        };
+        if let Err(retry_after) = rate_limiter.check(key) {
+            warn!("rate limit exceeded: {}", key);
+            let mut resp = error_response(StatusCode::TOO_MANY_REQUESTS, "Too many requests");
+            resp.headers_mut()
+                .insert(hyper::header::RETRY_AFTER, HeaderValue::from(retry_after));
+            return Err(resp);
+        }
+        Ok(())
+    }
+
+    // Err is the response to reply directly
+    pub async fn filter_request(
+        &self,
+        req: Request<Body>,
+        client_ip: IpAddr,
+    ) -> std::result::Result<Request<Body>, Response<Body>> {
+        // 按session token限流时只能使用认证通过的token,否则任意伪造的token都有自己的bucket
+        let limit_by_token = self
+            .rate_limiter
+            .as_ref()
+            .map(|r| r.key == RateLimitKey::SessionToken)
+            .unwrap_or(false);
+        if !limit_by_token {
+            self.check_rate_limit(&client_ip.to_string())?;
+        }
+
+        let mut req = req;
+        if let Some(max_body_size) = self.config.max_body_size {
+            req = Self::limit_body_size(req, max_body_size)?;
+        }
+
+        let mut verified_token = None;
+        if let Some(auth) = self.config.auth.as_ref() {
+            match self.check_auth(req, auth).await {
+                Ok((auth_req, token)) => {
+                    req = auth_req;
+                    verified_token = token;
+                }
+                Err(resp) => {
+                    if limit_by_token {
+                        self.check_rate_limit(&client_ip.to_string())?;
+                    }
+                    return Err(resp);
+                }
+            }
+        }
+        if limit_by_token {
+            let key = verified_token.unwrap_or_else(|| client_ip.to_string());
+            self.check_rate_limit(&key)?;
+        }
+
+        let (mut parts, body) = req.into_parts();
+        if let Some(rewrite) = self.config.rewrite.as_ref() {
+            match rewrite_uri(&parts.uri, rewrite) {
+                Some(uri) => {
+                    debug!("rewrite {} => {}", parts.uri, uri);
+                    parts.uri = uri;
+                }
+                None => warn!("rewrite {} failed, {:?}", parts.uri, rewrite),
+            }
+        }
+        if let Some(rules) = self.config.request_headers.as_ref() {
+            apply_header_rules(&mut parts.headers, rules);
+        }
+
+        Ok(Request::from_parts(parts, body))
+    }
+
+    pub fn filter_response(config: &RouteConfig, resp: &mut Response<Body>) {
+        if let Some(rules) = config.response_headers.as_ref() {
+            apply_header_rules(resp.headers_mut(), rules);
+        }
+    }
+
+    fn limit_body_size(
+        req: Request<Body>,
+        max_body_size: u64,
+    ) -> std::result::Result<Request<Body>, Response<Body>> {
+        let content_length = req
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if let Some(content_length) = content_length {
+            if content_length > max_body_size {
+                return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large"));
+            }
+            return Ok(req);
+        }
+
+        // chunked body, abort when the received size exceeds the limit
+        let (parts, body) = req.into_parts();
+        let mut received = 0u64;
+        let body = body.map(move |chunk| -> std::result::Result<_, BoxError> {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            if received > max_body_size {
+                return Err(format!("request body exceeds {} bytes", max_body_size).into());
+            }
+            Ok(chunk)
+        });
+        Ok(Request::from_parts(parts, Body::wrap_stream(body)))
+    }
+
+    // Ok返回认证通过的session token
+    async fn check_auth(
+        &self,
+        req: Request<Body>,
+        auth: &RouteAuthConfig,
+    ) -> std::result::Result<(Request<Body>, Option<String>), Response<Body>> {
+        match auth {
+            RouteAuthConfig::Basic { realm, .. } => {
+                if Self::verify_basic_auth(&req, &self.basic_users) {
+                    return Ok((req, None));
+                }
+
+                let realm = realm.as_deref().unwrap_or("cyfs-warp");
+                let mut resp = error_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+                if let Ok(value) = HeaderValue::from_str(&format!("Basic realm=\"{}\"", realm)) {
+                    resp.headers_mut().insert(hyper::header::WWW_AUTHENTICATE, value);
+                }
+                Err(resp)
+            }
+            RouteAuthConfig::KRPCToken { appids, .. } => {
+                let (req, token) = Self::get_krpc_token(req).await?;
+                let token = token.ok_or_else(|| {
+                    error_response(StatusCode::UNAUTHORIZED, "Session token required")
+                })?;
+
+                let mut session_token = RPCSessionToken::from_string(&token).map_err(|e| {
+                    warn!("invalid session token: {}", e);
+                    error_response(StatusCode::UNAUTHORIZED, "Invalid session token")
+                })?;
+                session_token.verify_by_key_map(&self.trust_keys).map_err(|e| {
+                    warn!("verify session token failed: {}", e);
+                    error_response(StatusCode::UNAUTHORIZED, "Invalid session token")
+                })?;
+
+                if let Some(appids) = appids.as_ref() {
+                    let appid = session_token.appid.as_deref().unwrap_or_default();
+                    if !appids.iter().any(|id| id == appid) {
+                        warn!("appid {} is not allowed", appid);
+                        return Err(error_response(StatusCode::FORBIDDEN, "Forbidden"));
+                    }
+                }
+                Ok((req, Some(token)))
+            }
+        }
+    }
+
+    fn verify_basic_auth(req: &Request<Body>, users: &HashMap<String, Vec<u8>>) -> bool {
+        use base64::Engine;
+
+        let value = req
+            .headers()
+            .get(hyper::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "));
+        let decoded = value
+            .and_then(|v| base64::engine::general_purpose::STANDARD.decode(v.trim()).ok())
+            .and_then(|v| String::from_utf8(v).ok());
+
+        match decoded.as_deref().and_then(|v| v.split_once(':')) {
+            Some((user, password)) => {
+                let digest = ring::digest::digest(&ring::digest::SHA256, password.as_bytes());
+                users
+                    .get(user)
+                    .map(|expected| constant_time_eq(digest.as_ref(), expected))
+                    .unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    // token in Authorization header first, then the token field of kRPC request body
+    async fn get_krpc_token(
+        req: Request<Body>,
+    ) -> std::result::Result<(Request<Body>, Option<String>), Response<Body>> {
+        if let Some(token) = get_bearer_token(&req) {
+            return Ok((req, Some(token)));
+        }
+        if req.method() != Method::POST {
+            return Ok((req, None));
+        }
+
+        let content_length = req
+            .headers()
+            .get(hyper::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if content_length.map(|len| len > MAX_KRPC_BODY_SIZE).unwrap_or(false) {
+            return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large"));
+        }
+
+        let (parts, mut body) = req.into_parts();
+        let mut body_bytes = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|e| {
+                warn!("read request body failed: {}", e);
+                error_response(StatusCode::PAYLOAD_TOO_LARGE, "Failed to read request body")
+            })?;
+            if (body_bytes.len() + chunk.len()) as u64 > MAX_KRPC_BODY_SIZE {
+                return Err(error_response(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large"));
+            }
+            body_bytes.extend_from_slice(&chunk);
+        }
+        let token = serde_json::from_slice::<RPCRequest>(&body_bytes)
+            .ok()
+            .and_then(|rpc_request| rpc_request.token);
+
+        Ok((Request::from_parts(parts, Body::from(body_bytes)), token))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_path() {
+        let rewrite = RewriteConfig {
+            strip_prefix: Some("/api".to_string()),
+            add_prefix: None,
+        };
+        assert_eq!(rewrite_path("/api/v1/x", &rewrite), "/v1/x");
+        assert_eq!(rewrite_path("/api", &rewrite), "/");
+        assert_eq!(rewrite_path("/other", &rewrite), "/other");
+        assert_eq!(rewrite_path("/apiv2/x", &rewrite), "/apiv2/x");
+
+        let rewrite = RewriteConfig {
+            strip_prefix: Some("/api/".to_string()),
+            add_prefix: None,
+        };
+        assert_eq!(rewrite_path("/api/v1/x", &rewrite), "/v1/x");
+        assert_eq!(rewrite_path("/apiv2/x", &rewrite), "/apiv2/x");
+
+        let rewrite = RewriteConfig {
+            strip_prefix: Some("/api".to_string()),
+            add_prefix: Some("/app/".to_string()),
+        };
+        assert_eq!(rewrite_path("/api/v1/x", &rewrite), "/app/v1/x");
+
+        let uri: Uri = "/api/v1?a=1".parse().unwrap();
+        assert_eq!(rewrite_uri(&uri, &rewrite).unwrap().to_string(), "/app/v1?a=1");
+    }
+
+    fn route_filter(json: &str) -> RouteFilter {
+        let config: RouteConfig = serde_json::from_str(json).unwrap();
+        RouteFilter::new(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        use base64::Engine;
+
+        let digest = hex::encode(ring::digest::digest(&ring::digest::SHA256, b"secret"));
+        let filter = route_filter(&format!(
+            r#"{{"auth": {{"type": "basic", "users": {{"admin": "sha256:{}", "plain": "secret"}}}}}}"#,
+            digest
+        ));
+        let client_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let request = |user: &str, password: &str| {
+            let value = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+            Request::builder()
+                .uri("/")
+                .header(hyper::header::AUTHORIZATION, format!("Basic {}", value))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert!(filter.filter_request(request("admin", "secret"), client_ip).await.is_ok());
+        let resp = filter.filter_request(request("admin", "wrong"), client_ip).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        // 明文密码的用户被忽略
+        assert!(filter.filter_request(request("plain", "secret"), client_ip).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_session_token_rate_limit() {
+        let filter = route_filter(
+            r#"{
+                "rate_limit": {"key": "session_token", "requests": 1, "period": 60},
+                "auth": {"type": "krpc_token", "trust_keys": {}}
+            }"#,
+        );
+        let client_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let request = |token: &str| {
+            Request::builder()
+                .uri("/")
+                .header(hyper::header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // 未通过认证的token都计入client ip
+        let resp = filter.filter_request(request("token1"), client_ip).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = filter.filter_request(request("token2"), client_ip).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_krpc_token_body_limit() {
+        let filter = route_filter(r#"{"auth": {"type": "krpc_token", "trust_keys": {}}}"#);
+        let client_ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let chunk = hyper::body::Bytes::from(vec![b' '; 64 * 1024]);
+            while sender.send_data(chunk.clone()).await.is_ok() {}
+        });
+        let req = Request::builder().method(Method::POST).uri("/").body(body).unwrap();
+        let resp = filter.filter_request(req, client_ip).await.unwrap_err();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_cors_preflight() {
+        let req = Request::builder()
+            .method(Method::OPTIONS)
+            .uri("/")
+            .header(hyper::header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .body(Body::empty())
+            .unwrap();
+        assert!(is_cors_preflight(&req));
+        let req = Request::builder().method(Method::OPTIONS).uri("/").body(Body::empty()).unwrap();
+        assert!(!is_cors_preflight(&req));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            key: RateLimitKey::ClientIp,
+            requests: 2,
+            period: 60,
+            burst: None,
+        });
+        assert!(limiter.check("1.1.1.1").is_ok());
+        assert!(limiter.check("1.1.1.1").is_ok());
+        assert!(limiter.check("1.1.1.1").is_err());
+        assert!(limiter.check("2.2.2.2").is_ok());
+    }
+}
@@ -19,6 +19,7 @@ use std::{net::SocketAddr, sync::Arc};
 use std::path::Path;
 use std::collections::HashMap;
 use cyfs_gateway_lib::*;
+use crate::cert::WarpChallengeEntry;
 use tokio::sync::{Mutex, OnceCell};
 use serde_json::json;
 use ::kRPC::*;
@@ -29,6 +30,9 @@ use cyfs_sn::*;
 use ndn_lib::*;
 
 use crate::ndn_router::*;
+use crate::doh::*;
+use crate::upstream::*;
+use crate::route_filter::*;
 use crate::*;
 
 lazy_static!{
@@ -59,6 +63,10 @@ impl Clone for Router {
 struct RouterInner {
     hosts: RwLock<HashMap<String, HashMap<String, Arc<RouteConfig>> >>,
     inner_service: OnceCell<Box<dyn InnerServiceHandler + Send + Sync> >,
+    // upstream groups created on first request, key is (host, route path)
+    upstream_groups: RwLock<HashMap<(String, String), Arc<UpstreamGroup>>>,
+    // rate limit buckets and auth keys of each route, key is (host, route path)
+    route_filters: RwLock<HashMap<(String, String), Arc<RouteFilter>>>,
 }
 
 impl Router {
@@ -67,30 +75,33 @@ impl Router {
             inner: Arc::new(RouterInner {
                 hosts: RwLock::new(hosts),
                 inner_service: OnceCell::new(),
+                upstream_groups: RwLock::new(HashMap::new()),
+                route_filters: RwLock::new(HashMap::new()),
             })
         }
     }
 
 
-    fn get_route_config(&self, host:&str, path:&str) -> Option<(String, Arc<RouteConfig>)> {
+    // return (matched host key, route path, route config)
+    fn get_route_config(&self, host:&str, path:&str) -> Option<(String, String, Arc<RouteConfig>)> {
         let hosts = self.inner.hosts.read().unwrap();
         let host_config = {
-            let host_config = hosts.get(host);
+            let host_config = hosts.get_key_value(host);
             if host_config.is_some() {
                 host_config
             } else {
-                let mut host_config =  hosts.get("*");
+                let mut host_config =  hosts.get_key_value("*");
                 for (key,value) in hosts.iter() {
                     if key.starts_with("*.") {
                         if host.ends_with(&key[1..]) {
-                            host_config = Some(value);
+                            host_config = Some((key, value));
                             break;
                         }
                     }
         
                     if key.ends_with(".*") {
                         if host.starts_with(&key[..key.len()-1]) {
-                            host_config = Some(value);
+                            host_config = Some((key, value));
                             break;
                         }
                     }
@@ -104,7 +115,7 @@ impl Router {
             return None;
         }
 
-        let host_config = host_config.unwrap();
+        let (host_key, host_config) = host_config.unwrap();
         debug!("host_config: {:?}", host_config);
 
         host_config
@@ -113,18 +124,47 @@ impl Router {
                 path.starts_with(*route)
             })
             .max_by_key(|(route, _)| route.len())
-            .map(|(route, config)| (route.clone(), config.clone()))
+            .map(|(route, config)| (host_key.clone(), route.clone(), config.clone()))
+    }
+
+    fn get_upstream_group(&self, host_key:&str, route_path:&str, config: &UpstreamGroupConfig) -> Arc<UpstreamGroup> {
+        let key = (host_key.to_string(), route_path.to_string());
+        if let Some(group) = self.inner.upstream_groups.read().unwrap().get(&key) {
+            return group.clone();
+        }
+
+        let mut groups = self.inner.upstream_groups.write().unwrap();
+        groups.entry(key).or_insert_with(|| UpstreamGroup::new(config.clone())).clone()
+    }
+
+    fn get_route_filter(&self, host_key:&str, route_path:&str, config: &Arc<RouteConfig>) -> Option<Arc<RouteFilter>> {
+        if !RouteFilter::need_filter(config) {
+            return None;
+        }
+
+        let key = (host_key.to_string(), route_path.to_string());
+        if let Some(filter) = self.inner.route_filters.read().unwrap().get(&key) {
+            return Some(filter.clone());
+        }
+
+        let mut filters = self.inner.route_filters.write().unwrap();
+        Some(filters.entry(key).or_insert_with(|| Arc::new(RouteFilter::new(config.clone()))).clone())
     }
 
     pub fn insert_route_config(&self, host:&str, path:&str, config: RouteConfig) -> Option<Arc<RouteConfig>> {
         let mut hosts = self.inner.hosts.write().unwrap();
         let host_config = hosts.entry(host.to_string()).or_insert(HashMap::new());
+        // the upstream group and filter are rebuilt with the new config on next request
+        self.inner.upstream_groups.write().unwrap().remove(&(host.to_string(), path.to_string()));
+        self.inner.route_filters.write().unwrap().remove(&(host.to_string(), path.to_string()));
         host_config.insert(path.to_string(), Arc::new(config))
     }
 
     pub fn remove_route_config(&self, host:&str, path:&str) -> Option<Arc<RouteConfig>> {
         let mut hosts = self.inner.hosts.write().unwrap();
         let host_config = hosts.entry(host.to_string()).or_insert(HashMap::new());
+        self.inner.upstream_groups.write().unwrap().remove(&(host.to_string(), path.to_string()));
+        self.inner.route_filters.write().unwrap().remove(&(host.to_string(), path.to_string()));
         host_config.remove(path)
     }
 
@@ -134,12 +174,7 @@ impl Router {
         req: Request<Body>,
         client_ip:SocketAddr,
     ) -> Result<Response<Body>> {
-        let mut host = req
-            .headers()
-            .get("host")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or_default()
-            .to_string();
+        let mut host = get_request_host(&req).unwrap_or_default();
 
         if host.len() > 1 {
             let result = host.split_once(':');
@@ -149,6 +184,7 @@ impl Router {
         }
         let req_path = req.uri().path();
         let req_method = req.method();
+        let client_addr = client_ip;
         let client_ip = client_ip.ip();
         info!("{}==> {} {},{:?}",client_ip.to_string(),req_method,req_path,req.headers());
 
@@ -160,54 +196,76 @@ impl Router {
                 .body(Body::from("Route not found"))?);
         }
 
-        let (route_path, route_config) = route_config.unwrap();
+        let (host_key, route_path, route_config) = route_config.unwrap();
         debug!("route_config: {:?}", route_config);
 
-        let real_resp = match &*route_config {
-            RouteConfig {
-                response: Some(response),
-                ..
-            } => {
-                let mut builder = Response::builder()
-                    .status(response.status.unwrap_or(200));
-                if let Some(headers) = &response.headers {
-                    for (key, value) in headers.iter() {
-                        builder = builder.header(key, value);
-                    }
-                }
-                let body = response.body.clone().unwrap_or_default();
-                let resp = builder.body(Body::from(body))?;
-                Ok(resp)
+        // rate limit, body size limit, auth, rewrite and request headers
+        let filtered = if route_config.enable_cors
+            && (is_cors_preflight(&req)
+                || (route_config.inner_service.is_some() && req.method() == hyper::Method::OPTIONS))
+        {
+            Err(Response::builder().status(StatusCode::OK).body(Body::empty())?)
+        } else {
+            match self.get_route_filter(&host_key, &route_path, &route_config) {
+                Some(filter) => filter.filter_request(req, client_ip).await,
+                None => Ok(req),
             }
-            RouteConfig {
-                upstream: Some(upstream),
-                ..
-            } => self.handle_upstream(req, upstream).await,
-            RouteConfig {
-                local_dir: Some(local_dir),
-                ..
-            } => self.handle_local_dir(req, local_dir.as_str(),route_path.as_str()).await,
-            RouteConfig {
-                inner_service: Some(inner_service),
-                ..
-            } => {
-                if route_config.enable_cors && req.method() == hyper::Method::OPTIONS {
-                    Ok(Response::builder()
-                        .status(StatusCode::OK)
-                        .body(Body::empty())?)
-                } else {
-                    self.handle_inner_service(inner_service.as_str(),req,client_ip).await
+        };
+
+        let real_resp = match filtered {
+            Err(resp) => Ok(resp),
+            Ok(req) => match &*route_config {
+                RouteConfig {
+                    response: Some(response),
+                    ..
+                } => {
+                    let mut builder = Response::builder()
+                        .status(response.status.unwrap_or(200));
+                    if let Some(headers) = &response.headers {
+                        for (key, value) in headers.iter() {
+                            builder = builder.header(key, value);
+                        }
+                    }
+                    let body = response.body.clone().unwrap_or_default();
+                    let resp = builder.body(Body::from(body))?;
+                    Ok(resp)
                 }
+                RouteConfig {
+                    upstream: Some(upstream),
+                    ..
+                } => {
+                    if let Some(group) = upstream.group.as_ref() {
+                        let group = self.get_upstream_group(&host_key, &route_path, group);
+                        group.forward(req, client_ip).await
+                    } else {
+                        self.handle_upstream(req, upstream).await
+                    }
+                },
+                RouteConfig {
+                    local_dir: Some(local_dir),
+                    ..
+                } => self.handle_local_dir(req, local_dir.as_str(),route_path.as_str()).await,
+                RouteConfig {
+                    inner_service: Some(inner_service),
+                    ..
+                } => {
+                    if let Some(dns_handler) = get_dns_message_handler(inner_service.as_str()) {
+                        // inner_service指向cyfs-dns server时,这个路由提供DoH
+                        handle_doh(dns_handler, req, client_addr).await
+                    } else {
+                        self.handle_inner_service(inner_service.as_str(),req,client_ip).await
+                    }
+                },
+                RouteConfig {
+                    tunnel_selector: Some(tunnel_selector),
+                    ..
+                } => self.handle_upstream_selector(tunnel_selector.as_str(), req, &host,  client_ip).await,
+                RouteConfig {
+                    named_mgr: Some(named_mgr),
+                    ..
+                } => handle_ndn(named_mgr, req, &host,  client_ip,route_path.as_str()).await,
+                _ => Err(anyhow::anyhow!("Invalid route configuration")),
             },
-            RouteConfig {
-                tunnel_selector: Some(tunnel_selector),
-                ..
-            } => self.handle_upstream_selector(tunnel_selector.as_str(), req, &host,  client_ip).await,
-            RouteConfig {
-                named_mgr: Some(named_mgr),
-                ..
-            } => handle_ndn(named_mgr, req, &host,  client_ip,route_path.as_str()).await,
-            _ => Err(anyhow::anyhow!("Invalid route configuration")),
         }.map(|mut resp| {
             if route_config.enable_cors {
                 //info!("enable cors for route: {}",route_path);
@@ -217,6 +275,7 @@ impl Router {
                 header.insert(hyper::header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST, OPTIONS"));
                 header.insert(hyper::header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("Content-Type, Authorization"));
             }
+            RouteFilter::filter_response(&route_config, &mut resp);
             resp
         });
 
@@ -298,7 +357,7 @@ impl Router {
             if tunnel_url.is_some() {
                 let tunnel_url = tunnel_url.unwrap();
                 info!("select tunnel: {}",tunnel_url.as_str());
-                return self.handle_upstream(req, &UpstreamRouteConfig{target:tunnel_url, redirect:RedirectType::None}).await;
+                return self.handle_upstream(req, &UpstreamRouteConfig{target:tunnel_url, redirect:RedirectType::None, group:None}).await;
             }
         } else {
             warn!("No sn server found for selector: {}",selector_id);
@@ -308,7 +367,7 @@ impl Router {
     }
 
     async fn handle_upstream(&self, req: Request<Body>, upstream: &UpstreamRouteConfig) -> Result<Response<Body>> {
-        let org_url = req.uri().to_string();
+        let org_url = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string();
         let url = format!("{}{}", upstream.target, org_url);
         info!("handle_upstream url: {}", url);
         let upstream_url = Url::parse(upstream.target.as_str());
@@ -322,17 +381,7 @@ impl Router {
             "tcp"|"http"|"https" => {
                 match &upstream.redirect {
                     RedirectType::None => {
-                        let client = Client::new();
-                        let header = req.headers().clone();
-                        let mut upstream_req = Request::builder()
-                        .method(req.method())
-                        .uri(&url)
-                        .body(req.into_body())?;
-
-                        *upstream_req.headers_mut() = header;
-                    
-                        let resp = client.request(upstream_req).await?;
-                        return Ok(resp)
+                        return forward_to_target(upstream.target.as_str(), req).await;
                     }, 
                     RedirectType::Permanent => {
                         let resp = Response::builder()
@@ -351,28 +400,7 @@ impl Router {
                 }
             },
             _ => {
-                let tunnel_connector = TunnelConnector {
-                    target_stream_url: upstream.target.clone(),
-                };
-
-                let client: Client<TunnelConnector, Body> = Client::builder()
-                    .build::<_, hyper::Body>(tunnel_connector);
-
-                let header = req.headers().clone();
-                let mut host_name = "127.0.0.1".to_string();
-                let hname =  req.headers().get("host");
-                if hname.is_some() {
-                    host_name = hname.unwrap().to_str().unwrap().to_string();
-                }
-                let fake_url = format!("http://{}{}", host_name, org_url);
-                let mut upstream_req = Request::builder()
-                    .method(req.method())
-                    .uri(fake_url)
-                    .body(req.into_body())?;
-
-                *upstream_req.headers_mut() = header;
-                let resp = client.request(upstream_req).await?;
-                return Ok(resp)
+                return forward_to_target(upstream.target.as_str(), req).await;
             }
         }
 
@@ -447,31 +475,42 @@ impl Router {
 }
 
 pub struct SNIResolver {
-    configs: HashMap<String, Arc<ServerConfig>>,
+    configs: RwLock<HashMap<String, Arc<ServerConfig>>>,
+    cert_mgr: Option<CertManager<WarpChallengeEntry>>,
 }
 
 impl SNIResolver {
     pub fn new(configs: HashMap<String, Arc<ServerConfig>>) -> Self {
-        SNIResolver { configs }
+        Self::new_with_cert_mgr(configs, None)
+    }
+
+    pub(crate) fn new_with_cert_mgr(configs: HashMap<String, Arc<ServerConfig>>, cert_mgr: Option<CertManager<WarpChallengeEntry>>) -> Self {
+        SNIResolver { configs: RwLock::new(configs), cert_mgr }
     }
 
-    fn get_config_by_host(&self,host:&str) -> Option<&Arc<ServerConfig>> {
-        let host_config = self.configs.get(host);
+    // new handshakes use the new configs, established tls connections are not affected
+    pub fn update_configs(&self, configs: HashMap<String, Arc<ServerConfig>>) {
+        *self.configs.write().unwrap() = configs;
+    }
+
+    fn get_config_by_host(&self,host:&str) -> Option<Arc<ServerConfig>> {
+        let configs = self.configs.read().unwrap();
+        let host_config = configs.get(host);
         if host_config.is_some() {
             debug!("find tls config for host: {}",host);
-            return host_config;
+            return host_config.cloned();
         }
 
-        for (key,value) in self.configs.iter() {
+        for (key,value) in configs.iter() {
             if key.starts_with("*.") {
                 if host.ends_with(&key[2..]) {
                     debug!("find tls config for host: {} ==> key:{}",host,key);
-                    return Some(value);
+                    return Some(value.clone());
                 }
             }
         }
 
-        return self.configs.get("*");
+        None
     }
 }
 
@@ -485,7 +524,26 @@ impl rustls::server::ResolvesServerCert for SNIResolver {
         let server_name = server_name.unwrap();
         debug!("try reslove tls certifiled key for : {}", server_name);
 
+        if let Some(cert_mgr) = self.cert_mgr.as_ref() {
+            if is_tls_alpn_challenge(&client_hello) {
+                return cert_mgr.get_tls_alpn_challenge_cert(server_name);
+            }
+        }
+
         let config = self.get_config_by_host(&server_name);
+        if config.is_some() {
+            return config.unwrap().cert_resolver.resolve(client_hello);
+        }
+
+        // acme 申请的证书和按需申请的证书
+        if let Some(cert_mgr) = self.cert_mgr.as_ref() {
+            let cert = cert_mgr.get_cert(server_name);
+            if cert.is_some() {
+                return cert;
+            }
+        }
+
+        let config = self.configs.read().unwrap().get("*").cloned();
         if config.is_some() {
             return config.unwrap().cert_resolver.resolve(client_hello);
         } else {
@@ -0,0 +1,852 @@
+// upstream group: load balancing, health check and retry
+
+use anyhow::Result;
+use cyfs_gateway_lib::*;
+use hyper::body::HttpBody;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, Client, Method, Request, Response, StatusCode, Version};
+use log::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+// consistent hash环上每单位权重的虚拟节点数
+const VIRTUAL_NODES_PER_WEIGHT: u32 = 40;
+// 没有配置health_check时,被摘除的target过多久重新参与调度
+const PASSIVE_EJECT_DURATION: Duration = Duration::from_secs(10);
+const PASSIVE_MAX_FAILS: u32 = 3;
+// 权重决定hash环上的虚拟节点数,需要限制上限
+const MAX_TARGET_WEIGHT: u32 = 100;
+// 重试需要缓存请求body,超过这个大小或者长度未知的请求不重试
+const MAX_REPLAY_BODY_SIZE: u64 = 1024 * 1024;
+
+// host header for http/1, :authority for http/2
+pub(crate) fn get_request_host(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.to_string())
+        .or_else(|| req.uri().authority().map(|a| a.to_string()))
+}
+
+// websocket and other http/1.1 upgrades
+fn is_upgrade_request(req: &Request<Body>) -> bool {
+    if req.version() != Version::HTTP_11 || !req.headers().contains_key(hyper::header::UPGRADE) {
+        return false;
+    }
+    req.headers()
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")))
+}
+
+// grpc需要http2的trailers,使用h2c(prior knowledge)转发到upstream
+fn is_grpc_request(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with("application/grpc"))
+        .unwrap_or(false)
+}
+
+// least_conn的连接计数,响应body转发完或者升级后的连接关闭时才释放
+struct ActiveGuard(Arc<AtomicUsize>);
+
+impl ActiveGuard {
+    fn new(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(active.clone())
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// 通过channel转发响应body,保留grpc需要的trailers
+fn track_body(mut body: Body, guard: ActiveGuard) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+
+    let (mut sender, tracked) = Body::channel();
+    tokio::task::spawn(async move {
+        let _guard = guard;
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(data) => {
+                    if sender.send_data(data).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    debug!("read upstream response body failed: {}", e);
+                    sender.abort();
+                    return;
+                }
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    tracked
+}
+
+// copy data between the upgraded client connection and upstream connection
+async fn bridge_upgraded(
+    target: String,
+    client: OnUpgrade,
+    upstream: OnUpgrade,
+    _guard: Option<ActiveGuard>,
+) {
+    let (mut client, mut upstream) = match tokio::try_join!(client, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            warn!("upgrade connection to upstream {} failed: {}", target, e);
+            return;
+        }
+    };
+
+    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((sent, received)) => {
+            debug!("upgraded connection to {} closed, sent {}, received {}", target, sent, received);
+        }
+        Err(e) => {
+            debug!("upgraded connection to {} closed with error: {}", target, e);
+        }
+    }
+}
+
+// forward the request to a http(s) url or a stream url (rtcp://...)
+pub(crate) async fn forward_to_target(target: &str, req: Request<Body>) -> Result<Response<Body>> {
+    forward_with_guard(target, req, None).await
+}
+
+async fn forward_with_guard(
+    target: &str,
+    mut req: Request<Body>,
+    guard: Option<ActiveGuard>,
+) -> Result<Response<Body>> {
+    let upstream_url = Url::parse(target)
+        .map_err(|e| anyhow::anyhow!("Failed to parse upstream url {}: {}", target, e))?;
+    let client_upgrade = if is_upgrade_request(&req) {
+        Some(hyper::upgrade::on(&mut req))
+    } else {
+        None
+    };
+    let use_h2 = is_grpc_request(&req);
+    let version = if use_h2 { Version::HTTP_2 } else { Version::HTTP_11 };
+    // http/2 request uri is absolute form
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or("/".to_string());
+    let host_name = get_request_host(&req).unwrap_or("127.0.0.1".to_string());
+    // http/2请求没有host header,转发给http/1.1的upstream时hyper会用upstream的地址填充
+    if !req.headers().contains_key(hyper::header::HOST) {
+        if let Some(authority) = req.uri().authority().cloned() {
+            if let Ok(value) = hyper::header::HeaderValue::from_str(authority.as_str()) {
+                req.headers_mut().insert(hyper::header::HOST, value);
+            }
+        }
+    }
+    let (parts, body) = req.into_parts();
+
+    let mut resp = match upstream_url.scheme() {
+        "tcp" | "http" | "https" => {
+            let url = format!("{}{}", target, path_and_query);
+            let client = Client::builder().http2_only(use_h2).build_http();
+            let mut upstream_req = Request::builder()
+                .method(parts.method)
+                .uri(&url)
+                .version(version)
+                .body(body)?;
+            *upstream_req.headers_mut() = parts.headers;
+
+            client.request(upstream_req).await?
+        }
+        _ => {
+            let tunnel_connector = TunnelConnector {
+                target_stream_url: target.to_string(),
+            };
+            let client: Client<TunnelConnector, Body> = Client::builder()
+                .http2_only(use_h2)
+                .build::<_, hyper::Body>(tunnel_connector);
+
+            let fake_url = format!("http://{}{}", host_name, path_and_query);
+            let mut upstream_req = Request::builder()
+                .method(parts.method)
+                .uri(fake_url)
+                .version(version)
+                .body(body)?;
+            *upstream_req.headers_mut() = parts.headers;
+
+            client.request(upstream_req).await?
+        }
+    };
+
+    if let Some(client_upgrade) = client_upgrade {
+        if resp.status() == StatusCode::SWITCHING_PROTOCOLS {
+            // 101 is sent to client by hyper, then both sides are bridged
+            let upstream_upgrade = hyper::upgrade::on(&mut resp);
+            tokio::task::spawn(bridge_upgraded(target.to_string(), client_upgrade, upstream_upgrade, guard));
+            return Ok(resp);
+        }
+    }
+
+    match guard {
+        Some(guard) => Ok(resp.map(|body| track_body(body, guard))),
+        None => Ok(resp),
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE | Method::TRACE
+    )
+}
+
+fn hash_of<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct UpstreamTarget {
+    url: String,
+    weight: u32,
+    healthy: AtomicBool,
+    fails: AtomicU32,
+    passes: AtomicU32,
+    // in-flight requests, used by least_conn
+    active: Arc<AtomicUsize>,
+    ejected_at: Mutex<Option<Instant>>,
+}
+
+impl UpstreamTarget {
+    fn new(config: &UpstreamTargetConfig) -> Self {
+        if config.weight > MAX_TARGET_WEIGHT {
+            warn!("upstream target {} weight {} is capped to {}", config.url, config.weight, MAX_TARGET_WEIGHT);
+        }
+        Self {
+            url: config.url.clone(),
+            weight: config.weight.clamp(1, MAX_TARGET_WEIGHT),
+            healthy: AtomicBool::new(true),
+            fails: AtomicU32::new(0),
+            passes: AtomicU32::new(0),
+            active: Arc::new(AtomicUsize::new(0)),
+            ejected_at: Mutex::new(None),
+        }
+    }
+
+    fn is_available(&self, active_check: bool) -> bool {
+        if self.healthy.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        // 没有主动健康检查时,摘除一段时间后再试
+        if !active_check {
+            let ejected_at = self.ejected_at.lock().unwrap();
+            if let Some(ejected_at) = *ejected_at {
+                return ejected_at.elapsed() >= PASSIVE_EJECT_DURATION;
+            }
+        }
+        false
+    }
+
+    fn on_success(&self, passes: u32) {
+        self.fails.store(0, Ordering::SeqCst);
+        if self.healthy.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let current = self.passes.fetch_add(1, Ordering::SeqCst) + 1;
+        if current >= passes {
+            self.passes.store(0, Ordering::SeqCst);
+            self.healthy.store(true, Ordering::SeqCst);
+            *self.ejected_at.lock().unwrap() = None;
+            info!("upstream target {} is healthy again", self.url);
+        }
+    }
+
+    fn on_failure(&self, fails: u32) {
+        self.passes.store(0, Ordering::SeqCst);
+        let current = self.fails.fetch_add(1, Ordering::SeqCst) + 1;
+        if current >= fails {
+            *self.ejected_at.lock().unwrap() = Some(Instant::now());
+            if self.healthy.swap(false, Ordering::SeqCst) {
+                warn!("upstream target {} ejected after {} failures", self.url, current);
+            }
+        }
+    }
+}
+
+pub struct UpstreamGroup {
+    config: UpstreamGroupConfig,
+    targets: Vec<UpstreamTarget>,
+    // current weights of smooth weighted round robin
+    rr_current: Mutex<Vec<i64>>,
+    // (hash, target index), sorted by hash
+    ring: Vec<(u64, usize)>,
+}
+
+impl UpstreamGroup {
+    // the health check task exits when the group is dropped
+    pub fn new(config: UpstreamGroupConfig) -> Arc<Self> {
+        let targets: Vec<UpstreamTarget> = config.targets.iter().map(UpstreamTarget::new).collect();
+        let rr_current = Mutex::new(vec![0; targets.len()]);
+        let ring = Self::build_ring(&targets);
+
+        let group = Arc::new(Self {
+            config,
+            targets,
+            rr_current,
+            ring,
+        });
+
+        if let Some(health_check) = group.config.health_check.clone() {
+            Self::start_health_check(&group, health_check);
+        }
+        group
+    }
+
+    pub fn config(&self) -> &UpstreamGroupConfig {
+        &self.config
+    }
+
+    // smooth weighted round robin, 只在可用的target之间分配
+    fn select_round_robin(&self, candidates: &[bool]) -> Option<usize> {
+        let mut current = self.rr_current.lock().unwrap();
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for (i, target) in self.targets.iter().enumerate() {
+            if !candidates[i] {
+                continue;
+            }
+            current[i] += target.weight as i64;
+            total += target.weight as i64;
+            if best.map(|b| current[i] > current[b]).unwrap_or(true) {
+                best = Some(i);
+            }
+        }
+        if let Some(best) = best {
+            current[best] -= total;
+        }
+        best
+    }
+
+    fn build_ring(targets: &[UpstreamTarget]) -> Vec<(u64, usize)> {
+        let mut ring = Vec::new();
+        for (i, target) in targets.iter().enumerate() {
+            for n in 0..target.weight * VIRTUAL_NODES_PER_WEIGHT {
+                ring.push((hash_of(&format!("{}#{}", target.url, n)), i));
+            }
+        }
+        ring.sort();
+        ring
+    }
+
+    fn fails_threshold(&self) -> u32 {
+        self.config
+            .health_check
+            .as_ref()
+            .map(|c| c.fails.max(1))
+            .unwrap_or(PASSIVE_MAX_FAILS)
+    }
+
+    fn passes_threshold(&self) -> u32 {
+        self.config.health_check.as_ref().map(|c| c.passes.max(1)).unwrap_or(1)
+    }
+
+    fn select(&self, hash: Option<u64>, excluded: &[usize]) -> Option<usize> {
+        let active_check = self.config.health_check.is_some();
+        let mut candidates: Vec<bool> = self
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(i, t)| !excluded.contains(&i) && t.is_available(active_check))
+            .collect();
+
+        if !candidates.iter().any(|c| *c) {
+            // 全部被摘除时仍然尝试转发,好过直接返回错误
+            candidates = (0..self.targets.len()).map(|i| !excluded.contains(&i)).collect();
+            if !candidates.iter().any(|c| *c) {
+                return None;
+            }
+            warn!("all upstream targets are unhealthy, try the ejected ones");
+        }
+
+        match self.config.policy {
+            LoadBalancePolicy::RoundRobin => self.select_round_robin(&candidates),
+            LoadBalancePolicy::LeastConn => (0..self.targets.len())
+                .filter(|i| candidates[*i])
+                .min_by_key(|i| {
+                    let target = &self.targets[*i];
+                    target.active.load(Ordering::SeqCst) as u64 * 1_000_000 / target.weight as u64
+                }),
+            LoadBalancePolicy::ConsistentHash => {
+                let hash = hash.unwrap_or(0);
+                let start = self.ring.partition_point(|(h, _)| *h < hash);
+                (0..self.ring.len())
+                    .map(|i| self.ring[(start + i) % self.ring.len()].1)
+                    .find(|i| candidates[*i])
+            }
+        }
+    }
+
+    fn request_hash(&self, req: &Request<Body>, client_ip: IpAddr) -> Option<u64> {
+        if self.config.policy != LoadBalancePolicy::ConsistentHash {
+            return None;
+        }
+
+        if let Some(hash_key) = self.config.hash_key.as_ref() {
+            if let Some(value) = req.headers().get(hash_key.as_str()) {
+                return Some(hash_of(value.as_bytes()));
+            }
+        }
+        Some(hash_of(&client_ip))
+    }
+
+    async fn forward_once(&self, index: usize, req: Request<Body>) -> Result<Response<Body>> {
+        let target = &self.targets[index];
+        debug!("forward {} {} to upstream target {}", req.method(), req.uri(), target.url);
+        let guard = ActiveGuard::new(&target.active);
+        let result = forward_with_guard(&target.url, req, Some(guard)).await;
+
+        match result.as_ref() {
+            Ok(_) => target.on_success(self.passes_threshold()),
+            Err(e) => {
+                warn!("forward to upstream target {} failed: {}", target.url, e);
+                target.on_failure(self.fails_threshold());
+            }
+        }
+        result
+    }
+
+    pub async fn forward(&self, req: Request<Body>, client_ip: IpAddr) -> Result<Response<Body>> {
+        let hash = self.request_hash(&req, client_ip);
+        // upgrade request can't be rebuilt, the upgrade handle is in its extensions
+        let replayable = req
+            .body()
+            .size_hint()
+            .upper()
+            .map(|size| size <= MAX_REPLAY_BODY_SIZE)
+            .unwrap_or(false);
+        let max_tries = if is_idempotent(req.method()) && !is_upgrade_request(&req) && replayable {
+            1 + self.config.retries as usize
+        } else {
+            1
+        };
+
+        if max_tries == 1 {
+            let index = self
+                .select(hash, &[])
+                .ok_or_else(|| anyhow::anyhow!("No upstream target available"))?;
+            return self.forward_once(index, req).await;
+        }
+
+        // 重试需要重放body
+        let (parts, body) = req.into_parts();
+        let body_bytes = hyper::body::to_bytes(body).await?;
+
+        let mut excluded = Vec::new();
+        let mut last_err = None;
+        for _ in 0..max_tries {
+            let index = match self.select(hash, &excluded) {
+                Some(index) => index,
+                None => break,
+            };
+
+            let mut upstream_req = Request::builder()
+                .method(parts.method.clone())
+                .uri(parts.uri.clone())
+                .version(parts.version)
+                .body(Body::from(body_bytes.clone()))?;
+            *upstream_req.headers_mut() = parts.headers.clone();
+
+            match self.forward_once(index, upstream_req).await {
+                Ok(resp) => return Ok(resp),
+                Err(e) => {
+                    excluded.push(index);
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No upstream target available")))
+    }
+
+    fn start_health_check(group: &Arc<Self>, health_check: UpstreamHealthCheckConfig) {
+        let group = Arc::downgrade(group);
+        tokio::task::spawn(async move {
+            let interval = Duration::from_secs(health_check.interval.max(1));
+            let timeout = Duration::from_secs(health_check.timeout.max(1));
+            loop {
+                tokio::time::sleep(interval).await;
+                // route config changed or removed
+                let group = match group.upgrade() {
+                    Some(group) => group,
+                    None => break,
+                };
+
+                let checks = group
+                    .targets
+                    .iter()
+                    .map(|target| Self::check_target(target, &health_check.path, timeout));
+                let results = futures::future::join_all(checks).await;
+                for (target, ok) in group.targets.iter().zip(results) {
+                    if ok {
+                        target.on_success(group.passes_threshold());
+                    } else {
+                        target.on_failure(group.fails_threshold());
+                    }
+                }
+            }
+        });
+    }
+
+    async fn check_target(target: &UpstreamTarget, path: &str, timeout: Duration) -> bool {
+        let req = match Request::builder().method(Method::GET).uri(path).body(Body::empty()) {
+            Ok(req) => req,
+            Err(e) => {
+                warn!("invalid health check path {}: {}", path, e);
+                return false;
+            }
+        };
+
+        match tokio::time::timeout(timeout, forward_to_target(&target.url, req)).await {
+            Ok(Ok(resp)) => {
+                let status = resp.status();
+                if status.is_success() || status.is_redirection() {
+                    true
+                } else {
+                    debug!("health check {}{} returned {}", target.url, path, status);
+                    false
+                }
+            }
+            Ok(Err(e)) => {
+                debug!("health check {}{} failed: {}", target.url, path, e);
+                false
+            }
+            Err(_) => {
+                debug!("health check {}{} timeout", target.url, path);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group_config(json: &str) -> UpstreamGroupConfig {
+        let config: UpstreamRouteConfig = serde_json::from_str(json).unwrap();
+        config.group.unwrap()
+    }
+
+    #[test]
+    fn test_upstream_config() {
+        let config: UpstreamRouteConfig =
+            serde_json::from_str(r#""http://localhost:9091 redirect""#).unwrap();
+        assert_eq!(config.target, "http://localhost:9091");
+        assert_eq!(config.redirect, RedirectType::Temporary);
+        assert!(config.group.is_none());
+
+        let config = group_config(
+            r#"{
+                "targets": ["http://127.0.0.1:8080", {"url": "rtcp://ood2/:8080", "weight": 3}],
+                "policy": "least_conn",
+                "health_check": {"path": "/health"}
+            }"#,
+        );
+        assert_eq!(config.targets[0].weight, 1);
+        assert_eq!(config.targets[1].url, "rtcp://ood2/:8080");
+        assert_eq!(config.targets[1].weight, 3);
+        assert_eq!(config.policy, LoadBalancePolicy::LeastConn);
+        assert_eq!(config.retries, 1);
+        assert_eq!(config.health_check.unwrap().fails, 3);
+    }
+
+    #[test]
+    fn test_upgrade_request() {
+        let req = Request::builder()
+            .uri("/ws")
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Upgrade", "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert!(is_upgrade_request(&req));
+
+        let req = Request::builder()
+            .uri("https://example.com/grpc.Service/Call")
+            .version(Version::HTTP_2)
+            .header("content-type", "application/grpc+proto")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!is_upgrade_request(&req));
+        assert!(is_grpc_request(&req));
+        assert_eq!(get_request_host(&req).unwrap(), "example.com");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_weight_capped() {
+        let group = UpstreamGroup::new(group_config(
+            r#"{"targets": [{"url": "http://a", "weight": 4000000000}, "http://b"], "policy": "consistent_hash"}"#,
+        ));
+        assert_eq!(group.targets[0].weight, MAX_TARGET_WEIGHT);
+        assert_eq!(group.ring.len() as u32, (MAX_TARGET_WEIGHT + 1) * VIRTUAL_NODES_PER_WEIGHT);
+    }
+
+    #[tokio::test]
+    async fn test_retry_replay_limit() {
+        let make_svc = hyper::service::make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(hyper::service::service_fn(|_req| async {
+                Ok::<_, hyper::Error>(Response::new(Body::from("ok")))
+            }))
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let config = format!(r#"{{"targets": ["http://{}", "http://{}"]}}"#, dead, addr);
+        let client_ip: IpAddr = "127.0.0.1".parse().unwrap();
+
+        // 小的body失败后在另一个target上重试
+        let group = UpstreamGroup::new(group_config(&config));
+        let req = Request::builder().method(Method::PUT).uri("/").body(Body::from("small")).unwrap();
+        assert!(group.forward(req, client_ip).await.is_ok());
+
+        // 超过上限的body不缓存,也就不重试
+        let group = UpstreamGroup::new(group_config(&config));
+        let body = vec![0u8; MAX_REPLAY_BODY_SIZE as usize + 1];
+        let req = Request::builder().method(Method::PUT).uri("/").body(Body::from(body)).unwrap();
+        assert!(group.forward(req, client_ip).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_least_conn_streaming_response() {
+        // upstream先返回header,body在收到通知后才结束
+        let (body_tx, body_rx) = tokio::sync::oneshot::channel::<()>();
+        let body_rx = Arc::new(Mutex::new(Some(body_rx)));
+        let make_svc = hyper::service::make_service_fn(move |_| {
+            let body_rx = body_rx.clone();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |_req| {
+                    let body_rx = body_rx.lock().unwrap().take();
+                    async move {
+                        let (mut sender, body) = Body::channel();
+                        tokio::spawn(async move {
+                            let _ = sender.send_data("hello".into()).await;
+                            if let Some(body_rx) = body_rx {
+                                let _ = body_rx.await;
+                            }
+                        });
+                        Ok::<_, hyper::Error>(Response::new(body))
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let group = UpstreamGroup::new(group_config(&format!(
+            r#"{{"targets": ["http://{}"], "policy": "least_conn"}}"#,
+            addr
+        )));
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let mut resp = group.forward(req, "127.0.0.1".parse().unwrap()).await.unwrap();
+        assert_eq!(resp.body_mut().data().await.unwrap().unwrap(), "hello");
+        assert_eq!(group.targets[0].active.load(Ordering::SeqCst), 1);
+
+        body_tx.send(()).unwrap();
+        assert!(resp.body_mut().data().await.is_none());
+        drop(resp);
+        for _ in 0..100 {
+            if group.targets[0].active.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("active connection not released");
+    }
+
+    async fn read_response_head(stream: &mut tokio::net::TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    // 前端的hyper server通过upstream group转发
+    fn start_front_server(group: Arc<UpstreamGroup>) -> std::net::SocketAddr {
+        let make_svc = hyper::service::make_service_fn(move |_| {
+            let group = group.clone();
+            async move {
+                Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+                    let group = group.clone();
+                    async move { group.forward(req, "127.0.0.1".parse().unwrap()).await }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_proxy_websocket_upgrade() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // upstream应答101后回显升级后的连接上的数据
+        let make_svc = hyper::service::make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(hyper::service::service_fn(|mut req: Request<Body>| async move {
+                let upgrade = hyper::upgrade::on(&mut req);
+                tokio::spawn(async move {
+                    let mut upgraded = upgrade.await.unwrap();
+                    let mut buf = [0u8; 64];
+                    loop {
+                        let n = upgraded.read(&mut buf).await.unwrap_or(0);
+                        if n == 0 || upgraded.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                Ok::<_, hyper::Error>(
+                    Response::builder()
+                        .status(StatusCode::SWITCHING_PROTOCOLS)
+                        .header(hyper::header::CONNECTION, "Upgrade")
+                        .header(hyper::header::UPGRADE, "websocket")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            }))
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let upstream_addr = server.local_addr();
+        tokio::spawn(server);
+
+        let group = UpstreamGroup::new(group_config(&format!(
+            r#"{{"targets": ["http://{}"], "policy": "least_conn"}}"#,
+            upstream_addr
+        )));
+        let front_addr = start_front_server(group.clone());
+
+        let mut stream = tokio::net::TcpStream::connect(front_addr).await.unwrap();
+        stream
+            .write_all(b"GET /ws HTTP/1.1\r\nHost: example.com\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\r\n")
+            .await
+            .unwrap();
+        let head = read_response_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        // 升级后的连接计入least_conn
+        assert_eq!(group.targets[0].active.load(Ordering::SeqCst), 1);
+
+        drop(stream);
+        for _ in 0..100 {
+            if group.targets[0].active.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("upgraded connection not released");
+    }
+
+    #[tokio::test]
+    async fn test_proxy_h2c() {
+        // upstream同时支持http/1.1和h2c, 返回收到的协议版本和host
+        let make_svc = hyper::service::make_service_fn(|_| async {
+            Ok::<_, hyper::Error>(hyper::service::service_fn(|req: Request<Body>| async move {
+                let host = req
+                    .headers()
+                    .get(hyper::header::HOST)
+                    .map(|h| h.to_str().unwrap().to_string())
+                    .unwrap_or_else(|| req.uri().authority().unwrap().to_string());
+                let (mut sender, body) = Body::channel();
+                let content = format!("{:?} {}", req.version(), host);
+                tokio::spawn(async move {
+                    let _ = sender.send_data(content.into()).await;
+                    let mut trailers = hyper::HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    let _ = sender.send_trailers(trailers).await;
+                });
+                Ok::<_, hyper::Error>(Response::new(body))
+            }))
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_svc);
+        let upstream_addr = server.local_addr();
+        tokio::spawn(server);
+
+        let group = UpstreamGroup::new(group_config(&format!(r#"{{"targets": ["http://{}"]}}"#, upstream_addr)));
+        let front_addr = start_front_server(group);
+        let client = Client::builder().http2_only(true).build_http::<Body>();
+
+        // grpc通过h2c转发,trailers保留
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/grpc.Service/Call", front_addr))
+            .header(hyper::header::CONTENT_TYPE, "application/grpc")
+            .body(Body::empty())
+            .unwrap();
+        let mut resp = client.request(req).await.unwrap();
+        let data = resp.body_mut().data().await.unwrap().unwrap();
+        assert_eq!(data, format!("HTTP/2.0 {}", front_addr));
+        let trailers = resp.body_mut().trailers().await.unwrap().unwrap();
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+
+        // 普通的h2请求用http/1.1转发, :authority作为host
+        let req = Request::builder()
+            .uri(format!("http://{}/index.html", front_addr))
+            .body(Body::empty())
+            .unwrap();
+        let resp = client.request(req).await.unwrap();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, format!("HTTP/1.1 {}", front_addr));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_select() {
+        let group = UpstreamGroup::new(group_config(
+            r#"{"targets": [{"url": "http://a", "weight": 2}, "http://b"]}"#,
+        ));
+        let selected: Vec<usize> = (0..6).map(|_| group.select(None, &[]).unwrap()).collect();
+        assert_eq!(selected.iter().filter(|i| **i == 0).count(), 4);
+        assert_eq!(group.select(None, &[0]), Some(1));
+
+        // ejected target is skipped
+        for _ in 0..PASSIVE_MAX_FAILS {
+            group.targets[0].on_failure(group.fails_threshold());
+        }
+        assert!((0..3).all(|_| group.select(None, &[]) == Some(1)));
+
+        let group = UpstreamGroup::new(group_config(
+            r#"{"targets": ["http://a", "http://b", "http://c"], "policy": "consistent_hash"}"#,
+        ));
+        let hash = Some(hash_of("client"));
+        let first = group.select(hash, &[]).unwrap();
+        assert!((0..5).all(|_| group.select(hash, &[]) == Some(first)));
+        assert_ne!(group.select(hash, &[first]), Some(first));
+    }
+}
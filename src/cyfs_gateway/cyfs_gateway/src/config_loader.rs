@@ -1,7 +1,9 @@
 use buckyos_kit::{adjust_path, get_buckyos_root_dir};
 use cyfs_gateway_lib::DNSServerConfig;
 use cyfs_gateway_lib::DispatcherConfig;
+use cyfs_gateway_lib::RTcpKeepaliveConfig;
 use cyfs_gateway_lib::ServerConfig;
+use cyfs_gateway_lib::StreamSelectorConfig;
 use cyfs_gateway_lib::WarpServerConfig;
 use cyfs_sn::*;
 use cyfs_socks::SocksProxyConfig;
@@ -13,12 +15,24 @@ use std::collections::HashMap;
 use std::path::PathBuf;
 use url::Url;
 use buckyos_api::ZONE_PROVIDER;
+use crate::tunnel_status::TunnelStatusService;
+use std::time::Duration;
 
 pub struct GatewayConfig {
     pub dispatcher: HashMap<Url, DispatcherConfig>,
     pub servers: HashMap<String, ServerConfig>,
+    pub selectors: HashMap<String, StreamSelectorConfig>,
     pub device_key_path: PathBuf,
     pub device_name: Option<String>,
+    // inner services are registered once at startup, changes need restart
+    pub inner_services: serde_json::Value,
+    // rtcp打洞/中继使用的SN rendezvous地址, like sn.example.com:2981
+    pub rtcp_rendezvous: Option<String>,
+    // rtcp tunnel保活的ping间隔和判定断线的超时, 单位秒
+    pub rtcp_ping_interval: Option<u64>,
+    pub rtcp_ping_timeout: Option<u64>,
+    // 是否允许与不支持协议协商的老版本rtcp对端建立tunnel, 默认不允许
+    pub rtcp_allow_legacy: bool,
     
     //pub device_private_key: Option<[u8; 48]>,
     //pub device_did: Option<String>,
@@ -129,20 +143,38 @@ impl GatewayConfig {
         Ok(dispatcher_cfg)
     }
 
-    pub async fn load_from_json_value(json_value: serde_json::Value) -> Result<Self, String> {
-        let mut device_key_path = PathBuf::new();
-        if let Some(Some(path)) = json_value.get("device_key_path").map(|p| p.as_str()) {
-            device_key_path = adjust_path(path).
-                map_err(|e| format!("adjust path failed! {}", e))?;
+    pub fn load_selector_config(selector_config_value: &serde_json::Value) -> Result<HashMap<String, StreamSelectorConfig>,String> {
+        let mut selector_cfg = HashMap::new();
+        let selector_config_value = selector_config_value.as_object();
+        if selector_config_value.is_none() {
+            return Err("Selectors config not object".to_string());
+        }
 
-            info!(
-                "adjust device key path {} to {}",
-                path,device_key_path.display()
-            );
+        for (selector_id, v) in selector_config_value.unwrap().iter() {
+            let selector_config = serde_json::from_value::<StreamSelectorConfig>(v.clone())
+                .map_err(|e| format!("Invalid selector config: {}, {}", selector_id, e))?;
+
+            let targets = selector_config.rules.iter().map(|rule| &rule.target)
+                .chain(selector_config.default.iter());
+            for target in targets {
+                Url::parse(target).map_err(|e| {
+                    let msg = format!("Invalid selector target url: {}, {}, {}", selector_id, target, e);
+                    error!("{}", msg);
+                    msg
+                })?;
+            }
+            selector_cfg.insert(selector_id.clone(), selector_config);
         }
+        Ok(selector_cfg)
+    }
 
-        let device_name:Option<String> = json_value.get("device_name").map(|v| v.as_str()).flatten().map(|s| s.to_string());
-        // register inner services
+    pub async fn load_from_json_value(json_value: serde_json::Value) -> Result<Self, String> {
+        GatewayConfig::register_inner_services(&json_value).await?;
+        GatewayConfig::parse_from_json_value(json_value).await
+    }
+
+    // register inner services
+    pub async fn register_inner_services(json_value: &serde_json::Value) -> Result<(), String> {
         //TODO:需要通过插件优化，否则每次添加一个新的类型都要在这里添加注册函数
         if let Some(Some(inner_services)) = json_value.get("inner_services").map(|v| v.as_object())
         {
@@ -165,6 +197,7 @@ impl GatewayConfig {
                         }
                         let sn_config = sn_config.unwrap();
                         let sn_server = SNServer::new(sn_config);
+                        sn_server.start_rendezvous().await?;
                         register_sn_server(server_id, sn_server.clone()).await;
                         info!("Register sn server: {:?}", server_id);
                         register_inner_service_builder(server_id, move || {
@@ -172,6 +205,12 @@ impl GatewayConfig {
                         })
                         .await;
                     }
+                    "tunnel-status" => {
+                        register_inner_service_builder(server_id, move || {
+                            Box::new(TunnelStatusService::new())
+                        })
+                        .await;
+                    }
                     "zone-provider" => {
                         register_inner_service_builder(server_id, move || {
                             Box::new(ZONE_PROVIDER.clone())
@@ -185,6 +224,66 @@ impl GatewayConfig {
             }
         }
 
+        Ok(())
+    }
+
+    // parse config without side effects, used by config reload
+    // tunnel-status会暴露设备的连接信息, 挂载它的route必须配置auth(basic或krpc_token)
+    fn check_tunnel_status_auth(
+        json_value: &serde_json::Value,
+        servers_cfg: &HashMap<String, ServerConfig>,
+    ) -> Result<(), String> {
+        let tunnel_status_services: Vec<&String> = match json_value
+            .get("inner_services")
+            .and_then(|v| v.as_object())
+        {
+            Some(inner_services) => inner_services
+                .iter()
+                .filter(|(_, v)| v.get("type").and_then(|t| t.as_str()) == Some("tunnel-status"))
+                .map(|(k, _)| k)
+                .collect(),
+            None => return Ok(()),
+        };
+
+        for server_config in servers_cfg.values() {
+            if let ServerConfig::Warp(warp_config) = server_config {
+                for (host, host_config) in warp_config.hosts.iter() {
+                    for (route, route_config) in host_config.routes.iter() {
+                        let is_tunnel_status = route_config
+                            .inner_service
+                            .as_ref()
+                            .map(|s| tunnel_status_services.contains(&s))
+                            .unwrap_or(false);
+                        if is_tunnel_status && route_config.auth.is_none() {
+                            return Err(format!(
+                                "tunnel-status route {}.{} must configure auth",
+                                host, route
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn parse_from_json_value(json_value: serde_json::Value) -> Result<Self, String> {
+        let mut device_key_path = PathBuf::new();
+        if let Some(Some(path)) = json_value.get("device_key_path").map(|p| p.as_str()) {
+            device_key_path = adjust_path(path).
+                map_err(|e| format!("adjust path failed! {}", e))?;
+
+            info!(
+                "adjust device key path {} to {}",
+                path,device_key_path.display()
+            );
+        }
+
+        let device_name:Option<String> = json_value.get("device_name").map(|v| v.as_str()).flatten().map(|s| s.to_string());
+        let rtcp_rendezvous:Option<String> = json_value.get("rtcp_rendezvous").map(|v| v.as_str()).flatten().map(|s| s.to_string());
+        let rtcp_ping_interval = json_value.get("rtcp_ping_interval").map(|v| v.as_u64()).flatten();
+        let rtcp_ping_timeout = json_value.get("rtcp_ping_timeout").map(|v| v.as_u64()).flatten();
+        let rtcp_allow_legacy = json_value.get("rtcp_allow_legacy").map(|v| v.as_bool()).flatten().unwrap_or(false);
         //register_inner_service_builder("cyfs_sn",|| {
         //    Box::new(SNServer::new(None))
         //}).await;
@@ -241,7 +340,20 @@ impl GatewayConfig {
                                 dns_config.err().unwrap()
                             ));
                         }
-                        let dns_config = dns_config.unwrap();
+                        let mut dns_config = dns_config.unwrap();
+                        // adjust dns zone key path, the key is usually kept next to the sn config
+                        if let Some(zone) = dns_config.zone.as_mut() {
+                            if let Some(key_path) = zone.dnssec_key_path.as_ref() {
+                                let new_path = adjust_path(key_path)
+                                    .map_err(|e| format!("adjust path failed! {}", e))?;
+                                info!(
+                                    "adjust dns zone key path {} to {}",
+                                    key_path,
+                                    new_path.display()
+                                );
+                                zone.dnssec_key_path = Some(new_path.to_string_lossy().to_string());
+                            }
+                        }
                         servers_cfg.insert(k.clone(), ServerConfig::DNS(dns_config));
                     }
                     "cyfs-socks" => {
@@ -262,6 +374,13 @@ impl GatewayConfig {
                 }
             }
         }
+        GatewayConfig::check_tunnel_status_auth(&json_value, &servers_cfg)?;
+
+        //load selectors
+        let selectors = match json_value.get("selectors") {
+            Some(selectors) => GatewayConfig::load_selector_config(selectors)?,
+            None => HashMap::new(),
+        };
 
         //load dispatcher
         let dispatcher_config_value = json_value.get("dispatcher");
@@ -276,9 +395,27 @@ impl GatewayConfig {
         Ok(Self {
             dispatcher,
             servers: servers_cfg,
+            selectors,
             device_key_path,
-            device_name:device_name
-
+            device_name:device_name,
+            inner_services: json_value.get("inner_services").cloned().unwrap_or_default(),
+            rtcp_rendezvous,
+            rtcp_ping_interval,
+            rtcp_ping_timeout,
+            rtcp_allow_legacy,
         })
     }
+
+    pub fn rtcp_keepalive(&self) -> RTcpKeepaliveConfig {
+        let mut keepalive = RTcpKeepaliveConfig::default();
+        if let Some(interval) = self.rtcp_ping_interval {
+            keepalive.ping_interval = Duration::from_secs(interval);
+        }
+        match self.rtcp_ping_timeout {
+            Some(timeout) => keepalive.ping_timeout = Duration::from_secs(timeout),
+            // 默认允许丢3个ping
+            None => keepalive.ping_timeout = keepalive.ping_interval * 3,
+        }
+        keepalive
+    }
 }
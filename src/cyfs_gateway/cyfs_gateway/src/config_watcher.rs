@@ -0,0 +1,203 @@
+use crate::config_loader::GatewayConfig;
+use crate::gateway::Gateway;
+use buckyos_api::SystemConfigClient;
+use json_value_merge::Merge;
+use log::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(5);
+const SESSION_TOKEN_ENV: &str = "CYFS_GATEWAY_SESSION_TOKEN";
+
+// (path, modified, len) of every file under the config dir
+type ConfigStamp = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+// 轮询配置文件目录和sys_config,有变化时重新解析配置并热更新gateway
+pub struct ConfigWatcher {
+    config_file: PathBuf,
+    config_key: Option<String>,
+    file_stamp: ConfigStamp,
+    sys_config_version: u64,
+    current: serde_json::Value,
+}
+
+impl ConfigWatcher {
+    pub fn new(config_file: PathBuf, config_key: Option<String>, current: serde_json::Value) -> Self {
+        let file_stamp = Self::scan_config_stamp(&config_file);
+        Self {
+            config_file,
+            config_key,
+            file_stamp,
+            sys_config_version: 0,
+            current,
+        }
+    }
+
+    pub fn start(mut self, gateway: Arc<Gateway>) {
+        info!(
+            "watch gateway config: {}, sys_config key: {:?}",
+            self.config_file.display(),
+            self.config_key
+        );
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(CONFIG_WATCH_INTERVAL).await;
+                match self.check_update().await {
+                    Ok(Some(config)) => gateway.reload_config(config).await,
+                    Ok(None) => {}
+                    Err(e) => {
+                        // keep the running config, retry in next round
+                        warn!("check gateway config update failed: {}", e);
+                    }
+                }
+            }
+        });
+    }
+
+    // 配置没有变化时返回None
+    async fn check_update(&mut self) -> Result<Option<GatewayConfig>, String> {
+        let file_stamp = Self::scan_config_stamp(&self.config_file);
+        let file_changed = file_stamp != self.file_stamp;
+
+        let mut sys_config = None;
+        if let Some(config_key) = self.config_key.as_ref() {
+            let (value, version) = Self::load_sys_config(config_key).await?;
+            if version != self.sys_config_version {
+                self.sys_config_version = version;
+                sys_config = Some(value);
+            }
+        }
+
+        if !file_changed && sys_config.is_none() {
+            return Ok(None);
+        }
+
+        let config_dir = self
+            .config_file
+            .parent()
+            .ok_or_else(|| format!("cannot get config dir: {:?}", self.config_file))?;
+        let mut new_config =
+            buckyos_kit::ConfigMerger::load_dir_with_root(config_dir, &self.config_file)
+                .await
+                .map_err(|e| format!("load config file failed: {}", e))?;
+        self.file_stamp = file_stamp;
+
+        // sys_config优先级高于本地配置文件
+        if self.config_key.is_some() {
+            if sys_config.is_none() {
+                let (value, _) = Self::load_sys_config(self.config_key.as_ref().unwrap()).await?;
+                sys_config = Some(value);
+            }
+            new_config.merge(sys_config.as_ref().unwrap());
+        }
+
+        if new_config == self.current {
+            return Ok(None);
+        }
+
+        let config = GatewayConfig::parse_from_json_value(new_config.clone()).await?;
+        info!("gateway config changed, will reload");
+        self.current = new_config;
+        Ok(Some(config))
+    }
+
+    pub async fn load_sys_config(config_key: &str) -> Result<(serde_json::Value, u64), String> {
+        let session_token = std::env::var(SESSION_TOKEN_ENV)
+            .map_err(|_| format!("{} is not set", SESSION_TOKEN_ENV))?;
+        let client = SystemConfigClient::new(None, Some(session_token.as_str()));
+        let (value, version) = client
+            .get(config_key)
+            .await
+            .map_err(|e| format!("get sys_config {} failed: {}", config_key, e))?;
+        let value = serde_json::from_str(&value)
+            .map_err(|e| format!("parse sys_config {} failed: {}", config_key, e))?;
+        Ok((value, version))
+    }
+
+    fn scan_config_stamp(config_file: &Path) -> ConfigStamp {
+        let mut stamp = Vec::new();
+        if let Some(dir) = config_file.parent() {
+            Self::scan_dir(dir, &mut stamp);
+        }
+        stamp.sort();
+        stamp
+    }
+
+    fn scan_dir(dir: &Path, stamp: &mut ConfigStamp) {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                Self::scan_dir(&path, stamp);
+            } else if let Ok(meta) = entry.metadata() {
+                stamp.push((path, meta.modified().ok(), meta.len()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(config_file: &Path, servers: serde_json::Value) {
+        let config = serde_json::json!({
+            "includes": [],
+            "servers": servers,
+        });
+        std::fs::write(config_file, serde_json::to_string_pretty(&config).unwrap()).unwrap();
+    }
+
+    fn warp_server(http_port: u16) -> serde_json::Value {
+        serde_json::json!({
+            "type": "cyfs-warp",
+            "bind": "127.0.0.1",
+            "http_port": http_port,
+            "tls_port": 0,
+            "hosts": {}
+        })
+    }
+
+    async fn create_watcher(config_file: &Path) -> ConfigWatcher {
+        let config_dir = config_file.parent().unwrap();
+        let current = buckyos_kit::ConfigMerger::load_dir_with_root(config_dir, config_file)
+            .await
+            .unwrap();
+        ConfigWatcher::new(config_file.to_path_buf(), None, current)
+    }
+
+    #[tokio::test]
+    async fn test_check_update_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("cyfs_gateway.json");
+        write_config(&config_file, serde_json::json!({ "main": warp_server(3190) }));
+
+        let mut watcher = create_watcher(&config_file).await;
+        assert!(watcher.check_update().await.unwrap().is_none());
+        assert!(watcher.check_update().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_check_update_edited() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("cyfs_gateway.json");
+        write_config(&config_file, serde_json::json!({ "main": warp_server(3191) }));
+        let mut watcher = create_watcher(&config_file).await;
+
+        write_config(
+            &config_file,
+            serde_json::json!({ "main": warp_server(3191), "second": warp_server(3192) }),
+        );
+        let config = watcher.check_update().await.unwrap().unwrap();
+        assert_eq!(config.servers.len(), 2);
+        assert!(config.servers.contains_key("second"));
+
+        // 同一份配置只通知一次
+        assert!(watcher.check_update().await.unwrap().is_none());
+    }
+}
+
@@ -2,8 +2,9 @@
 use cyfs_gateway_lib::*;
 use log::*;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 use tokio::task;
 use url::Url;
@@ -14,8 +15,10 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>
 pub struct ServiceDispatcher {
     tunnel_manager: TunnelManager,
     config_source: Option<String>,
-    config_version: u64,
+    config_version: Arc<AtomicU64>,
     config: Arc<Mutex<HashMap<Url, DispatcherConfig>>>,
+    // accept loop of each incoming, the streams accepted run in their own tasks
+    tasks: Arc<Mutex<HashMap<Url, task::JoinHandle<()>>>>,
 }
 
 impl ServiceDispatcher {
@@ -23,8 +26,37 @@ impl ServiceDispatcher {
         Self {
             tunnel_manager,
             config_source: None,
-            config_version: 0,
+            config_version: Arc::new(AtomicU64::new(0)),
             config: Arc::new(Mutex::new(config)),
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_config_source(mut self, config_source: &str) -> Self {
+        self.config_source = Some(config_source.to_string());
+        self
+    }
+
+    pub fn config_version(&self) -> u64 {
+        self.config_version.load(Ordering::SeqCst)
+    }
+
+    async fn add_task(&self, incoming: &Url, handle: task::JoinHandle<()>) {
+        let prev = self.tasks.lock().await.insert(incoming.clone(), handle);
+        if let Some(prev) = prev {
+            warn!("service of {} already running, abort the previous one", incoming);
+            prev.abort();
+        }
+    }
+
+    // 只停止accept,已经建立的stream继续运行
+    async fn stop_service(&self, incoming: &Url) {
+        let handle = self.tasks.lock().await.remove(incoming);
+        if let Some(handle) = handle {
+            handle.abort();
+            // wait the listener dropped, so the port can be bound again
+            let _ = handle.await;
+            info!("stop service of {}", incoming);
         }
     }
 
@@ -68,11 +100,12 @@ impl ServiceDispatcher {
         match incoming_category {
             ProtocolCategory::Stream => {
                 let listener = self.create_income_listener(incoming).await?;
+                let task_key = incoming.clone();
                 let incoming = incoming.clone();
                 let probe_id = probe_id.clone();
                 let selector_id = selector_id.clone();
                 let tunnel_manager = self.tunnel_manager.clone();
-                task::spawn(async move {
+                let handle = task::spawn(async move {
                     loop {
                         let accept_result = listener.accept().await;
                         if accept_result.is_err() {
@@ -91,7 +124,7 @@ impl ServiceDispatcher {
                         let selector_id = selector_id.clone();
                         let tunnel_manager = tunnel_manager.clone();
                         task::spawn(async move {
-                            let this_probe: Option<Box<dyn StreamProbe + Send>>;
+                            let this_probe: Option<Box<dyn StreamProbe + Send + Sync>>;
                             if probe_id.is_some() {
                                 let probe = tunnel_manager.get_stream_probe(probe_id.unwrap().as_str());
                                 if probe.is_err() {
@@ -119,7 +152,7 @@ impl ServiceDispatcher {
                             let mut read_len: usize = 0;
                             let mut stream_request = StreamRequest::new();
                             if this_probe.is_some() {
-                                let read_ret = income_stream.read(&mut probe_buffer).await;
+                                let read_ret = read_probe_buffer(&mut income_stream, &mut probe_buffer).await;
                                 if read_ret.is_err() {
                                     warn!("stream forward-selector service  read probe buffer failed: {}", read_ret.err().unwrap());
                                     return;
@@ -176,6 +209,7 @@ impl ServiceDispatcher {
                         });
                     }
                 });
+                self.add_task(&task_key, handle).await;
             }
             ProtocolCategory::Datagram => {
                 return Err(Box::new(TunnelError::UnknownProtocol(
@@ -205,11 +239,15 @@ impl ServiceDispatcher {
             e
         })?;
 
-        if incoming_category != target_category {
+        let category = if is_protocol_support_category(incoming.scheme(), &target_category) {
+            target_category
+        } else if is_protocol_support_category(target.scheme(), &incoming_category) {
+            incoming_category
+        } else {
             let msg = format!("start_forward_service failed, incoming protocol and target protocol must be the same: {} {}", incoming, target);
             error!("{}", msg);
             return Err(Box::new(TunnelError::UnknownProtocol(msg)));
-        }
+        };
 
         let target_port = target.port().unwrap_or(incoming.port().unwrap_or(0));
         if target_port == 0 {
@@ -221,13 +259,14 @@ impl ServiceDispatcher {
             return Err(Box::new(TunnelError::UnknownProtocol(msg)));
         }
 
-        match target_category {
+        match category {
             ProtocolCategory::Stream => {
                 let listener = self.create_income_listener(incoming).await?;
+                let task_key = incoming.clone();
                 let incoming = incoming.clone();
                 let target = target.clone();
                 let tunnel_manager = self.tunnel_manager.clone();
-                task::spawn(async move {
+                let handle = task::spawn(async move {
                     loop {
                         let accept_result = listener.accept().await;
                         if accept_result.is_err() {
@@ -254,16 +293,19 @@ impl ServiceDispatcher {
                         });
                     }
                 });
+                self.add_task(&task_key, handle).await;
             }
             ProtocolCategory::Datagram => {
                 let income_server = self.create_income_datagram_server(incoming).await?;
+                let task_key = incoming.clone();
                 let incoming = incoming.clone();
                 let target = target.clone();
                 let tunnel_manager = self.tunnel_manager.clone();
                 type DatagramClientSession = Box<dyn DatagramClientBox>;
                 type DatagramClientSessionMap =
                     Arc<Mutex<HashMap<TunnelEndpoint, DatagramClientSession>>>;
-                task::spawn(async move {
+                // datagram sessions are forwarded by this task, they are closed when the service stops
+                let handle = task::spawn(async move {
                     let mut buffer = vec![0u8; 1024 * 4];
                     let mut read_len: usize = 0;
                     let mut all_client_session: DatagramClientSessionMap =
@@ -331,6 +373,7 @@ impl ServiceDispatcher {
                         }
                     }
                 });
+                self.add_task(&task_key, handle).await;
             }
         }
 
@@ -342,108 +385,241 @@ impl ServiceDispatcher {
         let config = self.config.lock().await;
 
         for (incoming, target) in config.iter() {
-            match &target.target {
-                DispatcherTarget::Forward(target_url) => {
-                    //TODO: store the task handle to stop it when the config is changed
-                    match self.start_forward_service(incoming, target_url).await {
-                        Ok(_) => {
-                            info!(
-                                "Start forward service from {} to {} OK ",
-                                incoming.to_string(),
-                                target_url.to_string()
-                            );
-                        }
-                        Err(e) => {
-                            error!(
-                                "Start forward service from {} to {} failed, {}",
-                                incoming.to_string(),
-                                target_url.to_string(),
-                                e
-                            );
-                        }
-                    }
-                }
-                DispatcherTarget::Server(server_id) => {
-                    info!(
-                        "dispatcher from {} to server {}",
-                        incoming.to_string(),
-                        server_id
-                    );
-                    //looking for server config by server_id
-                    //start server with config
-                }
-                DispatcherTarget::Selector(selector_id) => {
-                    info!(
-                        "dispatcher from {} to selector {}",
-                        incoming.to_string(),
-                        selector_id
-                    );
-                    let start_result = self
-                        .start_selector_service(incoming, None, selector_id.clone())
-                        .await;
-                    if start_result.is_err() {
-                        error!(
-                            "start selector service from {} to {} failed, {}",
-                            incoming.to_string(),
-                            selector_id,
-                            start_result.err().unwrap()
-                        );
-                    } else {
-                        info!(
-                            "start selector service from {} to {} OK",
-                            incoming.to_string(),
-                            selector_id
-                        );
-                    }
-                }
-                DispatcherTarget::ProbeSelector(probe_id, selector_id) => {
-                    info!(
-                        "dispatcher from {} to probe_selector {}",
-                        incoming.to_string(),
-                        probe_id
-                    );
-                    let start_result = self
-                        .start_selector_service(
-                            incoming,
-                            Some(probe_id.clone()),
-                            selector_id.clone(),
-                        )
-                        .await;
-                    if start_result.is_err() {
-                        error!(
-                            "start probe_selector service from {} to {} failed, {}",
-                            incoming.to_string(),
-                            selector_id,
-                            start_result.err().unwrap()
-                        );
-                    } else {
-                        info!(
-                            "start probe_selector service from {} to {} OK",
-                            incoming.to_string(),
-                            selector_id
-                        );
-                    }
-                }
+            let _ = self.start_service(incoming, target).await;
+        }
+    }
+
+    async fn start_service(&self, incoming: &Url, target: &DispatcherConfig) -> Result<()> {
+        let result = match &target.target {
+            DispatcherTarget::Forward(target_url) => {
+                self.start_forward_service(incoming, target_url).await
+            }
+            DispatcherTarget::Server(server_id) => {
+                info!(
+                    "dispatcher from {} to server {}",
+                    incoming.to_string(),
+                    server_id
+                );
+                //looking for server config by server_id
+                //start server with config
+                Ok(())
+            }
+            DispatcherTarget::Selector(selector_id) => {
+                info!(
+                    "dispatcher from {} to selector {}",
+                    incoming.to_string(),
+                    selector_id
+                );
+                self.start_selector_service(incoming, None, selector_id.clone())
+                    .await
             }
+            DispatcherTarget::ProbeSelector(probe_id, selector_id) => {
+                info!(
+                    "dispatcher from {} to probe_selector {}",
+                    incoming.to_string(),
+                    probe_id
+                );
+                self.start_selector_service(incoming, Some(probe_id.clone()), selector_id.clone())
+                    .await
+            }
+        };
+
+        match &result {
+            Ok(_) => info!("start service from {} to {:?} OK", incoming, target.target),
+            Err(e) => error!(
+                "start service from {} to {:?} failed, {}",
+                incoming, target.target, e
+            ),
         }
+        result
     }
 
-    pub fn flush_new_config(&self, config: Option<DispatcherConfig>) {
-        unimplemented!();
-        // if config is None, then reload the config from the config_source
-        //verify new config
-        //calculate the difference between the new config and the old config
-        //execuite the difference
+    // 只重启变化的incoming,没有变化的listener和已经建立的stream不受影响
+    // 同一个incoming的端口只能有一个listener,所以先停掉旧的再启动新的,启动失败时恢复旧的服务
+    // 返回的错误包含启动失败的incoming,当前配置记录的是实际在运行的服务
+    pub async fn flush_new_config(&self, new_config: HashMap<Url, DispatcherConfig>) -> Result<()> {
+        let mut config = self.config.lock().await;
+
+        let mut stopped = 0;
+        for incoming in config.keys() {
+            if !new_config.contains_key(incoming) {
+                self.stop_service(incoming).await;
+                stopped += 1;
+            }
+        }
+
+        let mut applied = HashMap::new();
+        let mut failed = Vec::new();
+        let mut started = 0;
+        for (incoming, new_target) in new_config.iter() {
+            let old_target = config.get(incoming);
+            if old_target == Some(new_target) {
+                applied.insert(incoming.clone(), new_target.clone());
+                continue;
+            }
+
+            if old_target.is_some() {
+                self.stop_service(incoming).await;
+                stopped += 1;
+            }
+            if self.start_service(incoming, new_target).await.is_ok() {
+                applied.insert(incoming.clone(), new_target.clone());
+                started += 1;
+                continue;
+            }
+
+            failed.push(incoming.to_string());
+            if let Some(old_target) = old_target {
+                warn!("roll back service of {} to {:?}", incoming, old_target.target);
+                if self.start_service(incoming, old_target).await.is_ok() {
+                    applied.insert(incoming.clone(), old_target.clone());
+                }
+            }
+        }
+
+        *config = applied;
+        let version = self.config_version.fetch_add(1, Ordering::SeqCst) + 1;
+        info!(
+            "dispatcher config updated, source: {:?}, version: {}, stopped: {}, started: {}, failed: {}",
+            self.config_source, version, stopped, started, failed.len()
+        );
+
+        if !failed.is_empty() {
+            return Err(format!("start dispatcher service failed: {}", failed.join(", ")).into());
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use name_lib::DeviceConfig;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn create_test_dispatcher(config: HashMap<Url, DispatcherConfig>) -> ServiceDispatcher {
+        let device = GatewayDevice {
+            config: DeviceConfig::new("gateway", "M3-pAdhs0uFkWmmjdHLBfs494R91QmQeXzCEhEHP-tI".to_string()),
+            private_key: [0u8; 48],
+        };
+        ServiceDispatcher::new(TunnelManager::new(Arc::new(device)), config)
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    // 每次读到数据都回复tag,用来区分转发到了哪个目标
+    async fn start_tag_server(tag: u8) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 16];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 || stream.write_all(&[tag]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    fn forward_config(incoming: &Url, target_port: u16) -> DispatcherConfig {
+        let target = Url::parse(&format!("tcp:///127.0.0.1:{}", target_port)).unwrap();
+        DispatcherConfig::new_forward(incoming.clone(), target)
+    }
+
+    async fn request(stream: &mut TcpStream) -> u8 {
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf).await.unwrap();
+        buf[0]
+    }
+
+    async fn connect(incoming: &Url) -> TcpStream {
+        TcpStream::connect(format!("127.0.0.1:{}", incoming.port().unwrap())).await.unwrap()
+    }
 
     #[tokio::test]
-    async fn test_dispatcher() {
-        let dispatcher = ServiceDispatcher::new(HashMap::new());
+    async fn test_reload_unchanged_config() {
+        let target_port = start_tag_server(b'a').await;
+        let incoming = Url::parse(&format!("tcp://127.0.0.1:{}", free_port())).unwrap();
+        let config = HashMap::from([(incoming.clone(), forward_config(&incoming, target_port))]);
+
+        let dispatcher = create_test_dispatcher(config.clone());
         dispatcher.start().await;
+        let mut stream = connect(&incoming).await;
+        assert_eq!(request(&mut stream).await, b'a');
+
+        dispatcher.flush_new_config(config).await.unwrap();
+        assert_eq!(dispatcher.config_version(), 1);
+        // 没有变化的listener和已经建立的连接都不受影响
+        assert_eq!(request(&mut stream).await, b'a');
+        let mut stream = connect(&incoming).await;
+        assert_eq!(request(&mut stream).await, b'a');
+    }
+
+    #[tokio::test]
+    async fn test_reload_update_in_place() {
+        let old_port = start_tag_server(b'a').await;
+        let new_port = start_tag_server(b'b').await;
+        let incoming = Url::parse(&format!("tcp://127.0.0.1:{}", free_port())).unwrap();
+        let added = Url::parse(&format!("tcp://127.0.0.1:{}", free_port())).unwrap();
+
+        let dispatcher = create_test_dispatcher(HashMap::from([(
+            incoming.clone(),
+            forward_config(&incoming, old_port),
+        )]));
+        dispatcher.start().await;
+        let mut old_stream = connect(&incoming).await;
+        assert_eq!(request(&mut old_stream).await, b'a');
+
+        let new_config = HashMap::from([
+            (incoming.clone(), forward_config(&incoming, new_port)),
+            (added.clone(), forward_config(&added, old_port)),
+        ]);
+        dispatcher.flush_new_config(new_config).await.unwrap();
+
+        // 已经建立的连接继续使用旧的目标,新连接使用新的目标
+        assert_eq!(request(&mut old_stream).await, b'a');
+        let mut stream = connect(&incoming).await;
+        assert_eq!(request(&mut stream).await, b'b');
+        let mut stream = connect(&added).await;
+        assert_eq!(request(&mut stream).await, b'a');
+
+        // 删除的incoming停止监听
+        let new_config = HashMap::from([(incoming.clone(), forward_config(&incoming, new_port))]);
+        dispatcher.flush_new_config(new_config).await.unwrap();
+        assert!(TcpStream::connect(format!("127.0.0.1:{}", added.port().unwrap())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_failed() {
+        let target_port = start_tag_server(b'a').await;
+        let incoming = Url::parse(&format!("tcp://127.0.0.1:{}", free_port())).unwrap();
+        let config = HashMap::from([(incoming.clone(), forward_config(&incoming, target_port))]);
+        let dispatcher = create_test_dispatcher(config.clone());
+        dispatcher.start().await;
+
+        // 端口被占用的新incoming启动失败,不会记录到配置里
+        let occupied = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let busy = Url::parse(&format!("tcp://127.0.0.1:{}", occupied.local_addr().unwrap().port())).unwrap();
+        // 无效的目标启动失败后恢复旧的服务
+        let invalid = DispatcherConfig::new_forward(incoming.clone(), Url::parse("unknown:///127.0.0.1:80").unwrap());
+        let new_config = HashMap::from([
+            (incoming.clone(), invalid),
+            (busy.clone(), forward_config(&busy, target_port)),
+        ]);
+        assert!(dispatcher.flush_new_config(new_config).await.is_err());
+
+        assert_eq!(*dispatcher.config.lock().await, config);
+        let mut stream = connect(&incoming).await;
+        assert_eq!(request(&mut stream).await, b'a');
     }
 }
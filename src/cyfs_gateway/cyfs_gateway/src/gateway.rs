@@ -1,11 +1,16 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use super::config_loader::GatewayConfig;
 use super::dispatcher::ServiceDispatcher;
 use cyfs_dns::start_cyfs_dns_server;
 use cyfs_dns::DNSServer;
 use cyfs_gateway_lib::ServerConfig;
-use cyfs_gateway_lib::{GatewayDevice, GatewayDeviceRef, TunnelManager};
+use cyfs_gateway_lib::{
+    register_dns_message_handler, unregister_dns_message_handler, GatewayDevice, GatewayDeviceRef,
+    TunnelManager,
+};
 use cyfs_socks::Socks5Proxy;
 use cyfs_warp::start_cyfs_warp_server;
 use cyfs_warp::CyfsWarpServer;
@@ -19,17 +24,19 @@ use anyhow::Result;
 use buckyos_api::{*}; 
 pub struct GatewayParams {
     pub keep_tunnel: Vec<String>,
+    // where the config comes from, only used for logging
+    pub config_source: Option<String>,
 }
 
 pub struct Gateway {
-    config: GatewayConfig,
+    config: Mutex<GatewayConfig>,
     tunnel_manager: OnceCell<TunnelManager>,
+    dispatcher: OnceCell<ServiceDispatcher>,
 
-
-    // servers
-    warp_servers: Mutex<Vec<CyfsWarpServer>>,
-    dns_servers: Mutex<Vec<DNSServer>>,
-    socks_servers: Mutex<Vec<Socks5Proxy>>,
+    // servers, key is server id
+    warp_servers: Mutex<HashMap<String, CyfsWarpServer>>,
+    dns_servers: Mutex<HashMap<String, DNSServer>>,
+    socks_servers: Mutex<HashMap<String, Socks5Proxy>>,
     device_config: OnceCell<DeviceConfig>,
     device_private_key: OnceCell<[u8; 48]>,
 }
@@ -37,12 +44,13 @@ pub struct Gateway {
 impl Gateway {
     pub fn new(config: GatewayConfig) -> Self {
         Self {
-            config,
+            config: Mutex::new(config),
             tunnel_manager: OnceCell::new(),
+            dispatcher: OnceCell::new(),
             device_config: OnceCell::new(),
-            warp_servers: Mutex::new(Vec::new()),
-            dns_servers: Mutex::new(Vec::new()),
-            socks_servers: Mutex::new(Vec::new()),
+            warp_servers: Mutex::new(HashMap::new()),
+            dns_servers: Mutex::new(HashMap::new()),
+            socks_servers: Mutex::new(HashMap::new()),
             device_private_key: OnceCell::new(),
         }
     }
@@ -79,15 +87,16 @@ impl Gateway {
         self.start_servers().await;
 
         // Start dispatchers
-        self.start_dispatcher().await;
+        self.start_dispatcher(params.config_source).await;
     }
 
     async fn load_device_keypair(&self) -> Result<()> {
         //get device private key from config
         // if not set,try load from default path
+        let config = self.config.lock().await;
         let device_private_key_path;
-        if self.config.device_key_path.is_file() {
-            device_private_key_path = self.config.device_key_path.clone();
+        if config.device_key_path.is_file() {
+            device_private_key_path = config.device_key_path.clone();
         } else {
             device_private_key_path = get_buckyos_system_etc_dir().join("node_private_key.pem");
         }
@@ -122,12 +131,12 @@ impl Gateway {
         }
 
         if !will_use_current_device_from_env {   
-            if self.config.device_name.is_none() {
+            if config.device_name.is_none() {
                 error!("cann't load device config, device_name not set");
                 return Err(anyhow::anyhow!("device_name not set"));
             }
 
-            let this_device_config = DeviceConfig::new(self.config.device_name.as_ref().unwrap(), public_key);
+            let this_device_config = DeviceConfig::new(config.device_name.as_ref().unwrap(), public_key);
             let set_result = self.device_config.set(this_device_config.clone());
             if set_result.is_err() {
                 error!("device_config can only be set once");
@@ -151,7 +160,14 @@ impl Gateway {
             private_key: self.device_private_key.get().unwrap().clone(),
         };
         let gateway_device = GatewayDeviceRef::new(gateway_device);
-        let tunnel_manager = TunnelManager::new(gateway_device.clone());
+        let mut tunnel_manager = TunnelManager::new(gateway_device.clone());
+        tunnel_manager.set_rtcp_rendezvous(self.config.lock().await.rtcp_rendezvous.clone());
+        tunnel_manager.set_rtcp_keepalive(self.config.lock().await.rtcp_keepalive());
+        tunnel_manager.set_rtcp_allow_legacy(self.config.lock().await.rtcp_allow_legacy);
+        for (selector_id, selector_config) in self.config.lock().await.selectors.iter() {
+            tunnel_manager.register_rule_stream_selector(selector_id, selector_config.clone());
+            info!("Register stream selector: {}", selector_id);
+        }
         let set_result = self.tunnel_manager.set(tunnel_manager.clone());
         if set_result.is_err() {
             error!("tunnel_manager can only be set once");
@@ -181,96 +197,283 @@ impl Gateway {
             return;
         }
 
-        let tunnel_manager = self.tunnel_manager().clone();
-        tokio::task::spawn(async move {
-            let tunnel_url = tunnel_url.unwrap();
-            loop {
-                let last_ok;
-                let tunnel = tunnel_manager.get_tunnel(&tunnel_url, None).await;
-                if tunnel.is_err() {
-                    warn!("Error getting tunnel: {}", tunnel.err().unwrap());
-                    last_ok = false;
-                } else {
-                    let tunnel = tunnel.unwrap();
-                    let ping_result = tunnel.ping().await;
-                    if ping_result.is_err() {
-                        warn!("Error pinging tunnel: {}", ping_result.err().unwrap());
-                        last_ok = false;
-                    } else {
-                        last_ok = true;
+        // 由rtcp stack负责保活和断线后的退避重连
+        let tunnel_url = tunnel_url.unwrap();
+        if let Err(e) = self.tunnel_manager().keep_tunnel(&tunnel_url).await {
+            warn!("Keep tunnel {} failed: {}", tunnel_url, e);
+        }
+    }
+
+    async fn start_servers(&self) {
+        let config = self.config.lock().await;
+        // warp先启动,DoT需要复用warp的证书管理器
+        let (warp_servers, other_servers): (Vec<_>, Vec<_>) = config
+            .servers
+            .iter()
+            .partition(|(_, server_config)| matches!(server_config, ServerConfig::Warp(_)));
+        for (server_id, server_config) in warp_servers.into_iter().chain(other_servers) {
+            self.start_server(server_id, server_config).await;
+        }
+    }
+
+    async fn start_server(&self, server_id: &str, server_config: &ServerConfig) {
+        info!("Will start server: {}, {:?}", server_id, server_config);
+
+        match server_config {
+            ServerConfig::Warp(warp_config) => {
+                let warp_config = warp_config.clone();
+                match cyfs_warp::start_cyfs_warp_server(warp_config).await {
+                    Ok(warp_server) => {
+                        let mut warp_servers = self.warp_servers.lock().await;
+                        warp_servers.insert(server_id.to_string(), warp_server);
+                    }
+                    Err(e) => {
+                        // FIXME: should we return error here? or just ignore it?
+                        error!("Error starting warp server: {}", e);
                     }
                 }
+            }
+            ServerConfig::DNS(dns_config) => {
+                let dns_config = dns_config.clone();
 
-                if last_ok {
-                    tokio::time::sleep(std::time::Duration::from_secs(60 * 2)).await;
-                } else {
-                    tokio::time::sleep(std::time::Duration::from_secs(15)).await;
+                let ret = cyfs_dns::start_cyfs_dns_server(dns_config).await;
+                match ret {
+                    Ok(dns_server) => {
+                        // DoH is served by the cyfs-warp route whose inner_service is this server id
+                        register_dns_message_handler(server_id, Arc::new(dns_server.clone()));
+                        let mut dns_servers = self.dns_servers.lock().await;
+                        dns_servers.insert(server_id.to_string(), dns_server);
+                    }
+                    Err(e) => {
+                        // FIXME: should we return error here? or just ignore it?
+                        error!("Error starting dns server: {}", e);
+                    }
                 }
             }
-        });
-    }
+            ServerConfig::Socks(socks_config) => {
+                let tunnel_provider =
+                    crate::socks::SocksTunnelBuilder::new_ref(self.tunnel_manager().clone());
 
-    async fn start_servers(&self) {
-        for (server_id, server_config) in self.config.servers.iter() {
-            info!("Will start server: {}, {:?}", server_id, server_config);
-
-            match server_config {
-                ServerConfig::Warp(warp_config) => {
-                    let warp_config = warp_config.clone();
-                    match cyfs_warp::start_cyfs_warp_server(warp_config).await {
-                        Ok(warp_server) => {
-                            let mut warp_servers = self.warp_servers.lock().await;
-                            warp_servers.push(warp_server);
-                        }
-                        Err(e) => {
-                            // FIXME: should we return error here? or just ignore it?
-                            error!("Error starting warp server: {}", e);
-                        }
+                let socks_config = socks_config.clone();
+                let ret =
+                    cyfs_socks::start_cyfs_socks_server(socks_config, tunnel_provider).await;
+
+                match ret {
+                    Ok(socks_server) => {
+                        let mut socks_servers = self.socks_servers.lock().await;
+                        socks_servers.insert(server_id.to_string(), socks_server);
                     }
-                }
-                ServerConfig::DNS(dns_config) => {
-                    let dns_config = dns_config.clone();
-
-                    let ret = cyfs_dns::start_cyfs_dns_server(dns_config).await;
-                    match ret {
-                        Ok(dns_server) => {
-                            let mut dns_servers = self.dns_servers.lock().await;
-                            dns_servers.push(dns_server);
-                        }
-                        Err(e) => {
-                            // FIXME: should we return error here? or just ignore it?
-                            error!("Error starting dns server: {}", e);
-                        }
+                    Err(e) => {
+                        // FIXME: should we return error here? or just ignore it?
+                        error!("Error starting socks server: {}", e);
                     }
                 }
-                ServerConfig::Socks(socks_config) => {
-                    let tunnel_provider =
-                        crate::socks::SocksTunnelBuilder::new_ref(self.tunnel_manager().clone());
-
-                    let socks_config = socks_config.clone();
-                    let ret =
-                        cyfs_socks::start_cyfs_socks_server(socks_config, tunnel_provider).await;
-
-                    match ret {
-                        Ok(socks_server) => {
-                            let mut socks_servers = self.socks_servers.lock().await;
-                            socks_servers.push(socks_server);
+            }
+        }
+    }
+
+    // 只停止监听,已经建立的连接继续运行
+    async fn stop_server(&self, server_id: &str) {
+        let warp_server = self.warp_servers.lock().await.remove(server_id);
+        if let Some(warp_server) = warp_server {
+            if let Err(e) = warp_server.stop().await {
+                error!("Error stopping warp server {}: {}", server_id, e);
+            }
+        }
+
+        let dns_server = self.dns_servers.lock().await.remove(server_id);
+        if let Some(dns_server) = dns_server {
+            unregister_dns_message_handler(server_id);
+            dns_server.stop().await;
+        }
+
+        let socks_server = self.socks_servers.lock().await.remove(server_id);
+        if let Some(socks_server) = socks_server {
+            socks_server.stop_and_wait().await;
+        }
+
+        info!("Server stopped: {}", server_id);
+    }
+
+    async fn update_server(&self, server_id: &str, new_config: &ServerConfig) {
+        if let ServerConfig::Warp(warp_config) = new_config {
+            let warp_servers = self.warp_servers.lock().await;
+            if let Some(warp_server) = warp_servers.get(server_id) {
+                if warp_server.can_update_in_place(warp_config) {
+                    match warp_server.update_config(warp_config.clone()).await {
+                        Ok(_) => {
+                            info!("Warp server {} updated in place", server_id);
+                            return;
                         }
                         Err(e) => {
-                            // FIXME: should we return error here? or just ignore it?
-                            error!("Error starting socks server: {}", e);
+                            warn!("Update warp server {} in place failed, will restart: {}", server_id, e);
                         }
                     }
                 }
             }
         }
+
+        self.stop_server(server_id).await;
+        self.start_server(server_id, new_config).await;
     }
 
-    async fn start_dispatcher(&self) {
-        let dispatcher = ServiceDispatcher::new(
+    async fn start_dispatcher(&self, config_source: Option<String>) {
+        let mut dispatcher = ServiceDispatcher::new(
             self.tunnel_manager().clone(),
-            self.config.dispatcher.clone(),
+            self.config.lock().await.dispatcher.clone(),
         );
+        if let Some(config_source) = config_source {
+            dispatcher = dispatcher.with_config_source(&config_source);
+        }
         dispatcher.start().await;
+        if self.dispatcher.set(dispatcher).is_err() {
+            error!("dispatcher can only be set once");
+        }
+    }
+
+    // 热更新配置,只启停变化的部分,已经建立的tunnel和stream不受影响
+    pub async fn reload_config(&self, new_config: GatewayConfig) {
+        let mut config = self.config.lock().await;
+
+        if config.device_key_path != new_config.device_key_path
+            || config.device_name != new_config.device_name
+        {
+            warn!("device config changed, will take effect after restart");
+        }
+        if config.inner_services != new_config.inner_services {
+            warn!("inner_services changed, will take effect after restart");
+        }
+        if config.rtcp_rendezvous != new_config.rtcp_rendezvous {
+            warn!("rtcp_rendezvous changed, will take effect after restart");
+        }
+        if config.rtcp_ping_interval != new_config.rtcp_ping_interval
+            || config.rtcp_ping_timeout != new_config.rtcp_ping_timeout
+        {
+            warn!("rtcp keepalive changed, will take effect after restart");
+        }
+        if config.rtcp_allow_legacy != new_config.rtcp_allow_legacy {
+            warn!("rtcp_allow_legacy changed, will take effect after restart");
+        }
+
+        // selectors
+        let tunnel_manager = self.tunnel_manager();
+        for selector_id in config.selectors.keys() {
+            if !new_config.selectors.contains_key(selector_id) {
+                tunnel_manager.unregister_stream_selector(selector_id);
+                info!("Unregister stream selector: {}", selector_id);
+            }
+        }
+        for (selector_id, selector_config) in new_config.selectors.iter() {
+            if config.selectors.get(selector_id) != Some(selector_config) {
+                tunnel_manager.register_rule_stream_selector(selector_id, selector_config.clone());
+                info!("Register stream selector: {}", selector_id);
+            }
+        }
+
+        // servers
+        for (server_id, server_config) in config.servers.iter() {
+            match new_config.servers.get(server_id) {
+                None => self.stop_server(server_id).await,
+                Some(new_server_config) if new_server_config != server_config => {
+                    self.update_server(server_id, new_server_config).await
+                }
+                _ => {}
+            }
+        }
+        for (server_id, server_config) in new_config.servers.iter() {
+            if !config.servers.contains_key(server_id) {
+                self.start_server(server_id, server_config).await;
+            }
+        }
+
+        // dispatchers
+        if let Some(dispatcher) = self.dispatcher.get() {
+            if let Err(e) = dispatcher.flush_new_config(new_config.dispatcher.clone()).await {
+                error!("reload dispatcher config failed: {}", e);
+            }
+        }
+
+        *config = new_config;
+        info!("gateway config reloaded");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cyfs_gateway_lib::GatewayDevice;
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn is_port_in_use(port: u16) -> bool {
+        std::net::TcpListener::bind(("127.0.0.1", port)).is_err()
+    }
+
+    async fn load_config(servers: serde_json::Value) -> GatewayConfig {
+        GatewayConfig::parse_from_json_value(serde_json::json!({ "servers": servers }))
+            .await
+            .unwrap()
+    }
+
+    fn warp_server(http_port: u16) -> serde_json::Value {
+        serde_json::json!({
+            "type": "cyfs-warp",
+            "bind": "127.0.0.1",
+            "http_port": http_port,
+            "tls_port": 0,
+            "hosts": {}
+        })
+    }
+
+    fn create_test_gateway(config: GatewayConfig) -> Gateway {
+        let gateway = Gateway::new(config);
+        let device = GatewayDevice {
+            config: DeviceConfig::new("gateway", "M3-pAdhs0uFkWmmjdHLBfs494R91QmQeXzCEhEHP-tI".to_string()),
+            private_key: [0u8; 48],
+        };
+        let _ = gateway.tunnel_manager.set(TunnelManager::new(Arc::new(device)));
+        gateway
+    }
+
+    #[tokio::test]
+    async fn test_reload_config_servers() {
+        let (port_keep, port_remove, port_old, port_new, port_add) =
+            (free_port(), free_port(), free_port(), free_port(), free_port());
+        let config = load_config(serde_json::json!({
+            "keep": warp_server(port_keep),
+            "remove": warp_server(port_remove),
+            "update": warp_server(port_old),
+        }))
+        .await;
+        let gateway = create_test_gateway(config);
+        gateway.start_servers().await;
+        assert_eq!(gateway.warp_servers.lock().await.len(), 3);
+        assert!(is_port_in_use(port_remove));
+        assert!(is_port_in_use(port_old));
+
+        let new_config = load_config(serde_json::json!({
+            "keep": warp_server(port_keep),
+            "update": warp_server(port_new),
+            "add": warp_server(port_add),
+        }))
+        .await;
+        gateway.reload_config(new_config).await;
+
+        let warp_servers = gateway.warp_servers.lock().await;
+        let mut server_ids: Vec<&String> = warp_servers.keys().collect();
+        server_ids.sort();
+        assert_eq!(server_ids, vec!["add", "keep", "update"]);
+        drop(warp_servers);
+
+        // 删除的server停止监听,修改了端口的server在新端口上重启
+        assert!(!is_port_in_use(port_remove));
+        assert!(!is_port_in_use(port_old));
+        assert!(is_port_in_use(port_keep));
+        assert!(is_port_in_use(port_new));
+        assert!(is_port_in_use(port_add));
+        assert_eq!(gateway.config.lock().await.servers.len(), 3);
     }
 }
+
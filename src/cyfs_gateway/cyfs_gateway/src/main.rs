@@ -4,9 +4,11 @@
 //mod gateway;
 //mod interface;
 mod config_loader;
+mod config_watcher;
 mod dispatcher;
 mod gateway;
 mod socks;
+mod tunnel_status;
 
 //mod peer;
 //mod proxy;
@@ -17,6 +19,7 @@ mod socks;
 #[macro_use]
 extern crate log;
 
+use crate::config_watcher::ConfigWatcher;
 use crate::gateway::{Gateway, GatewayParams};
 use buckyos_kit::*;
 use clap::{Arg, ArgAction, Command};
@@ -28,6 +31,7 @@ use log::*;
 use name_client::*;
 use name_lib::*;
 use std::path::PathBuf;
+use std::sync::Arc;
 use serde_json::{Value};
 use tokio::task;
 use url::Url;
@@ -36,13 +40,15 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 async fn service_main(config_json: serde_json::Value, matches: &clap::ArgMatches) -> Result<()> {
     // Load config from json
-    let load_result = config_loader::GatewayConfig::load_from_json_value(config_json).await;
+    let load_result = config_loader::GatewayConfig::load_from_json_value(config_json.clone()).await;
     if load_result.is_err() {
         let msg = format!("Error loading config: {}", load_result.err().unwrap());
         error!("{}", msg);
         std::process::exit(1);
     }
     let config_loader = load_result.unwrap();
+    let config_file = get_config_file(matches);
+    let config_key = matches.get_one::<String>("config_key").cloned();
 
     // Extract necessary params from command line
     let params = GatewayParams {
@@ -51,27 +57,33 @@ async fn service_main(config_json: serde_json::Value, matches: &clap::ArgMatches
             .unwrap_or_default()
             .map(|s| s.to_string())
             .collect(),
+        config_source: Some(config_file.display().to_string()),
     };
 
-    let gateway = Gateway::new(config_loader);
+    let gateway = Arc::new(Gateway::new(config_loader));
     gateway.start(params).await;
 
+    // 配置文件或sys_config变化时热更新
+    ConfigWatcher::new(config_file, config_key, config_json).start(gateway.clone());
+
     // Sleep forever
     let _ = tokio::signal::ctrl_c().await;
 
     Ok(())
 }
 
-// Parse config first, then config file if supplied by user
-async fn load_config_from_args(matches: &clap::ArgMatches) -> Result<serde_json::Value> {
-    let default_config = get_buckyos_system_etc_dir().join("cyfs_gateway.json");
+fn get_config_file(matches: &clap::ArgMatches) -> PathBuf {
     let config_file = matches.get_one::<String>("config_file");
-    let real_config_file;
     if config_file.is_none() {
-        real_config_file = default_config;
+        get_buckyos_system_etc_dir().join("cyfs_gateway.json")
     } else {
-        real_config_file = PathBuf::from(config_file.unwrap());
+        PathBuf::from(config_file.unwrap())
     }
+}
+
+// Parse config first, then config file if supplied by user
+async fn load_config_from_args(matches: &clap::ArgMatches) -> Result<serde_json::Value> {
+    let real_config_file = get_config_file(matches);
 
     let config_dir = real_config_file.parent().ok_or_else(|| {
         let msg = format!("cannot get config dir: {:?}", real_config_file);
@@ -79,7 +91,13 @@ async fn load_config_from_args(matches: &clap::ArgMatches) -> Result<serde_json:
         msg
     })?;
     
-    let config_json = buckyos_kit::ConfigMerger::load_dir_with_root(&config_dir, &real_config_file).await?;
+    let mut config_json = buckyos_kit::ConfigMerger::load_dir_with_root(&config_dir, &real_config_file).await?;
+
+    // sys_config优先级高于本地配置文件
+    if let Some(config_key) = matches.get_one::<String>("config_key") {
+        let (sys_config, _) = ConfigWatcher::load_sys_config(config_key).await?;
+        json_value_merge::Merge::merge(&mut config_json, &sys_config);
+    }
 
     Ok(config_json)
 }
@@ -120,6 +138,12 @@ async fn main() {
                 .help("config file path file with json format content")
                 .required(false),
         )
+        .arg(
+            Arg::new("config_key")
+                .long("config_key")
+                .help("sys_config key of gateway config, merged over the config file and watched for changes")
+                .required(false),
+        )
         .arg(
             Arg::new("keep_tunnel")
                 .long("keep_tunnel")
@@ -240,4 +264,40 @@ mod tests {
         buckyos_kit::start_udp_echo_client("127.0.0.1:6002").await;
         tokio::time::sleep(std::time::Duration::from_secs(100)).await;
     }
+
+    #[test]
+    async fn test_tunnel_status_require_auth() {
+        let mut config = serde_json::json!({
+            "inner_services": {
+                "tunnel_status": { "type": "tunnel-status" }
+            },
+            "servers": {
+                "main_http_server": {
+                    "type": "cyfs-warp",
+                    "bind": "127.0.0.1",
+                    "http_port": 3180,
+                    "tls_port": 0,
+                    "hosts": {
+                        "*": {
+                            "routes": {
+                                "/tunnels": { "inner_service": "tunnel_status" }
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        let err = GatewayConfig::parse_from_json_value(config.clone())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.contains("must configure auth"));
+
+        config["servers"]["main_http_server"]["hosts"]["*"]["routes"]["/tunnels"]["auth"] =
+            serde_json::json!({
+                "type": "basic",
+                "users": { "admin": "sha256:8c6976e5b5410415bde908bd4dee15dfb167a9c873fc4bb8a81f6f2ab448a918" }
+            });
+        assert!(GatewayConfig::parse_from_json_value(config).await.is_ok());
+    }
 }
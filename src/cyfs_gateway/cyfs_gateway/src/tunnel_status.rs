@@ -0,0 +1,49 @@
+// 控制面板查询tunnel状态的inner service, 在inner_services里配置type为tunnel-status
+// 挂载它的route必须配置auth, 否则加载配置失败:
+//   basic: users里的密码写成 sha256:<hex>
+//   krpc_token: 用trust_keys校验session token
+use ::kRPC::*;
+use async_trait::async_trait;
+use cyfs_gateway_lib::GATEWAY_TUNNEL_MANAGER;
+use serde_json::json;
+use std::net::IpAddr;
+use std::result::Result;
+
+#[derive(Clone)]
+pub struct TunnelStatusService {}
+
+impl TunnelStatusService {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    async fn get_tunnel_stats(&self) -> serde_json::Value {
+        let tunnels = match GATEWAY_TUNNEL_MANAGER.get() {
+            Some(tunnel_manager) => tunnel_manager.get_rtcp_tunnel_stats().await,
+            None => vec![],
+        };
+        json!({ "tunnels": tunnels })
+    }
+}
+
+#[async_trait]
+impl InnerServiceHandler for TunnelStatusService {
+    async fn handle_rpc_call(&self, req: RPCRequest, _ip_from: IpAddr) -> Result<RPCResponse, RPCErrors> {
+        match req.method.as_str() {
+            "get_tunnel_stats" => {
+                let stats = self.get_tunnel_stats().await;
+                Ok(RPCResponse::new(RPCResult::Success(stats), req.id))
+            }
+            _ => Err(RPCErrors::UnknownMethod(req.method)),
+        }
+    }
+
+    async fn handle_http_get(&self, req_path: &str, _ip_from: IpAddr) -> Result<String, RPCErrors> {
+        if req_path.trim_end_matches('/').ends_with("/tunnels") {
+            let stats = self.get_tunnel_stats().await;
+            return serde_json::to_string(&stats)
+                .map_err(|e| RPCErrors::ReasonError(e.to_string()));
+        }
+        Err(RPCErrors::UnknownMethod(req_path.to_string()))
+    }
+}